@@ -0,0 +1,83 @@
+//! Time to the first accurate lookup after a restart with a 1M-network tree:
+//! mapping the flat snapshot and answering from it, against rebuilding the
+//! tree from the same networks.
+//!
+//! Run with `cargo bench --bench cold_start`. The target for
+//! `open_and_lookup` is well under 500ms on a laptop.
+
+#![allow(dead_code)]
+
+use std::hint::black_box;
+use std::net::{IpAddr, Ipv4Addr};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+// The service is a binary crate, so pull in just the tree and its dependencies
+#[path = "../src/ip_lookup/bloom.rs"]
+pub mod bloom;
+#[path = "../src/ip_lookup/flat.rs"]
+pub mod flat;
+#[path = "../src/ip_lookup/overlap.rs"]
+pub mod overlap;
+#[path = "../src/ip_lookup/tree.rs"]
+pub mod tree;
+#[path = "../src/ip_lookup/types.rs"]
+pub mod types;
+
+mod ip_lookup {
+    pub use super::{bloom, flat, overlap, tree, types};
+}
+
+use ip_lookup::flat::FlatTree;
+use ip_lookup::overlap::OverlapCollector;
+use ip_lookup::tree::RadixTree;
+use ip_lookup::types::IpCategory;
+
+const NETWORKS: u32 = 1_000_000;
+
+/// Deterministic, well spread 32-bit values
+fn scatter(i: u32) -> u32 {
+    i.wrapping_mul(2_654_435_761).rotate_left(7) ^ 0x5bd1_e995
+}
+
+fn networks() -> impl Iterator<Item = ip_network::IpNetwork> {
+    (0..NETWORKS).map(|i| {
+        let prefix = if i % 4 == 0 { 24 } else { 32 };
+        ip_network::Ipv4Network::new_truncate(Ipv4Addr::from(scatter(i)), prefix).unwrap().into()
+    })
+}
+
+fn build_tree() -> RadixTree {
+    let mut tree = RadixTree::new();
+    for network in networks() {
+        tree.insert_from(network, IpCategory::Vpn, "vpn");
+    }
+    tree
+}
+
+fn cold_start(c: &mut Criterion) {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("tree.flat");
+    flat::write(&path, build_tree().flatten(&mut OverlapCollector::default())).unwrap();
+    // A listed address, so the lookup has to find the right interval
+    let probe = IpAddr::V4(Ipv4Addr::from(scatter(NETWORKS / 2)));
+
+    let mut group = c.benchmark_group("cold_start");
+    group.sample_size(10);
+    group.bench_function("open_and_lookup", |b| {
+        b.iter(|| {
+            let tree = RadixTree::from_flat(FlatTree::open(&path).unwrap());
+            assert_eq!(tree.lookup(black_box(probe)), Some(IpCategory::Vpn));
+        })
+    });
+    group.bench_function("rebuild_and_lookup", |b| {
+        b.iter(|| {
+            let tree = build_tree();
+            assert_eq!(tree.lookup(black_box(probe)), Some(IpCategory::Vpn));
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, cold_start);
+criterion_main!(benches);
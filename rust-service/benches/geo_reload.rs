@@ -0,0 +1,102 @@
+//! Read throughput of the MaxMind readers while a reload task keeps swapping
+//! them in: the `ArcSwap` the provider uses, against the `RwLock` it replaced.
+//!
+//! Run with `cargo bench --bench geo_reload`. Each reload swaps in a freshly
+//! opened database, so the lock variant shows how long lookups wait behind
+//! the writer.
+
+#![allow(dead_code)]
+
+use std::hint::black_box;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use arc_swap::ArcSwap;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use maxminddb::{geoip2, Reader};
+use parking_lot::RwLock;
+
+// The service is a binary crate, so pull in just the fixture writer
+#[path = "../src/test_support/mmdb.rs"]
+mod mmdb;
+
+const LOOKUPS_PER_THREAD: u32 = 20_000;
+
+/// How a lookup reaches the current reader, and how a reload replaces it
+trait Readers: Send + Sync + 'static {
+    fn lookup(&self, ip: IpAddr) -> bool;
+    fn swap(&self, reader: Reader<Vec<u8>>);
+}
+
+impl Readers for ArcSwap<Reader<Vec<u8>>> {
+    fn lookup(&self, ip: IpAddr) -> bool {
+        self.load().lookup::<geoip2::City<'_>>(ip).unwrap().is_some()
+    }
+
+    fn swap(&self, reader: Reader<Vec<u8>>) {
+        self.store(Arc::new(reader));
+    }
+}
+
+impl Readers for RwLock<Reader<Vec<u8>>> {
+    fn lookup(&self, ip: IpAddr) -> bool {
+        self.read().lookup::<geoip2::City<'_>>(ip).unwrap().is_some()
+    }
+
+    fn swap(&self, reader: Reader<Vec<u8>>) {
+        *self.write() = reader;
+    }
+}
+
+/// `threads` threads doing their lookups while another swaps readers until they finish
+fn lookups_during_reloads<R: Readers>(readers: &Arc<R>, threads: u32) {
+    let ip: IpAddr = mmdb::FIXTURE_US_IP.parse().unwrap();
+    let done = Arc::new(AtomicBool::new(false));
+    let reloader = {
+        let readers = Arc::clone(readers);
+        let done = Arc::clone(&done);
+        thread::spawn(move || {
+            while !done.load(Ordering::Relaxed) {
+                readers.swap(mmdb::city_fixture());
+            }
+        })
+    };
+    let handles: Vec<_> = (0..threads)
+        .map(|_| {
+            let readers = Arc::clone(readers);
+            thread::spawn(move || {
+                for _ in 0..LOOKUPS_PER_THREAD {
+                    black_box(readers.lookup(black_box(ip)));
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    done.store(true, Ordering::Relaxed);
+    reloader.join().unwrap();
+}
+
+fn read_throughput(c: &mut Criterion) {
+    let mut group = c.benchmark_group("lookups_during_reloads");
+    for threads in [1u32, 4, 8] {
+        group.throughput(Throughput::Elements(u64::from(threads * LOOKUPS_PER_THREAD)));
+
+        let swapped = Arc::new(ArcSwap::from_pointee(mmdb::city_fixture()));
+        group.bench_with_input(BenchmarkId::new("arc_swap", threads), &threads, |b, &threads| {
+            b.iter(|| lookups_during_reloads(&swapped, threads));
+        });
+
+        let locked = Arc::new(RwLock::new(mmdb::city_fixture()));
+        group.bench_with_input(BenchmarkId::new("rwlock", threads), &threads, |b, &threads| {
+            b.iter(|| lookups_during_reloads(&locked, threads));
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, read_throughput);
+criterion_main!(benches);
@@ -0,0 +1,93 @@
+//! Concurrent lookups against a radix tree the size of the live feeds, with
+//! and without the IPv4 Bloom prefilter.
+//!
+//! Run with `cargo bench --bench radix_tree`. Most production lookups are
+//! for clean addresses, so the mix is 95% misses.
+
+#![allow(dead_code)]
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::thread;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+// The service is a binary crate, so pull in just the tree and its dependencies
+#[path = "../src/ip_lookup/bloom.rs"]
+pub mod bloom;
+#[path = "../src/ip_lookup/flat.rs"]
+pub mod flat;
+#[path = "../src/ip_lookup/overlap.rs"]
+pub mod overlap;
+#[path = "../src/ip_lookup/tree.rs"]
+pub mod tree;
+#[path = "../src/ip_lookup/types.rs"]
+pub mod types;
+
+mod ip_lookup {
+    pub use super::{bloom, flat, overlap, tree, types};
+}
+
+use ip_lookup::tree::{RadixTree, SharedRadixTree};
+use ip_lookup::types::IpCategory;
+
+const NETWORKS: u32 = 200_000;
+const LOOKUPS_PER_THREAD: u32 = 20_000;
+
+/// Deterministic, well spread 32-bit values
+fn scatter(i: u32) -> u32 {
+    i.wrapping_mul(2_654_435_761).rotate_left(7) ^ 0x5bd1_e995
+}
+
+fn build_tree(prefilter: bool) -> SharedRadixTree {
+    let mut tree = RadixTree::new();
+    for i in 0..NETWORKS {
+        let prefix = if i % 4 == 0 { 24 } else { 32 };
+        let network = ip_network::Ipv4Network::new_truncate(Ipv4Addr::from(scatter(i)), prefix).unwrap();
+        tree.insert(network.into(), IpCategory::Vpn);
+    }
+    if prefilter {
+        tree.build_prefilter();
+    }
+    let shared = SharedRadixTree::new();
+    shared.replace(tree);
+    shared
+}
+
+fn addresses(thread: u32) -> Vec<IpAddr> {
+    (0..LOOKUPS_PER_THREAD)
+        .map(|i| {
+            let n = thread * LOOKUPS_PER_THREAD + i;
+            let addr = if n.is_multiple_of(20) { scatter(n % NETWORKS) } else { scatter(n).reverse_bits() };
+            IpAddr::V4(Ipv4Addr::from(addr))
+        })
+        .collect()
+}
+
+fn concurrent_lookups(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_lookups");
+    for threads in [1u32, 4, 8] {
+        let inputs: Arc<Vec<Vec<IpAddr>>> = Arc::new((0..threads).map(addresses).collect());
+        group.throughput(Throughput::Elements(u64::from(threads * LOOKUPS_PER_THREAD)));
+
+        for (name, prefilter) in [("tree_only", false), ("bloom_prefilter", true)] {
+            let tree = build_tree(prefilter);
+            group.bench_with_input(BenchmarkId::new(name, threads), &threads, |b, &threads| {
+                b.iter(|| {
+                    let handles: Vec<_> = (0..threads as usize)
+                        .map(|t| {
+                            let tree = tree.clone();
+                            let inputs = Arc::clone(&inputs);
+                            thread::spawn(move || inputs[t].iter().filter(|ip| tree.lookup(**ip).is_some()).count())
+                        })
+                        .collect();
+                    handles.into_iter().map(|h| h.join().unwrap()).sum::<usize>()
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, concurrent_lookups);
+criterion_main!(benches);
@@ -0,0 +1,307 @@
+//! Typed async client for the InfraLock HTTP API.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), infralock_client::Error> {
+//! let client = infralock_client::Client::new("https://infralock.example.com", "my-api-key")?;
+//! let lookup = client.lookup("8.8.8.8").await?;
+//! println!("{} scored {}", lookup.ip, lookup.threat_score);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Requests failing with a transport error, `429` or a `5xx` are retried
+//! with exponential backoff and full jitter; other errors are returned as
+//! they are. Lookups can be cached for a fixed time with
+//! [`ClientBuilder::cache_ttl`]. [`ShardedLookup`] spreads lookups over
+//! instances that each own part of the address space.
+
+use std::time::Duration;
+
+use futures_util::{stream, StreamExt};
+use moka::sync::Cache;
+use rand::Rng;
+use reqwest::{Method, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use thiserror::Error;
+use url::Url;
+
+pub use infralock_types as types;
+pub use shard::{ConsistentHashRouter, ShardRouter, ShardedLookup};
+use infralock_types::{LookupRequest, LookupResponse, ProxyResponse, ThreatScoreResponse, TorResponse, VpnResponse};
+
+mod shard;
+
+const API_KEY_HEADER: &str = "x-api-key";
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("invalid base URL: {0}")]
+    InvalidUrl(#[from] url::ParseError),
+
+    #[error("HTTP request failed: {0}")]
+    Request(#[from] reqwest::Error),
+
+    /// The service answered with an error status; `body` is its error document
+    #[error("HTTP {status}: {body}")]
+    Api { status: StatusCode, body: String },
+
+    #[error("unexpected response: {0}")]
+    Decode(String),
+}
+
+impl Error {
+    /// Worth sending the request again: the service or the network failed,
+    /// not the request
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::Request(e) => e.is_timeout() || e.is_connect() || e.is_request(),
+            Self::Api { status, .. } => status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS,
+            _ => false,
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Clone)]
+pub struct ClientBuilder {
+    base_url: String,
+    api_key: String,
+    timeout: Duration,
+    max_retries: u32,
+    retry_base_delay: Duration,
+    cache_ttl: Option<Duration>,
+    cache_capacity: u64,
+    batch_concurrency: usize,
+}
+
+impl ClientBuilder {
+    /// Per-attempt timeout, 10 seconds by default
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Retries after the first attempt, 2 by default; 0 disables retrying
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Upper bound of the first retry's delay, doubled for each retry after
+    /// it, 100ms by default. The actual delay is picked at random below it.
+    pub fn retry_base_delay(mut self, delay: Duration) -> Self {
+        self.retry_base_delay = delay;
+        self
+    }
+
+    /// Cache lookups for `ttl`. Off by default; the service already caches,
+    /// so this only saves the round trip.
+    pub fn cache_ttl(mut self, ttl: Duration) -> Self {
+        self.cache_ttl = Some(ttl);
+        self
+    }
+
+    /// Lookups kept in the cache, 10,000 by default
+    pub fn cache_capacity(mut self, capacity: u64) -> Self {
+        self.cache_capacity = capacity;
+        self
+    }
+
+    /// Lookups in flight at once in [`Client::batch_lookup`], 8 by default
+    pub fn batch_concurrency(mut self, concurrency: usize) -> Self {
+        self.batch_concurrency = concurrency.max(1);
+        self
+    }
+
+    pub fn build(self) -> Result<Client> {
+        let base_url = Url::parse(&self.base_url)?;
+        let http = reqwest::Client::builder().timeout(self.timeout).build()?;
+        let cache = self.cache_ttl.map(|ttl| {
+            Cache::builder()
+                .max_capacity(self.cache_capacity)
+                .time_to_live(ttl)
+                .build()
+        });
+        Ok(Client {
+            http,
+            base_url,
+            api_key: self.api_key,
+            max_retries: self.max_retries,
+            retry_base_delay: self.retry_base_delay,
+            cache,
+            batch_concurrency: self.batch_concurrency,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: Url,
+    api_key: String,
+    max_retries: u32,
+    retry_base_delay: Duration,
+    cache: Option<Cache<String, LookupResponse>>,
+    batch_concurrency: usize,
+}
+
+impl Client {
+    /// A client with the default settings; see [`Client::builder`]
+    pub fn new(base_url: impl Into<String>, api_key: impl Into<String>) -> Result<Self> {
+        Self::builder(base_url, api_key).build()
+    }
+
+    pub fn builder(base_url: impl Into<String>, api_key: impl Into<String>) -> ClientBuilder {
+        ClientBuilder {
+            base_url: base_url.into(),
+            api_key: api_key.into(),
+            timeout: Duration::from_secs(10),
+            max_retries: 2,
+            retry_base_delay: Duration::from_millis(100),
+            cache_ttl: None,
+            cache_capacity: 10_000,
+            batch_concurrency: 8,
+        }
+    }
+
+    pub fn base_url(&self) -> &Url {
+        &self.base_url
+    }
+
+    /// Geo, ASN and threat data for `ip`. The IP goes in the request body,
+    /// so it stays out of access logs.
+    pub async fn lookup(&self, ip: &str) -> Result<LookupResponse> {
+        if let Some(cached) = self.cache.as_ref().and_then(|cache| cache.get(ip)) {
+            return Ok(cached);
+        }
+        let body = LookupRequest { ip: ip.to_string() };
+        let response: LookupResponse = self.send(Method::POST, &["api", "v1", "lookup"], Some(&body)).await?.json().await?;
+        if let Some(cache) = &self.cache {
+            cache.insert(ip.to_string(), response.clone());
+        }
+        Ok(response)
+    }
+
+    /// [`Client::lookup`] for the address the service sees the request from
+    pub async fn lookup_self(&self) -> Result<LookupResponse> {
+        self.get_json(&["api", "v1", "lookup", "self"]).await
+    }
+
+    pub async fn threat_score(&self, ip: &str) -> Result<ThreatScoreResponse> {
+        self.get_json(&["api", "v1", "threat-score", ip]).await
+    }
+
+    /// Whether `ip_or_cidr` is, or a range contains, a known proxy
+    pub async fn is_proxy(&self, ip_or_cidr: &str) -> Result<ProxyResponse> {
+        self.get_json(&["api", "v1", "proxy", ip_or_cidr]).await
+    }
+
+    /// Whether `ip_or_cidr` is, or a range contains, a VPN or datacenter
+    /// address. The service answers this one in plain text.
+    pub async fn is_vpn(&self, ip_or_cidr: &str) -> Result<VpnResponse> {
+        let text = self.send(Method::GET, &["api", "v1", "vpn", ip_or_cidr], None::<&()>).await?.text().await?;
+        VpnResponse::parse(&text).ok_or_else(|| Error::Decode(format!("no VPN verdict in {:?}", text)))
+    }
+
+    pub async fn is_tor(&self, ip: &str) -> Result<TorResponse> {
+        self.get_json(&["api", "v1", "tor", ip]).await
+    }
+
+    /// [`Client::lookup`] for each of `ips`, a few at a time, in the order
+    /// given. One failing lookup does not fail the others.
+    pub async fn batch_lookup<S: AsRef<str>>(&self, ips: &[S]) -> Vec<Result<LookupResponse>> {
+        stream::iter(ips)
+            .map(|ip| self.lookup(ip.as_ref()))
+            .buffered(self.batch_concurrency)
+            .collect()
+            .await
+    }
+
+    async fn get_json<T: DeserializeOwned>(&self, path: &[&str]) -> Result<T> {
+        Ok(self.send(Method::GET, path, None::<&()>).await?.json().await?)
+    }
+
+    /// Send the request, retrying transient failures, and return the first
+    /// successful response
+    async fn send<B: Serialize>(&self, method: Method, path: &[&str], body: Option<&B>) -> Result<reqwest::Response> {
+        let url = self.url(path);
+        let mut attempt = 0;
+        loop {
+            let mut request = self.http.request(method.clone(), url.clone()).header(API_KEY_HEADER, &self.api_key);
+            if let Some(body) = body {
+                request = request.json(body);
+            }
+            let result = match request.send().await {
+                Ok(response) if response.status().is_success() => return Ok(response),
+                Ok(response) => Err(Error::Api {
+                    status: response.status(),
+                    body: response.text().await.unwrap_or_default(),
+                }),
+                Err(e) => Err(Error::Request(e)),
+            };
+            match result {
+                Err(e) if e.is_transient() && attempt < self.max_retries => {
+                    tokio::time::sleep(retry_delay(self.retry_base_delay, attempt)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// `path` under the base URL, each segment percent-encoded, so a CIDR's
+    /// `/` is sent as `%2F`
+    fn url(&self, path: &[&str]) -> Url {
+        let mut url = self.base_url.clone();
+        url.path_segments_mut()
+            .expect("base URL parsed as http(s)")
+            .pop_if_empty()
+            .extend(path);
+        url
+    }
+}
+
+/// Full jitter: a random delay up to `base` doubled `attempt` times
+fn retry_delay(base: Duration, attempt: u32) -> Duration {
+    let cap = base.saturating_mul(1u32.checked_shl(attempt.min(16)).unwrap_or(u32::MAX));
+    cap.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_url_encodes_ranges_and_keeps_base_path() {
+        let client = Client::new("http://localhost:8080/geo", "key").unwrap();
+        assert_eq!(
+            client.url(&["api", "proxy", "10.0.0.0/8"]).as_str(),
+            "http://localhost:8080/geo/api/proxy/10.0.0.0%2F8"
+        );
+
+        let client = Client::new("http://localhost:8080/", "key").unwrap();
+        assert_eq!(client.url(&["api", "lookup", "self"]).as_str(), "http://localhost:8080/api/lookup/self");
+    }
+
+    #[test]
+    fn test_retry_delay_is_jittered_below_the_doubled_base() {
+        let base = Duration::from_millis(100);
+        for attempt in 0..4 {
+            let delay = retry_delay(base, attempt);
+            assert!(delay <= base * 2u32.pow(attempt), "{:?} on attempt {}", delay, attempt);
+        }
+        assert!(retry_delay(base, u32::MAX) <= base * 65_536);
+    }
+
+    #[test]
+    fn test_only_server_errors_are_retried() {
+        let api = |status: u16| Error::Api { status: StatusCode::from_u16(status).unwrap(), body: String::new() };
+        assert!(api(503).is_transient());
+        assert!(api(429).is_transient());
+        assert!(!api(400).is_transient());
+        assert!(!api(401).is_transient());
+        assert!(!Error::Decode("html".to_string()).is_transient());
+    }
+}
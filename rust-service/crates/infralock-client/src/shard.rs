@@ -0,0 +1,194 @@
+//! Client-side routing for deployments that split the range tree across
+//! instances by IP prefix.
+//!
+//! Every address in a prefix goes to the same instance: `/16` for IPv4 and
+//! `/32` for IPv6. The prefix is placed on a consistent-hash ring of the
+//! instances, so adding or removing one only moves the prefixes it gains or
+//! loses.
+
+use std::net::IpAddr;
+
+use futures_util::{stream, StreamExt};
+use infralock_types::{LookupResponse, ThreatScoreResponse};
+
+use crate::{Client, Result};
+
+/// IPv4 prefix length whose addresses share an instance
+pub const V4_SHARD_PREFIX: u8 = 16;
+/// IPv6 prefix length whose addresses share an instance
+pub const V6_SHARD_PREFIX: u8 = 32;
+
+/// Ring points per instance; more spread the prefixes more evenly
+const VIRTUAL_NODES: u32 = 64;
+
+/// Picks the instance that owns an IP
+pub trait ShardRouter: Send + Sync {
+    /// Index of the owning instance, below the number of instances routed to
+    fn shard_for(&self, ip: IpAddr) -> usize;
+}
+
+/// Routes each IP's prefix by consistent hashing over the instance names
+#[derive(Debug, Clone)]
+pub struct ConsistentHashRouter {
+    /// Ring points and the instance each belongs to, sorted by point
+    ring: Vec<(u64, usize)>,
+}
+
+impl ConsistentHashRouter {
+    /// A ring of `names`, one per instance in order. Names must be stable
+    /// and match on every client, e.g. the instances' base URLs.
+    pub fn new<S: AsRef<str>>(names: &[S]) -> Self {
+        assert!(!names.is_empty(), "a shard ring needs at least one instance");
+        let mut ring: Vec<(u64, usize)> = names
+            .iter()
+            .enumerate()
+            .flat_map(|(shard, name)| {
+                (0..VIRTUAL_NODES).map(move |node| (stable_hash(format!("{}#{}", name.as_ref(), node).as_bytes()), shard))
+            })
+            .collect();
+        ring.sort_unstable();
+        Self { ring }
+    }
+}
+
+impl ShardRouter for ConsistentHashRouter {
+    fn shard_for(&self, ip: IpAddr) -> usize {
+        let point = stable_hash(&shard_key(ip));
+        let next = self.ring.partition_point(|&(node, _)| node < point);
+        self.ring[next % self.ring.len()].1
+    }
+}
+
+/// The high bits the instance is picked by, tagged with the address family.
+/// IPv4-mapped IPv6 addresses count as IPv4, as the service treats them.
+fn shard_key(ip: IpAddr) -> Vec<u8> {
+    match ip.to_canonical() {
+        IpAddr::V4(v4) => {
+            let mut key = vec![4];
+            key.extend_from_slice(&v4.octets()[..usize::from(V4_SHARD_PREFIX / 8)]);
+            key
+        }
+        IpAddr::V6(v6) => {
+            let mut key = vec![6];
+            key.extend_from_slice(&v6.octets()[..usize::from(V6_SHARD_PREFIX / 8)]);
+            key
+        }
+    }
+}
+
+/// 64-bit FNV-1a with MurmurHash3's finalizer, which spreads the few bytes
+/// of a prefix over the whole ring. Unlike `DefaultHasher`, it is fixed
+/// across Rust releases, so every client build routes alike.
+fn stable_hash(bytes: &[u8]) -> u64 {
+    let mut hash = bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+/// Clients for each instance of a sharded deployment, sending each IP to the
+/// instance its [`ShardRouter`] picks
+#[derive(Debug, Clone)]
+pub struct ShardedLookup<R = ConsistentHashRouter> {
+    shards: Vec<Client>,
+    router: R,
+}
+
+impl ShardedLookup {
+    /// Route over `shards` by consistent hashing of their base URLs
+    pub fn new(shards: Vec<Client>) -> Self {
+        let names: Vec<&str> = shards.iter().map(|shard| shard.base_url().as_str()).collect();
+        let router = ConsistentHashRouter::new(&names);
+        Self::with_router(shards, router)
+    }
+}
+
+impl<R: ShardRouter> ShardedLookup<R> {
+    /// Route over `shards` with a custom router
+    pub fn with_router(shards: Vec<Client>, router: R) -> Self {
+        assert!(!shards.is_empty(), "a sharded lookup needs at least one instance");
+        Self { shards, router }
+    }
+
+    /// The client for the instance owning `ip`. Strings that are not IPs go
+    /// to the first instance, which rejects them like any other would.
+    pub fn shard(&self, ip: &str) -> &Client {
+        let index = ip.parse().map_or(0, |ip| self.router.shard_for(ip));
+        &self.shards[index.min(self.shards.len() - 1)]
+    }
+
+    /// [`Client::lookup`] on the owning instance
+    pub async fn lookup(&self, ip: &str) -> Result<LookupResponse> {
+        self.shard(ip).lookup(ip).await
+    }
+
+    /// [`Client::threat_score`] on the owning instance
+    pub async fn threat_score(&self, ip: &str) -> Result<ThreatScoreResponse> {
+        self.shard(ip).threat_score(ip).await
+    }
+
+    /// [`Client::batch_lookup`] across the instances, each IP sent to its
+    /// owner, with results in the order given
+    pub async fn batch_lookup<S: AsRef<str>>(&self, ips: &[S]) -> Vec<Result<LookupResponse>> {
+        let concurrency = self.shards[0].batch_concurrency * self.shards.len();
+        stream::iter(ips)
+            .map(|ip| self.lookup(ip.as_ref()))
+            .buffered(concurrency)
+            .collect()
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    fn names(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("http://shard-{}:8080/", i)).collect()
+    }
+
+    #[test]
+    fn test_prefix_stays_on_one_shard() {
+        let router = ConsistentHashRouter::new(&names(4));
+        assert_eq!(router.shard_for(ip("198.51.0.1")), router.shard_for(ip("198.51.255.254")));
+        assert_eq!(router.shard_for(ip("198.51.100.7")), router.shard_for(ip("::ffff:198.51.100.7")));
+        assert_eq!(router.shard_for(ip("2001:db8::1")), router.shard_for(ip("2001:db8:ffff::1")));
+    }
+
+    #[test]
+    fn test_prefixes_spread_and_mostly_stay_when_a_shard_is_added() {
+        let four = ConsistentHashRouter::new(&names(4));
+        let five = ConsistentHashRouter::new(&names(5));
+        let prefixes: Vec<IpAddr> = (0..=255u8).flat_map(|a| [0u8, 97, 201].map(|b| IpAddr::from([a, b, 0, 0]))).collect();
+
+        let mut counts = [0usize; 4];
+        for &prefix in &prefixes {
+            counts[four.shard_for(prefix)] += 1;
+        }
+        let fair = prefixes.len() / 4;
+        assert!(counts.iter().all(|&count| count > fair / 2 && count < fair * 2), "{:?}", counts);
+
+        // Only the prefixes the new shard takes over move
+        let moved = prefixes.iter().filter(|&&prefix| four.shard_for(prefix) != five.shard_for(prefix)).count();
+        assert!(prefixes.iter().all(|&prefix| {
+            let owner = five.shard_for(prefix);
+            owner == 4 || owner == four.shard_for(prefix)
+        }));
+        assert!(moved < prefixes.len() / 3, "{} of {} moved", moved, prefixes.len());
+    }
+
+    #[test]
+    fn test_invalid_ips_go_to_the_first_shard() {
+        let shards = names(3).into_iter().map(|name| Client::new(name, "key").unwrap()).collect();
+        let sharded = ShardedLookup::new(shards);
+        assert_eq!(sharded.shard("not-an-ip").base_url().as_str(), "http://shard-0:8080/");
+    }
+}
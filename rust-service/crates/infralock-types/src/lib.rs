@@ -0,0 +1,14 @@
+//! Request and response types of the InfraLock HTTP API.
+//!
+//! The service serializes these and `infralock-client` deserializes them, so
+//! a field added or renamed on one side is added or renamed on the other.
+//! Only serde is required; the `openapi` feature adds the `ToSchema` derives
+//! the service's OpenAPI document is generated from.
+
+pub mod location;
+pub mod lookup;
+pub mod ranges;
+
+pub use location::{AsnInfo, City, Country, GeoInfo, Location, NetworkTraits};
+pub use lookup::{CategoryFlags, CloudKind, LookupErrors, LookupRequest, LookupResponse, ThreatScoreResponse};
+pub use ranges::{ProxyResponse, TorResponse, VpnResponse};
@@ -0,0 +1,69 @@
+//! Geo and ASN records, as the lookup response carries them
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct GeoInfo {
+    pub city: Option<City>,
+    pub country: Option<Country>,
+    pub location: Option<Location>,
+    /// Network flags from the database's `traits` record. Not serialized here;
+    /// they are surfaced as top-level fields on the lookup response.
+    #[serde(skip)]
+    pub traits: Option<NetworkTraits>,
+}
+
+/// Network-level flags reported by GeoIP2-style databases.
+///
+/// `is_hosting_provider` is only present in Enterprise databases.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct NetworkTraits {
+    pub is_anonymous_proxy: Option<bool>,
+    pub is_anycast: Option<bool>,
+    pub is_satellite_provider: Option<bool>,
+    pub is_hosting_provider: Option<bool>,
+}
+
+impl NetworkTraits {
+    pub fn is_anonymous_proxy(&self) -> bool {
+        self.is_anonymous_proxy.unwrap_or(false)
+    }
+
+    pub fn is_anycast(&self) -> bool {
+        self.is_anycast.unwrap_or(false)
+    }
+
+    pub fn is_hosting_provider(&self) -> bool {
+        self.is_hosting_provider.unwrap_or(false)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct City {
+    pub names: Option<std::collections::HashMap<String, String>>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Country {
+    pub names: Option<std::collections::HashMap<String, String>>,
+    /// ISO 3166-1 alpha-2 code, e.g. `US`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iso_code: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Location {
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct AsnInfo {
+    pub autonomous_system_number: Option<u32>,
+    pub autonomous_system_organization: Option<String>,
+}
@@ -0,0 +1,162 @@
+//! Lookup and threat score responses
+
+use serde::{Deserialize, Serialize};
+
+use crate::location::{AsnInfo, GeoInfo};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct LookupResponse {
+    pub ip: String,
+    // `ip` with an embedded IPv4 address unwrapped (`::ffff:1.2.3.4` -> `1.2.3.4`); what the verdict is for
+    pub canonical_ip: String,
+    pub geo_info: Option<GeoInfo>,
+    pub asn_info: Option<AsnInfo>,
+    pub is_vpn_or_datacenter: bool,
+    pub is_proxy: bool,
+    pub proxy_type: Option<String>,
+    pub is_tor_exit_node: bool,
+    // Not a Tor exit now, but dropped off a Tor exit list within `ip_lookup.tor_delisted_window_secs`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub recently_delisted: bool,
+    // One flag per category, for every range containing the IP (and the tunnel origin's)
+    #[serde(default)]
+    pub categories: CategoryFlags,
+    // The matched range's category (e.g. `socks5_proxy`, `cloud_aws`), or the configured fallback
+    pub category: Option<String>,
+    // Feed whose range matched (e.g. `thespeedx-socks5`), for tracing false positives to their list
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matched_source: Option<String>,
+    // Provider whose published ranges contain the IP (aws/gcp/azure/oci)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cloud_provider: Option<CloudKind>,
+    // Network flags reported by the geo database; omitted when the database has no data
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_anonymous_proxy: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_anycast: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_satellite_provider: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_hosting_provider: Option<bool>,
+    pub threat_score: u8,  // 0-100 threat score
+    pub threat_details: Vec<String>,  // Descriptions of threats found
+    // Set when `threat_details` was cut to the configured maximum; the score still counts every finding
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub findings_truncated: bool,
+    pub recommended_action: String,  // Recommended response action (allow/challenge/block/redirect/monitor)
+    // In monitor mode, the action that would have been enforced
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shadow_action: Option<String>,
+    // Features whose portion of the response was omitted because they are disabled
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub disabled_features: Vec<String>,
+    // Database lookups that failed, leaving `geo_info` or `asn_info` null
+    #[serde(default, skip_serializing_if = "LookupErrors::is_empty")]
+    pub errors: LookupErrors,
+    // Set when a deadline cut the lookup short; `skipped` lists the databases left out (`geo`, `asn`)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub partial: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skipped: Vec<String>,
+    // Set while the tree lacks, or holds stale, data for a category it should have; the verdict may miss threats
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub data_unavailable: bool,
+    // With `challenge` and challenge tokens enabled, the token to redeem at /api/challenge/verify
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub challenge_token: Option<String>,
+}
+
+/// Errors from the geo and ASN databases, by the portion they left out
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct LookupErrors {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub geo: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub asn: Option<String>,
+}
+
+impl LookupErrors {
+    pub fn is_empty(&self) -> bool {
+        self.geo.is_none() && self.asn.is_none()
+    }
+}
+
+/// One flag per range category, set for each category of range containing
+/// an IP, so rules can test for one without parsing `category` or `proxy_type`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(default)]
+pub struct CategoryFlags {
+    pub vpn: bool,
+    pub proxy_http: bool,
+    pub proxy_socks4: bool,
+    pub proxy_socks5: bool,
+    pub tor: bool,
+    /// In any provider's ranges; `cloud_provider` names which
+    pub cloud: bool,
+}
+
+impl CategoryFlags {
+    /// The names of the set flags, as serialized, in declaration order
+    pub fn names(&self) -> impl Iterator<Item = &'static str> {
+        [
+            (self.vpn, "vpn"),
+            (self.proxy_http, "proxy_http"),
+            (self.proxy_socks4, "proxy_socks4"),
+            (self.proxy_socks5, "proxy_socks5"),
+            (self.tor, "tor"),
+            (self.cloud, "cloud"),
+        ]
+        .into_iter()
+        .filter_map(|(set, name)| set.then_some(name))
+    }
+}
+
+/// Cloud providers with published IP range feeds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum CloudKind {
+    Aws,
+    Gcp,
+    Azure,
+    Oci,
+}
+
+impl CloudKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Aws => "aws",
+            Self::Gcp => "gcp",
+            Self::Azure => "azure",
+            Self::Oci => "oci",
+        }
+    }
+}
+
+impl std::fmt::Display for CloudKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Body of `POST /api/lookup`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct LookupRequest {
+    /// IPv4 or IPv6 address
+    pub ip: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ThreatScoreResponse {
+    pub ip: String,
+    pub threat_score: u8,
+    /// The score before rounding, with `?precise=true`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score_precise: Option<f32>,
+    pub threat_details: Vec<String>,
+}
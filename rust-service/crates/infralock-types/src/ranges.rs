@@ -0,0 +1,74 @@
+//! Range check responses
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct TorResponse {
+    pub is_tor_exit_node: bool,
+    /// The range that was checked, with host bits cleared; only set for ranges
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub network: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ProxyResponse {
+    pub is_proxy: bool,
+    pub proxy_type: Option<String>,
+    /// The range that was checked, with host bits cleared; only set for ranges
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub network: Option<String>,
+}
+
+/// `GET /api/vpn/{ip_or_range}`, which answers in plain text: an
+/// `is_vpn/datacenter: <bool>` line for an IP, or `contains_vpn/datacenter:
+/// <bool>` and `network: <range>` lines for a range
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VpnResponse {
+    /// The IP, or any address in the range, is in a VPN or datacenter range
+    pub is_vpn_or_datacenter: bool,
+    /// The range that was checked, with host bits cleared; only set for ranges
+    pub network: Option<String>,
+}
+
+impl VpnResponse {
+    /// Read the plain-text answer; `None` if it has no verdict line
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut verdict = None;
+        let mut network = None;
+        for line in text.lines() {
+            match line.split_once(':').map(|(key, value)| (key.trim(), value.trim())) {
+                Some(("is_vpn/datacenter" | "contains_vpn/datacenter", value)) => verdict = value.parse().ok(),
+                Some(("network", value)) => network = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        Some(Self { is_vpn_or_datacenter: verdict?, network })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vpn_response_parses_both_forms() {
+        assert_eq!(
+            VpnResponse::parse("is_vpn/datacenter: true"),
+            Some(VpnResponse { is_vpn_or_datacenter: true, network: None })
+        );
+        assert_eq!(
+            VpnResponse::parse("contains_vpn/datacenter: false\nnetwork: 10.0.0.0/8"),
+            Some(VpnResponse { is_vpn_or_datacenter: false, network: Some("10.0.0.0/8".to_string()) })
+        );
+        assert_eq!(VpnResponse::parse("<html>"), None);
+    }
+
+    #[test]
+    fn test_proxy_response_keeps_unknown_proxy_types() {
+        // A newer service may report types this crate has never heard of
+        let response: ProxyResponse = serde_json::from_str(r#"{"is_proxy":true,"proxy_type":"HTTPS"}"#).unwrap();
+        assert_eq!(response.proxy_type.as_deref(), Some("HTTPS"));
+    }
+}
@@ -0,0 +1,152 @@
+//! Command-line interface.
+//!
+//! Without a subcommand the binary runs the HTTP service. `lookup` answers
+//! for one IP from the local geo databases and range files, without fetching
+//! feeds or binding a port, and prints the same JSON as `/api/lookup/{ip}`.
+
+use std::error::Error;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use clap::{Parser, Subcommand};
+use moka::sync::Cache;
+
+use crate::config::{runtime::RuntimeConfig, validation, Settings};
+use crate::errors::validation::validate_ip;
+use crate::geo::{self, GeoProvider};
+use crate::handlers::LookupResponse;
+use crate::ip_lookup::{self, IpLookupService};
+use crate::services::lookup_service::LookupService;
+
+#[derive(Debug, Parser)]
+#[command(name = "infralock", about = "IP geolocation and threat scoring service")]
+pub struct Cli {
+    /// Report every configuration problem and exit without starting
+    #[arg(long)]
+    pub validate_config: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Look up an IP against the local databases and print the result as JSON
+    Lookup {
+        ip: IpAddr,
+    },
+}
+
+/// Run `command` and print its output
+pub async fn run(command: Command, settings: &Settings) -> Result<(), Box<dyn Error>> {
+    check_data_files(settings)?;
+    match command {
+        Command::Lookup { ip } => {
+            let response = lookup(settings, ip).await?;
+            println!("{}", serde_json::to_string_pretty(&response)?);
+        }
+    }
+    Ok(())
+}
+
+/// Fail with every missing or unreadable data file at once, rather than
+/// with the first reader that cannot open its file
+fn check_data_files(settings: &Settings) -> Result<(), Box<dyn Error>> {
+    let diagnostics = validation::validate_paths(settings, std::env::vars());
+    let (errors, warnings): (Vec<_>, Vec<_>) =
+        diagnostics.iter().partition(|diagnostic| diagnostic.severity == validation::Severity::Error);
+    for warning in warnings {
+        eprintln!("{}", warning);
+    }
+    if errors.is_empty() {
+        return Ok(());
+    }
+    let errors: Vec<String> = errors.iter().map(ToString::to_string).collect();
+    Err(format!("data files are missing or unreadable:\n  {}", errors.join("\n  ")).into())
+}
+
+async fn lookup(settings: &Settings, ip: IpAddr) -> Result<LookupResponse, Box<dyn Error>> {
+    validate_ip(ip)?;
+    let geo_provider = geo::from_settings(settings)?;
+
+    // Read the range files as they are: no downloads, snapshots or archives
+    let mut config = ip_lookup::config_from_settings(settings)?;
+    config.offline = true;
+    config.check_updates = false;
+    config.snapshot_retention = 0;
+    config.archive_retention = 0;
+    let ip_lookup_service = Arc::new(IpLookupService::new(config));
+    if let Err(e) = ip_lookup_service.update_all_sources().await {
+        // Whatever did load is still in the tree
+        eprintln!("warning: {}", e);
+    }
+
+    let response = lookup_service(settings, geo_provider, ip_lookup_service).lookup_ip(ip).await?;
+    Ok(response)
+}
+
+/// A lookup service configured like the HTTP handlers' default one
+fn lookup_service(
+    settings: &Settings,
+    geo_provider: Arc<dyn GeoProvider>,
+    ip_lookup_service: Arc<IpLookupService>,
+) -> LookupService {
+    let runtime = RuntimeConfig::from_settings(settings);
+    LookupService::new(geo_provider, Arc::new(Cache::new(1)), ip_lookup_service, runtime.scoring_config)
+        .with_features(settings.features)
+        .with_require_geo(settings.geo.require_geo)
+        .with_tunnel_extraction(settings.ip_lookup.tunnel_extraction)
+        .with_6to4_canonicalization(settings.ip_lookup.canonicalize_6to4)
+        .with_missing_data_policy(settings.ip_lookup.missing_data_policy())
+        .with_category_fallback(settings.ip_lookup.category_fallback.as_deref().map(Arc::from))
+        .with_response_action_config(runtime.response_action_config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geo::MaxMindProvider;
+    use crate::ip_lookup::IpCategory;
+    use crate::test_support::{self, mmdb};
+
+    #[test]
+    fn test_parse_args() {
+        let cli = Cli::try_parse_from(["infralock"]).unwrap();
+        assert!(cli.command.is_none() && !cli.validate_config);
+
+        let cli = Cli::try_parse_from(["infralock", "--validate-config"]).unwrap();
+        assert!(cli.validate_config);
+
+        let cli = Cli::try_parse_from(["infralock", "lookup", "2001:db8::1"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Lookup { ip }) if ip == "2001:db8::1".parse::<IpAddr>().unwrap()));
+
+        assert!(Cli::try_parse_from(["infralock", "lookup", "not-an-ip"]).is_err());
+        assert!(Cli::try_parse_from(["infralock", "lookup"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_missing_data_files_reported_together() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = Settings::default();
+        settings.maxmind.db_path = dir.path().join("city.mmdb");
+        settings.maxmind.asn_db_path = dir.path().join("asn.mmdb");
+
+        let error = run(Command::Lookup { ip: "8.8.8.8".parse().unwrap() }, &settings).await.unwrap_err().to_string();
+        assert!(error.starts_with("data files are missing or unreadable:"), "{error}");
+        assert!(error.contains("maxmind.db_path") && error.contains("maxmind.asn_db_path"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn test_lookup_matches_http_response() {
+        let state = test_support::app_state_with_ranges(vec![test_support::range("5.1.1.1/32", IpCategory::TorExitNode)]);
+        let geo_provider = Arc::new(MaxMindProvider::new(mmdb::city_fixture(), mmdb::asn_fixture()));
+        let service = lookup_service(&Settings::default(), geo_provider, Arc::clone(&state.ip_lookup_service));
+
+        for ip in [mmdb::FIXTURE_US_IP, "5.1.1.1"] {
+            let ip: IpAddr = ip.parse().unwrap();
+            let cli = serde_json::to_value(service.lookup_ip(ip).await.unwrap()).unwrap();
+            let http = serde_json::to_value(crate::handlers::lookup_service(&state).lookup_ip(ip).await.unwrap()).unwrap();
+            assert_eq!(cli, http);
+        }
+    }
+}
@@ -9,6 +9,8 @@ use serde::Serialize;
 use thiserror::Error;
 use tracing::{error, warn, info};
 
+use crate::utils::http_client::{CertPins, OutboundHttpError};
+
 #[derive(Error, Debug)]
 pub enum ResilientClientError {
     #[error("Request failed after maximum retries")]
@@ -22,6 +24,10 @@ pub enum ResilientClientError {
     
     #[error("Invalid response: {0}")]
     InvalidResponse(String),
+
+    /// The server failed the certificate pinning policy
+    #[error("TLS policy violation: {0}")]
+    TlsPolicy(#[from] OutboundHttpError),
 }
 
 #[derive(Clone, Debug)]
@@ -101,18 +107,26 @@ pub struct ResilientClient {
     circuit_breaker: CircuitBreaker,
     cache: Arc<Cache<String, (String, Instant)>>,
     base_url: String,
+    cert_pins: CertPins,
 }
 
 impl ResilientClient {
-    pub fn new(base_url: String) -> Self {
+    pub fn new(base_url: String, client: Client) -> Self {
         Self {
-            client: Client::new(),
+            client,
             circuit_breaker: CircuitBreaker::new(5, Duration::from_secs(30)),
             cache: Arc::new(Cache::new(10_000)), // Cache up to 10,000 responses
             base_url,
+            cert_pins: CertPins::default(),
         }
     }
 
+    /// Only accept responses from servers presenting one of `cert_pins`
+    pub fn with_cert_pins(mut self, cert_pins: CertPins) -> Self {
+        self.cert_pins = cert_pins;
+        self
+    }
+
     pub async fn get_cached<T: DeserializeOwned + Send + 'static>(
         &self,
         path: &str,
@@ -227,6 +241,8 @@ impl ResilientClient {
             
             match request.send().await {
                 Ok(response) => {
+                    // Retrying would reach the same server, so this is not retried
+                    self.cert_pins.check(&response)?;
                     let status = response.status();
                     info!("Received response: {}", status);
                     
@@ -317,6 +333,7 @@ impl ResilientClient {
             
             match request.send().await {
                 Ok(response) => {
+                    self.cert_pins.check(&response)?;
                     if response.status().is_success() {
                         info!("Request successful - Status: {} - Path: {}", response.status(), path);
                         self.circuit_breaker.record_success().await;
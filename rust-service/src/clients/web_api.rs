@@ -3,6 +3,9 @@ use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use std::time::Duration;
 use crate::clients::resilient_client::{ResilientClient, ResilientClientError};
+use crate::config::OutboundHttpSettings;
+use crate::utils::http_client::{self, CertPins};
+use crate::services::usage::UsageReport;
 use std::time::Instant;
 use serde_json::json;
 use log::{info, warn, error};
@@ -43,6 +46,7 @@ pub struct WebApiClientConfig {
     pub base_url: String,
     pub service_token: String,
     pub cache_ttl: Duration,
+    pub outbound_http: OutboundHttpSettings,
 }
 
 impl Default for WebApiClientConfig {
@@ -53,6 +57,7 @@ impl Default for WebApiClientConfig {
             service_token: std::env::var("INTERNAL_SERVICE_TOKEN")
                 .unwrap_or_else(|_| "default-service-token".to_string()),
             cache_ttl: Duration::from_secs(300), // 5 minutes default cache TTL
+            outbound_http: OutboundHttpSettings::default(),
         }
     }
 }
@@ -66,7 +71,10 @@ pub struct WebApiClient {
 
 impl WebApiClient {
     pub fn new(config: WebApiClientConfig) -> Self {
-        let client = ResilientClient::new(config.base_url.clone());
+        let http_client = http_client::build_client(&config.outbound_http)
+            .expect("Failed to create HTTP client");
+        let cert_pins = CertPins::from_settings(&config.outbound_http).expect("Invalid certificate pins");
+        let client = ResilientClient::new(config.base_url.clone(), http_client).with_cert_pins(cert_pins);
         
         let cache = Arc::new(tokio::sync::RwLock::new(
             lru::LruCache::new(std::num::NonZeroUsize::new(1000).unwrap())
@@ -79,26 +87,43 @@ impl WebApiClient {
         }
     }
     
+    // The key itself is never recorded on the span
+    #[tracing::instrument(
+        name = "infralock.validate_api_key",
+        skip_all,
+        fields(cache_hit = tracing::field::Empty, role = tracing::field::Empty)
+    )]
     pub async fn validate_api_key(&self, api_key: &str) -> Result<ApiKeyValidationResponse, WebApiError> {
+        let span = tracing::Span::current();
+
         // Check cache first
         if let Some(cached) = self.check_cache(api_key).await {
             info!("API key found in cache for user: {:?}", cached.email);
+            span.record("cache_hit", true);
+            if let Some(role) = &cached.role {
+                span.record("role", role.as_str());
+            }
             return Ok(cached);
         }
+        span.record("cache_hit", false);
         
         info!("Validating API key (not found in cache)");
         
         let path = "/internal/validate-key";
+
+        // Carry the trace context so the web API joins this request's trace
+        let mut headers = vec![
+            ("Authorization".to_string(), format!("Bearer {}", self.config.service_token)),
+            ("x-api-key".to_string(), api_key.to_string())
+        ];
+        headers.extend(crate::telemetry::trace_context_headers());
         
         let response_result = self.client
             .post_json_with_headers::<serde_json::Value, ApiKeyValidationResponse>(
                 path,
                 &serde_json::json!({}),
                 self.config.cache_ttl,
-                &[
-                    ("Authorization".to_string(), format!("Bearer {}", self.config.service_token)),
-                    ("x-api-key".to_string(), api_key.to_string())
-                ]
+                &headers
             )
             .await
             .map_err(|e| {
@@ -130,6 +155,7 @@ impl WebApiClient {
                 let user_id = response.user_id.take().expect("user_id should be present");
                 let email = response.email.take().expect("email should be present");
                 let role = response.role.take().expect("role should be present");
+                span.record("role", role.as_str());
                 
                 // Cache the result if valid
                 self.cache_api_key(
@@ -193,6 +219,47 @@ impl WebApiClient {
         cache.put(api_key, cached);
     }
     
+    /// Treat `api_key` as already validated for `user_id` with `role`, so
+    /// tests can authenticate without a web API
+    #[cfg(test)]
+    pub fn with_cached_key(self, api_key: &str, user_id: &str, role: &str) -> Self {
+        let cached = CachedApiKey {
+            valid_until: Instant::now() + self.config.cache_ttl,
+            user_id: user_id.to_string(),
+            email: format!("{}@example.com", user_id),
+            role: role.to_string(),
+        };
+        self.cache.try_write().expect("cache is not shared yet").put(api_key.to_string(), cached);
+        self
+    }
+
+    /// Deliver a closed usage window. The web API must ignore a repeated
+    /// `Idempotency-Key`, since a window is resent until it is accepted.
+    pub async fn report_usage(&self, report: &UsageReport) -> Result<(), WebApiError> {
+        let mut headers = vec![
+            ("Authorization".to_string(), format!("Bearer {}", self.config.service_token)),
+            ("Idempotency-Key".to_string(), report.idempotency_key.clone()),
+        ];
+        headers.extend(crate::telemetry::trace_context_headers());
+
+        // A zero TTL keeps the resilient client from answering a resend from its cache
+        self.client
+            .post_json_with_headers::<UsageReport, serde_json::Value>(
+                "/internal/usage",
+                report,
+                Duration::ZERO,
+                &headers,
+            )
+            .await
+            .map_err(|e| {
+                warn!("Usage report {} was not accepted: {}", report.idempotency_key, e);
+                e
+            })?;
+
+        info!("Usage report {} accepted", report.idempotency_key);
+        Ok(())
+    }
+
     pub async fn reset_circuit_breaker(&self) {
         // This is a test method to reset the circuit breaker
         // In a real application, you'd want to handle this more carefully
@@ -0,0 +1,298 @@
+//! Where the service keeps its data files, and getting that tree ready at
+//! startup.
+//!
+//! Every data path defaults to a fixed place under one `data_root`, `data`
+//! in the working directory unless `GEO__DATA_ROOT` moves it. Before
+//! anything loads or downloads, [`prepare`] creates the directories, checks
+//! the service can write to them, and moves files left in the old `data/`
+//! tree when the root has moved, so a fresh or misconfigured container fails
+//! once at startup with every problem listed rather than later, one ENOENT
+//! at a time.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use super::Settings;
+
+/// Data root when none is configured, relative to the working directory
+pub const DEFAULT_DATA_ROOT: &str = "data";
+
+/// Name of the file written and removed to check a directory is writable
+const PROBE_FILE: &str = ".write-probe";
+
+/// The paths under a data root
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataLayout {
+    root: PathBuf,
+}
+
+impl Default for DataLayout {
+    fn default() -> Self {
+        Self::new(DEFAULT_DATA_ROOT)
+    }
+}
+
+impl DataLayout {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub fn maxmind_dir(&self) -> PathBuf {
+        self.root.join("maxmind")
+    }
+
+    pub fn maxmind_city_db(&self) -> PathBuf {
+        self.maxmind_dir().join("GeoLite2-City.mmdb")
+    }
+
+    pub fn maxmind_asn_db(&self) -> PathBuf {
+        self.maxmind_dir().join("GeoLite2-ASN.mmdb")
+    }
+
+    pub fn ip2location_db(&self) -> PathBuf {
+        self.root.join("ip2location").join("IP2LOCATION.BIN")
+    }
+
+    pub fn vpns_dir(&self) -> PathBuf {
+        self.root.join("vpns")
+    }
+
+    pub fn vpn_list(&self) -> PathBuf {
+        self.vpns_dir().join("ipv4.txt")
+    }
+
+    pub fn proxies_dir(&self) -> PathBuf {
+        self.root.join("proxies")
+    }
+
+    pub fn http_proxy_list(&self) -> PathBuf {
+        self.proxies_dir().join("http.txt")
+    }
+
+    pub fn socks4_proxy_list(&self) -> PathBuf {
+        self.proxies_dir().join("socks4.txt")
+    }
+
+    pub fn socks5_proxy_list(&self) -> PathBuf {
+        self.proxies_dir().join("socks5.txt")
+    }
+
+    pub fn tor_dir(&self) -> PathBuf {
+        self.root.join("tor")
+    }
+
+    /// Downloaded feeds and range snapshots
+    pub fn ip_ranges_dir(&self) -> PathBuf {
+        self.root.join("ip_ranges")
+    }
+
+    /// Archived feed entry lists
+    pub fn archive_dir(&self) -> PathBuf {
+        self.root.join("archive")
+    }
+
+    /// Hand-maintained entries merged over the feeds
+    pub fn overrides_file(&self) -> PathBuf {
+        self.root.join("overrides.txt")
+    }
+
+    pub fn usage_dir(&self) -> PathBuf {
+        self.root.join("usage")
+    }
+
+    /// Scratch space the background updater downloads into
+    pub fn tmp_update_dir(&self) -> PathBuf {
+        self.root.join("tmp_update")
+    }
+
+    /// Directories the service writes into, the root first
+    pub fn directories(&self) -> Vec<PathBuf> {
+        vec![
+            self.root.clone(),
+            self.maxmind_dir(),
+            self.vpns_dir(),
+            self.proxies_dir(),
+            self.tor_dir(),
+            self.ip_ranges_dir(),
+            self.archive_dir(),
+            self.usage_dir(),
+            self.tmp_update_dir(),
+        ]
+    }
+
+    /// Files worth keeping when the root moves; downloaded feeds and
+    /// snapshots under `ip_ranges` are fetched again instead
+    fn migrated_files(&self) -> Vec<PathBuf> {
+        vec![
+            self.maxmind_city_db(),
+            self.maxmind_asn_db(),
+            self.ip2location_db(),
+            self.vpn_list(),
+            self.http_proxy_list(),
+            self.socks4_proxy_list(),
+            self.socks5_proxy_list(),
+            self.tor_dir().join("exit-addresses.txt"),
+            self.overrides_file(),
+        ]
+    }
+
+    /// Create this layout's directories and `extra_dirs`, check each is
+    /// writable, then move the files [`migrated_files`](Self::migrated_files)
+    /// finds under `legacy` that are missing here. Existing files are never
+    /// overwritten. Returns the files moved, or every problem found.
+    pub fn prepare(&self, legacy: &DataLayout, extra_dirs: &[PathBuf]) -> Result<Vec<Migration>, DataLayoutError> {
+        let mut problems = Vec::new();
+        let mut dirs = self.directories();
+        for dir in extra_dirs {
+            if !dir.as_os_str().is_empty() && !dirs.contains(dir) {
+                dirs.push(dir.clone());
+            }
+        }
+        for dir in &dirs {
+            if let Err(e) = fs::create_dir_all(dir) {
+                problems.push(format!("cannot create {}: {}", dir.display(), e));
+            } else if let Err(e) = probe_writable(dir) {
+                problems.push(format!("{} is not writable: {}", dir.display(), e));
+            }
+        }
+        if !problems.is_empty() {
+            return Err(DataLayoutError { problems });
+        }
+
+        let mut migrations = Vec::new();
+        if !self.same_root(legacy) {
+            for (from, to) in legacy.migrated_files().into_iter().zip(self.migrated_files()) {
+                if !from.is_file() || to.exists() {
+                    continue;
+                }
+                match move_file(&from, &to) {
+                    Ok(()) => migrations.push(Migration { from, to }),
+                    Err(e) => problems.push(format!("cannot move {} to {}: {}", from.display(), to.display(), e)),
+                }
+            }
+        }
+        if problems.is_empty() {
+            Ok(migrations)
+        } else {
+            Err(DataLayoutError { problems })
+        }
+    }
+
+    /// Whether both layouts resolve to the same directory; a legacy root
+    /// that does not exist never is
+    fn same_root(&self, other: &DataLayout) -> bool {
+        match (fs::canonicalize(&self.root), fs::canonicalize(&other.root)) {
+            (Ok(root), Ok(other)) => root == other,
+            _ => self.root == other.root,
+        }
+    }
+}
+
+/// A file moved from the old tree into the configured root
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Migration {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+#[derive(Debug, Error)]
+#[error("data directory is not usable:\n  {}", problems.join("\n  "))]
+pub struct DataLayoutError {
+    pub problems: Vec<String>,
+}
+
+/// Prepare the configured data tree, migrating from the default `data/` in
+/// the working directory, and make sure every directory a path setting
+/// writes into exists as well
+pub fn prepare(settings: &Settings) -> Result<Vec<Migration>, DataLayoutError> {
+    let mut extra_dirs = vec![settings.usage.dir.clone()];
+    extra_dirs.extend(
+        settings
+            .background_updater
+            .sources()
+            .iter()
+            .filter(|(_, source)| source.enabled)
+            .filter_map(|(_, source)| source.path.parent().map(Path::to_path_buf)),
+    );
+    extra_dirs.extend(settings.ip_lookup.overrides_file.parent().map(Path::to_path_buf));
+    settings.data_layout().prepare(&DataLayout::default(), &extra_dirs)
+}
+
+fn probe_writable(dir: &Path) -> io::Result<()> {
+    let probe = dir.join(format!("{}-{}", PROBE_FILE, std::process::id()));
+    fs::write(&probe, b"")?;
+    fs::remove_file(&probe)
+}
+
+/// Rename, or copy and remove when the two are on different filesystems
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    fs::copy(from, to)?;
+    fs::remove_file(from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_fresh_root_gets_every_directory() {
+        let temp = tempdir().unwrap();
+        let layout = DataLayout::new(temp.path().join("srv/infralock"));
+        let extra = temp.path().join("elsewhere/usage");
+
+        let legacy = DataLayout::new(temp.path().join("data"));
+
+        assert!(layout.prepare(&legacy, std::slice::from_ref(&extra)).unwrap().is_empty());
+        for dir in layout.directories().iter().chain([&extra]) {
+            assert!(dir.is_dir(), "{} missing", dir.display());
+            assert_eq!(fs::read_dir(dir).unwrap().filter_map(Result::ok).filter(|e| e.path().is_file()).count(), 0);
+        }
+        // Running again on the existing tree changes nothing
+        assert!(layout.prepare(&legacy, &[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_legacy_files_move_into_the_new_root() {
+        let temp = tempdir().unwrap();
+        let legacy = DataLayout::new(temp.path().join("data"));
+        fs::create_dir_all(legacy.vpns_dir()).unwrap();
+        fs::write(legacy.vpn_list(), "5.2.2.0/24\n").unwrap();
+        fs::write(legacy.overrides_file(), "203.0.113.7 allow\n").unwrap();
+        let layout = DataLayout::new(temp.path().join("state"));
+        fs::create_dir_all(temp.path().join("state")).unwrap();
+        fs::write(layout.overrides_file(), "kept\n").unwrap();
+
+        let migrations = layout.prepare(&legacy, &[]).unwrap();
+        assert_eq!(migrations, vec![Migration { from: legacy.vpn_list(), to: layout.vpn_list() }]);
+        assert_eq!(fs::read_to_string(layout.vpn_list()).unwrap(), "5.2.2.0/24\n");
+        assert!(!legacy.vpn_list().exists());
+        // A file already in the new root wins over the legacy copy
+        assert_eq!(fs::read_to_string(layout.overrides_file()).unwrap(), "kept\n");
+        assert!(legacy.overrides_file().exists());
+
+        // The default root is its own legacy tree, so nothing moves
+        assert!(legacy.prepare(&DataLayout::new(temp.path().join("data")), &[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_every_problem_is_reported_at_once() {
+        let temp = tempdir().unwrap();
+        let blocker = temp.path().join("not-a-dir");
+        fs::write(&blocker, "").unwrap();
+        let layout = DataLayout::new(temp.path().join("data"));
+
+        let error = layout.prepare(&layout.clone(), &[blocker.join("usage"), blocker.join("lists")]).unwrap_err();
+        assert_eq!(error.problems.len(), 2, "{}", error);
+        assert!(error.to_string().starts_with("data directory is not usable:\n  cannot create"), "{}", error);
+    }
+}
@@ -1,97 +1,949 @@
-use serde::Deserialize;
-use std::{net::SocketAddr, path::PathBuf};
+use chrono::NaiveDate;
+use serde::{Deserialize, Deserializer};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-#[derive(Debug, Deserialize, Clone)]
+use crate::errors::validation::{CidrLimits, MissingIpPolicy};
+use crate::geo::GeoProviderKind;
+use crate::ip_lookup::IpCategory;
+use crate::models::threat_score::{ScoringModel, ThreatType};
+use crate::services::background_updater::{BackgroundUpdaterConfig, UpdateSource};
+use crate::services::response_action::{ResponseAction, ResponseActionConfig};
+use crate::utils::http_client::redact_credentials;
+use crate::utils::redact::IpRedaction;
+
+pub mod data_layout;
+pub mod runtime;
+pub mod validation;
+
+use data_layout::DataLayout;
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
 pub struct Settings {
+    /// Directory every data path defaults to a place under
+    pub data_root: PathBuf,
     pub server: ServerSettings,
     pub maxmind: MaxmindSettings,
     pub vpn_detector: VpnDetectorSettings,
     pub proxy_detector: ProxyDetectorSettings,
-    pub tor_detector: TorDetectorSettings,
+    pub geo: GeoSettings,
+    pub scoring: ScoringSettings,
+    pub ip_lookup: IpLookupSettings,
+    pub features: FeatureSettings,
+    pub response_action: ResponseActionConfig,
+    pub cache_warming: CacheWarmingSettings,
+    pub telemetry: TelemetrySettings,
+    pub usage: UsageSettings,
+    pub decision_log: DecisionLogSettings,
+    pub challenge: ChallengeSettings,
+    pub background_updater: BackgroundUpdaterSettings,
+    pub outbound_http: OutboundHttpSettings,
+    pub cache: CacheSettings,
+    /// Scoring/action profiles keyed by API key role
+    pub profiles: HashMap<String, ProfileSettings>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            data_root: PathBuf::from(data_layout::DEFAULT_DATA_ROOT),
+            server: ServerSettings::default(),
+            maxmind: MaxmindSettings::default(),
+            vpn_detector: VpnDetectorSettings::default(),
+            proxy_detector: ProxyDetectorSettings::default(),
+            geo: GeoSettings::default(),
+            scoring: ScoringSettings::default(),
+            ip_lookup: IpLookupSettings::default(),
+            features: FeatureSettings::default(),
+            response_action: ResponseActionConfig::default(),
+            cache_warming: CacheWarmingSettings::default(),
+            telemetry: TelemetrySettings::default(),
+            usage: UsageSettings::default(),
+            decision_log: DecisionLogSettings::default(),
+            challenge: ChallengeSettings::default(),
+            background_updater: BackgroundUpdaterSettings::default(),
+            outbound_http: OutboundHttpSettings::default(),
+            cache: CacheSettings::default(),
+            profiles: HashMap::new(),
+        }
+    }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(default)]
 pub struct ServerSettings {
     pub host: String,
     pub port: u16,
+    /// Addresses to listen on (`host:port`, hostnames resolved at startup);
+    /// empty listens on `host:port` alone
+    #[serde(deserialize_with = "comma_separated")]
+    pub listen: Vec<String>,
+    /// Longest a `/api/vpn` or `/api/proxy` range scan may run before it
+    /// gives up and reports no match
+    pub range_scan_timeout_ms: u64,
+    /// Longest the geo and ASN lookups of an IP lookup may run before the
+    /// response goes out without them; 0 waits for them
+    pub lookup_timeout_ms: u64,
+    /// What self-lookups and gates do when no proxy header names the client
+    pub on_missing_ip: MissingIpPolicy,
+    /// Most `X-Forwarded-For` entries accepted; longer chains get 400
+    pub max_forwarded_hops: usize,
+    /// Requests handled at once; further requests get 503 until one finishes
+    pub max_concurrent_requests: usize,
+    /// Broadest IPv4 prefix the range endpoints accept
+    pub min_range_prefix_v4: u8,
+    /// Broadest IPv6 prefix the range endpoints accept
+    pub min_range_prefix_v6: u8,
+    /// Let the range endpoints check ranges that overlap private or
+    /// reserved space
+    pub allow_private_ranges: bool,
+    /// Bearer token `/metrics` requires; unset leaves it open
+    pub metrics_token: Option<String>,
+    /// Also serve the API at its unversioned `/api/...` paths, marked
+    /// deprecated in favour of `/api/v1/...`
+    pub legacy_routes: bool,
+    /// Sunset date announced on the unversioned paths
+    pub legacy_routes_sunset: NaiveDate,
+    /// Put the verdict in `X-InfraLock-*` headers on every lookup and gate
+    /// response, not only when the request asks with `X-InfraLock-Headers`
+    pub verdict_headers: bool,
+    /// Most findings a lookup response lists in `threat_details`, keeping
+    /// the heaviest; 0 lists them all
+    pub max_findings: usize,
+}
+
+/// The metrics token is left out, so settings can be logged
+impl std::fmt::Debug for ServerSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServerSettings")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("listen", &self.listen)
+            .field("range_scan_timeout_ms", &self.range_scan_timeout_ms)
+            .field("lookup_timeout_ms", &self.lookup_timeout_ms)
+            .field("on_missing_ip", &self.on_missing_ip)
+            .field("max_forwarded_hops", &self.max_forwarded_hops)
+            .field("max_concurrent_requests", &self.max_concurrent_requests)
+            .field("min_range_prefix_v4", &self.min_range_prefix_v4)
+            .field("min_range_prefix_v6", &self.min_range_prefix_v6)
+            .field("allow_private_ranges", &self.allow_private_ranges)
+            .field("metrics_token", &self.metrics_token.as_ref().map(|_| "***"))
+            .field("legacy_routes", &self.legacy_routes)
+            .field("legacy_routes_sunset", &self.legacy_routes_sunset)
+            .field("verdict_headers", &self.verdict_headers)
+            .field("max_findings", &self.max_findings)
+            .finish()
+    }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            host: "0.0.0.0".to_string(),
+            port: 6000,
+            listen: Vec::new(),
+            range_scan_timeout_ms: 100,
+            lookup_timeout_ms: 0,
+            on_missing_ip: MissingIpPolicy::UseConnectInfo,
+            max_forwarded_hops: 16,
+            max_concurrent_requests: 1024,
+            min_range_prefix_v4: 8,
+            min_range_prefix_v6: 32,
+            allow_private_ranges: false,
+            metrics_token: None,
+            legacy_routes: true,
+            legacy_routes_sunset: NaiveDate::from_ymd_opt(2027, 6, 30).expect("valid date"),
+            verdict_headers: false,
+            max_findings: 0,
+        }
+    }
+}
+
+impl ServerSettings {
+    /// The `host:port` addresses to listen on
+    pub fn listen_addrs(&self) -> Vec<String> {
+        if self.listen.is_empty() {
+            vec![join_host_port(&self.host, self.port)]
+        } else {
+            self.listen.clone()
+        }
+    }
+
+    /// The lookup deadline, counted from the start of each lookup
+    pub fn lookup_timeout(&self) -> Option<Duration> {
+        (self.lookup_timeout_ms > 0).then(|| Duration::from_millis(self.lookup_timeout_ms))
+    }
+
+    /// `max_findings`, or `None` when unlimited
+    pub fn max_findings(&self) -> Option<usize> {
+        (self.max_findings > 0).then_some(self.max_findings)
+    }
+
+    pub fn cidr_limits(&self) -> CidrLimits {
+        CidrLimits {
+            min_v4_prefix: self.min_range_prefix_v4,
+            min_v6_prefix: self.min_range_prefix_v6,
+            allow_private_ranges: self.allow_private_ranges,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
 pub struct MaxmindSettings {
     pub db_path: PathBuf,
     pub asn_db_path: PathBuf,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+impl Default for MaxmindSettings {
+    fn default() -> Self {
+        Self {
+            db_path: DataLayout::default().maxmind_city_db(),
+            asn_db_path: DataLayout::default().maxmind_asn_db(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct GeoSettings {
+    pub provider: GeoProviderKind,
+    pub ip2location_db_path: PathBuf,
+    /// Fail lookups with 404 when the IP has no city record, instead of
+    /// returning `geo_info: null`
+    pub require_geo: bool,
+}
+
+impl Default for GeoSettings {
+    fn default() -> Self {
+        Self {
+            provider: GeoProviderKind::MaxMind,
+            ip2location_db_path: DataLayout::default().ip2location_db(),
+            require_geo: false,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct ScoringSettings {
+    pub scoring_model: ScoringModel,
+    pub vpn_weight: f32,
+    pub proxy_weight: f32,
+    pub tor_weight: f32,
+    pub anonymous_proxy_weight: f32,
+    pub hosting_provider_weight: f32,
+    pub datacenter_weight: f32,
+    pub anycast_suppresses_vpn: bool,
+    /// ASN organization patterns (case-insensitive regexes, plain names
+    /// match as substrings) that add a datacenter finding
+    #[serde(deserialize_with = "comma_separated")]
+    pub datacenter_asn_orgs: Vec<String>,
+}
+
+impl Default for ScoringSettings {
+    fn default() -> Self {
+        Self {
+            scoring_model: ScoringModel::Probabilistic,
+            vpn_weight: 0.6,
+            proxy_weight: 0.8,
+            tor_weight: 0.9,
+            anonymous_proxy_weight: 0.7,
+            hosting_provider_weight: 0.4,
+            datacenter_weight: 0.5,
+            anycast_suppresses_vpn: true,
+            datacenter_asn_orgs: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct IpLookupSettings {
+    /// Number of radix tree snapshots kept for rollback (0 disables)
+    pub snapshot_retention: usize,
+    /// Prefix that single-host IPv6 proxy/Tor entries are widened to (128 disables)
+    pub ipv6_aggregate_prefix: u8,
+    /// Entry lists kept per source under `data/archive/` (0 disables archiving and diffs)
+    pub archive_retention: usize,
+    /// Load the AWS and GCP published ranges to report `cloud_provider` on lookups
+    pub cloud_providers: bool,
+    /// Check IPv4 lookups against a Bloom filter before walking the radix tree
+    pub bloom_filter: bool,
+    /// Write each tree to `tree.flat` in the data dir and serve lookups from
+    /// it at startup while the first tree builds
+    pub flat_snapshot: bool,
+    /// Load sources only from their files under the data dir and never
+    /// download, for deployments that sync the lists out-of-band
+    pub offline: bool,
+    /// Hand-maintained `CIDR category` lines merged over the feeds; a
+    /// missing file means no overrides
+    pub overrides_file: PathBuf,
+    /// Also check the IPv4 origin embedded in 6to4 and Teredo addresses
+    pub tunnel_extraction: bool,
+    /// Look up and cache 6to4 addresses as the IPv4 address they embed
+    pub canonicalize_6to4: bool,
+    /// Reported as a lookup's `category` when no range matches; unset reports `null`
+    pub category_fallback: Option<String>,
+    /// Seconds an IP that left a Tor feed is reported as `recently_delisted` (0 disables)
+    pub tor_delisted_window_secs: u64,
+    /// Longest wait, in seconds, before retrying a source that keeps failing;
+    /// the wait doubles per failure from the update interval (0 disables)
+    pub feed_backoff_max_secs: u64,
+    /// Categories to detect (`vpn`, `tor`, `socks5_proxy`, `aws`, ...);
+    /// sources of other categories are not loaded. Empty detects all.
+    #[serde(deserialize_with = "comma_separated")]
+    pub enabled_categories: Vec<String>,
+    /// What lookups do while a category an enabled source feeds has no
+    /// entries in the tree, or data older than `max_data_age_secs`
+    pub on_missing_threat_data: ThreatDataPolicy,
+    /// Least strict action recommended under the `closed` policy
+    pub missing_data_action: ResponseAction,
+    /// Age, in seconds, past which a category's data counts as missing (0 disables)
+    pub max_data_age_secs: u64,
+}
+
+impl IpLookupSettings {
+    /// The parsed `enabled_categories`, `None` when every category is
+    /// enabled. Unknown names are left out; validation reports them.
+    pub fn enabled_categories(&self) -> Option<Vec<IpCategory>> {
+        if self.enabled_categories.is_empty() {
+            return None;
+        }
+        Some(self.enabled_categories.iter().filter_map(|name| name.parse().ok()).collect())
+    }
+
+    pub fn missing_data_policy(&self) -> MissingDataPolicy {
+        MissingDataPolicy {
+            policy: self.on_missing_threat_data,
+            action: self.missing_data_action,
+            max_age: (self.max_data_age_secs > 0).then(|| Duration::from_secs(self.max_data_age_secs)),
+        }
+    }
+}
+
+impl Default for IpLookupSettings {
+    fn default() -> Self {
+        Self {
+            snapshot_retention: 3,
+            ipv6_aggregate_prefix: 128,
+            archive_retention: 2,
+            cloud_providers: true,
+            bloom_filter: true,
+            flat_snapshot: true,
+            offline: false,
+            overrides_file: DataLayout::default().overrides_file(),
+            tunnel_extraction: false,
+            canonicalize_6to4: false,
+            category_fallback: None,
+            tor_delisted_window_secs: 0,
+            feed_backoff_max_secs: 86400,
+            enabled_categories: Vec::new(),
+            on_missing_threat_data: ThreatDataPolicy::Open,
+            missing_data_action: ResponseAction::Challenge,
+            max_data_age_secs: 0,
+        }
+    }
+}
+
+/// How lookups answer while threat data is missing or stale
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ThreatDataPolicy {
+    /// Score as usual, so IPs the missing data would flag are allowed
+    #[default]
+    Open,
+    /// Flag the response `data_unavailable` and recommend at least the
+    /// configured action
+    Closed,
+    /// Flag the response `data_unavailable` and leave the action alone
+    Degraded,
+}
+
+/// `ip_lookup`'s missing-data settings, as lookups apply them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MissingDataPolicy {
+    pub policy: ThreatDataPolicy,
+    pub action: ResponseAction,
+    /// Data older than this counts as missing; `None` only checks for entries
+    pub max_age: Option<Duration>,
+}
+
+impl Default for MissingDataPolicy {
+    fn default() -> Self {
+        IpLookupSettings::default().missing_data_policy()
+    }
+}
+
+/// Toggles for groups of endpoints; disabled groups are not routed at all
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct FeatureSettings {
+    pub geo_lookup: bool,
+    pub asn_lookup: bool,
+    pub range_queries: bool,
+    pub threat_score: bool,
+    pub admin: bool,
+}
+
+impl Default for FeatureSettings {
+    fn default() -> Self {
+        Self {
+            geo_lookup: true,
+            asn_lookup: true,
+            range_queries: true,
+            threat_score: true,
+            // Refused on a non-loopback listener, so it must be opted into
+            admin: false,
+        }
+    }
+}
+
+impl FeatureSettings {
+    fn flags(&self) -> [(&'static str, bool); 5] {
+        [
+            ("geo_lookup", self.geo_lookup),
+            ("asn_lookup", self.asn_lookup),
+            ("range_queries", self.range_queries),
+            ("threat_score", self.threat_score),
+            ("admin", self.admin),
+        ]
+    }
+
+    /// Names of the enabled features
+    pub fn enabled(&self) -> Vec<&'static str> {
+        self.flags().into_iter().filter(|(_, on)| *on).map(|(name, _)| name).collect()
+    }
+
+    /// Names of the disabled features
+    pub fn disabled(&self) -> Vec<&'static str> {
+        self.flags().into_iter().filter(|(_, on)| !*on).map(|(name, _)| name).collect()
+    }
+}
+
+/// Per-tenant overrides of the scoring weights and response action
+/// thresholds; unset fields keep the service-wide values
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct ProfileSettings {
+    pub vpn_weight: Option<f32>,
+    pub proxy_weight: Option<f32>,
+    pub tor_weight: Option<f32>,
+    pub anonymous_proxy_weight: Option<f32>,
+    pub hosting_provider_weight: Option<f32>,
+    pub datacenter_weight: Option<f32>,
+    pub monitor_threshold: Option<u8>,
+    pub challenge_threshold: Option<u8>,
+    pub redirect_threshold: Option<u8>,
+    pub block_immediate: Option<Vec<ThreatType>>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct CacheWarmingSettings {
+    /// Newline-delimited IP list to look up at startup; warming is off when unset
+    pub file: Option<PathBuf>,
+    /// Maximum warming lookups per second
+    pub rate_per_sec: u32,
+    /// Maximum warming lookups in flight
+    pub concurrency: usize,
+}
+
+impl Default for CacheWarmingSettings {
+    fn default() -> Self {
+        Self {
+            file: None,
+            rate_per_sec: 1000,
+            concurrency: 16,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct TelemetrySettings {
+    /// OTLP gRPC collector endpoint (e.g. `http://localhost:4317`); spans are
+    /// only exported when set and the `otel` feature is enabled
+    pub otlp_endpoint: Option<String>,
+    /// `service.name` reported on exported spans
+    pub service_name: String,
+    /// `EnvFilter` directives (e.g. `geolocation=debug,tower_http=info`);
+    /// unset uses `RUST_LOG`. Applied again on config reload.
+    pub log_filter: Option<String>,
+    /// How client IPs appear in logs and spans
+    pub log_ip_redaction: IpRedaction,
+    /// HMAC key for `hash` redaction; unset uses a random per-process key
+    pub log_ip_hash_key: Option<String>,
+    /// Count served lookups answered with `block` in the `blocked_total`
+    /// metric, by country and reason
+    pub blocked_metric: bool,
+}
+
+impl Default for TelemetrySettings {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: None,
+            service_name: "infralock".to_string(),
+            log_filter: None,
+            log_ip_redaction: IpRedaction::None,
+            log_ip_hash_key: None,
+            blocked_metric: true,
+        }
+    }
+}
+
+/// Where per-API-key usage counts are flushed
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UsageSinkKind {
+    /// JSONL files under `usage.dir`
+    File,
+    /// `POST /internal/usage` on the web API
+    WebApi,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct UsageSettings {
+    /// Length of a usage window; each flush closes one
+    pub flush_interval_secs: u64,
+    pub sink: UsageSinkKind,
+    /// Directory for the `file` sink
+    pub dir: PathBuf,
+    /// Undelivered windows kept for retry; the oldest is dropped beyond this
+    pub max_pending_reports: usize,
+}
+
+impl Default for UsageSettings {
+    fn default() -> Self {
+        Self {
+            flush_interval_secs: 300,
+            sink: UsageSinkKind::File,
+            dir: DataLayout::default().usage_dir(),
+            // A day of windows at the default interval
+            max_pending_reports: 288,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct DecisionLogSettings {
+    /// Share of allow and monitor decisions logged (0.0-1.0); block,
+    /// redirect and challenge decisions are always logged
+    pub sample_rate: f64,
+}
+
+impl Default for DecisionLogSettings {
+    fn default() -> Self {
+        Self { sample_rate: 0.01 }
+    }
+}
+
+/// Challenge tokens for the `challenge` action; off until `secret` is set
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct ChallengeSettings {
+    /// HMAC key signing challenge and clearance tokens
+    pub secret: Option<String>,
+    /// How long a challenge token can be redeemed
+    pub ttl_secs: u64,
+    /// How long a clearance downgrades `challenge` to `allow`
+    pub clearance_ttl_secs: u64,
+    /// Leading zero bits a proof-of-work solution needs; 0 accepts the
+    /// token echoed back
+    pub difficulty: u8,
+}
+
+impl std::fmt::Debug for ChallengeSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChallengeSettings")
+            .field("secret", &self.secret.as_ref().map(|_| "***"))
+            .field("ttl_secs", &self.ttl_secs)
+            .field("clearance_ttl_secs", &self.clearance_ttl_secs)
+            .field("difficulty", &self.difficulty)
+            .finish()
+    }
+}
+
+impl Default for ChallengeSettings {
+    fn default() -> Self {
+        Self {
+            secret: None,
+            ttl_secs: 300,
+            clearance_ttl_secs: 3600,
+            difficulty: 16,
+        }
+    }
+}
+
+/// Applied to every outbound HTTP client: feed downloads, the background
+/// updater and the web API client
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct OutboundHttpSettings {
+    /// Proxy for all outbound requests (e.g. `http://proxy.corp:3128`); unset connects directly
+    pub proxy_url: Option<String>,
+    /// Basic auth for the proxy
+    pub proxy_username: Option<String>,
+    pub proxy_password: Option<String>,
+    /// Comma-separated hosts reached without the proxy (e.g. `localhost,.internal`)
+    pub no_proxy: Option<String>,
+    pub user_agent: String,
+    pub connect_timeout_secs: u64,
+    /// Limit on a whole request, from sending it to the end of the body
+    pub read_timeout_secs: u64,
+    /// Extra headers for every request; `_` in a name is sent as `-`
+    pub headers: HashMap<String, String>,
+    /// Oldest TLS version accepted in handshakes: `1.0`, `1.1` or `1.2`
+    pub min_tls_version: String,
+    /// Comma-separated SHA-256 fingerprints (hex, `:` optional) of the leaf
+    /// certificates servers may present; unset accepts any trusted certificate
+    pub pinned_cert_sha256: Option<String>,
+}
+
+/// Proxy credentials are left out, so settings can be logged
+impl std::fmt::Debug for OutboundHttpSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OutboundHttpSettings")
+            .field("proxy_url", &self.proxy_url.as_deref().map(redact_credentials))
+            .field("proxy_username", &self.proxy_username)
+            .field("proxy_password", &self.proxy_password.as_ref().map(|_| "***"))
+            .field("no_proxy", &self.no_proxy)
+            .field("user_agent", &self.user_agent)
+            .field("connect_timeout_secs", &self.connect_timeout_secs)
+            .field("read_timeout_secs", &self.read_timeout_secs)
+            .field("headers", &self.headers)
+            .field("min_tls_version", &self.min_tls_version)
+            .field("pinned_cert_sha256", &self.pinned_cert_sha256)
+            .finish()
+    }
+}
+
+impl Default for OutboundHttpSettings {
+    fn default() -> Self {
+        Self {
+            proxy_url: None,
+            proxy_username: None,
+            proxy_password: None,
+            no_proxy: None,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            connect_timeout_secs: 10,
+            read_timeout_secs: 30,
+            headers: HashMap::new(),
+            min_tls_version: "1.2".to_string(),
+            pinned_cert_sha256: None,
+        }
+    }
+}
+
+const DEFAULT_USER_AGENT: &str = concat!("infralock/", env!("CARGO_PKG_VERSION"));
+
+/// A Redis cache shared by every replica, consulted after the in-process
+/// lookup cache; off until `redis_url` is set
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct CacheSettings {
+    /// e.g. `redis://:password@redis:6379/0`
+    pub redis_url: Option<String>,
+    /// How long a lookup stays in Redis
+    pub redis_ttl_secs: u64,
+    /// Longest a Redis command may take before the lookup carries on without it
+    pub redis_timeout_ms: u64,
+    /// Prepended to every key and channel, so deployments can share a server
+    pub redis_key_prefix: String,
+}
+
+/// The Redis password is left out, so settings can be logged
+impl std::fmt::Debug for CacheSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CacheSettings")
+            .field("redis_url", &self.redis_url.as_deref().map(redact_credentials))
+            .field("redis_ttl_secs", &self.redis_ttl_secs)
+            .field("redis_timeout_ms", &self.redis_timeout_ms)
+            .field("redis_key_prefix", &self.redis_key_prefix)
+            .finish()
+    }
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        Self {
+            redis_url: None,
+            redis_ttl_secs: 3600,
+            redis_timeout_ms: 50,
+            redis_key_prefix: "infralock".to_string(),
+        }
+    }
+}
+
+/// A remote list the background updater mirrors to a local file
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct UpdaterSourceSettings {
+    pub enabled: bool,
+    pub url: String,
+    pub path: PathBuf,
+}
+
+impl UpdaterSourceSettings {
+    fn new(url: &str, path: PathBuf) -> Self {
+        Self {
+            enabled: true,
+            url: url.to_string(),
+            path,
+        }
+    }
+}
+
+/// A source as configured, before the unset fields are filled in. Each
+/// source has its own defaults, so they cannot come from a `Default` impl.
+#[derive(Deserialize)]
+struct PartialUpdaterSource {
+    enabled: Option<bool>,
+    url: Option<String>,
+    path: Option<PathBuf>,
+}
+
+/// A list given as a TOML array or, from an environment variable, as a
+/// comma-separated string
+fn comma_separated<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum List {
+        Items(Vec<String>),
+        Joined(String),
+    }
+
+    Ok(match List::deserialize(deserializer)? {
+        List::Items(items) => items,
+        List::Joined(joined) => joined
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(str::to_string)
+            .collect(),
+    })
+}
+
+/// `host:port`, bracketing IPv6 literals
+fn join_host_port(host: &str, port: u16) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+fn updater_source<'de, D>(deserializer: D, default: UpdaterSourceSettings) -> Result<UpdaterSourceSettings, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let partial = PartialUpdaterSource::deserialize(deserializer)?;
+    Ok(UpdaterSourceSettings {
+        enabled: partial.enabled.unwrap_or(default.enabled),
+        url: partial.url.unwrap_or(default.url),
+        path: partial.path.unwrap_or(default.path),
+    })
+}
+
+fn vpn_source<'de, D: Deserializer<'de>>(deserializer: D) -> Result<UpdaterSourceSettings, D::Error> {
+    updater_source(deserializer, BackgroundUpdaterSettings::default().vpn)
+}
+
+fn http_proxy_source<'de, D: Deserializer<'de>>(deserializer: D) -> Result<UpdaterSourceSettings, D::Error> {
+    updater_source(deserializer, BackgroundUpdaterSettings::default().http_proxy)
+}
+
+fn socks4_proxy_source<'de, D: Deserializer<'de>>(deserializer: D) -> Result<UpdaterSourceSettings, D::Error> {
+    updater_source(deserializer, BackgroundUpdaterSettings::default().socks4_proxy)
+}
+
+fn socks5_proxy_source<'de, D: Deserializer<'de>>(deserializer: D) -> Result<UpdaterSourceSettings, D::Error> {
+    updater_source(deserializer, BackgroundUpdaterSettings::default().socks5_proxy)
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct BackgroundUpdaterSettings {
+    /// Turns off every source
+    pub enabled: bool,
+    /// How often the sources are checked for changes
+    pub interval_secs: u64,
+    #[serde(deserialize_with = "vpn_source")]
+    pub vpn: UpdaterSourceSettings,
+    #[serde(deserialize_with = "http_proxy_source")]
+    pub http_proxy: UpdaterSourceSettings,
+    #[serde(deserialize_with = "socks4_proxy_source")]
+    pub socks4_proxy: UpdaterSourceSettings,
+    #[serde(deserialize_with = "socks5_proxy_source")]
+    pub socks5_proxy: UpdaterSourceSettings,
+}
+
+impl Default for BackgroundUpdaterSettings {
+    fn default() -> Self {
+        let layout = DataLayout::default();
+        Self {
+            enabled: true,
+            interval_secs: 86400,
+            vpn: UpdaterSourceSettings::new(VPN_LIST_URL, layout.vpn_list()),
+            http_proxy: UpdaterSourceSettings::new(HTTP_PROXY_LIST_URL, layout.http_proxy_list()),
+            socks4_proxy: UpdaterSourceSettings::new(SOCKS4_PROXY_LIST_URL, layout.socks4_proxy_list()),
+            socks5_proxy: UpdaterSourceSettings::new(SOCKS5_PROXY_LIST_URL, layout.socks5_proxy_list()),
+        }
+    }
+}
+
+impl BackgroundUpdaterSettings {
+    /// Sources by config key, in the order they are checked
+    pub fn sources(&self) -> [(&'static str, &UpdaterSourceSettings); 4] {
+        [
+            ("vpn", &self.vpn),
+            ("http_proxy", &self.http_proxy),
+            ("socks4_proxy", &self.socks4_proxy),
+            ("socks5_proxy", &self.socks5_proxy),
+        ]
+    }
+
+    /// The updater configuration, or `None` when nothing is to be updated
+    pub fn updater_config(&self) -> Option<BackgroundUpdaterConfig> {
+        let sources: Vec<UpdateSource> = self
+            .sources()
+            .into_iter()
+            .filter(|(_, source)| source.enabled)
+            .map(|(_, source)| UpdateSource {
+                url: source.url.clone(),
+                path: source.path.to_string_lossy().into_owned(),
+            })
+            .collect();
+        if !self.enabled || sources.is_empty() {
+            return None;
+        }
+        Some(BackgroundUpdaterConfig {
+            sources,
+            interval_secs: self.interval_secs,
+        })
+    }
+}
+
+const VPN_LIST_URL: &str = "https://raw.githubusercontent.com/X4BNet/lists_vpn/refs/heads/main/output/datacenter/ipv4.txt";
+const HTTP_PROXY_LIST_URL: &str = "https://raw.githubusercontent.com/TheSpeedX/SOCKS-List/master/http.txt";
+const SOCKS4_PROXY_LIST_URL: &str = "https://raw.githubusercontent.com/TheSpeedX/SOCKS-List/master/socks4.txt";
+const SOCKS5_PROXY_LIST_URL: &str = "https://raw.githubusercontent.com/TheSpeedX/SOCKS-List/master/socks5.txt";
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
 pub struct VpnDetectorSettings {
     pub db_path: PathBuf,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+impl Default for VpnDetectorSettings {
+    fn default() -> Self {
+        Self {
+            db_path: DataLayout::default().vpn_list(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
 pub struct ProxyDetectorSettings {
     pub http_db_path: PathBuf,
     pub socks4_db_path: PathBuf,
     pub socks5_db_path: PathBuf,
 }
 
-#[derive(Debug, Deserialize, Clone)]
-pub struct TorDetectorSettings {
-    pub db_path: PathBuf,
-}
-
-impl Default for Settings {
+impl Default for ProxyDetectorSettings {
     fn default() -> Self {
-        Settings {
-            server: ServerSettings {
-                host: "0.0.0.0".to_string(),
-                port: 6000,
-            },
-            maxmind: MaxmindSettings {
-                db_path: PathBuf::from("data/maxmind/GeoLite2-City.mmdb"),
-                asn_db_path: PathBuf::from("data/maxmind/GeoLite2-ASN.mmdb"),
-            },
-            vpn_detector: VpnDetectorSettings {
-                db_path: PathBuf::from("data/vpns/ipv4.txt"),
-            },
-            proxy_detector: ProxyDetectorSettings {
-                http_db_path: PathBuf::from("data/proxies/http.txt"),
-                socks4_db_path: PathBuf::from("data/proxies/socks4.txt"),
-                socks5_db_path: PathBuf::from("data/proxies/socks5.txt"),
-            },
-            tor_detector: TorDetectorSettings {
-                db_path: PathBuf::from("data/tor/exit-addresses.txt"),
-            },
+        Self {
+            http_db_path: DataLayout::default().http_proxy_list(),
+            socks4_db_path: DataLayout::default().socks4_proxy_list(),
+            socks5_db_path: DataLayout::default().socks5_proxy_list(),
         }
     }
 }
 
+/// Directory holding the optional config files
+const CONFIG_DIR: &str = "config";
+/// Selects `config/{name}.toml`, e.g. `production`
+const CONFIG_ENV_VAR: &str = "INFRALOCK_ENV";
+
 impl Settings {
-    pub fn new() -> Result<Self, config::ConfigError> {
-        let settings = config::Config::builder()
-            // Set default values that will be used if environment variables are not set
-            .set_default("server.host", "0.0.0.0")?
-            .set_default("server.port", 6000)?
-            .set_default("maxmind.db_path", "data/maxmind/GeoLite2-City.mmdb")?
-            .set_default("maxmind.asn_db_path", "data/maxmind/GeoLite2-ASN.mmdb")?
-            .set_default("vpn_detector.db_path", "data/vpns/ipv4.txt")?
-            .set_default("proxy_detector.http_db_path", "data/proxies/http.txt")?
-            .set_default("proxy_detector.socks4_db_path", "data/proxies/socks4.txt")?
-            .set_default("proxy_detector.socks5_db_path", "data/proxies/socks5.txt")?
-            .set_default("tor_detector.db_path", "data/tor/exit-addresses.txt")?
-            .add_source(
-                config::Environment::with_prefix("GEO")
-                    .prefix_separator("__")
-                    .separator("__"),
-            )
-            .build()?;
-
-        settings.try_deserialize()
-    }
-
-    pub fn server_addr(&self) -> SocketAddr {
-        format!("{}:{}", self.server.host, self.server.port)
-            .parse()
-            .expect("Failed to parse server address")
+    /// Loads settings from, in increasing precedence: the built-in defaults,
+    /// `config/default.toml`, `config/{INFRALOCK_ENV}.toml` and `GEO__*`
+    /// environment variables.
+    ///
+    /// `default.toml` is optional. The environment's file is required once
+    /// `INFRALOCK_ENV` is set, so a misspelt name fails instead of silently
+    /// running on defaults.
+    pub fn layered() -> Result<Self, config::ConfigError> {
+        let env = std::env::var(CONFIG_ENV_VAR).ok().filter(|env| !env.is_empty());
+        Self::from_sources(
+            Path::new(CONFIG_DIR),
+            env.as_deref(),
+            config::Environment::with_prefix("GEO")
+                .prefix_separator("__")
+                .separator("__"),
+        )
+    }
+
+    /// Every field and section has a `#[serde(default)]`, so each layer
+    /// only needs the keys it changes
+    fn from_sources(
+        dir: &Path,
+        env: Option<&str>,
+        environment: config::Environment,
+    ) -> Result<Self, config::ConfigError> {
+        let mut builder = config::Config::builder().add_source(
+            config::File::from(dir.join("default.toml"))
+                .format(config::FileFormat::Toml)
+                .required(false),
+        );
+        if let Some(env) = env {
+            builder = builder.add_source(
+                config::File::from(dir.join(format!("{}.toml", env))).format(config::FileFormat::Toml),
+            );
+        }
+
+        let mut settings: Self = builder.add_source(environment).build()?.try_deserialize()?;
+        settings.rebase_data_paths();
+        Ok(settings)
+    }
+
+    /// The data tree under `data_root`
+    pub fn data_layout(&self) -> DataLayout {
+        DataLayout::new(&self.data_root)
+    }
+
+    /// Move every path still at its default under the default root to the
+    /// same place under `data_root`; paths set explicitly are left alone
+    fn rebase_data_paths(&mut self) {
+        let default = DataLayout::default();
+        let layout = self.data_layout();
+        if layout == default {
+            return;
+        }
+        let rebase = |path: &mut PathBuf, place: fn(&DataLayout) -> PathBuf| {
+            if *path == place(&default) {
+                *path = place(&layout);
+            }
+        };
+        rebase(&mut self.maxmind.db_path, DataLayout::maxmind_city_db);
+        rebase(&mut self.maxmind.asn_db_path, DataLayout::maxmind_asn_db);
+        rebase(&mut self.geo.ip2location_db_path, DataLayout::ip2location_db);
+        rebase(&mut self.vpn_detector.db_path, DataLayout::vpn_list);
+        rebase(&mut self.proxy_detector.http_db_path, DataLayout::http_proxy_list);
+        rebase(&mut self.proxy_detector.socks4_db_path, DataLayout::socks4_proxy_list);
+        rebase(&mut self.proxy_detector.socks5_db_path, DataLayout::socks5_proxy_list);
+        rebase(&mut self.ip_lookup.overrides_file, DataLayout::overrides_file);
+        rebase(&mut self.usage.dir, DataLayout::usage_dir);
+        rebase(&mut self.background_updater.vpn.path, DataLayout::vpn_list);
+        rebase(&mut self.background_updater.http_proxy.path, DataLayout::http_proxy_list);
+        rebase(&mut self.background_updater.socks4_proxy.path, DataLayout::socks4_proxy_list);
+        rebase(&mut self.background_updater.socks5_proxy.path, DataLayout::socks5_proxy_list);
     }
 
     pub fn resolve_db_path(&self) -> std::io::Result<PathBuf> {
@@ -112,15 +964,6 @@ impl Settings {
         }
     }
 
-    pub fn resolve_tor_detector_db_path(&self) -> std::io::Result<PathBuf> {
-        if self.tor_detector.db_path.is_absolute() {
-            Ok(self.tor_detector.db_path.clone())
-        } else {
-            let base_path = std::env::current_dir()?;
-            Ok(base_path.join(&self.tor_detector.db_path))
-        }
-    }
-
     pub fn resolve_proxy_detector_db_paths(&self) -> std::io::Result<(PathBuf, PathBuf, PathBuf)> {
         let http_db_path = if self.proxy_detector.http_db_path.is_absolute() {
             self.proxy_detector.http_db_path.clone()
@@ -154,4 +997,173 @@ impl Settings {
             Ok(base_path.join(&self.maxmind.asn_db_path))
         }
     }
-}
\ No newline at end of file
+
+    pub fn resolve_ip2location_db_path(&self) -> std::io::Result<PathBuf> {
+        if self.geo.ip2location_db_path.is_absolute() {
+            Ok(self.geo.ip2location_db_path.clone())
+        } else {
+            let base_path = std::env::current_dir()?;
+            Ok(base_path.join(&self.geo.ip2location_db_path))
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn environment(vars: &[(&str, &str)]) -> config::Environment {
+        let vars = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        config::Environment::with_prefix("GEO")
+            .prefix_separator("__")
+            .separator("__")
+            .source(Some(vars))
+    }
+
+    fn from_env(vars: &[(&str, &str)]) -> Settings {
+        Settings::from_sources(Path::new("no-such-config-dir"), None, environment(vars)).unwrap()
+    }
+
+    #[test]
+    fn test_empty_environment_is_default() {
+        assert_eq!(from_env(&[]), Settings::default());
+    }
+
+    #[test]
+    fn test_partial_environment_keeps_other_defaults() {
+        let settings = from_env(&[
+            ("GEO__SERVER__PORT", "7000"),
+            ("GEO__SCORING__TOR_WEIGHT", "0.5"),
+            ("GEO__IP_LOOKUP__CATEGORY_FALLBACK", "clean"),
+            ("GEO__OUTBOUND_HTTP__HEADERS__X_TEAM", "security"),
+            ("GEO__RESPONSE_ACTION__MONITOR_MODE", "true"),
+        ]);
+
+        let mut expected = Settings::default();
+        expected.server.port = 7000;
+        expected.scoring.tor_weight = 0.5;
+        expected.ip_lookup.category_fallback = Some("clean".to_string());
+        expected.outbound_http.headers.insert("x_team".to_string(), "security".to_string());
+        expected.response_action.monitor_mode = true;
+        assert_eq!(settings, expected);
+    }
+
+    #[test]
+    fn test_files_then_environment_precedence() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("default.toml"),
+            "[server]\nport = 7000\nhost = \"127.0.0.1\"\n\n[scoring]\nvpn_weight = 0.3\n\n[background_updater.vpn]\nenabled = false\n",
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("production.toml"), "[server]\nport = 8000\n").unwrap();
+
+        let load = |env: Option<&str>, vars: &[(&str, &str)]| {
+            Settings::from_sources(dir.path(), env, environment(vars)).unwrap()
+        };
+
+        let settings = load(None, &[]);
+        assert_eq!(settings.server.port, 7000);
+        assert_eq!(settings.server.host, "127.0.0.1");
+        assert_eq!(settings.scoring.vpn_weight, 0.3);
+        // Unset keys in a configured section keep their defaults
+        assert_eq!(settings.server.max_concurrent_requests, 1024);
+        assert_eq!(settings.scoring.tor_weight, 0.9);
+        assert!(!settings.background_updater.vpn.enabled);
+        assert_eq!(settings.background_updater.vpn.url, VPN_LIST_URL);
+
+        // The environment's file overrides `default.toml`...
+        let settings = load(Some("production"), &[]);
+        assert_eq!(settings.server.port, 8000);
+        assert_eq!(settings.server.host, "127.0.0.1");
+
+        // ...and variables override both
+        let settings = load(Some("production"), &[("GEO__SERVER__PORT", "9000")]);
+        assert_eq!(settings.server.port, 9000);
+        assert_eq!(settings.scoring.vpn_weight, 0.3);
+
+        // A selected environment must have a file
+        assert!(Settings::from_sources(dir.path(), Some("staging"), environment(&[])).is_err());
+    }
+
+    #[test]
+    fn test_listen_addresses_from_file_or_environment() {
+        let settings = Settings::default();
+        assert_eq!(settings.server.listen_addrs(), vec!["0.0.0.0:6000"]);
+        let settings = from_env(&[("GEO__SERVER__HOST", "::"), ("GEO__SERVER__PORT", "3000")]);
+        assert_eq!(settings.server.listen_addrs(), vec!["[::]:3000"]);
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("default.toml"),
+            "[server]\nlisten = [\"0.0.0.0:3000\", \"[::]:3000\"]\n",
+        )
+        .unwrap();
+        let settings = Settings::from_sources(dir.path(), None, environment(&[])).unwrap();
+        assert_eq!(settings.server.listen_addrs(), vec!["0.0.0.0:3000", "[::]:3000"]);
+
+        let settings = from_env(&[("GEO__SERVER__LISTEN", "127.0.0.1:3000, localhost:3001,")]);
+        assert_eq!(settings.server.listen_addrs(), vec!["127.0.0.1:3000", "localhost:3001"]);
+    }
+
+    #[test]
+    fn test_invalid_values_are_rejected() {
+        let result = Settings::from_sources(
+            Path::new("no-such-config-dir"),
+            None,
+            environment(&[("GEO__SERVER__PORT", "not-a-port")]),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_background_updater_defaults_match_default_settings() {
+        let settings = from_env(&[]);
+        assert_eq!(settings.background_updater, Settings::default().background_updater);
+
+        let config = settings.background_updater.updater_config().unwrap();
+        assert_eq!(config.interval_secs, 86400);
+        let paths: Vec<&str> = config.sources.iter().map(|s| s.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec!["data/vpns/ipv4.txt", "data/proxies/http.txt", "data/proxies/socks4.txt", "data/proxies/socks5.txt"]
+        );
+    }
+
+    #[test]
+    fn test_data_root_moves_only_default_paths() {
+        let settings = from_env(&[
+            ("GEO__DATA_ROOT", "/var/lib/infralock"),
+            ("GEO__PROXY_DETECTOR__HTTP_DB_PATH", "/srv/lists/http.txt"),
+        ]);
+        assert_eq!(settings.maxmind.db_path, PathBuf::from("/var/lib/infralock/maxmind/GeoLite2-City.mmdb"));
+        assert_eq!(settings.vpn_detector.db_path, PathBuf::from("/var/lib/infralock/vpns/ipv4.txt"));
+        assert_eq!(settings.background_updater.vpn.path, PathBuf::from("/var/lib/infralock/vpns/ipv4.txt"));
+        assert_eq!(settings.usage.dir, PathBuf::from("/var/lib/infralock/usage"));
+        assert_eq!(settings.proxy_detector.http_db_path, PathBuf::from("/srv/lists/http.txt"));
+        assert_eq!(from_env(&[]).maxmind, Settings::default().maxmind);
+    }
+
+    #[test]
+    fn test_background_updater_from_env() {
+        let settings = from_env(&[
+            ("GEO__BACKGROUND_UPDATER__INTERVAL_SECS", "3600"),
+            ("GEO__BACKGROUND_UPDATER__VPN__URL", "https://mirror.example.com/vpn.txt"),
+            ("GEO__BACKGROUND_UPDATER__VPN__PATH", "/srv/lists/vpn.txt"),
+            ("GEO__BACKGROUND_UPDATER__SOCKS4_PROXY__ENABLED", "false"),
+        ]);
+        let config = settings.background_updater.updater_config().unwrap();
+        assert_eq!(config.interval_secs, 3600);
+        assert_eq!(
+            config.sources[0],
+            UpdateSource {
+                url: "https://mirror.example.com/vpn.txt".to_string(),
+                path: "/srv/lists/vpn.txt".to_string(),
+            }
+        );
+        let urls: Vec<&str> = config.sources.iter().map(|s| s.url.as_str()).collect();
+        assert_eq!(urls[1..], [HTTP_PROXY_LIST_URL, SOCKS5_PROXY_LIST_URL]);
+
+        let settings = from_env(&[("GEO__BACKGROUND_UPDATER__ENABLED", "false")]);
+        assert_eq!(settings.background_updater.updater_config(), None);
+    }
+}
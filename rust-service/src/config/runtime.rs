@@ -0,0 +1,42 @@
+//! Settings that can change while the service runs.
+//!
+//! Handlers read these through [`SharedRuntimeConfig`] on every request
+//! instead of copying them at startup, so a config reload takes effect on
+//! the next request.
+
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+
+use super::Settings;
+use crate::models::threat_score::ThreatScoringConfig;
+use crate::services::profiles::ScoringProfiles;
+use crate::services::response_action::ResponseActionConfig;
+
+/// The currently applied [`RuntimeConfig`]
+pub type SharedRuntimeConfig = Arc<ArcSwap<RuntimeConfig>>;
+
+#[derive(Debug, Clone, Default)]
+pub struct RuntimeConfig {
+    pub scoring_config: ThreatScoringConfig,
+    pub response_action_config: ResponseActionConfig,
+    /// Per-role overrides of the two configs above
+    pub profiles: ScoringProfiles,
+    /// Reloads applied since startup; 0 is the startup config
+    pub generation: u64,
+}
+
+impl RuntimeConfig {
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            scoring_config: (&settings.scoring).into(),
+            response_action_config: settings.response_action.clone(),
+            profiles: ScoringProfiles::from_settings(settings),
+            generation: 0,
+        }
+    }
+
+    pub fn shared(self) -> SharedRuntimeConfig {
+        Arc::new(ArcSwap::from_pointee(self))
+    }
+}
@@ -6,18 +6,29 @@ use axum::{
 };
 use maxminddb::MaxMindDbError;
 use std::fmt::{self, Display};
-use crate::errors::validation::IpValidationError;
+use crate::errors::validation::{CidrValidationError, IpValidationError};
+use crate::geo::GeoProviderError;
+use crate::ip_lookup::types::IpRangeError;
 use sqlx::Error as SqlxError;
 
 #[derive(Debug)]
 pub enum AppError {
     ValidationError(IpValidationError),
+    /// A range endpoint was given a malformed or too-broad CIDR
+    InvalidRange(CidrValidationError),
     DatabaseError(SqlxError),
     MaxMindDbError(MaxMindDbError),
+    GeoProviderError(GeoProviderError),
+    IpRangeError(IpRangeError),
     ConfigError(config::ConfigError),
     AddrParseError(std::net::AddrParseError),
     IoError(std::io::Error),
     NotFound(String),
+    BadRequest(String),
+    /// The caller is authenticated but lacks the required role
+    Forbidden(String),
+    /// Shed because the concurrency limit is reached
+    Overloaded,
     InternalServerError,
 }
 
@@ -28,31 +39,97 @@ impl Display for AppError {
         match self {
             AppError::DatabaseError(e) => write!(f, "Database error: {}", e),
             AppError::MaxMindDbError(e) => write!(f, "MaxMind DB error: {}", e),
+            AppError::GeoProviderError(e) => write!(f, "Geo provider error: {}", e),
+            AppError::IpRangeError(e) => write!(f, "IP range error: {}", e),
             AppError::ConfigError(e) => write!(f, "Configuration error: {}", e),
             AppError::AddrParseError(e) => write!(f, "Address parse error: {}", e),
             AppError::IoError(e) => write!(f, "I/O error: {}", e),
             AppError::NotFound(msg) => write!(f, "Not found: {}", msg),
+            AppError::BadRequest(msg) => write!(f, "Bad request: {}", msg),
+            AppError::Forbidden(msg) => write!(f, "Forbidden: {}", msg),
             AppError::ValidationError(e) => write!(f, "Validation error: {}", e),
+            AppError::InvalidRange(e) => write!(f, "Invalid range: {}", e),
+            AppError::Overloaded => write!(f, "Service overloaded"),
             AppError::InternalServerError => write!(f, "Internal server error"),
         }
     }
 }
 
-impl IntoResponse for AppError {
-    fn into_response(self) -> Response {
-        let (status, error_message) = match self {
+/// Problem details attached to an error response as an extension, so the
+/// problem+json middleware can rewrite the body when the client asks for it
+#[derive(Debug, Clone)]
+pub struct Problem {
+    /// Last segment of the problem `type` URI, e.g. `invalid-ip`
+    pub slug: &'static str,
+    pub title: &'static str,
+    pub detail: String,
+}
+
+impl AppError {
+    /// Status code and message for the error body
+    fn status_and_message(self) -> (StatusCode, String) {
+        match self {
             AppError::DatabaseError(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
             AppError::MaxMindDbError(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+            AppError::GeoProviderError(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+            AppError::IpRangeError(e) => {
+                let status = match e {
+                    IpRangeError::SnapshotNotFound(_) => StatusCode::NOT_FOUND,
+                    IpRangeError::ChecksumMismatch(_) => StatusCode::CONFLICT,
+                    _ => StatusCode::INTERNAL_SERVER_ERROR,
+                };
+                (status, e.to_string())
+            }
             AppError::ConfigError(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
             AppError::AddrParseError(e) => (StatusCode::BAD_REQUEST, e.to_string()),
             AppError::IoError(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
             AppError::NotFound(msg) => (StatusCode::NOT_FOUND, msg),
+            AppError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg),
+            AppError::Forbidden(msg) => (StatusCode::FORBIDDEN, msg),
             AppError::ValidationError(e) => (StatusCode::BAD_REQUEST, e.to_string()),
+            AppError::InvalidRange(e) => (StatusCode::BAD_REQUEST, e.to_string()),
+            AppError::Overloaded => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Too many concurrent requests, try again shortly".to_string(),
+            ),
             AppError::InternalServerError => (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error".to_string()),
-        };
+        }
+    }
+
+    /// Stable problem type slug and title for this error class
+    fn problem_type(&self) -> (&'static str, &'static str) {
+        match self {
+            AppError::ValidationError(_) | AppError::AddrParseError(_) => ("invalid-ip", "Invalid IP address"),
+            AppError::InvalidRange(_) => ("invalid-range", "Invalid network range"),
+            AppError::BadRequest(_) => ("bad-request", "Bad request"),
+            AppError::Forbidden(_) => ("forbidden", "Forbidden"),
+            AppError::NotFound(_) => ("not-found", "Resource not found"),
+            AppError::IpRangeError(IpRangeError::SnapshotNotFound(_)) => ("snapshot-not-found", "Snapshot not found"),
+            AppError::IpRangeError(IpRangeError::ChecksumMismatch(_)) => ("snapshot-corrupt", "Snapshot checksum mismatch"),
+            AppError::IpRangeError(_) => ("ip-range-error", "IP range data error"),
+            AppError::MaxMindDbError(_) | AppError::GeoProviderError(_) => ("geo-lookup-failed", "Geo lookup failed"),
+            AppError::DatabaseError(_) => ("database-error", "Database error"),
+            AppError::ConfigError(_) => ("configuration-error", "Configuration error"),
+            AppError::IoError(_) => ("io-error", "I/O error"),
+            AppError::Overloaded => ("overloaded", "Service overloaded"),
+            AppError::InternalServerError => ("internal-error", "Internal server error"),
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let (slug, title) = self.problem_type();
+        let (status, error_message) = self.status_and_message();
 
         let body = serde_json::json!({ "error": error_message });
-        (status, axum::Json(body)).into_response()
+        let mut response = (status, axum::Json(body)).into_response();
+        response.extensions_mut().insert(Problem {
+            slug,
+            title,
+            detail: error_message,
+        });
+        response
     }
 }
 
@@ -63,6 +140,18 @@ impl From<MaxMindDbError> for AppError {
     }
 }
 
+impl From<GeoProviderError> for AppError {
+    fn from(err: GeoProviderError) -> Self {
+        AppError::GeoProviderError(err)
+    }
+}
+
+impl From<IpRangeError> for AppError {
+    fn from(err: IpRangeError) -> Self {
+        AppError::IpRangeError(err)
+    }
+}
+
 impl From<SqlxError> for AppError {
     fn from(err: SqlxError) -> Self {
         AppError::DatabaseError(err)
@@ -103,4 +192,10 @@ impl From<IpValidationError> for AppError {
     fn from(err: IpValidationError) -> Self {
         AppError::ValidationError(err)
     }
+}
+
+impl From<CidrValidationError> for AppError {
+    fn from(err: CidrValidationError) -> Self {
+        AppError::InvalidRange(err)
+    }
 }
\ No newline at end of file
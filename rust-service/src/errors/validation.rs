@@ -1,6 +1,8 @@
 // src/validation.rs
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use axum::http::HeaderMap;
+use ipnetwork::IpNetwork;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 #[derive(Debug, Error, PartialEq)]
@@ -10,14 +12,151 @@ pub enum IpValidationError {
     
     #[error("Missing required headers: 'X-Forwarded-For' or 'X-Real-IP'")]
     MissingIpHeaders,
+
+    #[error("X-Forwarded-For has more than {0} entries")]
+    TooManyForwardedHops(usize),
     
     #[error("IP address not allowed: {0}")]
     NotAllowed(String),
 }
 
-/// Validates if the IP address is allowed for lookups
+#[derive(Debug, Error, PartialEq)]
+pub enum CidrValidationError {
+    #[error("Invalid IP address or network range format: '{0}'. Expected format: '1.2.3.4' or '1.2.3.0/24'")]
+    Invalid(String),
+
+    #[error("Network range {network} is broader than the /{min_prefix} limit; query /{min_prefix} or narrower ranges")]
+    TooBroad { network: String, min_prefix: u8 },
+
+    #[error("Network range '{input}' has an IPv4-sized prefix on an IPv6 address; use {suggestion}")]
+    MixedFamily { input: String, suggestion: String },
+
+    #[error("Network range {network} overlaps reserved address space: {reserved}")]
+    Reserved { network: String, reserved: &'static str },
+}
+
+/// Broadest prefixes the range endpoints accept, so a query cannot turn into
+/// a scan of most of the address space
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CidrLimits {
+    pub min_v4_prefix: u8,
+    pub min_v6_prefix: u8,
+    /// Accept ranges that overlap the addresses [`validate_ip`] rejects
+    pub allow_private_ranges: bool,
+}
+
+impl Default for CidrLimits {
+    fn default() -> Self {
+        Self {
+            min_v4_prefix: 8,
+            min_v6_prefix: 32,
+            allow_private_ranges: false,
+        }
+    }
+}
+
+/// The blocks [`validate_ip`] rejects, so ranges can be checked against the
+/// same addresses
+const RESERVED_RANGES: [(&str, &str); 13] = [
+    ("0.0.0.0/32", "unspecified address (0.0.0.0)"),
+    ("::/128", "unspecified address (::)"),
+    ("127.0.0.0/8", "loopback address (127.0.0.0/8)"),
+    ("::1/128", "loopback address (::1)"),
+    ("10.0.0.0/8", "private address range (10.0.0.0/8)"),
+    ("172.16.0.0/12", "private address range (172.16.0.0/12)"),
+    ("192.168.0.0/16", "private address range (192.168.0.0/16)"),
+    ("169.254.0.0/16", "IPv4 link-local address (169.254.0.0/16)"),
+    ("fe80::/10", "IPv6 link-local address (fe80::/10)"),
+    ("192.0.2.0/24", "documentation address (TEST-NET-1)"),
+    ("198.51.100.0/24", "documentation address (TEST-NET-2)"),
+    ("203.0.113.0/24", "documentation address (TEST-NET-3)"),
+    ("2001:db8::/32", "documentation address (2001:db8::/32)"),
+];
+
+/// The first reserved block that shares any address with `network`
+fn reserved_overlap(network: IpNetwork) -> Option<&'static str> {
+    RESERVED_RANGES.iter().find_map(|(range, description)| {
+        let reserved: IpNetwork = range.parse().expect("reserved ranges are valid");
+        (network.contains(reserved.network()) || reserved.contains(network.network())).then_some(*description)
+    })
+}
+
+/// Parses a range given to a range endpoint and returns its true network
+/// address, so `1.2.3.4/8` is checked as `1.0.0.0/8` everywhere.
+///
+/// Ranges within `::ffff:0:0/96` are returned as the IPv4 range they map, like
+/// [`canonical_ip`]. Rejects prefixes broader than `limits`, and IPv4-mapped
+/// IPv6 addresses with a prefix of 32 or less, which are IPv4 ranges written
+/// in the wrong family. Unless `limits` allows it, also rejects ranges that
+/// overlap reserved space, which single-IP lookups refuse.
+pub fn validate_cidr(input: &str, limits: CidrLimits) -> Result<IpNetwork, CidrValidationError> {
+    let parsed: IpNetwork = input
+        .parse()
+        .map_err(|_| CidrValidationError::Invalid(input.to_string()))?;
+    let prefix = parsed.prefix();
+
+    let (address, prefix) = match parsed {
+        IpNetwork::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(v4) if prefix >= 96 => (IpAddr::V4(v4), prefix - 96),
+            Some(v4) if prefix <= 32 => {
+                let suggestion = IpNetwork::new(v4.into(), prefix).expect("prefix is at most 32");
+                return Err(CidrValidationError::MixedFamily {
+                    input: input.to_string(),
+                    suggestion: IpNetwork::new(suggestion.network(), prefix).expect("prefix is at most 32").to_string(),
+                });
+            }
+            _ => (parsed.ip(), prefix),
+        },
+        IpNetwork::V4(_) => (parsed.ip(), prefix),
+    };
+
+    let host = IpNetwork::new(address, prefix).expect("prefix was valid for this family");
+    let network = IpNetwork::new(host.network(), prefix).expect("prefix was valid for this family");
+    let min_prefix = match network {
+        IpNetwork::V4(_) => limits.min_v4_prefix,
+        IpNetwork::V6(_) => limits.min_v6_prefix,
+    };
+    if prefix < min_prefix {
+        return Err(CidrValidationError::TooBroad {
+            network: network.to_string(),
+            min_prefix,
+        });
+    }
+    if !limits.allow_private_ranges {
+        if let Some(reserved) = reserved_overlap(network) {
+            return Err(CidrValidationError::Reserved {
+                network: network.to_string(),
+                reserved,
+            });
+        }
+    }
+    Ok(network)
+}
+
+/// The IPv4 address embedded in an IPv4-mapped (`::ffff:a.b.c.d`) or
+/// IPv4-compatible (`::a.b.c.d`) IPv6 address, so dual-stack clients get the
+/// same verdicts as over IPv4. Other addresses, including `::` and `::1`,
+/// are returned unchanged.
+pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+    let IpAddr::V6(v6) = ip else {
+        return ip;
+    };
+    if let Some(v4) = v6.to_ipv4_mapped() {
+        return IpAddr::V4(v4);
+    }
+    match v6.segments() {
+        // Compatible addresses never embed 0.0.0.0/8, which keeps `::` and `::1` as IPv6
+        [0, 0, 0, 0, 0, 0, high, _] if high >> 8 != 0 => {
+            IpAddr::V4(v6.to_ipv4().expect("the first 96 bits are zero"))
+        }
+        _ => ip,
+    }
+}
+
+/// Validates if the IP address is allowed for lookups. Embedded IPv4
+/// addresses are checked as IPv4, so `::ffff:10.0.0.1` is private.
 pub fn validate_ip(ip: IpAddr) -> Result<(), IpValidationError> {
-    match ip {
+    match canonical_ip(ip) {
         // Unspecified (0.0.0.0, ::)
         IpAddr::V4(ip) if ip.is_unspecified() => 
             Err(IpValidationError::NotAllowed("unspecified address (0.0.0.0)".into())),
@@ -77,18 +216,49 @@ fn is_documentation_ipv6(ip: &Ipv6Addr) -> bool {
     ip.segments()[0] == 0x2001 && (ip.segments()[1] & 0xffff) == 0xdb8
 }
 
-/// Extracts the client IP address from request headers
-/// Returns an error if no valid IP could be extracted from headers
-pub fn extract_client_ip(headers: &HeaderMap) -> Result<IpAddr, IpValidationError> {
+/// Where a client IP was taken from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IpSource {
+    XForwardedFor,
+    XRealIp,
+    /// The TCP peer address, used when no proxy header is present
+    ConnectInfo,
+}
+
+/// What endpoints that act on the caller's IP do when the request has no
+/// `X-Forwarded-For` or `X-Real-IP` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MissingIpPolicy {
+    /// Fail with 400, for deployments where every request passes a proxy
+    Reject,
+    /// Use the TCP peer address
+    #[default]
+    UseConnectInfo,
+    /// Let the request through unscored: every self endpoint answers with an
+    /// `allow` verdict header, the JSON ones with `204 No Content`
+    Allow,
+}
+
+/// Extracts the client IP address from request headers, along with the header it came from.
+/// Embedded IPv4 addresses are returned in their [`canonical_ip`] form.
+/// Returns an error if no valid IP could be extracted from headers, or if
+/// `X-Forwarded-For` lists more than `max_hops` entries
+pub fn extract_client_ip(headers: &HeaderMap, max_hops: usize) -> Result<(IpAddr, IpSource), IpValidationError> {
     // Try X-Forwarded-For first (comma-separated list of IPs)
     if let Some(forwarded_for) = headers.get("x-forwarded-for") {
         let forwarded_for_str = forwarded_for.to_str().map_err(|_| 
             IpValidationError::InvalidIpAddress("Invalid X-Forwarded-For header".to_string())
         )?;
+        // Stops counting one past the limit, however long the header is
+        if forwarded_for_str.split(',').take(max_hops + 1).count() > max_hops {
+            return Err(IpValidationError::TooManyForwardedHops(max_hops));
+        }
         
         if let Some(first_ip) = forwarded_for_str.split(',').next() {
             let trimmed_ip = first_ip.trim();
-            return trimmed_ip.parse().map_err(|_| 
+            return trimmed_ip.parse().map(|ip| (canonical_ip(ip), IpSource::XForwardedFor)).map_err(|_| 
                 IpValidationError::InvalidIpAddress(
                     format!("Invalid IP in X-Forwarded-For header: {}", trimmed_ip)
                 )
@@ -102,7 +272,7 @@ pub fn extract_client_ip(headers: &HeaderMap) -> Result<IpAddr, IpValidationErro
             IpValidationError::InvalidIpAddress("Invalid X-Real-IP header".to_string())
         )?;
         
-        return ip_str.parse().map_err(|_| 
+        return ip_str.parse().map(|ip| (canonical_ip(ip), IpSource::XRealIp)).map_err(|_| 
             IpValidationError::InvalidIpAddress(
                 format!("Invalid IP in X-Real-IP header: {}", ip_str)
             )
@@ -111,4 +281,153 @@ pub fn extract_client_ip(headers: &HeaderMap) -> Result<IpAddr, IpValidationErro
 
     // No valid IP found in headers
     Err(IpValidationError::MissingIpHeaders)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn canonical(ip: &str) -> String {
+        canonical_ip(ip.parse().unwrap()).to_string()
+    }
+
+    #[test]
+    fn test_canonical_ip() {
+        // IPv4-mapped and IPv4-compatible
+        assert_eq!(canonical("::ffff:8.8.8.8"), "8.8.8.8");
+        assert_eq!(canonical("::ffff:808:808"), "8.8.8.8");
+        assert_eq!(canonical("::8.8.8.8"), "8.8.8.8");
+
+        // Genuine IPv6, and the special addresses that look compatible
+        assert_eq!(canonical("2606:4700::1111"), "2606:4700::1111");
+        assert_eq!(canonical("64:ff9b::808:808"), "64:ff9b::808:808");
+        assert_eq!(canonical("::"), "::");
+        assert_eq!(canonical("::1"), "::1");
+
+        assert_eq!(canonical("8.8.8.8"), "8.8.8.8");
+    }
+
+    #[test]
+    fn test_validate_cidr_normalizes_host_bits() {
+        let limits = CidrLimits::default();
+        assert_eq!(validate_cidr("1.2.3.4/8", limits).unwrap().to_string(), "1.0.0.0/8");
+        assert_eq!(validate_cidr("8.8.8.77/24", limits).unwrap().to_string(), "8.8.8.0/24");
+        assert_eq!(validate_cidr("2606:4700:1:2::1/48", limits).unwrap().to_string(), "2606:4700:1::/48");
+        // Already-normal ranges and bare addresses are unchanged
+        assert_eq!(validate_cidr("8.8.8.0/24", limits).unwrap().to_string(), "8.8.8.0/24");
+        assert_eq!(validate_cidr("8.8.8.7", limits).unwrap().to_string(), "8.8.8.7/32");
+    }
+
+    #[test]
+    fn test_validate_cidr_rejects_broad_prefixes() {
+        let limits = CidrLimits::default();
+        assert_eq!(
+            validate_cidr("0.0.0.0/0", limits),
+            Err(CidrValidationError::TooBroad { network: "0.0.0.0/0".to_string(), min_prefix: 8 })
+        );
+        assert_eq!(
+            validate_cidr("1.2.3.4/7", limits),
+            Err(CidrValidationError::TooBroad { network: "0.0.0.0/7".to_string(), min_prefix: 8 })
+        );
+        assert_eq!(
+            validate_cidr("2606:4700::/31", limits),
+            Err(CidrValidationError::TooBroad { network: "2606:4700::/31".to_string(), min_prefix: 32 })
+        );
+        assert!(validate_cidr("2606:4700::/32", limits).is_ok());
+
+        let strict = CidrLimits { min_v4_prefix: 16, min_v6_prefix: 48, ..limits };
+        assert!(validate_cidr("10.0.0.0/12", strict).is_err());
+        assert!(validate_cidr("2001:db8::/40", strict).is_err());
+    }
+
+    #[test]
+    fn test_validate_cidr_rejects_malformed_and_mixed_input() {
+        let limits = CidrLimits::default();
+        for input in ["", "nonsense", "1.2.3.0/33", "2001:db8::/129", "1.2.3/24", "1.2.3.0/-1"] {
+            assert_eq!(validate_cidr(input, limits), Err(CidrValidationError::Invalid(input.to_string())), "{}", input);
+        }
+        assert_eq!(
+            validate_cidr("::ffff:1.2.3.4/24", limits),
+            Err(CidrValidationError::MixedFamily {
+                input: "::ffff:1.2.3.4/24".to_string(),
+                suggestion: "1.2.3.0/24".to_string(),
+            })
+        );
+        // Within the mapped block, the range is the IPv4 range it maps
+        assert_eq!(validate_cidr("::ffff:1.2.3.4/120", limits).unwrap().to_string(), "1.2.3.0/24");
+    }
+
+    #[test]
+    fn test_validate_cidr_rejects_reserved_overlap() {
+        let limits = CidrLimits::default();
+        let reserved = |input: &str| match validate_cidr(input, limits) {
+            Err(CidrValidationError::Reserved { reserved, .. }) => reserved,
+            other => panic!("{} was not rejected as reserved: {:?}", input, other),
+        };
+
+        // Ranges containing, inside, or equal to a reserved block
+        assert_eq!(reserved("0.0.0.0/8"), "unspecified address (0.0.0.0)");
+        assert_eq!(reserved("10.20.0.0/16"), "private address range (10.0.0.0/8)");
+        assert_eq!(reserved("172.0.0.0/8"), "private address range (172.16.0.0/12)");
+        assert_eq!(reserved("192.168.1.0/24"), "private address range (192.168.0.0/16)");
+        assert_eq!(reserved("203.0.113.0/24"), "documentation address (TEST-NET-3)");
+        assert_eq!(reserved("::ffff:127.0.0.1/128"), "loopback address (127.0.0.0/8)");
+        assert_eq!(reserved("::/32"), "unspecified address (::)");
+        assert_eq!(reserved("fe80::/64"), "IPv6 link-local address (fe80::/10)");
+        assert_eq!(reserved("2001:db8::/48"), "documentation address (2001:db8::/32)");
+
+        // Neighbours of reserved blocks are fine
+        for input in ["11.0.0.0/8", "172.32.0.0/16", "1.0.0.0/8", "2606:4700::/32"] {
+            assert!(validate_cidr(input, limits).is_ok(), "{}", input);
+        }
+
+        let permissive = CidrLimits { allow_private_ranges: true, ..limits };
+        assert_eq!(validate_cidr("10.20.0.0/16", permissive).unwrap().to_string(), "10.20.0.0/16");
+        assert!(validate_cidr("0.0.0.0/0", permissive).is_err());
+    }
+
+    #[test]
+    fn test_validate_ip_checks_embedded_ipv4() {
+        for ip in ["::ffff:127.0.0.1", "::ffff:10.0.0.1", "::192.168.1.1", "::ffff:203.0.113.9"] {
+            assert!(validate_ip(ip.parse().unwrap()).is_err(), "{} should be rejected", ip);
+        }
+        assert!(validate_ip("::ffff:8.8.8.8".parse().unwrap()).is_ok());
+        assert!(validate_ip("::1".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_extract_client_ip_canonicalizes() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "::ffff:8.8.8.8, 10.0.0.1".parse().unwrap());
+        assert_eq!(
+            extract_client_ip(&headers, 16).unwrap(),
+            ("8.8.8.8".parse().unwrap(), IpSource::XForwardedFor)
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-real-ip", "2606:4700::1111".parse().unwrap());
+        assert_eq!(
+            extract_client_ip(&headers, 16).unwrap(),
+            ("2606:4700::1111".parse().unwrap(), IpSource::XRealIp)
+        );
+    }
+
+    #[test]
+    fn test_extract_client_ip_caps_forwarded_hops() {
+        let forwarded_for = |hops: usize| {
+            let chain = vec!["8.8.8.8"; hops].join(", ");
+            let mut headers = HeaderMap::new();
+            headers.insert("x-forwarded-for", chain.parse().unwrap());
+            headers
+        };
+        assert!(extract_client_ip(&forwarded_for(16), 16).is_ok());
+        assert_eq!(
+            extract_client_ip(&forwarded_for(17), 16),
+            Err(IpValidationError::TooManyForwardedHops(16))
+        );
+        assert_eq!(
+            extract_client_ip(&forwarded_for(5000), 16),
+            Err(IpValidationError::TooManyForwardedHops(16))
+        );
+        assert!(extract_client_ip(&forwarded_for(2), 1).is_err());
+    }
+}
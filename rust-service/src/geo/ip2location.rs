@@ -0,0 +1,189 @@
+//! IP2Location BIN database backed geo provider.
+
+use std::net::IpAddr;
+use std::path::Path;
+
+use ip2location::{error::Error as Ip2LocationError, LocationDB, LocationRecord};
+
+use crate::geo::{GeoProvider, GeoProviderError, ProviderMetadata};
+use crate::models::location::{AsnInfo, City, Country, GeoInfo, Location};
+
+/// Geo provider backed by a single IP2Location BIN database.
+///
+/// ASN information is only available with database editions that include the
+/// `asn`/`as_name` columns; other editions return `None` for ASN lookups.
+#[derive(Debug)]
+pub struct Ip2LocationProvider {
+    db: LocationDB,
+    database: String,
+}
+
+impl Ip2LocationProvider {
+    /// Open an IP2Location BIN database from disk
+    pub fn open<P: AsRef<Path>>(db_path: P) -> Result<Self, GeoProviderError> {
+        let database = db_path
+            .as_ref()
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let db = LocationDB::from_file(db_path.as_ref())
+            .map_err(|e| GeoProviderError::Ip2Location(e.to_string()))?;
+        Ok(Self { db, database })
+    }
+
+    fn lookup(&self, ip: IpAddr) -> Result<Option<LocationRecord<'_>>, GeoProviderError> {
+        match self.db.ip_lookup(ip) {
+            Ok(record) => Ok(Some(record)),
+            Err(Ip2LocationError::RecordNotFound) => Ok(None),
+            Err(e) => Err(GeoProviderError::Ip2Location(e.to_string())),
+        }
+    }
+}
+
+/// Names are stored the same way as the MaxMind conversion: an "en" entry.
+fn english_names(name: &str) -> Option<std::collections::HashMap<String, String>> {
+    Some([("en".to_string(), name.to_string())].into_iter().collect())
+}
+
+/// IP2Location uses "-" as a placeholder for fields it has no data for.
+fn non_placeholder(value: &str) -> Option<&str> {
+    let value = value.trim();
+    (!value.is_empty() && value != "-").then_some(value)
+}
+
+fn geo_info(record: &LocationRecord<'_>) -> GeoInfo {
+    GeoInfo {
+        city: record
+            .city
+            .as_deref()
+            .and_then(non_placeholder)
+            .map(|name| City { names: english_names(name) }),
+        country: record.country.as_ref().and_then(|c| {
+            let iso_code = non_placeholder(&c.short_name).map(str::to_string);
+            non_placeholder(&c.long_name).map(|name| Country { names: english_names(name), iso_code })
+        }),
+        location: match (record.latitude, record.longitude) {
+            (None, None) => None,
+            (latitude, longitude) => Some(Location {
+                latitude: latitude.map(f64::from),
+                longitude: longitude.map(f64::from),
+            }),
+        },
+        traits: None,
+    }
+}
+
+fn asn_info(record: &LocationRecord<'_>) -> AsnInfo {
+    AsnInfo {
+        autonomous_system_number: record
+            .asn
+            .as_deref()
+            .and_then(non_placeholder)
+            .and_then(|asn| asn.trim_start_matches("AS").parse().ok()),
+        autonomous_system_organization: record
+            .as_name
+            .as_deref()
+            .and_then(non_placeholder)
+            .map(str::to_string),
+    }
+}
+
+impl GeoProvider for Ip2LocationProvider {
+    fn lookup_city(&self, ip: IpAddr) -> Result<Option<GeoInfo>, GeoProviderError> {
+        Ok(self.lookup(ip)?.as_ref().map(geo_info))
+    }
+
+    fn lookup_asn(&self, ip: IpAddr) -> Result<Option<AsnInfo>, GeoProviderError> {
+        Ok(self
+            .lookup(ip)?
+            .as_ref()
+            .map(asn_info)
+            .filter(|asn| {
+                asn.autonomous_system_number.is_some() || asn.autonomous_system_organization.is_some()
+            }))
+    }
+
+    fn metadata(&self) -> ProviderMetadata {
+        ProviderMetadata {
+            provider: "ip2location",
+            databases: vec![self.database.clone()],
+            build_epoch: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+
+    // `ip2location` doesn't export its `Country` type, so records built here
+    // carry no country.
+    fn record(city: &'static str, asn: Option<&'static str>) -> LocationRecord<'static> {
+        LocationRecord {
+            ip: "8.8.8.8".parse().unwrap(),
+            latitude: Some(37.5),
+            longitude: Some(-122.25),
+            country: None,
+            region: None,
+            city: Some(Cow::Borrowed(city)),
+            isp: None,
+            domain: None,
+            zip_code: None,
+            time_zone: None,
+            net_speed: None,
+            idd_code: None,
+            area_code: None,
+            weather_station_code: None,
+            weather_station_name: None,
+            mcc: None,
+            mnc: None,
+            mobile_brand: None,
+            elevation: None,
+            usage_type: None,
+            address_type: None,
+            category: None,
+            district: None,
+            asn: asn.map(Cow::Borrowed),
+            as_name: asn.map(|_| Cow::Borrowed("Google LLC")),
+        }
+    }
+
+    #[test]
+    fn test_record_to_geo_info() {
+        let geo = geo_info(&record("Mountain View", None));
+
+        let city = geo.city.unwrap().names.unwrap();
+        assert_eq!(city.get("en").map(String::as_str), Some("Mountain View"));
+        assert!(geo.country.is_none());
+        let location = geo.location.unwrap();
+        assert_eq!(location.latitude, Some(37.5));
+        assert_eq!(location.longitude, Some(-122.25));
+    }
+
+    #[test]
+    fn test_placeholder_fields_are_dropped() {
+        let geo = geo_info(&record("-", Some("-")));
+        assert!(geo.city.is_none());
+
+        let asn = asn_info(&record("-", Some("-")));
+        assert_eq!(asn.autonomous_system_number, None);
+    }
+
+    #[test]
+    fn test_record_to_asn_info() {
+        let asn = asn_info(&record("Mountain View", Some("15169")));
+        assert_eq!(asn.autonomous_system_number, Some(15169));
+        assert_eq!(asn.autonomous_system_organization.as_deref(), Some("Google LLC"));
+
+        let asn = asn_info(&record("Mountain View", None));
+        assert_eq!(asn.autonomous_system_number, None);
+        assert_eq!(asn.autonomous_system_organization, None);
+    }
+
+    #[test]
+    fn test_open_missing_file() {
+        let result = Ip2LocationProvider::open("does/not/exist.BIN");
+        assert!(matches!(result, Err(GeoProviderError::Ip2Location(_))));
+    }
+}
@@ -0,0 +1,282 @@
+//! MaxMind DB backed geo provider.
+//!
+//! Works with any database in the MaxMind DB format, including GeoLite2/GeoIP2
+//! and DB-IP's mmdb releases.
+//!
+//! The readers sit behind an [`ArcSwap`], so lookups never take a lock and a
+//! reload is a single pointer swap: lookups already in flight finish against
+//! the old databases, which are freed once the last of them completes.
+
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+use maxminddb::{geoip2, Reader};
+use tracing::info;
+
+use crate::geo::{GeoProvider, GeoProviderError, ProviderMetadata};
+use crate::models::location::{self, AsnInfo, GeoInfo};
+
+/// A City and ASN reader pair that is swapped as a unit
+#[derive(Debug)]
+struct Readers {
+    city: Reader<Vec<u8>>,
+    asn: Reader<Vec<u8>>,
+    is_enterprise: bool,
+}
+
+impl Readers {
+    fn new(city: Reader<Vec<u8>>, asn: Reader<Vec<u8>>) -> Self {
+        let is_enterprise = city.metadata.database_type.contains("Enterprise");
+        Self {
+            city,
+            asn,
+            is_enterprise,
+        }
+    }
+}
+
+/// Geo provider wrapping a City and an ASN MaxMind DB reader
+///
+/// When the City reader is an Enterprise database, the richer Enterprise
+/// `traits` record (including `is_hosting_provider`) is used instead.
+#[derive(Debug)]
+pub struct MaxMindProvider {
+    readers: ArcSwap<Readers>,
+    /// City and ASN database paths, when opened from disk
+    paths: Option<(PathBuf, PathBuf)>,
+}
+
+impl MaxMindProvider {
+    /// Create a provider from already-opened readers
+    pub fn new(city_reader: Reader<Vec<u8>>, asn_reader: Reader<Vec<u8>>) -> Self {
+        Self {
+            readers: ArcSwap::from_pointee(Readers::new(city_reader, asn_reader)),
+            paths: None,
+        }
+    }
+
+    /// Open the City and ASN databases from disk
+    pub fn open<P: AsRef<Path>, Q: AsRef<Path>>(db_path: P, asn_db_path: Q) -> Result<Self, GeoProviderError> {
+        let city_reader = Reader::open_readfile(&db_path)?;
+        let asn_reader = Reader::open_readfile(&asn_db_path)?;
+        Ok(Self {
+            paths: Some((db_path.as_ref().to_path_buf(), asn_db_path.as_ref().to_path_buf())),
+            ..Self::new(city_reader, asn_reader)
+        })
+    }
+
+    /// Atomically replace both readers
+    pub fn swap(&self, city_reader: Reader<Vec<u8>>, asn_reader: Reader<Vec<u8>>) {
+        self.readers.store(Arc::new(Readers::new(city_reader, asn_reader)));
+    }
+}
+
+impl GeoProvider for MaxMindProvider {
+    fn lookup_city(&self, ip: IpAddr) -> Result<Option<GeoInfo>, GeoProviderError> {
+        let readers = self.readers.load();
+        let city: Option<geoip2::City<'_>> = readers.city.lookup(ip)?;
+        let mut geo_info = city.map(location::geo_info);
+
+        if readers.is_enterprise {
+            if let Some(geo_info) = geo_info.as_mut() {
+                let enterprise: Option<geoip2::Enterprise<'_>> = readers.city.lookup(ip)?;
+                geo_info.traits = enterprise
+                    .and_then(|record| record.traits)
+                    .as_ref()
+                    .map(location::enterprise_traits);
+            }
+        }
+
+        Ok(geo_info)
+    }
+
+    fn lookup_asn(&self, ip: IpAddr) -> Result<Option<AsnInfo>, GeoProviderError> {
+        let readers = self.readers.load();
+        let asn: Option<geoip2::Asn<'_>> = readers.asn.lookup(ip)?;
+        Ok(asn.as_ref().map(location::asn_info))
+    }
+
+    fn metadata(&self) -> ProviderMetadata {
+        let readers = self.readers.load();
+        ProviderMetadata {
+            provider: "maxmind",
+            databases: vec![
+                readers.city.metadata.database_type.clone(),
+                readers.asn.metadata.database_type.clone(),
+            ],
+            build_epoch: Some(readers.city.metadata.build_epoch),
+        }
+    }
+
+    /// Re-open both databases from disk and swap them in; a provider built
+    /// from in-memory readers has nothing to reload
+    fn reload(&self) -> Result<(), GeoProviderError> {
+        let Some((db_path, asn_db_path)) = &self.paths else {
+            return Ok(());
+        };
+        // Open both before swapping so a bad file leaves the current pair in place
+        let city_reader = Reader::open_readfile(db_path)?;
+        let asn_reader = Reader::open_readfile(asn_db_path)?;
+        self.swap(city_reader, asn_reader);
+        info!(
+            db_path = %db_path.display(),
+            asn_db_path = %asn_db_path.display(),
+            "Reloaded MaxMind databases"
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::mmdb::{
+        asn_fixture, city_fixture, enterprise_fixture, FIXTURE_DE_IP, FIXTURE_US_IP,
+    };
+
+    fn provider() -> MaxMindProvider {
+        MaxMindProvider::new(city_fixture(), asn_fixture())
+    }
+
+    #[test]
+    fn test_lookup_city() {
+        let geo = provider()
+            .lookup_city(FIXTURE_US_IP.parse().unwrap())
+            .unwrap()
+            .expect("fixture IP should have city data");
+
+        let city = geo.city.unwrap().names.unwrap();
+        assert_eq!(city.get("en").map(String::as_str), Some("Mountain View"));
+        let location = geo.location.unwrap();
+        assert_eq!(location.latitude, Some(37.386));
+        assert_eq!(location.longitude, Some(-122.0838));
+    }
+
+    #[test]
+    fn test_lookup_ipv6_country_only() {
+        let geo = provider()
+            .lookup_city(FIXTURE_DE_IP.parse().unwrap())
+            .unwrap()
+            .expect("fixture IP should have country data");
+
+        assert!(geo.city.is_none());
+        let country = geo.country.unwrap().names.unwrap();
+        assert_eq!(country.get("en").map(String::as_str), Some("Germany"));
+    }
+
+    #[test]
+    fn test_lookup_city_traits() {
+        let provider = provider();
+        let geo = provider.lookup_city(FIXTURE_US_IP.parse().unwrap()).unwrap().unwrap();
+        let traits = geo.traits.expect("fixture network has traits");
+        assert_eq!(traits.is_anycast, Some(true));
+        assert_eq!(traits.is_anonymous_proxy, None);
+        assert_eq!(traits.is_hosting_provider, None);
+
+        let geo = provider.lookup_city(FIXTURE_DE_IP.parse().unwrap()).unwrap().unwrap();
+        assert!(geo.traits.is_none());
+    }
+
+    #[test]
+    fn test_lookup_enterprise_traits() {
+        let provider = MaxMindProvider::new(enterprise_fixture(), asn_fixture());
+        let geo = provider.lookup_city(FIXTURE_US_IP.parse().unwrap()).unwrap().unwrap();
+
+        let country = geo.country.unwrap().names.unwrap();
+        assert_eq!(country.get("en").map(String::as_str), Some("United States"));
+        let traits = geo.traits.unwrap();
+        assert_eq!(traits.is_hosting_provider, Some(true));
+        assert_eq!(traits.is_anonymous_proxy, Some(true));
+        assert_eq!(traits.is_anycast, Some(false));
+    }
+
+    #[test]
+    fn test_lookup_asn() {
+        let asn = provider()
+            .lookup_asn(FIXTURE_US_IP.parse().unwrap())
+            .unwrap()
+            .expect("fixture IP should have ASN data");
+
+        assert_eq!(asn.autonomous_system_number, Some(15169));
+        assert_eq!(asn.autonomous_system_organization.as_deref(), Some("GOOGLE"));
+    }
+
+    #[test]
+    fn test_lookup_missing_ip() {
+        let provider = provider();
+        let ip = "1.1.1.1".parse().unwrap();
+        assert!(provider.lookup_city(ip).unwrap().is_none());
+        assert!(provider.lookup_asn(ip).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_swap_replaces_readers() {
+        let provider = provider();
+        let ip = FIXTURE_US_IP.parse().unwrap();
+        assert!(provider.lookup_city(ip).unwrap().unwrap().traits.unwrap().is_hosting_provider.is_none());
+
+        provider.swap(enterprise_fixture(), asn_fixture());
+        let traits = provider.lookup_city(ip).unwrap().unwrap().traits.unwrap();
+        assert_eq!(traits.is_hosting_provider, Some(true));
+        assert_eq!(provider.metadata().databases[0], "GeoIP2-Enterprise");
+    }
+
+    #[test]
+    fn test_lookups_continue_during_reloads() {
+        let provider = std::sync::Arc::new(provider());
+        let ip: IpAddr = FIXTURE_US_IP.parse().unwrap();
+        let stop = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        // Every reader has a lookup in flight before the first swap
+        let started = std::sync::Arc::new(std::sync::Barrier::new(5));
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let provider = std::sync::Arc::clone(&provider);
+                let stop = std::sync::Arc::clone(&stop);
+                let started = std::sync::Arc::clone(&started);
+                std::thread::spawn(move || {
+                    let readers = provider.readers.load();
+                    started.wait();
+                    // Answered by the databases it loaded, whatever was swapped in since
+                    let city: Option<geoip2::City<'_>> = readers.city.lookup(ip).unwrap();
+                    assert!(city.is_some());
+                    drop(readers);
+                    while !stop.load(std::sync::atomic::Ordering::Relaxed) {
+                        assert!(provider.lookup_city(ip).unwrap().is_some());
+                        assert!(provider.lookup_asn(ip).unwrap().is_some());
+                    }
+                })
+            })
+            .collect();
+
+        // Every swap goes through immediately, however busy the readers are
+        started.wait();
+        for i in 0..50 {
+            let city = if i % 2 == 0 { enterprise_fixture() } else { city_fixture() };
+            provider.swap(city, asn_fixture());
+        }
+        stop.store(true, std::sync::atomic::Ordering::Relaxed);
+
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(provider.metadata().databases[0], "GeoLite2-City");
+    }
+
+    #[test]
+    fn test_reload_without_paths_is_a_no_op() {
+        let provider = provider();
+        provider.reload().unwrap();
+        assert_eq!(provider.metadata().databases[0], "GeoLite2-City");
+    }
+
+    #[test]
+    fn test_metadata() {
+        let metadata = provider().metadata();
+        assert_eq!(metadata.provider, "maxmind");
+        assert_eq!(metadata.databases, vec!["GeoLite2-City", "GeoLite2-ASN"]);
+        assert!(metadata.build_epoch.is_some());
+    }
+}
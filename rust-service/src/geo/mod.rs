@@ -0,0 +1,142 @@
+//! Geolocation provider abstraction.
+//!
+//! Lookups go through the [`GeoProvider`] trait so the service can run against
+//! MaxMind-format databases (GeoLite2, DB-IP) or IP2Location BIN files,
+//! selected via the `geo.provider` setting.
+
+pub mod ip2location;
+pub mod maxmind;
+
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::config::Settings;
+use crate::models::location::{AsnInfo, GeoInfo};
+
+pub use self::ip2location::Ip2LocationProvider;
+pub use self::maxmind::MaxMindProvider;
+
+/// Supported geolocation backends
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GeoProviderKind {
+    /// MaxMind DB format (GeoLite2/GeoIP2, DB-IP mmdb)
+    #[default]
+    MaxMind,
+    /// IP2Location BIN database
+    Ip2Location,
+}
+
+/// Errors that can occur while querying a geolocation backend
+#[derive(Debug, Error)]
+pub enum GeoProviderError {
+    #[error("MaxMind DB error: {0}")]
+    MaxMind(#[from] maxminddb::MaxMindDbError),
+
+    #[error("IP2Location error: {0}")]
+    Ip2Location(String),
+
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Describes the databases backing a provider
+#[derive(Debug, Clone, Serialize)]
+pub struct ProviderMetadata {
+    /// Short provider name (e.g. "maxmind")
+    pub provider: &'static str,
+    /// Database types or file names in use
+    pub databases: Vec<String>,
+    /// Build time of the database as a unix timestamp, if known
+    pub build_epoch: Option<u64>,
+}
+
+/// A source of geolocation and ASN information for IP addresses
+pub trait GeoProvider: Send + Sync + std::fmt::Debug {
+    /// Look up city/country/location information for an IP
+    fn lookup_city(&self, ip: IpAddr) -> Result<Option<GeoInfo>, GeoProviderError>;
+
+    /// Look up autonomous system information for an IP
+    fn lookup_asn(&self, ip: IpAddr) -> Result<Option<AsnInfo>, GeoProviderError>;
+
+    /// Describe the underlying databases
+    fn metadata(&self) -> ProviderMetadata;
+
+    /// Re-read the databases from disk without interrupting lookups
+    fn reload(&self) -> Result<(), GeoProviderError> {
+        Ok(())
+    }
+}
+
+/// Stand-in used when both geo and ASN lookups are disabled, so no database
+/// has to be present
+#[derive(Debug)]
+pub struct DisabledProvider;
+
+impl GeoProvider for DisabledProvider {
+    fn lookup_city(&self, _ip: IpAddr) -> Result<Option<GeoInfo>, GeoProviderError> {
+        Ok(None)
+    }
+
+    fn lookup_asn(&self, _ip: IpAddr) -> Result<Option<AsnInfo>, GeoProviderError> {
+        Ok(None)
+    }
+
+    fn metadata(&self) -> ProviderMetadata {
+        ProviderMetadata {
+            provider: "disabled",
+            databases: vec![],
+            build_epoch: None,
+        }
+    }
+}
+
+/// Build the provider selected by `geo.provider`
+pub fn from_settings(settings: &Settings) -> Result<Arc<dyn GeoProvider>, GeoProviderError> {
+    if !settings.features.geo_lookup && !settings.features.asn_lookup {
+        return Ok(Arc::new(DisabledProvider));
+    }
+
+    let provider: Arc<dyn GeoProvider> = match settings.geo.provider {
+        GeoProviderKind::MaxMind => {
+            let db_path = settings.resolve_db_path()?;
+            let asn_db_path = settings.resolve_asn_db_path()?;
+            Arc::new(MaxMindProvider::open(db_path, asn_db_path)?)
+        }
+        GeoProviderKind::Ip2Location => {
+            let db_path = settings.resolve_ip2location_db_path()?;
+            Arc::new(Ip2LocationProvider::open(db_path)?)
+        }
+    };
+
+    Ok(provider)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_settings_select_provider() {
+        let mut settings = Settings::default();
+        assert_eq!(settings.geo.provider, GeoProviderKind::MaxMind);
+
+        settings.geo.provider = GeoProviderKind::Ip2Location;
+        settings.geo.ip2location_db_path = "does/not/exist.BIN".into();
+        assert!(from_settings(&settings).is_err());
+    }
+
+    #[test]
+    fn test_disabled_features_skip_databases() {
+        let mut settings = Settings::default();
+        settings.maxmind.db_path = "does/not/exist.mmdb".into();
+        settings.features.geo_lookup = false;
+        settings.features.asn_lookup = false;
+
+        let provider = from_settings(&settings).unwrap();
+        assert_eq!(provider.metadata().provider, "disabled");
+    }
+}
@@ -0,0 +1,576 @@
+//! `?fields=` projection for lookup responses.
+//!
+//! The projection borrows the cached [`LookupResponse`] and serializes only
+//! the selected fields, so every selection shares one cache entry.
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use axum::http::{HeaderMap, HeaderName};
+
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use serde::Deserialize;
+use tokio::time::Instant;
+use utoipa::IntoParams;
+
+use crate::errors::validation::IpSource;
+use crate::errors::AppError;
+use crate::handlers::LookupResponse;
+use crate::models::location::{AsnInfo, GeoInfo};
+
+/// Query parameters accepted by the lookup endpoints
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct LookupParams {
+    /// Comma-separated list of fields to return, e.g. `threat_score,geo_info.country`
+    pub fields: Option<String>,
+    /// Include diagnostics such as `ip_source` (self-lookups only)
+    #[serde(default)]
+    pub debug: bool,
+    /// Milliseconds to answer within; geo and ASN lookups still running by
+    /// then are skipped. Overrides the `X-InfraLock-Deadline-Ms` header.
+    pub deadline_ms: Option<u64>,
+}
+
+/// Time budget for a lookup in milliseconds, like `?deadline_ms=`
+pub const DEADLINE_HEADER: HeaderName = HeaderName::from_static("x-infralock-deadline-ms");
+
+impl LookupParams {
+    /// When the lookup must be answered by, counted from now, if the
+    /// request set a budget
+    pub fn deadline(&self, headers: &HeaderMap) -> Result<Option<Instant>, AppError> {
+        let millis = match (self.deadline_ms, headers.get(DEADLINE_HEADER)) {
+            (Some(millis), _) => millis,
+            (None, Some(value)) => value
+                .to_str()
+                .ok()
+                .and_then(|value| value.trim().parse().ok())
+                .ok_or_else(|| {
+                    AppError::BadRequest(format!("{} must be a whole number of milliseconds", DEADLINE_HEADER))
+                })?,
+            (None, None) => return Ok(None),
+        };
+        Ok(Some(Instant::now() + Duration::from_millis(millis)))
+    }
+}
+
+/// A field of [`LookupResponse`] that can be selected with `?fields=`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LookupField {
+    Ip,
+    CanonicalIp,
+    GeoInfo,
+    GeoCity,
+    GeoCountry,
+    GeoLocation,
+    AsnInfo,
+    AsnNumber,
+    AsnOrganization,
+    IsVpnOrDatacenter,
+    IsProxy,
+    ProxyType,
+    IsTorExitNode,
+    RecentlyDelisted,
+    Categories,
+    Category,
+    MatchedSource,
+    CloudProvider,
+    IsAnonymousProxy,
+    IsAnycast,
+    IsSatelliteProvider,
+    IsHostingProvider,
+    ThreatScore,
+    ThreatDetails,
+    FindingsTruncated,
+    RecommendedAction,
+    ShadowAction,
+    ChallengeToken,
+    DisabledFeatures,
+    Errors,
+    Partial,
+    Skipped,
+    DataUnavailable,
+}
+
+impl LookupField {
+    /// Every selectable field, in response order
+    pub const ALL: [LookupField; 33] = [
+        LookupField::Ip,
+        LookupField::CanonicalIp,
+        LookupField::GeoInfo,
+        LookupField::GeoCity,
+        LookupField::GeoCountry,
+        LookupField::GeoLocation,
+        LookupField::AsnInfo,
+        LookupField::AsnNumber,
+        LookupField::AsnOrganization,
+        LookupField::IsVpnOrDatacenter,
+        LookupField::IsProxy,
+        LookupField::ProxyType,
+        LookupField::IsTorExitNode,
+        LookupField::RecentlyDelisted,
+        LookupField::Categories,
+        LookupField::Category,
+        LookupField::MatchedSource,
+        LookupField::CloudProvider,
+        LookupField::IsAnonymousProxy,
+        LookupField::IsAnycast,
+        LookupField::IsSatelliteProvider,
+        LookupField::IsHostingProvider,
+        LookupField::ThreatScore,
+        LookupField::ThreatDetails,
+        LookupField::FindingsTruncated,
+        LookupField::RecommendedAction,
+        LookupField::ShadowAction,
+        LookupField::ChallengeToken,
+        LookupField::DisabledFeatures,
+        LookupField::Errors,
+        LookupField::Partial,
+        LookupField::Skipped,
+        LookupField::DataUnavailable,
+    ];
+
+    /// The name used in `?fields=`; nested fields use `parent.child`
+    pub fn name(self) -> &'static str {
+        match self {
+            LookupField::Ip => "ip",
+            LookupField::CanonicalIp => "canonical_ip",
+            LookupField::GeoInfo => "geo_info",
+            LookupField::GeoCity => "geo_info.city",
+            LookupField::GeoCountry => "geo_info.country",
+            LookupField::GeoLocation => "geo_info.location",
+            LookupField::AsnInfo => "asn_info",
+            LookupField::AsnNumber => "asn_info.autonomous_system_number",
+            LookupField::AsnOrganization => "asn_info.autonomous_system_organization",
+            LookupField::IsVpnOrDatacenter => "is_vpn_or_datacenter",
+            LookupField::IsProxy => "is_proxy",
+            LookupField::ProxyType => "proxy_type",
+            LookupField::IsTorExitNode => "is_tor_exit_node",
+            LookupField::RecentlyDelisted => "recently_delisted",
+            LookupField::Categories => "categories",
+            LookupField::Category => "category",
+            LookupField::MatchedSource => "matched_source",
+            LookupField::CloudProvider => "cloud_provider",
+            LookupField::IsAnonymousProxy => "is_anonymous_proxy",
+            LookupField::IsAnycast => "is_anycast",
+            LookupField::IsSatelliteProvider => "is_satellite_provider",
+            LookupField::IsHostingProvider => "is_hosting_provider",
+            LookupField::ThreatScore => "threat_score",
+            LookupField::ThreatDetails => "threat_details",
+            LookupField::FindingsTruncated => "findings_truncated",
+            LookupField::RecommendedAction => "recommended_action",
+            LookupField::ShadowAction => "shadow_action",
+            LookupField::ChallengeToken => "challenge_token",
+            LookupField::DisabledFeatures => "disabled_features",
+            LookupField::Errors => "errors",
+            LookupField::Partial => "partial",
+            LookupField::Skipped => "skipped",
+            LookupField::DataUnavailable => "data_unavailable",
+        }
+    }
+
+    /// The key this field is written under, without the parent prefix
+    fn key(self) -> &'static str {
+        let name = self.name();
+        name.rsplit_once('.').map_or(name, |(_, child)| child)
+    }
+}
+
+impl FromStr for LookupField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        LookupField::ALL
+            .into_iter()
+            .find(|field| field.name() == s)
+            .ok_or_else(|| s.to_string())
+    }
+}
+
+/// A validated `?fields=` selection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldSelection {
+    fields: Vec<LookupField>,
+}
+
+impl FieldSelection {
+    /// Parse a comma-separated field list, rejecting unknown names
+    pub fn parse(fields: &str) -> Result<Self, AppError> {
+        let mut selected = Vec::new();
+        let mut unknown = Vec::new();
+
+        for name in fields.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            match name.parse::<LookupField>() {
+                Ok(field) if !selected.contains(&field) => selected.push(field),
+                Ok(_) => {}
+                Err(name) => unknown.push(name),
+            }
+        }
+
+        if !unknown.is_empty() {
+            let valid: Vec<_> = LookupField::ALL.iter().map(|field| field.name()).collect();
+            return Err(AppError::BadRequest(format!(
+                "Unknown field(s): {}. Valid fields: {}",
+                unknown.join(", "),
+                valid.join(", ")
+            )));
+        }
+
+        Ok(Self { fields: selected })
+    }
+
+    fn contains(&self, field: LookupField) -> bool {
+        self.fields.contains(&field)
+    }
+
+    /// Whether `whole` or any of its `children` is selected
+    fn wants_any(&self, whole: LookupField, children: &[LookupField]) -> bool {
+        self.contains(whole) || children.iter().any(|child| self.contains(*child))
+    }
+}
+
+const GEO_CHILDREN: [LookupField; 3] = [
+    LookupField::GeoCity,
+    LookupField::GeoCountry,
+    LookupField::GeoLocation,
+];
+const ASN_CHILDREN: [LookupField; 2] = [LookupField::AsnNumber, LookupField::AsnOrganization];
+
+/// A lookup response, optionally restricted to a field selection
+#[derive(Debug, Clone)]
+pub struct LookupProjection {
+    pub response: LookupResponse,
+    pub selection: Option<FieldSelection>,
+    /// Which header the client IP came from, reported when `?debug=true`
+    pub ip_source: Option<IpSource>,
+}
+
+impl LookupProjection {
+    /// Project `response` according to the request's `?fields=` parameter
+    pub fn from_params(response: LookupResponse, params: &LookupParams) -> Result<Self, AppError> {
+        let selection = params.fields.as_deref().map(FieldSelection::parse).transpose()?;
+        Ok(Self {
+            response,
+            selection,
+            ip_source: None,
+        })
+    }
+}
+
+impl Serialize for LookupProjection {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let Some(selection) = &self.selection else {
+            #[derive(serde::Serialize)]
+            struct WithSource<'a> {
+                #[serde(flatten)]
+                response: &'a LookupResponse,
+                #[serde(skip_serializing_if = "Option::is_none")]
+                ip_source: Option<IpSource>,
+            }
+            return WithSource {
+                response: &self.response,
+                ip_source: self.ip_source,
+            }
+            .serialize(serializer);
+        };
+        let r = &self.response;
+
+        let mut map = serializer.serialize_map(None)?;
+        for field in LookupField::ALL {
+            match field {
+                // Nested fields are written as part of their parent
+                LookupField::GeoCity
+                | LookupField::GeoCountry
+                | LookupField::GeoLocation
+                | LookupField::AsnNumber
+                | LookupField::AsnOrganization => {}
+                LookupField::GeoInfo if selection.wants_any(field, &GEO_CHILDREN) => {
+                    map.serialize_entry(field.name(), &NestedGeo { geo: r.geo_info.as_ref(), selection })?;
+                }
+                LookupField::AsnInfo if selection.wants_any(field, &ASN_CHILDREN) => {
+                    map.serialize_entry(field.name(), &NestedAsn { asn: r.asn_info.as_ref(), selection })?;
+                }
+                // Reported whatever the selection, so a field nulled by a
+                // database error is not mistaken for missing data
+                LookupField::Errors if selection.contains(field) || !r.errors.is_empty() => {
+                    map.serialize_entry(field.name(), &r.errors)?;
+                }
+                // Likewise, so fields left out at the deadline are not mistaken for missing data
+                LookupField::Partial if selection.contains(field) || r.partial => {
+                    map.serialize_entry(field.name(), &r.partial)?;
+                }
+                LookupField::Skipped if selection.contains(field) || r.partial => {
+                    map.serialize_entry(field.name(), &r.skipped)?;
+                }
+                // And so a verdict made without the threat data is never mistaken for a clean one
+                LookupField::DataUnavailable if selection.contains(field) || r.data_unavailable => {
+                    map.serialize_entry(field.name(), &r.data_unavailable)?;
+                }
+                // Goes with the details it qualifies
+                LookupField::FindingsTruncated
+                    if selection.contains(field)
+                        || (r.findings_truncated && selection.contains(LookupField::ThreatDetails)) =>
+                {
+                    map.serialize_entry(field.name(), &r.findings_truncated)?;
+                }
+                // Goes with the `challenge` verdict it was issued for
+                LookupField::ChallengeToken
+                    if r.challenge_token.is_some()
+                        && (selection.contains(field) || selection.contains(LookupField::RecommendedAction)) =>
+                {
+                    map.serialize_entry(field.name(), &r.challenge_token)?;
+                }
+                _ if !selection.contains(field) => {}
+                LookupField::Ip => map.serialize_entry(field.name(), &r.ip)?,
+                LookupField::CanonicalIp => map.serialize_entry(field.name(), &r.canonical_ip)?,
+                LookupField::IsVpnOrDatacenter => map.serialize_entry(field.name(), &r.is_vpn_or_datacenter)?,
+                LookupField::IsProxy => map.serialize_entry(field.name(), &r.is_proxy)?,
+                LookupField::ProxyType => map.serialize_entry(field.name(), &r.proxy_type)?,
+                LookupField::IsTorExitNode => map.serialize_entry(field.name(), &r.is_tor_exit_node)?,
+                LookupField::RecentlyDelisted => map.serialize_entry(field.name(), &r.recently_delisted)?,
+                LookupField::Categories => map.serialize_entry(field.name(), &r.categories)?,
+                LookupField::Category => map.serialize_entry(field.name(), &r.category)?,
+                LookupField::MatchedSource => map.serialize_entry(field.name(), &r.matched_source)?,
+                LookupField::CloudProvider => map.serialize_entry(field.name(), &r.cloud_provider)?,
+                LookupField::IsAnonymousProxy => map.serialize_entry(field.name(), &r.is_anonymous_proxy)?,
+                LookupField::IsAnycast => map.serialize_entry(field.name(), &r.is_anycast)?,
+                LookupField::IsSatelliteProvider => map.serialize_entry(field.name(), &r.is_satellite_provider)?,
+                LookupField::IsHostingProvider => map.serialize_entry(field.name(), &r.is_hosting_provider)?,
+                LookupField::ThreatScore => map.serialize_entry(field.name(), &r.threat_score)?,
+                LookupField::ThreatDetails => map.serialize_entry(field.name(), &r.threat_details)?,
+                LookupField::RecommendedAction => map.serialize_entry(field.name(), &r.recommended_action)?,
+                LookupField::ShadowAction => map.serialize_entry(field.name(), &r.shadow_action)?,
+                LookupField::DisabledFeatures => map.serialize_entry(field.name(), &r.disabled_features)?,
+                LookupField::GeoInfo
+                | LookupField::AsnInfo
+                | LookupField::ChallengeToken
+                | LookupField::FindingsTruncated
+                | LookupField::Errors
+                | LookupField::Partial
+                | LookupField::Skipped
+                | LookupField::DataUnavailable => {}
+            }
+        }
+        if let Some(ip_source) = self.ip_source {
+            map.serialize_entry("ip_source", &ip_source)?;
+        }
+        map.end()
+    }
+}
+
+/// `geo_info`, whole or restricted to the selected children
+struct NestedGeo<'a> {
+    geo: Option<&'a GeoInfo>,
+    selection: &'a FieldSelection,
+}
+
+impl Serialize for NestedGeo<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let Some(geo) = self.geo else {
+            return serializer.serialize_none();
+        };
+        if self.selection.contains(LookupField::GeoInfo) {
+            return geo.serialize(serializer);
+        }
+
+        let mut map = serializer.serialize_map(None)?;
+        for field in GEO_CHILDREN {
+            if !self.selection.contains(field) {
+                continue;
+            }
+            match field {
+                LookupField::GeoCity => map.serialize_entry(field.key(), &geo.city)?,
+                LookupField::GeoCountry => map.serialize_entry(field.key(), &geo.country)?,
+                _ => map.serialize_entry(field.key(), &geo.location)?,
+            }
+        }
+        map.end()
+    }
+}
+
+/// `asn_info`, whole or restricted to the selected children
+struct NestedAsn<'a> {
+    asn: Option<&'a AsnInfo>,
+    selection: &'a FieldSelection,
+}
+
+impl Serialize for NestedAsn<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let Some(asn) = self.asn else {
+            return serializer.serialize_none();
+        };
+        if self.selection.contains(LookupField::AsnInfo) {
+            return asn.serialize(serializer);
+        }
+
+        let mut map = serializer.serialize_map(None)?;
+        for field in ASN_CHILDREN {
+            if !self.selection.contains(field) {
+                continue;
+            }
+            match field {
+                LookupField::AsnNumber => map.serialize_entry(field.key(), &asn.autonomous_system_number)?,
+                _ => map.serialize_entry(field.key(), &asn.autonomous_system_organization)?,
+            }
+        }
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::LookupErrors;
+    use crate::models::location::Country;
+    use serde_json::json;
+
+    fn response() -> LookupResponse {
+        LookupResponse {
+            ip: "203.0.113.7".to_string(),
+            canonical_ip: "203.0.113.7".to_string(),
+            geo_info: Some(GeoInfo {
+                city: None,
+                country: Some(Country {
+                    names: Some([("en".to_string(), "Mockland".to_string())].into_iter().collect()),
+                    iso_code: None,
+                }),
+                location: None,
+                traits: None,
+            }),
+            asn_info: Some(AsnInfo {
+                autonomous_system_number: Some(64500),
+                autonomous_system_organization: Some("Mock Networks".to_string()),
+            }),
+            is_vpn_or_datacenter: false,
+            is_proxy: false,
+            proxy_type: None,
+            is_tor_exit_node: true,
+            recently_delisted: false,
+            categories: crate::ip_lookup::CategoryFlags { tor: true, ..Default::default() },
+            category: Some("tor_exit_node".to_string()),
+            matched_source: Some("tor-exit-nodes".to_string()),
+            cloud_provider: None,
+            is_anonymous_proxy: None,
+            is_anycast: None,
+            is_satellite_provider: None,
+            is_hosting_provider: None,
+            threat_score: 90,
+            threat_details: vec!["IP is a Tor exit node".to_string()],
+            findings_truncated: false,
+            recommended_action: "block".to_string(),
+            shadow_action: None,
+            disabled_features: vec![],
+            errors: LookupErrors::default(),
+            partial: false,
+            data_unavailable: false,
+            skipped: vec![],
+            challenge_token: None,
+        }
+    }
+
+    fn project(fields: Option<&str>) -> Result<serde_json::Value, AppError> {
+        let params = LookupParams {
+            fields: fields.map(str::to_string),
+            debug: false,
+            deadline_ms: None,
+        };
+        let projection = LookupProjection::from_params(response(), &params)?;
+        Ok(serde_json::to_value(projection).unwrap())
+    }
+
+    #[test]
+    fn test_no_selection_returns_full_response() {
+        assert_eq!(project(None).unwrap(), serde_json::to_value(response()).unwrap());
+    }
+
+    #[test]
+    fn test_projects_top_level_fields() {
+        let value = project(Some("threat_score, recommended_action,is_tor_exit_node")).unwrap();
+        assert_eq!(
+            value,
+            json!({ "is_tor_exit_node": true, "threat_score": 90, "recommended_action": "block" })
+        );
+    }
+
+    #[test]
+    fn test_findings_truncated_goes_with_threat_details() {
+        let truncated = |fields: &str| {
+            let projection = LookupProjection {
+                response: LookupResponse { findings_truncated: true, ..response() },
+                selection: Some(FieldSelection::parse(fields).unwrap()),
+                ip_source: None,
+            };
+            serde_json::to_value(projection).unwrap()
+        };
+        assert_eq!(
+            truncated("threat_details"),
+            json!({ "threat_details": ["IP is a Tor exit node"], "findings_truncated": true })
+        );
+        assert_eq!(truncated("threat_score"), json!({ "threat_score": 90 }));
+        assert_eq!(project(Some("findings_truncated")).unwrap(), json!({ "findings_truncated": false }));
+    }
+
+    #[test]
+    fn test_projects_nested_fields() {
+        let value = project(Some("geo_info.country,asn_info.autonomous_system_number")).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "geo_info": { "country": { "names": { "en": "Mockland" } } },
+                "asn_info": { "autonomous_system_number": 64500 },
+            })
+        );
+
+        // Selecting the parent returns it whole, even alongside a child
+        let value = project(Some("geo_info,geo_info.country")).unwrap();
+        assert_eq!(value["geo_info"], serde_json::to_value(response().geo_info).unwrap());
+    }
+
+    #[test]
+    fn test_projection_keeps_database_errors() {
+        let mut response = response();
+        response.asn_info = None;
+        response.errors.asn = Some("MaxMind DB error: corrupt search tree".to_string());
+        let params = LookupParams {
+            fields: Some("asn_info.autonomous_system_number".to_string()),
+            debug: false,
+            deadline_ms: None,
+        };
+        let value = serde_json::to_value(LookupProjection::from_params(response, &params).unwrap()).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "asn_info": null,
+                "errors": { "asn": "MaxMind DB error: corrupt search tree" },
+            })
+        );
+    }
+
+    #[test]
+    fn test_challenge_token_follows_recommended_action() {
+        let mut response = response();
+        response.recommended_action = "challenge".to_string();
+        response.challenge_token = Some("claims.signature".to_string());
+        let params = LookupParams {
+            fields: Some("recommended_action".to_string()),
+            debug: false,
+            deadline_ms: None,
+        };
+        let value = serde_json::to_value(LookupProjection::from_params(response, &params).unwrap()).unwrap();
+        assert_eq!(
+            value,
+            json!({ "recommended_action": "challenge", "challenge_token": "claims.signature" })
+        );
+    }
+
+    #[test]
+    fn test_rejects_unknown_fields() {
+        let Err(AppError::BadRequest(message)) = project(Some("threat_score,threat_scroe,geo_info.zip"))
+        else {
+            panic!("expected a bad request");
+        };
+        assert!(message.contains("threat_scroe, geo_info.zip"));
+        assert!(message.contains("recommended_action"));
+        assert!(message.contains("geo_info.country"));
+    }
+}
@@ -0,0 +1,641 @@
+//! Status-code-only endpoints for edge integrations (nginx `auth_request`,
+//! HAProxy) that can act on a response status but not parse JSON, and the
+//! verdict headers they share with the JSON lookups.
+//!
+//! Verdicts come from [`LookupService`](crate::services::lookup_service::LookupService),
+//! so they always match the `recommended_action` of the JSON lookup.
+
+use axum::{
+    body::Body,
+    extract::{Path, Request, State},
+    Extension,
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use super::{log_decision, profile_lookup_service, resolve_client_ip, settle_challenge, AppState, LookupResponse};
+use crate::errors::{validation::validate_ip, AppError};
+use crate::models::auth::AuthenticatedUser;
+use crate::routes::openapi;
+use crate::services::lookup_service::CacheStatus;
+use crate::services::profiles::ProfileName;
+use crate::utils::redact;
+
+/// Recommended action, lowercase (allow/monitor/challenge/redirect/block)
+pub const ACTION_HEADER: HeaderName = HeaderName::from_static("x-infralock-action");
+/// Threat score, 0-100
+pub const SCORE_HEADER: HeaderName = HeaderName::from_static("x-infralock-score");
+/// Challenge token to redeem at `/api/challenge/verify`, with `challenge` verdicts
+pub const CHALLENGE_TOKEN_HEADER: HeaderName = HeaderName::from_static("x-infralock-challenge-token");
+/// Clearance token, sent by callers to have `challenge` answered `allow`
+pub const CLEARANCE_HEADER: HeaderName = HeaderName::from_static("x-infralock-clearance");
+/// Set flags of `categories`, comma-separated
+pub const CATEGORIES_HEADER: HeaderName = HeaderName::from_static("x-infralock-categories");
+/// `hit` or `miss`, whether the verdict came from the lookup cache
+pub const CACHE_HEADER: HeaderName = HeaderName::from_static("x-infralock-cache");
+/// Sent by callers as `true` or `false` to override `server.verdict_headers`
+pub const HEADERS_REQUEST_HEADER: HeaderName = HeaderName::from_static("x-infralock-headers");
+
+/// Maps a recommended action to the gate status code
+fn gate_status(action: &str) -> StatusCode {
+    match action {
+        "challenge" => StatusCode::UNAUTHORIZED,
+        "block" | "redirect" => StatusCode::FORBIDDEN,
+        _ => StatusCode::NO_CONTENT,
+    }
+}
+
+/// `text` as a header value, with anything but visible ASCII and spaces
+/// replaced by `?`
+fn ascii_header_value(text: &str) -> HeaderValue {
+    let sanitized: String = text.chars().map(|c| if c == ' ' || c.is_ascii_graphic() { c } else { '?' }).collect();
+    HeaderValue::from_str(&sanitized).expect("visible ASCII is a valid header value")
+}
+
+/// The verdict headers for a lookup response
+fn verdict_headers(response: &LookupResponse) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(ACTION_HEADER, ascii_header_value(&response.recommended_action));
+    headers.insert(SCORE_HEADER, HeaderValue::from(u16::from(response.threat_score)));
+    if let Some(token) = &response.challenge_token {
+        headers.insert(CHALLENGE_TOKEN_HEADER, ascii_header_value(token));
+    }
+    headers
+}
+
+/// [`verdict_headers`] plus the categories and cache status
+fn detailed_verdict_headers(response: &LookupResponse, cache: CacheStatus) -> HeaderMap {
+    let mut headers = verdict_headers(response);
+    let categories: Vec<&str> = response.categories.names().collect();
+    headers.insert(CATEGORIES_HEADER, ascii_header_value(&categories.join(",")));
+    headers.insert(CACHE_HEADER, HeaderValue::from_static(cache.as_str()));
+    headers
+}
+
+/// Whether the response to a request carries the detailed verdict headers:
+/// as `X-InfraLock-Headers` asks, or per `server.verdict_headers` if it
+/// does not say
+fn verdict_headers_requested(state: &AppState, headers: &HeaderMap) -> bool {
+    match headers.get(HEADERS_REQUEST_HEADER).and_then(|value| value.to_str().ok()).map(str::trim) {
+        Some(value) if value.eq_ignore_ascii_case("true") => true,
+        Some(value) if value.eq_ignore_ascii_case("false") => false,
+        _ => state.verdict_headers,
+    }
+}
+
+/// The detailed verdict headers for a JSON lookup, if the request asked
+/// for them; otherwise none
+pub(super) fn requested_verdict_headers(
+    state: &AppState,
+    request_headers: &HeaderMap,
+    response: &LookupResponse,
+    cache: CacheStatus,
+) -> HeaderMap {
+    if verdict_headers_requested(state, request_headers) {
+        detailed_verdict_headers(response, cache)
+    } else {
+        HeaderMap::new()
+    }
+}
+
+/// A lookup response body and the verdict headers to send with it. The
+/// body is serialized exactly as it would be on its own.
+#[derive(Debug)]
+pub struct WithVerdict<T>(pub T, pub HeaderMap);
+
+impl<T: IntoResponse> IntoResponse for WithVerdict<T> {
+    fn into_response(self) -> Response {
+        (self.1, self.0).into_response()
+    }
+}
+
+/// A self endpoint's answer, `None` when `on_missing_ip = allow` left no
+/// client IP to score: that is sent as `204 No Content` with an `allow`
+/// verdict header, the same verdict `HEAD /api/lookup/self` gives
+#[derive(Debug)]
+pub struct OrUnscored<T>(pub Option<T>);
+
+impl<T: IntoResponse> IntoResponse for OrUnscored<T> {
+    fn into_response(self) -> Response {
+        match self.0 {
+            Some(body) => body.into_response(),
+            None => (StatusCode::NO_CONTENT, unscored_headers()).into_response(),
+        }
+    }
+}
+
+/// The verdict for a request without a client IP under `on_missing_ip = allow`
+fn unscored_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(ACTION_HEADER, HeaderValue::from_static("allow"));
+    headers
+}
+
+/// The gate headers: the verdict, and the categories and cache status when
+/// requested
+fn gate_headers(state: &AppState, request_headers: &HeaderMap, response: &LookupResponse, cache: CacheStatus) -> HeaderMap {
+    if verdict_headers_requested(state, request_headers) {
+        detailed_verdict_headers(response, cache)
+    } else {
+        verdict_headers(response)
+    }
+}
+
+async fn lookup_path_ip(
+    ip: &str,
+    state: &AppState,
+    profile: Option<&ProfileName>,
+) -> Result<(LookupResponse, CacheStatus), AppError> {
+    let ip_addr: IpAddr = ip.parse()?;
+
+    // IP validation
+    if let Err(e) = validate_ip(ip_addr) {
+        tracing::warn!("Rejected IP lookup for {}: {}", redact::ip(ip_addr), e);
+        return Err(AppError::ValidationError(e));
+    }
+
+    profile_lookup_service(state, profile).lookup_ip_with_status(ip_addr, None).await
+}
+
+/// `GET /api/gate/{ip}`: 204 for allow/monitor, 401 for challenge, 403 for
+/// block/redirect, with the verdict in headers
+#[utoipa::path(
+    get,
+    path = "/api/v1/gate/{ip}",
+    tag = "gate",
+    params(
+        ("ip" = String, Path, description = "IPv4 or IPv6 address"),
+        ("X-InfraLock-Clearance" = Option<String>, Header, description = "Clearance token from `/api/v1/challenge/verify`; answers `challenge` with `allow`"),
+    ),
+    responses(
+        (status = 204, description = "Allow or monitor", headers(
+            ("X-InfraLock-Action" = String, description = "Recommended action: allow, monitor, challenge, redirect or block"),
+            ("X-InfraLock-Score" = u8, description = "Threat score, 0-100"),
+            ("X-InfraLock-Challenge-Token" = String, description = "With `challenge` verdicts, the token to redeem at `/api/v1/challenge/verify`"),
+        )),
+        (status = 401, description = "Challenge", headers(
+            ("X-InfraLock-Action" = String, description = "Recommended action: allow, monitor, challenge, redirect or block"),
+            ("X-InfraLock-Score" = u8, description = "Threat score, 0-100"),
+            ("X-InfraLock-Challenge-Token" = String, description = "With `challenge` verdicts, the token to redeem at `/api/v1/challenge/verify`"),
+        )),
+        (status = 403, description = "Block or redirect", headers(
+            ("X-InfraLock-Action" = String, description = "Recommended action: allow, monitor, challenge, redirect or block"),
+            ("X-InfraLock-Score" = u8, description = "Threat score, 0-100"),
+            ("X-InfraLock-Challenge-Token" = String, description = "With `challenge` verdicts, the token to redeem at `/api/v1/challenge/verify`"),
+        )),
+        (status = 400, description = "Invalid or non-public IP address", body = openapi::ErrorBody),
+    )
+)]
+#[tracing::instrument(name = "infralock.gate", skip_all, fields(ip = %redact::text(&ip)))]
+pub async fn gate(
+    Path(ip): Path<String>,
+    State(state): State<Arc<AppState>>,
+    profile: Option<Extension<ProfileName>>,
+    user: Option<Extension<AuthenticatedUser>>,
+    headers: HeaderMap,
+) -> Result<(StatusCode, HeaderMap), AppError> {
+    let (mut response, cache) = lookup_path_ip(&ip, &state, profile.as_deref()).await?;
+    settle_challenge(&state, &mut response, &headers).await;
+    log_decision(&state, &response, "/api/gate/{ip}", user.as_deref());
+    Ok((gate_status(&response.recommended_action), gate_headers(&state, &headers, &response, cache)))
+}
+
+/// `HEAD /api/lookup/{ip}`: the verdict headers without the body
+#[utoipa::path(
+    head,
+    path = "/api/v1/lookup/{ip}",
+    tag = "gate",
+    params(
+        ("ip" = String, Path, description = "IPv4 or IPv6 address"),
+        ("X-InfraLock-Clearance" = Option<String>, Header, description = "Clearance token from `/api/v1/challenge/verify`; answers `challenge` with `allow`"),
+    ),
+    responses(
+        (status = 200, description = "The verdict headers of `GET /api/v1/lookup/{ip}`, without the body", headers(
+            ("X-InfraLock-Action" = String, description = "Recommended action: allow, monitor, challenge, redirect or block"),
+            ("X-InfraLock-Score" = u8, description = "Threat score, 0-100"),
+            ("X-InfraLock-Challenge-Token" = String, description = "With `challenge` verdicts, the token to redeem at `/api/v1/challenge/verify`"),
+        )),
+        (status = 400, description = "Invalid or non-public IP address"),
+    )
+)]
+#[tracing::instrument(name = "infralock.lookup_head", skip_all, fields(ip = %redact::text(&ip)))]
+pub async fn head_lookup_ip(
+    Path(ip): Path<String>,
+    State(state): State<Arc<AppState>>,
+    profile: Option<Extension<ProfileName>>,
+    user: Option<Extension<AuthenticatedUser>>,
+    headers: HeaderMap,
+) -> Result<HeaderMap, AppError> {
+    let (mut response, cache) = lookup_path_ip(&ip, &state, profile.as_deref()).await?;
+    settle_challenge(&state, &mut response, &headers).await;
+    log_decision(&state, &response, "/api/lookup/{ip}", user.as_deref());
+    Ok(gate_headers(&state, &headers, &response, cache))
+}
+
+/// `HEAD /api/lookup/self`: the verdict headers for the caller's IP
+#[utoipa::path(
+    head,
+    path = "/api/v1/lookup/self",
+    tag = "gate",
+    params(
+        ("X-InfraLock-Clearance" = Option<String>, Header, description = "Clearance token from `/api/v1/challenge/verify`; answers `challenge` with `allow`"),
+    ),
+    responses(
+        (status = 200, description = "The verdict for the caller's address; `allow` when there is no client IP and `on_missing_ip = allow`", headers(
+            ("X-InfraLock-Action" = String, description = "Recommended action: allow, monitor, challenge, redirect or block"),
+            ("X-InfraLock-Score" = u8, description = "Threat score, 0-100"),
+            ("X-InfraLock-Challenge-Token" = String, description = "With `challenge` verdicts, the token to redeem at `/api/v1/challenge/verify`"),
+        )),
+        (status = 400, description = "Invalid or non-public IP address"),
+    )
+)]
+#[tracing::instrument(
+    name = "infralock.lookup_self_head",
+    skip_all,
+    fields(ip = tracing::field::Empty)
+)]
+pub async fn head_lookup_self(
+    State(state): State<Arc<AppState>>,
+    request: Request<Body>,
+) -> Result<HeaderMap, AppError> {
+    let Some((ip_addr, _)) = resolve_client_ip(&request, &state)? else {
+        // `on_missing_ip = allow`: nothing to score, so let the request through
+        return Ok(unscored_headers());
+    };
+    let (mut response, cache) = profile_lookup_service(&state, request.extensions().get())
+        .lookup_ip_with_status(ip_addr, None)
+        .await?;
+    settle_challenge(&state, &mut response, request.headers()).await;
+    log_decision(&state, &response, "/api/lookup/self", request.extensions().get());
+    Ok(gate_headers(&state, request.headers(), &response, cache))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clients::web_api::{WebApiClient, WebApiClientConfig};
+    use crate::config::{runtime::RuntimeConfig, Settings};
+    use crate::ip_lookup::{tree::RadixTree, IpCategory};
+    use crate::routes::create_router;
+    use crate::services::response_action::ResponseActionConfig;
+    use crate::test_support::{self, mmdb::FIXTURE_US_IP};
+    use axum::http::Method;
+    use axum::Router;
+    use tower::ServiceExt;
+
+    const TOR_IP: &str = "5.1.1.1";
+    const VPN_IP: &str = "5.2.2.2";
+    const PROXY_IP: &str = "5.3.3.3";
+
+    fn router_with(config: ResponseActionConfig) -> Router {
+        router_with_state(test_support::app_state(), config)
+    }
+
+    fn router_with_state(mut state: AppState, config: ResponseActionConfig) -> Router {
+        let settings = Settings {
+            response_action: config,
+            ..Settings::default()
+        };
+        state.runtime = RuntimeConfig::from_settings(&settings).shared();
+
+        let mut tree = RadixTree::new();
+        tree.insert(format!("{}/32", TOR_IP).parse().unwrap(), IpCategory::TorExitNode);
+        tree.insert(format!("{}/32", VPN_IP).parse().unwrap(), IpCategory::Vpn);
+        tree.insert(format!("{}/32", PROXY_IP).parse().unwrap(), IpCategory::ProxyHttp);
+        state.ip_lookup_service.tree().replace(tree);
+
+        create_router(state)
+    }
+
+    async fn send(router: &Router, method: Method, uri: &str) -> axum::response::Response {
+        send_as(router, method, uri, test_support::API_KEY).await
+    }
+
+    async fn send_as(router: &Router, method: Method, uri: &str, api_key: &str) -> axum::response::Response {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("x-api-key", api_key)
+            .body(Body::empty())
+            .unwrap();
+        router.clone().oneshot(request).await.unwrap()
+    }
+
+    async fn gate_verdict(router: &Router, ip: &str) -> (StatusCode, String) {
+        gate_verdict_as(router, ip, test_support::API_KEY).await
+    }
+
+    async fn gate_verdict_as(router: &Router, ip: &str, api_key: &str) -> (StatusCode, String) {
+        let response = send_as(router, Method::GET, &format!("/api/gate/{}", ip), api_key).await;
+        let action = response.headers()[ACTION_HEADER].to_str().unwrap().to_string();
+        assert!(response.headers().contains_key(SCORE_HEADER));
+        (response.status(), action)
+    }
+
+    #[test]
+    fn test_gate_status_per_action() {
+        assert_eq!(gate_status("allow"), StatusCode::NO_CONTENT);
+        assert_eq!(gate_status("monitor"), StatusCode::NO_CONTENT);
+        assert_eq!(gate_status("challenge"), StatusCode::UNAUTHORIZED);
+        assert_eq!(gate_status("redirect"), StatusCode::FORBIDDEN);
+        assert_eq!(gate_status("block"), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_gate_maps_actions_to_status() {
+        let router = router_with(ResponseActionConfig::default());
+
+        assert_eq!(
+            gate_verdict(&router, FIXTURE_US_IP).await,
+            (StatusCode::NO_CONTENT, "allow".to_string())
+        );
+        assert_eq!(
+            gate_verdict(&router, VPN_IP).await,
+            (StatusCode::UNAUTHORIZED, "challenge".to_string())
+        );
+        assert_eq!(
+            gate_verdict(&router, PROXY_IP).await,
+            (StatusCode::FORBIDDEN, "redirect".to_string())
+        );
+        // Tor is blocked immediately, regardless of score
+        assert_eq!(
+            gate_verdict(&router, TOR_IP).await,
+            (StatusCode::FORBIDDEN, "block".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_gate_passes_monitor_mode() {
+        let router = router_with(ResponseActionConfig {
+            monitor_mode: true,
+            ..Default::default()
+        });
+
+        assert_eq!(
+            gate_verdict(&router, TOR_IP).await,
+            (StatusCode::NO_CONTENT, "monitor".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_gate_verdict_follows_request_profile() {
+        use crate::config::ProfileSettings;
+        use crate::models::threat_score::ThreatType;
+        use crate::services::profiles::ScoringProfiles;
+
+        let mut profiles = ScoringProfiles::default();
+        profiles.insert(
+            "strict",
+            &ProfileSettings {
+                block_immediate: Some(vec![ThreatType::TorExitNode, ThreatType::VpnOrDatacenter]),
+                ..Default::default()
+            },
+        );
+        // A role that happens to share the default profile's name
+        profiles.insert(
+            "default",
+            &ProfileSettings {
+                block_immediate: Some(vec![ThreatType::VpnOrDatacenter]),
+                ..Default::default()
+            },
+        );
+        profiles.insert(
+            "lenient",
+            &ProfileSettings {
+                monitor_threshold: Some(90),
+                challenge_threshold: Some(95),
+                redirect_threshold: Some(99),
+                ..Default::default()
+            },
+        );
+
+        let mut state = test_support::app_state();
+        state.runtime = RuntimeConfig {
+            profiles,
+            ..RuntimeConfig::default()
+        }
+        .shared();
+        let mut tree = RadixTree::new();
+        tree.insert(format!("{}/32", VPN_IP).parse().unwrap(), IpCategory::Vpn);
+        state.ip_lookup_service.tree().replace(tree);
+
+        // The API key middleware resolves each caller's role to its profile
+        state.web_api_client = Arc::new(
+            WebApiClient::new(WebApiClientConfig::default())
+                .with_cached_key("strict-key", "tenant-a", "strict")
+                .with_cached_key("lenient-key", "tenant-b", "lenient")
+                .with_cached_key("plain-key", "tenant-c", "user")
+                .with_cached_key("default-role-key", "tenant-d", "default"),
+        );
+        // Both tenants share one router, and so one lookup cache
+        let router = create_router(state);
+
+        for _ in 0..2 {
+            assert_eq!(
+                gate_verdict_as(&router, VPN_IP, "strict-key").await,
+                (StatusCode::FORBIDDEN, "block".to_string())
+            );
+            assert_eq!(
+                gate_verdict_as(&router, VPN_IP, "lenient-key").await,
+                (StatusCode::NO_CONTENT, "allow".to_string())
+            );
+            // A role without a profile gets the default one, cached apart
+            // from the role named after it
+            assert_eq!(
+                gate_verdict_as(&router, VPN_IP, "plain-key").await,
+                (StatusCode::UNAUTHORIZED, "challenge".to_string())
+            );
+            assert_eq!(
+                gate_verdict_as(&router, VPN_IP, "default-role-key").await,
+                (StatusCode::FORBIDDEN, "block".to_string())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_gate_rejects_invalid_ip() {
+        let router = router_with(ResponseActionConfig::default());
+        let response = send(&router, Method::GET, "/api/gate/not-an-ip").await;
+        assert!(response.status().is_client_error());
+    }
+
+    #[tokio::test]
+    async fn test_head_lookup_returns_headers_only() {
+        let router = router_with(ResponseActionConfig::default());
+
+        let response = send(&router, Method::HEAD, &format!("/api/lookup/{}", TOR_IP)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[ACTION_HEADER], "block");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(body.is_empty());
+
+        let request = test_support::request()
+            .method(Method::HEAD)
+            .uri("/api/lookup/self")
+            .header("x-forwarded-for", VPN_IP)
+            .body(Body::empty())
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[ACTION_HEADER], "challenge");
+        assert_eq!(response.headers()[SCORE_HEADER], "60");
+    }
+
+    #[tokio::test]
+    async fn test_head_lookup_self_missing_ip_policy() {
+        use crate::errors::validation::MissingIpPolicy;
+
+        let head_self = |policy: MissingIpPolicy| async move {
+            let mut state = test_support::app_state();
+            state.on_missing_ip = policy;
+            let mut request = test_support::request()
+                .method(Method::HEAD)
+                .uri("/api/lookup/self")
+                .body(Body::empty())
+                .unwrap();
+            request
+                .extensions_mut()
+                .insert(axum::extract::ConnectInfo("8.8.4.4:443".parse::<std::net::SocketAddr>().unwrap()));
+            create_router(state).oneshot(request).await.unwrap()
+        };
+
+        let response = head_self(MissingIpPolicy::UseConnectInfo).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().contains_key(SCORE_HEADER));
+
+        let response = head_self(MissingIpPolicy::Reject).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // Let through without a score
+        let response = head_self(MissingIpPolicy::Allow).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[ACTION_HEADER], "allow");
+        assert!(!response.headers().contains_key(SCORE_HEADER));
+    }
+
+    #[tokio::test]
+    async fn test_challenge_token_redeemed_for_clearance() {
+        use crate::services::challenge::ChallengeService;
+        use std::time::Duration;
+
+        let mut state = test_support::app_state();
+        let challenges = ChallengeService::new(b"test-secret", Duration::from_secs(300), Duration::from_secs(3600), 0);
+        state.challenges = Some(Arc::new(challenges));
+        let router = router_with_state(state, ResponseActionConfig::default());
+        let gate_with = |clearance: Option<String>| {
+            let mut request = test_support::request().uri(format!("/api/gate/{}", VPN_IP));
+            if let Some(clearance) = clearance {
+                request = request.header(CLEARANCE_HEADER, clearance);
+            }
+            router.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        let response = gate_with(None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let token = response.headers()[CHALLENGE_TOKEN_HEADER].to_str().unwrap().to_string();
+        // Only challenge verdicts carry a token
+        let response = send(&router, Method::GET, &format!("/api/gate/{}", PROXY_IP)).await;
+        assert!(!response.headers().contains_key(CHALLENGE_TOKEN_HEADER));
+
+        let verify = |body: serde_json::Value| {
+            let request = test_support::request()
+                .method(Method::POST)
+                .uri("/api/challenge/verify")
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            router.clone().oneshot(request)
+        };
+        let response = verify(serde_json::json!({ "token": token, "solution": "wrong" })).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = verify(serde_json::json!({ "token": token, "solution": token })).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["ip"], VPN_IP);
+        let clearance = body["clearance_token"].as_str().unwrap().to_string();
+
+        let response = gate_with(Some(clearance.clone())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers()[ACTION_HEADER], "allow");
+
+        // A tampered clearance is ignored and the caller challenged again
+        let tampered = format!("{}x", clearance);
+        let response = gate_with(Some(tampered)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(response.headers().contains_key(CHALLENGE_TOKEN_HEADER));
+    }
+
+    async fn send_with(router: &Router, uri: &str, headers: &[(&str, &str)]) -> axum::response::Response {
+        let mut request = test_support::request().uri(uri);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        router.clone().oneshot(request.body(Body::empty()).unwrap()).await.unwrap()
+    }
+
+    async fn body_bytes(response: axum::response::Response) -> axum::body::Bytes {
+        axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_verdict_headers_only_when_requested() {
+        let router = router_with(ResponseActionConfig::default());
+        let uri = format!("/api/lookup/{}", TOR_IP);
+
+        let plain = send_with(&router, &uri, &[]).await;
+        assert!(!plain.headers().contains_key(SCORE_HEADER));
+        assert!(!plain.headers().contains_key(CACHE_HEADER));
+        let plain = body_bytes(plain).await;
+
+        let asked = send_with(&router, &uri, &[("x-infralock-headers", "true")]).await;
+        let headers = asked.headers().clone();
+        let body = body_bytes(asked).await;
+        assert_eq!(body, plain);
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(headers[SCORE_HEADER], json["threat_score"].to_string());
+        assert_eq!(headers[ACTION_HEADER], json["recommended_action"].as_str().unwrap());
+        assert_eq!(headers[CATEGORIES_HEADER], "tor");
+        assert_eq!(headers[CACHE_HEADER], "hit");
+
+        let own = send_with(
+            &router,
+            "/api/lookup/self?fields=ip",
+            &[("x-forwarded-for", VPN_IP), ("x-infralock-headers", "true")],
+        )
+        .await;
+        assert_eq!(own.headers()[CATEGORIES_HEADER], "vpn");
+        assert_eq!(own.headers()[CACHE_HEADER], "miss");
+        assert_eq!(own.headers()[ACTION_HEADER], "challenge");
+        assert_eq!(body_bytes(own).await, format!(r#"{{"ip":"{}"}}"#, VPN_IP));
+
+        // The gate always sends the verdict; categories and cache on request
+        let gate = send_with(&router, &format!("/api/gate/{}", PROXY_IP), &[]).await;
+        assert!(gate.headers().contains_key(SCORE_HEADER));
+        assert!(!gate.headers().contains_key(CATEGORIES_HEADER));
+        let gate = send_with(&router, &format!("/api/gate/{}", PROXY_IP), &[("x-infralock-headers", "true")]).await;
+        assert_eq!(gate.headers()[CATEGORIES_HEADER], "proxy_http");
+        assert_eq!(gate.headers()[CACHE_HEADER], "hit");
+    }
+
+    #[tokio::test]
+    async fn test_verdict_headers_setting_can_be_declined_per_request() {
+        let state = AppState { verdict_headers: true, ..test_support::app_state() };
+        let router = router_with_state(state, ResponseActionConfig::default());
+        let uri = format!("/api/lookup/{}", FIXTURE_US_IP);
+
+        let response = send_with(&router, &uri, &[]).await;
+        assert_eq!(response.headers()[CATEGORIES_HEADER], "");
+        assert_eq!(response.headers()[ACTION_HEADER], "allow");
+        let response = send_with(&router, &uri, &[("x-infralock-headers", "false")]).await;
+        assert!(!response.headers().contains_key(ACTION_HEADER));
+    }
+
+    #[test]
+    fn test_header_values_are_ascii() {
+        assert_eq!(ascii_header_value("bl\u{f6}ck\r\n"), "bl?ck??");
+        assert_eq!(ascii_header_value("allow"), "allow");
+    }
+}
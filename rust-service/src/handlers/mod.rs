@@ -1,127 +1,478 @@
+pub mod fields;
+pub mod gate;
+
 use axum::{
-    body::Body, extract::{ConnectInfo, Path, State}, Json
+    body::Body, extract::{ConnectInfo, Path, Query, State}, Extension, Json
 };
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use std::collections::{BTreeMap, HashSet};
 use std::net::{IpAddr};
 use std::sync::Arc;
-use tokio::sync::RwLock;    
+use std::time::{Duration, Instant};
 
 use axum::extract::Request;
+use axum::http::HeaderMap;
 
 use crate::{
     errors::{
         validation::{
-            extract_client_ip, validate_ip
+            canonical_ip, extract_client_ip, validate_cidr, validate_ip, CidrLimits, IpSource, IpValidationError,
+            MissingIpPolicy,
         }, AppError
-    }, services::lookup_service::LookupService
+    }, services::lookup_service::{LookupCache, LookupService}
 };
+use crate::services::config_reload::{ConfigReloader, ReloadReport};
+use crate::services::profiles::{ProfileName, ScoringProfile};
 use crate::services::vpn_detection::VpnDetector;
 use crate::services::proxy_detection::ProxyDetector;
-use crate::services::tor_detection::TorDetector;
-use crate::models::location::{GeoInfo, AsnInfo};
+use crate::services::aggregates::{self, AggregateStats, LookupAggregates};
+use crate::services::connectivity::{ConnectivityChecker, ProbeStatus, TargetReport};
+use crate::services::shared_cache::SharedCache;
+use crate::services::challenge::{ChallengeError, ChallengeService};
+use crate::services::decision_log::{DecisionContext, DecisionLog};
+use crate::services::debug_capture::{self, CaptureRecord, DebugCapture};
+use crate::services::usage::{UsageAccounting, UsageSnapshot};
+use crate::geo::GeoProvider;
 use percent_encoding::{percent_decode_str};
-use crate::models::threat_score::ThreatScore;
-use crate::ip_lookup::IpLookupService;
-use moka::sync::Cache;
+use crate::models::threat_score::{ScoreExplanation, ThreatScore, ThreatType};
+use crate::services::response_action::{
+    ActionDecision, MonitorOverride, ResponseAction, ResponseActionConfig, ResponseActionService,
+};
+use crate::ip_lookup::backoff::SourceFailure;
+use crate::ip_lookup::manual::ManualRange;
+use crate::ip_lookup::overlap::OverlapReport;
+use crate::ip_lookup::{service::{ExplainedMatch, SourceStatus}, snapshot::SnapshotInfo, CloudKind, IpCategory, IpLookupService};
 use crate::clients::web_api::WebApiClient;
+use crate::models::auth::AuthenticatedUser;
+use crate::config::{runtime::SharedRuntimeConfig, FeatureSettings, MissingDataPolicy};
+use crate::routes::openapi;
+use crate::utils::redact;
+use self::fields::{LookupParams, LookupProjection};
+use self::gate::{OrUnscored, WithVerdict};
+
+pub use infralock_types::{
+    LookupErrors, LookupRequest, LookupResponse, ProxyResponse, ThreatScoreResponse, TorResponse,
+};
 
 #[derive(Debug, Clone)]
 pub struct AppState {
-    pub maxmind_reader: Arc<RwLock<maxminddb::Reader<Vec<u8>>>>,
-    pub asn_reader: Arc<RwLock<maxminddb::Reader<Vec<u8>>>>, // ASN DB reader
-    pub lookup_cache: Arc<Cache<IpAddr, LookupResponse>>,
+    pub geo_provider: Arc<dyn GeoProvider>,
+    pub lookup_cache: Arc<LookupCache>,
+    /// Redis behind `lookup_cache`, shared with the other replicas
+    pub shared_cache: Option<Arc<SharedCache>>,
     pub ip_lookup_service: Arc<IpLookupService>,
     pub web_api_client: Arc<WebApiClient>,
+    /// Keys accepted without asking the web API
+    pub unlimited_api_keys: Arc<HashSet<String>>,
+    pub features: FeatureSettings,
+    /// Whether lookups of IPs without a city record fail with 404
+    pub require_geo: bool,
+    /// Whether 6to4 and Teredo addresses are also checked by their IPv4 origin
+    pub tunnel_extraction: bool,
+    /// Whether 6to4 addresses are looked up and cached as their IPv4 origin
+    pub canonicalize_6to4: bool,
+    /// How lookups treat missing or stale threat data
+    pub missing_data: MissingDataPolicy,
+    /// `category` of lookups that match no range
+    pub category_fallback: Option<Arc<str>>,
+    /// Scoring and response action settings, replaced on config reload
+    pub runtime: SharedRuntimeConfig,
+    pub config_reloader: Arc<ConfigReloader>,
+    /// Deadline for VPN and proxy range scans
+    pub range_scan_timeout: Duration,
+    /// Deadline for the geo and ASN lookups of every IP lookup
+    pub lookup_timeout: Option<Duration>,
+    /// Sunset date of the unversioned `/api/...` aliases; `None` serves
+    /// the API under `/api/v1` only
+    pub legacy_sunset: Option<chrono::NaiveDate>,
+    /// Whether lookup and gate responses always carry the verdict headers
+    pub verdict_headers: bool,
+    /// Most findings listed in a lookup response; `None` lists them all
+    pub max_findings: Option<usize>,
+    /// Fallback for self-lookups without a proxy header
+    pub on_missing_ip: MissingIpPolicy,
+    /// Most `X-Forwarded-For` entries a request may carry
+    pub max_forwarded_hops: usize,
+    /// Requests handled at once before new ones are shed with 503
+    pub max_concurrent_requests: usize,
+    /// Broadest ranges the range endpoints accept
+    pub cidr_limits: CidrLimits,
+    /// Per-API-key request counts, flushed for billing
+    pub usage: Arc<UsageAccounting>,
+    /// Logs the actions served, sampling allow and monitor
+    pub decision_log: Arc<DecisionLog>,
+    /// Forces monitor mode at runtime, set through the admin API
+    pub monitor_override: MonitorOverride,
+    /// Issues and checks challenge tokens; `None` unless `challenge.secret` is set
+    pub challenges: Option<Arc<ChallengeService>>,
+    /// Served lookups by country and ASN over the last two hours
+    pub aggregates: Arc<LookupAggregates>,
+    /// Whether served lookups answered with `block` are counted in `blocked_total`
+    pub blocked_metric: bool,
+    /// Probes the feed URLs and the web API on demand
+    pub connectivity: Arc<ConnectivityChecker>,
+    /// VPN/datacenter networks for the range endpoints and threat scores,
+    /// loaded at startup; `None` if the list could not be read
+    pub vpn_detector: Option<Arc<VpnDetector>>,
+    /// Proxy lists, likewise
+    pub proxy_detector: Option<Arc<ProxyDetector>>,
+    /// IPs whose lookups are recorded for `GET /api/admin/debug-capture/{ip}`
+    pub debug_capture: Arc<DebugCapture>,
 }
 
-#[derive(Debug, Serialize, Clone)]
-pub struct LookupResponse {
-    pub ip: String,
-    pub geo_info: Option<GeoInfo>,
-    pub asn_info: Option<AsnInfo>,
-    pub is_vpn_or_datacenter: bool,
-    pub is_proxy: bool,
-    pub proxy_type: Option<&'static str>,
-    pub is_tor_exit_node: bool,
-    pub threat_score: u8,  // 0-100 threat score
-    pub threat_details: Vec<String>,  // Descriptions of threats found
-    pub recommended_action: String,  // Recommended response action (allow/challenge/block/redirect/monitor)
+/// Query parameters accepted by the threat score endpoints
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ThreatScoreParams {
+    /// Include `score_precise`, the score before it is rounded to an integer
+    #[serde(default)]
+    pub precise: bool,
 }
 
-#[derive(Debug, Serialize)]
-pub struct ThreatScoreResponse {
-    pub ip: String,
-    pub threat_score: u8,
-    pub threat_details: Vec<String>,
+fn threat_score_response(threat_score: ThreatScore, params: &ThreatScoreParams) -> ThreatScoreResponse {
+    ThreatScoreResponse {
+        ip: threat_score.ip.to_string(),
+        threat_score: threat_score.score,
+        score_precise: params.precise.then_some(threat_score.score_precise),
+        threat_details: threat_score.findings.into_iter().map(|f| f.description).collect(),
+    }
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/v1/lookup/{ip}",
+    tag = "lookup",
+    params(
+        ("ip" = String, Path, description = "IPv4 or IPv6 address"),
+        LookupParams,
+        ("X-InfraLock-Deadline-Ms" = Option<u64>, Header, description = "Time budget in milliseconds, like `deadline_ms`"),
+        ("X-InfraLock-Headers" = Option<bool>, Header, description = "Also return the verdict in `X-InfraLock-*` response headers"),
+    ),
+    responses(
+        (status = 200, description = "Geo, ASN and threat data; `fields` narrows it to the selected keys", body = LookupResponse),
+        (status = 400, description = "Invalid or non-public IP address", body = openapi::ErrorBody),
+        (status = 404, description = "No geo record and `geo.require_geo` is set", body = openapi::ErrorBody),
+    )
+)]
 #[axum::debug_handler]
+#[tracing::instrument(name = "infralock.lookup", skip_all, fields(ip = %redact::text(&ip)))]
 pub async fn lookup_ip(
     Path(ip): Path<String>,
+    Query(params): Query<LookupParams>,
+    State(state): State<Arc<AppState>>,
+    profile: Option<Extension<ProfileName>>,
+    user: Option<Extension<AuthenticatedUser>>,
+    headers: HeaderMap,
+) -> Result<WithVerdict<Json<LookupProjection>>, AppError> {
+    let caller = LookupCaller { profile: profile.as_deref(), user: user.as_deref(), headers: &headers };
+    lookup_address(&ip, &params, &state, caller, "/api/lookup/{ip}").await
+}
+
+/// Like `GET /api/lookup/{ip}`, with the IP in the body so it stays out of
+/// access and proxy logs, which record the request line
+#[utoipa::path(
+    post,
+    path = "/api/v1/lookup",
+    tag = "lookup",
+    params(
+        LookupParams,
+        ("X-InfraLock-Deadline-Ms" = Option<u64>, Header, description = "Time budget in milliseconds, like `deadline_ms`"),
+        ("X-InfraLock-Headers" = Option<bool>, Header, description = "Also return the verdict in `X-InfraLock-*` response headers"),
+    ),
+    request_body = LookupRequest,
+    responses(
+        (status = 200, description = "Geo, ASN and threat data; `fields` narrows it to the selected keys", body = LookupResponse),
+        (status = 400, description = "Invalid or non-public IP address", body = openapi::ErrorBody),
+        (status = 404, description = "No geo record and `geo.require_geo` is set", body = openapi::ErrorBody),
+    )
+)]
+#[axum::debug_handler]
+#[tracing::instrument(name = "infralock.lookup", skip_all, fields(ip = %redact::text(&request.ip)))]
+pub async fn lookup_ip_body(
+    Query(params): Query<LookupParams>,
     State(state): State<Arc<AppState>>,
-) -> Result<Json<LookupResponse>, AppError> {
+    profile: Option<Extension<ProfileName>>,
+    user: Option<Extension<AuthenticatedUser>>,
+    headers: HeaderMap,
+    Json(request): Json<LookupRequest>,
+) -> Result<WithVerdict<Json<LookupProjection>>, AppError> {
+    let caller = LookupCaller { profile: profile.as_deref(), user: user.as_deref(), headers: &headers };
+    lookup_address(&request.ip, &params, &state, caller, "/api/lookup").await
+}
+
+/// Who is asking for a lookup, from the request's extensions and headers
+struct LookupCaller<'a> {
+    profile: Option<&'a ProfileName>,
+    user: Option<&'a AuthenticatedUser>,
+    headers: &'a HeaderMap,
+}
+
+/// Validate and look up `ip` for the lookup endpoints that name it
+async fn lookup_address(
+    ip: &str,
+    params: &LookupParams,
+    state: &AppState,
+    caller: LookupCaller<'_>,
+    endpoint: &'static str,
+) -> Result<WithVerdict<Json<LookupProjection>>, AppError> {
+    let deadline = params.deadline(caller.headers)?;
     let ip_addr: IpAddr = ip.parse()?;
     
     // IP validation
     if let Err(e) = validate_ip(ip_addr) {
-        tracing::warn!("Rejected IP lookup for {}: {}", ip_addr, e);
+        tracing::warn!("Rejected IP lookup for {}: {}", redact::ip(ip_addr), e);
         return Err(AppError::ValidationError(e));
     }
 
-    let lookup_service = LookupService::new(
-        Arc::clone(&state.maxmind_reader),
-        Arc::clone(&state.asn_reader),
-        state.lookup_cache.clone(),
-        Arc::clone(&state.ip_lookup_service),
-    );
+    let lookup_service = profile_lookup_service(state, caller.profile);
 
-    let response = lookup_service.lookup_ip(ip_addr).await?;
-    Ok(Json(response))
+    let (mut response, cache) = lookup_service.lookup_ip_with_status(ip_addr, deadline).await?;
+    settle_challenge(state, &mut response, caller.headers).await;
+    log_decision(state, &response, endpoint, caller.user);
+    let headers = gate::requested_verdict_headers(state, caller.headers, &response, cache);
+    Ok(WithVerdict(Json(LookupProjection::from_params(response, params)?), headers))
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/v1/lookup/self",
+    tag = "lookup",
+    params(
+        LookupParams,
+        ("X-InfraLock-Deadline-Ms" = Option<u64>, Header, description = "Time budget in milliseconds, like `deadline_ms`"),
+        ("X-InfraLock-Headers" = Option<bool>, Header, description = "Also return the verdict in `X-InfraLock-*` response headers"),
+    ),
+    responses(
+        (status = 200, description = "Lookup of the caller's address, from `X-Forwarded-For`, `X-Real-IP` or the peer", body = LookupResponse),
+        (status = 204, description = "No client IP and `on_missing_ip = allow`: unscored, with an `allow` verdict header"),
+        (status = 400, description = "Invalid or non-public IP address", body = openapi::ErrorBody),
+    )
+)]
 #[axum::debug_handler]
+#[tracing::instrument(name = "infralock.lookup_self", skip_all, fields(ip = tracing::field::Empty))]
 pub async fn lookup_self(
     State(state): State<Arc<AppState>>,
     request: Request<Body>,
-) -> Result<Json<LookupResponse>, AppError> {
+) -> Result<OrUnscored<WithVerdict<Json<LookupProjection>>>, AppError> {
+    let Query(params) = Query::<LookupParams>::try_from_uri(request.uri())
+        .map_err(|e| AppError::BadRequest(e.body_text()))?;
+    let deadline = params.deadline(request.headers())?;
+
+    let Some((ip_addr, ip_source)) = resolve_client_ip(&request, &state)? else {
+        return Ok(OrUnscored(None));
+    };
+
+    let lookup_service = profile_lookup_service(&state, request.extensions().get());
+
+    let (mut response, cache) = lookup_service.lookup_ip_with_status(ip_addr, deadline).await?;
+    settle_challenge(&state, &mut response, request.headers()).await;
+    tracing::debug!(
+        score = response.threat_score,
+        action = %response.recommended_action,
+        "Lookup response"
+    );
+    log_decision(&state, &response, "/api/lookup/self", request.extensions().get());
+    let headers = gate::requested_verdict_headers(&state, request.headers(), &response, cache);
+
+    let mut projection = LookupProjection::from_params(response, &params)?;
+    if params.debug {
+        projection.ip_source = Some(ip_source);
+    }
+    Ok(OrUnscored(Some(WithVerdict(Json(projection), headers))))
+}
+
+/// Resolves a `challenge` verdict when challenge tokens are enabled: a valid
+/// clearance for the IP in `X-InfraLock-Clearance` turns it into `allow`,
+/// otherwise the response carries a fresh challenge token
+async fn settle_challenge(state: &AppState, response: &mut LookupResponse, headers: &HeaderMap) {
+    let Some(challenges) = &state.challenges else {
+        return;
+    };
+    if response.recommended_action != "challenge" {
+        return;
+    }
+    let Ok(ip) = response.canonical_ip.parse::<IpAddr>() else {
+        return;
+    };
+
+    if let Some(clearance) = headers.get(gate::CLEARANCE_HEADER) {
+        let cleared = match clearance.to_str() {
+            Ok(token) => challenges.check_clearance(token, ip).await,
+            Err(_) => Err(ChallengeError::Malformed),
+        };
+        match cleared {
+            Ok(_) => {
+                response.recommended_action = "allow".to_string();
+                return;
+            }
+            Err(e) => tracing::debug!(ip = %redact::ip(ip), "Ignoring clearance: {}", e),
+        }
+    }
+    response.challenge_token = Some(challenges.issue_challenge(ip, response.threat_score));
+}
+
+/// Passes a served decision to the decision log
+fn log_decision(
+    state: &AppState,
+    response: &LookupResponse,
+    endpoint: &'static str,
+    user: Option<&AuthenticatedUser>,
+) {
+    let user_id = user.and_then(|user| user.user_id.as_deref());
+    state.decision_log.record(response, DecisionContext { endpoint, user_id });
+}
+
+/// Resolves and validates the caller's IP from proxy headers. Without one,
+/// `on_missing_ip` decides: an error, the peer address, or `None` to let the
+/// request through unscored.
+fn resolve_client_ip(
+    request: &Request<Body>,
+    state: &AppState,
+) -> Result<Option<(IpAddr, IpSource)>, AppError> {
     // First, check if we have any of the required headers
     let headers = request.headers();
     
     // Log available headers for debugging
     tracing::debug!("Available headers: {:?}", headers.keys().map(|h| h.as_str()).collect::<Vec<_>>());
     
-    // Extract and validate IP from headers
-    let ip_addr = extract_client_ip(headers)
-        .map_err(|e| {
+    // Extract and validate IP from headers, falling back to the peer address
+    let connect_info = request.extensions().get::<ConnectInfo<std::net::SocketAddr>>();
+    let extracted = extract_client_ip(headers, state.max_forwarded_hops);
+    let (ip_addr, ip_source) = match (extracted, state.on_missing_ip, connect_info) {
+        (Err(IpValidationError::MissingIpHeaders), MissingIpPolicy::UseConnectInfo, Some(ConnectInfo(addr))) => {
+            (addr.ip(), IpSource::ConnectInfo)
+        }
+        (Err(IpValidationError::MissingIpHeaders), MissingIpPolicy::Allow, _) => {
+            tracing::debug!("No client IP header, letting the request through");
+            return Ok(None);
+        }
+        (result, _, _) => result.map_err(|e| {
             tracing::warn!("IP extraction failed: {}", e);
             AppError::from(e)
-        })?;
+        })?,
+    };
 
     // Log the IP for debugging
-    tracing::debug!("Client IP: {}", ip_addr);
+    tracing::Span::current().record("ip", tracing::field::display(redact::ip(ip_addr)));
+    tracing::debug!("Client IP: {} (from {:?})", redact::ip(ip_addr), ip_source);
     
     // Validate the IP
     if let Err(e) = validate_ip(ip_addr) {
-        tracing::warn!("Rejected IP lookup for {}: {}", ip_addr, e);
+        tracing::warn!("Rejected IP lookup for {}: {}", redact::ip(ip_addr), e);
         return Err(AppError::from(e));
     }
 
-    let lookup_service = LookupService::new(
-        Arc::clone(&state.maxmind_reader),
-        Arc::clone(&state.asn_reader),
+    Ok(Some((ip_addr, ip_source)))
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ThreatScoreExplanationResponse {
+    pub ip: String,
+    pub threat_score: u8,
+    pub explanation: ScoreExplanation,
+    pub recommended_action: ResponseAction,
+    pub decision: ActionDecision,
+    /// Threat data was missing or stale, as in `/api/lookup`
+    pub data_unavailable: bool,
+}
+
+/// Builds a lookup service over the shared state and its configuration
+pub fn lookup_service(state: &AppState) -> LookupService {
+    let runtime = state.runtime.load();
+    LookupService::new(
+        Arc::clone(&state.geo_provider),
         state.lookup_cache.clone(),
         Arc::clone(&state.ip_lookup_service),
-    );
+        runtime.scoring_config.clone(),
+    )
+    .with_features(state.features)
+    .with_require_geo(state.require_geo)
+    .with_tunnel_extraction(state.tunnel_extraction)
+    .with_6to4_canonicalization(state.canonicalize_6to4)
+    .with_missing_data_policy(state.missing_data)
+    .with_category_fallback(state.category_fallback.clone())
+    .with_timeout(state.lookup_timeout)
+    .with_max_findings(state.max_findings)
+    .with_response_action_config(runtime.response_action_config.clone())
+    .with_monitor_override(state.monitor_override.clone())
+    .with_debug_capture(Arc::clone(&state.debug_capture), runtime.generation)
+    // A forced monitor mode is this replica's alone, so its verdicts must
+    // neither reach the other replicas nor be answered by theirs
+    .with_shared_cache(state.shared_cache.clone().filter(|_| !state.monitor_override.is_forced()))
+}
 
-    let response = lookup_service.lookup_ip(ip_addr).await?;
-    tracing::debug!("Response: {:#?}", response);
+/// Drops every cached lookup, on every replica when the cache is shared
+async fn purge_lookups(state: &AppState) {
+    match &state.shared_cache {
+        Some(shared_cache) => shared_cache.invalidate().await,
+        None => state.lookup_cache.invalidate_all(),
+    }
+}
 
-    Ok(Json(response))
+/// The scoring profile for a request the auth middleware tagged with a profile name
+fn request_profile(state: &AppState, name: Option<&ProfileName>) -> Option<Arc<ScoringProfile>> {
+    name.map(|ProfileName(key)| state.runtime.load().profiles.by_key(key))
 }
 
+/// Builds a lookup service that scores with the request's profile, if it has one
+pub fn profile_lookup_service(state: &AppState, name: Option<&ProfileName>) -> LookupService {
+    let service = lookup_service(state)
+        .with_aggregates(Some(Arc::clone(&state.aggregates)))
+        .with_blocked_metric(state.blocked_metric);
+    match request_profile(state, name) {
+        Some(profile) => service.with_profile(&profile),
+        None => service,
+    }
+}
+
+/// Checks the radix tree for a Tor exit node entry
+fn is_tor_in_tree(state: &AppState, ip_addr: IpAddr) -> bool {
+    state.ip_lookup_service.tree().lookup(canonical_ip(ip_addr)) == Some(IpCategory::TorExitNode)
+}
+
+/// The detector loaded at startup, or a 500 naming the list that is missing
+fn loaded<T>(detector: &Option<Arc<T>>, list: &str) -> Result<Arc<T>, AppError> {
+    detector.clone().ok_or_else(|| {
+        AppError::IoError(std::io::Error::other(format!(
+            "The {} list was not loaded at startup",
+            list
+        )))
+    })
+}
+
+/// Scores an IP the way `/api/lookup` does, under the request's profile
+fn request_threat_score(
+    state: &AppState,
+    ip_addr: IpAddr,
+    profile: Option<&ProfileName>,
+) -> Result<ThreatScore, AppError> {
+    let threat_score = profile_lookup_service(state, profile).threat_score(ip_addr)?;
+    tracing::Span::current().record("score", threat_score.score);
+    Ok(threat_score)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/threat-score/{ip}",
+    tag = "threat-score",
+    params(("ip" = String, Path, description = "IPv4 or IPv6 address"), ThreatScoreParams),
+    responses(
+        (status = 200, body = ThreatScoreResponse),
+        (status = 400, description = "Invalid or non-public IP address", body = openapi::ErrorBody),
+    )
+)]
 #[axum::debug_handler]
+#[tracing::instrument(
+    name = "infralock.threat_score",
+    skip_all,
+    fields(ip = %redact::text(&ip), score = tracing::field::Empty)
+)]
 pub async fn get_threat_score(
     Path(ip): Path<String>,
+    Query(params): Query<ThreatScoreParams>,
+    State(state): State<Arc<AppState>>,
+    profile: Option<Extension<ProfileName>>,
 ) -> Result<Json<ThreatScoreResponse>, AppError> {
     let ip_addr: IpAddr = ip.parse().map_err(|_| {
         AppError::from(std::io::Error::new(
@@ -132,96 +483,287 @@ pub async fn get_threat_score(
 
     // IP validation
     if let Err(e) = validate_ip(ip_addr) {
-        tracing::warn!("Rejected IP lookup for {}: {}", ip_addr, e);
+        tracing::warn!("Rejected IP lookup for {}: {}", redact::ip(ip_addr), e);
         return Err(AppError::ValidationError(e));
     }
 
-    // Get the necessary detection results
-    let vpn_detector = VpnDetector::get();
-    let is_vpn = vpn_detector.is_vpn_or_datacenter(ip_addr);
-    
-    let proxy_detector = ProxyDetector::get();
-    let proxy_type = proxy_detector.check_proxy(ip_addr);
-    let is_proxy = proxy_type.is_some();
-    
-    let tor_detector = TorDetector::get();
-    let is_tor = tor_detector.is_tor_exit_node(ip_addr);
-
-    // Calculate threat score
-    let threat_score = ThreatScore::from_ip_info(
-        ip_addr,
-        is_vpn,
-        is_proxy,
-        proxy_type,
-        is_tor,
-    );
+    let threat_score = request_threat_score(&state, ip_addr, profile.as_deref())?;
 
-    // Extract threat details
-    let threat_details = threat_score.findings
-        .iter()
-        .map(|f| f.description.clone())
-        .collect();
+    Ok(Json(threat_score_response(threat_score, &params)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/threat-score/self",
+    tag = "threat-score",
+    params(ThreatScoreParams),
+    responses(
+        (status = 200, description = "Threat score of the caller's address, resolved like `/api/lookup/self`", body = ThreatScoreResponse),
+        (status = 204, description = "No client IP and `on_missing_ip = allow`: unscored, with an `allow` verdict header"),
+        (status = 400, description = "Invalid or non-public IP address", body = openapi::ErrorBody),
+    )
+)]
+#[axum::debug_handler]
+#[tracing::instrument(
+    name = "infralock.threat_score_self",
+    skip_all,
+    fields(ip = tracing::field::Empty, score = tracing::field::Empty)
+)]
+pub async fn get_self_threat_score(
+    State(state): State<Arc<AppState>>,
+    request: Request<Body>,
+) -> Result<OrUnscored<Json<ThreatScoreResponse>>, AppError> {
+    let Query(params) = Query::<ThreatScoreParams>::try_from_uri(request.uri())
+        .map_err(|e| AppError::BadRequest(e.body_text()))?;
+
+    let Some((ip_addr, _)) = resolve_client_ip(&request, &state)? else {
+        return Ok(OrUnscored(None));
+    };
+
+    let threat_score = request_threat_score(&state, ip_addr, request.extensions().get())?;
 
-    Ok(Json(ThreatScoreResponse {
+    Ok(OrUnscored(Some(Json(threat_score_response(threat_score, &params)))))
+}
+
+/// Returns the full computation behind an IP's threat score and recommended action
+#[utoipa::path(
+    get,
+    path = "/api/v1/threat-score/{ip}/explain",
+    tag = "threat-score",
+    params(("ip" = String, Path, description = "IPv4 or IPv6 address")),
+    responses(
+        (status = 200, description = "Every finding's contribution, the model's intermediate values and the rule behind the action", body = ThreatScoreExplanationResponse),
+        (status = 400, description = "Invalid or non-public IP address", body = openapi::ErrorBody),
+    )
+)]
+#[axum::debug_handler]
+#[tracing::instrument(
+    name = "infralock.threat_score_explain",
+    skip_all,
+    fields(ip = %redact::text(&ip), score = tracing::field::Empty, action = tracing::field::Empty)
+)]
+pub async fn explain_threat_score(
+    Path(ip): Path<String>,
+    State(state): State<Arc<AppState>>,
+    profile_name: Option<Extension<ProfileName>>,
+) -> Result<Json<ThreatScoreExplanationResponse>, AppError> {
+    let ip_addr: IpAddr = ip.parse()?;
+
+    // IP validation
+    if let Err(e) = validate_ip(ip_addr) {
+        tracing::warn!("Rejected IP lookup for {}: {}", redact::ip(ip_addr), e);
+        return Err(AppError::ValidationError(e));
+    }
+
+    let profile = request_profile(&state, profile_name.as_deref());
+    let runtime = state.runtime.load();
+    let scoring_config = profile.as_ref().map_or(&runtime.scoring_config, |p| &p.scoring);
+    let lookup_service = profile_lookup_service(&state, profile_name.as_deref());
+    let threat_score = lookup_service.threat_score(ip_addr)?;
+    tracing::Span::current().record("score", threat_score.score);
+    let explanation = threat_score.explain(scoring_config);
+    let (recommended_action, decision, data_unavailable) = lookup_service.decide(&threat_score);
+    tracing::Span::current().record("action", format!("{:?}", recommended_action).to_lowercase());
+
+    Ok(Json(ThreatScoreExplanationResponse {
         ip: ip_addr.to_string(),
         threat_score: threat_score.score,
-        threat_details,
+        explanation,
+        recommended_action,
+        decision,
+        data_unavailable,
     }))
 }
 
+/// Query parameters accepted by the action endpoint
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ActionParams {
+    /// Comma-separated threat types behind the score, e.g. `TorExitNode,Proxy`,
+    /// checked against `block_immediate`
+    #[serde(default)]
+    pub threat_types: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ActionResponse {
+    pub score: u8,
+    pub recommended_action: ResponseAction,
+    pub decision: ActionDecision,
+}
+
+/// Maps a score computed by the caller to the action the current policy
+/// recommends, without a lookup
+#[utoipa::path(
+    get,
+    path = "/api/v1/action/{score}",
+    tag = "threat-score",
+    params(("score" = u8, Path, description = "Threat score, 0-100"), ActionParams),
+    responses(
+        (status = 200, body = ActionResponse),
+        (status = 400, description = "Score out of range or unknown threat type", body = openapi::ErrorBody),
+    )
+)]
 #[axum::debug_handler]
-pub async fn get_self_threat_score(
-    ConnectInfo(addr): ConnectInfo<std::net::SocketAddr>,
-) -> Result<Json<ThreatScoreResponse>, AppError> {
-    let ip_addr: IpAddr = addr.ip().to_string().parse().map_err(|_| {
-        AppError::from(std::io::Error::new(
-            std::io::ErrorKind::InvalidInput,
-            "Invalid IP address format",
-        ))
+pub async fn action_for_score(
+    Path(score): Path<String>,
+    Query(params): Query<ActionParams>,
+    State(state): State<Arc<AppState>>,
+    profile: Option<Extension<ProfileName>>,
+) -> Result<Json<ActionResponse>, AppError> {
+    let score = match score.parse::<u8>() {
+        Ok(score) if score <= 100 => score,
+        _ => return Err(AppError::BadRequest(format!("Score must be an integer from 0 to 100, got '{}'", score))),
+    };
+    let threat_types = parse_threat_types(params.threat_types.as_deref().unwrap_or_default())?;
+
+    let profile = request_profile(&state, profile.as_deref());
+    let runtime = state.runtime.load();
+    let response_action_config = profile.as_ref().map_or(&runtime.response_action_config, |p| &p.response_action);
+    let (recommended_action, decision) = ResponseActionService::with_config(response_action_config.clone())
+        .with_monitor_override(&state.monitor_override)
+        .decide_score(score, &threat_types);
+
+    Ok(Json(ActionResponse { score, recommended_action, decision }))
+}
+
+/// `TorExitNode,Proxy` as threat types, spelled as in `block_immediate`
+fn parse_threat_types(list: &str) -> Result<Vec<ThreatType>, AppError> {
+    use serde::de::IntoDeserializer;
+
+    list.split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            ThreatType::deserialize(name.into_deserializer())
+                .map_err(|e: serde::de::value::Error| AppError::BadRequest(format!("Invalid threat type: {}", e)))
+        })
+        .collect()
+}
+
+/// Request body for redeeming a challenge token
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ChallengeVerifyRequest {
+    pub token: String,
+    /// Proof-of-work nonce, or the token itself at difficulty 0
+    pub solution: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ChallengeVerifyResponse {
+    /// Send as `X-InfraLock-Clearance` on later lookups of `ip`
+    pub clearance_token: String,
+    #[schema(value_type = String)]
+    pub ip: IpAddr,
+    #[schema(value_type = String, format = DateTime)]
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Exchanges a solved challenge token for a clearance token
+#[utoipa::path(
+    post,
+    path = "/api/v1/challenge/verify",
+    tag = "threat-score",
+    request_body = ChallengeVerifyRequest,
+    responses(
+        (status = 200, body = ChallengeVerifyResponse),
+        (status = 400, description = "Malformed challenge token", body = openapi::ErrorBody),
+        (status = 403, description = "Wrong solution, or an expired, tampered or already redeemed token", body = openapi::ErrorBody),
+        (status = 404, description = "Challenge tokens are not enabled", body = openapi::ErrorBody),
+    )
+)]
+pub async fn verify_challenge(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<ChallengeVerifyRequest>,
+) -> Result<Json<ChallengeVerifyResponse>, AppError> {
+    let challenges = state
+        .challenges
+        .as_ref()
+        .ok_or_else(|| AppError::NotFound("Challenge tokens are not enabled".to_string()))?;
+    let (clearance_token, claims) = challenges.redeem(&request.token, &request.solution).await.map_err(|e| match e {
+        ChallengeError::Malformed => AppError::BadRequest(format!("Invalid challenge token: {}", e)),
+        e => AppError::Forbidden(format!("Challenge not passed: {}", e)),
     })?;
-    
+
+    Ok(Json(ChallengeVerifyResponse {
+        clearance_token,
+        ip: claims.ip,
+        expires_at: chrono::DateTime::from_timestamp(claims.exp, 0).ok_or(AppError::InternalServerError)?,
+    }))
+}
+
+/// Request body for previewing the action a hypothetical config would produce
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SimulateRequest {
+    pub ip: String,
+    #[serde(default)]
+    pub response_action_config: ResponseActionConfig,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SimulateResponse {
+    pub ip: String,
+    pub threat_score: u8,
+    pub threat_details: Vec<String>,
+    pub recommended_action: ResponseAction,
+}
+
+/// Computes the action `response_action_config` would produce for an IP
+/// without touching the live configuration.
+#[utoipa::path(
+    post,
+    path = "/api/v1/simulate",
+    tag = "threat-score",
+    request_body = SimulateRequest,
+    responses(
+        (status = 200, body = SimulateResponse),
+        (status = 400, description = "Invalid or non-public IP address", body = openapi::ErrorBody),
+    )
+)]
+#[axum::debug_handler]
+pub async fn simulate_action(
+    State(state): State<Arc<AppState>>,
+    profile: Option<Extension<ProfileName>>,
+    Json(request): Json<SimulateRequest>,
+) -> Result<Json<SimulateResponse>, AppError> {
+    let ip_addr: IpAddr = request.ip.parse()?;
+
     // IP validation
     if let Err(e) = validate_ip(ip_addr) {
-        tracing::warn!("Rejected IP lookup for {}: {}", ip_addr, e);
+        tracing::warn!("Rejected simulation for {}: {}", redact::ip(ip_addr), e);
         return Err(AppError::ValidationError(e));
     }
 
-    // Get the necessary detection results
-    let vpn_detector = VpnDetector::get();
-    let is_vpn = vpn_detector.is_vpn_or_datacenter(ip_addr);
-    
-    let proxy_detector = ProxyDetector::get();
-    let proxy_type = proxy_detector.check_proxy(ip_addr);
-    let is_proxy = proxy_type.is_some();
-    
-    let tor_detector = TorDetector::get();
-    let is_tor = tor_detector.is_tor_exit_node(ip_addr);
-
-    // Calculate threat score
-    let threat_score = ThreatScore::from_ip_info(
-        ip_addr,
-        is_vpn,
-        is_proxy,
-        proxy_type,
-        is_tor,
-    );
+    let threat_score = request_threat_score(&state, ip_addr, profile.as_deref())?;
 
-    // Extract threat details
-    let threat_details = threat_score.findings
-        .iter()
-        .map(|f| f.description.clone())
-        .collect();
+    let response_action_service = ResponseActionService::with_config(request.response_action_config);
+    let recommended_action = response_action_service.determine_action(&threat_score);
 
-    Ok(Json(ThreatScoreResponse {
+    Ok(Json(SimulateResponse {
         ip: ip_addr.to_string(),
         threat_score: threat_score.score,
-        threat_details,
+        threat_details: threat_score.findings
+            .iter()
+            .map(|f| f.description.clone())
+            .collect(),
+        recommended_action,
     }))
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/v1/tor/{ip_or_range}",
+    tag = "ranges",
+    params(("ip_or_range" = String, Path, description = "IP address or CIDR range, with `/` encoded as `%2F`")),
+    responses(
+        (status = 200, description = "For a range, whether any Tor exit node is inside it", body = TorResponse),
+        (status = 400, description = "Invalid IP address, or a malformed or too-broad range", body = openapi::ErrorBody),
+    )
+)]
 #[axum::debug_handler]
 pub async fn is_tor_exit_node(
     Path(ip_or_range): Path<String>,
+    State(state): State<Arc<AppState>>,
 ) -> Result<Json<TorResponse>, AppError> {
     // URL decode the path parameter to handle %2F in the URL
     let decoded = percent_decode_str(&ip_or_range)
@@ -231,29 +773,36 @@ pub async fn is_tor_exit_node(
             "Failed to decode URL-encoded input"
         )))?;
     
-    let detector = TorDetector::get();
-    
     // Try to parse as a single IP
     if let Ok(ip_addr) = decoded.parse::<IpAddr>() {
-        let is_tor = detector.is_tor_exit_node(ip_addr);
-        return Ok(Json(TorResponse { is_tor_exit_node: is_tor }));
+        let is_tor = is_tor_in_tree(&state, ip_addr);
+        return Ok(Json(TorResponse { is_tor_exit_node: is_tor, network: None }));
     }
-    
-    // If we get here, it's not a valid IP
-    Err(AppError::from(std::io::Error::new(
-        std::io::ErrorKind::InvalidInput,
-        format!("Invalid IP address format: '{}'", decoded),
-    )))
-}
 
-#[derive(Debug, Serialize)]
-pub struct TorResponse {
-    pub is_tor_exit_node: bool,
+    let network = validate_cidr(&decoded, state.cidr_limits)?;
+    let tree_network = ip_network::IpNetwork::new(network.network(), network.prefix())
+        .map_err(|_| AppError::InternalServerError)?;
+    let is_tor = state.ip_lookup_service.tree().overlaps(tree_network, IpCategory::TorExitNode);
+    Ok(Json(TorResponse {
+        is_tor_exit_node: is_tor,
+        network: Some(network.to_string()),
+    }))
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/v1/vpn/{ip_or_range}",
+    tag = "ranges",
+    params(("ip_or_range" = String, Path, description = "IP address or CIDR range, with `/` encoded as `%2F`")),
+    responses(
+        (status = 200, description = "`is_vpn/datacenter: <bool>` for an IP; `contains_vpn/datacenter: <bool>` and a `network: <range>` line with the range that was checked for a range", body = String, content_type = "text/plain"),
+        (status = 400, description = "Invalid IP address, or a malformed or too-broad range", body = openapi::ErrorBody),
+    )
+)]
 #[axum::debug_handler]
 pub async fn is_vpn_or_datacenter(
     Path(ip_or_range): Path<String>,
+    State(state): State<Arc<AppState>>,
 ) -> Result<String, AppError> {
     // URL decode the path parameter to handle %2F in the URL
     let decoded = percent_decode_str(&ip_or_range)
@@ -263,7 +812,7 @@ pub async fn is_vpn_or_datacenter(
             "Failed to decode URL-encoded input"
         )))?;
     
-    let detector = VpnDetector::get();
+    let detector = loaded(&state.vpn_detector, "VPN")?;
     
     // First try to parse as a single IP (Ex. 192.168.1.100)
     if let Ok(ip_addr) = decoded.parse::<IpAddr>() {
@@ -271,27 +820,32 @@ pub async fn is_vpn_or_datacenter(
         return Ok(format!("is_vpn/datacenter: {}", is_vpn));
     }
     
-    // If that fails, try to parse as a network range (Ex. 192.168.1.0/24)
-    if let Some(is_vpn) = detector.is_range_vpn_or_datacenter(&decoded) {
-        return Ok(format!("contains_vpn/datacenter: {}", is_vpn));
-    }
-    
-    // If we get here, it's not a valid IP or network range
-    Err(AppError::from(std::io::Error::new(
-        std::io::ErrorKind::InvalidInput,
-        format!("Invalid IP address or network range format: '{}'. Expected format: '1.2.3.4' or '1.2.3.0/24'", decoded),
-    )))
-}
-
-#[derive(Debug, Serialize)]
-pub struct ProxyResponse {
-    pub is_proxy: bool,
-    pub proxy_type: Option<&'static str>,
+    // If that fails, try to parse as a network range (Ex. 192.168.1.0/24).
+    // Range scans walk every address, so they run off the async workers.
+    let network = validate_cidr(&decoded, state.cidr_limits)?;
+    let deadline = Instant::now() + state.range_scan_timeout;
+    let range = network.to_string();
+    let result = tokio::task::spawn_blocking(move || detector.is_range_vpn_or_datacenter(&range, deadline))
+        .await
+        .map_err(|_| AppError::InternalServerError)?;
+    let is_vpn = result.ok_or(AppError::InternalServerError)?;
+    Ok(format!("contains_vpn/datacenter: {}\nnetwork: {}", is_vpn, network))
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/v1/proxy/{ip_or_range}",
+    tag = "ranges",
+    params(("ip_or_range" = String, Path, description = "IP address or CIDR range, with `/` encoded as `%2F`")),
+    responses(
+        (status = 200, description = "`proxy_type` is only set for single IPs, `network` only for ranges", body = ProxyResponse),
+        (status = 400, description = "Invalid IP address, or a malformed or too-broad range", body = openapi::ErrorBody),
+    )
+)]
 #[axum::debug_handler]
 pub async fn is_proxy(
     Path(ip_or_range): Path<String>,
+    State(state): State<Arc<AppState>>,
 ) -> Result<Json<ProxyResponse>, AppError> {
     // URL decode the path parameter
     let decoded = percent_decode_str(&ip_or_range)
@@ -301,42 +855,589 @@ pub async fn is_proxy(
             "Failed to decode URL-encoded input"
         )))?;
     
-    let detector = ProxyDetector::get();
+    let detector = loaded(&state.proxy_detector, "proxy")?;
     
     // First try to parse as a single IP
     if let Ok(ip_addr) = decoded.parse::<IpAddr>() {
         let proxy_type = detector.check_proxy(ip_addr);
         return Ok(Json(ProxyResponse {
             is_proxy: proxy_type.is_some(),
-            proxy_type,
+            proxy_type: proxy_type.map(str::to_string),
+            network: None,
         }));
     }
     
-    // If that fails, try to parse as a network range
-    if let Some(contains_proxy) = detector.is_range_proxy(&decoded) {
-        return Ok(Json(ProxyResponse {
-            is_proxy: contains_proxy,
-            proxy_type: None, // We don't have type information for ranges
-        }));
+    // If that fails, try to parse as a network range, off the async workers
+    let network = validate_cidr(&decoded, state.cidr_limits)?;
+    let deadline = Instant::now() + state.range_scan_timeout;
+    let range = network.to_string();
+    let result = tokio::task::spawn_blocking(move || detector.is_range_proxy(&range, deadline))
+        .await
+        .map_err(|_| AppError::InternalServerError)?;
+    let contains_proxy = result.ok_or(AppError::InternalServerError)?;
+    Ok(Json(ProxyResponse {
+        is_proxy: contains_proxy,
+        proxy_type: None, // We don't have type information for ranges
+        network: Some(network.to_string()),
+    }))
+}
+
+/// Largest `ips` list accepted by `/api/is_in_ranges`
+const MAX_MEMBERSHIP_IPS: usize = 10_000;
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RangeMembershipRequest {
+    pub ips: Vec<String>,
+    /// A tree category (`vpn`, `tor`, `socks5`, `aws`, ...), or `proxy` / `cloud`
+    /// for any proxy or cloud provider category
+    pub category: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RangeMembershipResponse {
+    pub category: String,
+    /// One entry per requested IP, in request order
+    pub results: Vec<RangeMembership>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RangeMembership {
+    pub ip: String,
+    pub in_range: bool,
+}
+
+/// The tree categories a membership `category` name stands for
+fn membership_categories(name: &str) -> Result<Vec<IpCategory>, AppError> {
+    match name.to_lowercase().as_str() {
+        "proxy" => Ok(vec![IpCategory::ProxyHttp, IpCategory::ProxySocks4, IpCategory::ProxySocks5]),
+        "cloud" => Ok([CloudKind::Aws, CloudKind::Gcp, CloudKind::Azure, CloudKind::Oci]
+            .into_iter()
+            .map(IpCategory::CloudProvider)
+            .collect()),
+        _ => name
+            .parse()
+            .map(|category| vec![category])
+            .map_err(|_| AppError::BadRequest(format!("Unknown category: '{}'", name))),
     }
-    
-    // If we get here, it's not a valid IP or network range
-    Err(AppError::from(std::io::Error::new(
-        std::io::ErrorKind::InvalidInput,
-        format!("Invalid IP address or network range format: '{}'. Expected format: '1.2.3.4' or '1.2.3.0/24'", decoded),
-    )))
+}
+
+/// Checks each IP against one category's ranges. Every containing network
+/// counts, so an IP inside a VPN range is a member even when a more
+/// specific proxy entry also covers it.
+#[utoipa::path(
+    post,
+    path = "/api/v1/is_in_ranges",
+    tag = "ranges",
+    request_body = RangeMembershipRequest,
+    responses(
+        (status = 200, body = RangeMembershipResponse),
+        (status = 400, description = "Unknown category, invalid IP or too many IPs", body = openapi::ErrorBody),
+    )
+)]
+#[axum::debug_handler]
+pub async fn is_in_ranges(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<RangeMembershipRequest>,
+) -> Result<Json<RangeMembershipResponse>, AppError> {
+    if request.ips.len() > MAX_MEMBERSHIP_IPS {
+        return Err(AppError::BadRequest(format!(
+            "At most {} IPs per request, got {}",
+            MAX_MEMBERSHIP_IPS,
+            request.ips.len()
+        )));
+    }
+    let wanted = membership_categories(&request.category)?;
+
+    let tree = state.ip_lookup_service.tree();
+    let results = request
+        .ips
+        .into_iter()
+        .map(|ip| {
+            let ip_addr: IpAddr = ip
+                .parse()
+                .map_err(|_| AppError::BadRequest(format!("Invalid IP address: '{}'", ip)))?;
+            let in_range = tree.lookup_all(canonical_ip(ip_addr)).iter().any(|category| wanted.contains(category));
+            Ok(RangeMembership { ip, in_range })
+        })
+        .collect::<Result<_, AppError>>()?;
+
+    Ok(Json(RangeMembershipResponse {
+        category: request.category,
+        results,
+    }))
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RangeCountResponse {
+    pub v4: usize,
+    pub v6: usize,
+    pub total: usize,
+    pub per_category: BTreeMap<String, usize>,
+}
+
+/// Counts the networks in the live tree, for dashboards and deploy checks
+#[utoipa::path(
+    get,
+    path = "/api/v1/ranges/count",
+    tag = "ranges",
+    responses((status = 200, body = RangeCountResponse))
+)]
+#[axum::debug_handler]
+pub async fn count_ranges(State(state): State<Arc<AppState>>) -> Json<RangeCountResponse> {
+    let tree = state.ip_lookup_service.tree();
+    let (v4, v6) = tree.len();
+    let per_category = tree
+        .category_counts()
+        .into_iter()
+        .map(|(category, count)| (category.to_string(), count))
+        .collect();
+
+    Json(RangeCountResponse {
+        v4,
+        v6,
+        total: v4 + v6,
+        per_category,
+    })
+}
+
+/// Re-reads the geo databases from disk and clears cached lookups
+#[axum::debug_handler]
+pub async fn reload_geo(
+    State(state): State<Arc<AppState>>,
+    user: Option<Extension<AuthenticatedUser>>,
+) -> Result<Json<crate::geo::ProviderMetadata>, AppError> {
+    require_admin(user.as_deref())?;
+    let provider = Arc::clone(&state.geo_provider);
+    let metadata = tokio::task::spawn_blocking(move || {
+        provider.reload().map(|()| provider.metadata())
+    })
+    .await
+    .map_err(|_| AppError::InternalServerError)??;
+    purge_lookups(&state).await;
+    Ok(Json(metadata))
+}
+
+/// Re-reads the configuration and applies the hot-swappable settings
+#[axum::debug_handler]
+pub async fn reload_config(
+    State(state): State<Arc<AppState>>,
+    user: Option<Extension<AuthenticatedUser>>,
+) -> Result<Json<ReloadReport>, AppError> {
+    require_admin(user.as_deref())?;
+    let reloader = Arc::clone(&state.config_reloader);
+    let report = tokio::task::spawn_blocking(move || reloader.reload())
+        .await
+        .map_err(|_| AppError::InternalServerError)??;
+    Ok(Json(report))
+}
+
+/// Lists the configured IP range sources with their last accepted count and feed diff
+#[axum::debug_handler]
+pub async fn list_sources(
+    State(state): State<Arc<AppState>>,
+    user: Option<Extension<AuthenticatedUser>>,
+) -> Result<Json<Vec<SourceStatus>>, AppError> {
+    require_admin(user.as_deref())?;
+    Ok(Json(state.ip_lookup_service.source_status()))
+}
+
+/// Networks the feeds disagree on in the live tree, from its last rebuild
+#[axum::debug_handler]
+pub async fn category_overlaps(
+    State(state): State<Arc<AppState>>,
+    user: Option<Extension<AuthenticatedUser>>,
+) -> Result<Json<OverlapReport>, AppError> {
+    require_admin(user.as_deref())?;
+    state
+        .ip_lookup_service
+        .overlap_report()
+        .map(Json)
+        .ok_or_else(|| AppError::NotFound("No tree has been built from the feeds yet".to_string()))
+}
+
+/// Per-API-key request counts in the current usage window
+#[axum::debug_handler]
+pub async fn usage_snapshot(
+    State(state): State<Arc<AppState>>,
+    user: Option<Extension<AuthenticatedUser>>,
+) -> Result<Json<UsageSnapshot>, AppError> {
+    require_admin(user.as_deref())?;
+    Ok(Json(state.usage.snapshot()))
+}
+
+/// Query parameters accepted by the aggregate statistics endpoint
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AggregateParams {
+    /// How far back to count, e.g. `15m` or `1h`; at most `2h`
+    #[serde(default = "AggregateParams::default_window")]
+    pub window: String,
+    /// Countries and ASNs listed individually; the rest are summed under `other`
+    #[serde(default = "AggregateParams::default_limit")]
+    pub limit: usize,
+}
+
+impl AggregateParams {
+    fn default_window() -> String {
+        "1h".to_string()
+    }
+
+    fn default_limit() -> usize {
+        10
+    }
+}
+
+/// Served lookups and flagged lookups by country and ASN over a recent window
+#[utoipa::path(
+    get,
+    path = "/api/v1/stats/aggregates",
+    tag = "lookup",
+    params(AggregateParams),
+    responses(
+        (status = 200, body = AggregateStats),
+        (status = 400, description = "Invalid window", body = openapi::ErrorBody),
+    )
+)]
+#[axum::debug_handler]
+pub async fn aggregate_stats(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<AggregateParams>,
+) -> Result<Json<AggregateStats>, AppError> {
+    let window = aggregates::parse_window(&params.window).ok_or_else(|| {
+        AppError::BadRequest(format!("Invalid window '{}', expected e.g. 15m or 1h", params.window))
+    })?;
+    Ok(Json(state.aggregates.stats(window, params.limit)))
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConnectivityReport {
+    /// Whether every target answered with a success or redirect status
+    pub ok: bool,
+    pub targets: Vec<TargetReport>,
+}
+
+/// Checks that every configured feed URL and the web API are reachable
+#[axum::debug_handler]
+pub async fn check_connectivity(
+    State(state): State<Arc<AppState>>,
+    user: Option<Extension<AuthenticatedUser>>,
+) -> Result<Json<ConnectivityReport>, AppError> {
+    require_admin(user.as_deref())?;
+    let targets = state.connectivity.check().await;
+    Ok(Json(ConnectivityReport {
+        ok: targets.iter().all(|target| target.status == ProbeStatus::Ok),
+        targets,
+    }))
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExplainResponse {
+    pub ip: String,
+    pub canonical_ip: String,
+    /// Every network containing the IP, the one lookups report first
+    pub matches: Vec<ExplainedMatch>,
+}
+
+/// Lists every network that flags an IP, with its source and when that
+/// source last changed, for tracing a false positive to its feed entry
+#[axum::debug_handler]
+pub async fn explain_ip(
+    Path(ip): Path<String>,
+    State(state): State<Arc<AppState>>,
+    user: Option<Extension<AuthenticatedUser>>,
+) -> Result<Json<ExplainResponse>, AppError> {
+    require_admin(user.as_deref())?;
+    let ip_addr: IpAddr = ip.parse()?;
+    let canonical = canonical_ip(ip_addr);
+    Ok(Json(ExplainResponse {
+        ip: ip_addr.to_string(),
+        canonical_ip: canonical.to_string(),
+        matches: state.ip_lookup_service.explain(canonical),
+    }))
+}
+
+/// Body of `POST /api/admin/debug-capture`
+#[derive(Debug, Deserialize)]
+pub struct DebugCaptureRequest {
+    pub ip: String,
+    /// How long to record the IP's lookups, 10 minutes by default
+    #[serde(default = "default_capture_secs")]
+    pub duration_secs: u64,
+}
+
+fn default_capture_secs() -> u64 {
+    600
+}
+
+#[derive(Debug, Serialize)]
+pub struct DebugCaptureResponse {
+    pub ip: String,
+    /// When recording stops; `None` once the watch has ended
+    pub watched_until: Option<chrono::DateTime<chrono::Utc>>,
+    /// Recorded lookups, oldest first. Kept after the watch ends, until
+    /// newer records of any IP push them out.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub records: Option<Vec<CaptureRecord>>,
+}
+
+/// Records every lookup of an IP for a while, with the cache, tree, scoring
+/// and config state behind its verdict
+#[axum::debug_handler]
+pub async fn start_debug_capture(
+    State(state): State<Arc<AppState>>,
+    user: Option<Extension<AuthenticatedUser>>,
+    Json(request): Json<DebugCaptureRequest>,
+) -> Result<Json<DebugCaptureResponse>, AppError> {
+    let actor = require_admin(user.as_deref())?;
+    let ip_addr: IpAddr = request.ip.parse()?;
+    let duration = Duration::from_secs(request.duration_secs);
+    if duration.is_zero() || duration > debug_capture::MAX_WATCH_DURATION {
+        return Err(AppError::BadRequest(format!(
+            "duration_secs must be between 1 and {}",
+            debug_capture::MAX_WATCH_DURATION.as_secs()
+        )));
+    }
+    let until = state.debug_capture.watch(ip_addr, duration).ok_or_else(|| {
+        AppError::BadRequest(format!(
+            "{} IPs are already being captured; wait for a capture to end",
+            debug_capture::MAX_WATCHES
+        ))
+    })?;
+    tracing::info!(
+        target: "audit",
+        action = "debug_capture.start",
+        actor = %actor,
+        ip = %redact::ip(ip_addr),
+        duration_secs = request.duration_secs,
+        "Debug capture started"
+    );
+
+    Ok(Json(DebugCaptureResponse {
+        ip: canonical_ip(ip_addr).to_string(),
+        watched_until: Some(until),
+        records: None,
+    }))
+}
+
+/// The lookups recorded for an IP by `POST /api/admin/debug-capture`
+#[axum::debug_handler]
+pub async fn get_debug_capture(
+    Path(ip): Path<String>,
+    State(state): State<Arc<AppState>>,
+    user: Option<Extension<AuthenticatedUser>>,
+) -> Result<Json<DebugCaptureResponse>, AppError> {
+    require_admin(user.as_deref())?;
+    let ip_addr: IpAddr = ip.parse()?;
+    Ok(Json(DebugCaptureResponse {
+        ip: canonical_ip(ip_addr).to_string(),
+        watched_until: state.debug_capture.watched_until(ip_addr),
+        records: Some(state.debug_capture.records(ip_addr)),
+    }))
+}
+
+/// Lists retained radix tree snapshots, newest first
+#[axum::debug_handler]
+pub async fn list_tree_snapshots(
+    State(state): State<Arc<AppState>>,
+    user: Option<Extension<AuthenticatedUser>>,
+) -> Result<Json<Vec<SnapshotInfo>>, AppError> {
+    require_admin(user.as_deref())?;
+    let service = Arc::clone(&state.ip_lookup_service);
+    let snapshots = tokio::task::spawn_blocking(move || service.snapshots())
+        .await
+        .map_err(|_| AppError::InternalServerError)??;
+    Ok(Json(snapshots))
+}
+
+/// Restores the radix tree from a retained snapshot (0 is the newest)
+#[axum::debug_handler]
+pub async fn rollback_tree(
+    Path(index): Path<usize>,
+    State(state): State<Arc<AppState>>,
+    user: Option<Extension<AuthenticatedUser>>,
+) -> Result<Json<SnapshotInfo>, AppError> {
+    require_admin(user.as_deref())?;
+    let service = Arc::clone(&state.ip_lookup_service);
+    let snapshot = tokio::task::spawn_blocking(move || service.rollback(index))
+        .await
+        .map_err(|_| AppError::InternalServerError)??;
+
+    // Cached responses were computed against the tree we just replaced
+    purge_lookups(&state).await;
+
+    Ok(Json(snapshot))
+}
+
+/// Body of `PUT /api/admin/ranges`
+#[derive(Debug, Deserialize)]
+pub struct ManualRangeRequest {
+    pub network: String,
+    /// Category name as accepted in overrides, e.g. `vpn` or `tor`
+    pub category: String,
+    /// Drop the entry after this many seconds; kept until deleted if absent
+    pub ttl_secs: Option<u64>,
+}
+
+/// Rejects callers whose API key resolved to a non-admin role and returns the
+/// name to attribute the change to in the audit log
+fn require_admin(user: Option<&AuthenticatedUser>) -> Result<String, AppError> {
+    // Only reachable without the API key middleware in front, which must
+    // never open the admin endpoints
+    let Some(user) = user else {
+        return Err(AppError::Forbidden("Authentication required".to_string()));
+    };
+    if !user.is_admin() {
+        return Err(AppError::Forbidden("Admin role required".to_string()));
+    }
+    Ok(user.email.clone().or_else(|| user.user_id.clone()).unwrap_or_else(|| "anonymous".to_string()))
+}
+
+fn manual_network(input: &str, limits: CidrLimits) -> Result<ip_network::IpNetwork, AppError> {
+    let decoded = percent_decode_str(input)
+        .decode_utf8()
+        .map_err(|_| AppError::BadRequest("Failed to decode URL-encoded input".to_string()))?;
+    let network = validate_cidr(&decoded, limits)?;
+    ip_network::IpNetwork::new(network.network(), network.prefix()).map_err(|_| AppError::InternalServerError)
+}
+
+/// Lists the ranges added through the admin API
+pub async fn list_manual_ranges(
+    State(state): State<Arc<AppState>>,
+    user: Option<Extension<AuthenticatedUser>>,
+) -> Result<Json<Vec<ManualRange>>, AppError> {
+    require_admin(user.as_deref())?;
+    Ok(Json(state.ip_lookup_service.manual_ranges()))
+}
+
+/// Adds a range to the live tree, replacing any manual entry for the same network
+pub async fn put_manual_range(
+    State(state): State<Arc<AppState>>,
+    user: Option<Extension<AuthenticatedUser>>,
+    Json(request): Json<ManualRangeRequest>,
+) -> Result<Json<ManualRange>, AppError> {
+    let actor = require_admin(user.as_deref())?;
+    let network = manual_network(&request.network, state.cidr_limits)?;
+    let category: IpCategory = request
+        .category
+        .parse()
+        .map_err(|_| AppError::BadRequest(format!("Unknown category: {}", request.category)))?;
+    if !state.ip_lookup_service.category_enabled(category) {
+        return Err(AppError::BadRequest(format!(
+            "Category {} is not in ip_lookup.enabled_categories",
+            request.category
+        )));
+    }
+    let ttl = match request.ttl_secs {
+        Some(0) => return Err(AppError::BadRequest("ttl_secs must be at least 1".to_string())),
+        ttl_secs => ttl_secs.map(Duration::from_secs),
+    };
+
+    let range = state.ip_lookup_service.add_manual_range(network, category, ttl);
+    tracing::info!(
+        target: "audit",
+        action = "manual_range.insert",
+        actor = %actor,
+        network = %range.network,
+        category = ?range.category,
+        expires_at = ?range.expires_at,
+        "Manual range added"
+    );
+    purge_lookups(&state).await;
+
+    Ok(Json(range))
+}
+
+/// Request body for forcing monitor mode on or off
+#[derive(Debug, Deserialize)]
+pub struct MonitorModeRequest {
+    pub enabled: bool,
+}
+
+/// Whether actions are being downgraded to `monitor`, and why
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct MonitorModeStatus {
+    /// Forced through `POST /api/admin/monitor_mode`
+    pub forced: bool,
+    /// `response_action.monitor_mode` in the applied config
+    pub configured: bool,
+    pub effective: bool,
+}
+
+impl MonitorModeStatus {
+    fn of(state: &AppState) -> Self {
+        let forced = state.monitor_override.is_forced();
+        let configured = state.runtime.load().response_action_config.monitor_mode;
+        Self { forced, configured, effective: forced || configured }
+    }
+}
+
+/// Forces every verdict to `monitor`, or hands back to the configured
+/// `monitor_mode`. Not persisted: a restart starts unforced.
+pub async fn set_monitor_mode(
+    State(state): State<Arc<AppState>>,
+    user: Option<Extension<AuthenticatedUser>>,
+    Json(request): Json<MonitorModeRequest>,
+) -> Result<Json<MonitorModeStatus>, AppError> {
+    let actor = require_admin(user.as_deref())?;
+    let was_forced = state.monitor_override.set(request.enabled);
+    tracing::info!(
+        target: "audit",
+        action = "monitor_mode.set",
+        actor = %actor,
+        forced = request.enabled,
+        was_forced,
+        "Monitor mode override changed"
+    );
+    // Cached lookups carry the action they were scored with
+    if was_forced != request.enabled {
+        state.lookup_cache.invalidate_all();
+    }
+
+    Ok(Json(MonitorModeStatus::of(&state)))
+}
+
+/// Removes a range added through the admin API
+pub async fn delete_manual_range(
+    Path(network): Path<String>,
+    State(state): State<Arc<AppState>>,
+    user: Option<Extension<AuthenticatedUser>>,
+) -> Result<Json<ManualRange>, AppError> {
+    let actor = require_admin(user.as_deref())?;
+    let network = manual_network(&network, state.cidr_limits)?;
+    let range = state
+        .ip_lookup_service
+        .remove_manual_range(network)
+        .ok_or_else(|| AppError::NotFound(format!("No manual range for {}", network)))?;
+    tracing::info!(
+        target: "audit",
+        action = "manual_range.delete",
+        actor = %actor,
+        network = %range.network,
+        "Manual range removed"
+    );
+    purge_lookups(&state).await;
+
+    Ok(Json(range))
 }
 
 #[derive(Debug, Serialize)]
 pub struct HealthResponse {
     pub status: String,
     pub version: &'static str,
+    pub monitor_mode: MonitorModeStatus,
+    /// Feeds whose recent updates failed; they are retried with backoff
+    pub failing_sources: Vec<SourceFailure>,
+    /// Categories lookups are missing data for, per `ip_lookup.on_missing_threat_data`
+    pub missing_threat_data: Vec<IpCategory>,
 }
 
-pub async fn health_check() -> Json<HealthResponse> {
+pub async fn health_check(State(state): State<Arc<AppState>>) -> Json<HealthResponse> {
     Json(HealthResponse {
         status: "ok".to_string(),
         version: env!("CARGO_PKG_VERSION"),
+        monitor_mode: MonitorModeStatus::of(&state),
+        failing_sources: state.ip_lookup_service.failing_sources(),
+        missing_threat_data: state.ip_lookup_service.missing_categories(state.missing_data.max_age),
     })
 }
 
@@ -360,22 +1461,52 @@ pub async fn metrics() -> Result<impl axum::response::IntoResponse, axum::http::
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ip_lookup::CategoryFlags;
+    use crate::test_support::{self, mmdb::FIXTURE_US_IP};
     use std::net::SocketAddr;
 
     // Test health_check handler
     #[tokio::test]
     async fn test_health_check() {
-        let response = health_check().await;
+        let state = setup_test_state();
+        let response = health_check(State(Arc::clone(&state))).await;
         assert_eq!(response.0.status, "ok");
+        assert!(!response.0.monitor_mode.effective);
+
+        state.monitor_override.set(true);
+        let response = health_check(State(state)).await;
+        assert_eq!(
+            response.0.monitor_mode,
+            MonitorModeStatus { forced: true, configured: false, effective: true }
+        );
     }
 
     // Test lookup_ip with valid IP
     #[tokio::test]
     async fn test_lookup_ip_valid() {
         let state = setup_test_state();
-        let ip = "8.8.8.8".to_string();
-        let result = lookup_ip(Path(ip), State(state)).await;
-        assert!(result.is_ok());
+        let ip = FIXTURE_US_IP.to_string();
+        let response = lookup_ip(Path(ip), Query(LookupParams::default()), State(state), None, None, HeaderMap::new()).await.unwrap();
+        let geo_info = response.0.response.geo_info.as_ref().unwrap();
+        let country = geo_info.country.as_ref().and_then(|c| c.names.as_ref()).unwrap();
+        assert_eq!(country.get("en").map(String::as_str), Some("United States"));
+        assert!(!response.0.response.is_tor_exit_node);
+    }
+
+    #[tokio::test]
+    async fn test_lookup_ip_from_body() {
+        let state = setup_test_state();
+        let body = |ip: &str| Json(LookupRequest { ip: ip.to_string() });
+        let params = LookupParams { fields: Some("ip,is_tor_exit_node".to_string()), ..Default::default() };
+        let response = lookup_ip_body(Query(params), State(Arc::clone(&state)), None, None, HeaderMap::new(), body("5.1.1.1"))
+            .await
+            .unwrap();
+        let json = serde_json::to_value(&response.0.0).unwrap();
+        assert_eq!(json, serde_json::json!({ "ip": "5.1.1.1", "is_tor_exit_node": true }));
+
+        let result =
+            lookup_ip_body(Query(LookupParams::default()), State(state), None, None, HeaderMap::new(), body("127.0.0.1")).await;
+        assert!(matches!(result, Err(AppError::ValidationError(_))));
     }
 
     // Test lookup_ip with invalid IP
@@ -383,10 +1514,20 @@ mod tests {
     async fn test_lookup_ip_invalid() {
         let state = setup_test_state();
         let ip = "invalid.ip".to_string();
-        let result = lookup_ip(Path(ip), State(state)).await;
+        let result = lookup_ip(Path(ip), Query(LookupParams::default()), State(state), None, None, HeaderMap::new()).await;
         assert!(result.is_err());
     }
 
+    #[tokio::test]
+    async fn test_lookup_ip_reports_tree_category() {
+        let state = setup_test_state();
+        let response = lookup_ip(Path(TOR_IP.to_string()), Query(LookupParams::default()), State(state), None, None, HeaderMap::new())
+            .await
+            .unwrap();
+        assert!(response.0.response.is_tor_exit_node);
+        assert!(response.0.response.threat_score > 0);
+    }
+
     // Test lookup_self
     #[tokio::test]
     async fn test_lookup_self() {
@@ -395,12 +1536,12 @@ mod tests {
         // Create a test request with X-Forwarded-For header
         let request = Request::builder()
             .uri("/lookup/self")
-            .header("x-forwarded-for", "203.0.113.1, 198.51.100.1")
+            .header("x-forwarded-for", "1.0.0.1, 198.51.100.1")
             .body(Body::empty())
             .unwrap();
             
         // Create a mock ConnectInfo
-        let remote_addr = "127.0.0.1:8080".parse::<SocketAddr>().unwrap();
+        let remote_addr = "8.8.4.4:8080".parse::<SocketAddr>().unwrap();
         let connect_info = ConnectInfo(remote_addr);
         
         // Insert ConnectInfo into extensions
@@ -412,14 +1553,14 @@ mod tests {
         assert!(result.is_ok());
         
         // The IP should be the first one from X-Forwarded-For
-        let response = result.unwrap();
-        assert_eq!(response.0.ip, "203.0.113.1");
+        let response = result.unwrap().0.unwrap();
+        assert_eq!(response.0.response.ip, "1.0.0.1");
         
         // Test with X-Real-IP header
         let state = setup_test_state();
         let request = Request::builder()
             .uri("/lookup/self")
-            .header("x-real-ip", "192.0.2.1")
+            .header("x-real-ip", "1.1.1.1")
             .body(Body::empty())
             .unwrap();
             
@@ -430,9 +1571,9 @@ mod tests {
         
         let result = lookup_self(State(state), request).await;
         assert!(result.is_ok());
-        assert_eq!(result.unwrap().0.ip, "192.0.2.1");
+        assert_eq!(result.unwrap().0.unwrap().0.response.ip, "1.1.1.1");
         
-        // Test with direct connection (no headers)
+        // Test with direct connection (no headers): the peer address is used
         let state = setup_test_state();
         let request = Request::builder()
             .uri("/lookup/self")
@@ -445,11 +1586,847 @@ mod tests {
         let request = Request::from_parts(parts, body);
         
         let result = lookup_self(State(state), request).await;
-        assert!(result.is_err());
+        assert_eq!(result.unwrap().0.unwrap().0.response.ip, "8.8.4.4");
+
+        // A loopback peer is not a public address
+        let state = setup_test_state();
+        let mut request = Request::builder().uri("/lookup/self").body(Body::empty()).unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo("127.0.0.1:8080".parse::<SocketAddr>().unwrap()));
+        assert!(lookup_self(State(state), request).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_lookup_reports_category() {
+        let category = |state: Arc<AppState>, ip: &str| {
+            let ip = ip.to_string();
+            async move {
+                lookup_ip(Path(ip), Query(LookupParams::default()), State(state), None, None, HeaderMap::new())
+                    .await
+                    .unwrap()
+                    .0.0
+                    .response
+                    .category
+            }
+        };
+
+        let state = setup_test_state();
+        assert_eq!(category(Arc::clone(&state), TOR_IP).await.as_deref(), Some("tor_exit_node"));
+        assert_eq!(category(Arc::clone(&state), VPN_IP).await.as_deref(), Some("vpn"));
+        assert_eq!(category(state, FIXTURE_US_IP).await, None);
+
+        let state = Arc::new(AppState {
+            category_fallback: Some("none".into()),
+            ..(*setup_test_state()).clone()
+        });
+        assert_eq!(category(Arc::clone(&state), FIXTURE_US_IP).await.as_deref(), Some("none"));
+        assert_eq!(category(state, VPN_IP).await.as_deref(), Some("vpn"));
+    }
+
+    #[tokio::test]
+    async fn test_lookup_flags_every_matching_category() {
+        let state = Arc::new(test_support::app_state_with_ranges(vec![
+            test_support::range("5.2.2.0/24", IpCategory::Vpn),
+            test_support::range("5.2.2.2/32", IpCategory::ProxySocks5),
+        ]));
+        let lookup = |ip: &str| lookup_ip(Path(ip.to_string()), Query(LookupParams::default()), State(Arc::clone(&state)), None, None, HeaderMap::new());
+
+        let response = lookup("5.2.2.2").await.unwrap().0.0.response;
+        // The most specific range decides the legacy fields alone
+        assert_eq!(response.category.as_deref(), Some("socks5_proxy"));
+        assert!(!response.is_vpn_or_datacenter);
+        assert_eq!(
+            response.categories,
+            CategoryFlags { vpn: true, proxy_socks5: true, ..CategoryFlags::default() }
+        );
+
+        let response = lookup(FIXTURE_US_IP).await.unwrap().0.0.response;
+        assert_eq!(response.categories, CategoryFlags::default());
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(json["categories"]["tor"], false);
+    }
+
+    #[tokio::test]
+    async fn test_lookup_reports_matched_source() {
+        let state = setup_test_state();
+        let lookup = |ip: &str| lookup_ip(Path(ip.to_string()), Query(LookupParams::default()), State(Arc::clone(&state)), None, None, HeaderMap::new());
+
+        let response = lookup(VPN_IP).await.unwrap().0.0.response;
+        assert_eq!(response.matched_source.as_deref(), Some("fixture"));
+        let response = lookup(FIXTURE_US_IP).await.unwrap().0.0.response;
+        assert_eq!(response.matched_source, None);
+        assert!(serde_json::to_value(&response).unwrap().get("matched_source").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_mapped_ipv6_shares_ipv4_verdict_and_cache_entry() {
+        let state = setup_test_state();
+        let mapped = format!("::ffff:{}", TOR_IP);
+
+        let response = lookup_ip(Path(mapped.clone()), Query(LookupParams::default()), State(Arc::clone(&state)), None, None, HeaderMap::new())
+            .await
+            .unwrap();
+        assert_eq!(response.0.response.ip, mapped);
+        assert_eq!(response.0.response.canonical_ip, TOR_IP);
+        assert!(response.0.response.is_tor_exit_node);
+
+        // Served from the entry the mapped lookup cached, under its own spelling
+        let response = lookup_ip(Path(TOR_IP.to_string()), Query(LookupParams::default()), State(Arc::clone(&state)), None, None, HeaderMap::new())
+            .await
+            .unwrap();
+        assert_eq!(response.0.response.ip, TOR_IP);
+        assert!(response.0.response.is_tor_exit_node);
+        state.lookup_cache.run_pending_tasks();
+        assert_eq!(state.lookup_cache.entry_count(), 1);
+
+        // Dual-stack proxies forward the mapped form
+        let request = Request::builder()
+            .uri("/lookup/self")
+            .header("x-forwarded-for", &mapped)
+            .body(Body::empty())
+            .unwrap();
+        let response = lookup_self(State(Arc::clone(&state)), request).await.unwrap().0.unwrap();
+        assert_eq!(response.0.response.canonical_ip, TOR_IP);
+        assert!(response.0.response.is_tor_exit_node);
+
+        let tor = is_tor_exit_node(Path(mapped), State(state)).await.unwrap();
+        assert!(tor.0.is_tor_exit_node);
     }
 
+    #[tokio::test]
+    async fn test_6to4_shares_ipv4_cache_entry_when_canonicalized() {
+        const SIX_TO_FOUR: &str = "2002:501:101::1";
+        let lookup = |state: &Arc<AppState>, ip: &str| {
+            lookup_ip(Path(ip.to_string()), Query(LookupParams::default()), State(Arc::clone(state)), None, None, HeaderMap::new())
+        };
+
+        let state = Arc::new(AppState { canonicalize_6to4: true, ..(*setup_test_state()).clone() });
+        let response = lookup(&state, SIX_TO_FOUR).await.unwrap().0.0.response;
+        assert_eq!(response.ip, SIX_TO_FOUR);
+        assert_eq!(response.canonical_ip, TOR_IP);
+        assert!(response.is_tor_exit_node);
+        let response = lookup(&state, "2002:501:101:8::2").await.unwrap().0.0.response;
+        assert!(response.is_tor_exit_node);
+        assert!(lookup(&state, TOR_IP).await.unwrap().0.0.response.is_tor_exit_node);
+        state.lookup_cache.run_pending_tasks();
+        assert_eq!(state.lookup_cache.entry_count(), 1);
+
+        // Off by default: each form is its own entry and the 6to4 one is not flagged
+        let state = setup_test_state();
+        assert!(!lookup(&state, SIX_TO_FOUR).await.unwrap().0.0.response.is_tor_exit_node);
+        assert!(lookup(&state, TOR_IP).await.is_ok());
+        state.lookup_cache.run_pending_tasks();
+        assert_eq!(state.lookup_cache.entry_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_origin_findings_are_merged() {
+        // 6to4 and Teredo addresses whose IPv4 origin is TOR_IP
+        const SIX_TO_FOUR: &str = "2002:501:101::1";
+        const TEREDO: &str = "2001:0:4136:e378:8000:63bf:fafe:fefe";
+
+        let state_with = |tunnel_extraction: bool| {
+            let state = test_support::app_state_with_ranges(vec![test_support::range(
+                "5.1.1.1/32",
+                IpCategory::TorExitNode,
+            )]);
+            Arc::new(AppState { tunnel_extraction, ..state })
+        };
+        let lookup = |state: &Arc<AppState>, ip: &str| {
+            lookup_ip(Path(ip.to_string()), Query(LookupParams::default()), State(Arc::clone(state)), None, None, HeaderMap::new())
+        };
+
+        let response = lookup(&state_with(false), SIX_TO_FOUR).await.unwrap();
+        assert!(!response.0.response.is_tor_exit_node);
+        assert_eq!(response.0.response.threat_score, 0);
+
+        let state = state_with(true);
+        for (ip, tunnel) in [(SIX_TO_FOUR, "6to4"), (TEREDO, "Teredo")] {
+            let response = lookup(&state, ip).await.unwrap().0.0.response;
+            assert!(response.is_tor_exit_node, "{}", ip);
+            assert!(response.threat_score > 0);
+            assert_eq!(
+                response.threat_details,
+                vec![format!("IP is a known Tor exit node (via {} tunnel from {})", tunnel, TOR_IP)]
+            );
+
+            let score = get_threat_score(Path(ip.to_string()), Query(ThreatScoreParams::default()), State(Arc::clone(&state)), None)
+                .await
+                .unwrap();
+            assert_eq!(score.0.threat_score, response.threat_score);
+        }
+
+        // Tunnels to clean origins add nothing
+        let response = lookup(&state, "2002:808:808::1").await.unwrap();
+        assert!(response.0.response.threat_details.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_scoring_endpoints_agree_with_lookup() {
+        use crate::config::{runtime::RuntimeConfig, ProfileSettings};
+        use crate::services::profiles::ScoringProfiles;
+
+        let mut profiles = ScoringProfiles::default();
+        profiles.insert("lenient", &ProfileSettings { vpn_weight: Some(0.1), ..Default::default() });
+        // The VPN range is only in the tree, not in the detector lists
+        let state = Arc::new(AppState {
+            tunnel_extraction: true,
+            canonicalize_6to4: true,
+            runtime: RuntimeConfig { profiles, ..RuntimeConfig::default() }.shared(),
+            ..test_support::app_state_with_ranges(vec![
+                test_support::range("9.9.9.0/24", IpCategory::Vpn),
+                test_support::range("5.1.1.1/32", IpCategory::TorExitNode),
+            ])
+        });
+
+        // A 6to4 address collapsed to its origin, a Teredo one scored by it
+        let ips = ["9.9.9.9", "2002:909:909::1", "2001:0:4136:e378:8000:63bf:fafe:fefe", FIXTURE_US_IP];
+        for profile in [None, Some(ProfileName::from("role:lenient"))] {
+            for ip in ips {
+                let lookup = profile_lookup_service(&state, profile.as_ref()).lookup_ip(ip.parse().unwrap()).await.unwrap();
+                let score = get_threat_score(
+                    Path(ip.to_string()),
+                    Query(ThreatScoreParams::default()),
+                    State(Arc::clone(&state)),
+                    profile.clone().map(Extension),
+                )
+                .await
+                .unwrap();
+                assert_eq!(score.0.threat_score, lookup.threat_score, "{} {:?}", ip, profile);
+                assert_eq!(score.0.threat_details, lookup.threat_details, "{} {:?}", ip, profile);
+
+                let explained = explain_threat_score(Path(ip.to_string()), State(Arc::clone(&state)), profile.clone().map(Extension))
+                    .await
+                    .unwrap();
+                assert_eq!(explained.0.threat_score, lookup.threat_score, "{} {:?}", ip, profile);
+                assert_eq!(format!("{:?}", explained.0.recommended_action).to_lowercase(), lookup.recommended_action);
+
+                let simulated = simulate_action(
+                    State(Arc::clone(&state)),
+                    profile.clone().map(Extension),
+                    Json(SimulateRequest { ip: ip.to_string(), response_action_config: ResponseActionConfig::default() }),
+                )
+                .await
+                .unwrap();
+                assert_eq!(simulated.0.threat_score, lookup.threat_score, "{} {:?}", ip, profile);
+            }
+        }
+
+        // The profile applies to every path
+        let vpn = |profile: Option<ProfileName>| {
+            get_threat_score(Path("9.9.9.9".to_string()), Query(ThreatScoreParams::default()), State(Arc::clone(&state)), profile.map(Extension))
+        };
+        assert!(vpn(Some(ProfileName::from("role:lenient"))).await.unwrap().0.threat_score < vpn(None).await.unwrap().0.threat_score);
+    }
+
+    #[tokio::test]
+    async fn test_get_threat_score() {
+        let state = setup_test_state();
+        let score = |ip: &str| get_threat_score(Path(ip.to_string()), Query(ThreatScoreParams::default()), State(Arc::clone(&state)), None);
+
+        let clean = score(FIXTURE_US_IP).await.unwrap();
+        assert_eq!(clean.0.threat_score, 0);
+        assert!(clean.0.threat_details.is_empty());
+
+        let tor = score(TOR_IP).await.unwrap();
+        assert!(tor.0.threat_score > 0);
+        assert!(!tor.0.threat_details.is_empty());
+        assert!(tor.0.score_precise.is_none());
+
+        // `?precise=true` adds the unrounded score
+        let precise = get_threat_score(
+            Path(TOR_IP.to_string()),
+            Query(ThreatScoreParams { precise: true }),
+            State(Arc::clone(&state)),
+            None,
+        )
+        .await
+        .unwrap();
+        let score_precise = precise.0.score_precise.unwrap();
+        assert_eq!(score_precise.round() as u8, tor.0.threat_score);
+
+        assert!(score("not-an-ip").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_is_tor_exit_node() {
+        let state = setup_test_state();
+        let check = |ip: &str| is_tor_exit_node(Path(ip.to_string()), State(Arc::clone(&state)));
+
+        assert!(check(TOR_IP).await.unwrap().0.is_tor_exit_node);
+        assert!(!check(VPN_IP).await.unwrap().0.is_tor_exit_node);
+        assert!(!check(FIXTURE_US_IP).await.unwrap().0.is_tor_exit_node);
+
+        // Ranges report whether any exit node is inside, with host bits cleared
+        let range = check("5.1.1.77%2F24").await.unwrap().0;
+        assert!(range.is_tor_exit_node);
+        assert_eq!(range.network.as_deref(), Some("5.1.1.0/24"));
+        assert!(!check("5.2.0.0%2F16").await.unwrap().0.is_tor_exit_node);
+    }
+
+    #[tokio::test]
+    async fn test_range_endpoints_validate_cidr() {
+        use axum::response::IntoResponse;
+
+        async fn error_body(result: Result<impl IntoResponse, AppError>) -> (axum::http::StatusCode, String) {
+            let response = result.err().expect("request should be rejected").into_response();
+            let status = response.status();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            (status, body["error"].as_str().unwrap().to_string())
+        }
+
+        let state = setup_test_state();
+        for input in ["0.0.0.0%2F0", "2001:db8::%2F16", "::ffff:5.1.1.0%2F24", "5.1.1.0%2F33", "192.168.0.0%2F16"] {
+            let path = || Path(input.to_string());
+            let (status, tor) = error_body(is_tor_exit_node(path(), State(Arc::clone(&state))).await).await;
+            assert_eq!(status, axum::http::StatusCode::BAD_REQUEST, "{}", input);
+            let (_, proxy) = error_body(is_proxy(path(), State(Arc::clone(&state))).await).await;
+            let (_, vpn) = error_body(is_vpn_or_datacenter(path(), State(Arc::clone(&state))).await).await;
+            assert!(tor == proxy && proxy == vpn, "{}", input);
+        }
+
+        let (_, message) = error_body(is_proxy(Path("0.0.0.0%2F0".to_string()), State(Arc::clone(&state))).await).await;
+        assert_eq!(message, "Network range 0.0.0.0/0 is broader than the /8 limit; query /8 or narrower ranges");
+        let (_, message) = error_body(is_proxy(Path("2001:db8::%2F16".to_string()), State(Arc::clone(&state))).await).await;
+        assert_eq!(message, "Network range 2001::/16 is broader than the /32 limit; query /32 or narrower ranges");
+        let (_, message) =
+            error_body(is_proxy(Path("::ffff:5.1.1.0%2F24".to_string()), State(Arc::clone(&state))).await).await;
+        assert_eq!(message, "Network range '::ffff:5.1.1.0/24' has an IPv4-sized prefix on an IPv6 address; use 5.1.1.0/24");
+        let (_, message) = error_body(is_vpn_or_datacenter(Path("0.0.0.0%2F8".to_string()), State(Arc::clone(&state))).await).await;
+        assert_eq!(message, "Network range 0.0.0.0/8 overlaps reserved address space: unspecified address (0.0.0.0)");
+
+        let proxy = is_proxy(Path("5.9.9.9%2F24".to_string()), State(Arc::clone(&state))).await.unwrap().0;
+        assert_eq!(proxy.network.as_deref(), Some("5.9.9.0/24"));
+        let vpn = is_vpn_or_datacenter(Path("5.9.9.9%2F24".to_string()), State(state)).await.unwrap();
+        assert!(vpn.ends_with("\nnetwork: 5.9.9.0/24"), "{}", vpn);
+    }
+
+    #[tokio::test]
+    async fn test_range_endpoints_use_the_detectors_loaded_at_startup() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = crate::config::Settings::default();
+        settings.vpn_detector.db_path = dir.path().join("vpn.txt");
+        settings.proxy_detector.http_db_path = dir.path().join("http.txt");
+        settings.proxy_detector.socks4_db_path = dir.path().join("socks4.txt");
+        settings.proxy_detector.socks5_db_path = dir.path().join("socks5.txt");
+        std::fs::write(&settings.vpn_detector.db_path, "5.7.0.0/16\n").unwrap();
+        std::fs::write(&settings.proxy_detector.http_db_path, "5.8.8.8:8080\n").unwrap();
+        std::fs::write(&settings.proxy_detector.socks4_db_path, "").unwrap();
+        std::fs::write(&settings.proxy_detector.socks5_db_path, "5.8.8.9:1080\n").unwrap();
+
+        let state = Arc::new(AppState {
+            vpn_detector: Some(Arc::new(VpnDetector::load(&settings).await.unwrap())),
+            proxy_detector: Some(Arc::new(ProxyDetector::load(&settings).await.unwrap())),
+            ..test_support::app_state()
+        });
+        let vpn = is_vpn_or_datacenter(Path("5.7.1.1".to_string()), State(Arc::clone(&state))).await.unwrap();
+        assert_eq!(vpn, "is_vpn/datacenter: true");
+        let proxy = is_proxy(Path("5.8.8.9".to_string()), State(Arc::clone(&state))).await.unwrap().0;
+        assert_eq!(proxy.proxy_type.as_deref(), Some("SOCKS5"));
+        let proxy = is_proxy(Path("5.8.8.0%2F24".to_string()), State(state)).await.unwrap().0;
+        assert!(proxy.is_proxy);
+
+        // A list that failed to load fails its endpoint instead of answering "no"
+        let state = Arc::new(AppState { vpn_detector: None, ..test_support::app_state() });
+        let error = is_vpn_or_datacenter(Path("5.7.1.1".to_string()), State(Arc::clone(&state))).await.unwrap_err();
+        assert!(error.to_string().contains("VPN list was not loaded"), "{}", error);
+        assert!(is_proxy(Path("5.8.8.9".to_string()), State(state)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_action_for_score() {
+        let state = setup_test_state();
+        let action = |score: &str, threat_types: Option<&str>| {
+            let params = ActionParams { threat_types: threat_types.map(str::to_string) };
+            action_for_score(Path(score.to_string()), Query(params), State(Arc::clone(&state)), None)
+        };
+
+        let response = action("60", None).await.unwrap().0;
+        assert_eq!(response.recommended_action, ResponseAction::Challenge);
+        assert_eq!(response.decision, ActionDecision::ScoreBand { min_score: 51, max_score: 75 });
+        let response = action("5", Some("Proxy, TorExitNode")).await.unwrap().0;
+        assert_eq!(response.recommended_action, ResponseAction::Block);
+
+        for (score, threat_types) in [("101", None), ("-1", None), ("high", None), ("5", Some("Tor"))] {
+            assert!(matches!(action(score, threat_types).await, Err(AppError::BadRequest(_))), "{}", score);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connectivity_check_requires_admin() {
+        let state = setup_test_state();
+        let user = |role: &str| {
+            Some(Extension(AuthenticatedUser { user_id: Some("u1".to_string()), email: None, role: Some(role.to_string()) }))
+        };
+
+        let denied = check_connectivity(State(Arc::clone(&state)), user("user")).await;
+        assert!(matches!(denied, Err(AppError::Forbidden(_))));
+        // The fixture state has nothing to probe
+        let report = check_connectivity(State(state), user("admin")).await.unwrap().0;
+        assert!(report.ok && report.targets.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_explain_ip() {
+        let state = setup_test_state();
+        let user = |role: &str| {
+            Some(Extension(AuthenticatedUser { user_id: Some("u1".to_string()), email: None, role: Some(role.to_string()) }))
+        };
+        let explain = |ip: &str, role: &str| explain_ip(Path(ip.to_string()), State(Arc::clone(&state)), user(role));
+
+        assert!(matches!(explain("5.2.2.7", "user").await, Err(AppError::Forbidden(_))));
+        assert!(matches!(explain("5.2.2", "admin").await, Err(AppError::AddrParseError(_))));
+
+        state.ip_lookup_service.add_manual_range("5.2.2.7/32".parse().unwrap(), IpCategory::ProxyHttp, None);
+        let explained = explain("::ffff:5.2.2.7", "admin").await.unwrap().0;
+        assert_eq!(explained.canonical_ip, "5.2.2.7");
+        let json = serde_json::to_value(&explained.matches).unwrap();
+        assert_eq!(json[0]["network"], "5.2.2.7/32");
+        assert_eq!(json[0]["category"], "http_proxy");
+        assert_eq!(json[0]["source"], "manual");
+        assert!(json[0]["source_updated_at"].is_string());
+        assert_eq!(json[1]["network"], "5.2.2.0/24");
+        assert_eq!(json[1]["source"], "fixture");
+        assert_eq!(explained.matches.len(), 2);
+
+        assert!(explain("8.8.8.8", "admin").await.unwrap().0.matches.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_debug_capture_records_watched_lookups() {
+        let state = setup_test_state();
+        let user = |role: &str| {
+            Some(Extension(AuthenticatedUser { user_id: Some("u1".to_string()), email: None, role: Some(role.to_string()) }))
+        };
+        let start = |ip: &str, duration_secs: u64, role: &str| {
+            let request = DebugCaptureRequest { ip: ip.to_string(), duration_secs };
+            start_debug_capture(State(Arc::clone(&state)), user(role), Json(request))
+        };
+        let lookup = |ip: &str| lookup_ip(Path(ip.to_string()), Query(LookupParams::default()), State(Arc::clone(&state)), None, None, HeaderMap::new());
+        let records = |ip: &str| get_debug_capture(Path(ip.to_string()), State(Arc::clone(&state)), user("admin"));
+
+        assert!(matches!(start("5.2.2.7", 600, "user").await, Err(AppError::Forbidden(_))));
+        assert!(matches!(start("5.2.2.7", 0, "admin").await, Err(AppError::BadRequest(_))));
+        assert!(matches!(start("5.2.2.7", 86_401, "admin").await, Err(AppError::BadRequest(_))));
+
+        let started = start("::ffff:5.2.2.7", 600, "admin").await.unwrap().0;
+        assert_eq!(started.ip, "5.2.2.7");
+        assert!(lookup("5.2.2.7").await.is_ok());
+        assert!(lookup("5.2.2.7").await.is_ok());
+        // Unwatched IPs leave nothing behind
+        assert!(lookup("5.2.2.8").await.is_ok());
+        assert!(records("5.2.2.8").await.unwrap().0.records.unwrap().is_empty());
+
+        let capture = records("5.2.2.7").await.unwrap().0;
+        assert_eq!(capture.watched_until, started.watched_until);
+        let captured = capture.records.unwrap();
+        assert_eq!(captured.len(), 2);
+        assert!(!captured[0].cache_hit && captured[1].cache_hit);
+        assert_eq!(captured[0].matches[0].source.as_deref(), Some("fixture"));
+        let findings = captured[0].findings.as_ref().unwrap();
+        assert!(findings.iter().any(|finding| finding.threat_type == ThreatType::VpnOrDatacenter && finding.weight > 0.0));
+        assert!(captured[1].findings.is_none());
+        assert_eq!(captured[0].response.recommended_action, captured[1].response.recommended_action);
+        assert_eq!(captured[0].config_generation, 0);
+
+        let json = serde_json::to_value(&captured[0]).unwrap();
+        assert_eq!(json["response"]["category"], "vpn");
+        assert_eq!(json["profile"], "default");
+    }
+
+    #[tokio::test]
+    async fn test_debug_capture_stops_when_the_watch_ends() {
+        let state = setup_test_state();
+        state.debug_capture.watch("5.2.2.7".parse().unwrap(), Duration::from_millis(50));
+        let lookup = || lookup_ip(Path("5.2.2.7".to_string()), Query(LookupParams::default()), State(Arc::clone(&state)), None, None, HeaderMap::new());
+
+        assert!(lookup().await.is_ok());
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        assert!(lookup().await.is_ok());
+
+        let admin = Extension(AuthenticatedUser {
+            user_id: Some("u1".to_string()),
+            email: None,
+            role: Some("admin".to_string()),
+        });
+        let capture = get_debug_capture(Path("5.2.2.7".to_string()), State(Arc::clone(&state)), Some(admin)).await.unwrap().0;
+        assert_eq!(capture.watched_until, None);
+        assert_eq!(capture.records.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_manual_ranges_require_admin() {
+        let state = setup_test_state();
+        let user = |role: &str| {
+            Some(Extension(AuthenticatedUser {
+                user_id: Some("u1".to_string()),
+                email: None,
+                role: Some(role.to_string()),
+            }))
+        };
+        let request = |network: &str, category: &str, ttl_secs| {
+            Json(ManualRangeRequest {
+                network: network.to_string(),
+                category: category.to_string(),
+                ttl_secs,
+            })
+        };
+        let tor = |ip: &str| {
+            let found = state.ip_lookup_service.tree().lookup_match(ip.parse().unwrap());
+            found.map(|found| (found.category, found.source.unwrap().to_string()))
+        };
+
+        let denied = put_manual_range(State(Arc::clone(&state)), user("user"), request("93.184.216.0/24", "tor", None)).await;
+        assert!(matches!(denied, Err(AppError::Forbidden(_))));
+        assert!(matches!(list_manual_ranges(State(Arc::clone(&state)), user("user")).await, Err(AppError::Forbidden(_))));
+        let anonymous = put_manual_range(State(Arc::clone(&state)), None, request("93.184.216.0/24", "tor", None)).await;
+        assert!(matches!(anonymous, Err(AppError::Forbidden(_))));
+        assert!(tor("93.184.216.1").is_none());
+
+        for (network, category, ttl_secs) in [
+            ("93.184.216.0/24", "nope", None),
+            ("93.184.216.0/24", "tor", Some(0)),
+            ("0.0.0.0/0", "tor", None),
+            ("10.0.0.0/8", "tor", None),
+        ] {
+            let rejected = put_manual_range(State(Arc::clone(&state)), user("admin"), request(network, category, ttl_secs)).await;
+            assert!(rejected.is_err(), "{} {}", network, category);
+        }
+
+        let added = put_manual_range(State(Arc::clone(&state)), user("admin"), request("93.184.216.9/24", "tor", Some(60)))
+            .await
+            .unwrap()
+            .0;
+        assert_eq!(added.network, "93.184.216.0/24");
+        assert!(added.expires_at.is_some());
+        assert_eq!(tor("93.184.216.1"), Some((IpCategory::TorExitNode, "manual".to_string())));
+        assert_eq!(list_manual_ranges(State(Arc::clone(&state)), user("admin")).await.unwrap().0, vec![added]);
+
+        let path = || Path("93.184.216.0%2F24".to_string());
+        assert!(matches!(delete_manual_range(path(), State(Arc::clone(&state)), user("user")).await, Err(AppError::Forbidden(_))));
+        let removed = delete_manual_range(path(), State(Arc::clone(&state)), user("admin")).await.unwrap().0;
+        assert_eq!(removed.category, IpCategory::TorExitNode);
+        assert!(tor("93.184.216.1").is_none());
+        assert!(matches!(delete_manual_range(path(), State(state), user("admin")).await, Err(AppError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn test_range_count_matches_fixture() {
+        let state = setup_test_state();
+        let counts = count_ranges(State(state)).await;
+        assert_eq!(counts.0.total, 2);
+    }
+
+    #[tokio::test]
+    async fn test_self_endpoints_follow_missing_ip_policy() {
+        use axum::{http::StatusCode, response::IntoResponse};
+
+        let with_peer = |header: Option<&str>| {
+            let mut builder = Request::builder().uri("/api/threat-score/self");
+            if let Some(ip) = header {
+                builder = builder.header("x-forwarded-for", ip);
+            }
+            let mut request = builder.body(Body::empty()).unwrap();
+            request
+                .extensions_mut()
+                .insert(ConnectInfo("8.8.4.4:443".parse::<SocketAddr>().unwrap()));
+            request
+        };
+        let state_with = |policy: MissingIpPolicy| {
+            let mut state = test_support::app_state();
+            state.on_missing_ip = policy;
+            Arc::new(state)
+        };
+
+        let state = state_with(MissingIpPolicy::UseConnectInfo);
+        let score = get_self_threat_score(State(Arc::clone(&state)), with_peer(None)).await.unwrap();
+        assert_eq!(score.0.unwrap().ip, "8.8.4.4");
+        // The proxy header wins over the peer address, as in lookup_self
+        let score = get_self_threat_score(State(state), with_peer(Some(FIXTURE_US_IP))).await.unwrap();
+        assert_eq!(score.0.unwrap().ip, FIXTURE_US_IP);
+
+        let state = state_with(MissingIpPolicy::Reject);
+        let result = lookup_self(State(Arc::clone(&state)), with_peer(None)).await;
+        assert!(matches!(result, Err(AppError::ValidationError(IpValidationError::MissingIpHeaders))));
+        assert!(get_self_threat_score(State(Arc::clone(&state)), with_peer(None)).await.is_err());
+
+        // Allow lets the caller through unscored, like HEAD /api/lookup/self
+        let state = state_with(MissingIpPolicy::Allow);
+        let lookup = lookup_self(State(Arc::clone(&state)), with_peer(None)).await.unwrap().into_response();
+        assert_eq!(lookup.status(), StatusCode::NO_CONTENT);
+        assert_eq!(lookup.headers()[gate::ACTION_HEADER], "allow");
+        let score = get_self_threat_score(State(Arc::clone(&state)), with_peer(None)).await.unwrap().into_response();
+        assert_eq!(score.status(), StatusCode::NO_CONTENT);
+        assert_eq!(score.headers()[gate::ACTION_HEADER], "allow");
+
+        for policy in [MissingIpPolicy::Reject, MissingIpPolicy::Allow] {
+            let state = state_with(policy);
+            let lookup = lookup_self(State(state), with_peer(Some(FIXTURE_US_IP))).await.unwrap();
+            assert_eq!(lookup.0.unwrap().0.response.ip, FIXTURE_US_IP);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_self_lookup_rejects_long_forwarded_chains() {
+        let mut state = test_support::app_state();
+        state.max_forwarded_hops = 3;
+        let state = Arc::new(state);
+        let with_chain = |hops: usize| {
+            let chain = vec![FIXTURE_US_IP; hops].join(", ");
+            Request::builder().uri("/api/lookup/self").header("x-forwarded-for", chain).body(Body::empty()).unwrap()
+        };
+
+        assert!(lookup_self(State(Arc::clone(&state)), with_chain(3)).await.is_ok());
+        let error = lookup_self(State(state), with_chain(4)).await.unwrap_err();
+        assert!(matches!(error, AppError::ValidationError(IpValidationError::TooManyForwardedHops(3))));
+        assert_eq!(axum::response::IntoResponse::into_response(error).status(), axum::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_lookup_self_reports_ip_source_in_debug_mode() {
+        use crate::test_support::{self, mmdb::FIXTURE_US_IP};
+
+        let state = Arc::new(test_support::app_state());
+        let self_lookup = |uri: &str, header: Option<(&str, &str)>| {
+            let mut builder = Request::builder().uri(uri);
+            if let Some((name, value)) = header {
+                builder = builder.header(name, value);
+            }
+            let mut request = builder.body(Body::empty()).unwrap();
+            request
+                .extensions_mut()
+                .insert(ConnectInfo("8.8.4.4:443".parse::<SocketAddr>().unwrap()));
+            let state = Arc::clone(&state);
+            async move { lookup_self(State(state), request).await.map(|answer| answer.0.unwrap()) }
+        };
+
+        let response = self_lookup("/api/lookup/self?debug=true", Some(("x-forwarded-for", FIXTURE_US_IP)))
+            .await
+            .unwrap();
+        let json = serde_json::to_value(&response.0.0).unwrap();
+        assert_eq!(json["ip"], FIXTURE_US_IP);
+        assert_eq!(json["ip_source"], "x-forwarded-for");
+
+        let response = self_lookup("/api/lookup/self?debug=true", Some(("x-real-ip", FIXTURE_US_IP)))
+            .await
+            .unwrap();
+        assert_eq!(response.0.ip_source, Some(IpSource::XRealIp));
+
+        // Without proxy headers the peer address is used
+        let response = self_lookup("/api/lookup/self?debug=true&fields=ip", None).await.unwrap();
+        let json = serde_json::to_value(&response.0.0).unwrap();
+        assert_eq!(json, serde_json::json!({ "ip": "8.8.4.4", "ip_source": "connect-info" }));
+
+        // Not reported unless asked for
+        let response = self_lookup("/api/lookup/self", Some(("x-forwarded-for", FIXTURE_US_IP)))
+            .await
+            .unwrap();
+        let json = serde_json::to_value(&response.0.0).unwrap();
+        assert!(json.get("ip_source").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_lookup_deadline_header() {
+        use crate::test_support::{self, mmdb::FIXTURE_US_IP};
+
+        let state = Arc::new(test_support::app_state());
+        let lookup = |headers: HeaderMap| {
+            lookup_ip(Path(FIXTURE_US_IP.to_string()), Query(LookupParams::default()), State(Arc::clone(&state)), None, None, headers)
+        };
+
+        // A spent budget leaves only the tree's answer
+        let mut headers = HeaderMap::new();
+        headers.insert(fields::DEADLINE_HEADER, "0".parse().unwrap());
+        let json = serde_json::to_value(lookup(headers).await.unwrap().0.0).unwrap();
+        assert_eq!(json["partial"], true);
+        assert_eq!(json["skipped"], serde_json::json!(["geo", "asn"]));
+        assert_eq!(json["geo_info"], serde_json::Value::Null);
+        assert_eq!(json["recommended_action"], "allow");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(fields::DEADLINE_HEADER, "soon".parse().unwrap());
+        assert!(matches!(lookup(headers).await, Err(AppError::BadRequest(_))));
+
+        // A generous budget changes nothing
+        let mut headers = HeaderMap::new();
+        headers.insert(fields::DEADLINE_HEADER, "5000".parse().unwrap());
+        let json = serde_json::to_value(lookup(headers).await.unwrap().0.0).unwrap();
+        assert!(json.get("partial").is_none() && json.get("skipped").is_none());
+        assert!(json["geo_info"].is_object());
+    }
+
+    #[tokio::test]
+    async fn test_lookup_spans() {
+        use crate::test_support::{self, mmdb::FIXTURE_US_IP, spans::SpanCapture};
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let capture = SpanCapture::default();
+        let subscriber = tracing_subscriber::registry().with(capture.clone());
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let state = Arc::new(test_support::app_state());
+        let response = lookup_ip(Path(FIXTURE_US_IP.to_string()), Query(LookupParams::default()), State(Arc::clone(&state)), None, None, HeaderMap::new())
+            .await
+            .unwrap();
+        assert_eq!(response.0.response.ip, FIXTURE_US_IP);
+
+        let handler = capture.span("infralock.lookup").unwrap();
+        assert_eq!(handler.parent, None);
+        assert_eq!(handler.fields["ip"], FIXTURE_US_IP);
+
+        let service = capture.span("infralock.lookup_service").unwrap();
+        assert_eq!(service.parent, Some("infralock.lookup"));
+        assert_eq!(service.fields["ip"], FIXTURE_US_IP);
+        assert_eq!(service.fields["cache_hit"], "false");
+        assert_eq!(service.fields["score"], "0");
+        assert_eq!(service.fields["action"], "allow");
+        // Clean IPs have no category
+        assert!(!service.fields.contains_key("category"));
+
+        // A repeat lookup is served from the cache
+        let cached_response = lookup_ip(Path(FIXTURE_US_IP.to_string()), Query(LookupParams::default()), State(state), None, None, HeaderMap::new()).await.unwrap();
+        assert_eq!(cached_response.0.response.threat_score, 0);
+        let cached = capture
+            .spans()
+            .into_iter()
+            .filter(|span| span.name == "infralock.lookup_service")
+            .nth(1)
+            .unwrap();
+        assert_eq!(cached.fields["cache_hit"], "true");
+        assert_eq!(cached.fields["action"], "allow");
+    }
+
+    #[tokio::test]
+    async fn test_missing_threat_data_policies() {
+        use crate::config::ThreatDataPolicy;
+        use crate::ip_lookup::{types::SourceFormat, IpRangeSource, IpVersion};
+        use crate::services::response_action::ResponseAction;
+
+        // A Tor feed is configured, but the tree is empty, as on a cold start
+        let state_with = |policy: ThreatDataPolicy, ranges| {
+            let mut config = test_support::ip_lookup_config();
+            config.sources = vec![IpRangeSource {
+                url: "https://example.invalid/tor.txt".to_string(),
+                category: IpCategory::TorExitNode,
+                name: "tor".to_string(),
+                enabled: true,
+                format: SourceFormat::Default,
+                max_delta_percent: None,
+                ip_version: IpVersion::V4,
+                mirrors: Vec::new(),
+            }];
+            AppState {
+                ip_lookup_service: Arc::new(IpLookupService::from_ranges(ranges, config)),
+                missing_data: MissingDataPolicy { policy, action: ResponseAction::Challenge, max_age: None },
+                ..test_support::app_state()
+            }
+        };
+        let ip: std::net::IpAddr = FIXTURE_US_IP.parse().unwrap();
+
+        let state = state_with(ThreatDataPolicy::Open, Vec::new());
+        let response = lookup_service(&state).lookup_ip(ip).await.unwrap();
+        assert_eq!(response.recommended_action, "allow");
+        assert!(!response.data_unavailable);
+        assert_eq!(health_check(State(Arc::new(state))).await.0.missing_threat_data, vec![IpCategory::TorExitNode]);
+
+        let state = state_with(ThreatDataPolicy::Degraded, Vec::new());
+        let response = lookup_service(&state).lookup_ip(ip).await.unwrap();
+        assert_eq!(response.recommended_action, "allow");
+        assert!(response.data_unavailable);
+
+        let state = state_with(ThreatDataPolicy::Closed, Vec::new());
+        let response = lookup_service(&state).lookup_ip(ip).await.unwrap();
+        assert_eq!(response.recommended_action, "challenge");
+        assert!(response.data_unavailable);
+        // Not cached, so the verdict clears as soon as the data arrives
+        assert!(!lookup_service(&state).is_cached(ip));
+        // Explanations carry the same escalation
+        let explain = |state: &AppState| explain_threat_score(Path(FIXTURE_US_IP.to_string()), State(Arc::new(state.clone())), None);
+        let explained = explain(&state).await.unwrap().0;
+        assert_eq!((explained.recommended_action, explained.decision), (ResponseAction::Challenge, ActionDecision::MissingData));
+        assert!(explained.data_unavailable);
+
+        // In monitor mode the escalation is only the shadow action
+        state.monitor_override.set(true);
+        let response = lookup_service(&state).lookup_ip(ip).await.unwrap();
+        assert_eq!(response.recommended_action, "monitor");
+        assert_eq!(response.shadow_action.as_deref(), Some("challenge"));
+        assert_eq!(explain(&state).await.unwrap().0.recommended_action, ResponseAction::Monitor);
+
+        // With the feed's entries loaded, lookups are scored as usual
+        let mut state = state_with(ThreatDataPolicy::Closed, vec![test_support::range("5.1.1.1/32", IpCategory::TorExitNode)]);
+        let response = lookup_service(&state).lookup_ip(ip).await.unwrap();
+        assert_eq!(response.recommended_action, "allow");
+        assert!(!response.data_unavailable);
+
+        // A verdict cached while the data was fresh is not served once it
+        // goes stale
+        let (response, status) = lookup_service(&state).lookup_ip_with_status(ip, None).await.unwrap();
+        assert_eq!((response.recommended_action.as_str(), status), ("allow", crate::services::lookup_service::CacheStatus::Hit));
+        state.missing_data.max_age = Some(Duration::from_millis(1));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let (response, status) = lookup_service(&state).lookup_ip_with_status(ip, None).await.unwrap();
+        assert_eq!((response.recommended_action.as_str(), status), ("challenge", crate::services::lookup_service::CacheStatus::Miss));
+        assert!(response.data_unavailable);
+    }
+
+    #[tokio::test]
+    async fn test_max_findings_truncates_threat_details() {
+        // A 6to4 address in a VPN range whose IPv4 origin is a Tor exit node
+        let state_with = |max_findings| AppState {
+            tunnel_extraction: true,
+            max_findings,
+            ..test_support::app_state_with_ranges(vec![
+                test_support::range("2002::/16", IpCategory::Vpn),
+                test_support::range("5.1.1.1/32", IpCategory::TorExitNode),
+            ])
+        };
+        let ip: std::net::IpAddr = "2002:501:101::1".parse().unwrap();
+        let full = lookup_service(&state_with(Some(2))).lookup_ip(ip).await.unwrap();
+        assert_eq!(full.threat_details.len(), 2);
+        assert!(!full.findings_truncated);
+        assert!(serde_json::to_value(&full).unwrap().get("findings_truncated").is_none());
+
+        let response = lookup_service(&state_with(Some(1))).lookup_ip(ip).await.unwrap();
+        // Tor outweighs the VPN; the verdict still counts both
+        assert_eq!(response.threat_details, vec!["IP is a known Tor exit node (via 6to4 tunnel from 5.1.1.1)"]);
+        assert!(response.findings_truncated);
+        assert_eq!((response.threat_score, response.recommended_action.as_str()), (full.threat_score, "block"));
+    }
+
+    #[tokio::test]
+    async fn test_blocked_lookups_counted_by_country() {
+        use crate::monitoring::BLOCKED_TOTAL;
+        use crate::test_support::mmdb::FIXTURE_DE_IP;
+
+        let blocked = || BLOCKED_TOTAL.with_label_values(&["DE", "tor_exit_node"]).get();
+        let state = test_support::app_state_with_ranges(vec![test_support::range("2a00:1450:4001::1/128", IpCategory::TorExitNode)]);
+        let ip: std::net::IpAddr = FIXTURE_DE_IP.parse().unwrap();
+        let before = blocked();
+
+        // Cache hits are served lookups too
+        for _ in 0..2 {
+            let response = profile_lookup_service(&state, None).lookup_ip(ip).await.unwrap();
+            assert_eq!(response.recommended_action, "block");
+        }
+        assert_eq!(blocked(), before + 2);
+
+        // Internal lookups and a disabled metric leave the counter alone
+        lookup_service(&state).lookup_ip(ip).await.unwrap();
+        let state = AppState { blocked_metric: false, ..state };
+        profile_lookup_service(&state, None).lookup_ip(ip).await.unwrap();
+        assert_eq!(blocked(), before + 2);
+    }
+
+    const TOR_IP: &str = "5.1.1.1";
+    const VPN_IP: &str = "5.2.2.2";
+
     fn setup_test_state() -> Arc<AppState> {
-        // Setup a test MaxMind reader with test data
-        unimplemented!()
+        Arc::new(test_support::app_state_with_ranges(vec![
+            test_support::range("5.1.1.1/32", IpCategory::TorExitNode),
+            test_support::range("5.2.2.0/24", IpCategory::Vpn),
+        ]))
     }
 }
\ No newline at end of file
@@ -0,0 +1,227 @@
+//! Per-source feed archives and the diffs between successive versions.
+//!
+//! Each time a source's entries change, the sorted, de-duplicated entry list
+//! is written to `<dir>/<source>/entries-<unix millis>.txt`, one network per
+//! line, keeping the newest `retain` files. The next version is diffed
+//! against the newest file by streaming it alongside the new sorted list, so
+//! the previous version is never held in memory.
+
+use std::cmp::Ordering;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tracing::warn;
+
+use crate::ip_lookup::types::Result;
+use crate::utils::file_ops::atomic_replace;
+
+const ARCHIVE_PREFIX: &str = "entries-";
+const ARCHIVE_EXTENSION: &str = "txt";
+
+/// How a source's entries changed between two versions
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct FeedDiff {
+    pub added: usize,
+    pub removed: usize,
+    pub unchanged: usize,
+}
+
+impl FeedDiff {
+    /// Whether the two versions hold the same entries
+    pub fn is_unchanged(&self) -> bool {
+        self.added == 0 && self.removed == 0
+    }
+}
+
+/// The last diff computed for a source
+#[derive(Debug, Clone, Serialize)]
+pub struct SourceDiff {
+    #[serde(flatten)]
+    pub diff: FeedDiff,
+    pub computed_at: DateTime<Utc>,
+}
+
+/// Count added, removed and unchanged entries between two ascending,
+/// de-duplicated entry streams
+pub fn diff_sorted<P, C, S>(previous: P, current: C) -> std::io::Result<FeedDiff>
+where
+    P: IntoIterator<Item = std::io::Result<String>>,
+    C: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let mut previous = previous.into_iter();
+    let mut current = current.into_iter();
+    let mut diff = FeedDiff::default();
+
+    let mut prev = previous.next().transpose()?;
+    let mut curr = current.next();
+    loop {
+        match (&prev, &curr) {
+            (Some(p), Some(c)) => match p.as_str().cmp(c.as_ref()) {
+                Ordering::Less => {
+                    diff.removed += 1;
+                    prev = previous.next().transpose()?;
+                }
+                Ordering::Greater => {
+                    diff.added += 1;
+                    curr = current.next();
+                }
+                Ordering::Equal => {
+                    diff.unchanged += 1;
+                    prev = previous.next().transpose()?;
+                    curr = current.next();
+                }
+            },
+            (Some(_), None) => {
+                diff.removed += 1;
+                prev = previous.next().transpose()?;
+            }
+            (None, Some(_)) => {
+                diff.added += 1;
+                curr = current.next();
+            }
+            (None, None) => return Ok(diff),
+        }
+    }
+}
+
+/// Keeps the last `retain` entry lists per source in a directory
+#[derive(Debug, Clone)]
+pub struct FeedArchive {
+    dir: PathBuf,
+    retain: usize,
+}
+
+impl FeedArchive {
+    /// Create an archive in `dir` that keeps at most `retain` files per source
+    pub fn new(dir: impl Into<PathBuf>, retain: usize) -> Self {
+        Self {
+            dir: dir.into(),
+            retain,
+        }
+    }
+
+    /// Diff `entries` (ascending, de-duplicated) against the newest archived
+    /// version of `source`, or `None` if there is no previous version
+    pub fn diff<S: AsRef<str>>(&self, source: &str, entries: &[S]) -> Result<Option<FeedDiff>> {
+        let Some(latest) = self.list(source)?.into_iter().next() else {
+            return Ok(None);
+        };
+        let reader = BufReader::new(File::open(latest)?);
+        Ok(Some(diff_sorted(reader.lines(), entries)?))
+    }
+
+    /// Write `entries` as the newest version of `source` and prune old ones
+    pub fn save<S: AsRef<str>>(&self, source: &str, entries: &[S]) -> Result<PathBuf> {
+        let dir = self.dir.join(source);
+        fs::create_dir_all(&dir)?;
+
+        let path = dir.join(format!(
+            "{}{}.{}",
+            ARCHIVE_PREFIX,
+            Utc::now().timestamp_millis(),
+            ARCHIVE_EXTENSION
+        ));
+
+        // Write to a temporary file first so a crash never leaves a partial list
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        for entry in entries {
+            writeln!(writer, "{}", entry.as_ref())?;
+        }
+        writer.flush()?;
+        drop(writer);
+        atomic_replace(&tmp_path, &path)?;
+
+        for old in self.list(source)?.into_iter().skip(self.retain) {
+            if let Err(e) = fs::remove_file(&old) {
+                warn!("Failed to remove old feed archive {}: {}", old.display(), e);
+            }
+        }
+        Ok(path)
+    }
+
+    /// Archived files for `source`, newest first
+    fn list(&self, source: &str) -> Result<Vec<PathBuf>> {
+        let entries = match fs::read_dir(self.dir.join(source)) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut files = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if let Some(millis) = archive_timestamp(&path) {
+                files.push((millis, path));
+            }
+        }
+        files.sort_by_key(|(millis, _)| std::cmp::Reverse(*millis));
+        Ok(files.into_iter().map(|(_, path)| path).collect())
+    }
+}
+
+/// Parse the timestamp from an `entries-<millis>.txt` file name
+fn archive_timestamp(path: &Path) -> Option<i64> {
+    if path.extension()? != ARCHIVE_EXTENSION {
+        return None;
+    }
+    path.file_stem()?
+        .to_str()?
+        .strip_prefix(ARCHIVE_PREFIX)?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn sorted(entries: &[&str]) -> Vec<String> {
+        let mut entries: Vec<String> = entries.iter().map(|s| s.to_string()).collect();
+        entries.sort();
+        entries.dedup();
+        entries
+    }
+
+    #[test]
+    fn test_diff_sorted() {
+        let previous = sorted(&["10.0.0.0/8", "172.16.0.0/12", "192.168.0.0/16"]);
+        let current = sorted(&["10.0.0.0/8", "192.168.0.0/16", "198.18.0.0/15", "203.0.113.0/24"]);
+
+        let diff = diff_sorted(previous.into_iter().map(Ok), &current).unwrap();
+        assert_eq!(diff, FeedDiff { added: 2, removed: 1, unchanged: 2 });
+
+        let diff = diff_sorted(std::iter::empty(), &current).unwrap();
+        assert_eq!(diff, FeedDiff { added: 4, removed: 0, unchanged: 0 });
+    }
+
+    #[test]
+    fn test_archive_diff_and_prune() {
+        let dir = tempdir().unwrap();
+        let archive = FeedArchive::new(dir.path(), 2);
+
+        let v1 = sorted(&["1.1.1.0/24", "2.2.2.0/24", "3.3.3.0/24", "4.4.4.0/24"]);
+        assert_eq!(archive.diff("vpn", &v1).unwrap(), None);
+        archive.save("vpn", &v1).unwrap();
+
+        // A collapsed feed: most entries gone, one new
+        let v2 = sorted(&["1.1.1.0/24", "5.5.5.0/24"]);
+        let diff = archive.diff("vpn", &v2).unwrap().unwrap();
+        assert_eq!(diff, FeedDiff { added: 1, removed: 3, unchanged: 1 });
+
+        for version in [&v2, &v1] {
+            std::thread::sleep(std::time::Duration::from_millis(5));
+            archive.save("vpn", version).unwrap();
+        }
+        assert_eq!(archive.list("vpn").unwrap().len(), 2);
+        assert!(archive.diff("vpn", &v1).unwrap().unwrap().is_unchanged());
+
+        // Sources are archived independently
+        assert_eq!(archive.diff("tor", &v1).unwrap(), None);
+    }
+}
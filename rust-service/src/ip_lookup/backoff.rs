@@ -0,0 +1,139 @@
+//! Backoff for feeds that keep failing.
+//!
+//! A source that is down gets retried on every update otherwise, adding a
+//! timeout and an error log each time. After each consecutive failure the
+//! next attempt is pushed back, doubling from one update interval up to a
+//! cap, and the first success clears it. A source waiting out its backoff
+//! is treated like a failed one.
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+
+/// A source whose recent updates failed
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SourceFailure {
+    pub name: String,
+    pub consecutive_failures: u32,
+    pub last_error: String,
+    /// Start of the first update in the current run of failures
+    pub failing_since: DateTime<Utc>,
+    /// Updates starting before this skip the source
+    pub next_attempt_at: DateTime<Utc>,
+}
+
+#[derive(Debug)]
+pub struct FeedBackoff {
+    /// Delay after the first failure, doubled by each one after it
+    base: Duration,
+    /// Longest delay; zero retries every update
+    max: Duration,
+    failures: HashMap<String, SourceFailure>,
+}
+
+impl FeedBackoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            failures: HashMap::new(),
+        }
+    }
+
+    /// Whether an update starting at `started` should try `source`. Updates
+    /// run on a fixed interval, so one starting within half an interval of
+    /// the retry time counts as on time.
+    pub fn is_due(&self, source: &str, started: DateTime<Utc>) -> bool {
+        self.failures
+            .get(source)
+            .is_none_or(|failure| started + self.base / 2 >= failure.next_attempt_at)
+    }
+
+    /// Record that the update starting at `started` failed to load `source`
+    pub fn record_failure(&mut self, source: &str, error: String, started: DateTime<Utc>) -> &SourceFailure {
+        let failure = self.failures.entry(source.to_string()).or_insert_with(|| SourceFailure {
+            name: source.to_string(),
+            consecutive_failures: 0,
+            last_error: String::new(),
+            failing_since: started,
+            next_attempt_at: started,
+        });
+        failure.consecutive_failures += 1;
+        failure.last_error = error;
+        failure.next_attempt_at = started + delay(self.base, self.max, failure.consecutive_failures);
+        failure
+    }
+
+    /// Clear `source`'s failures, returning them if it was failing
+    pub fn record_success(&mut self, source: &str) -> Option<SourceFailure> {
+        self.failures.remove(source)
+    }
+
+    pub fn get(&self, source: &str) -> Option<&SourceFailure> {
+        self.failures.get(source)
+    }
+
+    /// Failing sources, ordered by name
+    pub fn failures(&self) -> Vec<SourceFailure> {
+        let mut failures: Vec<_> = self.failures.values().cloned().collect();
+        failures.sort_by(|a, b| a.name.cmp(&b.name));
+        failures
+    }
+}
+
+/// `base` doubled for each failure after the first, capped at `max`
+fn delay(base: Duration, max: Duration, failures: u32) -> Duration {
+    if max <= Duration::zero() {
+        return Duration::zero();
+    }
+    let factor = 1i32.checked_shl(failures.saturating_sub(1).min(30)).unwrap_or(i32::MAX);
+    base.checked_mul(factor).map_or(max, |delay| delay.min(max))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_doubles_up_to_the_cap() {
+        let start = Utc::now();
+        let mut backoff = FeedBackoff::new(Duration::hours(1), Duration::hours(6));
+        let next: Vec<_> = (0..5)
+            .map(|_| (backoff.record_failure("vpn", "timed out".to_string(), start).next_attempt_at - start).num_hours())
+            .collect();
+        assert_eq!(next, vec![1, 2, 4, 6, 6]);
+
+        let failure = backoff.get("vpn").unwrap();
+        assert_eq!(failure.consecutive_failures, 5);
+        assert_eq!(failure.failing_since, start);
+    }
+
+    #[test]
+    fn test_skips_until_due_and_resets_on_success() {
+        let start = Utc::now();
+        let mut backoff = FeedBackoff::new(Duration::hours(1), Duration::days(1));
+        backoff.record_failure("vpn", "timed out".to_string(), start);
+        backoff.record_failure("vpn", "timed out".to_string(), start);
+        // Retry in two intervals: the next update skips, the one after tries
+        assert!(!backoff.is_due("vpn", start + Duration::hours(1)));
+        assert!(backoff.is_due("vpn", start + Duration::hours(2) - Duration::seconds(1)));
+        assert!(backoff.is_due("tor", start));
+
+        let cleared = backoff.record_success("vpn").unwrap();
+        assert_eq!(cleared.consecutive_failures, 2);
+        assert!(backoff.is_due("vpn", start));
+        assert!(backoff.failures().is_empty());
+    }
+
+    #[test]
+    fn test_zero_cap_retries_every_update() {
+        let start = Utc::now();
+        let mut backoff = FeedBackoff::new(Duration::hours(1), Duration::zero());
+        for _ in 0..3 {
+            backoff.record_failure("vpn", "timed out".to_string(), start);
+        }
+        assert!(backoff.is_due("vpn", start));
+        assert_eq!(backoff.failures()[0].consecutive_failures, 3);
+    }
+}
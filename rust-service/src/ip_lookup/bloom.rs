@@ -0,0 +1,178 @@
+//! Bloom filter over the IPv4 blocks that contain any tree entry.
+//!
+//! Every network is recorded at /16 granularity if it is /16 or wider, and at
+//! /24 granularity otherwise. An address whose /16 and /24 are both absent
+//! cannot be inside any network, so the tree walk can be skipped. The filter
+//! only ever produces false positives; those fall through to the tree.
+
+use std::net::Ipv4Addr;
+
+use ip_network::Ipv4Network;
+
+/// Target false positive rate when sizing the filter
+const FALSE_POSITIVE_RATE: f64 = 0.01;
+
+#[derive(Debug, Clone)]
+pub struct PrefixBloom {
+    bits: Vec<u64>,
+    /// Number of bits, always a power of two so indexes can be masked
+    len: u64,
+    hashes: u32,
+}
+
+/// A /16 or /24 block, tagged with its prefix length so the two levels never collide
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BlockKey(u64);
+
+impl BlockKey {
+    fn slash16(addr: u32) -> Self {
+        Self((16 << 32) | u64::from(addr >> 16))
+    }
+
+    fn slash24(addr: u32) -> Self {
+        Self((24 << 32) | u64::from(addr >> 8))
+    }
+
+    /// Keys covering every address in `network`
+    fn covering(network: Ipv4Network) -> impl Iterator<Item = BlockKey> {
+        let first = u32::from(network.network_address());
+        let prefix = network.netmask();
+        let (level, key): (u8, fn(u32) -> BlockKey) = if prefix <= 16 {
+            (16, BlockKey::slash16)
+        } else {
+            (24, BlockKey::slash24)
+        };
+        let count = 1u32 << level.saturating_sub(prefix);
+        let step = 1u32 << (32 - level);
+        (0..count).map(move |i| key(first.wrapping_add(i.wrapping_mul(step))))
+    }
+}
+
+/// SplitMix64 finalizer; cheap and well mixed for small integer keys
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+impl PrefixBloom {
+    /// Build a filter over `networks`
+    pub fn from_networks<I>(networks: I) -> Self
+    where
+        I: IntoIterator<Item = Ipv4Network>,
+    {
+        let keys: Vec<BlockKey> = networks.into_iter().flat_map(BlockKey::covering).collect();
+        let mut filter = Self::with_capacity(keys.len());
+        for key in keys {
+            filter.insert_key(key);
+        }
+        filter
+    }
+
+    fn with_capacity(keys: usize) -> Self {
+        let keys = keys.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-keys * FALSE_POSITIVE_RATE.ln() / (ln2 * ln2)).ceil() as u64;
+        let len = bits.next_power_of_two().max(64);
+        let hashes = ((len as f64 / keys) * ln2).round().clamp(1.0, 16.0) as u32;
+        Self {
+            bits: vec![0; (len / 64) as usize],
+            len,
+            hashes,
+        }
+    }
+
+    /// Record `network`, e.g. one inserted after the filter was built
+    pub fn insert(&mut self, network: Ipv4Network) {
+        for key in BlockKey::covering(network) {
+            self.insert_key(key);
+        }
+    }
+
+    /// `false` means no recorded network contains `addr`
+    pub fn may_contain(&self, addr: Ipv4Addr) -> bool {
+        let addr = u32::from(addr);
+        self.contains_key(BlockKey::slash16(addr)) || self.contains_key(BlockKey::slash24(addr))
+    }
+
+    /// Double hashing: bit `i` is `h1 + i * h2`
+    fn positions(len: u64, hashes: u32, key: BlockKey) -> impl Iterator<Item = u64> {
+        let hash = mix(key.0);
+        let (h1, h2) = (hash, (hash >> 32) | 1);
+        (0..u64::from(hashes)).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) & (len - 1))
+    }
+
+    fn insert_key(&mut self, key: BlockKey) {
+        for bit in Self::positions(self.len, self.hashes, key) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    fn contains_key(&self, key: BlockKey) -> bool {
+        Self::positions(self.len, self.hashes, key)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn net(s: &str) -> Ipv4Network {
+        s.parse().unwrap()
+    }
+
+    /// Deterministic pseudo-random addresses
+    fn addresses(count: u32) -> impl Iterator<Item = Ipv4Addr> {
+        (0..count).map(|i| Ipv4Addr::from(mix(u64::from(i)) as u32))
+    }
+
+    #[test]
+    fn test_no_false_negatives() {
+        let networks: Vec<Ipv4Network> = addresses(5_000)
+            .enumerate()
+            .map(|(i, addr)| {
+                let prefix = [8, 12, 16, 20, 24, 28, 32][i % 7];
+                Ipv4Network::new_truncate(addr, prefix).unwrap()
+            })
+            .collect();
+        let filter = PrefixBloom::from_networks(networks.iter().copied());
+
+        for network in &networks {
+            let first = u32::from(network.network_address());
+            let last = u32::from(network.broadcast_address());
+            for addr in [first, last, first + (last - first) / 2] {
+                assert!(filter.may_contain(addr.into()), "{} in {}", Ipv4Addr::from(addr), network);
+            }
+        }
+    }
+
+    #[test]
+    fn test_rejects_most_absent_addresses() {
+        let filter = PrefixBloom::from_networks(addresses(10_000).map(|addr| Ipv4Network::new(addr, 32).unwrap()));
+
+        let false_positives = addresses(110_000)
+            .skip(10_000)
+            .filter(|addr| filter.may_contain(*addr))
+            .count();
+        // Two keys are checked per address, so allow for twice the target rate
+        assert!(false_positives < 4_000, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn test_insert_after_build() {
+        let mut filter = PrefixBloom::from_networks([net("10.0.0.0/8")]);
+        assert!(filter.may_contain("10.200.1.1".parse().unwrap()));
+
+        filter.insert(net("192.0.2.0/24"));
+        assert!(filter.may_contain("192.0.2.77".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_whole_address_space() {
+        let filter = PrefixBloom::from_networks([net("0.0.0.0/0")]);
+        for addr in addresses(1_000) {
+            assert!(filter.may_contain(addr));
+        }
+    }
+}
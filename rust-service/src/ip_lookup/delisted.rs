@@ -0,0 +1,110 @@
+//! Tor exit nodes that recently dropped off their feed.
+//!
+//! Exit lists churn within hours, so a client may still hold a verdict for an
+//! IP the list stopped carrying minutes ago. Each load of a Tor source is
+//! diffed against its previous load, and the entries that disappeared are
+//! remembered for a window so lookups can report them as recently delisted.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::net::IpAddr;
+
+use chrono::{DateTime, Duration, Utc};
+use ip_network::IpNetwork;
+
+#[derive(Debug)]
+pub struct DelistedTracker {
+    window: Duration,
+    /// Entries from each source's last load
+    previous: HashMap<String, HashSet<IpNetwork>>,
+    /// When each delisted entry disappeared from its feed
+    delisted: HashMap<IpNetwork, DateTime<Utc>>,
+    /// Prefix lengths present in `delisted`, so lookups try only those
+    prefixes: BTreeSet<u8>,
+}
+
+impl DelistedTracker {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            previous: HashMap::new(),
+            delisted: HashMap::new(),
+            prefixes: BTreeSet::new(),
+        }
+    }
+
+    /// Diff `entries` against the source's last load. The first load of a
+    /// source delists nothing; entries listed again are no longer delisted.
+    pub fn record_load(&mut self, source: &str, entries: HashSet<IpNetwork>, now: DateTime<Utc>) {
+        self.expire(now);
+        if let Some(previous) = self.previous.get(source) {
+            for network in previous.difference(&entries) {
+                self.delisted.insert(*network, now);
+            }
+        }
+        for network in &entries {
+            self.delisted.remove(network);
+        }
+        self.previous.insert(source.to_string(), entries);
+        self.prefixes = self.delisted.keys().map(|network| network.netmask()).collect();
+    }
+
+    /// Whether `ip` falls in an entry delisted within the window before `now`
+    pub fn contains(&self, ip: IpAddr, now: DateTime<Utc>) -> bool {
+        self.prefixes.iter().any(|&prefix| {
+            IpNetwork::new_truncate(ip, prefix)
+                .ok()
+                .and_then(|network| self.delisted.get(&network))
+                .is_some_and(|delisted_at| now - *delisted_at < self.window)
+        })
+    }
+
+    fn expire(&mut self, now: DateTime<Utc>) {
+        let window = self.window;
+        self.delisted.retain(|_, delisted_at| now - *delisted_at < window);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(networks: &[&str]) -> HashSet<IpNetwork> {
+        networks.iter().map(|network| network.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_tracks_entries_that_left_the_feed() {
+        let start = Utc::now();
+        let mut tracker = DelistedTracker::new(Duration::hours(1));
+        tracker.record_load("tor", entries(&["1.1.1.1/32", "2.2.2.2/32"]), start);
+        // Nothing to diff the first load against
+        assert!(!tracker.contains("1.1.1.1".parse().unwrap(), start));
+
+        let later = start + Duration::minutes(10);
+        tracker.record_load("tor", entries(&["2.2.2.2/32", "2001:db8::/64"]), later);
+        assert!(tracker.contains("1.1.1.1".parse().unwrap(), later));
+        assert!(!tracker.contains("2.2.2.2".parse().unwrap(), later));
+        assert!(!tracker.contains("1.1.1.2".parse().unwrap(), later));
+        assert!(!tracker.contains("1.1.1.1".parse().unwrap(), later + Duration::hours(1)));
+
+        // Dropped IPv6 entries cover their whole prefix
+        tracker.record_load("tor", entries(&["1.1.1.1/32"]), later);
+        assert!(tracker.contains("2001:db8::42".parse().unwrap(), later));
+        // and listing an entry again clears it
+        assert!(!tracker.contains("1.1.1.1".parse().unwrap(), later));
+        assert!(tracker.contains("2.2.2.2".parse().unwrap(), later));
+    }
+
+    #[test]
+    fn test_sources_are_diffed_separately() {
+        let now = Utc::now();
+        let mut tracker = DelistedTracker::new(Duration::hours(1));
+        tracker.record_load("tor-a", entries(&["1.1.1.1/32"]), now);
+        tracker.record_load("tor-b", entries(&["2.2.2.2/32"]), now);
+        assert!(!tracker.contains("1.1.1.1".parse().unwrap(), now));
+
+        tracker.record_load("tor-b", entries(&[]), now);
+        assert!(tracker.contains("2.2.2.2".parse().unwrap(), now));
+        assert!(!tracker.contains("1.1.1.1".parse().unwrap(), now));
+    }
+}
@@ -0,0 +1,168 @@
+//! Diff feeds: sources with [`SourceFormat::Delta`] publish the entries added
+//! and removed since a cursor instead of their whole list.
+//!
+//! Each line is `+<CIDR or IP>` or `-<CIDR or IP>`, and a `# cursor: <value>`
+//! line names the position the diff brings the list to. The cursor is sent
+//! back as `?since=<value>` on the next fetch; a fetch without one should
+//! return the full list as `+` lines. The entries accumulated so far are kept
+//! in `<data dir>/<source>.delta.json`, so a restart resumes from the cursor
+//! instead of fetching everything again.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::ip_lookup::service::IpRangeSource;
+use crate::ip_lookup::types::{IpRange, IpRangeError, Result, SourceFormat};
+use crate::utils::file_ops::atomic_replace;
+
+/// Query parameter the cursor is sent back in
+const SINCE_PARAM: &str = "since";
+
+/// One line of a diff feed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeltaChange {
+    Add(String),
+    Remove(String),
+}
+
+/// A parsed diff feed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Delta {
+    pub changes: Vec<DeltaChange>,
+    /// Position the diff brings the list to; `None` keeps the current cursor
+    pub cursor: Option<String>,
+}
+
+/// A diff source's entries and how far into its feed they are
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeltaState {
+    pub cursor: Option<String>,
+    entries: BTreeSet<String>,
+}
+
+impl DeltaState {
+    /// Read the state saved at `path`; a missing file is an empty state
+    pub fn load(path: &Path) -> Result<Self> {
+        match fs::read(path) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_vec(self)?)?;
+        atomic_replace(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Apply `delta` and advance the cursor, returning the changes that did
+    /// something: adds of new entries and removals of present ones
+    pub fn apply(&mut self, delta: Delta) -> Vec<DeltaChange> {
+        let applied = delta
+            .changes
+            .into_iter()
+            .filter(|change| match change {
+                DeltaChange::Add(network) => self.entries.insert(network.clone()),
+                DeltaChange::Remove(network) => self.entries.remove(network),
+            })
+            .collect();
+        if delta.cursor.is_some() {
+            self.cursor = delta.cursor;
+        }
+        applied
+    }
+
+    /// Number of entries the source currently lists
+    pub fn count(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// The entries as ranges of `source`, for a full rebuild
+    pub fn ranges(&self, source: &IpRangeSource) -> Vec<IpRange> {
+        self.entries
+            .iter()
+            .map(|network| IpRange::new(network.as_str(), source.category, &source.name, SourceFormat::Delta))
+            .collect()
+    }
+
+    /// Entries other than `network` that `same_network` maps onto the same
+    /// tree network, which removing it must leave in place
+    pub fn shares_network(&self, network: &str, same_network: impl Fn(&str) -> bool) -> bool {
+        self.entries.iter().any(|entry| entry != network && same_network(entry))
+    }
+
+    /// `url` asking for the changes since the cursor
+    pub fn since_url(&self, url: &str) -> Result<Url> {
+        let mut url = Url::parse(url).map_err(|e| IpRangeError::InvalidUrl(format!("Invalid URL '{}': {}", url, e)))?;
+        if let Some(cursor) = &self.cursor {
+            url.query_pairs_mut().append_pair(SINCE_PARAM, cursor);
+        }
+        Ok(url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delta(changes: &[(&str, bool)], cursor: Option<&str>) -> Delta {
+        Delta {
+            changes: changes
+                .iter()
+                .map(|&(network, add)| {
+                    if add {
+                        DeltaChange::Add(network.to_string())
+                    } else {
+                        DeltaChange::Remove(network.to_string())
+                    }
+                })
+                .collect(),
+            cursor: cursor.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_apply_tracks_entries_and_cursor() {
+        let mut state = DeltaState::default();
+        let applied = state.apply(delta(&[("192.0.2.0/24", true), ("198.51.100.0/24", true)], Some("c1")));
+        assert_eq!(applied.len(), 2);
+        assert_eq!(state.cursor.as_deref(), Some("c1"));
+
+        // Repeated adds and removals of absent entries change nothing
+        let applied = state.apply(delta(
+            &[("192.0.2.0/24", true), ("203.0.113.0/24", false), ("198.51.100.0/24", false)],
+            None,
+        ));
+        assert_eq!(applied, vec![DeltaChange::Remove("198.51.100.0/24".to_string())]);
+        assert_eq!(state.cursor.as_deref(), Some("c1"));
+        assert_eq!(state.count(), 1);
+
+        assert_eq!(
+            state.since_url("https://feeds.example.com/delta?list=vpn").unwrap().as_str(),
+            "https://feeds.example.com/delta?list=vpn&since=c1"
+        );
+        assert_eq!(
+            DeltaState::default().since_url("https://feeds.example.com/delta").unwrap().as_str(),
+            "https://feeds.example.com/delta"
+        );
+    }
+
+    #[test]
+    fn test_state_round_trips_through_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("internal.delta.json");
+        assert_eq!(DeltaState::load(&path).unwrap(), DeltaState::default());
+
+        let mut state = DeltaState::default();
+        state.apply(delta(&[("192.0.2.0/24", true)], Some("2025-01-01")));
+        state.save(&path).unwrap();
+        assert_eq!(DeltaState::load(&path).unwrap(), state);
+    }
+}
@@ -0,0 +1,497 @@
+//! Flat, memory-mapped form of the radix tree for fast cold starts.
+//!
+//! Rebuilding the `IpNetworkTable`s for a million networks takes seconds and
+//! briefly holds the parsed ranges and the tree at once. So after each update
+//! the tree is also written as sorted, disjoint address intervals, each
+//! carrying the entry of its most specific network. On startup the file is
+//! mapped and lookups binary-search it in place until the rebuilt tree is
+//! swapped in.
+//!
+//! Layout, little-endian: [`MAGIC`], a `u32` length and the JSON [`Header`],
+//! then the IPv4 records (`start: u32, end: u32, category: u8, 0u8,
+//! source: u16`) and the IPv6 records (the same with `u128` bounds). Records
+//! are fixed-size so they can be read where they lie, which is also why the
+//! file is not compressed.
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+
+use crate::ip_lookup::types::{CloudKind, IpCategory, IpRangeError, Result};
+
+const MAGIC: &[u8; 8] = b"ILFLAT01";
+/// `source` of records whose network was inserted without one
+const NO_SOURCE: u16 = u16::MAX;
+const V4_RECORD: usize = 12;
+const V6_RECORD: usize = 36;
+
+/// What a flattened interval resolves to. `source` indexes [`FlatTree::sources`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlatEntry {
+    pub category: IpCategory,
+    pub source: Option<u16>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    sources: Vec<String>,
+    /// Networks per family in the tree the file was written from
+    networks: (usize, usize),
+    category_counts: Vec<(IpCategory, usize)>,
+    v4_intervals: usize,
+    v6_intervals: usize,
+    written_at: DateTime<Utc>,
+}
+
+/// A flat snapshot mapped into memory
+#[derive(Debug)]
+pub struct FlatTree {
+    map: Mmap,
+    header: Header,
+    /// Byte offsets of the IPv4 and IPv6 records
+    v4_offset: usize,
+    v6_offset: usize,
+}
+
+impl FlatTree {
+    /// Map the snapshot at `path`, checking only its header and size; records
+    /// are read on lookup
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::open(path.as_ref())?;
+        // SAFETY: snapshots are only ever replaced by renaming a new file over
+        // them, so the mapped file is never modified while it is in use
+        let map = unsafe { Mmap::map(&file)? };
+        let invalid = |reason: &str| IpRangeError::InvalidSnapshot(format!("{}: {}", path.as_ref().display(), reason));
+
+        if map.len() < MAGIC.len() + 4 || &map[..MAGIC.len()] != MAGIC {
+            return Err(invalid("not a flat tree snapshot"));
+        }
+        let header_len = u32::from_le_bytes(map[8..12].try_into().unwrap()) as usize;
+        let header_end = 12usize.checked_add(header_len).filter(|&end| end <= map.len()).ok_or_else(|| invalid("truncated header"))?;
+        let header: Header = serde_json::from_slice(&map[12..header_end])?;
+
+        // Counts from a corrupt header must not overflow into a size that matches
+        let v6_offset = header.v4_intervals.checked_mul(V4_RECORD).and_then(|len| len.checked_add(header_end));
+        let end = v6_offset
+            .zip(header.v6_intervals.checked_mul(V6_RECORD))
+            .and_then(|(offset, len)| offset.checked_add(len));
+        let (Some(v6_offset), Some(end)) = (v6_offset, end) else {
+            return Err(invalid("size does not match its header"));
+        };
+        if end != map.len() {
+            return Err(invalid("size does not match its header"));
+        }
+        Ok(Self {
+            map,
+            header,
+            v4_offset: header_end,
+            v6_offset,
+        })
+    }
+
+    /// Feed names that record sources index into
+    pub fn sources(&self) -> &[String] {
+        &self.header.sources
+    }
+
+    /// Networks per family in the tree the snapshot was written from
+    pub fn networks(&self) -> (usize, usize) {
+        self.header.networks
+    }
+
+    pub fn category_counts(&self) -> &[(IpCategory, usize)] {
+        &self.header.category_counts
+    }
+
+    pub fn written_at(&self) -> DateTime<Utc> {
+        self.header.written_at
+    }
+
+    /// The entry of the most specific network containing `ip`
+    pub fn lookup(&self, ip: IpAddr) -> Option<FlatEntry> {
+        let (family, ip) = self.family(ip);
+        let index = family.upper_bound(ip).checked_sub(1)?;
+        let (_, end, entry) = family.record(index);
+        if ip > end {
+            return None;
+        }
+        // A source the header does not name is dropped rather than looked up
+        entry.map(|entry| FlatEntry {
+            source: entry.source.filter(|&id| usize::from(id) < self.header.sources.len()),
+            ..entry
+        })
+    }
+
+    /// Whether any address from `start` to `end` resolves to `category`.
+    /// Less specific networks hidden under a more specific one are not seen.
+    pub fn overlaps(&self, start: IpAddr, end: IpAddr, category: IpCategory) -> bool {
+        let (family, start) = self.family(start);
+        let (_, end) = self.family(end);
+        let first = family.upper_bound(start).saturating_sub(1);
+        (first..family.len)
+            .map(|index| family.record(index))
+            .take_while(|&(interval_start, _, _)| interval_start <= end)
+            .any(|(_, interval_end, entry)| {
+                interval_end >= start && entry.is_some_and(|entry| entry.category == category)
+            })
+    }
+
+    fn family(&self, ip: IpAddr) -> (Family<'_>, u128) {
+        match ip {
+            IpAddr::V4(ip) => (
+                Family {
+                    records: &self.map[self.v4_offset..self.v6_offset],
+                    len: self.header.v4_intervals,
+                    v6: false,
+                },
+                u128::from(u32::from(ip)),
+            ),
+            IpAddr::V6(ip) => (
+                Family {
+                    records: &self.map[self.v6_offset..],
+                    len: self.header.v6_intervals,
+                    v6: true,
+                },
+                u128::from(ip),
+            ),
+        }
+    }
+}
+
+/// The records of one address family, with bounds widened to `u128`
+struct Family<'a> {
+    records: &'a [u8],
+    len: usize,
+    v6: bool,
+}
+
+impl Family<'_> {
+    fn record(&self, index: usize) -> (u128, u128, Option<FlatEntry>) {
+        if self.v6 {
+            let record = &self.records[index * V6_RECORD..(index + 1) * V6_RECORD];
+            let start = u128::from_le_bytes(record[0..16].try_into().unwrap());
+            let end = u128::from_le_bytes(record[16..32].try_into().unwrap());
+            (start, end, decode_entry(&record[32..36]))
+        } else {
+            let record = &self.records[index * V4_RECORD..(index + 1) * V4_RECORD];
+            let start = u32::from_le_bytes(record[0..4].try_into().unwrap());
+            let end = u32::from_le_bytes(record[4..8].try_into().unwrap());
+            (u128::from(start), u128::from(end), decode_entry(&record[8..12]))
+        }
+    }
+
+    /// Index of the first interval starting after `ip`
+    fn upper_bound(&self, ip: u128) -> usize {
+        let (mut low, mut high) = (0, self.len);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.record(mid).0 <= ip {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        low
+    }
+}
+
+/// A network or interval as `(first, last, entry)`
+pub type Interval = (u128, u128, FlatEntry);
+
+/// Disjoint intervals covering the same addresses as `networks`, each with the
+/// entry of the most specific network containing it. `networks` must nest or
+/// be disjoint, as CIDR blocks do. `nested` is called with each network
+/// containing another, then the one it contains, as the sorted pass finds them.
+pub fn flatten(mut networks: Vec<Interval>, mut nested: impl FnMut(&Interval, &Interval)) -> Vec<Interval> {
+    // Containing networks sort before the networks they contain
+    networks.sort_unstable_by_key(|&(start, end, _)| (start, std::cmp::Reverse(end)));
+
+    let mut intervals: Vec<Interval> = Vec::with_capacity(networks.len());
+    let mut emit = |start: u128, end: u128, entry: FlatEntry| match intervals.last_mut() {
+        Some(last) if last.2 == entry && last.1.checked_add(1) == Some(start) => last.1 = end,
+        _ => intervals.push((start, end, entry)),
+    };
+
+    // Networks containing the cursor, innermost last; `None` once the
+    // cursor has passed the top of the address space
+    let mut open: Vec<Interval> = Vec::new();
+    let mut cursor = Some(0u128);
+    for network in networks {
+        let start = network.0;
+        while let Some(&(_, open_end, open_entry)) = open.last() {
+            if open_end >= start {
+                break;
+            }
+            if let Some(from) = cursor.filter(|&from| from <= open_end) {
+                emit(from, open_end, open_entry);
+            }
+            cursor = open_end.checked_add(1);
+            open.pop();
+        }
+        if let (Some(&(_, _, open_entry)), Some(from)) = (open.last(), cursor) {
+            if from < start {
+                emit(from, start - 1, open_entry);
+            }
+        }
+        for outer in &open {
+            nested(outer, &network);
+        }
+        cursor = Some(start);
+        open.push(network);
+    }
+    while let Some((_, open_end, open_entry)) = open.pop() {
+        if let Some(from) = cursor.filter(|&from| from <= open_end) {
+            emit(from, open_end, open_entry);
+        }
+        cursor = open_end.checked_add(1);
+    }
+    intervals
+}
+
+/// The tree contents [`write`] needs, with each family's networks already
+/// passed through [`flatten`]
+pub struct FlatContents<'a> {
+    pub sources: &'a [Arc<str>],
+    pub networks: (usize, usize),
+    pub category_counts: Vec<(IpCategory, usize)>,
+    pub v4: Vec<Interval>,
+    pub v6: Vec<Interval>,
+}
+
+/// Write `contents` to `path`, replacing it atomically so a mapped copy is
+/// never modified
+pub fn write<P: AsRef<Path>>(path: P, contents: FlatContents<'_>) -> Result<()> {
+    let path = path.as_ref();
+    let (v4, v6) = (contents.v4, contents.v6);
+    let header = serde_json::to_vec(&Header {
+        sources: contents.sources.iter().map(|source| source.to_string()).collect(),
+        networks: contents.networks,
+        category_counts: contents.category_counts,
+        v4_intervals: v4.len(),
+        v6_intervals: v6.len(),
+        written_at: Utc::now(),
+    })?;
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp_path = path.with_extension("tmp");
+    let mut out = BufWriter::new(File::create(&tmp_path)?);
+    out.write_all(MAGIC)?;
+    out.write_all(&(header.len() as u32).to_le_bytes())?;
+    out.write_all(&header)?;
+    for (start, end, entry) in v4 {
+        out.write_all(&(start as u32).to_le_bytes())?;
+        out.write_all(&(end as u32).to_le_bytes())?;
+        out.write_all(&encode_entry(entry))?;
+    }
+    for (start, end, entry) in v6 {
+        out.write_all(&start.to_le_bytes())?;
+        out.write_all(&end.to_le_bytes())?;
+        out.write_all(&encode_entry(entry))?;
+    }
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+fn encode_entry(entry: FlatEntry) -> [u8; 4] {
+    let category = match entry.category {
+        IpCategory::Vpn => 0,
+        IpCategory::ProxyHttp => 1,
+        IpCategory::ProxySocks4 => 2,
+        IpCategory::ProxySocks5 => 3,
+        IpCategory::TorExitNode => 4,
+        IpCategory::CloudProvider(CloudKind::Aws) => 16,
+        IpCategory::CloudProvider(CloudKind::Gcp) => 17,
+        IpCategory::CloudProvider(CloudKind::Azure) => 18,
+        IpCategory::CloudProvider(CloudKind::Oci) => 19,
+    };
+    let [low, high] = entry.source.unwrap_or(NO_SOURCE).to_le_bytes();
+    [category, 0, low, high]
+}
+
+/// `None` for category codes this build doesn't know, so they read as misses
+fn decode_entry(bytes: &[u8]) -> Option<FlatEntry> {
+    let category = match bytes[0] {
+        0 => IpCategory::Vpn,
+        1 => IpCategory::ProxyHttp,
+        2 => IpCategory::ProxySocks4,
+        3 => IpCategory::ProxySocks5,
+        4 => IpCategory::TorExitNode,
+        16 => IpCategory::CloudProvider(CloudKind::Aws),
+        17 => IpCategory::CloudProvider(CloudKind::Gcp),
+        18 => IpCategory::CloudProvider(CloudKind::Azure),
+        19 => IpCategory::CloudProvider(CloudKind::Oci),
+        _ => return None,
+    };
+    let source = u16::from_le_bytes([bytes[2], bytes[3]]);
+    Some(FlatEntry {
+        category,
+        source: (source != NO_SOURCE).then_some(source),
+    })
+}
+
+/// First and last address of an IPv4 network, as [`FlatContents`] takes them
+pub fn v4_bounds(network: ip_network::Ipv4Network) -> (u128, u128) {
+    (
+        u128::from(u32::from(network.network_address())),
+        u128::from(u32::from(network.broadcast_address())),
+    )
+}
+
+/// First and last address of an IPv6 network, as [`FlatContents`] takes them
+pub fn v6_bounds(network: ip_network::Ipv6Network) -> (u128, u128) {
+    (u128::from(network.network_address()), u128::from(network.last_address()))
+}
+
+/// The network whose bounds [`v4_bounds`] or [`v6_bounds`] gave
+pub fn bounds_network(v6: bool, start: u128, end: u128) -> Option<ip_network::IpNetwork> {
+    let host_bits = (end - start).count_ones() as u8;
+    if v6 {
+        ip_network::Ipv6Network::new(start.into(), 128 - host_bits).ok().map(Into::into)
+    } else {
+        let start = u32::try_from(start).ok()?;
+        ip_network::Ipv4Network::new(start.into(), 32 - host_bits).ok().map(Into::into)
+    }
+}
+
+/// First and last address of `network` as addresses, for [`FlatTree::overlaps`]
+pub fn network_bounds(network: ip_network::IpNetwork) -> (IpAddr, IpAddr) {
+    match network {
+        ip_network::IpNetwork::V4(net) => (net.network_address().into(), net.broadcast_address().into()),
+        ip_network::IpNetwork::V6(net) => (net.network_address().into(), net.last_address().into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(category: IpCategory, source: u16) -> FlatEntry {
+        FlatEntry {
+            category,
+            source: Some(source),
+        }
+    }
+
+    fn v4(network: &str, entry: FlatEntry) -> Interval {
+        let (start, end) = v4_bounds(network.parse().unwrap());
+        (start, end, entry)
+    }
+
+    #[test]
+    fn test_flatten_prefers_the_most_specific_network() {
+        let vpn = entry(IpCategory::Vpn, 0);
+        let tor = entry(IpCategory::TorExitNode, 1);
+        let proxy = entry(IpCategory::ProxyHttp, 2);
+        let mut pairs = Vec::new();
+        let intervals = flatten(
+            vec![
+                v4("10.0.0.128/25", proxy),
+                v4("10.0.0.0/8", vpn),
+                v4("10.0.0.0/24", tor),
+                v4("255.255.255.255/32", proxy),
+                v4("255.255.255.0/24", vpn),
+            ],
+            |outer, inner| pairs.push((outer.2.category, inner.2.category)),
+        );
+        let addr = |ip: &str| u128::from(u32::from(ip.parse::<std::net::Ipv4Addr>().unwrap()));
+        assert_eq!(
+            intervals,
+            vec![
+                (addr("10.0.0.0"), addr("10.0.0.127"), tor),
+                (addr("10.0.0.128"), addr("10.0.0.255"), proxy),
+                (addr("10.0.1.0"), addr("10.255.255.255"), vpn),
+                (addr("255.255.255.0"), addr("255.255.255.254"), vpn),
+                (addr("255.255.255.255"), addr("255.255.255.255"), proxy),
+            ]
+        );
+        // Every containing network is reported, outermost first
+        assert_eq!(
+            pairs,
+            vec![
+                (IpCategory::Vpn, IpCategory::TorExitNode),
+                (IpCategory::Vpn, IpCategory::ProxyHttp),
+                (IpCategory::TorExitNode, IpCategory::ProxyHttp),
+                (IpCategory::Vpn, IpCategory::ProxyHttp),
+            ]
+        );
+        // Adjacent intervals with the same entry are merged
+        assert_eq!(flatten(vec![v4("10.0.0.0/25", vpn), v4("10.0.0.128/25", vpn)], |_, _| {}).len(), 1);
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("tree.flat");
+        let sources: Vec<Arc<str>> = vec!["vpn".into(), "tor".into()];
+        let all: (u128, u128) = (0, u128::MAX);
+        write(
+            &path,
+            FlatContents {
+                sources: &sources,
+                networks: (2, 2),
+                category_counts: vec![(IpCategory::Vpn, 3), (IpCategory::TorExitNode, 1)],
+                v4: flatten(
+                    vec![v4("10.0.0.0/8", entry(IpCategory::Vpn, 0)), v4("10.1.2.3/32", entry(IpCategory::TorExitNode, 1))],
+                    |_, _| {},
+                ),
+                v6: flatten(
+                    vec![
+                        (all.0, all.1, FlatEntry { category: IpCategory::Vpn, source: None }),
+                        {
+                            let (start, end) = v6_bounds("2001:db8::/32".parse().unwrap());
+                            (start, end, entry(IpCategory::CloudProvider(CloudKind::Gcp), 0))
+                        },
+                    ],
+                    |_, _| {},
+                ),
+            },
+        )
+        .unwrap();
+
+        let flat = FlatTree::open(&path).unwrap();
+        assert_eq!(flat.sources(), ["vpn", "tor"]);
+        assert_eq!(flat.networks(), (2, 2));
+        let lookup = |ip: &str| flat.lookup(ip.parse().unwrap());
+        assert_eq!(lookup("10.1.2.3"), Some(entry(IpCategory::TorExitNode, 1)));
+        assert_eq!(lookup("10.1.2.4"), Some(entry(IpCategory::Vpn, 0)));
+        assert_eq!(lookup("9.255.255.255"), None);
+        assert_eq!(lookup("11.0.0.0"), None);
+        assert_eq!(lookup("2001:db8::1"), Some(entry(IpCategory::CloudProvider(CloudKind::Gcp), 0)));
+        assert_eq!(lookup("ffff::1"), Some(FlatEntry { category: IpCategory::Vpn, source: None }));
+
+        let (start, end) = network_bounds("10.1.0.0/16".parse().unwrap());
+        assert!(flat.overlaps(start, end, IpCategory::TorExitNode));
+        let (start, end) = network_bounds("10.2.0.0/16".parse().unwrap());
+        assert!(!flat.overlaps(start, end, IpCategory::TorExitNode));
+        assert!(flat.overlaps(start, end, IpCategory::Vpn));
+
+        // Truncated files are refused rather than read past their end
+        let bytes = fs::read(&path).unwrap();
+        let truncated = dir.path().join("truncated.flat");
+        fs::write(&truncated, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(matches!(FlatTree::open(&truncated), Err(IpRangeError::InvalidSnapshot(_))));
+        fs::write(&truncated, b"{}").unwrap();
+        assert!(matches!(FlatTree::open(&truncated), Err(IpRangeError::InvalidSnapshot(_))));
+
+        // Interval counts that overflow are refused, not multiplied
+        let header_len = u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize;
+        let mut header: serde_json::Value = serde_json::from_slice(&bytes[12..12 + header_len]).unwrap();
+        header["v4_intervals"] = serde_json::json!(usize::MAX / 2);
+        let header = serde_json::to_vec(&header).unwrap();
+        let mut corrupt = bytes[..8].to_vec();
+        corrupt.extend((header.len() as u32).to_le_bytes());
+        corrupt.extend(&header);
+        corrupt.extend(&bytes[12 + header_len..]);
+        fs::write(&truncated, corrupt).unwrap();
+        assert!(matches!(FlatTree::open(&truncated), Err(IpRangeError::InvalidSnapshot(_))));
+    }
+}
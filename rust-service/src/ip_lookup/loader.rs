@@ -1,7 +1,8 @@
 use std::path::{Path, PathBuf};
 use std::fs;
 use std::io;
-use std::net::IpAddr;
+use std::collections::BTreeMap;
+use std::net::{AddrParseError, IpAddr, SocketAddr};
 use chrono::{DateTime, Utc};
 use ipnetwork::IpNetwork;
 use reqwest::Client;
@@ -9,12 +10,375 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use url::Url;
 use filetime;
-use tracing::{info, error};
+use tracing::{info, error, warn};
 
 use crate::ip_lookup::{
+    delta::{Delta, DeltaChange, DeltaState},
     service::IpRangeSource,
     types::{IpCategory, IpRange, IpRangeError, Result, SourceFormat, IpVersion},
 };
+use crate::config::data_layout::DataLayout;
+use crate::utils::http_client::{redact_credentials, CertPins};
+
+/// Parse an `ExitAddress <ip> <date> <time>` line from a Tor exit list.
+///
+/// Returns `None` for any other line (`ExitNode`, `Published`, `LastStatus`),
+/// so IPv4 and IPv6 exit addresses go through the same parsing path.
+pub fn parse_tor_exit_address(line: &str) -> Option<std::result::Result<IpAddr, AddrParseError>> {
+    let mut parts = line.split_whitespace();
+    if parts.next()? != "ExitAddress" {
+        return None;
+    }
+    Some(parts.next().unwrap_or_default().parse())
+}
+
+#[derive(Deserialize)]
+struct AwsIpRanges {
+    #[serde(default)]
+    prefixes: Vec<AwsPrefix>,
+    #[serde(default)]
+    ipv6_prefixes: Vec<AwsIpv6Prefix>,
+}
+
+#[derive(Deserialize)]
+struct AwsPrefix {
+    ip_prefix: String,
+}
+
+#[derive(Deserialize)]
+struct AwsIpv6Prefix {
+    ipv6_prefix: String,
+}
+
+/// Networks in an AWS `ip-ranges.json` document, IPv4 first.
+///
+/// AWS lists a prefix once per service (`AMAZON`, `EC2`, ...), so the same
+/// network can appear more than once.
+pub fn parse_aws_ip_ranges(content: &str) -> serde_json::Result<Vec<String>> {
+    let doc: AwsIpRanges = serde_json::from_str(content)?;
+    Ok(doc
+        .prefixes
+        .into_iter()
+        .map(|p| p.ip_prefix)
+        .chain(doc.ipv6_prefixes.into_iter().map(|p| p.ipv6_prefix))
+        .collect())
+}
+
+#[derive(Deserialize)]
+struct GcpCloudJson {
+    #[serde(default)]
+    prefixes: Vec<GcpPrefix>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GcpPrefix {
+    ipv4_prefix: Option<String>,
+    ipv6_prefix: Option<String>,
+}
+
+/// Networks in a GCP `cloud.json` document; each prefix entry carries
+/// either an `ipv4Prefix` or an `ipv6Prefix`
+pub fn parse_gcp_cloud_json(content: &str) -> serde_json::Result<Vec<String>> {
+    let doc: GcpCloudJson = serde_json::from_str(content)?;
+    Ok(doc
+        .prefixes
+        .into_iter()
+        .filter_map(|p| p.ipv4_prefix.or(p.ipv6_prefix))
+        .collect())
+}
+
+/// Format a single address as a host network (`/32` or `/128`)
+fn host_network(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(_) => format!("{}/32", ip),
+        IpAddr::V6(_) => format!("{}/128", ip),
+    }
+}
+
+/// Entries from a JSON list feed: either a MISP warning list
+/// (`{"list": [...]}`) or a bare array
+fn parse_json_list(content: &str) -> Option<Vec<Value>> {
+    match serde_json::from_str::<Value>(content).ok()? {
+        Value::Object(mut doc) => match doc.remove("list")? {
+            Value::Array(list) => Some(list),
+            _ => None,
+        },
+        Value::Array(list) => Some(list),
+        _ => None,
+    }
+}
+
+/// Feed bytes as text for the parsers: a UTF-8 byte order mark is dropped
+/// and `\r\n` or lone `\r` line endings become `\n`. UTF-16 and binary
+/// content are rejected rather than parsed into nothing.
+pub fn decode_feed(bytes: &[u8]) -> Result<String> {
+    if bytes.starts_with(&[0xFF, 0xFE]) || bytes.starts_with(&[0xFE, 0xFF]) {
+        return Err(IpRangeError::UnsupportedEncoding("UTF-16 (byte order mark)".to_string()));
+    }
+    let nuls = bytes.iter().filter(|&&byte| byte == 0).count();
+    if nuls > 0 {
+        // ASCII in UTF-16 has every other byte zero
+        let odd_nuls = bytes.iter().skip(1).step_by(2).filter(|&&byte| byte == 0).count();
+        return Err(if nuls * 3 >= bytes.len() && (odd_nuls == nuls || odd_nuls == 0) {
+            IpRangeError::UnsupportedEncoding("UTF-16 (no byte order mark)".to_string())
+        } else {
+            IpRangeError::BinaryContent(format!("{} NUL bytes", nuls))
+        });
+    }
+    let text = std::str::from_utf8(bytes)
+        .map_err(|e| IpRangeError::BinaryContent(format!("invalid UTF-8 at byte {}", e.valid_up_to())))?;
+    let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+    if text.contains('\r') {
+        Ok(text.replace("\r\n", "\n").replace('\r', "\n"))
+    } else {
+        Ok(text.to_string())
+    }
+}
+
+/// Read a feed file per [`decode_feed`] and hand it to `parse`, for loaders
+/// that report `io::Error`s. The file is read with `tokio::fs`, and decoding
+/// and parsing run on the blocking pool, so a large list does not stall an
+/// async worker.
+pub async fn load_feed_file<T, F>(path: PathBuf, parse: F) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&str, &Path) -> io::Result<T> + Send + 'static,
+{
+    let bytes = tokio::fs::read(&path)
+        .await
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    tokio::task::spawn_blocking(move || {
+        let content = decode_feed(&bytes)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?;
+        parse(&content, &path)
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+/// Parse one line of a line-based feed into a network. `None` means the
+/// format ignores the line (e.g. Tor `ExitNode` records).
+fn parse_line(format: SourceFormat, line: &str) -> Option<std::result::Result<String, String>> {
+    match format {
+        // Only "ExitAddress IP DATE TIME" lines carry addresses
+        SourceFormat::TorExitList => Some(
+            parse_tor_exit_address(line)?
+                .map(host_network)
+                .map_err(|e| e.to_string()),
+        ),
+        // Extract IP from IP:PORT format
+        SourceFormat::IpPort => {
+            let ip_str = line.split(':').next().unwrap_or_default();
+            Some(ip_str.parse::<IpAddr>().map(host_network).map_err(|e| e.to_string()))
+        }
+        // CIDR or plain IP
+        _ => Some(match line.parse::<IpNetwork>() {
+            Ok(network) => Ok(network.to_string()),
+            Err(e) => line.parse::<IpAddr>().map(host_network).map_err(|_| e.to_string()),
+        }),
+    }
+}
+
+/// `ranges`, unless a feed with content yielded none of them
+fn non_empty(ranges: Vec<IpRange>, report: ParseReport, source: &IpRangeSource) -> Result<(Vec<IpRange>, ParseReport)> {
+    if ranges.is_empty() && report.total_lines > 0 {
+        return Err(IpRangeError::NoEntries { feed: source.name.clone(), lines: report.total_lines });
+    }
+    Ok((ranges, report))
+}
+
+/// How a [`SourceFormat::Auto`] line was read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LineStrategy {
+    Ip,
+    IpPort,
+    Cidr,
+}
+
+impl LineStrategy {
+    fn name(self) -> &'static str {
+        match self {
+            LineStrategy::Ip => "ip",
+            LineStrategy::IpPort => "ip_port",
+            LineStrategy::Cidr => "cidr",
+        }
+    }
+}
+
+/// Parse a line of a mixed feed by trying each line format in turn
+fn parse_auto_line(line: &str) -> std::result::Result<(String, LineStrategy), String> {
+    // Extra columns such as `1.2.3.4,US` or `1.2.3.4 # note`
+    let entry = line
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .next()
+        .unwrap_or_default();
+
+    if let Ok(ip) = entry.parse::<IpAddr>() {
+        return Ok((host_network(ip), LineStrategy::Ip));
+    }
+    // `1.2.3.4:8080` or `[2001:db8::1]:8080`
+    if let Ok(addr) = entry.parse::<SocketAddr>() {
+        return Ok((host_network(addr.ip()), LineStrategy::IpPort));
+    }
+    entry
+        .parse::<IpNetwork>()
+        .map(|network| (network.to_string(), LineStrategy::Cidr))
+        .map_err(|_| "not an IP, IP:PORT or CIDR".to_string())
+}
+
+/// Source name that override entries are tagged with
+pub const OVERRIDES_SOURCE: &str = "overrides";
+
+/// Parse an overrides file: one `CIDR category` pair per line (a bare IP is
+/// a host network), `#` comments allowed.
+pub fn parse_overrides(content: &str) -> (Vec<IpRange>, ParseReport) {
+    let mut ranges = Vec::new();
+    let mut report = ParseReport::default();
+
+    for (line_num, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        report.total_lines += 1;
+        if line.starts_with('#') {
+            report.skipped_comments += 1;
+            continue;
+        }
+
+        let mut fields = line.split_whitespace();
+        let (Some(network), Some(category), None) = (fields.next(), fields.next(), fields.next()) else {
+            report.record_invalid(line_num + 1, line, "expected `CIDR category`");
+            continue;
+        };
+        let network = match network.parse::<ip_network::IpNetwork>() {
+            Ok(network) => network.to_string(),
+            Err(e) => match network.parse::<IpAddr>() {
+                Ok(ip) => host_network(ip),
+                Err(_) => {
+                    report.record_invalid(line_num + 1, line, &e.to_string());
+                    continue;
+                }
+            },
+        };
+        match category.parse::<IpCategory>() {
+            Ok(category) => {
+                report.parsed += 1;
+                ranges.push(IpRange::new(network, category, OVERRIDES_SOURCE, SourceFormat::Default));
+            }
+            Err(e) => report.record_invalid(line_num + 1, line, &e.to_string()),
+        }
+    }
+
+    (ranges, report)
+}
+
+/// Prefix of the line naming a diff feed's new cursor
+const CURSOR_PREFIX: &str = "# cursor:";
+
+/// Parse a diff feed: `+CIDR` and `-CIDR` lines (a bare IP is a host
+/// network), `#` comments, and a `# cursor: <value>` line.
+pub fn parse_delta(content: &str) -> (Delta, ParseReport) {
+    let mut delta = Delta::default();
+    let mut report = ParseReport::default();
+
+    for (line_num, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        report.total_lines += 1;
+        if let Some(cursor) = line.strip_prefix(CURSOR_PREFIX) {
+            report.skipped_comments += 1;
+            delta.cursor = Some(cursor.trim().to_string()).filter(|cursor| !cursor.is_empty());
+            continue;
+        }
+        if line.starts_with('#') {
+            report.skipped_comments += 1;
+            continue;
+        }
+
+        let (change, entry): (fn(String) -> DeltaChange, _) = if let Some(entry) = line.strip_prefix('+') {
+            (DeltaChange::Add, entry)
+        } else if let Some(entry) = line.strip_prefix('-') {
+            (DeltaChange::Remove, entry)
+        } else {
+            report.record_invalid(line_num + 1, line, "expected `+` or `-` before the entry");
+            continue;
+        };
+        match parse_line(SourceFormat::Default, entry.trim()) {
+            Some(Ok(network)) => {
+                report.parsed += 1;
+                delta.changes.push(change(network));
+            }
+            Some(Err(reason)) => report.record_invalid(line_num + 1, line, &reason),
+            None => report.skipped_comments += 1,
+        }
+    }
+
+    (delta, report)
+}
+
+/// Sample errors kept per report; the rest are only counted
+const MAX_SAMPLE_ERRORS: usize = 20;
+
+/// Longest line content kept in a sample error
+const MAX_SAMPLE_CONTENT: usize = 120;
+
+/// Parse quality of one source, from its last load
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ParseReport {
+    /// Non-blank lines (entries, for JSON feeds)
+    pub total_lines: usize,
+    pub parsed: usize,
+    /// Comments and lines the format carries no address on
+    pub skipped_comments: usize,
+    pub invalid: usize,
+    /// The first few invalid entries
+    pub sample_errors: Vec<ParseErrorSample>,
+    /// Lines read by each format (`ip`, `ip_port`, `cidr`), for `Auto` sources
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub line_formats: BTreeMap<&'static str, usize>,
+}
+
+/// An entry that failed to parse
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ParseErrorSample {
+    pub line: usize,
+    pub content: String,
+    pub reason: String,
+}
+
+impl ParseReport {
+    fn record_invalid(&mut self, line: usize, content: &str, reason: &str) {
+        self.invalid += 1;
+        if self.sample_errors.len() < MAX_SAMPLE_ERRORS {
+            self.sample_errors.push(ParseErrorSample {
+                line,
+                content: content.chars().take(MAX_SAMPLE_CONTENT).collect(),
+                reason: reason.to_string(),
+            });
+        }
+    }
+}
+
+/// One URL tried on a source's download
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FetchAttempt {
+    /// With any credentials redacted
+    pub url: String,
+    /// Why the URL was passed over; `None` for the one that was used
+    pub error: Option<String>,
+}
+
+/// The URLs tried on a source's last download, in order
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct FetchReport {
+    pub attempts: Vec<FetchAttempt>,
+    /// The URL whose content was used; `None` if every URL failed
+    pub fetched_from: Option<String>,
+}
 
 /// Configuration for loading IP ranges
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -30,7 +394,7 @@ pub struct IpRangeLoaderConfig {
 impl Default for IpRangeLoaderConfig {
     fn default() -> Self {
         Self {
-            data_dir: PathBuf::from("data/ip_ranges"),
+            data_dir: DataLayout::default().ip_ranges_dir(),
             check_updates: true,
             max_cache_age_secs: 86400, // 24 hours
         }
@@ -42,18 +406,19 @@ impl Default for IpRangeLoaderConfig {
 pub struct IpRangeLoader {
     config: IpRangeLoaderConfig,
     http_client: Client,
+    cert_pins: CertPins,
 }
 
 impl IpRangeLoader {
-    /// Create a new IP range loader with the given configuration
-    pub fn new(config: IpRangeLoaderConfig) -> Self {
-        Self {
-            config,
-            http_client: Client::builder()
-                .timeout(std::time::Duration::from_secs(30))
-                .build()
-                .expect("Failed to create HTTP client"),
-        }
+    /// Create a loader that downloads with `http_client`
+    pub fn with_http_client(config: IpRangeLoaderConfig, http_client: Client) -> Self {
+        Self { config, http_client, cert_pins: CertPins::default() }
+    }
+
+    /// Only accept feeds from servers presenting one of `cert_pins`
+    pub fn with_cert_pins(mut self, cert_pins: CertPins) -> Self {
+        self.cert_pins = cert_pins;
+        self
     }
 
     /// Load IP ranges from a file
@@ -63,135 +428,45 @@ impl IpRangeLoader {
         category: IpCategory,
         source: &str,
         format: SourceFormat,
-    ) -> Result<Vec<IpRange>> {
-        // For JsonList format, read the entire file and parse it as JSON
-        if format == SourceFormat::JsonList {
-            let content = tokio::fs::read_to_string(path.as_ref()).await.map_err(|e| {
-                IpRangeError::IoError(io::Error::new(
-                    e.kind(),
-                    format!("Failed to read {}: {}", path.as_ref().display(), e),
-                ))
-            })?;
-            
-            // Create a temporary source to pass to parse_ranges
-            let temp_source = IpRangeSource {
-                url: "file://".to_string() + path.as_ref().to_str().unwrap_or(""),
-                category,
-                name: source.to_string(),
-                enabled: true,
-                format: SourceFormat::JsonList,
-                ip_version: if path.as_ref().to_string_lossy().contains("_v6") {
-                    IpVersion::V6
-                } else {
-                    IpVersion::V4
-                },
-            };
-            
-            return self.parse_ranges(&content, &temp_source);
-        }
-        
-        // For other formats, use the line-by-line processing
-        use tokio::io::AsyncBufReadExt;
-        
-        let file = tokio::fs::File::open(path.as_ref()).await.map_err(|e| {
+    ) -> Result<(Vec<IpRange>, ParseReport)> {
+        let bytes = tokio::fs::read(path.as_ref()).await.map_err(|e| {
             IpRangeError::IoError(io::Error::new(
                 e.kind(),
-                format!("Failed to open {}: {}", path.as_ref().display(), e),
+                format!("Failed to read {}: {}", path.as_ref().display(), e),
             ))
         })?;
+        let content = decode_feed(&bytes)?;
 
-        let mut ranges = Vec::new();
-        let now = Utc::now();
-        let reader = tokio::io::BufReader::new(file);
-        let mut lines = reader.lines();
-        let mut line_num = 0;
-
-        while let Some(line) = lines.next_line().await.map_err(|e| {
-            IpRangeError::IoError(io::Error::new(
-                e.kind(),
-                format!("Error reading line {}: {}", line_num + 1, e),
-            ))
-        })? {
-            line_num += 1;
-            let line = line.trim();
-            if line.is_empty() || line.starts_with('#') {
-                continue;
-            }
-
-            // Handle different formats
-            let network = match format {
-                SourceFormat::TorExitList => {
-                    if line.starts_with("ExitNode") || line.starts_with("Published") || line.starts_with("LastStatus") {
-                        continue;
-                    }
-                    if let Some(ip_part) = line.split_whitespace().nth(1) {
-                        if let Ok(ip) = ip_part.parse::<IpAddr>() {
-                            match ip {
-                                IpAddr::V4(_) => format!("{}/32", ip),
-                                IpAddr::V6(_) => format!("{}/128", ip),
-                            }
-                        } else {
-                            error!("Failed to parse IP address at line {}: '{}'", line_num, line);
-                            continue;
-                        }
-                    } else {
-                        continue;
-                    }
-                },
-                SourceFormat::IpPort => {
-                    if let Some(ip_str) = line.split(':').next() {
-                        if let Ok(ip) = ip_str.parse::<IpAddr>() {
-                            match ip {
-                                IpAddr::V4(_) => format!("{}/32", ip_str),
-                                IpAddr::V6(_) => format!("{}/128", ip_str),
-                            }
-                        } else {
-                            error!("Failed to parse IP address at line {}: '{}'", line_num, line);
-                            continue;
-                        }
-                    } else {
-                        continue;
-                    }
-                },
-                SourceFormat::Default => {
-                    if line.contains('/') {
-                        line.to_string()
-                    } else if let Ok(ip) = line.parse::<IpAddr>() {
-                        match ip {
-                            IpAddr::V4(_) => format!("{}/32", line),
-                            IpAddr::V6(_) => format!("{}/128", line),
-                        }
-                    } else {
-                        error!("Failed to parse IP network at line {}: '{}'", line_num, line);
-                        continue;
-                    }
-                },
-                SourceFormat::JsonList => {
-                    // This should never be reached due to the early return above
-                    error!("Unexpected JsonList format in line processing loop");
-                    continue;
-                }
-            };
-
-            ranges.push(IpRange {
-                network,
-                category,
-                source: source.to_string(),
-                first_seen: now,
-                last_updated: now,
-                format,
-            });
-        }
+        // Create a temporary source to pass to parse_ranges
+        let temp_source = IpRangeSource {
+            url: "file://".to_string() + path.as_ref().to_str().unwrap_or(""),
+            category,
+            name: source.to_string(),
+            enabled: true,
+            format,
+            max_delta_percent: None,
+            ip_version: if path.as_ref().to_string_lossy().contains("_v6") {
+                IpVersion::V6
+            } else {
+                IpVersion::V4
+            },
+            mirrors: Vec::new(),
+        };
 
-        Ok(ranges)
+        self.parse_ranges(&content, &temp_source)
     }
 
     /// Download IP ranges from a URL
+    #[tracing::instrument(
+        name = "infralock.download_ranges",
+        skip_all,
+        fields(url = %url, source = %source.name)
+    )]
     pub async fn download_ranges(
         &self,
         url: &str,
         source: &IpRangeSource,
-    ) -> Result<Vec<IpRange>> {
+    ) -> Result<(Vec<IpRange>, ParseReport)> {
         // Parse the URL
         let url_obj = Url::parse(url).map_err(|e| {
             IpRangeError::InvalidUrl(format!("Invalid URL '{}': {}", url, e))
@@ -213,7 +488,7 @@ impl IpRangeLoader {
         let content = self.download_file(url).await?;
         
         // Parse the content
-        let ranges = self.parse_ranges(&content, source)?;
+        let (ranges, report) = self.parse_ranges(&content, source)?;
         
         // Save to file
         tokio::fs::write(&filepath, &content).await.map_err(|e| {
@@ -237,177 +512,145 @@ impl IpRangeLoader {
             filepath.display()
         );
         
-        Ok(ranges)
+        Ok((ranges, report))
+    }
+
+    /// Download a source from its URL, falling back to each mirror in turn
+    /// when a URL cannot be reached, answers with an error status or serves
+    /// content that is rejected. Of several failures, a rejected download is
+    /// returned over the others, so the caller can still fall back to its
+    /// last good copy.
+    pub async fn download_with_mirrors(&self, source: &IpRangeSource) -> (Result<(Vec<IpRange>, ParseReport)>, FetchReport) {
+        let mut fetch = FetchReport::default();
+        let mut rejected = None;
+        let mut last_error = None;
+        for url in source.urls() {
+            let shown = redact_credentials(url);
+            match self.download_ranges(url, source).await {
+                Ok(found) => {
+                    if !fetch.attempts.is_empty() {
+                        info!(source = %source.name, url = %shown, failed = fetch.attempts.len(), "Downloaded from a mirror");
+                    }
+                    fetch.attempts.push(FetchAttempt { url: shown.clone(), error: None });
+                    fetch.fetched_from = Some(shown);
+                    return (Ok(found), fetch);
+                }
+                Err(e) => {
+                    warn!(source = %source.name, url = %shown, error = %e, "Failed to download source");
+                    fetch.attempts.push(FetchAttempt { url: shown, error: Some(e.to_string()) });
+                    if e.is_bad_content() && rejected.is_none() {
+                        rejected = Some(e);
+                    } else {
+                        last_error = Some(e);
+                    }
+                }
+            }
+        }
+        let error = rejected.or(last_error).expect("a source has at least one URL");
+        (Err(error), fetch)
+    }
+
+    /// Download the changes to a diff feed since `state`'s cursor
+    #[tracing::instrument(
+        name = "infralock.download_delta",
+        skip_all,
+        fields(url = %source.url, source = %source.name, cursor = ?state.cursor)
+    )]
+    pub async fn download_delta(&self, source: &IpRangeSource, state: &DeltaState) -> Result<(Delta, ParseReport)> {
+        let url = state.since_url(&source.url)?;
+        let content = self.download_file(url.as_str()).await?;
+        Ok(parse_delta(&content))
     }
 
     /// Parse IP ranges from a string
+    ///
+    /// Malformed entries are skipped and counted in the returned report
+    /// rather than logged one by one.
     pub fn parse_ranges(
         &self,
         content: &str,
         source: &IpRangeSource,
-    ) -> Result<Vec<IpRange>> {
+    ) -> Result<(Vec<IpRange>, ParseReport)> {
         let mut ranges = Vec::new();
-        //info!("Starting to parse ranges for source: {} (format: {:?})", source.name, source.format);
-        
-        // Handle JSON format first
-        if source.format == SourceFormat::JsonList {
-            //info!("Processing as JSON list");
-            // Try to parse as JSON array (MISP warning list format)
-            if let Ok(json_value) = serde_json::from_str::<serde_json::Value>(content) {
-                //info!("Parsing JSON content");
-                
-                // Handle MISP warning list format ({"list": ["cidr1", "cidr2", ...]})
-                if let Some(list) = json_value.get("list").and_then(|l| l.as_array()) {
-                    info!("Found MISP warning list format with {} entries", list.len());
-                    for (i, cidr_value) in list.iter().enumerate() {
-                        if let Some(cidr) = cidr_value.as_str() {
-                            //info!("[{}] Parsing CIDR: {}", i, cidr);
-                            match cidr.parse::<IpNetwork>() {
-                                Ok(ip_net) => {
-                                    //info!("Successfully parsed network: {}", ip_net);
-                                    ranges.push(IpRange::new(
-                                        cidr.to_string(),
-                                        source.category,
-                                        &source.name,
-                                        source.format
-                                    ));
-                                }
-                                Err(e) => {
-                                    error!("Failed to parse CIDR '{}': {}", cidr, e);
-                                }
-                            }
-                        } else {
-                            error!("Expected string in MISP list at index {}", i);
-                        }
-                    }
-                    //info!("Processed {} networks from MISP warning list", ranges.len());
-                    return Ok(ranges);
-                }
-                // Handle root array format (["cidr1", "cidr2", ...])
-                else if let Some(array) = json_value.as_array() {
-                    //info!("Found root array format with {} entries", array.len());
-                    for (i, cidr_value) in array.iter().enumerate() {
-                        if let Some(cidr) = cidr_value.as_str() {
-                            //info!("[{}] Parsing CIDR: {}", i, cidr);
-                            match cidr.parse::<IpNetwork>() {
-                                Ok(ip_net) => {
-                                    //info!("Successfully parsed network: {}", ip_net);
-                                    ranges.push(IpRange::new(
-                                        cidr.to_string(),
-                                        source.category,
-                                        &source.name,
-                                        source.format
-                                    ));
-                                }
-                                Err(e) => {
-                                    error!("Failed to parse CIDR '{}': {}", cidr, e);
-                                }
-                            }
-                        } else {
-                            error!("Expected string in array at index {}", i);
-                        }
-                    }
-                    //info!("Processed {} networks from root array", ranges.len());
-                    return Ok(ranges);
-                } else {
-                    info!("JSON is not in a recognized format, falling back to text parsing");
+        let mut report = ParseReport::default();
+        let mut accept = |network: String, report: &mut ParseReport| {
+            report.parsed += 1;
+            ranges.push(IpRange::new(network, source.category, &source.name, source.format));
+        };
+
+        // Cloud provider feeds have their own document layouts
+        let entries = match source.format {
+            SourceFormat::AwsIpRanges => Some(parse_aws_ip_ranges(content)?.into_iter().map(Value::String).collect()),
+            SourceFormat::GcpCloudJson => Some(parse_gcp_cloud_json(content)?.into_iter().map(Value::String).collect()),
+            SourceFormat::JsonList => {
+                let list = parse_json_list(content);
+                if list.is_none() {
+                    info!(source = %source.name, "Content is not a recognized JSON list, falling back to plain text parsing");
                 }
-            } else {
-                info!("Content is not valid JSON, falling back to text parsing");
+                list
             }
-            
-            // If we get here, it's not in MISP format, try parsing as plain text
-            info!("Falling back to plain text parsing");
-            for (i, line) in content.lines().enumerate() {
-                let line = line.trim();
-                if line.is_empty() || line.starts_with('#') {
+            _ => None,
+        };
+
+        if let Some(entries) = entries {
+            for (i, entry) in entries.into_iter().enumerate() {
+                report.total_lines += 1;
+                let Value::String(cidr) = entry else {
+                    report.record_invalid(i + 1, &entry.to_string(), "expected a string");
                     continue;
-                }
-                
-                info!("[{}] Parsing line: {}", i, line);
-                match line.parse::<IpNetwork>() {
-                    Ok(ip_net) => {
-                        info!("Successfully parsed network: {}", ip_net);
-                        ranges.push(IpRange::new(
-                            ip_net.to_string(),
-                            source.category,
-                            &source.name,
-                            source.format
-                        ));
-                    }
-                    Err(e) => {
-                        error!("Failed to parse line '{}' as network: {}", line, e);
-                    }
+                };
+                match cidr.parse::<IpNetwork>() {
+                    Ok(ip_net) => accept(ip_net.to_string(), &mut report),
+                    Err(e) => report.record_invalid(i + 1, &cidr, &e.to_string()),
                 }
             }
-            
-            info!("Processed {} networks from plain text", ranges.len());
-            return Ok(ranges);
+            return non_empty(ranges, report, source);
         }
-        
-        // Handle other formats
+
+        // Line formats; a JSON list that failed to parse is read as plain CIDRs
+        let format = match source.format {
+            SourceFormat::JsonList => SourceFormat::Default,
+            format => format,
+        };
         for (line_num, line) in content.lines().enumerate() {
             let line = line.trim();
-            if line.is_empty() || line.starts_with('#') {
+            if line.is_empty() {
                 continue;
             }
-    
-            match source.format {
-                SourceFormat::TorExitList => {
-                    // Skip non-IP lines in Tor exit node list
-                    if line.starts_with("ExitNode") || line.starts_with("Published") || line.starts_with("LastStatus") {
-                        continue;
-                    }
-                    
-                    // Format is "ExitAddress IP NICKNAME" - we want the second field
-                    if let Some(ip_part) = line.split_whitespace().nth(1) {
-                        if let Ok(ip) = ip_part.parse::<IpAddr>() {
-                            let network = match ip {
-                                IpAddr::V4(_) => format!("{}/32", ip),
-                                IpAddr::V6(_) => format!("{}/128", ip),
-                            };
-                            ranges.push(IpRange::new(network, source.category, &source.name, source.format));
-                        } else {
-                            error!("Failed to parse IP address at line {}: '{}'", line_num + 1, line);
-                        }
-                    }
-                },
-                SourceFormat::IpPort => {
-                    // Extract IP from IP:PORT format
-                    if let Some(ip_str) = line.split(':').next() {
-                        if let Ok(ip) = ip_str.parse::<IpAddr>() {
-                            let network = match ip {
-                                IpAddr::V4(_) => format!("{}/32", ip_str),
-                                IpAddr::V6(_) => format!("{}/128", ip_str),
-                            };
-                            ranges.push(IpRange::new(network, source.category, &source.name, source.format));
-                        } else {
-                            error!("Failed to parse IP address at line {}: '{}'", line_num + 1, line);
-                        }
-                    }
-                },
-                SourceFormat::Default => {
-                    // Default format - try to parse as CIDR, then as plain IP
-                    if let Ok(network) = line.parse::<IpNetwork>() {
-                        ranges.push(IpRange::new(network.to_string(), source.category, &source.name, source.format));
-                    } else if let Ok(ip) = line.parse::<IpAddr>() {
-                        let network = match ip {
-                            IpAddr::V4(_) => format!("{}/32", ip),
-                            IpAddr::V6(_) => format!("{}/128", ip),
-                        };
-                        ranges.push(IpRange::new(network, source.category, &source.name, source.format));
-                    } else {
-                        error!("Failed to parse IP network at line {}: '{}'", line_num + 1, line);
+            report.total_lines += 1;
+            if line.starts_with('#') {
+                report.skipped_comments += 1;
+                continue;
+            }
+
+            if format == SourceFormat::Auto {
+                match parse_auto_line(line) {
+                    Ok((network, strategy)) => {
+                        *report.line_formats.entry(strategy.name()).or_default() += 1;
+                        accept(network, &mut report);
                     }
-                },
-                SourceFormat::JsonList => {
-                    // This should never be reached due to the early return above
-                    error!("Unexpected JsonList format in line processing loop");
-                },
+                    Err(reason) => report.record_invalid(line_num + 1, line, &reason),
+                }
+                continue;
             }
+
+            match parse_line(format, line) {
+                Some(Ok(network)) => accept(network, &mut report),
+                Some(Err(reason)) => report.record_invalid(line_num + 1, line, &reason),
+                None => report.skipped_comments += 1,
+            }
+        }
+
+        if format == SourceFormat::Auto {
+            info!(
+                source = %source.name,
+                line_formats = ?report.line_formats,
+                invalid = report.invalid,
+                "Auto-detected line formats"
+            );
         }
-    
-        Ok(ranges)
+
+        non_empty(ranges, report, source)
     }
 
     /// Get the last modified time of a file
@@ -434,12 +677,12 @@ impl IpRangeLoader {
     pub fn filename_from_url(&self, _url: &Url, category: IpCategory, ip_version: IpVersion) -> String {
         // Map category to a simple string representation
         let category_str = match category {
-            IpCategory::Vpn => "vpns",
-            IpCategory::ProxyHttp => "http_proxies",
-            IpCategory::ProxySocks4 => "socks4_proxies",
-            IpCategory::ProxySocks5 => "socks5_proxies",
-            IpCategory::TorExitNode => "tor_exit_nodes",
-            _ => "ranges",
+            IpCategory::Vpn => "vpns".to_string(),
+            IpCategory::ProxyHttp => "http_proxies".to_string(),
+            IpCategory::ProxySocks4 => "socks4_proxies".to_string(),
+            IpCategory::ProxySocks5 => "socks5_proxies".to_string(),
+            IpCategory::TorExitNode => "tor_exit_nodes".to_string(),
+            IpCategory::CloudProvider(kind) => format!("{}_ranges", kind),
         };
         
         // Add IP version
@@ -449,8 +692,16 @@ impl IpRangeLoader {
         }
     }
 
-    /// Download a file from a URL
+    /// Download a feed from a URL, as text per [`decode_feed`]. `file://`
+    /// URLs are read from the local filesystem.
     async fn download_file(&self, url: &str) -> Result<String> {
+        if let Some(path) = local_path(url)? {
+            let bytes = tokio::fs::read(&path).await.map_err(|e| {
+                IpRangeError::IoError(io::Error::new(e.kind(), format!("Failed to read {}: {}", path.display(), e)))
+            })?;
+            return decode_feed(&bytes);
+        }
+
         let response = self
             .http_client
             .get(url)
@@ -461,6 +712,10 @@ impl IpRangeLoader {
                 e,
             )))?;
 
+        self.cert_pins
+            .check(&response)
+            .map_err(|e| IpRangeError::IoError(io::Error::new(io::ErrorKind::Other, e)))?;
+
         if !response.status().is_success() {
             return Err(IpRangeError::IoError(io::Error::new(
                 io::ErrorKind::Other,
@@ -468,12 +723,410 @@ impl IpRangeLoader {
             )));
         }
 
-        response
-            .text()
+        let bytes = response
+            .bytes()
             .await
             .map_err(|e| IpRangeError::IoError(io::Error::new(
                 io::ErrorKind::Other,
                 e,
-            )))
+            )))?;
+        decode_feed(&bytes)
+    }
+}
+
+/// The local path a `file://` URL points to; `None` for other schemes
+fn local_path(url: &str) -> Result<Option<PathBuf>> {
+    if !url.starts_with("file:") {
+        return Ok(None);
+    }
+    let parsed = Url::parse(url).map_err(|e| IpRangeError::InvalidUrl(format!("Invalid URL '{}': {}", url, e)))?;
+    parsed
+        .to_file_path()
+        .map(Some)
+        .map_err(|()| IpRangeError::InvalidUrl(format!("'{}' is not an absolute local path", url)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::OutboundHttpSettings;
+    use crate::utils::http_client;
+    use crate::ip_lookup::types::CloudKind;
+
+    const TOR_EXIT_LIST: &str = "ExitNode ABCDEF1234567890ABCDEF1234567890ABCDEF12
+Published 2023-01-01 10:00:00
+LastStatus 2023-01-01 11:00:00
+ExitAddress 1.2.3.4 2023-01-01 12:00:00
+ExitNode FEDCBA0987654321FEDCBA0987654321FEDCBA09
+ExitAddress 2001:db8::1 2023-01-01 12:00:00
+ExitAddress invalid-ip 2023-01-01 12:00:00
+";
+
+    fn default_loader() -> IpRangeLoader {
+        let http_client = http_client::build_client(&OutboundHttpSettings::default()).unwrap();
+        IpRangeLoader::with_http_client(IpRangeLoaderConfig::default(), http_client)
+    }
+
+    fn tor_source() -> IpRangeSource {
+        IpRangeSource {
+            url: "https://check.torproject.org/exit-addresses".to_string(),
+            category: IpCategory::TorExitNode,
+            name: "tor-exit-nodes".to_string(),
+            enabled: true,
+            format: SourceFormat::TorExitList,
+            max_delta_percent: None,
+            ip_version: IpVersion::V4,
+            mirrors: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_parse_tor_exit_address() {
+        assert_eq!(
+            parse_tor_exit_address("ExitAddress 1.2.3.4 2023-01-01 12:00:00"),
+            Some(Ok("1.2.3.4".parse().unwrap()))
+        );
+        assert_eq!(
+            parse_tor_exit_address("ExitAddress 2001:db8::1 2023-01-01 12:00:00"),
+            Some(Ok("2001:db8::1".parse().unwrap()))
+        );
+        assert!(matches!(parse_tor_exit_address("ExitAddress invalid-ip"), Some(Err(_))));
+        assert!(matches!(parse_tor_exit_address("ExitAddress"), Some(Err(_))));
+        assert_eq!(parse_tor_exit_address("ExitNode ABCDEF1234567890"), None);
+        assert_eq!(parse_tor_exit_address("Published 2023-01-01 10:00:00"), None);
+    }
+
+    #[test]
+    fn test_parse_ranges_tor_exit_list() {
+        let loader = default_loader();
+        let (ranges, report) = loader.parse_ranges(TOR_EXIT_LIST, &tor_source()).unwrap();
+        let networks: Vec<&str> = ranges.iter().map(|r| r.network.as_str()).collect();
+        assert_eq!(networks, vec!["1.2.3.4/32", "2001:db8::1/128"]);
+        // ExitNode, Published and LastStatus lines carry no address
+        assert_eq!((report.total_lines, report.parsed, report.skipped_comments, report.invalid), (7, 2, 4, 1));
+    }
+
+    /// A plain list with every kind of line a real feed ends up with
+    const DIRTY_LIST: &str = "# VPN ranges, generated 2024-01-01
+# another comment
+
+10.0.0.0/8
+192.0.2.1
+not-an-ip
+2001:db8::/32
+300.1.1.1
+10.0.0.0/33
+   # indented comment
+198.51.100.0/24 trailing garbage
+";
+
+    fn default_source() -> IpRangeSource {
+        IpRangeSource {
+            url: "https://example.com/vpn.txt".to_string(),
+            category: IpCategory::Vpn,
+            name: "dirty".to_string(),
+            enabled: true,
+            format: SourceFormat::Default,
+            max_delta_percent: None,
+            ip_version: IpVersion::V4,
+            mirrors: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_parse_report_for_dirty_list() {
+        let loader = default_loader();
+        let (ranges, report) = loader.parse_ranges(DIRTY_LIST, &default_source()).unwrap();
+
+        let networks: Vec<&str> = ranges.iter().map(|r| r.network.as_str()).collect();
+        assert_eq!(networks, vec!["10.0.0.0/8", "192.0.2.1/32", "2001:db8::/32"]);
+        assert_eq!(report.total_lines, 10);
+        assert_eq!(report.parsed, 3);
+        assert_eq!(report.skipped_comments, 3);
+        assert_eq!(report.invalid, 4);
+
+        let samples: Vec<(usize, &str)> = report
+            .sample_errors
+            .iter()
+            .map(|e| (e.line, e.content.as_str()))
+            .collect();
+        assert_eq!(
+            samples,
+            vec![
+                (6, "not-an-ip"),
+                (8, "300.1.1.1"),
+                (9, "10.0.0.0/33"),
+                (11, "198.51.100.0/24 trailing garbage"),
+            ]
+        );
+        assert!(report.sample_errors.iter().all(|e| !e.reason.is_empty()));
+    }
+
+    #[test]
+    fn test_parse_overrides() {
+        let (ranges, report) = parse_overrides(
+            "# known bad\n10.1.2.0/24 tor\n192.0.2.7   vpn\n2001:db8::/48 socks5\n10.1.2.3/24 vpn\n198.51.100.0/24\n198.51.100.0/24 martian\n",
+        );
+        let entries: Vec<(&str, IpCategory)> = ranges.iter().map(|r| (r.network.as_str(), r.category)).collect();
+        assert_eq!(
+            entries,
+            vec![
+                ("10.1.2.0/24", IpCategory::TorExitNode),
+                ("192.0.2.7/32", IpCategory::Vpn),
+                ("2001:db8::/48", IpCategory::ProxySocks5),
+            ]
+        );
+        assert!(ranges.iter().all(|r| r.source == OVERRIDES_SOURCE));
+        assert_eq!((report.total_lines, report.parsed, report.skipped_comments, report.invalid), (7, 3, 1, 3));
+        let lines: Vec<usize> = report.sample_errors.iter().map(|e| e.line).collect();
+        assert_eq!(lines, vec![5, 6, 7]);
+    }
+
+    #[test]
+    fn test_parse_delta() {
+        let content = "# cursor: 2025-01-02\n+192.0.2.0/24\n- 198.51.100.7\n+2001:db8::/32\n203.0.113.0/24\n+not-a-network\n# note\n";
+        let (delta, report) = parse_delta(content);
+
+        assert_eq!(delta.cursor.as_deref(), Some("2025-01-02"));
+        assert_eq!(
+            delta.changes,
+            vec![
+                DeltaChange::Add("192.0.2.0/24".to_string()),
+                DeltaChange::Remove("198.51.100.7/32".to_string()),
+                DeltaChange::Add("2001:db8::/32".to_string()),
+            ]
+        );
+        assert_eq!(report.parsed, 3);
+        assert_eq!(report.skipped_comments, 2);
+        assert_eq!(report.invalid, 2);
+        assert_eq!(report.sample_errors[0].line, 5);
+        assert_eq!(report.sample_errors[0].reason, "expected `+` or `-` before the entry");
+        assert_eq!(report.sample_errors[1].line, 6);
+    }
+
+    #[test]
+    fn test_parse_report_caps_sample_errors() {
+        let loader = default_loader();
+        let content: String = (0..500).map(|i| format!("garbage-{}\n10.{}.0.0/16\n", i, i % 256)).collect();
+        let (ranges, report) = loader.parse_ranges(&content, &default_source()).unwrap();
+
+        assert_eq!(ranges.len(), 500);
+        assert_eq!(report.invalid, 500);
+        assert_eq!(report.sample_errors.len(), MAX_SAMPLE_ERRORS);
+        assert_eq!(report.sample_errors[0].line, 1);
+        assert_eq!(report.sample_errors[MAX_SAMPLE_ERRORS - 1].content, "garbage-19");
+    }
+
+    #[test]
+    fn test_parse_report_for_json_list() {
+        let loader = default_loader();
+        let source = IpRangeSource { format: SourceFormat::JsonList, ..default_source() };
+
+        let (ranges, report) = loader
+            .parse_ranges(r#"{"list": ["10.0.0.0/8", "bogus", 42, "2001:db8::/32"]}"#, &source)
+            .unwrap();
+        assert_eq!(ranges.len(), 2);
+        assert_eq!((report.total_lines, report.parsed, report.invalid), (4, 2, 2));
+        assert_eq!(report.sample_errors[1].line, 3);
+        assert_eq!(report.sample_errors[1].reason, "expected a string");
+
+        // Not JSON at all: read as plain CIDRs
+        let (ranges, report) = loader.parse_ranges(DIRTY_LIST, &source).unwrap();
+        assert_eq!(ranges.len(), 3);
+        assert_eq!(report.invalid, 4);
+    }
+
+    #[test]
+    fn test_parse_auto_mixed_feed() {
+        let loader = default_loader();
+        let source = IpRangeSource { format: SourceFormat::Auto, ..default_source() };
+        let content = "# mixed proxy feed
+192.0.2.1
+192.0.2.2:8080
+192.0.2.3,US
+[2001:db8::1]:3128
+10.0.0.0/8
+198.51.100.0/24 DE
+not-an-ip:80
+";
+
+        let (ranges, report) = loader.parse_ranges(content, &source).unwrap();
+        let networks: Vec<&str> = ranges.iter().map(|r| r.network.as_str()).collect();
+        assert_eq!(
+            networks,
+            vec!["192.0.2.1/32", "192.0.2.2/32", "192.0.2.3/32", "2001:db8::1/128", "10.0.0.0/8", "198.51.100.0/24"]
+        );
+        assert_eq!((report.total_lines, report.parsed, report.skipped_comments, report.invalid), (8, 6, 1, 1));
+        assert_eq!(report.sample_errors[0].line, 8);
+        let formats: Vec<(&str, usize)> = report.line_formats.iter().map(|(k, v)| (*k, *v)).collect();
+        assert_eq!(formats, vec![("cidr", 2), ("ip", 2), ("ip_port", 2)]);
+
+        // Single-format sources leave the counts out
+        let (_, report) = loader.parse_ranges(content, &default_source()).unwrap();
+        assert!(report.line_formats.is_empty());
+    }
+
+    const AWS_IP_RANGES: &str = r#"{
+  "syncToken": "1700000000",
+  "prefixes": [
+    {"ip_prefix": "3.5.140.0/22", "region": "ap-northeast-2", "service": "AMAZON"},
+    {"ip_prefix": "3.5.140.0/22", "region": "ap-northeast-2", "service": "EC2"},
+    {"ip_prefix": "not-a-cidr", "region": "us-east-1", "service": "EC2"}
+  ],
+  "ipv6_prefixes": [
+    {"ipv6_prefix": "2600:1f14::/35", "region": "us-west-2", "service": "EC2"}
+  ]
+}"#;
+
+    const GCP_CLOUD_JSON: &str = r#"{
+  "syncToken": "1700000000",
+  "prefixes": [
+    {"ipv4Prefix": "34.1.208.0/20", "service": "Google Cloud", "scope": "africa-south1"},
+    {"ipv6Prefix": "2600:1900:8000::/44", "service": "Google Cloud", "scope": "africa-south1"}
+  ]
+}"#;
+
+    fn cloud_source(kind: CloudKind, format: SourceFormat) -> IpRangeSource {
+        IpRangeSource {
+            url: "https://example.com/ranges.json".to_string(),
+            category: IpCategory::CloudProvider(kind),
+            name: format!("{}-ranges", kind),
+            enabled: true,
+            format,
+            max_delta_percent: None,
+            ip_version: IpVersion::V4,
+            mirrors: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_parse_cloud_provider_feeds() {
+        let loader = default_loader();
+
+        let aws = cloud_source(CloudKind::Aws, SourceFormat::AwsIpRanges);
+        let (ranges, report) = loader.parse_ranges(AWS_IP_RANGES, &aws).unwrap();
+        let networks: Vec<&str> = ranges.iter().map(|r| r.network.as_str()).collect();
+        assert_eq!(networks, vec!["3.5.140.0/22", "3.5.140.0/22", "2600:1f14::/35"]);
+        assert_eq!(report.invalid, 1);
+        assert_eq!(report.sample_errors[0].content, "not-a-cidr");
+        assert!(ranges.iter().all(|r| r.category == IpCategory::CloudProvider(CloudKind::Aws)));
+
+        let gcp = cloud_source(CloudKind::Gcp, SourceFormat::GcpCloudJson);
+        let (ranges, _) = loader.parse_ranges(GCP_CLOUD_JSON, &gcp).unwrap();
+        let networks: Vec<&str> = ranges.iter().map(|r| r.network.as_str()).collect();
+        assert_eq!(networks, vec!["34.1.208.0/20", "2600:1900:8000::/44"]);
+
+        // A document in the wrong layout is an error, not an empty feed
+        assert!(loader.parse_ranges(GCP_CLOUD_JSON, &aws).is_err());
+        assert_eq!(
+            loader.filename_from_url(&Url::parse(&gcp.url).unwrap(), gcp.category, gcp.ip_version),
+            "gcp_ranges_v4.txt"
+        );
+    }
+
+    /// `text` as UTF-16LE, with a byte order mark if `bom`
+    fn utf16le(text: &str, bom: bool) -> Vec<u8> {
+        let bom = if bom { vec![0xFF, 0xFE] } else { Vec::new() };
+        bom.into_iter().chain(text.encode_utf16().flat_map(u16::to_le_bytes)).collect()
+    }
+
+    #[test]
+    fn test_decode_feed() {
+        let expected = "10.0.0.0/8\n192.0.2.1\n";
+        assert_eq!(decode_feed(b"\xEF\xBB\xBF10.0.0.0/8\r\n192.0.2.1\r\n").unwrap(), expected);
+        assert_eq!(decode_feed(b"10.0.0.0/8\r192.0.2.1\r").unwrap(), expected);
+        assert_eq!(decode_feed(expected.as_bytes()).unwrap(), expected);
+
+        for bom in [true, false] {
+            let err = decode_feed(&utf16le(expected, bom)).unwrap_err();
+            assert!(matches!(err, IpRangeError::UnsupportedEncoding(_)), "{:?}", err);
+            assert!(err.is_bad_content());
+        }
+        let mut big_endian = vec![0xFE, 0xFF];
+        big_endian.extend(expected.encode_utf16().flat_map(u16::to_be_bytes));
+        assert!(matches!(decode_feed(&big_endian), Err(IpRangeError::UnsupportedEncoding(_))));
+
+        assert!(matches!(decode_feed(b"\x1f\x8b\x08\x00gzip"), Err(IpRangeError::BinaryContent(_))));
+        assert!(matches!(decode_feed(b"10.0.0.0/8\n\xC0\xFF\n"), Err(IpRangeError::BinaryContent(_))));
+    }
+
+    #[test]
+    fn test_parse_ranges_rejects_feed_without_entries() {
+        let loader = default_loader();
+        for content in ["not-an-ip\n300.1.1.1\n", "# moved to https://example.com/new-list\n"] {
+            let err = loader.parse_ranges(content, &default_source()).unwrap_err();
+            assert!(matches!(err, IpRangeError::NoEntries { .. }), "{:?}", err);
+            assert!(err.is_bad_content());
+        }
+        let json = IpRangeSource { format: SourceFormat::JsonList, ..default_source() };
+        assert!(matches!(loader.parse_ranges(r#"["bogus"]"#, &json), Err(IpRangeError::NoEntries { lines: 1, .. })));
+
+        // Nothing at all is an empty list, not a broken one
+        assert!(loader.parse_ranges("", &default_source()).unwrap().0.is_empty());
+        assert!(loader.parse_ranges("[]", &json).unwrap().0.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_load_from_file_normalizes_encoding() {
+        let dir = tempfile::tempdir().unwrap();
+        let loader = default_loader();
+        let load = |name: &str, bytes: Vec<u8>| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, bytes).unwrap();
+            let loader = loader.clone();
+            async move { loader.load_from_file(&path, IpCategory::ProxyHttp, "http", SourceFormat::IpPort).await }
+        };
+
+        let (ranges, report) = load("bom_crlf.txt", b"\xEF\xBB\xBF1.2.3.4:8080\r\n5.6.7.8:3128\r\n".to_vec()).await.unwrap();
+        let networks: Vec<&str> = ranges.iter().map(|r| r.network.as_str()).collect();
+        assert_eq!(networks, vec!["1.2.3.4/32", "5.6.7.8/32"]);
+        assert_eq!(report.invalid, 0);
+
+        let err = load("utf16.txt", utf16le("1.2.3.4:8080\r\n", true)).await.unwrap_err();
+        assert!(matches!(err, IpRangeError::UnsupportedEncoding(_)), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn test_load_from_file_matches_parse_ranges() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tor_exit_nodes_v4.txt");
+        std::fs::write(&path, TOR_EXIT_LIST).unwrap();
+
+        let loader = default_loader();
+        let (from_file, file_report) = loader
+            .load_from_file(&path, IpCategory::TorExitNode, "tor-exit-nodes", SourceFormat::TorExitList)
+            .await
+            .unwrap();
+        let (parsed, report) = loader.parse_ranges(TOR_EXIT_LIST, &tor_source()).unwrap();
+        assert_eq!(file_report, report);
+
+        let from_file: Vec<&str> = from_file.iter().map(|r| r.network.as_str()).collect();
+        let parsed: Vec<&str> = parsed.iter().map(|r| r.network.as_str()).collect();
+        assert_eq!(from_file, parsed);
+    }
+
+    #[tokio::test]
+    async fn test_download_ranges_uses_outbound_http_settings() {
+        let (proxy_addr, request) = crate::test_support::mock_proxy(TOR_EXIT_LIST).await;
+        let settings = OutboundHttpSettings {
+            proxy_url: Some(format!("http://{}", proxy_addr)),
+            user_agent: "infralock-test/1.0".to_string(),
+            ..OutboundHttpSettings::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let config = IpRangeLoaderConfig {
+            data_dir: dir.path().to_path_buf(),
+            ..IpRangeLoaderConfig::default()
+        };
+        let loader = IpRangeLoader::with_http_client(config, http_client::build_client(&settings).unwrap());
+
+        let mut source = tor_source();
+        source.url = "http://lists.example.invalid/exit-addresses".to_string();
+        let (ranges, _) = loader.download_ranges(&source.url, &source).await.unwrap();
+        assert!(!ranges.is_empty());
+
+        let request = request.await.unwrap();
+        assert!(request.starts_with("GET http://lists.example.invalid/exit-addresses HTTP/1.1\r\n"), "{}", request);
+        assert!(request.contains("user-agent: infralock-test/1.0\r\n"), "{}", request);
     }
 }
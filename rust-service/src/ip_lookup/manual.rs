@@ -0,0 +1,154 @@
+//! Ranges pushed through the admin API, e.g. to block a network during an
+//! incident without waiting for the feeds.
+//!
+//! They are kept apart from the feeds and applied to every tree before it goes
+//! live, over any feed entry for the same network. The feed entry each one
+//! replaced is remembered and put back when it is deleted or expires. Entries
+//! with a TTL are dropped by the update loop once they expire.
+
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::ip_lookup::{overlap::OverlapLabel, types::IpCategory};
+
+/// Source that manual entries are attributed to in lookups
+pub const MANUAL_SOURCE: &str = "manual";
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ManualRange {
+    /// Network in CIDR notation, host bits cleared
+    pub network: String,
+    pub category: IpCategory,
+    pub added_at: DateTime<Utc>,
+    /// When the entry is dropped; `None` keeps it until deleted
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl ManualRange {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// Manual entries keyed by network
+#[derive(Debug, Default)]
+pub struct ManualRanges {
+    entries: BTreeMap<String, ManualRange>,
+    /// Feed entry each manual entry replaced in the live tree
+    shadowed: HashMap<String, OverlapLabel>,
+}
+
+impl ManualRanges {
+    /// Add an entry, returning the one it replaced for the same network
+    pub fn insert(&mut self, range: ManualRange) -> Option<ManualRange> {
+        self.entries.insert(range.network.clone(), range)
+    }
+
+    pub fn remove(&mut self, network: &str) -> Option<ManualRange> {
+        self.entries.remove(network)
+    }
+
+    /// Remove and return the entries that have expired by `now`
+    pub fn expire(&mut self, now: DateTime<Utc>) -> Vec<ManualRange> {
+        let expired: Vec<String> = self
+            .entries
+            .values()
+            .filter(|range| range.is_expired(now))
+            .map(|range| range.network.clone())
+            .collect();
+        expired.iter().filter_map(|network| self.entries.remove(network)).collect()
+    }
+
+    /// Remember `replaced` as the feed entry under the manual entry for
+    /// `network`, or forget it when there is none. A manual entry replacing
+    /// itself keeps the feed entry it already shadows.
+    pub fn shadow(&mut self, network: &str, replaced: Option<OverlapLabel>) {
+        match replaced {
+            Some(label) if label.source.as_deref() == Some(MANUAL_SOURCE) => {}
+            Some(label) => {
+                self.shadowed.insert(network.to_string(), label);
+            }
+            None => {
+                self.shadowed.remove(network);
+            }
+        }
+    }
+
+    /// Forget the feed entry shadowed for `network` if it came from `source`
+    pub fn unshadow(&mut self, network: &str, source: &str) {
+        if self.shadowed.get(network).is_some_and(|label| label.source.as_deref() == Some(source)) {
+            self.shadowed.remove(network);
+        }
+    }
+
+    /// Take the feed entry to put back once the manual entry for `network`
+    /// is gone
+    pub fn take_shadowed(&mut self, network: &str) -> Option<OverlapLabel> {
+        self.shadowed.remove(network)
+    }
+
+    /// Whether a manual entry for `network` is in effect at `now`
+    pub fn covers(&self, network: &str, now: DateTime<Utc>) -> bool {
+        self.entries.get(network).is_some_and(|range| !range.is_expired(now))
+    }
+
+    /// Entries still in effect at `now`, ordered by network
+    pub fn active(&self, now: DateTime<Utc>) -> impl Iterator<Item = &ManualRange> {
+        self.entries.values().filter(move |range| !range.is_expired(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(network: &str, expires_at: Option<DateTime<Utc>>) -> ManualRange {
+        ManualRange {
+            network: network.to_string(),
+            category: IpCategory::Vpn,
+            added_at: Utc::now(),
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn test_expired_entries_are_dropped() {
+        let now = Utc::now();
+        let mut ranges = ManualRanges::default();
+        ranges.insert(range("192.0.2.0/24", None));
+        ranges.insert(range("198.51.100.0/24", Some(now - chrono::Duration::seconds(1))));
+        ranges.insert(range("203.0.113.0/24", Some(now + chrono::Duration::seconds(60))));
+
+        let active: Vec<&str> = ranges.active(now).map(|range| range.network.as_str()).collect();
+        assert_eq!(active, vec!["192.0.2.0/24", "203.0.113.0/24"]);
+
+        let expired = ranges.expire(now);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].network, "198.51.100.0/24");
+        assert!(ranges.expire(now).is_empty());
+        assert_eq!(ranges.active(now + chrono::Duration::seconds(60)).count(), 1);
+    }
+
+    #[test]
+    fn test_shadowed_feed_entries() {
+        let label = |category, source: &str| OverlapLabel {
+            category,
+            source: Some(source.to_string()),
+        };
+        let mut ranges = ManualRanges::default();
+        ranges.insert(range("192.0.2.0/24", None));
+        ranges.shadow("192.0.2.0/24", Some(label(IpCategory::TorExitNode, "tor")));
+
+        // Re-adding the manual entry replaces itself, not the feed entry
+        ranges.shadow("192.0.2.0/24", Some(label(IpCategory::Vpn, MANUAL_SOURCE)));
+        ranges.unshadow("192.0.2.0/24", "other");
+        assert_eq!(ranges.take_shadowed("192.0.2.0/24"), Some(label(IpCategory::TorExitNode, "tor")));
+        assert_eq!(ranges.take_shadowed("192.0.2.0/24"), None);
+
+        ranges.shadow("192.0.2.0/24", Some(label(IpCategory::TorExitNode, "tor")));
+        ranges.unshadow("192.0.2.0/24", "tor");
+        assert_eq!(ranges.take_shadowed("192.0.2.0/24"), None);
+    }
+}
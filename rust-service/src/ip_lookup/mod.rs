@@ -4,28 +4,37 @@
 //! It's designed to work with various IP categories (VPN, proxy, TOR, etc.)
 //! and integrates with the background updater for automatic updates.
 
+pub mod archive;
+pub mod backoff;
+pub mod bloom;
+pub mod delisted;
+pub mod delta;
+pub mod flat;
 pub mod tree;
 pub mod types;
 pub mod loader;
+pub mod manual;
+pub mod overlap;
 pub mod service;
+pub mod snapshot;
+pub mod tunnel;
 
 // Re-export the main types for easier access
 pub use tree::SharedRadixTree;
-pub use types::{IpCategory, IpVersion};
+pub use types::{CategoryFlags, CloudKind, IpCategory, IpVersion};
 pub use service::{IpLookupService, IpLookupServiceConfig, IpRangeSource};
 
+use std::future::Future;
 use std::net::IpAddr;
 use std::sync::Arc;
-use once_cell::sync::Lazy;
-use tokio::sync::RwLock;
+use tokio::sync::OnceCell;
 
+use crate::config::data_layout::DataLayout;
+use crate::config::{OutboundHttpSettings, Settings};
 use crate::ip_lookup::types::SourceFormat;
 
-/// Default path for storing IP range data
-pub const DEFAULT_DATA_DIR: &str = "data/ip_ranges";
-
 /// Global instance of the IP lookup service
-static IP_LOOKUP_SERVICE: Lazy<RwLock<Option<Arc<IpLookupService>>>> = Lazy::new(|| RwLock::new(None));
+static IP_LOOKUP_SERVICE: OnceCell<Arc<IpLookupService>> = OnceCell::const_new();
 
 /// Initialize the global IP lookup service with default configuration
 pub async fn init_with_defaults() -> anyhow::Result<()> {
@@ -35,39 +44,50 @@ pub async fn init_with_defaults() -> anyhow::Result<()> {
 
 /// Initialize the global IP lookup service with a custom configuration
 pub async fn init_with_config(config: IpLookupServiceConfig) -> anyhow::Result<()> {
-    let mut service_guard = IP_LOOKUP_SERVICE.write().await;
-    
-    if service_guard.is_some() {
+    register(|| {
+        let service = Arc::new(IpLookupService::new(config));
+        service.start_background_updates();
+        service
+    })
+    .await
+}
+
+/// Register an already configured service as the global instance
+///
+/// The caller owns the service's background updates; this only makes the
+/// free functions in this module use the same tree as the caller.
+pub async fn init_with_instance(service: Arc<IpLookupService>) -> anyhow::Result<()> {
+    register(|| service).await
+}
+
+/// Make the service built by `build` the global instance, failing if one is set
+async fn register<F>(build: F) -> anyhow::Result<()>
+where
+    F: FnOnce() -> Arc<IpLookupService>,
+{
+    let mut initialized = false;
+    get_or_init_in(&IP_LOOKUP_SERVICE, || async {
+        initialized = true;
+        Ok(build())
+    })
+    .await?;
+
+    if !initialized {
         return Err(anyhow::anyhow!("IP lookup service is already initialized"));
     }
-    
-    let service = Arc::new(IpLookupService::new(config));
-    service.start_background_updates();
-    *service_guard = Some(service);
-    
     Ok(())
 }
 
-/// Get the global IP lookup service
+/// Get the global IP lookup service, initializing it with defaults if needed
+///
+/// Concurrent first callers wait for a single initialization.
 pub async fn get_service() -> anyhow::Result<Arc<IpLookupService>> {
-    loop {
-        // First try to get a read lock
-        let guard = IP_LOOKUP_SERVICE.read().await;
-        if let Some(service) = guard.as_ref() {
-            return Ok(service.clone());
-        }
-        drop(guard); // Release the read lock before trying to initialize
-        
-        // If we get here, the service needs to be initialized
-        if let Err(e) = init_with_defaults().await {
-            // If another task is initializing, we'll retry
-            if !e.to_string().contains("already initialized") {
-                return Err(e);
-            }
-            // Small delay to prevent tight loop
-            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
-        }
-    }
+    get_or_init_in(&IP_LOOKUP_SERVICE, || async {
+        let service = Arc::new(IpLookupService::new(default_config()?));
+        service.start_background_updates();
+        Ok(service)
+    })
+    .await
 }
 
 /// Check if an IP address matches any known category
@@ -76,15 +96,44 @@ pub async fn check_ip(ip: IpAddr) -> anyhow::Result<Option<IpCategory>> {
     Ok(service.tree().lookup(ip))
 }
 
+/// Return the service in `cell`, running `init` only if it is empty
+async fn get_or_init_in<F, Fut>(
+    cell: &OnceCell<Arc<IpLookupService>>,
+    init: F,
+) -> anyhow::Result<Arc<IpLookupService>>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = anyhow::Result<Arc<IpLookupService>>>,
+{
+    cell.get_or_try_init(init).await.cloned()
+}
+
 /// Create a default configuration for the IP lookup service
 pub fn default_config() -> anyhow::Result<IpLookupServiceConfig> {
-    let data_dir = std::env::current_dir()?.join(DEFAULT_DATA_DIR);
+    let base_path = std::env::current_dir()?;
+    let layout = DataLayout::default();
+    let data_dir = base_path.join(layout.ip_ranges_dir());
+    let archive_dir = base_path.join(layout.archive_dir());
     
     Ok(IpLookupServiceConfig {
         data_dir,
         check_updates: true,
         update_interval_secs: 3600, // 1 hour
         max_cache_age_secs: 86400,  // 24 hours
+        snapshot_retention: 0,
+        archive_dir,
+        archive_retention: 0,
+        ipv6_aggregate_prefix: 128,
+        bloom_filter: true,
+        flat_snapshot: true,
+        offline: false,
+        overrides_file: Some(base_path.join(layout.overrides_file())),
+        outbound_http: OutboundHttpSettings::default(),
+        tor_delisted_window_secs: 0,
+        feed_backoff_max_secs: 86400,
+        enabled_categories: None,
+        // GitHub raw URLs are rate limited or blocked on some networks, so
+        // lists hosted there fall back to the jsDelivr CDN
         sources: vec![
             // VPN list (ipv4)
             IpRangeSource {
@@ -93,7 +142,9 @@ pub fn default_config() -> anyhow::Result<IpLookupServiceConfig> {
                 name: "vpn-ipv4".to_string(),
                 enabled: true,
                 format: SourceFormat::Default,
+                max_delta_percent: Some(50.0),
                 ip_version: IpVersion::V4,
+                mirrors: vec!["https://cdn.jsdelivr.net/gh/X4BNet/lists_vpn@main/output/datacenter/ipv4.txt".to_string()],
             },
             // VPN list (ipv6)
             IpRangeSource {
@@ -102,7 +153,9 @@ pub fn default_config() -> anyhow::Result<IpLookupServiceConfig> {
                 name: "misp-vpn-ipv6".to_string(),
                 enabled: true,
                 format: SourceFormat::JsonList,
+                max_delta_percent: Some(50.0),
                 ip_version: IpVersion::V6,
+                mirrors: vec!["https://cdn.jsdelivr.net/gh/MISP/misp-warninglists@main/lists/vpn-ipv6/list.json".to_string()],
             },
             // HTTP proxies (ipv4)
             IpRangeSource {
@@ -111,7 +164,9 @@ pub fn default_config() -> anyhow::Result<IpLookupServiceConfig> {
                 name: "thespeedx-http".to_string(),
                 enabled: true,
                 format: SourceFormat::IpPort,
+                max_delta_percent: Some(90.0),
                 ip_version: IpVersion::V4,
+                mirrors: vec!["https://cdn.jsdelivr.net/gh/TheSpeedX/SOCKS-List@master/http.txt".to_string()],
             },
             // SOCKS5 proxies (ipv4)
             IpRangeSource {
@@ -120,7 +175,9 @@ pub fn default_config() -> anyhow::Result<IpLookupServiceConfig> {
                 name: "thespeedx-socks5".to_string(),
                 enabled: true,
                 format: SourceFormat::IpPort,
+                max_delta_percent: Some(90.0),
                 ip_version: IpVersion::V4,
+                mirrors: vec!["https://cdn.jsdelivr.net/gh/TheSpeedX/SOCKS-List@master/socks5.txt".to_string()],
             },
             // Tor exit nodes (ipv4)
             IpRangeSource {
@@ -129,7 +186,9 @@ pub fn default_config() -> anyhow::Result<IpLookupServiceConfig> {
                 name: "tor-exit-nodes-ipv4".to_string(),
                 enabled: true,
                 format: SourceFormat::TorExitList,
+                max_delta_percent: Some(50.0),
                 ip_version: IpVersion::V4,
+                mirrors: Vec::new(),
             },
             // Tor exit nodes (ipv6) - same URL as IPv4, but will be filtered by ip_version
             IpRangeSource {
@@ -138,8 +197,142 @@ pub fn default_config() -> anyhow::Result<IpLookupServiceConfig> {
                 name: "tor-exit-nodes-ipv6".to_string(),
                 enabled: true,
                 format: SourceFormat::TorExitList,
+                max_delta_percent: Some(50.0),
                 ip_version: IpVersion::V6,
+                mirrors: Vec::new(),
+            },
+            // AWS published ranges (ipv4 and ipv6 in one document)
+            IpRangeSource {
+                url: "https://ip-ranges.amazonaws.com/ip-ranges.json".to_string(),
+                category: IpCategory::CloudProvider(CloudKind::Aws),
+                name: "aws-ip-ranges".to_string(),
+                enabled: true,
+                format: SourceFormat::AwsIpRanges,
+                max_delta_percent: Some(50.0),
+                ip_version: IpVersion::V4,
+                mirrors: Vec::new(),
+            },
+            // GCP published ranges (ipv4 and ipv6 in one document)
+            IpRangeSource {
+                url: "https://www.gstatic.com/ipranges/cloud.json".to_string(),
+                category: IpCategory::CloudProvider(CloudKind::Gcp),
+                name: "gcp-cloud".to_string(),
+                enabled: true,
+                format: SourceFormat::GcpCloudJson,
+                max_delta_percent: Some(50.0),
+                ip_version: IpVersion::V4,
+                mirrors: Vec::new(),
             },
         ],
     })
-}
\ No newline at end of file
+}
+
+/// The default configuration with the `ip_lookup` settings applied
+pub fn config_from_settings(settings: &Settings) -> anyhow::Result<IpLookupServiceConfig> {
+    let mut config = default_config()?;
+    let base_path = std::env::current_dir()?;
+    config.data_dir = base_path.join(settings.data_layout().ip_ranges_dir());
+    config.archive_dir = base_path.join(settings.data_layout().archive_dir());
+    config.snapshot_retention = settings.ip_lookup.snapshot_retention;
+    config.archive_retention = settings.ip_lookup.archive_retention;
+    config.ipv6_aggregate_prefix = settings.ip_lookup.ipv6_aggregate_prefix;
+    config.bloom_filter = settings.ip_lookup.bloom_filter;
+    config.flat_snapshot = settings.ip_lookup.flat_snapshot;
+    config.offline = settings.ip_lookup.offline;
+    config.overrides_file = Some(settings.ip_lookup.overrides_file.clone());
+    config.outbound_http = settings.outbound_http.clone();
+    config.tor_delisted_window_secs = settings.ip_lookup.tor_delisted_window_secs;
+    config.feed_backoff_max_secs = settings.ip_lookup.feed_backoff_max_secs;
+    config.enabled_categories = settings.ip_lookup.enabled_categories();
+    for source in &mut config.sources {
+        if matches!(source.category, IpCategory::CloudProvider(_)) {
+            source.enabled = settings.ip_lookup.cloud_providers;
+        }
+        // Never downloaded or loaded, so a slim deployment does not hold feeds it ignores
+        if let Some(enabled) = &config.enabled_categories {
+            source.enabled &= enabled.contains(&source.category);
+        }
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tempfile::tempdir;
+
+    fn offline_config(data_dir: std::path::PathBuf) -> IpLookupServiceConfig {
+        IpLookupServiceConfig {
+            archive_dir: data_dir.join("archive"),
+            data_dir,
+            check_updates: false,
+            update_interval_secs: 3600,
+            max_cache_age_secs: 86400,
+            snapshot_retention: 0,
+            archive_retention: 0,
+            ipv6_aggregate_prefix: 128,
+            bloom_filter: true,
+            flat_snapshot: false,
+            offline: false,
+            overrides_file: None,
+            outbound_http: OutboundHttpSettings::default(),
+            tor_delisted_window_secs: 0,
+            feed_backoff_max_secs: 0,
+            enabled_categories: None,
+            sources: Vec::new(),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_first_callers_initialize_once() {
+        static CELL: OnceCell<Arc<IpLookupService>> = OnceCell::const_new();
+        static INITS: AtomicUsize = AtomicUsize::new(0);
+
+        let dir = tempdir().unwrap();
+        let data_dir = dir.path().to_path_buf();
+
+        let callers: Vec<_> = (0..32)
+            .map(|_| {
+                let data_dir = data_dir.clone();
+                tokio::spawn(async move {
+                    get_or_init_in(&CELL, || async move {
+                        INITS.fetch_add(1, Ordering::SeqCst);
+                        // Widen the window in which other callers find the cell empty
+                        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                        Ok(Arc::new(IpLookupService::new(offline_config(data_dir))))
+                    })
+                    .await
+                    .unwrap()
+                })
+            })
+            .collect();
+
+        let mut services = Vec::new();
+        for caller in callers {
+            services.push(caller.await.unwrap());
+        }
+
+        assert_eq!(INITS.load(Ordering::SeqCst), 1);
+        assert!(services.iter().all(|s| Arc::ptr_eq(s, &services[0])));
+    }
+
+    #[tokio::test]
+    async fn test_registered_instance_backs_free_functions() {
+        let dir = tempdir().unwrap();
+        let service = Arc::new(IpLookupService::new(offline_config(dir.path().to_path_buf())));
+        let mut tree = tree::RadixTree::new();
+        tree.insert("198.51.100.0/24".parse().unwrap(), IpCategory::Vpn);
+        service.tree().replace(tree);
+
+        init_with_instance(Arc::clone(&service)).await.unwrap();
+
+        assert!(Arc::ptr_eq(&get_service().await.unwrap(), &service));
+        assert_eq!(
+            check_ip("198.51.100.7".parse().unwrap()).await.unwrap(),
+            Some(IpCategory::Vpn)
+        );
+        assert!(init_with_instance(service).await.is_err());
+        assert!(init_with_config(offline_config(dir.path().to_path_buf())).await.is_err());
+    }
+}
@@ -0,0 +1,165 @@
+//! Where feeds disagree about a network's category.
+//!
+//! The tree keeps one entry per network, so when two feeds list the same
+//! network the one loaded last wins, and a network nested in one of another
+//! category wins for its addresses. Neither is visible in lookups. Each
+//! tree build counts both kinds of conflict and keeps the most contested
+//! prefixes with their competing labels, for data-quality monitoring only:
+//! nothing here changes what lookups return.
+//!
+//! Feeds agreeing on a category are not a conflict, whatever their sources.
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use ip_network::IpNetwork;
+use serde::Serialize;
+
+use crate::ip_lookup::types::IpCategory;
+
+/// Contested prefixes kept per report
+pub const MAX_SAMPLES: usize = 20;
+
+/// A category a feed gave a network
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OverlapLabel {
+    pub category: IpCategory,
+    /// Feed name, `overrides`, or `None` for entries inserted without one
+    pub source: Option<String>,
+}
+
+/// A network other entries disagree with
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ContestedPrefix {
+    pub network: String,
+    /// Conflicting entries for the network itself or nested in it
+    pub conflicts: usize,
+    /// Every label involved, the network's own included, sorted by category
+    pub labels: Vec<OverlapLabel>,
+}
+
+/// Category conflicts found while building a tree
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OverlapReport {
+    /// Networks listed more than once with different categories; the
+    /// entry loaded last is the one kept
+    pub identical: usize,
+    /// Networks nested in a network of another category
+    pub nested: usize,
+    /// The most contested prefixes, most conflicts first
+    pub top: Vec<ContestedPrefix>,
+    pub computed_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Default)]
+pub struct OverlapCollector {
+    identical: usize,
+    nested: usize,
+    contested: HashMap<IpNetwork, (usize, Vec<OverlapLabel>)>,
+}
+
+impl OverlapCollector {
+    /// `network` was listed as `replaced`, then again as `kept`
+    pub fn identical(&mut self, network: IpNetwork, replaced: OverlapLabel, kept: OverlapLabel) {
+        if replaced.category == kept.category {
+            return;
+        }
+        self.identical += 1;
+        self.record(network, replaced, kept);
+    }
+
+    /// `inner` is nested in `outer`
+    pub fn nested(&mut self, outer: (IpNetwork, OverlapLabel), inner: OverlapLabel) {
+        if outer.1.category == inner.category {
+            return;
+        }
+        self.nested += 1;
+        self.record(outer.0, outer.1, inner);
+    }
+
+    fn record(&mut self, network: IpNetwork, own: OverlapLabel, other: OverlapLabel) {
+        let (conflicts, labels) = self.contested.entry(network).or_default();
+        *conflicts += 1;
+        for label in [own, other] {
+            if !labels.contains(&label) {
+                labels.push(label);
+            }
+        }
+    }
+
+    pub fn finish(self) -> OverlapReport {
+        let mut top: Vec<ContestedPrefix> = self
+            .contested
+            .into_iter()
+            .map(|(network, (conflicts, mut labels))| {
+                labels.sort_by_key(|label| (label.category.to_string(), label.source.clone()));
+                ContestedPrefix {
+                    network: network.to_string(),
+                    conflicts,
+                    labels,
+                }
+            })
+            .collect();
+        top.sort_by(|a, b| b.conflicts.cmp(&a.conflicts).then_with(|| a.network.cmp(&b.network)));
+        top.truncate(MAX_SAMPLES);
+        OverlapReport {
+            identical: self.identical,
+            nested: self.nested,
+            top,
+            computed_at: Utc::now(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn label(category: IpCategory, source: &str) -> OverlapLabel {
+        OverlapLabel { category, source: Some(source.to_string()) }
+    }
+
+    #[test]
+    fn test_counts_only_disagreeing_categories() {
+        let network: IpNetwork = "10.0.0.0/24".parse().unwrap();
+        let mut collector = OverlapCollector::default();
+        collector.identical(network, label(IpCategory::Vpn, "vpn"), label(IpCategory::Vpn, "other-vpn"));
+        collector.identical(network, label(IpCategory::Vpn, "vpn"), label(IpCategory::ProxySocks5, "socks5"));
+        collector.nested(
+            (network, label(IpCategory::ProxySocks5, "socks5")),
+            label(IpCategory::TorExitNode, "tor"),
+        );
+        collector.nested((network, label(IpCategory::ProxySocks5, "socks5")), label(IpCategory::ProxySocks5, "socks5"));
+
+        let report = collector.finish();
+        assert_eq!((report.identical, report.nested), (1, 1));
+        assert_eq!(
+            report.top,
+            vec![ContestedPrefix {
+                network: "10.0.0.0/24".to_string(),
+                conflicts: 2,
+                labels: vec![
+                    label(IpCategory::ProxySocks5, "socks5"),
+                    label(IpCategory::TorExitNode, "tor"),
+                    label(IpCategory::Vpn, "vpn"),
+                ],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_keeps_the_most_contested_prefixes() {
+        let mut collector = OverlapCollector::default();
+        for i in 0..MAX_SAMPLES + 5 {
+            let outer: IpNetwork = format!("10.{}.0.0/16", i).parse().unwrap();
+            for _ in 0..=i {
+                collector.nested((outer, label(IpCategory::Vpn, "vpn")), label(IpCategory::TorExitNode, "tor"));
+            }
+        }
+        let report = collector.finish();
+        assert_eq!(report.top.len(), MAX_SAMPLES);
+        assert_eq!(report.top[0].network, format!("10.{}.0.0/16", MAX_SAMPLES + 4));
+        assert_eq!(report.top[0].conflicts, MAX_SAMPLES + 5);
+        assert!(report.top.windows(2).all(|pair| pair[0].conflicts >= pair[1].conflicts));
+    }
+}
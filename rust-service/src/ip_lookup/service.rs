@@ -1,16 +1,39 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
 use std::path::PathBuf;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use arc_swap::ArcSwap;
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
 use serde::{Serialize, Deserialize};
 use tracing::{debug, error, info, warn};
-use ip_network::IpNetwork;
+use ip_network::{IpNetwork, Ipv6Network};
 use url::Url;
 
 use crate::ip_lookup::{
-    loader::{IpRangeLoader, IpRangeLoaderConfig},
+    archive::{FeedArchive, SourceDiff},
+    backoff::{FeedBackoff, SourceFailure},
+    delisted::DelistedTracker,
+    delta::{DeltaChange, DeltaState},
+    flat::{self, FlatTree},
+    loader::{self, FetchReport, IpRangeLoader, IpRangeLoaderConfig, ParseReport, OVERRIDES_SOURCE},
+    manual::{ManualRange, ManualRanges, MANUAL_SOURCE},
+    overlap::{OverlapCollector, OverlapLabel, OverlapReport},
     tree::RadixTree,
-    types::{IpCategory, IpRange, SourceFormat, IpVersion},
+    snapshot::{SnapshotInfo, SnapshotStore},
+    types::{IpCategory, IpRange, IpRangeError, SourceFormat, IpVersion},
     SharedRadixTree,
 };
+use crate::config::OutboundHttpSettings;
+use crate::monitoring;
+use crate::utils::http_client::{self, CertPins};
+
+/// How often the overrides file is checked for changes
+const OVERRIDES_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Flat snapshot of the latest tree, relative to the data dir
+const FLAT_SNAPSHOT_FILE: &str = "tree.flat";
 
 /// Configuration for the IP lookup service
 #[derive(Debug, Clone)]
@@ -23,6 +46,40 @@ pub struct IpLookupServiceConfig {
     pub update_interval_secs: u64,
     /// Maximum age of cached data before updating (in seconds)
     pub max_cache_age_secs: u64,
+    /// Number of tree snapshots to keep for rollback (0 disables snapshots)
+    pub snapshot_retention: usize,
+    /// Directory for archived per-source entry lists
+    pub archive_dir: PathBuf,
+    /// Number of entry lists to keep per source (0 disables archiving and
+    /// feed diffs)
+    pub archive_retention: usize,
+    /// Prefix length that single-host IPv6 proxy and Tor entries are widened
+    /// to (128 keeps them as-is)
+    pub ipv6_aggregate_prefix: u8,
+    /// Build a Bloom filter prefilter with each tree so most clean IPv4
+    /// lookups skip the tree walk
+    pub bloom_filter: bool,
+    /// Write each tree as a flat snapshot and, on startup, answer lookups
+    /// from it until the first tree is built
+    pub flat_snapshot: bool,
+    /// Never download: updates load each source from its file under
+    /// `data_dir`, however old, and background updates are disabled
+    pub offline: bool,
+    /// Hand-maintained entries merged over the feeds, reloaded when the
+    /// file changes
+    pub overrides_file: Option<PathBuf>,
+    /// Proxy, User-Agent and timeouts for feed downloads
+    pub outbound_http: OutboundHttpSettings,
+    /// How long an entry that left a Tor feed is reported as recently
+    /// delisted (0 disables tracking)
+    pub tor_delisted_window_secs: u64,
+    /// Longest a repeatedly failing source waits before its next attempt;
+    /// the wait starts at `update_interval_secs` and doubles per failure
+    /// (0 retries every update)
+    pub feed_backoff_max_secs: u64,
+    /// Categories kept in the tree; `None` keeps every category. Entries
+    /// of other categories, from feeds or overrides, are dropped.
+    pub enabled_categories: Option<Vec<IpCategory>>,
     /// List of data sources to load
     pub sources: Vec<IpRangeSource>,
 }
@@ -36,7 +93,69 @@ pub struct IpRangeSource {
     pub enabled: bool,
     #[serde(default)]
     pub format: SourceFormat,
+    /// Largest allowed change in entry count between updates, as a percentage
+    /// of the last accepted count. Larger swings keep the current tree.
+    #[serde(default)]
+    pub max_delta_percent: Option<f64>,
     pub ip_version: IpVersion,
+    /// URLs serving the same list, tried in order when `url` fails to
+    /// download or its content is rejected
+    #[serde(default)]
+    pub mirrors: Vec<String>,
+}
+
+impl IpRangeSource {
+    /// `url`, then each mirror
+    pub fn urls(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.url.as_str()).chain(self.mirrors.iter().map(String::as_str))
+    }
+
+    /// Whether the list is read from a local `file://` URL, e.g. one synced
+    /// by external tooling, rather than downloaded
+    pub fn is_local(&self) -> bool {
+        self.url.starts_with("file:")
+    }
+}
+
+/// A configured source and what its recent updates looked like
+#[derive(Debug, Clone, Serialize)]
+pub struct SourceStatus {
+    pub name: String,
+    pub category: IpCategory,
+    pub enabled: bool,
+    /// Entry count from the last update that made it into the tree
+    pub accepted_count: Option<usize>,
+    /// Change against the previously archived version, from the last fetch
+    pub last_diff: Option<SourceDiff>,
+    /// Parse quality of the last load
+    pub last_parse: Option<ParseReport>,
+    /// The URLs tried on the last download, and the one that was used
+    pub last_fetch: Option<FetchReport>,
+    /// Set while the source's updates keep failing
+    pub failure: Option<SourceFailure>,
+}
+
+/// A category's entries in the live tree, and how old their data is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct CategoryData {
+    pub entries: usize,
+    /// When the oldest of the data went in: the newest download of the
+    /// category's feeds, or the build time of a tree from a snapshot
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A network containing an explained IP, and where it came from
+#[derive(Debug, Clone, Serialize)]
+pub struct ExplainedMatch {
+    /// The entry as stored, in CIDR notation; `None` while a flat snapshot
+    /// answers, since it keeps no networks
+    pub network: Option<String>,
+    pub category: IpCategory,
+    /// Feed name, `overrides` or `manual`
+    pub source: Option<String>,
+    /// When the source last changed: the feed file's download time, the
+    /// overrides file's modification time or the manual entry's creation
+    pub source_updated_at: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 /// The IP lookup service
@@ -48,6 +167,38 @@ pub struct IpLookupService {
     loader: IpRangeLoader,
     /// Service configuration
     config: IpLookupServiceConfig,
+    /// Retained snapshots, if enabled
+    snapshots: Option<SnapshotStore>,
+    /// Entry count per source from the last accepted update
+    accepted_counts: Arc<Mutex<HashMap<String, usize>>>,
+    /// Archived entry lists, if enabled
+    archive: Option<FeedArchive>,
+    /// Diff per source from its last fetch
+    last_diffs: Arc<Mutex<HashMap<String, SourceDiff>>>,
+    /// Parse report per source from its last load
+    parse_reports: Arc<Mutex<HashMap<String, ParseReport>>>,
+    /// URLs tried per source on its last download
+    fetch_reports: Arc<Mutex<HashMap<String, FetchReport>>>,
+    /// Modification time of the overrides file when it was last loaded
+    overrides_mtime: Arc<Mutex<Option<SystemTime>>>,
+    /// Entries added through the admin API. Held while a new tree is swapped
+    /// in, so an entry added meanwhile is never lost.
+    manual_ranges: Arc<Mutex<ManualRanges>>,
+    /// Entries and cursor per diff source, read from the data dir on first use
+    delta_states: Arc<Mutex<HashMap<String, DeltaState>>>,
+    /// Entries recently dropped from the Tor feeds, if tracking is enabled
+    delisted: Option<Arc<Mutex<DelistedTracker>>>,
+    /// Consecutive failures per source, and when each is retried
+    backoff: Arc<Mutex<FeedBackoff>>,
+    /// Category conflicts found in the last tree built from the feeds
+    overlaps: Arc<Mutex<Option<OverlapReport>>>,
+    /// Entry count and age per category, as of the last tree install or
+    /// feed update; read on every lookup, so kept lock-free
+    category_data: Arc<ArcSwap<HashMap<IpCategory, CategoryData>>>,
+    /// Set once the first tree has been installed
+    loaded: Arc<tokio::sync::watch::Sender<bool>>,
+    /// Counts the feed and overrides updates that changed the live tree
+    updates: Arc<tokio::sync::watch::Sender<u64>>,
 }
 
 impl IpLookupService {
@@ -59,20 +210,414 @@ impl IpLookupService {
             max_cache_age_secs: config.max_cache_age_secs,
         };
 
+        let snapshots = (config.snapshot_retention > 0).then(|| {
+            SnapshotStore::new(config.data_dir.join("snapshots"), config.snapshot_retention)
+        });
+
+        let archive = (config.archive_retention > 0)
+            .then(|| FeedArchive::new(&config.archive_dir, config.archive_retention));
+
+        let delisted = (config.tor_delisted_window_secs > 0).then(|| {
+            let window = chrono::Duration::seconds(config.tor_delisted_window_secs as i64);
+            Arc::new(Mutex::new(DelistedTracker::new(window)))
+        });
+
+        let backoff = FeedBackoff::new(
+            chrono::Duration::seconds(config.update_interval_secs as i64),
+            chrono::Duration::seconds(config.feed_backoff_max_secs as i64),
+        );
+
         Self {
             tree: SharedRadixTree::new(),
-            loader: IpRangeLoader::new(loader_config),
+            loader: IpRangeLoader::with_http_client(
+                loader_config,
+                http_client::build_client(&config.outbound_http).expect("Failed to create HTTP client"),
+            )
+            .with_cert_pins(CertPins::from_settings(&config.outbound_http).expect("Invalid certificate pins")),
             config,
+            snapshots,
+            accepted_counts: Arc::new(Mutex::new(HashMap::new())),
+            archive,
+            last_diffs: Arc::new(Mutex::new(HashMap::new())),
+            parse_reports: Arc::new(Mutex::new(HashMap::new())),
+            fetch_reports: Arc::new(Mutex::new(HashMap::new())),
+            overrides_mtime: Arc::new(Mutex::new(None)),
+            manual_ranges: Arc::new(Mutex::new(ManualRanges::default())),
+            delta_states: Arc::new(Mutex::new(HashMap::new())),
+            delisted,
+            backoff: Arc::new(Mutex::new(backoff)),
+            overlaps: Arc::new(Mutex::new(None)),
+            category_data: Arc::new(ArcSwap::default()),
+            loaded: Arc::new(tokio::sync::watch::Sender::new(false)),
+            updates: Arc::new(tokio::sync::watch::Sender::new(0)),
         }
     }
 
+    /// Create a service whose tree already holds `ranges`, without reading the
+    /// data directory or downloading any feed
+    pub fn from_ranges(ranges: Vec<IpRange>, config: IpLookupServiceConfig) -> Self {
+        let service = Self::new(config);
+        let mut tree = RadixTree::new();
+        for range in &ranges {
+            match range.network.parse::<IpNetwork>() {
+                Ok(network) => {
+                    let network = aggregate_v6_host(network, range.category, service.config.ipv6_aggregate_prefix);
+                    insert_built(&mut tree, network, range.category, &range.source);
+                }
+                Err(e) => error!("Failed to parse network '{}' from source '{}': {}", range.network, range.source, e),
+            }
+        }
+        if service.config.bloom_filter {
+            tree.build_prefilter();
+        }
+        service.replace_tree(tree, Utc::now());
+        service.loaded.send_replace(true);
+        service
+    }
+
+    /// Whether `ip` is in an entry that left a Tor feed within the
+    /// configured window. Always false with tracking disabled.
+    pub fn is_recently_delisted(&self, ip: IpAddr) -> bool {
+        self.delisted
+            .as_ref()
+            .is_some_and(|delisted| delisted.lock().contains(ip, chrono::Utc::now()))
+    }
+
     /// Get a reference to the radix tree
     pub fn tree(&self) -> &SharedRadixTree {
         &self.tree
     }
 
-    /// Start the background update task
+    /// Wait until the first tree has been loaded from the feeds
+    pub async fn wait_until_loaded(&self) {
+        let mut loaded = self.loaded.subscribe();
+        // The sender lives as long as `self`, so this cannot fail
+        let _ = loaded.wait_for(|loaded| *loaded).await;
+    }
+
+    /// Notified after each feed update changes the live tree, whether by a
+    /// rebuild or by a diff source's changes, and after the overrides change
+    pub fn subscribe_updates(&self) -> tokio::sync::watch::Receiver<u64> {
+        self.updates.subscribe()
+    }
+
+    /// List retained tree snapshots, newest first
+    pub fn snapshots(&self) -> Result<Vec<SnapshotInfo>, IpRangeError> {
+        match &self.snapshots {
+            Some(store) => store.list(),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn flat_snapshot_path(&self) -> PathBuf {
+        self.config.data_dir.join(FLAT_SNAPSHOT_FILE)
+    }
+
+    /// Answer lookups from the flat snapshot of the last tree until the first
+    /// tree is built. Call before starting updates; returns whether a
+    /// snapshot was loaded.
+    pub fn load_flat_snapshot(&self) -> bool {
+        let path = self.flat_snapshot_path();
+        if !self.config.flat_snapshot || !path.exists() || !self.tree.is_empty() {
+            return false;
+        }
+        let started = std::time::Instant::now();
+        match FlatTree::open(&path) {
+            Ok(flat) => {
+                let (v4, v6) = flat.networks();
+                info!(
+                    path = %path.display(),
+                    v4,
+                    v6,
+                    written_at = %flat.written_at(),
+                    elapsed_ms = started.elapsed().as_millis() as u64,
+                    "Serving lookups from the flat tree snapshot until the tree is built"
+                );
+                let written_at = flat.written_at();
+                self.replace_tree(RadixTree::from_flat(flat), written_at);
+                true
+            }
+            Err(e) => {
+                warn!(path = %path.display(), error = %e, "Ignoring unreadable flat tree snapshot");
+                false
+            }
+        }
+    }
+
+    /// Replace the live tree with the snapshot at `index` (0 is the newest)
+    ///
+    /// The next scheduled update will rebuild the tree from the feeds again.
+    pub fn rollback(&self, index: usize) -> Result<SnapshotInfo, IpRangeError> {
+        let store = self
+            .snapshots
+            .as_ref()
+            .ok_or(IpRangeError::SnapshotNotFound(index))?;
+        let (mut tree, info) = store.load(index)?;
+        warn!(
+            index,
+            created_at = %info.created_at,
+            "Rolling back radix tree to snapshot"
+        );
+        if self.config.bloom_filter {
+            tree.build_prefilter();
+        }
+        self.replace_tree(tree, info.created_at);
+        Ok(info)
+    }
+
+    /// Put `tree`, built from data as of `built_at`, live with the manual
+    /// entries applied over it
+    fn replace_tree(&self, mut tree: RadixTree, built_at: DateTime<Utc>) {
+        let mut manual_ranges = self.manual_ranges.lock();
+        let mut shadowed = Vec::new();
+        for range in manual_ranges.active(chrono::Utc::now()) {
+            match range.network.parse::<IpNetwork>() {
+                Ok(network) => {
+                    let replaced = tree.replace_from(network, range.category, MANUAL_SOURCE);
+                    shadowed.push((range.network.clone(), replaced));
+                }
+                Err(e) => error!(network = %range.network, error = %e, "Skipping unparseable manual range"),
+            }
+        }
+        for (network, replaced) in shadowed {
+            manual_ranges.shadow(&network, replaced);
+        }
+        self.tree.replace(tree);
+        drop(manual_ranges);
+        self.record_category_data(built_at);
+    }
+
+    /// Count the live tree's entries per category and date each category by
+    /// its feeds' downloads, no later than `built_at`
+    fn record_category_data(&self, built_at: DateTime<Utc>) {
+        let data = self
+            .tree
+            .category_counts()
+            .into_iter()
+            .map(|(category, entries)| {
+                let downloaded = self
+                    .config
+                    .sources
+                    .iter()
+                    .filter(|source| source.category == category)
+                    .filter_map(|source| self.source_updated_at(&source.name))
+                    .max();
+                let updated_at = downloaded.map_or(built_at, |downloaded| downloaded.min(built_at));
+                (category, CategoryData { entries, updated_at })
+            })
+            .collect();
+        self.category_data.store(Arc::new(data));
+    }
+
+    /// Categories fed by an enabled source that have no entries in the live
+    /// tree, or whose data is older than `max_age`
+    pub fn missing_categories(&self, max_age: Option<Duration>) -> Vec<IpCategory> {
+        let data = self.category_data.load();
+        let now = Utc::now();
+        let mut missing = Vec::new();
+        for source in &self.config.sources {
+            if !source.enabled || !self.category_enabled(source.category) || missing.contains(&source.category) {
+                continue;
+            }
+            let available = data.get(&source.category).is_some_and(|data| {
+                data.entries > 0 && max_age.is_none_or(|max_age| (now - data.updated_at).to_std().map_or(true, |age| age <= max_age))
+            });
+            if !available {
+                missing.push(source.category);
+            }
+        }
+        missing
+    }
+
+    /// Add a manual entry and put it in the live tree right away. It replaces
+    /// any feed entry for the same network until it is deleted or expires;
+    /// overrides still win.
+    pub fn add_manual_range(&self, network: IpNetwork, category: IpCategory, ttl: Option<Duration>) -> ManualRange {
+        let added_at = chrono::Utc::now();
+        let range = ManualRange {
+            network: network.to_string(),
+            category,
+            added_at,
+            expires_at: ttl.and_then(|ttl| chrono::Duration::from_std(ttl).ok()).map(|ttl| added_at + ttl),
+        };
+        let mut manual_ranges = self.manual_ranges.lock();
+        manual_ranges.insert(range.clone());
+        let replaced = self.tree.replace_from(network, category, MANUAL_SOURCE);
+        manual_ranges.shadow(&range.network, replaced);
+        range
+    }
+
+    /// Remove a manual entry and its network from the live tree, putting back
+    /// the feed entry it replaced
+    pub fn remove_manual_range(&self, network: IpNetwork) -> Option<ManualRange> {
+        let mut manual_ranges = self.manual_ranges.lock();
+        let removed = manual_ranges.remove(&network.to_string())?;
+        self.remove_manual_from_tree(&mut manual_ranges, network);
+        Some(removed)
+    }
+
+    /// Take a manual entry's network out of the live tree, restoring the
+    /// feed entry it shadowed
+    fn remove_manual_from_tree(&self, manual_ranges: &mut ManualRanges, network: IpNetwork) {
+        self.tree.remove_from(network, MANUAL_SOURCE);
+        if let Some(label) = manual_ranges.take_shadowed(&network.to_string()) {
+            self.tree
+                .insert_from(network, label.category, label.source.as_deref().unwrap_or_default());
+        }
+    }
+
+    /// Manual entries still in effect, ordered by network
+    pub fn manual_ranges(&self) -> Vec<ManualRange> {
+        self.manual_ranges.lock().active(chrono::Utc::now()).cloned().collect()
+    }
+
+    /// Drop expired manual entries from the live tree, returning them
+    pub fn expire_manual_ranges(&self) -> Vec<ManualRange> {
+        let mut manual_ranges = self.manual_ranges.lock();
+        let expired = manual_ranges.expire(chrono::Utc::now());
+        for range in &expired {
+            if let Ok(network) = range.network.parse::<IpNetwork>() {
+                self.remove_manual_from_tree(&mut manual_ranges, network);
+            }
+            info!(
+                target: "audit",
+                action = "manual_range.expire",
+                network = %range.network,
+                category = %range.category,
+                "Manual range expired"
+            );
+        }
+        expired
+    }
+
+    /// Every network in the live tree containing `ip`, most specific (the
+    /// one lookups report) first
+    pub fn explain(&self, ip: IpAddr) -> Vec<ExplainedMatch> {
+        self.tree
+            .lookup_networks(ip)
+            .into_iter()
+            .map(|found| {
+                let network = found.network.map(|network| network.to_string());
+                let source_updated_at = match (found.source.as_deref(), &network) {
+                    (Some(MANUAL_SOURCE), Some(network)) => self
+                        .manual_ranges()
+                        .into_iter()
+                        .find(|range| &range.network == network)
+                        .map(|range| range.added_at),
+                    (Some(OVERRIDES_SOURCE), _) => self.overrides_mtime().map(Into::into),
+                    (Some(name), _) => self.source_updated_at(name),
+                    (None, _) => None,
+                };
+                ExplainedMatch {
+                    network,
+                    category: found.category,
+                    source: found.source.map(|source| source.to_string()),
+                    source_updated_at,
+                }
+            })
+            .collect()
+    }
+
+    /// Modification time of a configured source's local copy
+    fn source_updated_at(&self, name: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+        let source = self.config.sources.iter().find(|source| source.name == name)?;
+        let path = match source.format {
+            SourceFormat::Delta => self.delta_state_path(source),
+            _ => self.source_path(source).ok()?,
+        };
+        self.loader.last_modified(&path)
+    }
+
+    /// Configured sources with their last accepted count, feed diff, parse
+    /// report and any ongoing failures
+    pub fn source_status(&self) -> Vec<SourceStatus> {
+        let accepted = self.accepted_counts.lock();
+        let diffs = self.last_diffs.lock();
+        let reports = self.parse_reports.lock();
+        let fetches = self.fetch_reports.lock();
+        let backoff = self.backoff.lock();
+        self.config
+            .sources
+            .iter()
+            .map(|source| SourceStatus {
+                name: source.name.clone(),
+                category: source.category,
+                enabled: source.enabled,
+                accepted_count: accepted.get(&source.name).copied(),
+                last_diff: diffs.get(&source.name).cloned(),
+                last_parse: reports.get(&source.name).cloned(),
+                last_fetch: fetches.get(&source.name).cloned(),
+                failure: backoff.get(&source.name).cloned(),
+            })
+            .collect()
+    }
+
+    /// Where the feeds disagreed on categories in the last tree built from
+    /// them; `None` until one is built
+    pub fn overlap_report(&self) -> Option<OverlapReport> {
+        self.overlaps.lock().clone()
+    }
+
+    /// Sources whose recent updates failed, ordered by name
+    pub fn failing_sources(&self) -> Vec<SourceFailure> {
+        self.backoff.lock().failures()
+    }
+
+    fn record_overlaps(&self, report: OverlapReport) {
+        if report.identical + report.nested > 0 {
+            info!(
+                identical = report.identical,
+                nested = report.nested,
+                most_contested = report.top.first().map(|prefix| prefix.network.as_str()),
+                "Feeds disagree on the category of some networks"
+            );
+        }
+        monitoring::record_category_conflicts(report.identical, report.nested);
+        *self.overlaps.lock() = Some(report);
+    }
+
+    /// Track a source's update outcome for backoff, logging when a failing
+    /// source recovers
+    fn record_source_outcome(&self, source: &IpRangeSource, error: Option<String>, started: chrono::DateTime<chrono::Utc>) {
+        let mut backoff = self.backoff.lock();
+        match error {
+            Some(error) => {
+                let failure = backoff.record_failure(&source.name, error, started);
+                if failure.consecutive_failures > 1 {
+                    warn!(
+                        source = %source.name,
+                        consecutive_failures = failure.consecutive_failures,
+                        next_attempt_at = %failure.next_attempt_at,
+                        "Source keeps failing, backing off"
+                    );
+                }
+            }
+            None => {
+                if let Some(failure) = backoff.record_success(&source.name) {
+                    info!(
+                        source = %source.name,
+                        consecutive_failures = failure.consecutive_failures,
+                        failing_since = %failure.failing_since,
+                        "Source recovered"
+                    );
+                }
+            }
+        }
+    }
+
+    /// Start the background update task, and the overrides file watch,
+    /// which also runs in offline mode. The overrides are loaded before this
+    /// returns.
     pub fn start_background_updates(&self) -> tokio::task::JoinHandle<()> {
+        self.reload_overrides();
+        let service = self.clone();
+        let watch = tokio::spawn(async move {
+            service.watch_overrides().await;
+        });
+        if self.config.offline {
+            info!("Offline mode, background IP range updates are disabled");
+            return watch;
+        }
         let service = self.clone();
         tokio::spawn(async move {
             service.run_update_loop().await;
@@ -89,7 +634,6 @@ impl IpLookupService {
             error!(error = %e, "Failed to perform initial update");
         }
 
-        // Periodic updates
         loop {
             interval.tick().await;
             if let Err(e) = self.update_all_sources().await {
@@ -98,41 +642,192 @@ impl IpLookupService {
         }
     }
 
+    /// Expire manual entries and swap in the overrides whenever their file
+    /// changes, independently of feed updates
+    async fn watch_overrides(&self) {
+        let mut overrides_poll = tokio::time::interval(OVERRIDES_POLL_INTERVAL);
+        loop {
+            overrides_poll.tick().await;
+            self.expire_manual_ranges();
+            self.reload_overrides();
+        }
+    }
+
+    fn overrides_mtime(&self) -> Option<SystemTime> {
+        let path = self.config.overrides_file.as_ref()?;
+        std::fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+
+    /// Whether the overrides file was modified, created or removed since it
+    /// was last loaded
+    fn overrides_changed(&self) -> bool {
+        self.overrides_mtime() != *self.overrides_mtime.lock()
+    }
+
+    /// Put the overrides file's entries live, ahead of every feed and manual
+    /// entry, if it changed since it was last loaded. Returns whether it had.
+    pub fn reload_overrides(&self) -> bool {
+        if !self.overrides_changed() {
+            return false;
+        }
+        let mut overrides = RadixTree::new();
+        for range in self.load_overrides().iter().filter(|range| self.category_enabled(range.category)) {
+            match range.network.parse::<IpNetwork>() {
+                Ok(network) => {
+                    overrides.insert_from(network, range.category, OVERRIDES_SOURCE);
+                }
+                Err(e) => error!(network = %range.network, error = %e, "Skipping unparseable override"),
+            }
+        }
+        self.tree.replace_overrides(overrides);
+        self.record_category_data(Utc::now());
+        self.updates.send_modify(|updates| *updates += 1);
+        true
+    }
+
+    /// Entries from the overrides file; empty if it is unset or missing
+    fn load_overrides(&self) -> Vec<IpRange> {
+        let Some(path) = &self.config.overrides_file else {
+            return Vec::new();
+        };
+        let mtime = self.overrides_mtime();
+        *self.overrides_mtime.lock() = mtime;
+        if mtime.is_none() {
+            return Vec::new();
+        }
+
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) => {
+                warn!(path = %path.display(), error = %e, "Failed to read overrides file");
+                return Vec::new();
+            }
+        };
+        let (ranges, report) = loader::parse_overrides(&content);
+        match report.sample_errors.first() {
+            Some(first) => {
+                monitoring::record_parse_errors(OVERRIDES_SOURCE, report.invalid);
+                warn!(
+                    path = %path.display(),
+                    num_ranges = ranges.len(),
+                    invalid = report.invalid,
+                    first_error_line = first.line,
+                    first_error = %first.reason,
+                    "Loaded overrides with malformed lines skipped"
+                );
+            }
+            None => info!(path = %path.display(), num_ranges = ranges.len(), "Loaded overrides"),
+        }
+        self.parse_reports.lock().insert(OVERRIDES_SOURCE.to_string(), report);
+        ranges
+    }
+
     /// Update all data sources
     pub async fn update_all_sources(&self) -> anyhow::Result<()> {
         info!("Starting update of all IP range sources");
+        let started = chrono::Utc::now();
         let mut all_ranges = Vec::new();
+        let mut source_counts = HashMap::new();
         let mut errors = Vec::new();
+        let mut delta_ranges = Vec::new();
+        let mut delta_changes = Vec::new();
+        // Where each source's entries sit in `all_ranges` or `delta_ranges`,
+        // to be archived once the update they belong to is accepted
+        let mut loaded = Vec::new();
+        let mut delta_loaded = Vec::new();
 
         for source in &self.config.sources {
-            if !source.enabled {
+            if !source.enabled || !self.category_enabled(source.category) {
+                continue;
+            }
+
+            // A source backing off is left out as if this attempt failed too
+            let due = self.backoff.lock().is_due(&source.name, started);
+            if !due {
+                debug!(source = %source.name, "Source is backing off after repeated failures, skipping");
+            }
+
+            if source.format == SourceFormat::Delta {
+                let outcome = if due { Some(self.update_delta_source(source).await) } else { None };
+                let report = match outcome {
+                    Some(Ok((changes, report))) => {
+                        self.record_source_outcome(source, None, started);
+                        delta_changes.push((source, changes));
+                        report
+                    }
+                    // The changes applied so far still stand
+                    Some(Err(e)) => {
+                        self.record_source_outcome(source, Some(e.to_string()), started);
+                        let error_msg = format!("Failed to update diff source {} ({}): {}", source.name, source.url, e);
+                        error!("{}", error_msg);
+                        errors.push(error_msg);
+                        None
+                    }
+                    None => None,
+                };
+                let ranges = self.delta_ranges(source);
+                if let Some(report) = report {
+                    self.record_parse_report(&source.name, source.category, ranges.len(), report);
+                }
+                source_counts.insert(source.name.clone(), ranges.len());
+                delta_loaded.push((source, delta_ranges.len()..delta_ranges.len() + ranges.len()));
+                delta_ranges.extend(ranges);
+                continue;
+            }
+
+            // Left out of the delta check too, so the next good update is
+            // compared with the last accepted count rather than with zero
+            if !due {
                 continue;
             }
 
             match self.update_source(source).await {
-                Ok(ranges) => {
-                    info!(
-                        source = %source.name,
-                        category = ?source.category,
-                        num_ranges = ranges.len(),
-                        "Successfully updated source"
-                    );
+                Ok((ranges, report)) => {
+                    self.record_source_outcome(source, None, started);
+                    self.record_parse_report(&source.name, source.category, ranges.len(), report);
+                    source_counts.insert(source.name.clone(), ranges.len());
+                    loaded.push((source, all_ranges.len()..all_ranges.len() + ranges.len()));
                     all_ranges.extend(ranges);
                 }
                 Err(e) => {
+                    self.record_source_outcome(source, Some(e.to_string()), started);
                     let error_msg = format!(
                         "Failed to update source {} ({}): {}",
                         source.name, source.url, e
                     );
                     error!("{}", error_msg);
                     errors.push(error_msg);
+                    // A failed source contributes nothing to the new tree,
+                    // and is not counted as having shrunk to nothing
                 }
             }
         }
 
-        // Update the radix tree with all ranges
-        if !all_ranges.is_empty() {
-            self.update_tree(all_ranges).await?;
+        if all_ranges.is_empty() && *self.loaded.borrow() {
+            // Only diff feeds to update, so change the live tree in place
+            let mut accepted = self.accepted_counts.lock();
+            for (source, changes) in &delta_changes {
+                self.apply_delta_changes(source, changes);
+                if let Some(&count) = source_counts.get(&source.name) {
+                    accepted.insert(source.name.clone(), count);
+                }
+            }
+            if delta_changes.iter().any(|(_, changes)| !changes.is_empty()) {
+                self.updates.send_modify(|updates| *updates += 1);
+            }
+            drop(accepted);
+            self.record_category_data(Utc::now());
+            self.record_accepted(&delta_ranges, delta_loaded);
+        } else {
+            let offset = all_ranges.len();
+            loaded.extend(delta_loaded.into_iter().map(|(source, span)| (source, span.start + offset..span.end + offset)));
+            all_ranges.extend(delta_ranges);
+
+            // Update the radix tree with all ranges
+            if !all_ranges.is_empty() {
+                self.guarded_update(source_counts, &all_ranges).await?;
+                self.record_accepted(&all_ranges, loaded);
+            }
         }
 
         // Log any errors that occurred
@@ -147,17 +842,33 @@ impl IpLookupService {
     }
 
     /// Update a single data source
-    async fn update_source(&self, source: &IpRangeSource) -> anyhow::Result<Vec<IpRange>> {
+    #[tracing::instrument(
+        name = "infralock.update_source",
+        skip_all,
+        fields(source = %source.name, category = ?source.category, ranges = tracing::field::Empty)
+    )]
+    async fn update_source(&self, source: &IpRangeSource) -> anyhow::Result<(Vec<IpRange>, ParseReport)> {
         info!("Checking source: {} ({})", source.name, source.url);
         
-        // Generate a filename for this source
-        let url = Url::parse(&source.url)?;
-        let filename = self.loader.filename_from_url(&url, source.category, source.ip_version);
-        let filepath = self.config.data_dir.join(&filename);
+        let filepath = self.source_path(source)?;
+
+        // Lists are synced out-of-band, so use whatever copy is on disk
+        if self.config.offline {
+            if !filepath.exists() {
+                return Err(anyhow::anyhow!(
+                    "offline mode and no local copy at {}",
+                    filepath.display()
+                ));
+            }
+            info!("Offline mode, loading {} from {}", source.name, filepath.display());
+            return self.loader.load_from_file(&filepath, source.category, &source.name, source.format).await
+                .map_err(|e| anyhow::anyhow!("Failed to load from cache: {}", e));
+        }
         
-        // Check if the file exists and needs an update
+        // Check if the file exists and needs an update. A local list is
+        // cheap to read, so changes to it are picked up on every cycle.
         if filepath.exists() {
-            if !self.loader.needs_update(&filepath) {
+            if !source.is_local() && !self.loader.needs_update(&filepath) {
                 info!("Source {} is up to date, loading from cache", source.name);
                 return self.loader.load_from_file(&filepath, source.category, &source.name, source.format).await
                     .map_err(|e| anyhow::anyhow!("Failed to load from cache: {}", e));
@@ -176,19 +887,316 @@ impl IpLookupService {
         
         // Download and parse the ranges
         info!("Downloading ranges from {}", source.url);
-        let ranges = self.loader.download_ranges(&source.url, source).await?;
+        let (downloaded, fetch) = self.loader.download_with_mirrors(source).await;
+        self.fetch_reports.lock().insert(source.name.clone(), fetch);
+        let (ranges, report) = match downloaded {
+            Ok(found) => found,
+            // Rejected content is never saved, so the local copy is the last good one
+            Err(e) if e.is_bad_content() && filepath.exists() => {
+                error!(source = %source.name, error = %e, "Rejected downloaded feed, keeping the previous copy");
+                return self.loader.load_from_file(&filepath, source.category, &source.name, source.format).await
+                    .map_err(|e| anyhow::anyhow!("Failed to load from cache: {}", e));
+            }
+            Err(e) => return Err(e.into()),
+        };
         
         info!(
             "Downloaded {} ranges from {}",
             ranges.len(),
             source.name
         );
+        tracing::Span::current().record("ranges", ranges.len());
         
-        Ok(ranges)
+        Ok((ranges, report))
+    }
+
+    /// Where a source's downloaded copy is kept
+    fn source_path(&self, source: &IpRangeSource) -> anyhow::Result<PathBuf> {
+        let url = Url::parse(&source.url)?;
+        let filename = self.loader.filename_from_url(&url, source.category, source.ip_version);
+        Ok(self.config.data_dir.join(filename))
+    }
+
+    /// Where a diff source's entries and cursor are kept
+    fn delta_state_path(&self, source: &IpRangeSource) -> PathBuf {
+        self.config.data_dir.join(format!("{}.delta.json", source.name))
+    }
+
+    /// Take a diff source's state out of the map, reading it from disk on
+    /// first use
+    fn take_delta_state(&self, source: &IpRangeSource) -> DeltaState {
+        if let Some(state) = self.delta_states.lock().remove(&source.name) {
+            return state;
+        }
+        DeltaState::load(&self.delta_state_path(source)).unwrap_or_else(|e| {
+            warn!(source = %source.name, error = %e, "Failed to read diff feed state, fetching the full list");
+            DeltaState::default()
+        })
+    }
+
+    /// Fetch a diff source's changes since its cursor and apply them to its
+    /// entries, returning the changes that took effect and the parse report
+    /// (none in offline mode, which keeps the saved entries)
+    #[tracing::instrument(
+        name = "infralock.update_delta_source",
+        skip_all,
+        fields(source = %source.name, category = ?source.category)
+    )]
+    async fn update_delta_source(
+        &self,
+        source: &IpRangeSource,
+    ) -> anyhow::Result<(Vec<DeltaChange>, Option<ParseReport>)> {
+        let mut state = self.take_delta_state(source);
+        if self.config.offline {
+            info!("Offline mode, keeping the saved entries of {}", source.name);
+            self.delta_states.lock().insert(source.name.clone(), state);
+            return Ok((Vec::new(), None));
+        }
+
+        let result = self.loader.download_delta(source, &state).await.map(|(delta, report)| {
+            let changes = state.apply(delta);
+            if let Err(e) = state.save(&self.delta_state_path(source)) {
+                warn!(source = %source.name, error = %e, "Failed to save diff feed state");
+            }
+            info!(
+                source = %source.name,
+                changes = changes.len(),
+                entries = state.count(),
+                cursor = ?state.cursor,
+                "Applied diff feed"
+            );
+            (changes, Some(report))
+        });
+        self.delta_states.lock().insert(source.name.clone(), state);
+        Ok(result?)
+    }
+
+    /// A diff source's current entries, for a full rebuild
+    fn delta_ranges(&self, source: &IpRangeSource) -> Vec<IpRange> {
+        self.delta_states
+            .lock()
+            .get(&source.name)
+            .map(|state| state.ranges(source))
+            .unwrap_or_default()
+    }
+
+    /// Apply a diff source's changes to the live tree without a rebuild.
+    /// Manual entries for the same network are left in place, with the
+    /// change applied to the feed entry they shadow instead.
+    fn apply_delta_changes(&self, source: &IpRangeSource, changes: &[DeltaChange]) {
+        let prefix = self.config.ipv6_aggregate_prefix;
+        let tree_network = |entry: &str| {
+            entry
+                .parse::<IpNetwork>()
+                .ok()
+                .map(|network| aggregate_v6_host(network, source.category, prefix))
+        };
+        let mut manual_ranges = self.manual_ranges.lock();
+        let states = self.delta_states.lock();
+        let now = chrono::Utc::now();
+
+        for change in changes {
+            let (DeltaChange::Add(entry) | DeltaChange::Remove(entry)) = change;
+            let Some(network) = tree_network(entry) else {
+                error!("Failed to parse network '{}' from source '{}'", entry, source.name);
+                continue;
+            };
+            let key = network.to_string();
+            let manual = manual_ranges.covers(&key, now);
+            match change {
+                DeltaChange::Add(_) if manual => manual_ranges.shadow(
+                    &key,
+                    Some(OverlapLabel {
+                        category: source.category,
+                        source: Some(source.name.clone()),
+                    }),
+                ),
+                DeltaChange::Add(_) => {
+                    self.tree.insert_from(network, source.category, &source.name);
+                }
+                DeltaChange::Remove(_) => {
+                    // An IPv6 host widened to its subnet stays while another
+                    // host in that subnet is still listed
+                    let widened = entry.parse::<IpNetwork>().ok() != Some(network);
+                    let still_listed = widened
+                        && states
+                            .get(&source.name)
+                            .is_some_and(|state| state.shares_network(entry, |other| tree_network(other) == Some(network)));
+                    if still_listed {
+                        continue;
+                    }
+                    if manual {
+                        manual_ranges.unshadow(&key, &source.name);
+                    } else {
+                        self.tree.remove_from(network, &source.name);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Log one summary event for an updated source and keep its parse
+    /// report for the admin sources endpoint
+    fn record_parse_report(&self, source: &str, category: IpCategory, num_ranges: usize, report: ParseReport) {
+        match report.sample_errors.first() {
+            Some(first) => {
+                monitoring::record_parse_errors(source, report.invalid);
+                warn!(
+                    source,
+                    ?category,
+                    num_ranges,
+                    total_lines = report.total_lines,
+                    skipped = report.skipped_comments,
+                    invalid = report.invalid,
+                    first_error_line = first.line,
+                    first_error = %first.reason,
+                    "Updated source with malformed entries skipped"
+                );
+            }
+            None => info!(
+                source,
+                ?category,
+                num_ranges,
+                total_lines = report.total_lines,
+                skipped = report.skipped_comments,
+                "Successfully updated source"
+            ),
+        }
+        self.parse_reports.lock().insert(source.to_string(), report);
+    }
+
+    /// Archive and diff the entries of an accepted update, and track Tor
+    /// delistings, for each source's span of `ranges`. A rejected update
+    /// never becomes the baseline the next one is compared with.
+    fn record_accepted(&self, ranges: &[IpRange], loaded: Vec<(&IpRangeSource, std::ops::Range<usize>)>) {
+        for (source, span) in loaded {
+            self.record_feed_diff(&source.name, &ranges[span.clone()]);
+            self.record_tor_entries(source, &ranges[span]);
+        }
+    }
+
+    /// Diff a Tor source's entries against its previous load, so those that
+    /// disappeared are reported as recently delisted
+    fn record_tor_entries(&self, source: &IpRangeSource, ranges: &[IpRange]) {
+        let Some(delisted) = &self.delisted else {
+            return;
+        };
+        if source.category != IpCategory::TorExitNode {
+            return;
+        }
+        let prefix = self.config.ipv6_aggregate_prefix;
+        let entries = ranges
+            .iter()
+            .filter_map(|range| range.network.parse::<IpNetwork>().ok())
+            .map(|network| aggregate_v6_host(network, source.category, prefix))
+            .collect();
+        delisted.lock().record_load(&source.name, entries, chrono::Utc::now());
+    }
+
+    /// Diff a source's entries against its archived previous version, then
+    /// archive them if they changed
+    fn record_feed_diff(&self, source: &str, ranges: &[IpRange]) {
+        let Some(archive) = &self.archive else {
+            return;
+        };
+
+        let mut entries: Vec<&str> = ranges.iter().map(|r| r.network.as_str()).collect();
+        entries.sort_unstable();
+        entries.dedup();
+
+        let diff = match archive.diff(source, &entries) {
+            Ok(diff) => diff,
+            Err(e) => {
+                warn!(source, error = %e, "Failed to diff feed against its archive");
+                return;
+            }
+        };
+
+        if let Some(diff) = &diff {
+            info!(
+                source,
+                added = diff.added,
+                removed = diff.removed,
+                unchanged = diff.unchanged,
+                "Feed diff against previous version"
+            );
+            self.last_diffs.lock().insert(
+                source.to_string(),
+                SourceDiff { diff: diff.clone(), computed_at: chrono::Utc::now() },
+            );
+        }
+
+        // Only archive new versions, so the retained files are distinct
+        if diff.is_none_or(|diff| !diff.is_unchanged()) {
+            if let Err(e) = archive.save(source, &entries) {
+                warn!(source, error = %e, "Failed to archive feed entries");
+            }
+        }
+    }
+
+    /// Replace the tree unless a source's entry count moved by more than its
+    /// `max_delta_percent` since the last accepted update
+    async fn guarded_update(
+        &self,
+        source_counts: HashMap<String, usize>,
+        ranges: &[IpRange],
+    ) -> anyhow::Result<()> {
+        let rejected = self.check_feed_deltas(&source_counts);
+        if !rejected.is_empty() {
+            for source in &rejected {
+                monitoring::record_feed_update_rejected(source);
+            }
+            error!(
+                sources = ?rejected,
+                "ALERT: IP range feed sanity check failed, keeping the current tree"
+            );
+            return Err(anyhow::anyhow!(
+                "Refusing tree update: entry count changed too much for {}",
+                rejected.join(", ")
+            ));
+        }
+
+        self.update_tree(ranges).await?;
+        self.accepted_counts.lock().extend(source_counts);
+        Ok(())
+    }
+
+    /// Return the sources whose new entry count exceeds their allowed delta
+    fn check_feed_deltas(&self, source_counts: &HashMap<String, usize>) -> Vec<String> {
+        let accepted = self.accepted_counts.lock();
+        let mut rejected = Vec::new();
+
+        for source in &self.config.sources {
+            let (Some(max_delta), Some(&previous), Some(&current)) = (
+                source.max_delta_percent,
+                accepted.get(&source.name),
+                source_counts.get(&source.name),
+            ) else {
+                continue;
+            };
+
+            if exceeds_delta(previous, current, max_delta) {
+                warn!(
+                    source = %source.name,
+                    previous,
+                    current,
+                    max_delta_percent = max_delta,
+                    "Feed entry count changed beyond the allowed delta"
+                );
+                rejected.push(source.name.clone());
+            }
+        }
+
+        rejected
+    }
+
+    /// Whether entries of `category` go in the tree
+    pub fn category_enabled(&self, category: IpCategory) -> bool {
+        self.config.enabled_categories.as_ref().is_none_or(|enabled| enabled.contains(&category))
     }
 
     /// Update the radix tree with new ranges
-    async fn update_tree(&self, ranges: Vec<IpRange>) -> anyhow::Result<()> {
+    async fn update_tree(&self, ranges: &[IpRange]) -> anyhow::Result<()> {
         //info!("Updating radix tree with {} ranges", ranges.len());
         let mut v4_count = 0;
         let mut v6_count = 0;
@@ -207,9 +1215,10 @@ impl IpLookupService {
         
         // Create a new tree to build up
         let mut new_tree = RadixTree::new();
+        let mut overlaps = OverlapCollector::default();
         
         // Process each range
-        for range in &ranges {
+        for range in ranges.iter().filter(|range| self.category_enabled(range.category)) {
             match range.network.parse::<IpNetwork>() {
                 Ok(network) => {
                     match network {
@@ -227,7 +1236,12 @@ impl IpLookupService {
                     }
                     
                     // Insert into the new tree
-                    let prev_category = new_tree.insert(network, range.category);
+                    let network = aggregate_v6_host(network, range.category, self.config.ipv6_aggregate_prefix);
+                    let replaced = insert_built(&mut new_tree, network, range.category, &range.source);
+                    let prev_category = replaced.as_ref().map(|(dropped, _)| dropped.category);
+                    if let Some((dropped, kept)) = replaced {
+                        overlaps.identical(network, dropped, kept);
+                    }
                     
                     // Track insertions vs skips
                     match network {
@@ -291,9 +1305,35 @@ impl IpLookupService {
         //    v4_size, v6_size, v4_size + v6_size
         //);
 
+        // Built before the swap so the filter always matches the live tree
+        if self.config.bloom_filter {
+            new_tree.build_prefilter();
+        }
+
+        // Keep a copy of the new tree so a bad update can be rolled back
+        if let Some(store) = &self.snapshots {
+            if let Err(e) = store.save(&new_tree) {
+                error!(error = %e, "Failed to save tree snapshot");
+            }
+        }
+        // The pass that flattens the tree also finds nested conflicts
+        let flattened = new_tree.flatten(&mut overlaps);
+        // Lets the next start answer from this tree while it rebuilds
+        if self.config.flat_snapshot {
+            if let Err(e) = flat::write(self.flat_snapshot_path(), flattened) {
+                error!(error = %e, "Failed to save flat tree snapshot");
+            }
+        }
+        self.record_overlaps(overlaps.finish());
+
+        if self.tree.is_flat() {
+            info!("Replacing the flat tree snapshot with the built tree");
+        }
         // Atomically replace the tree
         info!("Replacing the radix tree with new data...");
-        self.tree.replace(new_tree);
+        self.replace_tree(new_tree, Utc::now());
+        self.loaded.send_replace(true);
+        self.updates.send_modify(|updates| *updates += 1);
         
         // Log final tree size (using the tree we just updated)
         let (final_v4, final_v6) = self.tree.len();
@@ -335,10 +1375,76 @@ impl Clone for IpLookupService {
             tree: self.tree.clone(),
             loader: self.loader.clone(),
             config: self.config.clone(),
+            snapshots: self.snapshots.clone(),
+            accepted_counts: Arc::clone(&self.accepted_counts),
+            archive: self.archive.clone(),
+            last_diffs: Arc::clone(&self.last_diffs),
+            parse_reports: Arc::clone(&self.parse_reports),
+            fetch_reports: Arc::clone(&self.fetch_reports),
+            overrides_mtime: Arc::clone(&self.overrides_mtime),
+            manual_ranges: Arc::clone(&self.manual_ranges),
+            delta_states: Arc::clone(&self.delta_states),
+            delisted: self.delisted.clone(),
+            backoff: Arc::clone(&self.backoff),
+            overlaps: Arc::clone(&self.overlaps),
+            category_data: Arc::clone(&self.category_data),
+            loaded: Arc::clone(&self.loaded),
+            updates: Arc::clone(&self.updates),
         }
     }
 }
 
+fn is_cloud(category: IpCategory) -> bool {
+    matches!(category, IpCategory::CloudProvider(_))
+}
+
+/// Insert into a tree being built, returning the dropped and kept labels
+/// when another entry had the same network. A cloud provider listing the
+/// same network as a VPN or proxy feed does not hide that entry's flags.
+fn insert_built(
+    tree: &mut RadixTree,
+    network: IpNetwork,
+    category: IpCategory,
+    source: &str,
+) -> Option<(OverlapLabel, OverlapLabel)> {
+    let replaced = tree.replace_from(network, category, source)?;
+    let new = OverlapLabel { category, source: Some(source.to_string()) };
+    if is_cloud(category) && !is_cloud(replaced.category) {
+        tree.replace_from(network, replaced.category, replaced.source.as_deref().unwrap_or_default());
+        return Some((new, replaced));
+    }
+    Some((replaced, new))
+}
+
+/// Widen a single-host IPv6 proxy or Tor entry to its enclosing `/prefix`,
+/// since whoever runs it usually controls the whole subnet. VPN, datacenter
+/// and cloud provider feeds already list ranges and are left alone.
+fn aggregate_v6_host(network: IpNetwork, category: IpCategory, prefix: u8) -> IpNetwork {
+    let IpNetwork::V6(net) = network else {
+        return network;
+    };
+    if net.netmask() != 128
+        || prefix >= 128
+        || matches!(category, IpCategory::Vpn | IpCategory::CloudProvider(_))
+    {
+        return network;
+    }
+    Ipv6Network::new_truncate(net.network_address(), prefix)
+        .map(IpNetwork::V6)
+        .unwrap_or(network)
+}
+
+/// Whether moving from `previous` to `current` entries is a change of more
+/// than `max_delta_percent` percent. A source with no previous entries is
+/// always accepted.
+fn exceeds_delta(previous: usize, current: usize, max_delta_percent: f64) -> bool {
+    if previous == 0 {
+        return false;
+    }
+    let delta = previous.abs_diff(current) as f64 / previous as f64 * 100.0;
+    delta > max_delta_percent
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -355,7 +1461,9 @@ mod tests {
             name: "test".to_string(),
             enabled: true,
             format: SourceFormat::Default,
+            max_delta_percent: None,
             ip_version: IpVersion::V4,
+            mirrors: Vec::new(),
         };
 
         let config = IpLookupServiceConfig {
@@ -363,6 +1471,18 @@ mod tests {
             check_updates: true,
             update_interval_secs: 3600,
             max_cache_age_secs: 86400,
+            snapshot_retention: 0,
+            archive_dir: temp_dir.path().join("archive"),
+            archive_retention: 0,
+            ipv6_aggregate_prefix: 128,
+            bloom_filter: true,
+            flat_snapshot: false,
+            offline: false,
+            overrides_file: None,
+            outbound_http: OutboundHttpSettings::default(),
+            tor_delisted_window_secs: 0,
+            feed_backoff_max_secs: 0,
+            enabled_categories: None,
             sources: vec![test_source],
         };
 
@@ -393,7 +1513,7 @@ mod tests {
         ];
         
         // Update the tree with test ranges
-        service.update_tree(test_ranges).await.unwrap();
+        service.update_tree(&test_ranges).await.unwrap();
         
         // Verify the tree was updated
         let (v4_count, v6_count) = service.tree().len();
@@ -403,4 +1523,789 @@ mod tests {
         // Test that the service can be started
         let _handle = service.start_background_updates();
     }
+
+    fn vpn_ranges(count: u32) -> Vec<IpRange> {
+        (0..count)
+            .map(|i| IpRange {
+                network: format!("10.{}.{}.0/24", i / 256, i % 256),
+                category: IpCategory::Vpn,
+                source: "vpn".to_string(),
+                first_seen: Utc::now(),
+                last_updated: Utc::now(),
+                format: SourceFormat::Default,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_record_feed_diff() {
+        let temp_dir = tempdir().unwrap();
+        let service = IpLookupService::new(IpLookupServiceConfig {
+            data_dir: temp_dir.path().to_path_buf(),
+            check_updates: false,
+            update_interval_secs: 3600,
+            max_cache_age_secs: 86400,
+            snapshot_retention: 0,
+            archive_dir: temp_dir.path().join("archive"),
+            archive_retention: 2,
+            ipv6_aggregate_prefix: 128,
+            bloom_filter: true,
+            flat_snapshot: false,
+            offline: false,
+            overrides_file: None,
+            outbound_http: OutboundHttpSettings::default(),
+            tor_delisted_window_secs: 0,
+            feed_backoff_max_secs: 0,
+            enabled_categories: None,
+            sources: vec![IpRangeSource {
+                url: "https://example.com/vpn.txt".to_string(),
+                category: IpCategory::Vpn,
+                name: "vpn".to_string(),
+                enabled: true,
+                format: SourceFormat::Default,
+                max_delta_percent: None,
+                ip_version: IpVersion::V4,
+                mirrors: Vec::new(),
+            }],
+        });
+
+        // The first version has nothing to diff against, but is archived
+        service.record_feed_diff("vpn", &vpn_ranges(100));
+        let status = service.source_status();
+        assert!(status[0].last_diff.is_none());
+
+        // The second drops 60 entries; duplicates are counted once
+        let mut ranges = vpn_ranges(40);
+        ranges.extend(vpn_ranges(10));
+        service.record_feed_diff("vpn", &ranges);
+        let diff = service.source_status()[0].last_diff.clone().unwrap().diff;
+        assert_eq!((diff.added, diff.removed, diff.unchanged), (0, 60, 40));
+
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        service.record_feed_diff("vpn", &vpn_ranges(50));
+        let diff = service.source_status()[0].last_diff.clone().unwrap().diff;
+        assert_eq!((diff.added, diff.removed, diff.unchanged), (10, 0, 40));
+    }
+
+    #[tokio::test]
+    async fn test_source_status_reports_last_parse() {
+        let temp_dir = tempdir().unwrap();
+        let service = IpLookupService::new(IpLookupServiceConfig {
+            data_dir: temp_dir.path().to_path_buf(),
+            check_updates: false,
+            update_interval_secs: 3600,
+            max_cache_age_secs: 86400,
+            snapshot_retention: 0,
+            archive_dir: temp_dir.path().join("archive"),
+            archive_retention: 0,
+            ipv6_aggregate_prefix: 128,
+            bloom_filter: true,
+            flat_snapshot: false,
+            offline: false,
+            overrides_file: None,
+            outbound_http: OutboundHttpSettings::default(),
+            tor_delisted_window_secs: 0,
+            feed_backoff_max_secs: 0,
+            enabled_categories: None,
+            sources: vec![IpRangeSource {
+                url: "https://example.com/vpn.txt".to_string(),
+                category: IpCategory::Vpn,
+                name: "vpn".to_string(),
+                enabled: true,
+                format: SourceFormat::Default,
+                max_delta_percent: None,
+                ip_version: IpVersion::V4,
+                mirrors: Vec::new(),
+            }],
+        });
+        // A fresh cached copy is loaded without fetching the URL
+        std::fs::write(temp_dir.path().join("vpns_v4.txt"), "# header\n10.0.0.0/8\nbogus\n192.0.2.1\n").unwrap();
+        assert!(service.source_status()[0].last_parse.is_none());
+
+        service.update_all_sources().await.unwrap();
+        let report = service.source_status()[0].last_parse.clone().unwrap();
+        assert_eq!((report.total_lines, report.parsed, report.skipped_comments, report.invalid), (4, 2, 1, 1));
+        assert_eq!(report.sample_errors[0].line, 3);
+        assert_eq!(service.tree().len(), (2, 0));
+    }
+
+    fn offline_config(data_dir: &std::path::Path, sources: Vec<IpRangeSource>) -> IpLookupServiceConfig {
+        IpLookupServiceConfig {
+            data_dir: data_dir.to_path_buf(),
+            check_updates: true,
+            update_interval_secs: 3600,
+            // Every cached copy counts as stale
+            max_cache_age_secs: 0,
+            snapshot_retention: 0,
+            archive_dir: data_dir.join("archive"),
+            archive_retention: 0,
+            ipv6_aggregate_prefix: 128,
+            bloom_filter: true,
+            flat_snapshot: false,
+            offline: true,
+            overrides_file: None,
+            outbound_http: OutboundHttpSettings::default(),
+            tor_delisted_window_secs: 0,
+            feed_backoff_max_secs: 0,
+            enabled_categories: None,
+            sources,
+        }
+    }
+
+    fn source(name: &str, category: IpCategory) -> IpRangeSource {
+        IpRangeSource {
+            // Unroutable, so a download attempt would fail the test
+            url: format!("https://example.invalid/{}.txt", name),
+            category,
+            name: name.to_string(),
+            enabled: true,
+            format: SourceFormat::Default,
+            max_delta_percent: None,
+            ip_version: IpVersion::V4,
+            mirrors: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delta_source_applies_changes_in_place() {
+        use crate::test_support::mock_proxy;
+
+        let temp_dir = tempdir().unwrap();
+        let delta_source = |url: String| IpRangeSource {
+            url,
+            format: SourceFormat::Delta,
+            ..source("internal", IpCategory::Vpn)
+        };
+        let mut config = offline_config(temp_dir.path(), vec![]);
+        config.offline = false;
+        config.snapshot_retention = 5;
+
+        // Without a cursor the feed sends its whole list, which builds the tree
+        let (addr, request) = mock_proxy("# cursor: c1\n+192.0.2.0/24\n+198.51.100.0/24\n").await;
+        config.sources = vec![delta_source(format!("http://{}/feed", addr))];
+        let mut service = IpLookupService::new(config.clone());
+        service.update_all_sources().await.unwrap();
+        assert!(request.await.unwrap().starts_with("GET /feed HTTP/1.1\r\n"));
+        assert_eq!(service.tree().lookup("192.0.2.1".parse().unwrap()), Some(IpCategory::Vpn));
+        assert_eq!(service.snapshots().unwrap().len(), 1);
+
+        // The next fetch asks for the changes since the cursor and applies
+        // them to the live tree without a rebuild, under any manual entries
+        let (addr, request) = mock_proxy("# cursor: c2\n-192.0.2.0/24\n+203.0.113.0/24\n").await;
+        service.config.sources = vec![delta_source(format!("http://{}/feed", addr))];
+        service.add_manual_range("192.0.2.0/24".parse().unwrap(), IpCategory::TorExitNode, None);
+        service.add_manual_range("203.0.113.0/24".parse().unwrap(), IpCategory::TorExitNode, None);
+        service.update_all_sources().await.unwrap();
+        assert!(request.await.unwrap().starts_with("GET /feed?since=c1 HTTP/1.1\r\n"));
+        assert_eq!(service.tree().lookup("192.0.2.1".parse().unwrap()), Some(IpCategory::TorExitNode));
+        assert_eq!(service.tree().lookup("203.0.113.1".parse().unwrap()), Some(IpCategory::TorExitNode));
+        service.remove_manual_range("192.0.2.0/24".parse().unwrap());
+        service.remove_manual_range("203.0.113.0/24".parse().unwrap());
+        assert_eq!(service.tree().lookup("192.0.2.1".parse().unwrap()), None);
+        assert_eq!(service.tree().lookup("203.0.113.1".parse().unwrap()), Some(IpCategory::Vpn));
+        assert_eq!(service.tree().lookup_match("198.51.100.1".parse().unwrap()).unwrap().source.as_deref(), Some("internal"));
+        assert_eq!(service.snapshots().unwrap().len(), 1);
+        assert_eq!(service.source_status()[0].accepted_count, Some(2));
+
+        // A restart resumes from the saved entries
+        config.offline = true;
+        let restarted = IpLookupService::new(config);
+        restarted.update_all_sources().await.unwrap();
+        assert_eq!(restarted.tree().len(), (2, 0));
+        assert_eq!(restarted.tree().lookup("203.0.113.1".parse().unwrap()), Some(IpCategory::Vpn));
+    }
+
+    #[tokio::test]
+    async fn test_rejected_download_keeps_previous_copy() {
+        use crate::test_support::mock_proxy;
+
+        let temp_dir = tempdir().unwrap();
+        let list = temp_dir.path().join("vpns_v4.txt");
+        std::fs::write(&list, "10.0.0.0/8\n").unwrap();
+        filetime::set_file_mtime(&list, filetime::FileTime::from_unix_time(0, 0)).unwrap();
+        // The upstream list was replaced by a notice
+        let (addr, request) = mock_proxy("# this list has moved\n").await;
+        let mut config = offline_config(
+            temp_dir.path(),
+            vec![IpRangeSource { url: format!("http://{}/vpn.txt", addr), ..source("vpn", IpCategory::Vpn) }],
+        );
+        config.offline = false;
+
+        let service = IpLookupService::new(config);
+        service.update_all_sources().await.unwrap();
+        request.await.unwrap();
+        assert_eq!(service.tree().lookup("10.1.2.3".parse().unwrap()), Some(IpCategory::Vpn));
+        assert_eq!(std::fs::read_to_string(&list).unwrap(), "10.0.0.0/8\n");
+    }
+
+    #[tokio::test]
+    async fn test_failing_source_url_falls_back_to_mirrors() {
+        use crate::test_support::{mock_proxy, mock_response};
+
+        let temp_dir = tempdir().unwrap();
+        let (unavailable, _) = mock_response("503 Service Unavailable", "").await;
+        let (moved, _) = mock_proxy("# this list has moved\n").await;
+        let (mirror, request) = mock_proxy("10.0.0.0/8\n").await;
+        let urls = [unavailable, moved, mirror].map(|addr| format!("http://{}/vpn.txt", addr));
+        let mut config = offline_config(
+            temp_dir.path(),
+            vec![IpRangeSource {
+                url: urls[0].clone(),
+                mirrors: urls[1..].to_vec(),
+                ..source("vpn", IpCategory::Vpn)
+            }],
+        );
+        config.offline = false;
+
+        let service = IpLookupService::new(config);
+        service.update_all_sources().await.unwrap();
+        request.await.unwrap();
+        assert_eq!(service.tree().lookup("10.1.2.3".parse().unwrap()), Some(IpCategory::Vpn));
+        assert_eq!(std::fs::read_to_string(temp_dir.path().join("vpns_v4.txt")).unwrap(), "10.0.0.0/8\n");
+
+        let fetch = service.source_status()[0].last_fetch.clone().unwrap();
+        assert_eq!(fetch.fetched_from.as_deref(), Some(urls[2].as_str()));
+        let tried: Vec<(&str, bool)> = fetch.attempts.iter().map(|a| (a.url.as_str(), a.error.is_some())).collect();
+        assert_eq!(tried, vec![(urls[0].as_str(), true), (urls[1].as_str(), true), (urls[2].as_str(), false)]);
+        assert!(fetch.attempts[0].error.as_ref().unwrap().contains("503"));
+        assert!(fetch.attempts[1].error.as_ref().unwrap().contains("No entries"));
+    }
+
+    #[tokio::test]
+    async fn test_file_url_source_is_read_on_every_update() {
+        let temp_dir = tempdir().unwrap();
+        let synced = temp_dir.path().join("synced-vpn.txt");
+        std::fs::write(&synced, "10.0.0.0/8\n").unwrap();
+        let url = Url::from_file_path(&synced).unwrap().to_string();
+        let mut config = offline_config(temp_dir.path().join("data").as_path(), vec![IpRangeSource { url, ..source("vpn", IpCategory::Vpn) }]);
+        config.offline = false;
+        config.max_cache_age_secs = 86400;
+
+        let service = IpLookupService::new(config);
+        service.update_all_sources().await.unwrap();
+        assert_eq!(service.tree().lookup("10.1.2.3".parse().unwrap()), Some(IpCategory::Vpn));
+        assert_eq!(service.source_status()[0].last_fetch.clone().unwrap().attempts.len(), 1);
+
+        // Changes made by the syncing tool are picked up despite the fresh copy
+        std::fs::write(&synced, "192.0.2.0/24\n").unwrap();
+        service.update_all_sources().await.unwrap();
+        assert_eq!(service.tree().lookup("10.1.2.3".parse().unwrap()), None);
+        assert_eq!(service.tree().lookup("192.0.2.1".parse().unwrap()), Some(IpCategory::Vpn));
+    }
+
+    #[test]
+    fn test_from_ranges() {
+        let temp_dir = tempdir().unwrap();
+        let ranges = vec![
+            IpRange::new("10.0.0.0/8", IpCategory::Vpn, "fixture", SourceFormat::Default),
+            IpRange::new("192.0.2.1/32", IpCategory::TorExitNode, "fixture", SourceFormat::Default),
+            IpRange::new("not a network", IpCategory::Vpn, "fixture", SourceFormat::Default),
+        ];
+        let service = IpLookupService::from_ranges(ranges, offline_config(temp_dir.path(), vec![]));
+
+        assert_eq!(service.tree().len(), (2, 0));
+        assert_eq!(service.tree().lookup("10.1.2.3".parse().unwrap()), Some(IpCategory::Vpn));
+        assert_eq!(service.tree().lookup("192.0.2.1".parse().unwrap()), Some(IpCategory::TorExitNode));
+        assert_eq!(service.tree().lookup("8.8.8.8".parse().unwrap()), None);
+        // Nothing was read from or written to the data dir
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_missing_categories_counts_entries_and_age() {
+        let temp_dir = tempdir().unwrap();
+        let sources = vec![
+            source("vpn", IpCategory::Vpn),
+            source("tor", IpCategory::TorExitNode),
+            IpRangeSource { enabled: false, ..source("socks5", IpCategory::ProxySocks5) },
+        ];
+        let ranges = vec![IpRange::new("10.0.0.0/8", IpCategory::Vpn, "vpn", SourceFormat::Default)];
+        let service = IpLookupService::from_ranges(ranges, offline_config(temp_dir.path(), sources.clone()));
+
+        // The disabled source's category is not expected to have data
+        assert_eq!(service.missing_categories(None), vec![IpCategory::TorExitNode]);
+        assert_eq!(service.missing_categories(Some(Duration::from_secs(60))), vec![IpCategory::TorExitNode]);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(
+            service.missing_categories(Some(Duration::from_millis(10))),
+            vec![IpCategory::Vpn, IpCategory::TorExitNode]
+        );
+
+        let empty = IpLookupService::from_ranges(Vec::new(), offline_config(temp_dir.path(), sources));
+        assert_eq!(empty.missing_categories(None), vec![IpCategory::Vpn, IpCategory::TorExitNode]);
+    }
+
+    #[tokio::test]
+    async fn test_offline_update_reads_local_files() {
+        let temp_dir = tempdir().unwrap();
+        let service = IpLookupService::new(offline_config(
+            temp_dir.path(),
+            vec![source("vpn", IpCategory::Vpn), source("tor", IpCategory::TorExitNode)],
+        ));
+        std::fs::write(temp_dir.path().join("vpns_v4.txt"), "10.0.0.0/8\n").unwrap();
+
+        // The stale VPN list is used as-is; the Tor list has no local copy
+        let err = service.update_all_sources().await.unwrap_err().to_string();
+        assert!(err.contains("Failed to update source tor"), "{}", err);
+        assert!(err.contains("offline mode and no local copy"), "{}", err);
+        assert_eq!(service.tree().lookup("10.1.2.3".parse().unwrap()), Some(IpCategory::Vpn));
+
+        std::fs::write(temp_dir.path().join("tor_exit_nodes_v4.txt"), "192.0.2.1\n").unwrap();
+        service.update_all_sources().await.unwrap();
+        assert_eq!(service.tree().lookup("192.0.2.1".parse().unwrap()), Some(IpCategory::TorExitNode));
+
+        // Only the overrides file is watched; the feeds are not read again
+        std::fs::remove_file(temp_dir.path().join("vpns_v4.txt")).unwrap();
+        let watch = service.start_background_updates();
+        tokio::task::yield_now().await;
+        assert!(!watch.is_finished());
+        assert!(service.failing_sources().is_empty());
+        watch.abort();
+    }
+
+    #[tokio::test]
+    async fn test_failed_source_does_not_block_other_feeds() {
+        let temp_dir = tempdir().unwrap();
+        let guarded = |name, category| IpRangeSource { max_delta_percent: Some(50.0), ..source(name, category) };
+        let service = IpLookupService::new(offline_config(
+            temp_dir.path(),
+            vec![guarded("vpn", IpCategory::Vpn), guarded("tor", IpCategory::TorExitNode)],
+        ));
+        let vpn_list = temp_dir.path().join("vpns_v4.txt");
+        let tor_list = temp_dir.path().join("tor_exit_nodes_v4.txt");
+        std::fs::write(&vpn_list, "10.0.0.0/8\n11.0.0.0/8\n12.0.0.0/8\n13.0.0.0/8\n").unwrap();
+        std::fs::write(&tor_list, "192.0.2.1\n").unwrap();
+        service.update_all_sources().await.unwrap();
+
+        // The Tor list goes missing while the VPN list grows within its delta
+        std::fs::remove_file(&tor_list).unwrap();
+        std::fs::write(&vpn_list, "10.0.0.0/8\n11.0.0.0/8\n12.0.0.0/8\n13.0.0.0/8\n14.0.0.0/8\n").unwrap();
+        let err = service.update_all_sources().await.unwrap_err().to_string();
+        assert!(err.contains("Failed to update source tor"), "{}", err);
+        assert!(!err.contains("Refusing tree update"), "{}", err);
+        assert_eq!(service.tree().lookup("14.1.2.3".parse().unwrap()), Some(IpCategory::Vpn));
+
+        // Once back, the Tor list is compared with its last accepted count
+        std::fs::write(&tor_list, "192.0.2.1\n").unwrap();
+        service.update_all_sources().await.unwrap();
+        assert_eq!(service.tree().lookup("192.0.2.1".parse().unwrap()), Some(IpCategory::TorExitNode));
+    }
+
+    #[tokio::test]
+    async fn test_rejected_update_is_not_archived() {
+        let temp_dir = tempdir().unwrap();
+        let service = IpLookupService::new(IpLookupServiceConfig {
+            archive_retention: 2,
+            ..offline_config(
+                temp_dir.path(),
+                vec![IpRangeSource { max_delta_percent: Some(50.0), ..source("vpn", IpCategory::Vpn) }],
+            )
+        });
+        let vpn_list = temp_dir.path().join("vpns_v4.txt");
+        std::fs::write(&vpn_list, "10.0.0.0/8\n11.0.0.0/8\n12.0.0.0/8\n13.0.0.0/8\n").unwrap();
+        service.update_all_sources().await.unwrap();
+
+        // Shrinks past the delta, so the tree and the archive keep the first version
+        std::fs::write(&vpn_list, "10.0.0.0/8\n").unwrap();
+        assert!(service.update_all_sources().await.is_err());
+        assert!(service.source_status()[0].last_diff.is_none());
+
+        std::fs::write(&vpn_list, "10.0.0.0/8\n11.0.0.0/8\n12.0.0.0/8\n13.0.0.0/8\n14.0.0.0/8\n").unwrap();
+        service.update_all_sources().await.unwrap();
+        let diff = service.source_status()[0].last_diff.clone().unwrap().diff;
+        assert_eq!((diff.added, diff.removed, diff.unchanged), (1, 0, 4));
+    }
+
+    #[tokio::test]
+    async fn test_update_reports_category_conflicts() {
+        let temp_dir = tempdir().unwrap();
+        let service = IpLookupService::new(offline_config(
+            temp_dir.path(),
+            vec![
+                source("vpn", IpCategory::Vpn),
+                source("socks5", IpCategory::ProxySocks5),
+                source("tor", IpCategory::TorExitNode),
+            ],
+        ));
+        assert!(service.overlap_report().is_none());
+        std::fs::write(temp_dir.path().join("vpns_v4.txt"), "10.0.0.0/24\n10.1.0.0/16\n192.0.2.0/24\n").unwrap();
+        std::fs::write(temp_dir.path().join("socks5_proxies_v4.txt"), "10.0.0.0/24\n10.1.2.0/24\n").unwrap();
+        std::fs::write(temp_dir.path().join("tor_exit_nodes_v4.txt"), "10.1.3.4\n10.1.4.5\n192.0.2.9\n").unwrap();
+        service.update_all_sources().await.unwrap();
+
+        let report = service.overlap_report().unwrap();
+        // The /24 listed by both, and the proxy and exits nested in VPN ranges
+        assert_eq!((report.identical, report.nested), (1, 4));
+        let label = |category, source: &str| OverlapLabel { category, source: Some(source.to_string()) };
+        let top: Vec<_> = report.top.iter().map(|prefix| (prefix.network.as_str(), prefix.conflicts)).collect();
+        assert_eq!(top, vec![("10.1.0.0/16", 3), ("10.0.0.0/24", 1), ("192.0.2.0/24", 1)]);
+        assert_eq!(
+            report.top[0].labels,
+            vec![
+                label(IpCategory::ProxySocks5, "socks5"),
+                label(IpCategory::TorExitNode, "tor"),
+                label(IpCategory::Vpn, "vpn"),
+            ]
+        );
+        assert_eq!(
+            report.top[1].labels,
+            vec![label(IpCategory::ProxySocks5, "socks5"), label(IpCategory::Vpn, "vpn")]
+        );
+        // Last writer still wins; the report changes nothing
+        assert_eq!(service.tree().lookup("10.0.0.1".parse().unwrap()), Some(IpCategory::ProxySocks5));
+    }
+
+    #[tokio::test]
+    async fn test_failing_source_backs_off_until_it_recovers() {
+        let temp_dir = tempdir().unwrap();
+        let config = IpLookupServiceConfig {
+            feed_backoff_max_secs: 86400,
+            ..offline_config(temp_dir.path(), vec![source("tor", IpCategory::TorExitNode)])
+        };
+        let service = IpLookupService::new(config.clone());
+        assert!(service.update_all_sources().await.is_err());
+        std::fs::write(temp_dir.path().join("tor_exit_nodes_v4.txt"), "192.0.2.1\n").unwrap();
+
+        // Not retried before the next scheduled update, so the copy is not read yet
+        service.update_all_sources().await.unwrap();
+        assert_eq!(service.tree().lookup("192.0.2.1".parse().unwrap()), None);
+        let failing = service.failing_sources();
+        assert_eq!(failing.len(), 1);
+        assert_eq!(failing[0].consecutive_failures, 1);
+        assert!(failing[0].last_error.contains("offline mode and no local copy"), "{}", failing[0].last_error);
+        assert_eq!(service.source_status()[0].failure.as_ref(), Some(&failing[0]));
+
+        // With no interval to wait out, the retry is due and clears the failure
+        let service = IpLookupService::new(IpLookupServiceConfig { update_interval_secs: 0, ..config });
+        std::fs::remove_file(temp_dir.path().join("tor_exit_nodes_v4.txt")).unwrap();
+        assert!(service.update_all_sources().await.is_err());
+        assert_eq!(service.failing_sources().len(), 1);
+        std::fs::write(temp_dir.path().join("tor_exit_nodes_v4.txt"), "192.0.2.1\n").unwrap();
+        service.update_all_sources().await.unwrap();
+        assert_eq!(service.tree().lookup("192.0.2.1".parse().unwrap()), Some(IpCategory::TorExitNode));
+        assert!(service.failing_sources().is_empty());
+        assert!(service.source_status()[0].failure.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_disabled_categories_are_not_loaded() {
+        let temp_dir = tempdir().unwrap();
+        std::fs::write(temp_dir.path().join("tor_exit_nodes_v4.txt"), "192.0.2.1\n").unwrap();
+        std::fs::write(temp_dir.path().join("overrides.txt"), "198.51.100.0/24 vpn\n198.51.100.7 tor\n").unwrap();
+        // No VPN list on disk: loading it would fail the update
+        let service = IpLookupService::new(IpLookupServiceConfig {
+            enabled_categories: Some(vec![IpCategory::TorExitNode]),
+            overrides_file: Some(temp_dir.path().join("overrides.txt")),
+            ..offline_config(
+                temp_dir.path(),
+                vec![source("vpn", IpCategory::Vpn), source("tor", IpCategory::TorExitNode)],
+            )
+        });
+
+        service.update_all_sources().await.unwrap();
+        service.reload_overrides();
+        assert_eq!(service.tree().lookup("192.0.2.1".parse().unwrap()), Some(IpCategory::TorExitNode));
+        assert_eq!(service.tree().lookup("198.51.100.7".parse().unwrap()), Some(IpCategory::TorExitNode));
+        // The VPN override is dropped too
+        assert_eq!(service.tree().lookup("198.51.100.1".parse().unwrap()), None);
+        assert!(service.failing_sources().is_empty());
+        assert!(!service.category_enabled(IpCategory::Vpn));
+    }
+
+    #[tokio::test]
+    async fn test_tor_entries_dropped_between_loads_are_delisted() {
+        let temp_dir = tempdir().unwrap();
+        let list = temp_dir.path().join("tor_exit_nodes_v4.txt");
+        let service = IpLookupService::new(IpLookupServiceConfig {
+            tor_delisted_window_secs: 3600,
+            ..offline_config(temp_dir.path(), vec![source("tor", IpCategory::TorExitNode)])
+        });
+
+        std::fs::write(&list, "192.0.2.1\n192.0.2.2\n").unwrap();
+        service.update_all_sources().await.unwrap();
+        assert!(!service.is_recently_delisted("192.0.2.1".parse().unwrap()));
+
+        std::fs::write(&list, "192.0.2.2\n").unwrap();
+        service.update_all_sources().await.unwrap();
+        assert!(service.is_recently_delisted("192.0.2.1".parse().unwrap()));
+        assert!(!service.is_recently_delisted("192.0.2.2".parse().unwrap()));
+        assert_eq!(service.tree().lookup("192.0.2.1".parse().unwrap()), None);
+    }
+
+    #[tokio::test]
+    async fn test_explain_attributes_every_match() {
+        let temp_dir = tempdir().unwrap();
+        let service = IpLookupService::new(offline_config(
+            temp_dir.path(),
+            vec![source("vpn", IpCategory::Vpn), source("tor", IpCategory::TorExitNode)],
+        ));
+        std::fs::write(temp_dir.path().join("vpns_v4.txt"), "10.0.0.0/8\n").unwrap();
+        std::fs::write(temp_dir.path().join("tor_exit_nodes_v4.txt"), "10.1.2.3\n").unwrap();
+        service.update_all_sources().await.unwrap();
+        let manual = service.add_manual_range("10.1.0.0/16".parse().unwrap(), IpCategory::ProxyHttp, None);
+
+        let explained = service.explain("10.1.2.3".parse().unwrap());
+        let found: Vec<_> = explained
+            .iter()
+            .map(|m| (m.network.as_deref().unwrap(), m.category, m.source.as_deref().unwrap()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("10.1.2.3/32", IpCategory::TorExitNode, "tor"),
+                ("10.1.0.0/16", IpCategory::ProxyHttp, MANUAL_SOURCE),
+                ("10.0.0.0/8", IpCategory::Vpn, "vpn"),
+            ]
+        );
+        let vpn_file = service.loader.last_modified(&temp_dir.path().join("vpns_v4.txt"));
+        assert!(vpn_file.is_some());
+        assert_eq!(explained[2].source_updated_at, vpn_file);
+        assert_eq!(explained[1].source_updated_at, Some(manual.added_at));
+
+        assert!(service.explain("192.0.2.1".parse().unwrap()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_overrides_take_precedence_and_reload() {
+        let temp_dir = tempdir().unwrap();
+        let overrides = temp_dir.path().join("overrides.txt");
+        let service = IpLookupService::new(IpLookupServiceConfig {
+            overrides_file: Some(overrides.clone()),
+            ..offline_config(temp_dir.path(), vec![source("vpn", IpCategory::Vpn)])
+        });
+        std::fs::write(temp_dir.path().join("vpns_v4.txt"), "10.0.0.0/8\n192.0.2.0/24\n192.0.2.128/25\n").unwrap();
+
+        // No overrides file yet
+        service.update_all_sources().await.unwrap();
+        assert!(!service.reload_overrides());
+        assert_eq!(service.tree().lookup("192.0.2.1".parse().unwrap()), Some(IpCategory::Vpn));
+        let mut updates = service.subscribe_updates();
+        updates.borrow_and_update();
+
+        // Offline, starting the background updates still loads and watches the file
+        std::fs::write(&overrides, "# local bad actors\n192.0.2.0/24 tor\n203.0.113.9 http_proxy\nnonsense\n").unwrap();
+        service.add_manual_range("192.0.2.0/24".parse().unwrap(), IpCategory::ProxyHttp, None);
+        let watch = service.start_background_updates();
+        assert!(updates.has_changed().unwrap());
+        assert!(!service.reload_overrides());
+
+        let tree = service.tree();
+        assert_eq!(tree.lookup("192.0.2.1".parse().unwrap()), Some(IpCategory::TorExitNode));
+        // Over a narrower feed entry too
+        assert_eq!(tree.lookup("192.0.2.200".parse().unwrap()), Some(IpCategory::TorExitNode));
+        assert_eq!(tree.lookup("203.0.113.9".parse().unwrap()), Some(IpCategory::ProxyHttp));
+        assert_eq!(tree.lookup("10.1.1.1".parse().unwrap()), Some(IpCategory::Vpn));
+        assert_eq!(service.explain("192.0.2.200".parse().unwrap())[0].source.as_deref(), Some(OVERRIDES_SOURCE));
+        assert_eq!(service.parse_reports.lock()[OVERRIDES_SOURCE].invalid, 1);
+
+        // Feed rebuilds keep the overrides
+        service.update_all_sources().await.unwrap();
+        assert_eq!(service.tree().lookup("192.0.2.200".parse().unwrap()), Some(IpCategory::TorExitNode));
+
+        // Removing the file drops the overrides without a rebuild
+        std::fs::remove_file(&overrides).unwrap();
+        assert!(service.reload_overrides());
+        assert_eq!(service.tree().lookup("192.0.2.1".parse().unwrap()), Some(IpCategory::ProxyHttp));
+        assert_eq!(service.tree().lookup("192.0.2.200".parse().unwrap()), Some(IpCategory::Vpn));
+        assert_eq!(service.tree().lookup("203.0.113.9".parse().unwrap()), None);
+        watch.abort();
+    }
+
+    #[tokio::test]
+    async fn test_flat_snapshot_serves_until_rebuilt() {
+        let temp_dir = tempdir().unwrap();
+        let config = IpLookupServiceConfig {
+            data_dir: temp_dir.path().to_path_buf(),
+            check_updates: false,
+            update_interval_secs: 3600,
+            max_cache_age_secs: 86400,
+            snapshot_retention: 0,
+            archive_dir: temp_dir.path().join("archive"),
+            archive_retention: 0,
+            ipv6_aggregate_prefix: 128,
+            bloom_filter: true,
+            flat_snapshot: true,
+            offline: false,
+            overrides_file: None,
+            outbound_http: OutboundHttpSettings::default(),
+            tor_delisted_window_secs: 0,
+            feed_backoff_max_secs: 0,
+            enabled_categories: None,
+            sources: vec![IpRangeSource {
+                url: "https://example.com/vpn.txt".to_string(),
+                category: IpCategory::Vpn,
+                name: "vpn".to_string(),
+                enabled: true,
+                format: SourceFormat::Default,
+                max_delta_percent: None,
+                ip_version: IpVersion::V4,
+                mirrors: Vec::new(),
+            }],
+        };
+        std::fs::write(temp_dir.path().join("vpns_v4.txt"), "10.0.0.0/8\n10.1.0.0/16\n").unwrap();
+
+        // Nothing to load before the first tree has been built
+        let first = IpLookupService::new(config.clone());
+        assert!(!first.load_flat_snapshot());
+        first.update_all_sources().await.unwrap();
+        assert!(temp_dir.path().join(FLAT_SNAPSHOT_FILE).exists());
+
+        // A restart answers from the snapshot before any feed is read
+        let restarted = IpLookupService::new(config.clone());
+        assert!(restarted.load_flat_snapshot());
+        assert!(restarted.tree().is_flat());
+        let found = restarted.tree().lookup_match("10.1.2.3".parse().unwrap()).unwrap();
+        assert_eq!((found.category, found.source.as_deref()), (IpCategory::Vpn, Some("vpn")));
+        assert_eq!(restarted.tree().len(), (2, 0));
+
+        restarted.update_all_sources().await.unwrap();
+        assert!(!restarted.tree().is_flat());
+        assert_eq!(restarted.tree().lookup("10.1.2.3".parse().unwrap()), Some(IpCategory::Vpn));
+
+        // Disabled, the snapshot is neither loaded nor required
+        let disabled = IpLookupService::new(IpLookupServiceConfig { flat_snapshot: false, ..config });
+        assert!(!disabled.load_flat_snapshot());
+    }
+
+    #[tokio::test]
+    async fn test_manual_ranges_survive_rebuilds() {
+        let temp_dir = tempdir().unwrap();
+        let service = IpLookupService::new(IpLookupServiceConfig {
+            data_dir: temp_dir.path().to_path_buf(),
+            check_updates: false,
+            update_interval_secs: 3600,
+            max_cache_age_secs: 86400,
+            snapshot_retention: 0,
+            archive_dir: temp_dir.path().join("archive"),
+            archive_retention: 0,
+            ipv6_aggregate_prefix: 128,
+            bloom_filter: true,
+            flat_snapshot: false,
+            offline: false,
+            overrides_file: None,
+            outbound_http: OutboundHttpSettings::default(),
+            tor_delisted_window_secs: 0,
+            feed_backoff_max_secs: 0,
+            enabled_categories: None,
+            sources: vec![IpRangeSource {
+                url: "https://example.com/vpn.txt".to_string(),
+                category: IpCategory::Vpn,
+                name: "vpn".to_string(),
+                enabled: true,
+                format: SourceFormat::Default,
+                max_delta_percent: None,
+                ip_version: IpVersion::V4,
+                mirrors: Vec::new(),
+            }],
+        });
+        std::fs::write(temp_dir.path().join("vpns_v4.txt"), "10.0.0.0/8\n192.0.2.0/24\n").unwrap();
+        service.update_all_sources().await.unwrap();
+        let source = |ip: &str| {
+            service
+                .tree()
+                .lookup_match(ip.parse().unwrap())
+                .map(|found| (found.category, found.source.as_deref().unwrap_or_default().to_string()))
+        };
+
+        // Goes live immediately, over the feed entry for the same network
+        service.add_manual_range("198.51.100.0/24".parse().unwrap(), IpCategory::TorExitNode, None);
+        service.add_manual_range("192.0.2.0/24".parse().unwrap(), IpCategory::ProxyHttp, None);
+        assert_eq!(source("198.51.100.7"), Some((IpCategory::TorExitNode, MANUAL_SOURCE.to_string())));
+        assert_eq!(source("192.0.2.1"), Some((IpCategory::ProxyHttp, MANUAL_SOURCE.to_string())));
+
+        // A scheduled rebuild keeps them
+        service.update_all_sources().await.unwrap();
+        assert_eq!(source("198.51.100.7"), Some((IpCategory::TorExitNode, MANUAL_SOURCE.to_string())));
+        assert_eq!(source("192.0.2.1"), Some((IpCategory::ProxyHttp, MANUAL_SOURCE.to_string())));
+        assert_eq!(service.manual_ranges().len(), 2);
+
+        // Deleting removes the manual entry and puts back the feed's right away
+        assert!(service.remove_manual_range("198.51.100.0/24".parse().unwrap()).is_some());
+        assert!(service.remove_manual_range("198.51.100.0/24".parse().unwrap()).is_none());
+        assert_eq!(source("198.51.100.7"), None);
+        service.remove_manual_range("192.0.2.0/24".parse().unwrap());
+        assert_eq!(source("192.0.2.1"), Some((IpCategory::Vpn, "vpn".to_string())));
+
+        // Expired entries leave the overlay and the live tree, restoring the feed's too
+        service.add_manual_range("203.0.113.0/24".parse().unwrap(), IpCategory::Vpn, Some(Duration::ZERO));
+        service.add_manual_range("10.0.0.0/8".parse().unwrap(), IpCategory::TorExitNode, Some(Duration::ZERO));
+        assert!(service.manual_ranges().is_empty());
+        assert_eq!(service.expire_manual_ranges().len(), 2);
+        assert_eq!(source("203.0.113.1"), None);
+        assert_eq!(source("10.1.2.3"), Some((IpCategory::Vpn, "vpn".to_string())));
+    }
+
+    #[test]
+    fn test_aggregate_v6_host() {
+        let host: IpNetwork = "2001:db8:1:2:abcd::1/128".parse().unwrap();
+
+        let widened = aggregate_v6_host(host, IpCategory::TorExitNode, 64);
+        assert_eq!(widened, "2001:db8:1:2::/64".parse::<IpNetwork>().unwrap());
+        assert_eq!(aggregate_v6_host(host, IpCategory::ProxySocks5, 64), widened);
+
+        // Default prefix, VPN ranges, shorter prefixes and IPv4 are unchanged
+        assert_eq!(aggregate_v6_host(host, IpCategory::TorExitNode, 128), host);
+        assert_eq!(aggregate_v6_host(host, IpCategory::Vpn, 64), host);
+        let range: IpNetwork = "2001:db8::/48".parse().unwrap();
+        assert_eq!(aggregate_v6_host(range, IpCategory::ProxyHttp, 64), range);
+        let v4: IpNetwork = "192.0.2.1/32".parse().unwrap();
+        assert_eq!(aggregate_v6_host(v4, IpCategory::TorExitNode, 64), v4);
+    }
+
+    #[test]
+    fn test_exceeds_delta() {
+        assert!(!exceeds_delta(0, 500_000, 50.0));
+        assert!(!exceeds_delta(1000, 1400, 50.0));
+        assert!(!exceeds_delta(1000, 500, 50.0));
+        assert!(exceeds_delta(1000, 499, 50.0));
+        assert!(exceeds_delta(500_000, 3, 90.0));
+        assert!(exceeds_delta(100, 1000, 90.0));
+    }
+
+    #[tokio::test]
+    async fn test_guarded_update_rejects_collapsed_feed() {
+        let temp_dir = tempdir().unwrap();
+        let source = IpRangeSource {
+            url: "https://example.com/vpn.txt".to_string(),
+            category: IpCategory::Vpn,
+            name: "vpn".to_string(),
+            enabled: true,
+            format: SourceFormat::Default,
+            max_delta_percent: Some(50.0),
+            ip_version: IpVersion::V4,
+            mirrors: Vec::new(),
+        };
+        let service = IpLookupService::new(IpLookupServiceConfig {
+            data_dir: temp_dir.path().to_path_buf(),
+            check_updates: false,
+            update_interval_secs: 3600,
+            max_cache_age_secs: 86400,
+            snapshot_retention: 0,
+            archive_dir: temp_dir.path().join("archive"),
+            archive_retention: 0,
+            ipv6_aggregate_prefix: 128,
+            bloom_filter: true,
+            flat_snapshot: false,
+            offline: false,
+            overrides_file: None,
+            outbound_http: OutboundHttpSettings::default(),
+            tor_delisted_window_secs: 0,
+            feed_backoff_max_secs: 0,
+            enabled_categories: None,
+            sources: vec![source],
+        });
+
+        let counts = |n| HashMap::from([("vpn".to_string(), n)]);
+
+        service.guarded_update(counts(100), &vpn_ranges(100)).await.unwrap();
+        assert_eq!(service.tree().total_len(), 100);
+
+        // A feed that collapses to a handful of entries keeps the old tree
+        assert!(service.guarded_update(counts(3), &vpn_ranges(3)).await.is_err());
+        assert_eq!(service.tree().total_len(), 100);
+
+        // Changes within the threshold are applied
+        service.guarded_update(counts(120), &vpn_ranges(120)).await.unwrap();
+        assert_eq!(service.tree().total_len(), 120);
+    }
 }
@@ -0,0 +1,227 @@
+//! Retained radix tree snapshots for rolling back bad feed updates.
+//!
+//! Each snapshot is the serialized tree (`tree-<unix millis>.json`) plus a
+//! `.sha256` sidecar holding its checksum. Snapshots are listed newest first,
+//! so index 0 is the tree installed by the most recent update.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::ip_lookup::tree::RadixTree;
+use crate::ip_lookup::types::{IpRangeError, Result};
+use crate::utils::file_ops::{atomic_replace, file_sha256};
+
+const SNAPSHOT_PREFIX: &str = "tree-";
+const SNAPSHOT_EXTENSION: &str = "json";
+const CHECKSUM_EXTENSION: &str = "sha256";
+
+/// Describes a retained snapshot
+#[derive(Debug, Clone, Serialize)]
+pub struct SnapshotInfo {
+    /// Position in the newest-first list; pass this to rollback
+    pub index: usize,
+    pub created_at: DateTime<Utc>,
+    /// SHA256 of the serialized tree
+    pub checksum: String,
+    pub size_bytes: u64,
+    #[serde(skip)]
+    pub path: PathBuf,
+}
+
+/// Keeps the last `retain` serialized trees in a directory
+#[derive(Debug, Clone)]
+pub struct SnapshotStore {
+    dir: PathBuf,
+    retain: usize,
+}
+
+impl SnapshotStore {
+    /// Create a store in `dir` that keeps at most `retain` snapshots
+    pub fn new(dir: impl Into<PathBuf>, retain: usize) -> Self {
+        Self {
+            dir: dir.into(),
+            retain,
+        }
+    }
+
+    /// Serialize `tree` as the newest snapshot and prune old ones
+    pub fn save(&self, tree: &RadixTree) -> Result<SnapshotInfo> {
+        fs::create_dir_all(&self.dir)?;
+
+        let created_at = Utc::now();
+        let path = self.dir.join(format!(
+            "{}{}.{}",
+            SNAPSHOT_PREFIX,
+            created_at.timestamp_millis(),
+            SNAPSHOT_EXTENSION
+        ));
+
+        // Write to a temporary file first so a crash never leaves a partial snapshot
+        let tmp_path = path.with_extension("tmp");
+        tree.save_to_file(&tmp_path)?;
+        let checksum = file_sha256(&tmp_path)?;
+        atomic_replace(&tmp_path, &path)?;
+        fs::write(checksum_path(&path), &checksum)?;
+
+        let size_bytes = fs::metadata(&path)?.len();
+        info!("Saved tree snapshot {} ({} bytes)", path.display(), size_bytes);
+
+        self.prune()?;
+
+        Ok(SnapshotInfo {
+            index: 0,
+            created_at,
+            checksum,
+            size_bytes,
+            path,
+        })
+    }
+
+    /// List retained snapshots, newest first
+    pub fn list(&self) -> Result<Vec<SnapshotInfo>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut snapshots = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            let Some(created_at) = snapshot_timestamp(&path) else {
+                continue;
+            };
+            let checksum = fs::read_to_string(checksum_path(&path))
+                .map(|s| s.trim().to_string())
+                .unwrap_or_default();
+            let size_bytes = fs::metadata(&path)?.len();
+            snapshots.push(SnapshotInfo {
+                index: 0,
+                created_at,
+                checksum,
+                size_bytes,
+                path,
+            });
+        }
+
+        snapshots.sort_by_key(|s| std::cmp::Reverse(s.created_at));
+        for (index, snapshot) in snapshots.iter_mut().enumerate() {
+            snapshot.index = index;
+        }
+        Ok(snapshots)
+    }
+
+    /// Load the snapshot at `index`, verifying its checksum first
+    pub fn load(&self, index: usize) -> Result<(RadixTree, SnapshotInfo)> {
+        let snapshot = self
+            .list()?
+            .into_iter()
+            .nth(index)
+            .ok_or(IpRangeError::SnapshotNotFound(index))?;
+
+        let actual = file_sha256(&snapshot.path)?;
+        if snapshot.checksum.is_empty() || actual != snapshot.checksum {
+            return Err(IpRangeError::ChecksumMismatch(format!(
+                "snapshot {} has checksum {} but {} was recorded",
+                snapshot.path.display(),
+                actual,
+                snapshot.checksum
+            )));
+        }
+
+        let tree = RadixTree::load_from_file(&snapshot.path)?;
+        Ok((tree, snapshot))
+    }
+
+    /// Delete snapshots beyond the retention limit
+    fn prune(&self) -> Result<()> {
+        for snapshot in self.list()?.into_iter().skip(self.retain) {
+            if let Err(e) = fs::remove_file(&snapshot.path) {
+                warn!("Failed to remove old snapshot {}: {}", snapshot.path.display(), e);
+                continue;
+            }
+            let _ = fs::remove_file(checksum_path(&snapshot.path));
+        }
+        Ok(())
+    }
+}
+
+fn checksum_path(path: &Path) -> PathBuf {
+    path.with_extension(format!("{}.{}", SNAPSHOT_EXTENSION, CHECKSUM_EXTENSION))
+}
+
+/// Parse the creation time from a `tree-<millis>.json` file name
+fn snapshot_timestamp(path: &Path) -> Option<DateTime<Utc>> {
+    if path.extension()? != SNAPSHOT_EXTENSION {
+        return None;
+    }
+    let millis = path
+        .file_stem()?
+        .to_str()?
+        .strip_prefix(SNAPSHOT_PREFIX)?
+        .parse::<i64>()
+        .ok()?;
+    Utc.timestamp_millis_opt(millis).single()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ip_lookup::IpCategory;
+    use ip_network::IpNetwork;
+    use tempfile::tempdir;
+
+    fn tree_with(network: &str) -> RadixTree {
+        let mut tree = RadixTree::new();
+        tree.insert(network.parse::<IpNetwork>().unwrap(), IpCategory::Vpn);
+        tree
+    }
+
+    #[test]
+    fn test_save_list_and_prune() {
+        let dir = tempdir().unwrap();
+        let store = SnapshotStore::new(dir.path(), 2);
+
+        for network in ["10.0.0.0/8", "172.16.0.0/12", "192.168.0.0/16"] {
+            store.save(&tree_with(network)).unwrap();
+            // Snapshot names have millisecond resolution
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+
+        let snapshots = store.list().unwrap();
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(snapshots[0].index, 0);
+        assert!(snapshots[0].created_at > snapshots[1].created_at);
+
+        // Newest first: index 1 is the second tree, the first was pruned
+        let (tree, info) = store.load(1).unwrap();
+        assert_eq!(info.index, 1);
+        assert_eq!(tree.lookup("172.16.1.1".parse().unwrap()), Some(IpCategory::Vpn));
+        assert_eq!(tree.lookup("10.1.1.1".parse().unwrap()), None);
+
+        assert!(matches!(store.load(2), Err(IpRangeError::SnapshotNotFound(2))));
+    }
+
+    #[test]
+    fn test_truncated_snapshot_is_rejected() {
+        let dir = tempdir().unwrap();
+        let store = SnapshotStore::new(dir.path(), 3);
+        let info = store.save(&tree_with("10.0.0.0/8")).unwrap();
+
+        let data = fs::read(&info.path).unwrap();
+        fs::write(&info.path, &data[..data.len() / 2]).unwrap();
+
+        assert!(matches!(store.load(0), Err(IpRangeError::ChecksumMismatch(_))));
+    }
+
+    #[test]
+    fn test_list_missing_dir() {
+        let dir = tempdir().unwrap();
+        let store = SnapshotStore::new(dir.path().join("missing"), 3);
+        assert!(store.list().unwrap().is_empty());
+    }
+}
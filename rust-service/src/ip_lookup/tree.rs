@@ -1,26 +1,84 @@
 use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use parking_lot::RwLock;
 use ip_network::IpNetwork;
 use ip_network_table::IpNetworkTable;
 use serde::{Serialize, Deserialize, Serializer, Deserializer};
 use serde::ser::SerializeStruct;
-use tracing::{debug, error, info};
+use tracing::error;
 use std::fmt;
-use crate::ip_lookup::types::{IpCategory, IpRange, Result, IpRangeError};
+use crate::ip_lookup::bloom::PrefixBloom;
+use crate::ip_lookup::flat::{self, FlatContents, FlatEntry, FlatTree};
+use crate::ip_lookup::overlap::{OverlapCollector, OverlapLabel};
+use crate::ip_lookup::types::{IpCategory, Result};
 use std::collections::HashMap;
 use std::path::{Path};
 use std::fs;
 
+/// Index into [`RadixTree`]'s source names, so entries don't each hold a string
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SourceId(u16);
+
+/// What the tree stores per network
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Entry {
+    category: IpCategory,
+    /// The feed the network came from; `None` for ranges inserted without one
+    source: Option<SourceId>,
+}
+
+impl From<Entry> for FlatEntry {
+    fn from(entry: Entry) -> Self {
+        Self {
+            category: entry.category,
+            source: entry.source.map(|id| id.0),
+        }
+    }
+}
+
+impl From<FlatEntry> for Entry {
+    fn from(entry: FlatEntry) -> Self {
+        Self {
+            category: entry.category,
+            source: entry.source.map(SourceId),
+        }
+    }
+}
+
+/// A lookup hit with the feed that flagged the address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeMatch {
+    pub category: IpCategory,
+    pub source: Option<Arc<str>>,
+}
+
+/// A network containing a looked-up address, with the feed it came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkMatch {
+    /// `None` for a match from a flat snapshot, which keeps address
+    /// intervals rather than networks
+    pub network: Option<IpNetwork>,
+    pub category: IpCategory,
+    pub source: Option<Arc<str>>,
+}
+
 /// A radix tree for efficient IP address lookups.
 /// 
 /// This structure uses separate trees for IPv4 and IPv6 addresses to optimize
 /// memory usage and lookup performance.
 pub struct RadixTree {
-    v4_table: IpNetworkTable<IpCategory>,
-    v6_table: IpNetworkTable<IpCategory>,
+    v4_table: IpNetworkTable<Entry>,
+    v6_table: IpNetworkTable<Entry>,
+    /// Feed names, indexed by [`SourceId`]
+    sources: Vec<Arc<str>>,
     metadata: HashMap<String, String>,
-    stats: LookupStats,
+    counters: LookupCounters,
+    last_updated: Option<chrono::DateTime<chrono::Utc>>,
+    /// IPv4 pre-check that lets most clean addresses skip the tree walk
+    prefilter: Option<PrefixBloom>,
+    /// Mapped snapshot answering for the feeds until a tree is built; see [`flat`]
+    flat: Option<FlatTree>,
 }
 
 // Implement Debug manually for RadixTree
@@ -30,7 +88,9 @@ impl fmt::Debug for RadixTree {
             .field("v4_entries", &self.v4_table.iter().count())
             .field("v6_entries", &self.v6_table.iter().count())
             .field("metadata", &self.metadata)
-            .field("stats", &self.stats)
+            .field("stats", &self.stats())
+            .field("prefilter", &self.prefilter.is_some())
+            .field("flat", &self.flat.is_some())
             .finish()
     }
 }
@@ -38,12 +98,7 @@ impl fmt::Debug for RadixTree {
 // Implement Default manually for RadixTree
 impl Default for RadixTree {
     fn default() -> Self {
-        Self {
-            v4_table: IpNetworkTable::new(),
-            v6_table: IpNetworkTable::new(),
-            metadata: HashMap::new(),
-            stats: LookupStats::default(),
-        }
+        Self::new()
     }
 }
 
@@ -53,22 +108,24 @@ impl Serialize for RadixTree {
     where
         S: Serializer,
     {
-        // Convert IpNetworkTable to a serializable format (Vec of (network, category))
-        let v4_entries: Vec<(String, IpCategory)> = self.v4_table
+        // Convert IpNetworkTable to a serializable format (Vec of (network, category, source index))
+        let v4_entries: Vec<(String, IpCategory, Option<u16>)> = self.v4_table
             .iter()
-            .map(|(net, &cat)| (net.to_string(), cat))
+            .map(|(net, entry)| (net.to_string(), entry.category, entry.source.map(|id| id.0)))
             .collect();
             
-        let v6_entries: Vec<(String, IpCategory)> = self.v6_table
+        let v6_entries: Vec<(String, IpCategory, Option<u16>)> = self.v6_table
             .iter()
-            .map(|(net, &cat)| (net.to_string(), cat))
+            .map(|(net, entry)| (net.to_string(), entry.category, entry.source.map(|id| id.0)))
             .collect();
+        let sources: Vec<&str> = self.sources.iter().map(|name| &**name).collect();
 
-        let mut state = serializer.serialize_struct("RadixTree", 4)?;
+        let mut state = serializer.serialize_struct("RadixTree", 5)?;
         state.serialize_field("v4_entries", &v4_entries)?;
         state.serialize_field("v6_entries", &v6_entries)?;
+        state.serialize_field("sources", &sources)?;
         state.serialize_field("metadata", &self.metadata)?;
-        state.serialize_field("stats", &self.stats)?;
+        state.serialize_field("stats", &self.stats())?;
         state.end()
     }
 }
@@ -79,31 +136,57 @@ impl<'de> Deserialize<'de> for RadixTree {
     where
         D: Deserializer<'de>,
     {
+        /// Snapshots written before source attribution have no source index
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum EntryData {
+            Attributed(String, IpCategory, Option<u16>),
+            Legacy(String, IpCategory),
+        }
+
         #[derive(Deserialize)]
         struct RadixTreeData {
-            v4_entries: Vec<(String, IpCategory)>,
-            v6_entries: Vec<(String, IpCategory)>,
+            v4_entries: Vec<EntryData>,
+            v6_entries: Vec<EntryData>,
+            #[serde(default)]
+            sources: Vec<String>,
             metadata: HashMap<String, String>,
             stats: LookupStats,
         }
 
         let data = RadixTreeData::deserialize(deserializer)?;
-        let mut tree = RadixTree::default();
+        let mut tree = RadixTree {
+            sources: data.sources.into_iter().map(Arc::from).collect(),
+            ..RadixTree::default()
+        };
+        let source_count = tree.sources.len();
+        let entry = |data: EntryData| -> std::result::Result<(IpNetwork, Entry), D::Error> {
+            let (net_str, category, source) = match data {
+                EntryData::Attributed(net_str, category, source) => (net_str, category, source),
+                EntryData::Legacy(net_str, category) => (net_str, category, None),
+            };
+            let net: IpNetwork = net_str.parse().map_err(serde::de::Error::custom)?;
+            if source.is_some_and(|id| usize::from(id) >= source_count) {
+                return Err(serde::de::Error::custom(format!("unknown source index for {}", net_str)));
+            }
+            Ok((net, Entry { category, source: source.map(SourceId) }))
+        };
         
         // Rebuild the v4 table
-        for (net_str, cat) in data.v4_entries {
-            let net: IpNetwork = net_str.parse().map_err(serde::de::Error::custom)?;
-            tree.v4_table.insert(net, cat);
+        for data in data.v4_entries {
+            let (net, entry) = entry(data)?;
+            tree.v4_table.insert(net, entry);
         }
         
         // Rebuild the v6 table
-        for (net_str, cat) in data.v6_entries {
-            let net: IpNetwork = net_str.parse().map_err(serde::de::Error::custom)?;
-            tree.v6_table.insert(net, cat);
+        for data in data.v6_entries {
+            let (net, entry) = entry(data)?;
+            tree.v6_table.insert(net, entry);
         }
         
         tree.metadata = data.metadata;
-        tree.stats = data.stats;
+        tree.counters = LookupCounters::from(&data.stats);
+        tree.last_updated = data.stats.last_updated;
         
         Ok(tree)
     }
@@ -115,31 +198,128 @@ pub struct LookupStats {
     pub total_lookups: u64,
     pub hits: u64,
     pub misses: u64,
+    /// Misses answered by the prefilter without walking the tree
+    #[serde(default)]
+    pub filtered: u64,
     pub last_updated: Option<chrono::DateTime<chrono::Utc>>,
 }
 
+/// Lookup counters, bumped under a shared lock
+#[derive(Debug, Default)]
+struct LookupCounters {
+    total_lookups: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    filtered: AtomicU64,
+}
+
+impl LookupCounters {
+    fn record(&self, hit: bool) {
+        self.total_lookups.fetch_add(1, Ordering::Relaxed);
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl From<&LookupStats> for LookupCounters {
+    fn from(stats: &LookupStats) -> Self {
+        Self {
+            total_lookups: AtomicU64::new(stats.total_lookups),
+            hits: AtomicU64::new(stats.hits),
+            misses: AtomicU64::new(stats.misses),
+            filtered: AtomicU64::new(stats.filtered),
+        }
+    }
+}
+
 impl RadixTree {
     /// Create a new, empty RadixTree
     pub fn new() -> Self {
         Self {
             v4_table: IpNetworkTable::new(),
             v6_table: IpNetworkTable::new(),
+            sources: Vec::new(),
             metadata: HashMap::new(),
-            stats: LookupStats::default(),
+            counters: LookupCounters::default(),
+            last_updated: None,
+            prefilter: None,
+            flat: None,
         }
     }
 
+    /// A tree that answers from a mapped flat snapshot. Networks inserted
+    /// later, such as manual ranges, take precedence over the snapshot.
+    pub fn from_flat(flat: FlatTree) -> Self {
+        let mut tree = Self::new();
+        tree.sources = flat.sources().iter().map(|source| Arc::from(source.as_str())).collect();
+        tree.last_updated = Some(flat.written_at());
+        tree.flat = Some(flat);
+        tree
+    }
+
+    /// Whether lookups are still served from a flat snapshot
+    pub fn is_flat(&self) -> bool {
+        self.flat.is_some()
+    }
+
+    /// Build the IPv4 prefilter over the current entries. Later inserts are
+    /// added to it; removals leave it conservative.
+    pub fn build_prefilter(&mut self) {
+        let networks = self.v4_table.iter_ipv4().map(|(network, _)| network);
+        self.prefilter = Some(PrefixBloom::from_networks(networks));
+    }
+
     /// Insert an IP network with its category into the tree
     /// 
     /// Returns the previous category if the network was already in the tree, or None if it was a new entry.
+    #[cfg(test)]
     pub fn insert(&mut self, network: IpNetwork, category: IpCategory) -> Option<IpCategory> {
+        self.insert_entry(network, Entry { category, source: None }).map(|previous| previous.category)
+    }
+
+    /// Insert an IP network flagged by the feed named `source`, which
+    /// [`RadixTree::lookup_match`] reports back
+    pub fn insert_from(&mut self, network: IpNetwork, category: IpCategory, source: &str) -> Option<IpCategory> {
+        self.replace_from(network, category, source).map(|previous| previous.category)
+    }
+
+    /// Like [`RadixTree::insert_from`], returning the label of the entry the
+    /// new one replaced
+    pub fn replace_from(&mut self, network: IpNetwork, category: IpCategory, source: &str) -> Option<OverlapLabel> {
+        let source = self.source_id(source);
+        let previous = self.insert_entry(network, Entry { category, source })?;
+        Some(self.label(previous.into()))
+    }
+
+    fn label(&self, entry: FlatEntry) -> OverlapLabel {
+        OverlapLabel {
+            category: entry.category,
+            source: entry.source.map(|id| self.sources[usize::from(id)].to_string()),
+        }
+    }
+
+    /// The id of `name`, added on first use. Past `u16::MAX` names the
+    /// entry is stored unattributed rather than failing the load.
+    fn source_id(&mut self, name: &str) -> Option<SourceId> {
+        if let Some(index) = self.sources.iter().position(|source| &**source == name) {
+            return Some(SourceId(index as u16));
+        }
+        let id = u16::try_from(self.sources.len()).ok()?;
+        self.sources.push(Arc::from(name));
+        Some(SourceId(id))
+    }
+
+    fn insert_entry(&mut self, network: IpNetwork, entry: Entry) -> Option<Entry> {
         //debug!("Attempting to insert network: {}", network);
         
         let result = match network {
             IpNetwork::V4(net) => {
                 //debug!("Inserting IPv4 network: {}/{} - Category: {:?}", 
                      //net.network_address(), net.netmask(), category);
-                self.v4_table.insert(net, category)
+                if let Some(prefilter) = &mut self.prefilter {
+                    prefilter.insert(net);
+                }
+                self.v4_table.insert(net, entry)
             },
             IpNetwork::V6(net) => {
                 let netmask = net.netmask();
@@ -163,12 +343,12 @@ impl RadixTree {
                     }
                 }
                 
-                let result = self.v6_table.insert(net, category);
+                let result = self.v6_table.insert(net, entry);
                 
                 // Verify the insertion
                 let verify = self.v6_table.longest_match(network_addr);
                 match verify {
-                    Some((found_net, found_cat)) => {
+                    Some((found_net, _)) => {
                         if found_net == net {
                             //debug!("Successfully verified IPv6 network insertion");
                         } else {
@@ -185,42 +365,127 @@ impl RadixTree {
             },
         };
         
-        let (v4_count, v6_count) = self.len();
-        //debug!(
-        //    "Current tree size - IPv4: {:?}, IPv6: {:?}, Total: {:?}",
-        //    v4_count, v6_count, v4_count + v6_count
-        //);
-        
         result
     }
 
     /// Remove an IP network from the tree
     pub fn remove(&mut self, network: IpNetwork) -> Option<IpCategory> {
-        match network {
+        let removed = match network {
             IpNetwork::V4(net) => self.v4_table.remove(net),
             IpNetwork::V6(net) => self.v6_table.remove(net),
+        };
+        removed.map(|entry| entry.category)
+    }
+
+    /// Remove `network` if its entry came from `source`, leaving other
+    /// sources' entries for the same network in place
+    pub fn remove_from(&mut self, network: IpNetwork, source: &str) -> Option<IpCategory> {
+        let entry = match network {
+            IpNetwork::V4(net) => self.v4_table.exact_match(net).copied(),
+            IpNetwork::V6(net) => self.v6_table.exact_match(net).copied(),
+        }?;
+        let from_source = entry.source.is_some_and(|id| &*self.sources[usize::from(id.0)] == source);
+        if !from_source {
+            return None;
         }
+        self.remove(network)
     }
 
     /// Check if an IP address is in the tree and return its category if found
     pub fn lookup(&self, ip: IpAddr) -> Option<IpCategory> {
-        let result = match ip {
-            IpAddr::V4(ip) => self.v4_table.longest_match(ip).map(|(_, &t)| t),
-            IpAddr::V6(ip) => self.v6_table.longest_match(ip).map(|(_, &t)| t),
+        self.lookup_entry(ip).map(|entry| entry.category)
+    }
+
+    /// Like [`RadixTree::lookup`], with the feed that flagged the address
+    pub fn lookup_match(&self, ip: IpAddr) -> Option<RangeMatch> {
+        self.lookup_entry(ip).map(|entry| RangeMatch {
+            category: entry.category,
+            source: entry.source.map(|id| self.sources[usize::from(id.0)].clone()),
+        })
+    }
+
+    fn lookup_entry(&self, ip: IpAddr) -> Option<Entry> {
+        if let Some(flat) = &self.flat {
+            return self.table_entry(ip).or_else(|| flat.lookup(ip).map(Entry::from));
+        }
+        self.table_entry(ip)
+    }
+
+    fn table_entry(&self, ip: IpAddr) -> Option<Entry> {
+        match ip {
+            IpAddr::V4(ip) => {
+                if let Some(prefilter) = &self.prefilter {
+                    if !prefilter.may_contain(ip) {
+                        self.counters.filtered.fetch_add(1, Ordering::Relaxed);
+                        return None;
+                    }
+                }
+                self.v4_table.longest_match(ip).map(|(_, &entry)| entry)
+            }
+            IpAddr::V6(ip) => self.v6_table.longest_match(ip).map(|(_, &entry)| entry),
+        }
+    }
+
+    /// Whether a `category` network overlaps `network`, either containing it or
+    /// lying inside it. Walks every entry of the address family.
+    pub fn overlaps(&self, network: IpNetwork, category: IpCategory) -> bool {
+        if let Some(flat) = &self.flat {
+            let (start, end) = flat::network_bounds(network);
+            if flat.overlaps(start, end, category) {
+                return true;
+            }
+        }
+        match network {
+            IpNetwork::V4(net) => self.v4_table.iter_ipv4().any(|(entry_net, entry)| {
+                entry.category == category
+                    && (entry_net.contains(net.network_address()) || net.contains(entry_net.network_address()))
+            }),
+            IpNetwork::V6(net) => self.v6_table.iter_ipv6().any(|(entry_net, entry)| {
+                entry.category == category
+                    && (entry_net.contains(net.network_address()) || net.contains(entry_net.network_address()))
+            }),
+        }
+    }
+
+    /// Categories of every network containing `ip`, not just the most specific one
+    pub fn lookup_all(&self, ip: IpAddr) -> Vec<IpCategory> {
+        let matches: Vec<IpCategory> = match ip {
+            IpAddr::V4(ip) if self.prefilter.as_ref().is_some_and(|p| !p.may_contain(ip)) => Vec::new(),
+            IpAddr::V4(ip) => self.v4_table.matches_ipv4(ip).map(|(_, entry)| entry.category).collect(),
+            IpAddr::V6(ip) => self.v6_table.matches_ipv6(ip).map(|(_, entry)| entry.category).collect(),
         };
+        // A flat snapshot only knows the most specific match
+        let flat_match = self.flat.as_ref().and_then(|flat| flat.lookup(ip)).map(|entry| entry.category);
+        let mut categories = Vec::with_capacity(matches.len() + 1);
+        for category in matches.into_iter().chain(flat_match) {
+            if !categories.contains(&category) {
+                categories.push(category);
+            }
+        }
+        categories
+    }
 
-        // Update stats
-        let mut stats = self.stats.clone();
-        stats.total_lookups += 1;
-        if result.is_some() {
-            stats.hits += 1;
-        } else {
-            stats.misses += 1;
+    /// Every network containing `ip`, most specific first, so the first is
+    /// the one [`RadixTree::lookup_match`] reports
+    pub fn lookup_networks(&self, ip: IpAddr) -> Vec<NetworkMatch> {
+        let mut entries: Vec<(IpNetwork, Entry)> = match ip {
+            IpAddr::V4(ip) if self.prefilter.as_ref().is_some_and(|p| !p.may_contain(ip)) => Vec::new(),
+            IpAddr::V4(ip) => self.v4_table.matches_ipv4(ip).map(|(net, &entry)| (net.into(), entry)).collect(),
+            IpAddr::V6(ip) => self.v6_table.matches_ipv6(ip).map(|(net, &entry)| (net.into(), entry)).collect(),
+        };
+        entries.sort_by_key(|(network, _)| std::cmp::Reverse(network.netmask()));
+        let source = |entry: Entry| entry.source.map(|id| self.sources[usize::from(id.0)].clone());
+        let mut matches: Vec<NetworkMatch> = entries
+            .into_iter()
+            .map(|(network, entry)| NetworkMatch { network: Some(network), category: entry.category, source: source(entry) })
+            .collect();
+        // The snapshot answers only where no built network does
+        if matches.is_empty() {
+            if let Some(entry) = self.flat.as_ref().and_then(|flat| flat.lookup(ip)).map(Entry::from) {
+                matches.push(NetworkMatch { network: None, category: entry.category, source: source(entry) });
+            }
         }
-        // Note: We're not updating stats here since we can't mutate self in a shared reference
-        // The actual stats update happens in the SharedRadixTree wrapper
-        
-        result
+        matches
     }
 
     /// Get the number of networks in the tree as a tuple (v4_count, v6_count)
@@ -230,138 +495,41 @@ impl RadixTree {
         let v4_total = v4_len.0 + v4_len.1;  // Sum IPv4 and IPv6 counts from v4_table
         let v6_total = v6_len.0 + v6_len.1;  // Sum IPv4 and IPv6 counts from v6_table
         
-        // Log detailed information about the tree sizes
-        /*debug!(
-            "Radix tree sizes - IPv4 entries: {}, IPv6 entries: {}, Total: {}",
-            v4_total, v6_total, v4_total + v6_total
-        );*/
-        
-        // Log sample of IPv6 networks if any exist
-        if v6_total > 0 {
-            let sample: Vec<_> = self.v6_table.iter()
-                .take(5)
-                .map(|(net, cat)| format!("{} -> {:?}", net, cat))
-                .collect();
-                
-            //debug!("Sample of {} IPv6 networks: {:?}", v6_total, sample);
-        } else if v6_total == 0 && v4_total > 0 {
-            //debug!("No IPv6 networks in the tree. IPv4 count: {}", v4_total);
-            
-            // Log the first few IPv4 networks to ensure the tree is working
-            let v4_sample: Vec<_> = self.v4_table.iter()
-                .take(3)
-                .map(|(net, cat)| format!("{} -> {:?}", net, cat))
-                .collect();
-                
-            //debug!("Sample IPv4 networks: {:?}", v4_sample);
-        }
-        
-        (v4_total, v6_total)
+        // A flat snapshot reports the sizes of the tree it was written from
+        let (flat_v4, flat_v6) = self.flat.as_ref().map_or((0, 0), FlatTree::networks);
+        (v4_total + flat_v4, v6_total + flat_v6)
     }
 
     /// Get the total number of networks in the tree (both IPv4 and IPv6)
     pub fn total_len(&self) -> usize {
-        self.v4_table.len().0 + self.v4_table.len().1 + self.v6_table.len().0 + self.v6_table.len().1
+        let (v4, v6) = self.len();
+        v4 + v6
     }
 
     /// Check if the tree is empty
     pub fn is_empty(&self) -> bool {
-        self.v4_table.is_empty() && self.v6_table.is_empty()
+        self.total_len() == 0
     }
 
-    /// Load IP ranges into the tree
-    pub fn load_ranges(&mut self, ranges: &[IpRange]) -> Result<()> {
-        let total_ranges = ranges.len();
-        let mut v4_count = 0;
-        let mut v6_count = 0;
-        let mut parse_errors = 0;
-        let mut v6_parse_errors = 0;
-            
-        info!("Loading {} IP ranges into the tree", total_ranges);
-            
-        for (i, range) in ranges.iter().enumerate() {
-            if i % 1000 == 0 {
-                debug!("Processed {}/{} ranges ({}%)", i, total_ranges, (i as f32 / total_ranges as f32 * 100.0) as u32);
-            }
-                
-            // Log the first few IPv6 networks for debugging
-            if i < 5 && range.network.contains(':') {
-                debug!("Sample IPv6 network to process: {}", range.network);
-            }
-                
-            match range.network.parse::<IpNetwork>() {
-                Ok(network) => {
-                    match network {
-                        IpNetwork::V4(_) => v4_count += 1,
-                        IpNetwork::V6(net) => {
-                            v6_count += 1;
-                            debug!(
-                                "Processing IPv6 range: {}/{} - Category: {:?} - Source: {}", 
-                                net.network_address(), 
-                                net.netmask(), 
-                                range.category, 
-                                range.source
-                            );
-                        },
-                    }
-                    
-                    let prev_category = self.insert(network, range.category);
-                    
-                    if let IpNetwork::V6(net) = network {
-                        if prev_category.is_some() {
-                            debug!("IPv6 range already existed in the tree: {}/{}", net.network_address(), net.netmask());
-                        } else {
-                            debug!("Successfully inserted new IPv6 range: {}/{}", net.network_address(), net.netmask());
-                            // Verify the insertion
-                            let check = self.v6_table.longest_match(net.network_address());
-                            debug!("Verification lookup for {}: {:?}", net.network_address(), check);
-                        }
-                    }
-                }
-                Err(e) => {
-                    parse_errors += 1;
-                    if range.network.contains(':') {
-                        v6_parse_errors += 1;
-                        error!(
-                            "Failed to parse IPv6 network '{}' from source '{}': {}",
-                            range.network, range.source, e
-                        );
-                    } else {
-                        error!(
-                            "Failed to parse network '{}' from source '{}': {}",
-                            range.network, range.source, e
-                        );
-                    }
-                }
-            }
-        }
-        
-        info!(
-            "Finished loading ranges - Total: {}, IPv4: {}, IPv6: {}, Parse errors: {} ({} IPv6 parse errors)",
-            total_ranges, v4_count, v6_count, parse_errors, v6_parse_errors
-        );
-        
-        // Log final tree sizes
-        let (v4_size, v6_size) = self.len();
-        info!(
-            "Final tree sizes - IPv4: {}, IPv6: {}, Total: {}",
-            v4_size, v6_size, v4_size + v6_size
-        );
-        
-        if v6_count > 0 && v6_size == 0 {
-            error!(
-                "WARNING: Processed {} IPv6 networks but none were inserted into the tree. This indicates a critical issue with IPv6 handling.",
-                v6_count
-            );
+    /// Number of networks per category
+    pub fn category_counts(&self) -> HashMap<IpCategory, usize> {
+        let mut counts: HashMap<IpCategory, usize> =
+            self.flat.iter().flat_map(|flat| flat.category_counts().iter().copied()).collect();
+        for (_, entry) in self.v4_table.iter().chain(self.v6_table.iter()) {
+            *counts.entry(entry.category).or_insert(0) += 1;
         }
-        
-        self.stats.last_updated = Some(chrono::Utc::now());
-        Ok(())
+        counts
     }
 
     /// Get the current lookup statistics
-    pub fn stats(&self) -> &LookupStats {
-        &self.stats
+    pub fn stats(&self) -> LookupStats {
+        LookupStats {
+            total_lookups: self.counters.total_lookups.load(Ordering::Relaxed),
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            filtered: self.counters.filtered.load(Ordering::Relaxed),
+            last_updated: self.last_updated,
+        }
     }
 
     /// Save the tree to a file
@@ -380,12 +548,54 @@ impl RadixTree {
         let tree: Self = serde_json::from_slice(&data)?;
         Ok(tree)
     }
+
+    /// Write the tree as a flat snapshot for [`RadixTree::from_flat`]
+    #[cfg(test)]
+    pub fn save_flat<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        flat::write(path, self.flatten(&mut OverlapCollector::default()))
+    }
+
+    /// The tree as a flat snapshot's contents. The sorted pass that flattens
+    /// it also reports each network nested in one of another category to
+    /// `overlaps`.
+    pub fn flatten(&self, overlaps: &mut OverlapCollector) -> FlatContents<'_> {
+        let v4 = self.v4_table.iter_ipv4().map(|(network, &entry)| {
+            let (start, end) = flat::v4_bounds(network);
+            (start, end, FlatEntry::from(entry))
+        });
+        let v6 = self.v6_table.iter_ipv6().map(|(network, &entry)| {
+            let (start, end) = flat::v6_bounds(network);
+            (start, end, FlatEntry::from(entry))
+        });
+        let mut nested = |v6: bool, outer: &flat::Interval, inner: &flat::Interval| {
+            if outer.2.category == inner.2.category {
+                return;
+            }
+            if let Some(network) = flat::bounds_network(v6, outer.0, outer.1) {
+                overlaps.nested((network, self.label(outer.2)), self.label(inner.2));
+            }
+        };
+        let v4 = flat::flatten(v4.collect(), |outer, inner| nested(false, outer, inner));
+        let v6 = flat::flatten(v6.collect(), |outer, inner| nested(true, outer, inner));
+        FlatContents {
+            sources: &self.sources,
+            networks: self.len(),
+            category_counts: self.category_counts().into_iter().collect(),
+            v4,
+            v6,
+        }
+    }
 }
 
 /// A thread-safe wrapper around RadixTree
+///
+/// Overrides are kept in a layer of their own that lookups check first, so
+/// an override wins over any feed entry, however specific, and can be
+/// swapped without rebuilding the tree.
 #[derive(Debug, Clone)]
 pub struct SharedRadixTree {
     inner: Arc<RwLock<RadixTree>>,
+    overrides: Arc<RwLock<RadixTree>>,
 }
 
 impl SharedRadixTree {
@@ -393,51 +603,112 @@ impl SharedRadixTree {
     pub fn new() -> Self {
         Self {
             inner: Arc::new(RwLock::new(RadixTree::new())),
+            overrides: Arc::new(RwLock::new(RadixTree::new())),
         }
     }
 
     /// Lookup an IP address in the tree
     pub fn lookup(&self, ip: IpAddr) -> Option<IpCategory> {
-        let mut tree = self.inner.write();
-        let result = tree.lookup(ip);
-        
-        // Update stats
-        if result.is_some() {
-            tree.stats.hits += 1;
-        } else {
-            tree.stats.misses += 1;
-        }
-        tree.stats.total_lookups += 1;
-        
+        self.lookup_match(ip).map(|found| found.category)
+    }
+
+    /// Like [`SharedRadixTree::lookup`], with the feed that flagged the address
+    pub fn lookup_match(&self, ip: IpAddr) -> Option<RangeMatch> {
+        let overridden = self.overrides.read().lookup_match(ip);
+        let tree = self.inner.read();
+        let result = overridden.or_else(|| tree.lookup_match(ip));
+        tree.counters.record(result.is_some());
         result
     }
 
-    /// Replace the current tree with a new one
+    /// Whether a `category` network overlaps `network`; not counted in the lookup stats
+    pub fn overlaps(&self, network: IpNetwork, category: IpCategory) -> bool {
+        self.overrides.read().overlaps(network, category) || self.inner.read().overlaps(network, category)
+    }
+
+    /// Categories of every network containing `ip`, overrides first; not
+    /// counted in the lookup stats
+    pub fn lookup_all(&self, ip: IpAddr) -> Vec<IpCategory> {
+        let mut categories = self.overrides.read().lookup_all(ip);
+        for category in self.inner.read().lookup_all(ip) {
+            if !categories.contains(&category) {
+                categories.push(category);
+            }
+        }
+        categories
+    }
+
+    /// Every network containing `ip`, overrides first, so the first is the
+    /// one [`SharedRadixTree::lookup_match`] reports; not counted in the
+    /// lookup stats
+    pub fn lookup_networks(&self, ip: IpAddr) -> Vec<NetworkMatch> {
+        let mut matches = self.overrides.read().lookup_networks(ip);
+        matches.extend(self.inner.read().lookup_networks(ip));
+        matches
+    }
+
+    /// Insert into the live tree; see [`RadixTree::insert_from`]
+    pub fn insert_from(&self, network: IpNetwork, category: IpCategory, source: &str) -> Option<IpCategory> {
+        self.inner.write().insert_from(network, category, source)
+    }
+
+    /// Insert into the live tree; see [`RadixTree::replace_from`]
+    pub fn replace_from(&self, network: IpNetwork, category: IpCategory, source: &str) -> Option<OverlapLabel> {
+        self.inner.write().replace_from(network, category, source)
+    }
+
+    /// Remove from the live tree; see [`RadixTree::remove_from`]
+    pub fn remove_from(&self, network: IpNetwork, source: &str) -> Option<IpCategory> {
+        self.inner.write().remove_from(network, source)
+    }
+
+    /// Replace the current tree with a new one, keeping the overrides
     pub fn replace(&self, new_tree: RadixTree) {
         *self.inner.write() = new_tree;
     }
 
+    /// Replace the overrides checked ahead of the tree
+    pub fn replace_overrides(&self, overrides: RadixTree) {
+        *self.overrides.write() = overrides;
+    }
+
     /// Get the current lookup statistics
     pub fn stats(&self) -> LookupStats {
-        self.inner.read().stats.clone()
+        self.inner.read().stats()
     }
 
-    /// Get the number of networks in the tree
+    /// Get the number of networks in the tree, overrides included
     pub fn len(&self) -> (usize, usize) {
-        self.inner.read().len()
+        let (v4, v6) = self.inner.read().len();
+        let (overrides_v4, overrides_v6) = self.overrides.read().len();
+        (v4 + overrides_v4, v6 + overrides_v6)
     }
 
     /// Get the total number of networks in the tree (both IPv4 and IPv6)
     pub fn total_len(&self) -> usize {
-        self.inner.read().total_len()
+        self.inner.read().total_len() + self.overrides.read().total_len()
     }
 
     /// Check if the tree is empty
     pub fn is_empty(&self) -> bool {
-        self.inner.read().is_empty()
+        self.inner.read().is_empty() && self.overrides.read().is_empty()
     }
 
-    /// Save the tree to a file
+    /// Whether lookups are still served from a flat snapshot
+    pub fn is_flat(&self) -> bool {
+        self.inner.read().is_flat()
+    }
+
+    /// Get the number of networks per category, overrides included
+    pub fn category_counts(&self) -> HashMap<IpCategory, usize> {
+        let mut counts = self.inner.read().category_counts();
+        for (category, count) in self.overrides.read().category_counts() {
+            *counts.entry(category).or_insert(0) += count;
+        }
+        counts
+    }
+
+    /// Save the tree, without the overrides, to a file
     pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
         self.inner.read().save_to_file(path)
     }
@@ -447,6 +718,7 @@ impl SharedRadixTree {
         let tree = RadixTree::load_from_file(path)?;
         Ok(Self {
             inner: Arc::new(RwLock::new(tree)),
+            overrides: Arc::new(RwLock::new(RadixTree::new())),
         })
     }
 }
@@ -462,16 +734,20 @@ mod tests {
     use super::*;
     use std::net::Ipv4Addr;
 
+    fn v4(a: u8, b: u8, c: u8, d: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(a, b, c, d))
+    }
+
     #[test]
     fn test_insert_and_lookup() {
         let mut tree = RadixTree::new();
         let network = IpNetwork::V4("192.168.1.0/24".parse().unwrap());
         tree.insert(network, IpCategory::Vpn);
 
-        let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10));
+        let ip = v4(192, 168, 1, 10);
         assert_eq!(tree.lookup(ip), Some(IpCategory::Vpn));
         
-        let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 2, 10));
+        let ip = v4(192, 168, 2, 10);
         assert_eq!(tree.lookup(ip), None);
         
         // Test len()
@@ -480,10 +756,154 @@ mod tests {
         assert!(!tree.is_empty());
     }
 
+    #[test]
+    fn test_lookup_all_returns_overlapping_categories() {
+        let mut tree = RadixTree::new();
+        tree.insert("10.0.0.0/8".parse().unwrap(), IpCategory::Vpn);
+        tree.insert("10.1.0.0/16".parse().unwrap(), IpCategory::Vpn);
+        tree.insert("10.1.2.3/32".parse().unwrap(), IpCategory::ProxyHttp);
+
+        let ip = v4(10, 1, 2, 3);
+        assert_eq!(tree.lookup(ip), Some(IpCategory::ProxyHttp));
+        let mut all = tree.lookup_all(ip);
+        all.sort_by_key(|category| category.to_string());
+        assert_eq!(all, vec![IpCategory::ProxyHttp, IpCategory::Vpn]);
+
+        assert!(tree.lookup_all(v4(11, 0, 0, 1)).is_empty());
+    }
+
+    #[test]
+    fn test_lookup_networks_lists_every_containing_network() {
+        let mut tree = RadixTree::new();
+        tree.insert_from("10.0.0.0/8".parse().unwrap(), IpCategory::Vpn, "x4bnet-datacenter");
+        tree.insert_from("10.1.2.0/24".parse().unwrap(), IpCategory::ProxyHttp, "thespeedx-http");
+        tree.insert("10.1.0.0/16".parse().unwrap(), IpCategory::Vpn);
+
+        let matches = tree.lookup_networks(v4(10, 1, 2, 3));
+        let found: Vec<_> = matches
+            .iter()
+            .map(|m| (m.network.unwrap().to_string(), m.category, m.source.as_deref()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("10.1.2.0/24".to_string(), IpCategory::ProxyHttp, Some("thespeedx-http")),
+                ("10.1.0.0/16".to_string(), IpCategory::Vpn, None),
+                ("10.0.0.0/8".to_string(), IpCategory::Vpn, Some("x4bnet-datacenter")),
+            ]
+        );
+        assert!(tree.lookup_networks(v4(11, 0, 0, 1)).is_empty());
+    }
+
+    #[test]
+    fn test_lookup_match_reports_the_source() {
+        let mut tree = RadixTree::new();
+        tree.insert_from("10.0.0.0/8".parse().unwrap(), IpCategory::Vpn, "x4bnet-datacenter");
+        tree.insert_from("10.1.2.0/24".parse().unwrap(), IpCategory::ProxySocks5, "thespeedx-socks5");
+        tree.insert("192.0.2.0/24".parse().unwrap(), IpCategory::TorExitNode);
+
+        let found = tree.lookup_match("10.1.2.3".parse().unwrap()).unwrap();
+        assert_eq!(found.category, IpCategory::ProxySocks5);
+        assert_eq!(found.source.as_deref(), Some("thespeedx-socks5"));
+        let found = tree.lookup_match("10.9.9.9".parse().unwrap()).unwrap();
+        assert_eq!(found.source.as_deref(), Some("x4bnet-datacenter"));
+        assert_eq!(tree.lookup_match("192.0.2.1".parse().unwrap()).unwrap().source, None);
+        assert_eq!(tree.lookup_match("8.8.8.8".parse().unwrap()), None);
+
+        // Sources survive a snapshot round trip
+        let restored: RadixTree = serde_json::from_slice(&serde_json::to_vec(&tree).unwrap()).unwrap();
+        let found = restored.lookup_match("10.1.2.3".parse().unwrap()).unwrap();
+        assert_eq!(found.source.as_deref(), Some("thespeedx-socks5"));
+        assert_eq!(restored.lookup_match("192.0.2.1".parse().unwrap()).unwrap().source, None);
+    }
+
+    #[test]
+    fn test_snapshots_without_sources_still_load() {
+        let legacy = r#"{
+            "v4_entries": [["10.0.0.0/8", "Vpn"]],
+            "v6_entries": [],
+            "metadata": {},
+            "stats": {"total_lookups": 0, "hits": 0, "misses": 0, "last_updated": null}
+        }"#;
+        let tree: RadixTree = serde_json::from_str(legacy).unwrap();
+        let found = tree.lookup_match("10.1.1.1".parse().unwrap()).unwrap();
+        assert_eq!(found.category, IpCategory::Vpn);
+        assert_eq!(found.source, None);
+    }
+
+    #[test]
+    fn test_overlaps_checks_both_directions() {
+        let mut tree = RadixTree::new();
+        tree.insert("10.1.0.0/16".parse().unwrap(), IpCategory::TorExitNode);
+        tree.insert("192.0.2.7/32".parse().unwrap(), IpCategory::TorExitNode);
+        tree.insert("198.51.100.0/24".parse().unwrap(), IpCategory::Vpn);
+
+        let overlaps = |network: &str, category| tree.overlaps(network.parse().unwrap(), category);
+        // Inside a listed network, and containing one
+        assert!(overlaps("10.1.2.0/24", IpCategory::TorExitNode));
+        assert!(overlaps("192.0.2.0/24", IpCategory::TorExitNode));
+        assert!(overlaps("10.0.0.0/8", IpCategory::TorExitNode));
+        assert!(!overlaps("10.2.0.0/16", IpCategory::TorExitNode));
+        assert!(!overlaps("198.51.100.0/24", IpCategory::TorExitNode));
+        assert!(overlaps("198.51.100.128/25", IpCategory::Vpn));
+    }
+
+    #[test]
+    fn test_remove_from_only_removes_that_source() {
+        let mut tree = RadixTree::new();
+        tree.insert_from("192.0.2.0/24".parse().unwrap(), IpCategory::Vpn, "feed");
+        tree.insert_from("198.51.100.0/24".parse().unwrap(), IpCategory::Vpn, "manual");
+
+        assert_eq!(tree.remove_from("192.0.2.0/24".parse().unwrap(), "manual"), None);
+        assert_eq!(tree.remove_from("198.51.100.0/25".parse().unwrap(), "manual"), None);
+        assert_eq!(tree.remove_from("198.51.100.0/24".parse().unwrap(), "manual"), Some(IpCategory::Vpn));
+        assert!(tree.lookup("192.0.2.1".parse().unwrap()).is_some());
+        assert!(tree.lookup("198.51.100.1".parse().unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_flat_snapshot_answers_like_the_tree() {
+        // Deterministic, well spread 32-bit values
+        let scatter = |i: u32| i.wrapping_mul(2_654_435_761).rotate_left(7) ^ 0x5bd1_e995;
+        let categories = [IpCategory::Vpn, IpCategory::TorExitNode, IpCategory::ProxyHttp];
+        let mut tree = RadixTree::new();
+        for i in 0..2_000u32 {
+            // Nested prefixes from /8 to /32 around a few hundred anchors
+            let prefix = [8, 16, 20, 24, 28, 32][(i % 6) as usize];
+            let addr = Ipv4Addr::from(scatter(i % 300) ^ scatter(i).checked_shr(prefix).unwrap_or(0));
+            let network = ip_network::Ipv4Network::new_truncate(addr, prefix as u8).unwrap();
+            tree.insert_from(network.into(), categories[(i % 3) as usize], ["a", "b"][(i % 2) as usize]);
+        }
+        tree.insert("2001:db8::/32".parse().unwrap(), IpCategory::Vpn);
+        tree.insert("2001:db8:1::/48".parse().unwrap(), IpCategory::TorExitNode);
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("tree.flat");
+        tree.save_flat(&path).unwrap();
+        let flat = RadixTree::from_flat(FlatTree::open(&path).unwrap());
+        assert!(flat.is_flat());
+        assert_eq!(flat.len(), tree.len());
+        assert_eq!(flat.category_counts(), tree.category_counts());
+
+        for i in 0..50_000u32 {
+            let ip = IpAddr::V4(Ipv4Addr::from(if i % 2 == 0 { scatter(i % 300) ^ i } else { scatter(i) }));
+            assert_eq!(flat.lookup_match(ip), tree.lookup_match(ip), "{}", ip);
+        }
+        for ip in ["2001:db8::1", "2001:db8:1::1", "2001:db9::1"] {
+            let ip: IpAddr = ip.parse().unwrap();
+            assert_eq!(flat.lookup_match(ip), tree.lookup_match(ip), "{}", ip);
+        }
+
+        // Entries inserted over the snapshot win
+        let mut flat = flat;
+        flat.insert_from("2001:db8:1::/48".parse().unwrap(), IpCategory::ProxyHttp, "manual");
+        assert_eq!(flat.lookup("2001:db8:1::1".parse().unwrap()), Some(IpCategory::ProxyHttp));
+    }
+
     #[test]
     fn test_shared_tree() {
         let tree = SharedRadixTree::new();
-        let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10));
+        let ip = v4(192, 168, 1, 10);
         
         // Initially empty
         assert_eq!(tree.lookup(ip), None);
@@ -510,4 +930,87 @@ mod tests {
         assert_eq!(stats.hits, 1);
         assert_eq!(stats.misses, 0);
     }
+
+    #[test]
+    fn test_overrides_win_over_narrower_entries() {
+        let tree = SharedRadixTree::new();
+        let mut feeds = RadixTree::new();
+        feeds.insert_from("10.0.0.0/8".parse().unwrap(), IpCategory::Vpn, "vpn-feed");
+        feeds.insert_from("10.1.2.0/24".parse().unwrap(), IpCategory::TorExitNode, "tor-feed");
+        tree.replace(feeds);
+
+        let mut overrides = RadixTree::new();
+        overrides.insert_from("10.1.0.0/16".parse().unwrap(), IpCategory::ProxyHttp, "overrides");
+        tree.replace_overrides(overrides);
+
+        let ip = v4(10, 1, 2, 3);
+        let found = tree.lookup_match(ip).unwrap();
+        assert_eq!(found.category, IpCategory::ProxyHttp);
+        assert_eq!(found.source.as_deref(), Some("overrides"));
+        let categories = tree.lookup_all(ip);
+        assert_eq!(categories[0], IpCategory::ProxyHttp);
+        assert_eq!(categories.len(), 3);
+        let sources: Vec<_> = tree.lookup_networks(ip).into_iter().map(|found| found.source.unwrap()).collect();
+        assert_eq!(sources.iter().map(|source| &**source).collect::<Vec<_>>(), ["overrides", "tor-feed", "vpn-feed"]);
+        assert_eq!(tree.len(), (3, 0));
+        assert_eq!(tree.stats().hits, 1);
+
+        // A rebuilt tree keeps the overrides, and clearing them uncovers the feeds
+        let mut feeds = RadixTree::new();
+        feeds.insert_from("10.1.2.0/24".parse().unwrap(), IpCategory::TorExitNode, "tor-feed");
+        tree.replace(feeds);
+        assert_eq!(tree.lookup(ip), Some(IpCategory::ProxyHttp));
+        tree.replace_overrides(RadixTree::new());
+        assert_eq!(tree.lookup(ip), Some(IpCategory::TorExitNode));
+    }
+
+    #[test]
+    fn test_prefilter_matches_unfiltered_lookups() {
+        let mut plain = RadixTree::new();
+        for (network, category) in [
+            ("10.0.0.0/8", IpCategory::Vpn),
+            ("172.16.0.0/12", IpCategory::ProxyHttp),
+            ("192.168.1.0/24", IpCategory::TorExitNode),
+            ("203.0.113.7/32", IpCategory::Vpn),
+            ("198.51.100.128/25", IpCategory::ProxySocks5),
+        ] {
+            plain.insert(network.parse().unwrap(), category);
+        }
+        let mut filtered = RadixTree::new();
+        for (network, entry) in plain.v4_table.iter() {
+            filtered.insert(network, entry.category);
+        }
+        filtered.build_prefilter();
+        // Added after the build, so it must go into the existing filter
+        plain.insert("100.64.0.0/10".parse().unwrap(), IpCategory::Vpn);
+        filtered.insert("100.64.0.0/10".parse().unwrap(), IpCategory::Vpn);
+
+        for i in 0..200_000u32 {
+            let ip = IpAddr::V4(Ipv4Addr::from(i.wrapping_mul(2_654_435_761)));
+            assert_eq!(filtered.lookup(ip), plain.lookup(ip), "{}", ip);
+            assert_eq!(filtered.lookup_all(ip), plain.lookup_all(ip), "{}", ip);
+        }
+        for ip in ["10.255.255.255", "172.31.0.1", "192.168.1.0", "203.0.113.7", "198.51.100.255", "100.127.1.1"] {
+            let ip: IpAddr = ip.parse().unwrap();
+            assert!(filtered.lookup(ip).is_some(), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn test_prefilter_counts_filtered_misses() {
+        let mut new_tree = RadixTree::new();
+        new_tree.insert("192.168.1.0/24".parse().unwrap(), IpCategory::Vpn);
+        new_tree.build_prefilter();
+        let tree = SharedRadixTree::new();
+        tree.replace(new_tree);
+
+        assert_eq!(tree.lookup(v4(192, 168, 1, 10)), Some(IpCategory::Vpn));
+        assert_eq!(tree.lookup(v4(8, 8, 8, 8)), None);
+
+        let stats = tree.stats();
+        assert_eq!(stats.total_lookups, 2);
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.filtered, 1);
+    }
 }
@@ -0,0 +1,99 @@
+//! IPv4 origins embedded in IPv6 transition addresses.
+//!
+//! 6to4 (`2002::/16`) and Teredo (`2001:0::/32`) addresses carry the IPv4
+//! address of the host behind the tunnel, which v4-only blocklists would
+//! otherwise never see.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+
+use crate::errors::validation::{canonical_ip, validate_ip};
+
+/// The transition mechanism an IPv4 origin was decoded from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TunnelKind {
+    /// `2002:AABB:CCDD::/48` embeds `A.B.C.D` in bits 16-47
+    SixToFour,
+    /// The client address is the last 32 bits, inverted
+    Teredo,
+}
+
+impl fmt::Display for TunnelKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TunnelKind::SixToFour => f.write_str("6to4"),
+            TunnelKind::Teredo => f.write_str("Teredo"),
+        }
+    }
+}
+
+/// The IPv4 origin of a 6to4 or Teredo address, with the mechanism it came from
+pub fn embedded_ipv4(ip: IpAddr) -> Option<(TunnelKind, Ipv4Addr)> {
+    let IpAddr::V6(v6) = ip else {
+        return None;
+    };
+    let segments = v6.segments();
+    let join = |high: u16, low: u16| (u32::from(high) << 16) | u32::from(low);
+
+    match segments {
+        [0x2002, high, low, ..] => Some((TunnelKind::SixToFour, Ipv4Addr::from(join(high, low)))),
+        [0x2001, 0, .., high, low] => Some((TunnelKind::Teredo, Ipv4Addr::from(!join(high, low)))),
+        _ => None,
+    }
+}
+
+/// [`canonical_ip`], and with `collapse_6to4` also the public IPv4 address
+/// a 6to4 address embeds, so a dual-stack host seen under both forms shares
+/// one cache entry and verdict. Teredo addresses are left alone: their last
+/// 32 bits are the NAT's address, which many clients share.
+pub fn canonicalize_ip(ip: IpAddr, collapse_6to4: bool) -> IpAddr {
+    match embedded_ipv4(ip) {
+        Some((TunnelKind::SixToFour, v4)) if collapse_6to4 && validate_ip(IpAddr::V4(v4)).is_ok() => IpAddr::V4(v4),
+        _ => canonical_ip(ip),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(ip: &str) -> Option<(TunnelKind, String)> {
+        embedded_ipv4(ip.parse().unwrap()).map(|(kind, v4)| (kind, v4.to_string()))
+    }
+
+    #[test]
+    fn test_six_to_four() {
+        assert_eq!(decode("2002:501:101::1"), Some((TunnelKind::SixToFour, "5.1.1.1".to_string())));
+        assert_eq!(decode("2002:c000:204:1::"), Some((TunnelKind::SixToFour, "192.0.2.4".to_string())));
+    }
+
+    #[test]
+    fn test_teredo() {
+        // Server 65.54.227.120, client 5.1.1.1 stored as !0x05010101
+        assert_eq!(
+            decode("2001:0:4136:e378:8000:63bf:fafe:fefe"),
+            Some((TunnelKind::Teredo, "5.1.1.1".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_canonicalize_collapses_6to4_when_enabled() {
+        let canonical = |ip: &str, collapse: bool| canonicalize_ip(ip.parse().unwrap(), collapse).to_string();
+        assert_eq!(canonical("2002:501:101::1", true), "5.1.1.1");
+        assert_eq!(canonical("2002:501:101:ffff::9", true), "5.1.1.1");
+        assert_eq!(canonical("2002:501:101::1", false), "2002:501:101::1");
+        assert_eq!(canonical("::ffff:5.1.1.1", false), "5.1.1.1");
+        // Non-public origins and Teredo keep their IPv6 form
+        assert_eq!(canonical("2002:a00:1::1", true), "2002:a00:1::1");
+        assert_eq!(canonical("2001:0:4136:e378:8000:63bf:fafe:fefe", true), "2001:0:4136:e378:8000:63bf:fafe:fefe");
+    }
+
+    #[test]
+    fn test_other_addresses_have_no_origin() {
+        assert_eq!(decode("2001:db8::1"), None);
+        assert_eq!(decode("2001:4860:4860::8888"), None);
+        assert_eq!(decode("2606:4700::1111"), None);
+        assert_eq!(decode("::ffff:5.1.1.1"), None);
+        assert_eq!(decode("5.1.1.1"), None);
+    }
+}
@@ -1,10 +1,15 @@
 use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
+use serde::de::{self, Deserializer, MapAccess, Unexpected, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
 use std::str::FromStr;
 use thiserror::Error;
 
 /// Categories for IP addresses
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+///
+/// Serialized as its [`Display`](std::fmt::Display) name (`http_proxy`,
+/// `cloud_aws`, ...). Anything [`FromStr`] accepts deserializes, as do the
+/// variant names and `{"CloudProvider": "aws"}` maps written before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum IpCategory {
     /// IP belongs to a VPN or datacenter
     Vpn,
@@ -16,6 +21,36 @@ pub enum IpCategory {
     ProxySocks5,
     /// IP is a TOR exit node
     TorExitNode,
+    /// IP is in a cloud provider's published ranges
+    CloudProvider(CloudKind),
+}
+
+pub use infralock_types::{CategoryFlags, CloudKind};
+
+/// Sets the flag for each category. The match is exhaustive, so a new
+/// category does not compile until it has a flag here.
+impl Extend<IpCategory> for CategoryFlags {
+    fn extend<I: IntoIterator<Item = IpCategory>>(&mut self, categories: I) {
+        for category in categories {
+            let flag = match category {
+                IpCategory::Vpn => &mut self.vpn,
+                IpCategory::ProxyHttp => &mut self.proxy_http,
+                IpCategory::ProxySocks4 => &mut self.proxy_socks4,
+                IpCategory::ProxySocks5 => &mut self.proxy_socks5,
+                IpCategory::TorExitNode => &mut self.tor,
+                IpCategory::CloudProvider(_) => &mut self.cloud,
+            };
+            *flag = true;
+        }
+    }
+}
+
+impl FromIterator<IpCategory> for CategoryFlags {
+    fn from_iter<I: IntoIterator<Item = IpCategory>>(categories: I) -> Self {
+        let mut flags = Self::default();
+        flags.extend(categories);
+        flags
+    }
 }
 
 impl std::fmt::Display for IpCategory {
@@ -26,6 +61,7 @@ impl std::fmt::Display for IpCategory {
             Self::ProxySocks4 => write!(f, "socks4_proxy"),
             Self::ProxySocks5 => write!(f, "socks5_proxy"),
             Self::TorExitNode => write!(f, "tor_exit_node"),
+            Self::CloudProvider(kind) => write!(f, "cloud_{}", kind),
         }
     }
 }
@@ -33,18 +69,69 @@ impl std::fmt::Display for IpCategory {
 impl FromStr for IpCategory {
     type Err = IpRangeError;
 
+    /// Case, `_`, `-` and spaces are ignored, so `TorExitNode`, `tor-exit`
+    /// and `tor_exit_node` are the same category
     fn from_str(s: &str) -> Result<Self> {
-        match s.to_lowercase().as_str() {
+        match normalized(s).as_str() {
             "vpn" => Ok(Self::Vpn),
-            "http" | "http_proxy" => Ok(Self::ProxyHttp),
-            "socks4" | "socks4_proxy" => Ok(Self::ProxySocks4),
-            "socks5" | "socks5_proxy" => Ok(Self::ProxySocks5),
-            "tor" | "tor_exit" | "tor_exit_node" => Ok(Self::TorExitNode),
+            "http" | "httpproxy" | "proxyhttp" => Ok(Self::ProxyHttp),
+            "socks4" | "socks4proxy" | "proxysocks4" => Ok(Self::ProxySocks4),
+            "socks5" | "socks5proxy" | "proxysocks5" => Ok(Self::ProxySocks5),
+            "tor" | "torexit" | "torexitnode" => Ok(Self::TorExitNode),
+            "aws" | "cloudaws" => Ok(Self::CloudProvider(CloudKind::Aws)),
+            "gcp" | "cloudgcp" => Ok(Self::CloudProvider(CloudKind::Gcp)),
+            "azure" | "cloudazure" => Ok(Self::CloudProvider(CloudKind::Azure)),
+            "oci" | "cloudoci" => Ok(Self::CloudProvider(CloudKind::Oci)),
             _ => Err(IpRangeError::UnknownCategory(format!("Unknown IP category: {}", s))),
         }
     }
 }
 
+impl Serialize for IpCategory {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for IpCategory {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        deserializer.deserialize_any(IpCategoryVisitor)
+    }
+}
+
+struct IpCategoryVisitor;
+
+impl<'de> Visitor<'de> for IpCategoryVisitor {
+    type Value = IpCategory;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("an IP category such as \"vpn\" or \"tor_exit_node\"")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> std::result::Result<IpCategory, E> {
+        value.parse().map_err(|_| E::invalid_value(Unexpected::Str(value), &self))
+    }
+
+    /// The externally tagged form older snapshots hold for cloud ranges
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> std::result::Result<IpCategory, A::Error> {
+        let (tag, kind): (String, CloudKind) =
+            map.next_entry()?.ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        if normalized(&tag) != "cloudprovider" {
+            return Err(de::Error::unknown_variant(&tag, &["CloudProvider"]));
+        }
+        Ok(IpCategory::CloudProvider(kind))
+    }
+}
+
+/// Lowercased, without `_`, `-` or spaces, for matching names however
+/// they were spelled
+fn normalized(name: &str) -> String {
+    name.chars()
+        .filter(|c| !matches!(c, '_' | '-' | ' '))
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
 /// Represents a range of IP addresses with associated metadata
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IpRange {
@@ -96,9 +183,39 @@ pub enum IpRangeError {
     
     #[error("Unknown category: {0}")]
     UnknownCategory(String),
+
+    #[error("Unknown source format: {0}")]
+    UnknownFormat(String),
     
     #[error("Invalid URL: {0}")]
     InvalidUrl(String),
+
+    #[error("No snapshot at index {0}")]
+    SnapshotNotFound(usize),
+
+    #[error("Snapshot checksum mismatch: {0}")]
+    ChecksumMismatch(String),
+
+    #[error("Invalid flat snapshot {0}")]
+    InvalidSnapshot(String),
+
+    /// The feed is text, but not UTF-8 (e.g. UTF-16)
+    #[error("Unsupported feed encoding: {0}")]
+    UnsupportedEncoding(String),
+
+    #[error("Feed is not text: {0}")]
+    BinaryContent(String),
+
+    /// Nothing usable in a feed with content, which would otherwise empty its category
+    #[error("No entries parsed from {feed} ({lines} non-blank lines)")]
+    NoEntries { feed: String, lines: usize },
+}
+
+impl IpRangeError {
+    /// Whether a feed was fetched but its content is unusable
+    pub fn is_bad_content(&self) -> bool {
+        matches!(self, Self::UnsupportedEncoding(_) | Self::BinaryContent(_) | Self::NoEntries { .. })
+    }
 }
 
 impl From<std::net::AddrParseError> for IpRangeError {
@@ -110,9 +227,15 @@ impl From<std::net::AddrParseError> for IpRangeError {
 /// Result type for IP range operations
 pub type Result<T> = std::result::Result<T, IpRangeError>;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+/// How a source's entries are laid out
+///
+/// Serialized in snake_case (`ip_port`); the variant names older snapshots
+/// hold and the aliases [`FromStr`] accepts deserialize too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum SourceFormat {
     /// Plain IP or CIDR (default)
+    #[default]
     Default,
     /// IP:PORT format (extracts just the IP part)
     IpPort,
@@ -120,11 +243,63 @@ pub enum SourceFormat {
     TorExitList,
     /// JSON array of CIDR strings
     JsonList,
+    /// AWS `ip-ranges.json` (`prefixes[].ip_prefix`, `ipv6_prefixes[].ipv6_prefix`)
+    AwsIpRanges,
+    /// GCP `cloud.json` (`prefixes[].ipv4Prefix` / `ipv6Prefix`)
+    GcpCloudJson,
+    /// Mixed lines, each tried as an IP, then IP:PORT, then CIDR; anything
+    /// after the first comma or whitespace (e.g. a country column) is ignored
+    Auto,
+    /// `+CIDR` / `-CIDR` lines changing the list since a cursor; see
+    /// [`delta`](crate::ip_lookup::delta)
+    Delta,
+}
+
+
+impl SourceFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Default => "default",
+            Self::IpPort => "ip_port",
+            Self::TorExitList => "tor_exit_list",
+            Self::JsonList => "json_list",
+            Self::AwsIpRanges => "aws_ip_ranges",
+            Self::GcpCloudJson => "gcp_cloud_json",
+            Self::Auto => "auto",
+            Self::Delta => "delta",
+        }
+    }
+}
+
+impl std::fmt::Display for SourceFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for SourceFormat {
+    type Err = IpRangeError;
+
+    /// Like [`IpCategory`], ignoring case, `_`, `-` and spaces
+    fn from_str(s: &str) -> Result<Self> {
+        match normalized(s).as_str() {
+            "default" | "plain" | "cidr" => Ok(Self::Default),
+            "ipport" => Ok(Self::IpPort),
+            "tor" | "torexit" | "torexitlist" => Ok(Self::TorExitList),
+            "json" | "jsonlist" => Ok(Self::JsonList),
+            "aws" | "awsipranges" => Ok(Self::AwsIpRanges),
+            "gcp" | "gcpcloudjson" => Ok(Self::GcpCloudJson),
+            "auto" => Ok(Self::Auto),
+            "delta" => Ok(Self::Delta),
+            _ => Err(IpRangeError::UnknownFormat(s.to_string())),
+        }
+    }
 }
 
-impl Default for SourceFormat {
-    fn default() -> Self {
-        Self::Default
+impl<'de> Deserialize<'de> for SourceFormat {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        name.parse().map_err(|_| de::Error::invalid_value(Unexpected::Str(&name), &"a source format such as \"ip_port\""))
     }
 }
 
@@ -132,4 +307,191 @@ impl Default for SourceFormat {
 pub enum IpVersion {
     V4,
     V6,
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_every_category_has_its_own_flag() {
+        let categories = [
+            IpCategory::Vpn,
+            IpCategory::ProxyHttp,
+            IpCategory::ProxySocks4,
+            IpCategory::ProxySocks5,
+            IpCategory::TorExitNode,
+            IpCategory::CloudProvider(CloudKind::Aws),
+        ];
+        let flags: Vec<serde_json::Value> = categories
+            .iter()
+            .map(|category| serde_json::to_value(CategoryFlags::from_iter([*category])).unwrap())
+            .collect();
+        for (category, value) in categories.iter().zip(&flags) {
+            let set: Vec<_> = value.as_object().unwrap().iter().filter(|(_, flag)| flag.as_bool() == Some(true)).collect();
+            assert_eq!(set.len(), 1, "{} sets {:?}", category, set);
+        }
+        // No two categories share a flag
+        for (i, value) in flags.iter().enumerate() {
+            assert!(!flags[i + 1..].contains(value));
+        }
+    }
+
+    #[test]
+    fn test_category_flags_serialize_every_flag() {
+        let flags: CategoryFlags = [IpCategory::Vpn, IpCategory::CloudProvider(CloudKind::Gcp)].into_iter().collect();
+        assert_eq!(
+            serde_json::to_value(flags).unwrap(),
+            serde_json::json!({
+                "vpn": true,
+                "proxy_http": false,
+                "proxy_socks4": false,
+                "proxy_socks5": false,
+                "tor": false,
+                "cloud": true,
+            })
+        );
+        // Flags added later default to false in older serialized responses
+        let parsed: CategoryFlags = serde_json::from_str(r#"{"tor": true}"#).unwrap();
+        assert_eq!(parsed, [IpCategory::TorExitNode].into_iter().collect());
+    }
+
+    const CATEGORIES: [IpCategory; 9] = [
+        IpCategory::Vpn,
+        IpCategory::ProxyHttp,
+        IpCategory::ProxySocks4,
+        IpCategory::ProxySocks5,
+        IpCategory::TorExitNode,
+        IpCategory::CloudProvider(CloudKind::Aws),
+        IpCategory::CloudProvider(CloudKind::Gcp),
+        IpCategory::CloudProvider(CloudKind::Azure),
+        IpCategory::CloudProvider(CloudKind::Oci),
+    ];
+
+    const FORMATS: [SourceFormat; 8] = [
+        SourceFormat::Default,
+        SourceFormat::IpPort,
+        SourceFormat::TorExitList,
+        SourceFormat::JsonList,
+        SourceFormat::AwsIpRanges,
+        SourceFormat::GcpCloudJson,
+        SourceFormat::Auto,
+        SourceFormat::Delta,
+    ];
+
+    #[test]
+    fn test_category_serializes_as_its_display_name() {
+        for category in CATEGORIES {
+            let value = serde_json::to_value(category).unwrap();
+            assert_eq!(value, serde_json::Value::String(category.to_string()));
+            assert_eq!(serde_json::from_value::<IpCategory>(value).unwrap(), category);
+            assert_eq!(category.to_string().parse::<IpCategory>().unwrap(), category);
+        }
+    }
+
+    #[test]
+    fn test_category_aliases_and_legacy_forms() {
+        let aliases = [
+            ("Vpn", IpCategory::Vpn),
+            ("VPN", IpCategory::Vpn),
+            ("http", IpCategory::ProxyHttp),
+            ("ProxyHttp", IpCategory::ProxyHttp),
+            ("proxy_http", IpCategory::ProxyHttp),
+            ("socks4", IpCategory::ProxySocks4),
+            ("ProxySocks4", IpCategory::ProxySocks4),
+            ("SOCKS5", IpCategory::ProxySocks5),
+            ("proxy-socks5", IpCategory::ProxySocks5),
+            ("tor", IpCategory::TorExitNode),
+            ("tor_exit", IpCategory::TorExitNode),
+            ("TorExitNode", IpCategory::TorExitNode),
+            ("Tor-Exit-Node", IpCategory::TorExitNode),
+            ("aws", IpCategory::CloudProvider(CloudKind::Aws)),
+            ("Cloud_GCP", IpCategory::CloudProvider(CloudKind::Gcp)),
+            ("azure", IpCategory::CloudProvider(CloudKind::Azure)),
+            ("cloud-oci", IpCategory::CloudProvider(CloudKind::Oci)),
+        ];
+        for (alias, category) in aliases {
+            assert_eq!(alias.parse::<IpCategory>().unwrap(), category, "{}", alias);
+            assert_eq!(serde_json::from_value::<IpCategory>(serde_json::json!(alias)).unwrap(), category, "{}", alias);
+        }
+        // Cloud ranges in snapshots written with the derived representation
+        let legacy: IpCategory = serde_json::from_str(r#"{"CloudProvider": "azure"}"#).unwrap();
+        assert_eq!(legacy, IpCategory::CloudProvider(CloudKind::Azure));
+
+        assert!("datacenter".parse::<IpCategory>().is_err());
+        let error = serde_json::from_str::<IpCategory>(r#""proxy""#).unwrap_err().to_string();
+        assert!(error.contains("tor_exit_node"), "{}", error);
+        assert!(serde_json::from_str::<IpCategory>(r#"{"Vpn": "aws"}"#).is_err());
+    }
+
+    #[test]
+    fn test_source_format_snake_case_and_aliases() {
+        for format in FORMATS {
+            let value = serde_json::to_value(format).unwrap();
+            assert_eq!(value, serde_json::Value::String(format.to_string()));
+            assert_eq!(serde_json::from_value::<SourceFormat>(value).unwrap(), format);
+            assert_eq!(format.to_string().parse::<SourceFormat>().unwrap(), format);
+            // The variant name older snapshots hold
+            let variant = format!("{:?}", format);
+            assert_eq!(serde_json::from_value::<SourceFormat>(serde_json::json!(variant)).unwrap(), format);
+        }
+        let aliases = [
+            ("plain", SourceFormat::Default),
+            ("ip-port", SourceFormat::IpPort),
+            ("IP_PORT", SourceFormat::IpPort),
+            ("tor", SourceFormat::TorExitList),
+            ("json", SourceFormat::JsonList),
+            ("aws", SourceFormat::AwsIpRanges),
+            ("gcp", SourceFormat::GcpCloudJson),
+        ];
+        for (alias, format) in aliases {
+            assert_eq!(alias.parse::<SourceFormat>().unwrap(), format, "{}", alias);
+        }
+        let error = serde_json::from_str::<SourceFormat>(r#""csv""#).unwrap_err().to_string();
+        assert!(error.contains("ip_port"), "{}", error);
+    }
+
+    #[test]
+    fn test_source_documents_in_any_spelling() {
+        use crate::ip_lookup::IpRangeSource;
+
+        let toml = r#"
+            url = "https://lists.example/socks5.txt"
+            category = "socks5"
+            name = "socks5"
+            enabled = true
+            format = "ip_port"
+            ip_version = "V4"
+        "#;
+        let source: IpRangeSource = config::Config::builder()
+            .add_source(config::File::from_str(toml, config::FileFormat::Toml))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        assert_eq!((source.category, source.format), (IpCategory::ProxySocks5, SourceFormat::IpPort));
+
+        // Written now, then as older builds wrote it
+        let json = serde_json::to_value(&source).unwrap();
+        assert_eq!((json["category"].as_str(), json["format"].as_str()), (Some("socks5_proxy"), Some("ip_port")));
+        let legacy = serde_json::json!({
+            "url": "https://lists.example/aws.json",
+            "category": { "CloudProvider": "aws" },
+            "name": "aws",
+            "enabled": true,
+            "format": "AwsIpRanges",
+            "ip_version": "V4",
+        });
+        let source: IpRangeSource = serde_json::from_value(legacy).unwrap();
+        assert_eq!(
+            (source.category, source.format),
+            (IpCategory::CloudProvider(CloudKind::Aws), SourceFormat::AwsIpRanges)
+        );
+
+        let range: IpRange = serde_json::from_str(
+            r#"{"network": "5.1.1.1/32", "category": "TorExitNode", "source": "tor", "format": "TorExitList",
+                "first_seen": "2025-01-01T00:00:00Z", "last_updated": "2025-01-01T00:00:00Z"}"#,
+        )
+        .unwrap();
+        assert_eq!((range.category, range.format), (IpCategory::TorExitNode, SourceFormat::TorExitList));
+    }
+}
@@ -2,17 +2,17 @@ use std::sync::Arc;
 use std::collections::HashSet;
 use std::time::Duration;
 use moka::sync::Cache;
-use tokio::net::TcpListener;
-use tokio::sync::RwLock;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+use clap::Parser;
 use dotenv::dotenv;
 
 use crate::clients::web_api::{WebApiClient, WebApiClientConfig};
 
 mod alerting;
+mod cli;
 mod clients;
 mod config;
 mod errors;
+mod geo;
 mod handlers;
 mod ip_lookup;
 mod middleware;
@@ -20,12 +20,30 @@ mod models;
 mod monitoring;
 mod routes;
 mod services;
+mod telemetry;
 mod utils;
+#[cfg(test)]
+mod test_support;
 
-use crate::config::Settings;
+use crate::config::{data_layout, validation, Settings};
 use crate::handlers::AppState;
 use crate::routes::{create_router, metrics::metrics_routes};
-use crate::services::background_updater::{BackgroundUpdater, BackgroundUpdaterConfig};
+use crate::services::background_updater::BackgroundUpdater;
+use crate::services::cache_warming::{self, CacheWarmingConfig};
+use crate::config::runtime::RuntimeConfig;
+use crate::utils::{http_client, listen, redact};
+use crate::services::config_reload::{self, ConfigReloader};
+use crate::services::challenge::ChallengeService;
+use crate::services::aggregates::LookupAggregates;
+use crate::services::connectivity::{self, ConnectivityChecker};
+use crate::services::debug_capture::DebugCapture;
+use crate::services::decision_log::DecisionLog;
+use crate::services::response_action::MonitorOverride;
+use crate::services::shared_cache::SharedCache;
+use crate::services::usage::{self, UsageAccounting, UsageSink};
+use crate::services::proxy_detection::ProxyDetector;
+use crate::services::vpn_detection::VpnDetector;
+use crate::config::UsageSinkKind;
 
 fn parse_unlimited_api_keys() -> HashSet<String> {
     std::env::var("UNLIMITED_API_KEYS")
@@ -38,69 +56,127 @@ fn parse_unlimited_api_keys() -> HashSet<String> {
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = cli::Cli::parse();
+
     // Load .env file
     dotenv().ok();
     
-    // Configure logging format based on environment
-    tracing_subscriber::fmt()
-    .with_env_filter(EnvFilter::from_default_env()
-        .add_directive("debug".parse().unwrap())
-        .add_directive("hyper=info".parse().unwrap())
-        .add_directive("tower_http=info".parse().unwrap()))
-    .with_target(true)
-    .with_thread_ids(true)
-    .init();
-
-    tracing::info!("Starting geolocation service");
+    // Load configuration
+    let settings = Settings::layered()?;
+
+    // Subcommands run against the local data and exit without serving
+    if let Some(command) = cli.command {
+        return cli::run(command, &settings).await;
+    }
+
+    let ip_lookup_config = ip_lookup::config_from_settings(&settings)?;
+    let diagnostics = validation::validate(&settings, &ip_lookup_config.sources, std::env::vars());
+
+    // `--validate-config` reports every problem and exits without starting
+    if cli.validate_config {
+        for diagnostic in &diagnostics {
+            eprintln!("{}", diagnostic);
+        }
+        if validation::has_errors(&diagnostics) {
+            std::process::exit(1);
+        }
+        println!("configuration OK");
+        return Ok(());
+    }
+
+    // Logging, plus OTLP span export when configured
+    let telemetry = telemetry::init(&settings.telemetry)?;
+    redact::install(redact::IpRedactor::new(
+        settings.telemetry.log_ip_redaction,
+        settings.telemetry.log_ip_hash_key.as_deref(),
+    ));
+
+    for diagnostic in &diagnostics {
+        match diagnostic.severity {
+            validation::Severity::Error => tracing::error!("{}", diagnostic),
+            validation::Severity::Warning => tracing::warn!("{}", diagnostic),
+        }
+    }
+    if validation::has_errors(&diagnostics) {
+        return Err("invalid configuration; run with --validate-config for details".into());
+    }
+
+    tracing::info!(service = %settings.telemetry.service_name, "Starting geolocation service");
     tracing::debug!("Debug logging is enabled");
 
-    // Load configuration
-    let settings = Settings::new()?;
-
-    // --- BackgroundUpdater configuration ---
-    let updater_config = BackgroundUpdaterConfig {
-        vpn_url: "https://raw.githubusercontent.com/X4BNet/lists_vpn/refs/heads/main/output/datacenter/ipv4.txt".to_string(),
-        http_proxy_url: "https://raw.githubusercontent.com/TheSpeedX/SOCKS-List/master/http.txt".to_string(),
-        socks4_proxy_url: "https://raw.githubusercontent.com/TheSpeedX/SOCKS-List/master/socks4.txt".to_string(),
-        socks5_proxy_url: "https://raw.githubusercontent.com/TheSpeedX/SOCKS-List/master/socks5.txt".to_string(),
-        tor_exit_nodes_url: "https://check.torproject.org/exit-addresses".to_string(),
-        interval_secs: 86400, // 24 hours in seconds
-        vpn_path: "data/vpns/ipv4.txt".to_string(),
-        http_proxy_path: "data/proxies/http.txt".to_string(),
-        socks4_proxy_path: "data/proxies/socks4.txt".to_string(),
-        socks5_proxy_path: "data/proxies/socks5.txt".to_string(),
-        tor_exit_nodes_path: "data/tor/exit-addresses.txt".to_string(),
-    };
-    
-    let updater = BackgroundUpdater::new(updater_config);
-    tokio::spawn(async move {
-        updater.start().await;
-    });
-    // --- End BackgroundUpdater configuration ---
+    // Every directory the service writes to exists and is writable before anything loads
+    for migration in data_layout::prepare(&settings)? {
+        tracing::info!(from = %migration.from.display(), to = %migration.to.display(), "Moved data file into the data root");
+    }
+
+    // Every outbound client applies the same proxy, User-Agent, timeouts and TLS policy
+    let http_client = http_client::build_client(&settings.outbound_http)?;
+    if let Some(proxy_url) = &settings.outbound_http.proxy_url {
+        tracing::info!(proxy = %http_client::redact_credentials(proxy_url), "Outbound HTTP goes through a proxy");
+    }
+
+    match settings.background_updater.updater_config() {
+        Some(updater_config) => {
+            let cert_pins = http_client::CertPins::from_settings(&settings.outbound_http)?;
+            let updater = BackgroundUpdater::new(updater_config, http_client.clone())
+                .with_cert_pins(cert_pins)
+                .with_temp_dir(settings.data_layout().tmp_update_dir());
+            tokio::spawn(async move {
+                updater.start().await;
+            });
+        }
+        None => tracing::info!("Background updater disabled"),
+    }
     
     // Parse unlimited API keys from environment
     let unlimited_api_keys = parse_unlimited_api_keys();
     tracing::info!("Loaded {} unlimited API keys", unlimited_api_keys.len());
     
-    // Clone the db_path to avoid moving settings
-    let db_path = settings.resolve_db_path().unwrap_or_else(|e| {
-        panic!("Failed to resolve database path: {}", e);
-    });
-    let reader = maxminddb::Reader::open_readfile(db_path)?;
-
-    // ASN DB initialization
-    let asn_db_path = settings.resolve_asn_db_path().unwrap_or_else(|e| {
-        panic!("Failed to resolve ASN database path: {}", e);
-    });
-    let asn_reader = maxminddb::Reader::open_readfile(asn_db_path)?;
+    tracing::info!(
+        enabled = ?settings.features.enabled(),
+        disabled = ?settings.features.disabled(),
+        "Active feature set"
+    );
+
+    // Geo provider initialization (MaxMind or IP2Location, per `geo.provider`)
+    let geo_provider = geo::from_settings(&settings)?;
+    tracing::info!(metadata = ?geo_provider.metadata(), "Geo provider initialized");
+
+    // VPN and proxy lists for the range endpoints and threat scores, parsed
+    // concurrently before serving so no request waits for them
+    let (vpn_detector, proxy_detector) = tokio::join!(VpnDetector::load(&settings), ProxyDetector::load(&settings));
+    let vpn_detector = vpn_detector
+        .inspect_err(|e| tracing::error!(error = %e, "Failed to load the VPN list; VPN checks fail until restart"))
+        .ok()
+        .map(Arc::new);
+    let proxy_detector = proxy_detector
+        .inspect_err(|e| tracing::error!(error = %e, "Failed to load the proxy lists; proxy checks fail until restart"))
+        .ok()
+        .map(Arc::new);
 
     // Initialize IP lookup service
-    let ip_lookup_config = ip_lookup::default_config()?;
+    // Kept for the connectivity check; the service owns the config
+    let range_sources = ip_lookup_config.sources.clone();
     let ip_lookup_service = Arc::new(ip_lookup::IpLookupService::new(ip_lookup_config));
+    // Answers from the last tree right away; replaced once the feeds are loaded
+    ip_lookup_service.load_flat_snapshot();
+    if settings.ip_lookup.offline {
+        // Nothing to refresh from, so load the local copies once
+        if let Err(e) = ip_lookup_service.update_all_sources().await {
+            tracing::error!(error = %e, "Failed to load IP ranges from local files");
+        }
+    }
+    // Offline, this only watches the overrides file
     ip_lookup_service.start_background_updates();
+    // Register it globally so `ip_lookup::check_ip` shares the handlers' tree
+    ip_lookup::init_with_instance(Arc::clone(&ip_lookup_service)).await?;
 
     // Initialize Web API client for API key validation
-    let web_api_config = WebApiClientConfig::default();
+    let web_api_config = WebApiClientConfig {
+        outbound_http: settings.outbound_http.clone(),
+        ..WebApiClientConfig::default()
+    };
+    let connectivity_targets = connectivity::targets(&settings, &range_sources, &web_api_config.base_url);
     let web_api_client = Arc::new(WebApiClient::new(web_api_config));
 
     // In main.rs
@@ -115,37 +191,118 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             .max_capacity(100_000)
             .build()
         );
+
+    // Replicas share lookups through Redis, if configured
+    let shared_cache = SharedCache::from_settings(&settings.cache, Arc::clone(&lookup_cache))?.map(Arc::new);
+    if let Some(shared_cache) = &shared_cache {
+        tracing::info!(
+            redis = %settings.cache.redis_url.as_deref().map(http_client::redact_credentials).unwrap_or_default(),
+            "Sharing the lookup cache through Redis"
+        );
+        shared_cache.spawn_subscriber();
+        shared_cache.spawn_tree_watch(ip_lookup_service.subscribe_updates());
+    }
     
+    let runtime = RuntimeConfig::from_settings(&settings);
+    tracing::info!(roles = ?runtime.profiles.roles().collect::<Vec<_>>(), "Loaded scoring profiles");
+    let runtime = runtime.shared();
+
+    // Scoring and log filter changes apply on SIGHUP or POST /api/admin/reload-config
+    let config_reloader = Arc::new(ConfigReloader::new(
+        settings.clone(),
+        Arc::clone(&runtime),
+        Arc::clone(&lookup_cache),
+        Some(telemetry.log_filter()),
+    )
+    .with_shared_cache(shared_cache.clone()));
+    #[cfg(unix)]
+    config_reload::spawn_sighup_handler(Arc::clone(&config_reloader))?;
+
+    // Per-API-key usage, closed into a billing report every flush interval
+    let usage_accounting = Arc::new(UsageAccounting::new().with_max_pending(settings.usage.max_pending_reports));
+    let usage_sink = match settings.usage.sink {
+        UsageSinkKind::File => UsageSink::File(settings.usage.dir.clone()),
+        UsageSinkKind::WebApi => UsageSink::WebApi(Arc::clone(&web_api_client)),
+    };
+    usage::spawn_flush_loop(
+        Arc::clone(&usage_accounting),
+        usage_sink,
+        Duration::from_secs(settings.usage.flush_interval_secs),
+    );
+
+    // Clearances and redeemed tokens go through Redis too, if it is configured
+    let challenges = ChallengeService::from_settings(&settings.challenge)
+        .map(|challenges| Arc::new(challenges.with_shared_cache(shared_cache.clone())));
+
     // Create application state
     let state = AppState { 
-        maxmind_reader: Arc::new(RwLock::new(reader)),
-        asn_reader: Arc::new(RwLock::new(asn_reader)),
+        geo_provider,
         lookup_cache,
+        shared_cache,
         ip_lookup_service,
         web_api_client: web_api_client.clone(),  // Clone here for AppState
+        unlimited_api_keys: Arc::new(unlimited_api_keys),
+        features: settings.features,
+        require_geo: settings.geo.require_geo,
+        tunnel_extraction: settings.ip_lookup.tunnel_extraction,
+        canonicalize_6to4: settings.ip_lookup.canonicalize_6to4,
+        missing_data: settings.ip_lookup.missing_data_policy(),
+        category_fallback: settings.ip_lookup.category_fallback.as_deref().map(Arc::from),
+        runtime,
+        config_reloader,
+        range_scan_timeout: Duration::from_millis(settings.server.range_scan_timeout_ms),
+        lookup_timeout: settings.server.lookup_timeout(),
+        legacy_sunset: settings.server.legacy_routes.then_some(settings.server.legacy_routes_sunset),
+        verdict_headers: settings.server.verdict_headers,
+        max_findings: settings.server.max_findings(),
+        on_missing_ip: settings.server.on_missing_ip,
+        max_forwarded_hops: settings.server.max_forwarded_hops,
+        max_concurrent_requests: settings.server.max_concurrent_requests,
+        cidr_limits: settings.server.cidr_limits(),
+        usage: usage_accounting,
+        decision_log: Arc::new(DecisionLog::new(settings.decision_log.sample_rate)),
+        monitor_override: MonitorOverride::default(),
+        challenges,
+        aggregates: Arc::new(LookupAggregates::new()),
+        blocked_metric: settings.telemetry.blocked_metric,
+        connectivity: Arc::new(ConnectivityChecker::new(http_client, connectivity_targets)),
+        vpn_detector,
+        proxy_detector,
+        debug_capture: Arc::new(DebugCapture::new()),
     };
     
+    // Warm the lookup cache once the first tree is in place
+    if let Some(path) = settings.cache_warming.file.clone() {
+        let warming_config = CacheWarmingConfig {
+            path,
+            rate_per_sec: settings.cache_warming.rate_per_sec,
+            concurrency: settings.cache_warming.concurrency,
+        };
+        let ip_lookup_service = Arc::clone(&state.ip_lookup_service);
+        cache_warming::spawn_warming(
+            Arc::new(handlers::lookup_service(&state)),
+            warming_config,
+            async move { ip_lookup_service.wait_until_loaded().await },
+        );
+    }
+
     // Create the main application router
     let app = create_router(state);
     
     // Create the metrics router
-    let metrics_router = metrics_routes();
+    let metrics_router = metrics_routes(settings.server.metrics_token.as_deref());
     
     // Combine both routers
     let app = app.merge(metrics_router);
 
-    // Run the server
-    let addr = settings.server_addr();
-    let listener = TcpListener::bind(addr).await?;
-    
-    tracing::info!("listening on {}", addr);
-    
-    // Use into_make_service_with_connect_info to enable ConnectInfo
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
-    )
-    .await?;
+    // Run the server on every listen address; a bind failure on any of
+    // them stops startup
+    let addrs = listen::resolve(&settings.server.listen_addrs()).await?;
+    let listeners = listen::bind(&addrs)?;
+    for addr in &addrs {
+        tracing::info!("listening on {}", addr);
+    }
+    listen::serve(listeners, app).await?;
 
     Ok(())
 }
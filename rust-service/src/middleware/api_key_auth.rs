@@ -4,61 +4,23 @@ use axum::{
     http::{Request, StatusCode},
     middleware::Next,
     response::Response,
-    extract::State,
+    extract::{OriginalUri, State},
 };
-use thiserror::Error;
-use serde::{Deserialize, Serialize};
 use crate::clients::web_api::{WebApiClient, WebApiError};
+use crate::config::runtime::SharedRuntimeConfig;
+use crate::models::auth::AuthenticatedUser;
+use crate::services::profiles::ProfileName;
+use crate::services::usage::{self, EndpointClass, UsageAccounting};
 use log::{info, warn, error};
 
-#[derive(Debug, Error)]
-pub enum ApiKeyAuthError {
-    #[error("API key is missing")]
-    MissingApiKey,
-    
-    #[error("Invalid API key")]
-    InvalidApiKey,
-    
-    #[error("Service unavailable: {0}")]
-    ServiceUnavailable(String),
-}
-
-impl From<WebApiError> for ApiKeyAuthError {
-    fn from(err: WebApiError) -> Self {
-        match err {
-            WebApiError::ValidationError(msg) => {
-                warn!("API key validation failed: {}", msg);
-                ApiKeyAuthError::InvalidApiKey
-            },
-            WebApiError::RequestError(e) => {
-                let status = e.status().map(|s| s.to_string()).unwrap_or_else(|| "unknown".to_string());
-                let error_msg = format!("Request failed: {} (status: {})", e, status);
-                error!("Service unavailable: {}", error_msg);
-                ApiKeyAuthError::ServiceUnavailable(error_msg)
-            },
-            WebApiError::ServiceUnavailable(msg) => {
-                error!("Service unavailable: {}", msg);
-                ApiKeyAuthError::ServiceUnavailable(msg)
-            },
-            WebApiError::ResilientClientError(e) => {
-                error!("Resilient client error: {}", e);
-                ApiKeyAuthError::ServiceUnavailable(format!("Resilient client error: {}", e))
-            }
-        }
-    }
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct AuthenticatedUser {
-    pub user_id: Option<String>,
-    pub email: Option<String>,
-    pub role: Option<String>,
-}
-
 #[derive(Debug, Clone)]
 pub struct ApiKeyAuthState {
     pub web_api_client: Arc<WebApiClient>,
     pub unlimited_api_keys: HashSet<String>,
+    /// Profiles are resolved from the current runtime config on each request
+    pub runtime: SharedRuntimeConfig,
+    /// Counts each authenticated request for billing
+    pub usage: Arc<UsageAccounting>,
 }
 
 pub async fn api_key_auth(
@@ -122,7 +84,23 @@ pub async fn api_key_auth(
         validation.role.as_deref().unwrap_or("unknown")
     );
 
-    // Attach user information to the request extensions
+    // Nested routers see the path without their prefix
+    let path = req
+        .extensions()
+        .get::<OriginalUri>()
+        .map_or_else(|| req.uri().clone(), |original| original.0.clone());
+
+    // Attach user information and the caller's scoring profile to the request extensions
+    let profile = state.runtime.load().profiles.resolve(validation.role.as_deref());
+    req.extensions_mut().insert(ProfileName(profile.key.clone()));
+
+    // Unlimited keys have no user ID, so they are counted under a key hash
+    state.usage.record(
+        &usage::usage_key(validation.user_id.as_deref(), &api_key),
+        EndpointClass::from_path(path.path()),
+        is_unlimited_key,
+    );
+
     let user = AuthenticatedUser {
         user_id: validation.user_id,
         email: validation.email,
@@ -0,0 +1,45 @@
+//! Deprecation headers for the unversioned `/api/...` aliases.
+//!
+//! The API is served under `/api/v1`; the unprefixed paths answer the same
+//! way for existing consumers, with `Deprecation`, `Sunset` and a `Link` to
+//! the versioned path, so clients can find out before the aliases go away.
+
+use axum::{
+    extract::{OriginalUri, Request, State},
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use chrono::NaiveDate;
+
+/// Prefix of the versioned routes the aliases point to
+pub const CURRENT_PREFIX: &str = "/api/v1";
+
+const DEPRECATION: HeaderName = HeaderName::from_static("deprecation");
+const SUNSET: HeaderName = HeaderName::from_static("sunset");
+
+/// Mark a response from an unversioned alias as deprecated in favour of the
+/// `/api/v1` path, which goes away at the start of `sunset` (UTC)
+pub async fn legacy_alias(State(sunset): State<NaiveDate>, request: Request, next: Next) -> Response {
+    // Nested routers see the path without `/api`; the link needs all of it
+    let uri = request
+        .extensions()
+        .get::<OriginalUri>()
+        .map_or_else(|| request.uri().clone(), |original| original.0.clone());
+    let mut response = next.run(request).await;
+
+    let rest = uri.path().strip_prefix("/api").unwrap_or(uri.path());
+    let query = uri.query().map(|query| format!("?{}", query)).unwrap_or_default();
+    let link = format!("<{}{}{}>; rel=\"successor-version\"", CURRENT_PREFIX, rest, query);
+    let sunset = sunset.and_time(chrono::NaiveTime::MIN).format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+
+    let headers = response.headers_mut();
+    headers.insert(DEPRECATION, HeaderValue::from_static("true"));
+    if let Ok(sunset) = HeaderValue::from_str(&sunset) {
+        headers.insert(SUNSET, sunset);
+    }
+    if let Ok(link) = HeaderValue::from_str(&link) {
+        headers.append(axum::http::header::LINK, link);
+    }
+    response
+}
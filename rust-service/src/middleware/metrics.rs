@@ -0,0 +1,92 @@
+//! Per-endpoint request counts and latencies.
+//!
+//! Requests are labelled with the matched route template rather than the
+//! request path, so `/api/lookup/8.8.8.8` and `/api/lookup/1.1.1.1` share
+//! the `/api/lookup/{ip}` series. Requests that match no route are grouped
+//! under [`UNMATCHED_PATH`].
+
+use std::time::Instant;
+
+use axum::{
+    extract::{MatchedPath, Request},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::monitoring;
+
+/// `path` label of requests that matched no route
+pub const UNMATCHED_PATH: &str = "unmatched";
+
+/// Record `http_requests_total` and `http_request_duration_seconds`
+pub async fn track_metrics(request: Request, next: Next) -> Response {
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|matched| matched.as_str().to_string())
+        .unwrap_or_else(|| UNMATCHED_PATH.to_string());
+    let method = request.method().clone();
+
+    let started = Instant::now();
+    let response = next.run(request).await;
+    monitoring::record_http_request(
+        &path,
+        method.as_str(),
+        response.status().as_u16(),
+        started.elapsed(),
+    );
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitoring::HTTP_REQUESTS_TOTAL;
+    use crate::routes::create_router;
+    use crate::test_support;
+    use axum::body::Body;
+    use axum::http::{Method, StatusCode};
+    use tower::ServiceExt;
+
+    fn requests(path: &str, method: &str, status: &str) -> u64 {
+        HTTP_REQUESTS_TOTAL.with_label_values(&[path, method, status]).get()
+    }
+
+    async fn send(method: Method, uri: &str) -> StatusCode {
+        let router = create_router(test_support::app_state());
+        let request = test_support::request().method(method).uri(uri).body(Body::empty()).unwrap();
+        router.oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_requests_labelled_by_route_template() {
+        let before = requests("/api/tor/{ip_or_range}", "GET", "200");
+
+        assert_eq!(send(Method::GET, "/api/tor/9.9.9.9").await, StatusCode::OK);
+        assert_eq!(send(Method::GET, "/api/tor/9.9.9.10").await, StatusCode::OK);
+
+        assert!(requests("/api/tor/{ip_or_range}", "GET", "200") >= before + 2);
+        let families = prometheus::gather();
+        let labels = families
+            .iter()
+            .filter(|family| family.name() == "http_requests_total")
+            .flat_map(|family| family.get_metric())
+            .flat_map(|metric| metric.get_label())
+            .map(|label| label.value().to_string())
+            .collect::<Vec<_>>();
+        assert!(!labels.iter().any(|value| value.contains("9.9.9.9")));
+    }
+
+    #[tokio::test]
+    async fn test_unmatched_and_error_statuses_recorded() {
+        let unmatched = requests(UNMATCHED_PATH, "GET", "404");
+        let bad_request = requests("/api/lookup/{ip}", "GET", "400");
+
+        assert_eq!(send(Method::GET, "/api/no-such-route/1").await, StatusCode::NOT_FOUND);
+        assert_eq!(send(Method::GET, "/api/lookup/not-an-ip").await, StatusCode::BAD_REQUEST);
+
+        assert!(requests(UNMATCHED_PATH, "GET", "404") > unmatched);
+        assert!(requests("/api/lookup/{ip}", "GET", "400") > bad_request);
+    }
+}
@@ -0,0 +1,4 @@
+pub mod api_key_auth;
+pub mod deprecation;
+pub mod metrics;
+pub mod problem;
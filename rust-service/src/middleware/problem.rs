@@ -0,0 +1,164 @@
+//! RFC 9457 `application/problem+json` error bodies.
+//!
+//! [`AppError`](crate::errors::AppError) responses carry a [`Problem`]
+//! extension. When the request's `Accept` header lists
+//! `application/problem+json`, this middleware rewrites those responses into
+//! problem documents, filling in `instance` from the request path. Other
+//! clients keep the plain `{"error": ...}` body.
+
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, HeaderMap, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use serde::Serialize;
+
+use crate::errors::Problem;
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+/// Prefix of every problem `type` URI
+pub const PROBLEM_TYPE_BASE: &str = "https://infralock.dev/errors/";
+/// Request id header echoed into the problem document when present
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+#[derive(Debug, Serialize)]
+struct ProblemDocument<'a> {
+    #[serde(rename = "type")]
+    problem_type: String,
+    title: &'a str,
+    status: u16,
+    detail: &'a str,
+    instance: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+/// Whether `Accept` lists `application/problem+json` as one of its media ranges
+fn accepts_problem_json(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|range| range.split(';').next())
+        .any(|media_type| media_type.trim().eq_ignore_ascii_case(PROBLEM_JSON))
+}
+
+/// Rewrite [`AppError`](crate::errors::AppError) responses as problem+json
+/// for clients that ask for it
+pub async fn problem_json(request: Request, next: Next) -> Response {
+    if !accepts_problem_json(request.headers()) {
+        return next.run(request).await;
+    }
+
+    let instance = request.uri().path().to_string();
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+
+    let response = next.run(request).await;
+    let Some(problem) = response.extensions().get::<Problem>().cloned() else {
+        return response;
+    };
+
+    let (mut parts, _) = response.into_parts();
+    let document = ProblemDocument {
+        problem_type: format!("{}{}", PROBLEM_TYPE_BASE, problem.slug),
+        title: problem.title,
+        status: parts.status.as_u16(),
+        detail: &problem.detail,
+        instance,
+        request_id,
+    };
+    let body = match serde_json::to_vec(&document) {
+        Ok(body) => body,
+        Err(e) => {
+            tracing::error!("Failed to serialize problem document: {}", e);
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+
+    parts.headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::create_router;
+    use crate::test_support;
+    use axum::http::{Method, StatusCode};
+    use axum::Router;
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    async fn send(router: &Router, method: Method, uri: &str, accept: Option<&str>) -> (StatusCode, String, Value) {
+        let mut request = test_support::request().method(method).uri(uri).header(REQUEST_ID_HEADER, "req-42");
+        if let Some(accept) = accept {
+            request = request.header(header::ACCEPT, accept);
+        }
+        let response = router.clone().oneshot(request.body(Body::empty()).unwrap()).await.unwrap();
+
+        let status = response.status();
+        let content_type = response.headers()[header::CONTENT_TYPE].to_str().unwrap().to_string();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, content_type, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[test]
+    fn test_accept_negotiation() {
+        let accepts = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, HeaderValue::from_str(value).unwrap());
+            accepts_problem_json(&headers)
+        };
+
+        assert!(accepts("application/problem+json"));
+        assert!(accepts("application/json, Application/Problem+JSON;q=0.9"));
+        assert!(!accepts("application/json"));
+        assert!(!accepts("*/*"));
+        assert!(!accepts_problem_json(&HeaderMap::new()));
+    }
+
+    #[tokio::test]
+    async fn test_error_variants_as_problem_json() {
+        let router = create_router(test_support::app_state());
+        let cases = [
+            (Method::GET, "/api/lookup/not-an-ip", StatusCode::BAD_REQUEST, "invalid-ip"),
+            (Method::GET, "/api/lookup/127.0.0.1", StatusCode::BAD_REQUEST, "invalid-ip"),
+            (Method::GET, "/api/lookup/8.8.8.8?fields=bogus", StatusCode::BAD_REQUEST, "bad-request"),
+            (Method::POST, "/api/admin/tree/rollback/9", StatusCode::NOT_FOUND, "snapshot-not-found"),
+        ];
+
+        for (method, uri, status, slug) in cases {
+            let (actual, content_type, body) = send(&router, method, uri, Some(PROBLEM_JSON)).await;
+            assert_eq!(actual, status, "{}", uri);
+            assert_eq!(content_type, PROBLEM_JSON);
+            assert_eq!(body["type"], format!("{}{}", PROBLEM_TYPE_BASE, slug));
+            assert_eq!(body["status"], status.as_u16());
+            assert_eq!(body["instance"], uri.split('?').next().unwrap());
+            assert_eq!(body["request_id"], "req-42");
+            assert!(body["title"].is_string());
+            assert!(body["detail"].is_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_plain_json_stays_default() {
+        let router = create_router(test_support::app_state());
+
+        for accept in [None, Some("application/json")] {
+            let (status, content_type, body) =
+                send(&router, Method::GET, "/api/lookup/not-an-ip", accept).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(content_type, "application/json");
+            assert!(body["error"].is_string());
+            assert!(body.get("type").is_none());
+        }
+    }
+}
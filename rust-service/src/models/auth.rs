@@ -0,0 +1,16 @@
+use serde::{Deserialize, Serialize};
+
+/// Caller resolved from the API key, attached as a request extension by the
+/// API key middleware
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthenticatedUser {
+    pub user_id: Option<String>,
+    pub email: Option<String>,
+    pub role: Option<String>,
+}
+
+impl AuthenticatedUser {
+    pub fn is_admin(&self) -> bool {
+        self.role.as_deref() == Some("admin")
+    }
+}
@@ -1,65 +1,117 @@
-use serde::{Deserialize, Serialize};
+//! Geo and ASN records, converted from the MaxMind database types. The
+//! records themselves live in `infralock-types`, shared with the client.
+
 use maxminddb::geoip2;
 
-impl From<geoip2::City<'_>> for GeoInfo {
-    fn from(city: geoip2::City<'_>) -> Self {
-        GeoInfo {
-            city: city.city.and_then(|c| {
-                c.names.and_then(|names| {
-                    names.get("en").map(|name| City {
-                        names: Some([("en".to_string(), name.to_string())].into_iter().collect()),
-                    })
-                })
-            }),
-            country: city.country.and_then(|c| {
-                c.names.and_then(|names| {
-                    names.get("en").map(|name| Country {
-                        names: Some([("en".to_string(), name.to_string())].into_iter().collect()),
-                    })
+pub use infralock_types::location::{AsnInfo, City, Country, GeoInfo, Location, NetworkTraits};
+
+pub fn geo_info(city: geoip2::City<'_>) -> GeoInfo {
+    GeoInfo {
+        city: city.city.and_then(|c| {
+            c.names.and_then(|names| {
+                names.get("en").map(|name| City {
+                    names: Some([("en".to_string(), name.to_string())].into_iter().collect()),
                 })
-            }),
-            location: city.location.map(|loc| Location {
-                latitude: loc.latitude,
-                longitude: loc.longitude,
-            }),
-        }
+            })
+        }),
+        country: city.country.and_then(|c| {
+            let names = c.names.as_ref().and_then(|names| names.get("en")).map(|name| {
+                [("en".to_string(), name.to_string())].into_iter().collect()
+            });
+            let iso_code = c.iso_code.map(str::to_string);
+            (names.is_some() || iso_code.is_some()).then_some(Country { names, iso_code })
+        }),
+        location: city.location.map(|loc| Location {
+            latitude: loc.latitude,
+            longitude: loc.longitude,
+        }),
+        traits: city.traits.as_ref().map(city_traits),
     }
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
-pub struct GeoInfo {
-    pub city: Option<City>,
-    pub country: Option<Country>,
-    pub location: Option<Location>,
-}
-
-#[derive(Debug, Deserialize, Serialize, Clone)]
-pub struct City {
-    pub names: Option<std::collections::HashMap<String, String>>,
+pub fn city_traits(traits: &geoip2::city::Traits) -> NetworkTraits {
+    NetworkTraits {
+        is_anonymous_proxy: traits.is_anonymous_proxy,
+        is_anycast: traits.is_anycast,
+        is_satellite_provider: traits.is_satellite_provider,
+        is_hosting_provider: None,
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
-pub struct Country {
-    pub names: Option<std::collections::HashMap<String, String>>,
+pub fn enterprise_traits(traits: &geoip2::enterprise::Traits<'_>) -> NetworkTraits {
+    NetworkTraits {
+        is_anonymous_proxy: traits.is_anonymous_proxy,
+        is_anycast: traits.is_anycast,
+        is_satellite_provider: traits.is_satellite_provider,
+        is_hosting_provider: traits.is_hosting_provider,
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
-pub struct Location {
-    pub latitude: Option<f64>,
-    pub longitude: Option<f64>,
+pub fn asn_info(asn: &geoip2::Asn<'_>) -> AsnInfo {
+    AsnInfo {
+        autonomous_system_number: asn.autonomous_system_number,
+        autonomous_system_organization: asn.autonomous_system_organization.as_ref().map(|s| s.to_string()),
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
-pub struct AsnInfo {
-    pub autonomous_system_number: Option<u32>,
-    pub autonomous_system_organization: Option<String>,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-impl<'a> From<&geoip2::Asn<'a>> for AsnInfo {
-    fn from(asn: &geoip2::Asn<'a>) -> Self {
-        AsnInfo {
-            autonomous_system_number: asn.autonomous_system_number,
-            autonomous_system_organization: asn.autonomous_system_organization.as_ref().map(|s| s.to_string()),
+    fn city_with_traits(traits: Option<geoip2::city::Traits>) -> geoip2::City<'static> {
+        geoip2::City {
+            city: None,
+            continent: None,
+            country: None,
+            location: None,
+            postal: None,
+            registered_country: None,
+            represented_country: None,
+            subdivisions: None,
+            traits,
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_city_without_traits() {
+        let geo = geo_info(city_with_traits(None));
+        assert!(geo.traits.is_none());
+    }
+
+    #[test]
+    fn test_city_traits_flags() {
+        let geo = geo_info(city_with_traits(Some(geoip2::city::Traits {
+            is_anonymous_proxy: Some(true),
+            is_anycast: None,
+            is_satellite_provider: Some(false),
+        })));
+        let traits = geo.traits.unwrap();
+        assert!(traits.is_anonymous_proxy());
+        assert!(!traits.is_anycast());
+        assert_eq!(traits.is_anycast, None);
+        assert_eq!(traits.is_satellite_provider, Some(false));
+        // City databases never carry the hosting flag
+        assert_eq!(traits.is_hosting_provider, None);
+
+        let geo = geo_info(city_with_traits(Some(geoip2::city::Traits {
+            is_anonymous_proxy: None,
+            is_anycast: Some(true),
+            is_satellite_provider: Some(true),
+        })));
+        let traits = geo.traits.unwrap();
+        assert!(!traits.is_anonymous_proxy());
+        assert!(traits.is_anycast());
+        assert_eq!(traits.is_satellite_provider, Some(true));
+    }
+
+    #[test]
+    fn test_traits_not_serialized_in_geo_info() {
+        let geo = geo_info(city_with_traits(Some(geoip2::city::Traits {
+            is_anonymous_proxy: Some(true),
+            is_anycast: Some(true),
+            is_satellite_provider: None,
+        })));
+        let json = serde_json::to_value(&geo).unwrap();
+        assert!(json.get("traits").is_none());
+    }
+}
@@ -1,2 +1,3 @@
+pub mod auth;
 pub mod location;
 pub mod threat_score;
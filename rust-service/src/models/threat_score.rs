@@ -1,15 +1,54 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::net::IpAddr;
 
+use regex::{RegexSet, RegexSetBuilder};
+use utoipa::ToSchema;
+
+use crate::config::ScoringSettings;
+use crate::models::location::{AsnInfo, NetworkTraits};
+
 /// Represents different types of threats that can contribute to the overall threat score
-#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, ToSchema)]
 pub enum ThreatType {
     VpnOrDatacenter,
     Proxy,
     TorExitNode,
+    /// Flagged as an anonymous proxy by the geo database itself
+    AnonymousProxy,
+    /// Flagged as a hosting provider by the geo database (Enterprise only)
+    HostingProvider,
+    /// Announced by an ASN whose organization matches `scoring.datacenter_asn_orgs`
+    Datacenter,
     // Add more threat types here as needed
 }
 
+impl ThreatType {
+    /// Fixed rank used to order findings, most severe first. Independent of
+    /// the configured weights, so every profile lists findings the same way.
+    pub fn severity(self) -> u8 {
+        match self {
+            ThreatType::TorExitNode => 6,
+            ThreatType::Proxy => 5,
+            ThreatType::AnonymousProxy => 4,
+            ThreatType::VpnOrDatacenter => 3,
+            ThreatType::Datacenter => 2,
+            ThreatType::HostingProvider => 1,
+        }
+    }
+
+    /// Snake-case name, as used in metric labels
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ThreatType::VpnOrDatacenter => "vpn_or_datacenter",
+            ThreatType::Proxy => "proxy",
+            ThreatType::TorExitNode => "tor_exit_node",
+            ThreatType::AnonymousProxy => "anonymous_proxy",
+            ThreatType::HostingProvider => "hosting_provider",
+            ThreatType::Datacenter => "datacenter",
+        }
+    }
+}
+
 /// Represents a single threat finding with its type and weight
 #[derive(Debug, Clone, Serialize)]
 pub struct ThreatFinding {
@@ -18,29 +57,167 @@ pub struct ThreatFinding {
     pub weight: f32,  // Weight between 0.0 and 1.0 indicating severity
 }
 
+/// How individual findings are combined into the 0-100 score
+///
+/// With `c_i = raw_weight_i * category_weight_i` for each finding:
+/// - `probabilistic`: `100 * (1 - Π(1 - c_i))`. A single finding scores its
+///   category weight (a VPN alone is 60), and each extra finding moves the
+///   score further toward 100 without reaching it.
+/// - `legacy` (alias `average`): `100 * Σc_i / Σcategory_weight_i`, a weighted
+///   average. Any single full-weight finding scores 100, so findings never
+///   compound.
+/// - `max`: `100 * max(c_i)`, the strongest finding alone.
+/// - `additive_capped`: `100 * min(Σc_i, 1)`. Stacked findings add up, so a
+///   VPN that is also a proxy or Tor node quickly reaches 100.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ScoringModel {
+    #[default]
+    Probabilistic,
+    #[serde(alias = "average")]
+    Legacy,
+    Max,
+    AdditiveCapped,
+}
+
 /// Configuration for threat scoring
 #[derive(Debug, Clone)]
 pub struct ThreatScoringConfig {
+    pub scoring_model: ScoringModel,
     pub vpn_weight: f32,
     pub proxy_weight: f32,
     pub tor_weight: f32,
+    pub anonymous_proxy_weight: f32,
+    pub hosting_provider_weight: f32,
+    pub datacenter_weight: f32,
+    /// Drop the VPN/datacenter finding for anycast networks (e.g. public DNS resolvers)
+    pub anycast_suppresses_vpn: bool,
+    /// ASN organizations flagged as datacenters
+    pub datacenter_asn_orgs: AsnOrgPatterns,
     // Add more weights for future threat types
 }
 
+/// Case-insensitive regexes matched anywhere in an ASN organization name,
+/// so a plain name such as `Hetzner` matches as a substring
+#[derive(Debug, Clone)]
+pub struct AsnOrgPatterns(RegexSet);
+
+impl AsnOrgPatterns {
+    pub fn new<I, S>(patterns: I) -> Result<Self, regex::Error>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        RegexSetBuilder::new(patterns).case_insensitive(true).build().map(Self)
+    }
+
+    /// The first pattern matching `organization`
+    pub fn matching(&self, organization: &str) -> Option<&str> {
+        let index = self.0.matches(organization).into_iter().next()?;
+        Some(&self.0.patterns()[index])
+    }
+}
+
+impl Default for AsnOrgPatterns {
+    fn default() -> Self {
+        Self(RegexSet::empty())
+    }
+}
+
 impl Default for ThreatScoringConfig {
     fn default() -> Self {
         Self {
+            scoring_model: ScoringModel::Probabilistic,
             vpn_weight: 0.6,    // High weight for VPN/Data center
             proxy_weight: 0.8,  // Higher weight for proxies
             tor_weight: 0.9,    // Very high weight for Tor exit nodes
+            anonymous_proxy_weight: 0.7,   // Database-reported anonymous proxy
+            hosting_provider_weight: 0.4,  // Hosting alone is only mildly suspicious
+            datacenter_weight: 0.5,        // Hosting ASN missing from the VPN feeds
+            anycast_suppresses_vpn: true,
+            datacenter_asn_orgs: AsnOrgPatterns::default(),
+        }
+    }
+}
+
+impl ThreatScoringConfig {
+    /// Configured weight for a threat category
+    pub fn category_weight(&self, threat_type: ThreatType) -> f32 {
+        match threat_type {
+            ThreatType::VpnOrDatacenter => self.vpn_weight,
+            ThreatType::Proxy => self.proxy_weight,
+            ThreatType::TorExitNode => self.tor_weight,
+            ThreatType::AnonymousProxy => self.anonymous_proxy_weight,
+            ThreatType::HostingProvider => self.hosting_provider_weight,
+            ThreatType::Datacenter => self.datacenter_weight,
+            // Add new threat types here
         }
     }
 }
 
+impl From<&ScoringSettings> for ThreatScoringConfig {
+    fn from(settings: &ScoringSettings) -> Self {
+        Self {
+            scoring_model: settings.scoring_model,
+            vpn_weight: settings.vpn_weight,
+            proxy_weight: settings.proxy_weight,
+            tor_weight: settings.tor_weight,
+            anonymous_proxy_weight: settings.anonymous_proxy_weight,
+            hosting_provider_weight: settings.hosting_provider_weight,
+            datacenter_weight: settings.datacenter_weight,
+            anycast_suppresses_vpn: settings.anycast_suppresses_vpn,
+            // Validation rejects invalid patterns before settings are applied
+            datacenter_asn_orgs: AsnOrgPatterns::new(&settings.datacenter_asn_orgs).unwrap_or_else(|e| {
+                tracing::error!("Ignoring scoring.datacenter_asn_orgs: {}", e);
+                AsnOrgPatterns::default()
+            }),
+        }
+    }
+}
+
+/// How a single finding contributed to the score
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct FindingContribution {
+    pub threat_type: ThreatType,
+    pub description: String,
+    /// Severity of the finding itself (0.0-1.0)
+    pub raw_weight: f32,
+    /// Configured weight for the finding's category
+    pub category_weight: f32,
+    /// `raw_weight * category_weight`
+    pub contribution: f32,
+}
+
+/// Step-by-step breakdown of a threat score computation
+///
+/// See [`ScoringModel`] for the formulas. All intermediate values are always
+/// reported; `normalized_score` is derived from the one the model uses.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ScoreExplanation {
+    pub scoring_model: ScoringModel,
+    pub findings: Vec<FindingContribution>,
+    /// `Σcontribution` (legacy numerator, additive_capped sum)
+    pub weighted_sum: f32,
+    /// `Σcategory_weight` (legacy denominator)
+    pub total_weight: f32,
+    /// `Π(1 - contribution)`, with each contribution clamped to 0.0-1.0
+    /// (probabilistic model)
+    pub miss_probability: f32,
+    /// `max(contribution)` (max model)
+    pub max_contribution: f32,
+    /// Model result on a 0-100 scale, before truncation (legacy) or rounding
+    /// (other models) and capping
+    pub normalized_score: f32,
+    /// Final 0-100 score
+    pub score: u8,
+}
+
 /// Calculates a threat score based on various threat findings
 #[derive(Debug, Clone, Serialize)]
 pub struct ThreatScore {
     pub score: u8,  // 0-100, higher is more suspicious
+    /// `score` before it is rounded to an integer, for callers that rank by it
+    pub score_precise: f32,
     pub findings: Vec<ThreatFinding>,
     pub ip: IpAddr,
 }
@@ -50,6 +227,7 @@ impl ThreatScore {
     pub fn new(ip: IpAddr) -> Self {
         Self {
             score: 0,
+            score_precise: 0.0,
             findings: Vec::new(),
             ip,
         }
@@ -62,51 +240,141 @@ impl ThreatScore {
     }*/
 
     /// Adds multiple threat findings and updates the score
-    pub fn add_findings(&mut self, findings: impl IntoIterator<Item = ThreatFinding>) {
+    ///
+    /// Findings are kept sorted by severity (highest first), then
+    /// description, and a repeated (type, description) pair is kept once
+    /// with its highest weight. The score and `threat_details` therefore do
+    /// not depend on the order findings were added in.
+    pub fn add_findings(
+        &mut self,
+        findings: impl IntoIterator<Item = ThreatFinding>,
+        config: &ThreatScoringConfig,
+    ) {
         self.findings.extend(findings);
-        self.calculate_score();
+        self.normalize_findings();
+        self.calculate_score(config);
+    }
+
+    /// Adds the findings for the IPv4 origin of a tunnelled address, each
+    /// noting the tunnel and the origin
+    pub fn add_tunnel_findings(
+        &mut self,
+        origin: ThreatScore,
+        tunnel: impl std::fmt::Display,
+        config: &ThreatScoringConfig,
+    ) {
+        let findings = origin.findings.into_iter().map(|finding| ThreatFinding {
+            description: format!("{} (via {} tunnel from {})", finding.description, tunnel, origin.ip),
+            ..finding
+        });
+        self.add_findings(findings, config);
+    }
+
+    fn normalize_findings(&mut self) {
+        self.findings.sort_by(|a, b| {
+            b.threat_type
+                .severity()
+                .cmp(&a.threat_type.severity())
+                .then_with(|| a.description.cmp(&b.description))
+                .then_with(|| b.weight.total_cmp(&a.weight))
+        });
+        // The heaviest of each duplicate run sorts first and is the one kept
+        self.findings.dedup_by(|later, kept| {
+            later.threat_type == kept.threat_type && later.description == kept.description
+        });
+    }
+
+    /// The at most `max` findings contributing the most to the score, in
+    /// their usual order, and whether any were left out. Ties keep the more
+    /// severe finding.
+    pub fn top_findings(&self, max: usize, config: &ThreatScoringConfig) -> (Vec<&ThreatFinding>, bool) {
+        if self.findings.len() <= max {
+            return (self.findings.iter().collect(), false);
+        }
+        let contribution = |finding: &ThreatFinding| finding.weight * config.category_weight(finding.threat_type);
+        let mut ranked: Vec<usize> = (0..self.findings.len()).collect();
+        // Stable, so equal contributions stay in severity order
+        ranked.sort_by(|&a, &b| contribution(&self.findings[b]).total_cmp(&contribution(&self.findings[a])));
+        ranked.truncate(max);
+        ranked.sort_unstable();
+        (ranked.into_iter().map(|index| &self.findings[index]).collect(), true)
     }
 
     /// Calculates the overall threat score based on all findings
-    fn calculate_score(&mut self) {
-        let config = ThreatScoringConfig::default();
+    fn calculate_score(&mut self, config: &ThreatScoringConfig) {
+        let explanation = self.explain(config);
+        self.score = explanation.score;
+        self.score_precise = explanation.normalized_score.clamp(0.0, 100.0);
+    }
+
+    /// Recomputes the score from the findings, recording each step
+    pub fn explain(&self, config: &ThreatScoringConfig) -> ScoreExplanation {
         let mut weighted_sum = 0.0;
         let mut total_weight = 0.0;
+        let mut miss_probability = 1.0;
+        let mut max_contribution: f32 = 0.0;
+        let mut contributions = Vec::with_capacity(self.findings.len());
 
         for finding in &self.findings {
-            let weight = match finding.threat_type {
-                ThreatType::VpnOrDatacenter => config.vpn_weight,
-                ThreatType::Proxy => config.proxy_weight,
-                ThreatType::TorExitNode => config.tor_weight,
-                // Add new threat types here
-            };
-            
-            weighted_sum += finding.weight * weight;
+            let weight = config.category_weight(finding.threat_type);
+            let contribution = finding.weight * weight;
+
+            weighted_sum += contribution;
             total_weight += weight;
+            miss_probability *= 1.0 - contribution.clamp(0.0, 1.0);
+            max_contribution = max_contribution.max(contribution);
+            contributions.push(FindingContribution {
+                threat_type: finding.threat_type,
+                description: finding.description.clone(),
+                raw_weight: finding.weight,
+                category_weight: weight,
+                contribution,
+            });
         }
 
         // Normalize the score to 0-100 range
-        let normalized_score = if total_weight > 0.0 {
-            (weighted_sum / total_weight * 100.0) as u8
-        } else {
-            0
+        let normalized_score = match config.scoring_model {
+            ScoringModel::Probabilistic => (1.0 - miss_probability) * 100.0,
+            ScoringModel::Legacy if total_weight > 0.0 => weighted_sum / total_weight * 100.0,
+            ScoringModel::Legacy => 0.0,
+            ScoringModel::Max => max_contribution * 100.0,
+            ScoringModel::AdditiveCapped => weighted_sum.min(1.0) * 100.0,
         };
 
-        self.score = normalized_score.min(100); // Cap at 100
+        ScoreExplanation {
+            scoring_model: config.scoring_model,
+            findings: contributions,
+            weighted_sum,
+            total_weight,
+            miss_probability,
+            max_contribution,
+            normalized_score,
+            score: match config.scoring_model {
+                ScoringModel::Legacy => normalized_score as u8,
+                _ => normalized_score.round() as u8,
+            }
+            .min(100), // Cap at 100
+        }
     }
 
     /// Creates a threat score from common IP information
+    ///
+    /// `traits` are the network flags reported by the geo database, if any.
     pub fn from_ip_info(
         ip: IpAddr,
         is_vpn: bool,
         is_proxy: bool,
         proxy_type: Option<&'static str>,
         is_tor: bool,
+        traits: Option<&NetworkTraits>,
+        config: &ThreatScoringConfig,
     ) -> Self {
         let mut score = Self::new(ip);
+        // Pushed in any order; `add_findings` sorts them
         let mut findings = Vec::new();
 
-        if is_vpn {
+        let is_anycast = traits.is_some_and(NetworkTraits::is_anycast);
+        if is_vpn && !(is_anycast && config.anycast_suppresses_vpn) {
             findings.push(ThreatFinding {
                 threat_type: ThreatType::VpnOrDatacenter,
                 description: "IP is associated with a VPN or data center".to_string(),
@@ -136,7 +404,482 @@ impl ThreatScore {
             });
         }
 
-        score.add_findings(findings);
+        if traits.is_some_and(NetworkTraits::is_anonymous_proxy) {
+            findings.push(ThreatFinding {
+                threat_type: ThreatType::AnonymousProxy,
+                description: "IP is flagged as an anonymous proxy by the geo database".to_string(),
+                weight: 1.0,
+            });
+        }
+
+        if traits.is_some_and(NetworkTraits::is_hosting_provider) {
+            findings.push(ThreatFinding {
+                threat_type: ThreatType::HostingProvider,
+                description: "IP belongs to a hosting provider".to_string(),
+                weight: 1.0,
+            });
+        }
+
+        score.add_findings(findings, config);
         score
     }
+
+    /// Adds a datacenter finding when the ASN's organization matches
+    /// `datacenter_asn_orgs`, for hosting networks the VPN feeds miss. An IP
+    /// already flagged by a feed is left alone.
+    pub fn add_asn_findings(&mut self, asn: Option<&AsnInfo>, config: &ThreatScoringConfig) {
+        if self.findings.iter().any(|f| f.threat_type == ThreatType::VpnOrDatacenter) {
+            return;
+        }
+        let Some(organization) = asn.and_then(|asn| asn.autonomous_system_organization.as_deref()) else {
+            return;
+        };
+        if config.datacenter_asn_orgs.matching(organization).is_some() {
+            self.add_findings(
+                [ThreatFinding {
+                    threat_type: ThreatType::Datacenter,
+                    description: format!("IP is announced by datacenter ASN organization {}", organization),
+                    weight: 1.0,
+                }],
+                config,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip() -> IpAddr {
+        "1.1.1.1".parse().unwrap()
+    }
+
+    fn traits(anonymous_proxy: bool, anycast: bool, hosting: bool) -> NetworkTraits {
+        NetworkTraits {
+            is_anonymous_proxy: Some(anonymous_proxy),
+            is_anycast: Some(anycast),
+            is_satellite_provider: None,
+            is_hosting_provider: Some(hosting),
+        }
+    }
+
+    fn types(score: &ThreatScore) -> Vec<ThreatType> {
+        score.findings.iter().map(|f| f.threat_type).collect()
+    }
+
+    #[test]
+    fn test_no_traits() {
+        let config = ThreatScoringConfig::default();
+        let score = ThreatScore::from_ip_info(ip(), true, false, None, false, None, &config);
+        assert_eq!(types(&score), vec![ThreatType::VpnOrDatacenter]);
+        assert_eq!(score.score, 60);
+    }
+
+    #[test]
+    fn test_anonymous_proxy_and_hosting_findings() {
+        let config = ThreatScoringConfig::default();
+        let traits = traits(true, false, true);
+        let score = ThreatScore::from_ip_info(ip(), false, false, None, false, Some(&traits), &config);
+        assert_eq!(
+            types(&score),
+            vec![ThreatType::AnonymousProxy, ThreatType::HostingProvider]
+        );
+        assert!(score.score > 0);
+
+        let clean = NetworkTraits::default();
+        let score = ThreatScore::from_ip_info(ip(), false, false, None, false, Some(&clean), &config);
+        assert!(score.findings.is_empty());
+        assert_eq!(score.score, 0);
+    }
+
+    #[test]
+    fn test_anycast_suppresses_vpn() {
+        let config = ThreatScoringConfig::default();
+        let traits = traits(false, true, false);
+        let score = ThreatScore::from_ip_info(ip(), true, false, None, false, Some(&traits), &config);
+        assert!(score.findings.is_empty());
+        assert_eq!(score.score, 0);
+
+        // Anycast only suppresses the VPN finding, not other findings
+        let score = ThreatScore::from_ip_info(ip(), true, true, Some("http"), false, Some(&traits), &config);
+        assert_eq!(types(&score), vec![ThreatType::Proxy]);
+    }
+
+    #[test]
+    fn test_anycast_suppression_disabled() {
+        let config = ThreatScoringConfig {
+            anycast_suppresses_vpn: false,
+            ..ThreatScoringConfig::default()
+        };
+        let traits = traits(false, true, false);
+        let score = ThreatScore::from_ip_info(ip(), true, false, None, false, Some(&traits), &config);
+        assert_eq!(types(&score), vec![ThreatType::VpnOrDatacenter]);
+    }
+
+    fn with_model(scoring_model: ScoringModel) -> ThreatScoringConfig {
+        ThreatScoringConfig {
+            scoring_model,
+            ..ThreatScoringConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_datacenter_asn_orgs() {
+        let config = ThreatScoringConfig {
+            datacenter_asn_orgs: AsnOrgPatterns::new(["DigitalOcean", r"^OVH\b", "Hetzner Online"]).unwrap(),
+            ..ThreatScoringConfig::default()
+        };
+        let asn = |organization: &str| AsnInfo {
+            autonomous_system_number: Some(64500),
+            autonomous_system_organization: Some(organization.to_string()),
+        };
+
+        let mut score = ThreatScore::from_ip_info(ip(), false, false, None, false, None, &config);
+        score.add_asn_findings(Some(&asn("DIGITALOCEAN-ASN")), &config);
+        assert_eq!(types(&score), vec![ThreatType::Datacenter]);
+        assert_eq!(score.score, 50);
+
+        for organization in ["OVH SAS", "hetzner online gmbh"] {
+            let mut score = ThreatScore::from_ip_info(ip(), false, false, None, false, None, &config);
+            score.add_asn_findings(Some(&asn(organization)), &config);
+            assert_eq!(types(&score), vec![ThreatType::Datacenter], "{}", organization);
+        }
+
+        // Unmatched, anchored elsewhere, or without ASN data
+        for asn_info in [Some(asn("Comcast")), Some(asn("NOT OVH")), None] {
+            let mut score = ThreatScore::from_ip_info(ip(), false, false, None, false, None, &config);
+            score.add_asn_findings(asn_info.as_ref(), &config);
+            assert!(score.findings.is_empty());
+        }
+
+        // A feed match already covers the datacenter
+        let mut score = ThreatScore::from_ip_info(ip(), true, false, None, false, None, &config);
+        score.add_asn_findings(Some(&asn("DigitalOcean, LLC")), &config);
+        assert_eq!(types(&score), vec![ThreatType::VpnOrDatacenter]);
+    }
+
+    fn legacy() -> ThreatScoringConfig {
+        with_model(ScoringModel::Legacy)
+    }
+
+    /// Score for a combination of (vpn, proxy, tor) findings
+    fn combined(vpn: bool, proxy: bool, tor: bool, config: &ThreatScoringConfig) -> u8 {
+        let proxy_type = proxy.then_some("socks5");
+        ThreatScore::from_ip_info(ip(), vpn, proxy, proxy_type, tor, None, config).score
+    }
+
+    #[test]
+    fn test_probabilistic_single_vs_combined() {
+        let config = ThreatScoringConfig::default();
+
+        // A single finding scores its category weight
+        assert_eq!(combined(true, false, false, &config), 60);
+        assert_eq!(combined(false, true, false, &config), 80);
+        assert_eq!(combined(false, false, true, &config), 90);
+
+        // Findings compound toward, but never reach, 100
+        let vpn_proxy = combined(true, true, false, &config);
+        assert_eq!(vpn_proxy, 92);
+        let all = combined(true, true, true, &config);
+        assert_eq!(all, 99);
+        assert!(vpn_proxy > combined(false, true, false, &config));
+        assert!(all > vpn_proxy);
+
+        // Weak database findings give moderate scores on their own
+        let hosting = traits(false, false, true);
+        let score = ThreatScore::from_ip_info(ip(), false, false, None, false, Some(&hosting), &config);
+        assert!((30..=50).contains(&score.score), "hosting alone scored {}", score.score);
+    }
+
+    #[test]
+    fn test_precise_score_before_rounding() {
+        let hosting = traits(false, false, true);
+        for config in [ThreatScoringConfig::default(), legacy(), with_model(ScoringModel::AdditiveCapped)] {
+            for (vpn, proxy, tor) in [(false, false, false), (true, false, false), (true, true, true)] {
+                let proxy_type = proxy.then_some("socks5");
+                let score = ThreatScore::from_ip_info(ip(), vpn, proxy, proxy_type, tor, Some(&hosting), &config);
+                let expected = match config.scoring_model {
+                    ScoringModel::Legacy => score.score_precise.trunc(),
+                    _ => score.score_precise.round(),
+                };
+                assert_eq!(expected as u8, score.score);
+                assert!((0.0..=100.0).contains(&score.score_precise));
+            }
+        }
+
+        // Combined findings keep their fractional part
+        let config = ThreatScoringConfig::default();
+        let score = ThreatScore::from_ip_info(ip(), true, false, None, false, Some(&hosting), &config);
+        assert_eq!(score.score_precise, score.explain(&config).normalized_score);
+    }
+
+    #[test]
+    fn test_legacy_model_averages() {
+        let config = legacy();
+        assert_eq!(combined(true, false, false, &config), 100);
+        assert_eq!(combined(false, false, true, &config), 100);
+        assert_eq!(combined(true, true, true, &config), 100);
+        assert_eq!(combined(false, false, false, &config), 0);
+    }
+
+    #[test]
+    fn test_max_model_takes_strongest_finding() {
+        let config = with_model(ScoringModel::Max);
+        assert_eq!(combined(true, false, false, &config), 60);
+        assert_eq!(combined(true, true, false, &config), 80);
+        assert_eq!(combined(true, true, true, &config), 90);
+        assert_eq!(combined(false, false, false, &config), 0);
+    }
+
+    #[test]
+    fn test_additive_capped_model_stacks_findings() {
+        let config = with_model(ScoringModel::AdditiveCapped);
+        let vpn = combined(true, false, false, &config);
+        let proxy = combined(false, true, false, &config);
+        let tor = combined(false, false, true, &config);
+        assert_eq!((vpn, proxy, tor), (60, 80, 90));
+
+        let all = combined(true, true, true, &config);
+        assert_eq!(all, 100);
+        assert!(all > vpn.max(proxy).max(tor));
+
+        // Weak findings add without hitting the cap
+        let hosting = traits(false, false, true);
+        let score = ThreatScore::from_ip_info(ip(), true, false, None, false, Some(&hosting), &config);
+        assert_eq!(score.score, 100);
+        let score = ThreatScore::from_ip_info(ip(), false, false, None, false, Some(&hosting), &config);
+        assert_eq!(score.score, 40);
+    }
+
+    #[test]
+    fn test_scoring_model_serde() {
+        assert_eq!(ScoringModel::default(), ScoringModel::Probabilistic);
+        let model: ScoringModel = serde_json::from_str("\"legacy\"").unwrap();
+        assert_eq!(model, ScoringModel::Legacy);
+        let model: ScoringModel = serde_json::from_str("\"average\"").unwrap();
+        assert_eq!(model, ScoringModel::Legacy);
+        let model: ScoringModel = serde_json::from_str("\"additive_capped\"").unwrap();
+        assert_eq!(model, ScoringModel::AdditiveCapped);
+        assert_eq!(serde_json::to_value(ScoringModel::Probabilistic).unwrap(), "probabilistic");
+        assert_eq!(serde_json::to_value(ScoringModel::Max).unwrap(), "max");
+    }
+
+    #[test]
+    fn test_explanation_matches_score() {
+        for model in [
+            ScoringModel::Probabilistic,
+            ScoringModel::Legacy,
+            ScoringModel::Max,
+            ScoringModel::AdditiveCapped,
+        ] {
+            let config = with_model(model);
+            let traits = traits(true, false, true);
+            let score = ThreatScore::from_ip_info(ip(), true, true, Some("socks5"), true, Some(&traits), &config);
+            let explanation = score.explain(&config);
+
+            assert_eq!(explanation.scoring_model, config.scoring_model);
+            assert_eq!(explanation.findings.len(), score.findings.len());
+            let mut weighted_sum = 0.0;
+            let mut total_weight = 0.0;
+            let mut miss_probability = 1.0;
+            let mut max_contribution: f32 = 0.0;
+            for contribution in &explanation.findings {
+                assert_eq!(
+                    contribution.category_weight,
+                    config.category_weight(contribution.threat_type)
+                );
+                assert_eq!(contribution.contribution, contribution.raw_weight * contribution.category_weight);
+                weighted_sum += contribution.contribution;
+                total_weight += contribution.category_weight;
+                miss_probability *= 1.0 - contribution.contribution;
+                max_contribution = max_contribution.max(contribution.contribution);
+            }
+            assert_eq!(explanation.weighted_sum, weighted_sum);
+            assert_eq!(explanation.total_weight, total_weight);
+            assert_eq!(explanation.miss_probability, miss_probability);
+            assert_eq!(explanation.max_contribution, max_contribution);
+
+            let expected = match config.scoring_model {
+                ScoringModel::Probabilistic => (1.0 - miss_probability) * 100.0,
+                ScoringModel::Legacy => weighted_sum / total_weight * 100.0,
+                ScoringModel::Max => max_contribution * 100.0,
+                ScoringModel::AdditiveCapped => weighted_sum.min(1.0) * 100.0,
+            };
+            assert_eq!(explanation.normalized_score, expected);
+            assert_eq!(explanation.score, score.score);
+        }
+    }
+
+    #[test]
+    fn test_explanation_partial_weights() {
+        let config = legacy();
+        let mut score = ThreatScore::new(ip());
+        score.add_findings(
+            [
+                ThreatFinding {
+                    threat_type: ThreatType::VpnOrDatacenter,
+                    description: "partial".to_string(),
+                    weight: 0.5,
+                },
+                ThreatFinding {
+                    threat_type: ThreatType::Proxy,
+                    description: "full".to_string(),
+                    weight: 1.0,
+                },
+            ],
+            &config,
+        );
+
+        let explanation = score.explain(&config);
+        // (0.5 * 0.6 + 1.0 * 0.8) / (0.6 + 0.8) * 100 = 78.57...
+        assert!((explanation.weighted_sum - 1.1).abs() < 1e-6);
+        assert!((explanation.total_weight - 1.4).abs() < 1e-6);
+        assert!((explanation.normalized_score - 78.571_43).abs() < 1e-3);
+        assert_eq!(explanation.score, 78);
+        assert_eq!(score.score, 78);
+
+        // Probabilistic: 1 - (1 - 0.3) * (1 - 0.8) = 0.86
+        let config = ThreatScoringConfig::default();
+        let explanation = score.explain(&config);
+        assert!((explanation.miss_probability - 0.14).abs() < 1e-6);
+        assert_eq!(explanation.score, 86);
+    }
+
+    #[test]
+    fn test_explanation_empty() {
+        let config = ThreatScoringConfig::default();
+        let explanation = ThreatScore::new(ip()).explain(&config);
+        assert!(explanation.findings.is_empty());
+        assert_eq!(explanation.total_weight, 0.0);
+        assert_eq!(explanation.normalized_score, 0.0);
+        assert_eq!(explanation.score, 0);
+    }
+
+    fn finding(threat_type: ThreatType, description: &str, weight: f32) -> ThreatFinding {
+        ThreatFinding {
+            threat_type,
+            description: description.to_string(),
+            weight,
+        }
+    }
+
+    /// Every ordering of `items` (Heap's algorithm)
+    fn permutations<T: Clone>(items: &[T]) -> Vec<Vec<T>> {
+        fn permute<T: Clone>(k: usize, items: &mut Vec<T>, out: &mut Vec<Vec<T>>) {
+            if k <= 1 {
+                out.push(items.clone());
+                return;
+            }
+            for i in 0..k {
+                permute(k - 1, items, out);
+                let swap = if k.is_multiple_of(2) { i } else { 0 };
+                items.swap(swap, k - 1);
+            }
+        }
+        let mut out = Vec::new();
+        permute(items.len(), &mut items.to_vec(), &mut out);
+        out
+    }
+
+    fn details(score: &ThreatScore) -> Vec<(ThreatType, String, f32)> {
+        score
+            .findings
+            .iter()
+            .map(|f| (f.threat_type, f.description.clone(), f.weight))
+            .collect()
+    }
+
+    #[test]
+    fn test_findings_sorted_and_deduplicated() {
+        let config = ThreatScoringConfig::default();
+        let mut score = ThreatScore::new(ip());
+        score.add_findings(
+            [
+                finding(ThreatType::HostingProvider, "hosting", 1.0),
+                finding(ThreatType::Proxy, "b proxy", 0.5),
+                finding(ThreatType::TorExitNode, "tor", 1.0),
+                finding(ThreatType::Proxy, "a proxy", 1.0),
+                finding(ThreatType::Proxy, "b proxy", 0.9),
+            ],
+            &config,
+        );
+        assert_eq!(
+            details(&score),
+            vec![
+                (ThreatType::TorExitNode, "tor".to_string(), 1.0),
+                (ThreatType::Proxy, "a proxy".to_string(), 1.0),
+                (ThreatType::Proxy, "b proxy".to_string(), 0.9),
+                (ThreatType::HostingProvider, "hosting".to_string(), 1.0),
+            ]
+        );
+
+        // Findings added later are merged into the same order
+        score.add_findings([finding(ThreatType::VpnOrDatacenter, "vpn", 1.0)], &config);
+        assert_eq!(types(&score)[3], ThreatType::VpnOrDatacenter);
+    }
+
+    #[test]
+    fn test_top_findings_by_contribution() {
+        let config = ThreatScoringConfig::default();
+        let mut score = ThreatScore::new(ip());
+        score.add_findings(
+            [
+                finding(ThreatType::HostingProvider, "hosting", 1.0),
+                finding(ThreatType::Proxy, "proxy", 0.5),
+                finding(ThreatType::VpnOrDatacenter, "vpn", 1.0),
+                finding(ThreatType::TorExitNode, "tor", 1.0),
+            ],
+            &config,
+        );
+        let top = |max| {
+            let (findings, truncated) = score.top_findings(max, &config);
+            (findings.iter().map(|f| f.description.as_str()).collect::<Vec<_>>(), truncated)
+        };
+
+        // Proxy at half weight counts less than a full VPN, but the listing keeps severity order
+        assert_eq!(top(2), (vec!["tor", "vpn"], true));
+        // Proxy and hosting both contribute 0.4; the more severe one is kept
+        assert_eq!(top(3), (vec!["tor", "proxy", "vpn"], true));
+        assert_eq!(top(4), (vec!["tor", "proxy", "vpn", "hosting"], false));
+    }
+
+    #[test]
+    fn test_findings_order_insensitive() {
+        let findings = vec![
+            finding(ThreatType::VpnOrDatacenter, "vpn", 1.0),
+            finding(ThreatType::Proxy, "socks5 proxy", 0.7),
+            finding(ThreatType::Proxy, "socks5 proxy", 1.0),
+            finding(ThreatType::Proxy, "http proxy", 0.4),
+            finding(ThreatType::AnonymousProxy, "anonymous", 1.0),
+            finding(ThreatType::HostingProvider, "hosting", 0.3),
+        ];
+
+        for model in [
+            ScoringModel::Probabilistic,
+            ScoringModel::Legacy,
+            ScoringModel::Max,
+            ScoringModel::AdditiveCapped,
+        ] {
+            let config = with_model(model);
+            let mut expected = ThreatScore::new(ip());
+            expected.add_findings(findings.clone(), &config);
+            assert_eq!(expected.findings.len(), 5);
+
+            for ordering in permutations(&findings) {
+                // Also split across two calls, as a multi-source lookup would
+                let (first, rest) = ordering.split_at(ordering.len() / 2);
+                let mut score = ThreatScore::new(ip());
+                score.add_findings(first.to_vec(), &config);
+                score.add_findings(rest.to_vec(), &config);
+
+                assert_eq!(score.score, expected.score, "{:?}: {:?}", model, ordering);
+                assert_eq!(details(&score), details(&expected));
+                assert_eq!(
+                    serde_json::to_string(&score.explain(&config)).unwrap(),
+                    serde_json::to_string(&expected.explain(&config)).unwrap()
+                );
+            }
+        }
+    }
 }
@@ -1,5 +1,5 @@
 use lazy_static::lazy_static;
-use prometheus::{self, Encoder, Histogram, IntCounter, IntCounterVec, register_int_counter, register_int_counter_vec, register_histogram};
+use prometheus::{self, Encoder, Histogram, HistogramVec, IntCounter, IntCounterVec, register_int_counter, register_int_counter_vec, register_histogram, register_histogram_vec};
 
 lazy_static! {
     // API Key Validation Metrics
@@ -46,6 +46,59 @@ lazy_static! {
         "cache_misses_total",
         "Total number of cache misses"
     ).unwrap();
+
+    // Geo/ASN database lookups that failed and were left out of the response
+    pub static ref GEO_LOOKUP_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "geo_lookup_errors_total",
+        "Total number of failed geo database lookups, by database (geo or asn)",
+        &["database"]
+    ).unwrap();
+
+    // Shared Redis cache commands that failed or timed out, leaving the lookup to the local cache
+    pub static ref SHARED_CACHE_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "shared_cache_errors_total",
+        "Total number of failed shared cache operations, by operation (get, set, invalidate or subscribe)",
+        &["operation"]
+    ).unwrap();
+
+    // IP Range Feed Metrics
+    pub static ref FEED_UPDATES_REJECTED: IntCounterVec = register_int_counter_vec!(
+        "ip_feed_updates_rejected_total",
+        "Total number of IP range updates rejected by the feed sanity check, by source",
+        &["source"]
+    ).unwrap();
+
+    // HTTP Endpoint Metrics; `path` is the route template, e.g. `/api/lookup/{ip}`
+    pub static ref HTTP_REQUESTS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "http_requests_total",
+        "Total number of HTTP requests by route, method and status",
+        &["path", "method", "status"]
+    ).unwrap();
+
+    pub static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "http_request_duration_seconds",
+        "The duration of HTTP requests in seconds, by route",
+        &["path"]
+    ).unwrap();
+
+    pub static ref PARSE_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "ip_ranges_parse_errors_total",
+        "Total number of malformed entries skipped while parsing IP range sources, by source",
+        &["source"]
+    ).unwrap();
+
+    pub static ref CATEGORY_CONFLICTS: IntCounterVec = register_int_counter_vec!(
+        "ip_ranges_category_conflicts_total",
+        "Total number of networks found listed with conflicting categories across tree builds, by kind (identical or nested)",
+        &["kind"]
+    ).unwrap();
+
+    // Lookups answered with `block`; `country` is an ISO 3166 code or `unknown`
+    pub static ref BLOCKED_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "blocked_total",
+        "Total number of lookups answered with a block action, by country and reason",
+        &["country", "reason"]
+    ).unwrap();
 }
 
 /// Record API key validation metrics
@@ -83,6 +136,47 @@ pub fn record_cache_miss() {
     CACHE_MISSES.inc();
 }
 
+/// Record an IP range update rejected by the feed sanity check
+pub fn record_feed_update_rejected(source: &str) {
+    FEED_UPDATES_REJECTED.with_label_values(&[source]).inc();
+}
+
+/// Record entries of a source that failed to parse
+pub fn record_parse_errors(source: &str, count: usize) {
+    PARSE_ERRORS.with_label_values(&[source]).inc_by(count as u64);
+}
+
+/// Record the category conflicts found while building a tree
+pub fn record_category_conflicts(identical: usize, nested: usize) {
+    CATEGORY_CONFLICTS.with_label_values(&["identical"]).inc_by(identical as u64);
+    CATEGORY_CONFLICTS.with_label_values(&["nested"]).inc_by(nested as u64);
+}
+
+/// Record a lookup answered with `block`. Anything but a two-letter country
+/// code is counted as `unknown`, so the label stays bounded whatever the geo
+/// database returns.
+pub fn record_blocked(country: Option<&str>, reason: &str) {
+    let country = country_label(country);
+    BLOCKED_TOTAL.with_label_values(&[&country, reason]).inc();
+}
+
+fn country_label(country: Option<&str>) -> String {
+    match country {
+        Some(code) if code.len() == 2 && code.bytes().all(|b| b.is_ascii_alphabetic()) => code.to_ascii_uppercase(),
+        _ => "unknown".to_string(),
+    }
+}
+
+/// Record a handled HTTP request
+pub fn record_http_request(path: &str, method: &str, status: u16, duration: std::time::Duration) {
+    HTTP_REQUESTS_TOTAL
+        .with_label_values(&[path, method, &status.to_string()])
+        .inc();
+    HTTP_REQUEST_DURATION
+        .with_label_values(&[path])
+        .observe(duration.as_secs_f64());
+}
+
 /// Collect all metrics for Prometheus
 pub fn gather_metrics() -> Vec<u8> {
     let mut buffer = vec![];
@@ -90,4 +184,17 @@ pub fn gather_metrics() -> Vec<u8> {
     let metric_families = prometheus::gather();
     encoder.encode(&metric_families, &mut buffer).unwrap();
     buffer
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_country_label_is_bounded() {
+        assert_eq!(country_label(Some("DE")), "DE");
+        assert_eq!(country_label(Some("us")), "US");
+        for code in [None, Some(""), Some("-"), Some("USA"), Some("A1"), Some("ÄÖ")] {
+            assert_eq!(country_label(code), "unknown", "{:?}", code);
+        }
+    }
+}
@@ -0,0 +1,138 @@
+//! Contract tests for `infralock-client`: the real router on a local port,
+//! called through every client method, so a response type the client can no
+//! longer read fails here rather than in a user's deployment.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use axum::extract::Request;
+use axum::http::HeaderValue;
+use infralock_client::{Client, Error, ShardedLookup};
+
+use super::create_router;
+use crate::ip_lookup::IpCategory;
+use crate::test_support::{self, mmdb::{FIXTURE_DE_IP, FIXTURE_US_IP}};
+
+/// The address the fronting proxy reports for every request
+const CALLER_IP: &str = "5.1.1.1";
+
+/// Serve the router with a Tor exit at `5.1.1.1` and a VPN range at
+/// `5.2.2.0/24`, behind a stand-in reverse proxy that sets
+/// `X-Forwarded-For`, since the loopback peer address is not a public IP
+async fn serve() -> Client {
+    let state = test_support::app_state_with_ranges(vec![
+        test_support::range("5.1.1.1/32", IpCategory::TorExitNode),
+        test_support::range("5.2.2.0/24", IpCategory::Vpn),
+    ]);
+    let app = create_router(state).layer(axum::middleware::map_request(|mut request: Request| async move {
+        request.headers_mut().insert("x-forwarded-for", HeaderValue::from_static(CALLER_IP));
+        request
+    }));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await
+    });
+    Client::builder(format!("http://{}", addr), test_support::API_KEY).max_retries(0).build().unwrap()
+}
+
+#[tokio::test]
+async fn test_lookup_methods() {
+    let client = serve().await;
+
+    let lookup = client.lookup(FIXTURE_US_IP).await.unwrap();
+    assert_eq!(lookup.ip, FIXTURE_US_IP);
+    assert!(lookup.geo_info.is_some());
+
+    let tor = client.lookup(CALLER_IP).await.unwrap();
+    assert!(tor.is_tor_exit_node);
+    assert!(tor.categories.tor);
+
+    let own = client.lookup_self().await.unwrap();
+    assert_eq!(own.ip, CALLER_IP);
+    assert!(own.is_tor_exit_node);
+
+    let batch = client.batch_lookup(&[FIXTURE_US_IP, "not-an-ip", "5.2.2.9"]).await;
+    assert_eq!(batch[0].as_ref().unwrap().ip, FIXTURE_US_IP);
+    assert!(matches!(&batch[1], Err(Error::Api { status, .. }) if status.as_u16() == 400));
+    assert!(batch[2].as_ref().unwrap().categories.vpn);
+}
+
+#[tokio::test]
+async fn test_threat_score() {
+    let client = serve().await;
+
+    let score = client.threat_score(CALLER_IP).await.unwrap();
+    assert_eq!(score.ip, CALLER_IP);
+    assert!(score.threat_score > 0);
+    assert!(!score.threat_details.is_empty());
+}
+
+#[tokio::test]
+async fn test_range_methods() {
+    let client = serve().await;
+
+    let tor = client.is_tor(CALLER_IP).await.unwrap();
+    assert!(tor.is_tor_exit_node);
+    assert_eq!(tor.network, None);
+    assert!(!client.is_tor(FIXTURE_US_IP).await.unwrap().is_tor_exit_node);
+
+    let proxy = client.is_proxy(FIXTURE_US_IP).await.unwrap();
+    assert!(!proxy.is_proxy);
+    assert_eq!(proxy.network, None);
+    let proxy = client.is_proxy("5.3.0.0/24").await.unwrap();
+    assert_eq!(proxy.network.as_deref(), Some("5.3.0.0/24"));
+
+    let vpn = client.is_vpn(FIXTURE_US_IP).await.unwrap();
+    assert_eq!(vpn.network, None);
+    let vpn = client.is_vpn("5.3.0.7/24").await.unwrap();
+    assert_eq!(vpn.network.as_deref(), Some("5.3.0.0/24"));
+}
+
+#[tokio::test]
+async fn test_cached_lookup_skips_the_service() {
+    let state = test_support::app_state();
+    let aggregates = std::sync::Arc::clone(&state.aggregates);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, create_router(state).into_make_service_with_connect_info::<SocketAddr>()).await
+    });
+    let client = Client::builder(format!("http://{}", addr), test_support::API_KEY)
+        .cache_ttl(Duration::from_secs(60))
+        .build()
+        .unwrap();
+    let served = || aggregates.stats(Duration::from_secs(3600), 0).total.lookups;
+
+    let first = client.lookup(FIXTURE_US_IP).await.unwrap();
+    let cached = client.lookup(FIXTURE_US_IP).await.unwrap();
+    assert_eq!(cached.ip, first.ip);
+    assert_eq!(served(), 1);
+
+    client.batch_lookup(&[FIXTURE_US_IP, FIXTURE_DE_IP]).await;
+    assert_eq!(served(), 2);
+}
+
+#[tokio::test]
+async fn test_sharded_lookup_reaches_only_the_owning_instance() {
+    let mut shards = Vec::new();
+    let mut served = Vec::new();
+    for _ in 0..3 {
+        let state = test_support::app_state();
+        served.push(std::sync::Arc::clone(&state.aggregates));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(listener, create_router(state).into_make_service_with_connect_info::<SocketAddr>()).await
+        });
+        shards.push(Client::builder(format!("http://{}", addr), test_support::API_KEY).max_retries(0).build().unwrap());
+    }
+    let sharded = ShardedLookup::new(shards.clone());
+    let owner = shards.iter().position(|shard| shard.base_url() == sharded.shard(FIXTURE_US_IP).base_url()).unwrap();
+
+    assert_eq!(sharded.lookup(FIXTURE_US_IP).await.unwrap().ip, FIXTURE_US_IP);
+    let lookups: Vec<u64> = served.iter().map(|aggregates| aggregates.stats(Duration::from_secs(3600), 0).total.lookups).collect();
+    assert_eq!(lookups.iter().sum::<u64>(), 1);
+    assert_eq!(lookups[owner], 1);
+}
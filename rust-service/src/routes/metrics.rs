@@ -1,20 +1,60 @@
 use axum::{
-    http::StatusCode,
-    response::IntoResponse,
+    extract::{Request, State},
+    http::{
+        header::{AUTHORIZATION, WWW_AUTHENTICATE},
+        StatusCode,
+    },
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
     routing::get,
     Router,
 };
+use sha2::{Digest, Sha256};
 
-// Temporarily disabled metrics collection
-// use crate::monitoring::gather_metrics;
+use crate::monitoring::gather_metrics;
 
-pub fn metrics_routes() -> Router {
-    Router::new().route("/metrics", get(metrics_handler))
+/// Serves `/metrics`, requiring `Authorization: Bearer <token>` when a
+/// token is configured
+pub fn metrics_routes(token: Option<&str>) -> Router {
+    let router = Router::new().route("/metrics", get(metrics_handler));
+    match token {
+        Some(token) => router.layer(middleware::from_fn_with_state(MetricsToken::new(token), require_token)),
+        None => router,
+    }
+}
+
+/// SHA-256 of the expected token. Digests are compared instead of the
+/// tokens, so the time taken does not depend on how much of it matched.
+#[derive(Clone)]
+struct MetricsToken([u8; 32]);
+
+impl MetricsToken {
+    fn new(token: &str) -> Self {
+        Self(Sha256::digest(token.as_bytes()).into())
+    }
+
+    fn matches(&self, presented: &str) -> bool {
+        Self::new(presented).0 == self.0
+    }
+}
+
+async fn require_token(State(expected): State<MetricsToken>, request: Request, next: Next) -> Response {
+    let presented = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+        .map(|(_, token)| token.trim());
+
+    match presented {
+        Some(token) if expected.matches(token) => next.run(request).await,
+        _ => (StatusCode::UNAUTHORIZED, [(WWW_AUTHENTICATE, "Bearer")]).into_response(),
+    }
 }
 
 pub async fn metrics_handler() -> Result<impl IntoResponse, StatusCode> {
-    // Temporarily return empty metrics
-    let metrics = Vec::new();
+    let metrics = gather_metrics();
     
     match String::from_utf8(metrics) {
         Ok(metrics_string) => Ok((
@@ -27,28 +67,53 @@ pub async fn metrics_handler() -> Result<impl IntoResponse, StatusCode> {
     }
 }
 
-// Temporarily disabled tests that depend on metrics
-// #[cfg(test)]
-// mod tests {
-//     use super::*;
-//     use axum::{
-//         body::Body,
-//         http::{Request, StatusCode},
-//     };
-//     use tower::ServiceExt;
-
-//     #[tokio::test]
-//     async fn test_metrics_endpoint() {
-//         let app = metrics_routes();
-
-//         let response = app
-//             .oneshot(Request::builder()
-//                 .uri("/metrics")
-//                 .body(Body::empty())
-//                 .unwrap())
-//             .await
-//             .unwrap();
-
-//         assert_eq!(response.status(), StatusCode::OK);
-//     }
-// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+    };
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_metrics_endpoint() {
+        crate::monitoring::record_http_request("/health", "GET", 200, std::time::Duration::ZERO);
+        let app = metrics_routes(None);
+
+        let response = app
+            .oneshot(Request::builder()
+                .uri("/metrics")
+                .body(Body::empty())
+                .unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("http_requests_total"));
+        assert!(body.contains("http_request_duration_seconds"));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_token_required_when_set() {
+        let app = metrics_routes(Some("scrape-secret"));
+        let scrape = |authorization: Option<&str>| {
+            let mut request = Request::builder().uri("/metrics");
+            if let Some(authorization) = authorization {
+                request = request.header(AUTHORIZATION, authorization);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        for authorization in [None, Some("Bearer wrong"), Some("Basic scrape-secret"), Some("scrape-secret")] {
+            let response = scrape(authorization).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{:?}", authorization);
+            assert_eq!(response.headers()[WWW_AUTHENTICATE], "Bearer");
+        }
+        for authorization in ["Bearer scrape-secret", "bearer scrape-secret"] {
+            assert_eq!(scrape(Some(authorization)).await.unwrap().status(), StatusCode::OK, "{}", authorization);
+        }
+    }
+}
@@ -1,46 +1,169 @@
 pub mod metrics;
+pub mod openapi;
 
 use axum::{
+    error_handling::HandleErrorLayer,
     extract::State,
     http::StatusCode,
+    middleware,
     response::IntoResponse,
-    routing::{get, post},
-    Router,
+    routing::{delete, get, post, MethodRouter},
+    BoxError, Router,
 };
 use std::sync::Arc;
+use tower::limit::GlobalConcurrencyLimitLayer;
+use tower::load_shed::{error::Overloaded, LoadShedLayer};
+use tower::ServiceBuilder;
 use tower_http::trace::TraceLayer;
 
+use crate::config::FeatureSettings;
+use crate::errors::AppError;
 use crate::handlers::{self, AppState};
+use crate::middleware::api_key_auth::{api_key_auth, ApiKeyAuthState};
+use crate::middleware::{deprecation, metrics as request_metrics, problem};
+use crate::telemetry;
 
 // Helper function to create the router with state
+//
+// Route groups for disabled features are not registered, so they 404.
 pub fn create_router(state: AppState) -> Router {
+    let features = state.features;
+    let max_concurrent_requests = state.max_concurrent_requests;
+    let challenges_enabled = state.challenges.is_some();
+    let legacy_sunset = state.legacy_sunset;
+
     // Create the shared state
     let shared_state = Arc::new(state);
 
     // Public routes that don't require authentication
     let public_routes = Router::new()
-        .route("/health", get(|| async { "OK" }));
+        .route("/health", get(handlers::health_check))
+        .route("/api/openapi.json", get(openapi::openapi_json));
 
     // Protected routes that require authentication
-    let protected_routes = Router::new()
-        .route("/api/lookup/self", get(handlers::lookup_self))
-        .route("/api/lookup/{ip}", get(handlers::lookup_ip))
-        .route("/api/threat-score/{ip}", get(handlers::get_threat_score))
-        .route("/api/threat-score/self", get(handlers::get_self_threat_score))
-        .route("/api/tor/{ip_or_range}", get(handlers::is_tor_exit_node))
-        .route("/api/vpn/{ip_or_range}", get(handlers::is_vpn_or_datacenter))
-        .route("/api/proxy/{ip_or_range}", get(handlers::is_proxy));
-
-    // Debug routes
-    let debug_routes = Router::new()
-        .route("/debug/reset-circuit-breaker", post(reset_circuit_breaker));
+    let mut protected_routes = Router::new();
+    for (path, route) in api_routes(features, challenges_enabled) {
+        protected_routes = protected_routes.route(path, route);
+    }
+
+    let mut api = protected_routes;
+    let mut app = public_routes;
+
+    if features.admin {
+        // Admin routes
+        let admin_routes = Router::new()
+            .route("/admin/sources", get(handlers::list_sources))
+            .route("/admin/sources/overlaps", get(handlers::category_overlaps))
+            .route("/admin/geo/reload", post(handlers::reload_geo))
+            .route("/admin/reload-config", post(handlers::reload_config))
+            .route("/admin/usage", get(handlers::usage_snapshot))
+            .route("/admin/connectivity", get(handlers::check_connectivity))
+            .route("/admin/explain/{ip}", get(handlers::explain_ip))
+            .route("/admin/debug-capture", post(handlers::start_debug_capture))
+            .route("/admin/debug-capture/{ip}", get(handlers::get_debug_capture))
+            .route("/admin/monitor_mode", post(handlers::set_monitor_mode))
+            .route("/admin/tree/snapshots", get(handlers::list_tree_snapshots))
+            .route("/admin/tree/rollback/{index}", post(handlers::rollback_tree))
+            .route(
+                "/admin/ranges",
+                get(handlers::list_manual_ranges).put(handlers::put_manual_range),
+            )
+            .route("/admin/ranges/{network}", delete(handlers::delete_manual_range));
+
+        // Debug routes
+        let debug_routes = Router::new()
+            .route("/debug/reset-circuit-breaker", post(reset_circuit_breaker));
+
+        api = api.merge(admin_routes);
+        app = app.merge(debug_routes);
+    }
+
+    // Everything under /api but the OpenAPI document needs an API key
+    let auth_state = Arc::new(ApiKeyAuthState {
+        web_api_client: Arc::clone(&shared_state.web_api_client),
+        unlimited_api_keys: (*shared_state.unlimited_api_keys).clone(),
+        runtime: Arc::clone(&shared_state.runtime),
+        usage: Arc::clone(&shared_state.usage),
+    });
+    let api = api.route_layer(middleware::from_fn_with_state(auth_state, api_key_auth));
+
+    // The API is served under /api/v1, and at its old unversioned paths
+    // with deprecation headers until the aliases are turned off
+    app = app.nest(deprecation::CURRENT_PREFIX, api.clone());
+    if let Some(sunset) = legacy_sunset {
+        app = app.nest("/api", api.layer(middleware::from_fn_with_state(sunset, deprecation::legacy_alias)));
+    }
 
     // Combine all routes with the shared state
-    public_routes
-        .merge(protected_routes)
-        .merge(debug_routes)
-        .with_state(shared_state)
-        .layer(TraceLayer::new_for_http())
+    with_concurrency_limit(app.with_state(shared_state), max_concurrent_requests)
+        .layer(middleware::from_fn(problem::problem_json))
+        .layer(middleware::from_fn(request_metrics::track_metrics))
+        .layer(TraceLayer::new_for_http().make_span_with(telemetry::http_span))
+}
+
+/// The API routes of the enabled features but admin, relative to the API
+/// prefix. These are the routes the OpenAPI document covers.
+fn api_routes(features: FeatureSettings, challenges_enabled: bool) -> Vec<(&'static str, MethodRouter<Arc<AppState>>)> {
+    let mut routes = Vec::new();
+
+    if features.geo_lookup || features.asn_lookup {
+        routes.extend([
+            ("/lookup/self", get(handlers::lookup_self).head(handlers::gate::head_lookup_self)),
+            ("/lookup/{ip}", get(handlers::lookup_ip).head(handlers::gate::head_lookup_ip)),
+            ("/lookup", post(handlers::lookup_ip_body)),
+            ("/stats/aggregates", get(handlers::aggregate_stats)),
+        ]);
+    }
+
+    if features.threat_score {
+        routes.extend([
+            ("/threat-score/{ip}", get(handlers::get_threat_score)),
+            ("/threat-score/self", get(handlers::get_self_threat_score)),
+            ("/threat-score/{ip}/explain", get(handlers::explain_threat_score)),
+            ("/simulate", post(handlers::simulate_action)),
+            ("/action/{score}", get(handlers::action_for_score)),
+            ("/gate/{ip}", get(handlers::gate::gate)),
+        ]);
+
+        if challenges_enabled {
+            routes.push(("/challenge/verify", post(handlers::verify_challenge)));
+        }
+    }
+
+    if features.range_queries {
+        routes.extend([
+            ("/tor/{ip_or_range}", get(handlers::is_tor_exit_node)),
+            ("/vpn/{ip_or_range}", get(handlers::is_vpn_or_datacenter)),
+            ("/proxy/{ip_or_range}", get(handlers::is_proxy)),
+            ("/ranges/count", get(handlers::count_ranges)),
+            ("/is_in_ranges", post(handlers::is_in_ranges)),
+        ]);
+    }
+
+    routes
+}
+
+/// Cap in-flight requests across all routes at `max`, answering 503 instead
+/// of queueing once the cap is reached.
+///
+/// `Router::layer` wraps each route separately, so the global limit is used
+/// to share one semaphore between them.
+fn with_concurrency_limit(router: Router, max: usize) -> Router {
+    router.layer(
+        ServiceBuilder::new()
+            .layer(HandleErrorLayer::new(shed_error))
+            .layer(LoadShedLayer::new())
+            .layer(GlobalConcurrencyLimitLayer::new(max)),
+    )
+}
+
+async fn shed_error(error: BoxError) -> AppError {
+    if error.is::<Overloaded>() {
+        AppError::Overloaded
+    } else {
+        tracing::error!("Unhandled middleware error: {}", error);
+        AppError::InternalServerError
+    }
 }
 
 async fn reset_circuit_breaker(
@@ -48,4 +171,498 @@ async fn reset_circuit_breaker(
 ) -> impl IntoResponse {
     state.web_api_client.reset_circuit_breaker().await;
     (StatusCode::OK, "Circuit breaker reset")
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod contract;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use axum::body::Body;
+    use axum::http::{Method, Request};
+    use tower::ServiceExt;
+
+    async fn status(router: &Router, method: Method, uri: &str) -> StatusCode {
+        let request = test_support::request()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(r#"{"ip":"8.8.8.8"}"#))
+            .unwrap();
+        router.clone().oneshot(request).await.unwrap().status()
+    }
+
+    /// Sends `body` as `api_key`, or without any key for `None`
+    async fn status_as(router: &Router, method: Method, uri: &str, api_key: Option<&str>, body: &str) -> StatusCode {
+        let mut request = Request::builder().method(method).uri(uri).header("content-type", "application/json");
+        if let Some(api_key) = api_key {
+            request = request.header("x-api-key", api_key);
+        }
+        let request = request.body(Body::from(body.to_string())).unwrap();
+        router.clone().oneshot(request).await.unwrap().status()
+    }
+
+    fn router_with(features: FeatureSettings) -> Router {
+        let mut state = test_support::app_state();
+        state.features = features;
+        create_router(state)
+    }
+
+    #[tokio::test]
+    async fn test_all_features_but_admin_registered_by_default() {
+        let router = router_with(FeatureSettings::default());
+        assert_eq!(status(&router, Method::GET, "/api/lookup/8.8.8.8").await, StatusCode::OK);
+        assert_eq!(status(&router, Method::GET, "/api/admin/tree/snapshots").await, StatusCode::NOT_FOUND);
+
+        let router = router_with(FeatureSettings { admin: true, ..FeatureSettings::default() });
+
+        assert_eq!(status(&router, Method::GET, "/api/lookup/8.8.8.8").await, StatusCode::OK);
+        assert_eq!(status(&router, Method::POST, "/api/simulate").await, StatusCode::OK);
+        assert_eq!(status(&router, Method::GET, "/api/tor/8.8.8.8").await, StatusCode::OK);
+        assert_eq!(status(&router, Method::GET, "/api/admin/tree/snapshots").await, StatusCode::OK);
+        assert_eq!(status(&router, Method::POST, "/api/admin/geo/reload").await, StatusCode::OK);
+        assert_eq!(status(&router, Method::GET, "/api/admin/ranges").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_threat_score_only_deployment() {
+        let router = router_with(FeatureSettings {
+            geo_lookup: false,
+            asn_lookup: false,
+            range_queries: false,
+            threat_score: true,
+            admin: false,
+        });
+
+        assert_eq!(status(&router, Method::POST, "/api/simulate").await, StatusCode::OK);
+        assert_eq!(status(&router, Method::GET, "/api/lookup/8.8.8.8").await, StatusCode::NOT_FOUND);
+        assert_eq!(status(&router, Method::GET, "/api/tor/8.8.8.8").await, StatusCode::NOT_FOUND);
+        assert_eq!(status(&router, Method::GET, "/api/vpn/10.0.0.0%2F8").await, StatusCode::NOT_FOUND);
+        assert_eq!(
+            status(&router, Method::GET, "/api/admin/tree/snapshots").await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status(&router, Method::POST, "/debug/reset-circuit-breaker").await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(status(&router, Method::GET, "/health").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_manual_ranges_reject_non_admins() {
+        let router = create_router(test_support::app_state());
+        let range = r#"{"network":"93.184.216.0/24","category":"tor"}"#;
+        let delete = "/api/admin/ranges/93.184.216.0%2F24";
+
+        for prefix in ["/api", "/api/v1"] {
+            let put = format!("{}/admin/ranges", prefix);
+            let delete = delete.replacen("/api", prefix, 1);
+            assert_eq!(status_as(&router, Method::PUT, &put, None, range).await, StatusCode::UNAUTHORIZED);
+            assert_eq!(status_as(&router, Method::DELETE, &delete, None, "").await, StatusCode::UNAUTHORIZED);
+            assert_eq!(
+                status_as(&router, Method::PUT, &put, Some(test_support::USER_API_KEY), range).await,
+                StatusCode::FORBIDDEN
+            );
+            assert_eq!(
+                status_as(&router, Method::DELETE, &delete, Some(test_support::USER_API_KEY), "").await,
+                StatusCode::FORBIDDEN
+            );
+        }
+
+        let put = status_as(&router, Method::PUT, "/api/v1/admin/ranges", Some(test_support::API_KEY), range).await;
+        assert_eq!(put, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_tree_snapshots_require_admin() {
+        let router = create_router(test_support::app_state());
+
+        for (method, uri) in [(Method::GET, "/api/admin/tree/snapshots"), (Method::POST, "/api/admin/tree/rollback/0")] {
+            assert_eq!(status_as(&router, method.clone(), uri, None, "").await, StatusCode::UNAUTHORIZED, "{}", uri);
+            assert_eq!(
+                status_as(&router, method, uri, Some(test_support::USER_API_KEY), "").await,
+                StatusCode::FORBIDDEN,
+                "{}",
+                uri
+            );
+        }
+        assert_eq!(status(&router, Method::GET, "/api/admin/tree/snapshots").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_source_reports_require_admin() {
+        let router = create_router(test_support::app_state());
+
+        for uri in ["/api/admin/sources", "/api/admin/sources/overlaps"] {
+            assert_eq!(status_as(&router, Method::GET, uri, None, "").await, StatusCode::UNAUTHORIZED, "{}", uri);
+            assert_eq!(
+                status_as(&router, Method::GET, uri, Some(test_support::USER_API_KEY), "").await,
+                StatusCode::FORBIDDEN,
+                "{}",
+                uri
+            );
+        }
+        assert_eq!(status(&router, Method::GET, "/api/admin/sources").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_reloads_require_admin() {
+        let router = create_router(test_support::app_state());
+
+        for uri in ["/api/admin/geo/reload", "/api/admin/reload-config"] {
+            assert_eq!(status_as(&router, Method::POST, uri, None, "").await, StatusCode::UNAUTHORIZED, "{}", uri);
+            assert_eq!(
+                status_as(&router, Method::POST, uri, Some(test_support::USER_API_KEY), "").await,
+                StatusCode::FORBIDDEN,
+                "{}",
+                uri
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_usage_is_recorded_and_admin_only() {
+        let router = create_router(test_support::app_state());
+
+        let lookup = status_as(&router, Method::GET, "/api/v1/lookup/8.8.8.8", Some(test_support::USER_API_KEY), "").await;
+        assert_eq!(lookup, StatusCode::OK);
+        assert_eq!(status_as(&router, Method::GET, "/api/admin/usage", None, "").await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            status_as(&router, Method::GET, "/api/admin/usage", Some(test_support::USER_API_KEY), "").await,
+            StatusCode::FORBIDDEN
+        );
+
+        let (_, usage) = fetch(&router, "/api/admin/usage").await;
+        let user = usage["keys"].as_array().unwrap().iter().find(|key| key["key"] == "user-1").unwrap();
+        assert_eq!(user["requests"]["lookup"], 1);
+    }
+
+    async fn fetch(router: &Router, uri: &str) -> (axum::http::HeaderMap, serde_json::Value) {
+        let request = test_support::request().uri(uri).body(Body::empty()).unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "{}", uri);
+        let headers = response.headers().clone();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (headers, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_unversioned_paths_alias_v1() {
+        let router = create_router(test_support::app_state_with_ranges(vec![test_support::range(
+            "5.1.1.1/32",
+            crate::ip_lookup::IpCategory::TorExitNode,
+        )]));
+
+        for path in ["/tor/5.1.1.1", "/threat-score/5.1.1.1", "/ranges/count", "/admin/sources"] {
+            let (v1_headers, v1) = fetch(&router, &format!("/api/v1{}", path)).await;
+            let (legacy_headers, legacy) = fetch(&router, &format!("/api{}", path)).await;
+            assert_eq!(v1, legacy, "{}", path);
+            assert!(v1_headers.get("deprecation").is_none());
+            assert_eq!(legacy_headers["deprecation"], "true");
+        }
+
+        let (headers, _) = fetch(&router, "/api/lookup/5.1.1.1?fields=threat_score").await;
+        assert_eq!(headers["sunset"], "Wed, 30 Jun 2027 00:00:00 GMT");
+        assert_eq!(
+            headers["link"],
+            "</api/v1/lookup/5.1.1.1?fields=threat_score>; rel=\"successor-version\""
+        );
+        // Errors from an alias are marked too
+        let request = test_support::request().uri("/api/lookup/not-an-ip").body(Body::empty()).unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.headers()["deprecation"], "true");
+    }
+
+    #[tokio::test]
+    async fn test_legacy_routes_disabled() {
+        let mut state = test_support::app_state();
+        state.legacy_sunset = None;
+        let router = create_router(state);
+
+        assert_eq!(status(&router, Method::GET, "/api/v1/lookup/8.8.8.8").await, StatusCode::OK);
+        assert_eq!(status(&router, Method::POST, "/api/v1/lookup").await, StatusCode::OK);
+        assert_eq!(status(&router, Method::GET, "/api/lookup/8.8.8.8").await, StatusCode::NOT_FOUND);
+        assert_eq!(status(&router, Method::POST, "/api/lookup").await, StatusCode::NOT_FOUND);
+        assert_eq!(status(&router, Method::GET, "/api/admin/ranges").await, StatusCode::NOT_FOUND);
+        // Neither versioned nor deprecated
+        assert_eq!(status(&router, Method::GET, "/api/openapi.json").await, StatusCode::OK);
+        assert_eq!(status(&router, Method::GET, "/health").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_range_count() {
+        use crate::ip_lookup::{tree::RadixTree, CloudKind, IpCategory};
+
+        let state = test_support::app_state();
+        let mut tree = RadixTree::new();
+        tree.insert("5.1.1.1/32".parse().unwrap(), IpCategory::TorExitNode);
+        tree.insert("10.0.0.0/8".parse().unwrap(), IpCategory::Vpn);
+        tree.insert("172.16.0.0/12".parse().unwrap(), IpCategory::Vpn);
+        tree.insert("2600:1f14::/35".parse().unwrap(), IpCategory::CloudProvider(CloudKind::Aws));
+        state.ip_lookup_service.tree().replace(tree);
+        let router = create_router(state);
+
+        let request = test_support::request().uri("/api/ranges/count").body(Body::empty()).unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let counts: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            counts,
+            serde_json::json!({
+                "v4": 3,
+                "v6": 1,
+                "total": 4,
+                "per_category": { "vpn": 2, "tor_exit_node": 1, "cloud_aws": 1 },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_is_in_ranges() {
+        use crate::ip_lookup::{tree::RadixTree, IpCategory};
+
+        let state = test_support::app_state();
+        let mut tree = RadixTree::new();
+        tree.insert("10.0.0.0/8".parse().unwrap(), IpCategory::Vpn);
+        tree.insert("10.1.2.3/32".parse().unwrap(), IpCategory::ProxySocks5);
+        tree.insert("5.1.1.1/32".parse().unwrap(), IpCategory::TorExitNode);
+        state.ip_lookup_service.tree().replace(tree);
+        let router = create_router(state);
+
+        let check = |category: &'static str| {
+            let router = router.clone();
+            async move {
+                let body = serde_json::json!({
+                    "ips": ["10.1.2.3", "10.9.9.9", "5.1.1.1", "2001:db8::1"],
+                    "category": category,
+                });
+                let request = test_support::request()
+                    .method(Method::POST)
+                    .uri("/api/is_in_ranges")
+                    .header("content-type", "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap();
+                let response = router.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                let body: serde_json::Value = serde_json::from_slice(&body).unwrap_or_default();
+                let members: Vec<bool> = body["results"]
+                    .as_array()
+                    .map(|results| results.iter().map(|r| r["in_range"].as_bool().unwrap()).collect())
+                    .unwrap_or_default();
+                (status, members)
+            }
+        };
+
+        assert_eq!(check("proxy").await, (StatusCode::OK, vec![true, false, false, false]));
+        // The /8 VPN range still counts under the more specific proxy entry
+        assert_eq!(check("vpn").await, (StatusCode::OK, vec![true, true, false, false]));
+        assert_eq!(check("tor").await, (StatusCode::OK, vec![false, false, true, false]));
+        assert_eq!(check("bogus").await.0, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_missing_geo_record() {
+        // Public, but absent from the city fixture
+        let uri = "/api/lookup/1.1.1.1";
+
+        let router = create_router(test_support::app_state());
+        let request = test_support::request().uri(uri).body(Body::empty()).unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let lookup: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(lookup["geo_info"].is_null());
+        assert!(lookup["threat_score"].is_number());
+
+        let mut state = test_support::app_state();
+        state.require_geo = true;
+        assert_eq!(status(&create_router(state), Method::GET, uri).await, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_threat_score_disabled() {
+        let router = router_with(FeatureSettings {
+            threat_score: false,
+            ..FeatureSettings::default()
+        });
+
+        assert_eq!(status(&router, Method::POST, "/api/simulate").await, StatusCode::NOT_FOUND);
+        assert_eq!(
+            status(&router, Method::GET, "/api/threat-score/8.8.8.8/explain").await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(status(&router, Method::GET, "/api/lookup/8.8.8.8").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_monitor_mode_override() {
+        use crate::ip_lookup::IpCategory;
+
+        let state = test_support::app_state_with_ranges(vec![test_support::range("5.1.1.1/32", IpCategory::TorExitNode)]);
+        let router = create_router(state);
+        let json = |method: Method, uri: &str, body: &'static str| {
+            let request = test_support::request()
+                .method(method)
+                .uri(uri)
+                .header("content-type", "application/json")
+                .body(Body::from(body))
+                .unwrap();
+            let router = router.clone();
+            async move {
+                let response = router.oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+            }
+        };
+
+        // Cached as a block before the switch is flipped
+        let lookup = json(Method::GET, "/api/lookup/5.1.1.1", "").await;
+        assert_eq!(lookup["recommended_action"], "block");
+
+        let status = json(Method::POST, "/api/admin/monitor_mode", r#"{"enabled":true}"#).await;
+        assert_eq!(status, serde_json::json!({ "forced": true, "configured": false, "effective": true }));
+        let lookup = json(Method::GET, "/api/lookup/5.1.1.1", "").await;
+        assert_eq!(lookup["recommended_action"], "monitor");
+        assert_eq!(lookup["shadow_action"], "block");
+        let health = json(Method::GET, "/health", "").await;
+        assert_eq!(health["monitor_mode"]["forced"], true);
+
+        json(Method::POST, "/api/admin/monitor_mode", r#"{"enabled":false}"#).await;
+        let lookup = json(Method::GET, "/api/lookup/5.1.1.1", "").await;
+        assert_eq!(lookup["recommended_action"], "block");
+    }
+
+    #[tokio::test]
+    async fn test_monitor_mode_rejects_non_admins() {
+        let state = test_support::app_state();
+        let monitor_override = state.monitor_override.clone();
+        let router = create_router(state);
+        let enable = r#"{"enabled":true}"#;
+
+        let anonymous = status_as(&router, Method::POST, "/api/admin/monitor_mode", None, enable).await;
+        assert_eq!(anonymous, StatusCode::UNAUTHORIZED);
+        let user = status_as(&router, Method::POST, "/api/v1/admin/monitor_mode", Some(test_support::USER_API_KEY), enable).await;
+        assert_eq!(user, StatusCode::FORBIDDEN);
+        assert!(!monitor_override.is_forced());
+    }
+
+    #[tokio::test]
+    async fn test_debug_capture_rejects_non_admins() {
+        let state = test_support::app_state();
+        let debug_capture = Arc::clone(&state.debug_capture);
+        let router = create_router(state);
+        let start = r#"{"ip":"5.2.2.7"}"#;
+
+        for api_key in [None, Some(test_support::USER_API_KEY)] {
+            let expected = if api_key.is_none() { StatusCode::UNAUTHORIZED } else { StatusCode::FORBIDDEN };
+            assert_eq!(status_as(&router, Method::POST, "/api/admin/debug-capture", api_key, start).await, expected);
+            assert_eq!(status_as(&router, Method::GET, "/api/admin/debug-capture/5.2.2.7", api_key, "").await, expected);
+        }
+        assert_eq!(debug_capture.watched_until("5.2.2.7".parse().unwrap()), None);
+    }
+
+    #[tokio::test]
+    async fn test_explain_ip_rejects_non_admins() {
+        let router = create_router(test_support::app_state());
+
+        let anonymous = status_as(&router, Method::GET, "/api/admin/explain/8.8.8.8", None, "").await;
+        assert_eq!(anonymous, StatusCode::UNAUTHORIZED);
+        let user = status_as(&router, Method::GET, "/api/v1/admin/explain/8.8.8.8", Some(test_support::USER_API_KEY), "").await;
+        assert_eq!(user, StatusCode::FORBIDDEN);
+        assert_eq!(status(&router, Method::GET, "/api/admin/explain/8.8.8.8").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_connectivity_check_rejects_non_admins() {
+        let router = create_router(test_support::app_state());
+
+        let anonymous = status_as(&router, Method::GET, "/api/admin/connectivity", None, "").await;
+        assert_eq!(anonymous, StatusCode::UNAUTHORIZED);
+        let user = status_as(&router, Method::GET, "/api/v1/admin/connectivity", Some(test_support::USER_API_KEY), "").await;
+        assert_eq!(user, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_aggregate_stats() {
+        use crate::ip_lookup::IpCategory;
+
+        let state = test_support::app_state_with_ranges(vec![test_support::range("5.1.1.1/32", IpCategory::TorExitNode)]);
+        let router = create_router(state);
+        let get = |uri: &str| {
+            let request = test_support::request().uri(uri).body(Body::empty()).unwrap();
+            let router = router.clone();
+            async move {
+                let response = router.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap_or_default())
+            }
+        };
+
+        // The second lookup is a cache hit and counts all the same
+        get(&format!("/api/lookup/{}", test_support::mmdb::FIXTURE_US_IP)).await;
+        get(&format!("/api/lookup/{}", test_support::mmdb::FIXTURE_US_IP)).await;
+        get("/api/lookup/5.1.1.1").await;
+
+        let (status, stats) = get("/api/stats/aggregates?window=15m").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(stats["window_secs"], 900);
+        assert_eq!(stats["total"], serde_json::json!({ "lookups": 3, "flagged": 1 }));
+        assert_eq!(stats["countries"]["top"][0], serde_json::json!({ "key": "US", "lookups": 2, "flagged": 0 }));
+
+        let (status, _) = get("/api/stats/aggregates?window=1d").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_requests_over_concurrency_limit_are_shed() {
+        use tokio::sync::{Notify, Semaphore};
+
+        let entered = Arc::new(Notify::new());
+        let release = Arc::new(Semaphore::new(0));
+        let app = Router::new()
+            .route(
+                "/slow",
+                get({
+                    let (entered, release) = (Arc::clone(&entered), Arc::clone(&release));
+                    move || async move {
+                        entered.notify_one();
+                        let _permit = release.acquire().await.unwrap();
+                        "slow"
+                    }
+                }),
+            )
+            .route("/fast", get(|| async { "fast" }));
+        let router = with_concurrency_limit(app, 1);
+
+        let slow = tokio::spawn({
+            let router = router.clone();
+            async move {
+                let request = test_support::request().uri("/slow").body(Body::empty()).unwrap();
+                router.oneshot(request).await.unwrap().status()
+            }
+        });
+        // The slow request now holds the only slot
+        entered.notified().await;
+
+        // The limit is shared across routes
+        let request = test_support::request().uri("/fast").body(Body::empty()).unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            response.extensions().get::<crate::errors::Problem>().map(|p| p.slug),
+            Some("overloaded")
+        );
+
+        release.add_permits(1);
+        assert_eq!(slow.await.unwrap(), StatusCode::OK);
+        assert_eq!(status(&router, Method::GET, "/fast").await, StatusCode::OK);
+    }
+}
@@ -0,0 +1,174 @@
+//! OpenAPI document for the public API, served at `/api/openapi.json`.
+//!
+//! Built from the `#[utoipa::path]` annotations on the handlers and the
+//! `ToSchema` derives on their request and response types. Every route
+//! `create_router` registers under `/api/v1`, but the admin and debug ones,
+//! must be listed here; a test compares the two. The unversioned `/api`
+//! aliases are left out.
+
+use axum::Json;
+use serde::Serialize;
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, SecurityScheme};
+use utoipa::{Modify, OpenApi, ToSchema};
+
+use crate::handlers;
+
+/// Body of every error response (or an RFC 9457 problem document when the
+/// client asks for `application/problem+json`)
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorBody {
+    pub error: String,
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "InfraLock IP intelligence API"),
+    paths(
+        handlers::lookup_ip,
+        handlers::lookup_ip_body,
+        handlers::lookup_self,
+        handlers::gate::head_lookup_ip,
+        handlers::gate::head_lookup_self,
+        handlers::aggregate_stats,
+        handlers::get_threat_score,
+        handlers::get_self_threat_score,
+        handlers::explain_threat_score,
+        handlers::simulate_action,
+        handlers::action_for_score,
+        handlers::gate::gate,
+        handlers::verify_challenge,
+        handlers::is_tor_exit_node,
+        handlers::is_vpn_or_datacenter,
+        handlers::is_proxy,
+        handlers::is_in_ranges,
+        handlers::count_ranges,
+    ),
+    components(schemas(ErrorBody)),
+    modifiers(&ApiKeyScheme),
+    security(("api_key" = [])),
+    tags(
+        (name = "lookup", description = "Geo, ASN and threat data for an address"),
+        (name = "threat-score", description = "Threat score, its explanation and the recommended action"),
+        (name = "gate", description = "Verdict as a status code and headers, for edge proxies"),
+        (name = "ranges", description = "Membership in the VPN, proxy, Tor and cloud range lists"),
+    )
+)]
+pub struct ApiDoc;
+
+/// Every documented route needs the `X-API-Key` header
+struct ApiKeyScheme;
+
+impl Modify for ApiKeyScheme {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme("api_key", SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("X-API-Key"))));
+    }
+}
+
+pub async fn openapi_json() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::FeatureSettings;
+    use crate::handlers::fields::LookupField;
+    use crate::middleware::deprecation::CURRENT_PREFIX;
+    use crate::routes::{api_routes, create_router};
+    use crate::services::challenge::ChallengeService;
+    use crate::test_support;
+    use axum::body::Body;
+    use axum::http::{Method, Request, StatusCode};
+    use std::collections::BTreeSet;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_openapi_document() {
+        let router = create_router(test_support::app_state());
+        let request = Request::builder().uri("/api/openapi.json").body(Body::empty()).unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let doc: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        for path in ["/api/v1/lookup/{ip}", "/api/v1/lookup/self", "/api/v1/threat-score/{ip}", "/api/v1/proxy/{ip_or_range}"] {
+            assert!(doc["paths"][path]["get"].is_object(), "missing {}", path);
+        }
+        assert!(doc["paths"]["/api/v1/is_in_ranges"]["post"].is_object());
+        assert!(doc["paths"]["/api/v1/lookup"]["post"]["requestBody"].is_object());
+        assert!(doc["paths"]["/api/v1/lookup/{ip}"]["head"].is_object());
+        let params = doc["paths"]["/api/v1/lookup/{ip}"]["get"]["parameters"].as_array().unwrap();
+        assert!(params.iter().any(|p| p["name"] == "fields" && p["in"] == "query"));
+
+        let scheme = &doc["components"]["securitySchemes"]["api_key"];
+        assert_eq!(scheme["type"], "apiKey");
+        assert_eq!(scheme["in"], "header");
+        assert_eq!(scheme["name"], "X-API-Key");
+        assert!(doc["security"].as_array().unwrap().iter().any(|requirement| requirement.get("api_key").is_some()));
+
+        let schemas = &doc["components"]["schemas"];
+        for schema in ["LookupResponse", "ThreatScoreResponse", "TorResponse", "ProxyResponse", "GeoInfo", "ErrorBody"] {
+            assert!(schemas[schema].is_object(), "missing schema {}", schema);
+        }
+        // Not part of the response body
+        assert!(schemas["GeoInfo"]["properties"].get("traits").is_none());
+    }
+
+    #[test]
+    fn test_lookup_schema_matches_selectable_fields() {
+        let doc = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let properties = doc["components"]["schemas"]["LookupResponse"]["properties"].as_object().unwrap();
+        let top_level: Vec<&str> = LookupField::ALL
+            .iter()
+            .map(|field| field.name())
+            .filter(|name| !name.contains('.'))
+            .collect();
+        assert_eq!(properties.len(), top_level.len());
+        for name in top_level {
+            assert!(properties.contains_key(name), "LookupResponse schema lacks {}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_documented_paths_match_the_router() {
+        let doc = ApiDoc::openapi();
+        let documented: BTreeSet<&str> = doc.paths.paths.keys().map(String::as_str).collect();
+        let routed: BTreeSet<String> = api_routes(FeatureSettings::default(), true)
+            .into_iter()
+            .map(|(path, _)| format!("{}{}", CURRENT_PREFIX, path))
+            .collect();
+        assert_eq!(documented, routed.iter().map(String::as_str).collect());
+
+        let mut state = test_support::app_state();
+        let challenges = ChallengeService::new(b"test-secret", Duration::from_secs(300), Duration::from_secs(3600), 0);
+        state.challenges = Some(Arc::new(challenges));
+        let router = create_router(state);
+        for (path, item) in &doc.paths.paths {
+            let uri = path.replace("{ip}", "8.8.8.8").replace("{ip_or_range}", "8.8.8.8").replace("{score}", "50");
+            for (method, operation) in [
+                (Method::GET, &item.get),
+                (Method::HEAD, &item.head),
+                (Method::POST, &item.post),
+                (Method::PUT, &item.put),
+                (Method::DELETE, &item.delete),
+            ] {
+                let request = test_support::request()
+                    .method(method.clone())
+                    .uri(&uri)
+                    .header("content-type", "application/json")
+                    .body(Body::from("{}"))
+                    .unwrap();
+                let status = router.clone().oneshot(request).await.unwrap().status();
+                let routed = status != StatusCode::METHOD_NOT_ALLOWED && status != StatusCode::NOT_FOUND;
+                // GET routes also answer HEAD without it being documented
+                if method == Method::HEAD && item.get.is_some() && operation.is_none() {
+                    continue;
+                }
+                assert_eq!(routed, operation.is_some(), "{} {} answered {}", method, path, status);
+            }
+        }
+    }
+}
@@ -0,0 +1,391 @@
+//! Per-country and per-ASN lookup counts over the last couple of hours, for
+//! dashboards that want "top countries among flagged lookups" without a log
+//! pipeline.
+//!
+//! Counts go into a ring of fixed-length time buckets; a bucket is cleared
+//! when the ring wraps around to it. Each bucket tracks at most `max_keys`
+//! countries and ASNs, and lookups for keys beyond that are counted under
+//! `other`, so memory stays bounded however diverse the traffic is. Keys seen
+//! early in a bucket keep their slot, which favours the heavy hitters without
+//! the bookkeeping of an exact top-K.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::handlers::LookupResponse;
+
+/// Five-minute buckets
+pub const BUCKET_SECS: u64 = 300;
+/// Two hours of history
+pub const BUCKETS: usize = 24;
+/// Countries and ASNs tracked individually per bucket
+pub const MAX_KEYS: usize = 256;
+
+/// Lookups and how many of them had at least one threat finding
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, ToSchema)]
+pub struct Counts {
+    pub lookups: u64,
+    pub flagged: u64,
+}
+
+impl Counts {
+    fn add(&mut self, other: Counts) {
+        self.lookups += other.lookups;
+        self.flagged += other.flagged;
+    }
+}
+
+/// Counts for one dimension, bounded to `max_keys` keys
+#[derive(Debug, Default)]
+struct KeyCounts {
+    keys: HashMap<String, Counts>,
+    other: Counts,
+}
+
+impl KeyCounts {
+    fn record(&mut self, key: Option<String>, counts: Counts, max_keys: usize) {
+        let Some(key) = key else {
+            return;
+        };
+        if let Some(existing) = self.keys.get_mut(&key) {
+            existing.add(counts);
+        } else if self.keys.len() < max_keys {
+            self.keys.insert(key, counts);
+        } else {
+            self.other.add(counts);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Bucket {
+    /// Start of the interval the counts belong to, in Unix seconds
+    start: u64,
+    total: Counts,
+    countries: KeyCounts,
+    asns: KeyCounts,
+}
+
+/// One key's counts in a [`Ranking`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct KeyCount {
+    pub key: String,
+    #[serde(flatten)]
+    pub counts: Counts,
+}
+
+/// The top keys of a dimension, most flagged first, with the rest folded
+/// into `other`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, ToSchema)]
+pub struct Ranking {
+    pub top: Vec<KeyCount>,
+    pub other: Counts,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct AggregateStats {
+    pub window_secs: u64,
+    pub total: Counts,
+    /// By ISO country code
+    pub countries: Ranking,
+    /// By autonomous system number
+    pub asns: Ranking,
+}
+
+pub struct LookupAggregates {
+    buckets: Vec<Mutex<Bucket>>,
+    bucket_secs: u64,
+    max_keys: usize,
+    /// Unix seconds; replaceable so tests control bucket boundaries
+    clock: Box<dyn Fn() -> u64 + Send + Sync>,
+}
+
+impl std::fmt::Debug for LookupAggregates {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LookupAggregates")
+            .field("buckets", &self.buckets.len())
+            .field("bucket_secs", &self.bucket_secs)
+            .field("max_keys", &self.max_keys)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for LookupAggregates {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LookupAggregates {
+    pub fn new() -> Self {
+        Self::with_clock(BUCKETS, BUCKET_SECS, MAX_KEYS, unix_now)
+    }
+
+    /// `buckets` buckets of `bucket_secs` each, reading the time from `clock`
+    pub fn with_clock(
+        buckets: usize,
+        bucket_secs: u64,
+        max_keys: usize,
+        clock: impl Fn() -> u64 + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            buckets: (0..buckets.max(1)).map(|_| Mutex::new(Bucket::default())).collect(),
+            bucket_secs: bucket_secs.max(1),
+            max_keys,
+            clock: Box::new(clock),
+        }
+    }
+
+    /// The longest window [`LookupAggregates::stats`] can cover
+    pub fn max_window(&self) -> Duration {
+        Duration::from_secs(self.bucket_secs * self.buckets.len() as u64)
+    }
+
+    /// Count a served lookup under its country and ASN
+    pub fn record(&self, response: &LookupResponse) {
+        let counts = Counts {
+            lookups: 1,
+            flagged: u64::from(!response.threat_details.is_empty()),
+        };
+        let country = response
+            .geo_info
+            .as_ref()
+            .and_then(|geo| geo.country.as_ref())
+            .and_then(|country| country.iso_code.clone());
+        let asn = response
+            .asn_info
+            .as_ref()
+            .and_then(|asn| asn.autonomous_system_number)
+            .map(|number| number.to_string());
+
+        let start = self.bucket_start((self.clock)());
+        let mut bucket = self.buckets[self.slot(start)].lock();
+        if bucket.start != start {
+            // Left over from a previous turn of the ring
+            *bucket = Bucket { start, ..Bucket::default() };
+        }
+        bucket.total.add(counts);
+        bucket.countries.record(country, counts, self.max_keys);
+        bucket.asns.record(asn, counts, self.max_keys);
+    }
+
+    /// Counts over the buckets overlapping the last `window`, capped at
+    /// [`LookupAggregates::max_window`], with the top `limit` keys per dimension
+    pub fn stats(&self, window: Duration, limit: usize) -> AggregateStats {
+        let window = window.min(self.max_window());
+        let buckets = window.as_secs().div_ceil(self.bucket_secs).max(1);
+        let current = self.bucket_start((self.clock)());
+        let oldest = current.saturating_sub((buckets - 1) * self.bucket_secs);
+
+        let mut total = Counts::default();
+        let mut countries = Merged::default();
+        let mut asns = Merged::default();
+        for bucket in &self.buckets {
+            let bucket = bucket.lock();
+            if bucket.total.lookups == 0 || bucket.start < oldest || bucket.start > current {
+                continue;
+            }
+            total.add(bucket.total);
+            countries.add(&bucket.countries);
+            asns.add(&bucket.asns);
+        }
+
+        AggregateStats {
+            window_secs: buckets * self.bucket_secs,
+            total,
+            countries: countries.rank(limit),
+            asns: asns.rank(limit),
+        }
+    }
+
+    fn bucket_start(&self, now: u64) -> u64 {
+        now - now % self.bucket_secs
+    }
+
+    fn slot(&self, start: u64) -> usize {
+        (start / self.bucket_secs % self.buckets.len() as u64) as usize
+    }
+}
+
+/// Several buckets' counts for one dimension
+#[derive(Default)]
+struct Merged {
+    keys: HashMap<String, Counts>,
+    other: Counts,
+}
+
+impl Merged {
+    fn add(&mut self, counts: &KeyCounts) {
+        for (key, key_counts) in &counts.keys {
+            self.keys.entry(key.clone()).or_default().add(*key_counts);
+        }
+        self.other.add(counts.other);
+    }
+
+    fn rank(self, limit: usize) -> Ranking {
+        let mut keys: Vec<KeyCount> = self.keys.into_iter().map(|(key, counts)| KeyCount { key, counts }).collect();
+        keys.sort_by(|a, b| {
+            b.counts
+                .flagged
+                .cmp(&a.counts.flagged)
+                .then(b.counts.lookups.cmp(&a.counts.lookups))
+                .then_with(|| a.key.cmp(&b.key))
+        });
+        let mut other = self.other;
+        for spilled in keys.drain(limit.min(keys.len())..) {
+            other.add(spilled.counts);
+        }
+        Ranking { top: keys, other }
+    }
+}
+
+/// Parses a window like `90s`, `15m` or `1h`
+pub fn parse_window(window: &str) -> Option<Duration> {
+    let split = window.find(|c: char| !c.is_ascii_digit())?;
+    let (amount, unit) = window.split_at(split);
+    let amount: u64 = amount.parse().ok()?;
+    let unit_secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        _ => return None,
+    };
+    Some(Duration::from_secs(amount.checked_mul(unit_secs)?)).filter(|window| !window.is_zero())
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::handlers::LookupErrors;
+    use crate::models::location::{AsnInfo, Country, GeoInfo};
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    fn response(country: &str, asn: u32, flagged: bool) -> LookupResponse {
+        LookupResponse {
+            ip: "203.0.113.7".to_string(),
+            canonical_ip: "203.0.113.7".to_string(),
+            geo_info: Some(GeoInfo {
+                city: None,
+                country: Some(Country { names: None, iso_code: Some(country.to_string()) }),
+                location: None,
+                traits: None,
+            }),
+            asn_info: Some(AsnInfo {
+                autonomous_system_number: Some(asn),
+                autonomous_system_organization: None,
+            }),
+            is_vpn_or_datacenter: flagged,
+            is_proxy: false,
+            proxy_type: None,
+            is_tor_exit_node: false,
+            recently_delisted: false,
+            categories: Default::default(),
+            category: None,
+            matched_source: None,
+            cloud_provider: None,
+            is_anonymous_proxy: None,
+            is_anycast: None,
+            is_satellite_provider: None,
+            is_hosting_provider: None,
+            threat_score: if flagged { 60 } else { 0 },
+            threat_details: if flagged { vec!["IP is associated with a VPN or data center".to_string()] } else { vec![] },
+            findings_truncated: false,
+            recommended_action: "allow".to_string(),
+            shadow_action: None,
+            disabled_features: Vec::new(),
+            errors: LookupErrors::default(),
+            partial: false,
+            data_unavailable: false,
+            skipped: vec![],
+            challenge_token: None,
+        }
+    }
+
+    /// Aggregates on a clock that starts at `start` and is moved by the test
+    fn aggregates(max_keys: usize, start: u64) -> (LookupAggregates, Arc<AtomicU64>) {
+        let now = Arc::new(AtomicU64::new(start));
+        let clock = Arc::clone(&now);
+        (LookupAggregates::with_clock(BUCKETS, BUCKET_SECS, max_keys, move || clock.load(Ordering::Relaxed)), now)
+    }
+
+    fn key(key: &str, lookups: u64, flagged: u64) -> KeyCount {
+        KeyCount { key: key.to_string(), counts: Counts { lookups, flagged } }
+    }
+
+    #[test]
+    fn test_buckets_rotate_at_boundaries() {
+        let (aggregates, now) = aggregates(MAX_KEYS, 1_000_200);
+        // 1_000_200 is 0 s into its bucket: 1_000_200 % 300 == 0
+        aggregates.record(&response("US", 15169, true));
+        now.store(1_000_499, Ordering::Relaxed);
+        aggregates.record(&response("US", 15169, false));
+        assert_eq!(aggregates.stats(Duration::from_secs(300), 10).total, Counts { lookups: 2, flagged: 1 });
+
+        // The next second starts a new bucket
+        now.store(1_000_500, Ordering::Relaxed);
+        aggregates.record(&response("DE", 3320, false));
+        let stats = aggregates.stats(Duration::from_secs(300), 10);
+        assert_eq!(stats.total, Counts { lookups: 1, flagged: 0 });
+        assert_eq!(stats.countries.top, vec![key("DE", 1, 0)]);
+        let stats = aggregates.stats(Duration::from_secs(600), 10);
+        assert_eq!(stats.window_secs, 600);
+        assert_eq!(stats.countries.top, vec![key("US", 2, 1), key("DE", 1, 0)]);
+
+        // A whole turn of the ring later, both slots are reused and cleared
+        let turn = BUCKET_SECS * BUCKETS as u64;
+        now.store(1_000_200 + turn, Ordering::Relaxed);
+        aggregates.record(&response("FR", 16276, false));
+        let stats = aggregates.stats(HOUR * 2, 10);
+        assert_eq!(stats.total, Counts { lookups: 2, flagged: 0 });
+        assert_eq!(stats.countries.top, vec![key("DE", 1, 0), key("FR", 1, 0)]);
+
+        // Buckets older than the window are left out without being cleared
+        now.store(1_000_200 + turn + HOUR.as_secs(), Ordering::Relaxed);
+        assert_eq!(aggregates.stats(HOUR, 10).total, Counts::default());
+        assert_eq!(aggregates.stats(HOUR * 3, 10).window_secs, turn);
+    }
+
+    #[test]
+    fn test_parse_window() {
+        assert_eq!(parse_window("1h"), Some(HOUR));
+        assert_eq!(parse_window("15m"), Some(Duration::from_secs(900)));
+        assert_eq!(parse_window("90s"), Some(Duration::from_secs(90)));
+        for invalid in ["", "h", "1", "0m", "1d", "-1h", "1.5h"] {
+            assert_eq!(parse_window(invalid), None, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_keys_beyond_limits_spill_into_other() {
+        let (aggregates, _) = aggregates(2, 1_000_200);
+        for (country, flagged) in [("US", true), ("DE", false), ("DE", false), ("FR", true), ("US", true), ("NL", false)] {
+            aggregates.record(&response(country, 64500, flagged));
+        }
+
+        // FR and NL arrived after the bucket's two slots were taken
+        let stats = aggregates.stats(HOUR, 10);
+        assert_eq!(stats.countries.top, vec![key("US", 2, 2), key("DE", 2, 0)]);
+        assert_eq!(stats.countries.other, Counts { lookups: 2, flagged: 1 });
+        assert_eq!(stats.asns.top, vec![key("64500", 6, 3)]);
+        assert_eq!(stats.total, Counts { lookups: 6, flagged: 3 });
+
+        // Ranking past `limit` folds the rest into other too
+        let stats = aggregates.stats(HOUR, 1);
+        assert_eq!(stats.countries.top, vec![key("US", 2, 2)]);
+        assert_eq!(stats.countries.other, Counts { lookups: 4, flagged: 1 });
+    }
+}
@@ -2,43 +2,55 @@
 //!
 //! Periodically checks remote sources for updated files, compares with local versions, and updates if necessary.
 
+use std::path::PathBuf;
 use std::time::Duration;
+use reqwest::Client;
 use tokio::time::sleep;
+use crate::config::data_layout::DataLayout;
 use crate::utils::file_ops::{files_differ, atomic_replace};
-use crate::utils::http_client::download_file;
+use crate::utils::http_client::{download_file, CertPins};
 use tempfile::TempDir;
 
-/// Configuration for the background updater.
+/// A remote file and the local path it is mirrored to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpdateSource {
+    pub url: String,
+    pub path: String,
+}
+
+/// Configuration for the background updater, built from `Settings::background_updater`.
+#[derive(Debug, Clone, PartialEq)]
 pub struct BackgroundUpdaterConfig {
-    /// Remote URL for VPN list
-    pub vpn_url: String,
-    /// Remote URL for HTTP proxies
-    pub http_proxy_url: String,
-    /// Remote URL for SOCKS4 proxies
-    pub socks4_proxy_url: String,
-    /// Remote URL for SOCKS5 proxies
-    pub socks5_proxy_url: String,
-    /// Remote URL for Tor exit nodes
-    pub tor_exit_nodes_url: String,
+    /// Files to keep up to date, checked in order
+    pub sources: Vec<UpdateSource>,
     /// How often to check for updates (seconds)
     pub interval_secs: u64,
-    /// Local file paths
-    pub vpn_path: String,
-    pub http_proxy_path: String,
-    pub socks4_proxy_path: String,
-    pub socks5_proxy_path: String,
-    pub tor_exit_nodes_path: String,
 }
 
 /// Main background updater struct.
 pub struct BackgroundUpdater {
     pub config: BackgroundUpdaterConfig,
+    http_client: Client,
+    cert_pins: CertPins,
+    temp_dir: PathBuf,
 }
 
 impl BackgroundUpdater {
-    /// Create a new BackgroundUpdater with the given configuration.
-    pub fn new(config: BackgroundUpdaterConfig) -> Self {
-        Self { config }
+    /// Create a new BackgroundUpdater that downloads with `http_client`.
+    pub fn new(config: BackgroundUpdaterConfig, http_client: Client) -> Self {
+        Self { config, http_client, cert_pins: CertPins::default(), temp_dir: DataLayout::default().tmp_update_dir() }
+    }
+
+    /// Download into `temp_dir` before replacing the local copies
+    pub fn with_temp_dir(mut self, temp_dir: PathBuf) -> Self {
+        self.temp_dir = temp_dir;
+        self
+    }
+
+    /// Only accept downloads from servers presenting one of `cert_pins`
+    pub fn with_cert_pins(mut self, cert_pins: CertPins) -> Self {
+        self.cert_pins = cert_pins;
+        self
     }
 
     /// Start the background update loop as a Tokio task.
@@ -53,34 +65,11 @@ impl BackgroundUpdater {
 
     /// Check and update all files if needed.
     async fn check_and_update(&self) -> std::io::Result<()> {
-        std::fs::create_dir_all("data/tmp_update")?;
-        let temp_dir = TempDir::new_in("data/tmp_update")?;
-        self.check_one(
-            &self.config.vpn_url,
-            &self.config.vpn_path,
-            &temp_dir
-        ).await?;
-        self.check_one(
-            &self.config.http_proxy_url,
-            &self.config.http_proxy_path,
-            &temp_dir
-        ).await?;
-        self.check_one(
-            &self.config.socks4_proxy_url,
-            &self.config.socks4_proxy_path,
-            &temp_dir
-        ).await?;
-        self.check_one(
-            &self.config.socks5_proxy_url,
-            &self.config.socks5_proxy_path,
-            &temp_dir
-        ).await?;
-        // Tor Exit Nodes
-        self.check_one(
-            &self.config.tor_exit_nodes_url,
-            &self.config.tor_exit_nodes_path,
-            &temp_dir
-        ).await?;
+        std::fs::create_dir_all(&self.temp_dir)?;
+        let temp_dir = TempDir::new_in(&self.temp_dir)?;
+        for source in &self.config.sources {
+            self.check_one(&source.url, &source.path, &temp_dir).await?;
+        }
         // After all checks/updates
         // temp_dir is dropped here, and the directory + all files are deleted automatically
         Ok(())
@@ -96,7 +85,7 @@ impl BackgroundUpdater {
         let temp_path = temp_dir.path().join("download.tmp");
         
         // Download the file
-        if let Err(e) = download_file(url, &temp_path).await {
+        if let Err(e) = download_file(&self.http_client, &self.cert_pins, url, &temp_path).await {
             eprintln!("[BackgroundUpdater] Download error for {}: {}", url, e);
             return Err(e);
         }
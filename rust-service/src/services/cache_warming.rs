@@ -0,0 +1,205 @@
+//! Pre-populates the lookup cache from an operator-supplied IP list so the
+//! first requests after a deploy don't all miss.
+
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::time::MissedTickBehavior;
+use tracing::{info, warn};
+
+use crate::services::lookup_service::LookupService;
+use crate::utils::redact;
+
+/// How often to log progress, in processed entries
+const PROGRESS_INTERVAL: usize = 10_000;
+
+/// Settings for a warming run
+#[derive(Debug, Clone)]
+pub struct CacheWarmingConfig {
+    /// Newline-delimited IP list; blank lines and `#` comments are ignored
+    pub path: PathBuf,
+    /// Maximum lookups started per second
+    pub rate_per_sec: u32,
+    /// Maximum lookups in flight at once
+    pub concurrency: usize,
+}
+
+/// Outcome of a warming run
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WarmingSummary {
+    /// Entries looked up and cached
+    pub warmed: usize,
+    /// Entries that were already cached
+    pub cache_hits: usize,
+    /// Lookups that failed (usually geo database errors)
+    pub errors: usize,
+    /// Lines that were not valid IP addresses
+    pub malformed: usize,
+}
+
+/// Parse an IP list, returning the addresses and the number of malformed lines
+fn parse_ip_list(content: &str) -> (Vec<IpAddr>, usize) {
+    let mut ips = Vec::new();
+    let mut malformed = 0;
+
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.parse() {
+            Ok(ip) => ips.push(ip),
+            Err(_) => malformed += 1,
+        }
+    }
+
+    (ips, malformed)
+}
+
+/// Look up every IP in `config.path` through `service`, filling its cache
+pub async fn warm_cache(
+    service: Arc<LookupService>,
+    config: &CacheWarmingConfig,
+) -> std::io::Result<WarmingSummary> {
+    let content = tokio::fs::read_to_string(&config.path).await?;
+    let (ips, malformed) = parse_ip_list(&content);
+    info!(
+        path = %config.path.display(),
+        entries = ips.len(),
+        malformed,
+        "Warming lookup cache"
+    );
+
+    let mut summary = WarmingSummary {
+        malformed,
+        ..Default::default()
+    };
+
+    let mut rate = tokio::time::interval(Duration::from_secs(1) / config.rate_per_sec.max(1));
+    rate.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let permits = Arc::new(Semaphore::new(config.concurrency.max(1)));
+    let mut tasks = JoinSet::new();
+    let total = ips.len();
+
+    for (i, ip) in ips.into_iter().enumerate() {
+        rate.tick().await;
+
+        if service.is_cached(ip) {
+            summary.cache_hits += 1;
+        } else {
+            let permit = Arc::clone(&permits)
+                .acquire_owned()
+                .await
+                .expect("warming semaphore is never closed");
+            let service = Arc::clone(&service);
+            tasks.spawn(async move {
+                let _permit = permit;
+                service.lookup_ip(ip).await.map_err(|e| (ip, e))
+            });
+        }
+
+        // Collect finished lookups as we go so the set doesn't grow unbounded
+        while let Some(result) = tasks.try_join_next() {
+            record(&mut summary, result);
+        }
+
+        if (i + 1) % PROGRESS_INTERVAL == 0 {
+            info!(processed = i + 1, total, "Cache warming progress");
+        }
+    }
+
+    while let Some(result) = tasks.join_next().await {
+        record(&mut summary, result);
+    }
+
+    info!(
+        warmed = summary.warmed,
+        cache_hits = summary.cache_hits,
+        errors = summary.errors,
+        malformed = summary.malformed,
+        "Cache warming complete"
+    );
+    Ok(summary)
+}
+
+fn record<T>(
+    summary: &mut WarmingSummary,
+    result: Result<Result<T, (IpAddr, crate::errors::AppError)>, tokio::task::JoinError>,
+) {
+    match result {
+        Ok(Ok(_)) => summary.warmed += 1,
+        Ok(Err((ip, e))) => {
+            warn!(ip = %redact::ip(ip), error = %e, "Cache warming lookup failed");
+            summary.errors += 1;
+        }
+        Err(e) => {
+            warn!(error = %e, "Cache warming task failed");
+            summary.errors += 1;
+        }
+    }
+}
+
+/// Start warming in the background once `ready` resolves
+pub fn spawn_warming<F>(
+    service: Arc<LookupService>,
+    config: CacheWarmingConfig,
+    ready: F,
+) -> tokio::task::JoinHandle<()>
+where
+    F: std::future::Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(async move {
+        ready.await;
+        if let Err(e) = warm_cache(service, &config).await {
+            warn!(path = %config.path.display(), error = %e, "Cache warming failed");
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::lookup_service;
+    use crate::test_support::{self, mmdb::{FIXTURE_DE_IP, FIXTURE_US_IP}};
+
+    #[test]
+    fn test_parse_ip_list() {
+        let (ips, malformed) = parse_ip_list("8.8.8.8\n\n# comment\n  2a00:1450:4001::1 \nnope\n1.2.3\n");
+        assert_eq!(ips.len(), 2);
+        assert_eq!(malformed, 2);
+    }
+
+    #[tokio::test]
+    async fn test_warm_cache_fills_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("warm.txt");
+        std::fs::write(&path, format!("{}\nnot-an-ip\n{}\n{}\n", FIXTURE_US_IP, FIXTURE_DE_IP, FIXTURE_US_IP))
+            .unwrap();
+
+        let state = test_support::app_state();
+        let service = Arc::new(lookup_service(&state));
+        let config = CacheWarmingConfig {
+            path,
+            rate_per_sec: 1000,
+            concurrency: 4,
+        };
+
+        let summary = warm_cache(Arc::clone(&service), &config).await.unwrap();
+        assert_eq!(summary.malformed, 1);
+        assert_eq!(summary.errors, 0);
+        // The duplicate is either a cache hit or a second (idempotent) lookup
+        assert_eq!(summary.warmed + summary.cache_hits, 3);
+
+        for ip in [FIXTURE_US_IP, FIXTURE_DE_IP] {
+            assert!(service.is_cached(ip.parse().unwrap()));
+        }
+
+        // A second run finds everything cached
+        let summary = warm_cache(service, &config).await.unwrap();
+        assert_eq!(summary.cache_hits, 3);
+        assert_eq!(summary.warmed, 0);
+    }
+}
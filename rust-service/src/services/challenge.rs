@@ -0,0 +1,433 @@
+//! Challenge and clearance tokens for the `challenge` response action.
+//!
+//! With `challenge.secret` set, lookups and gates that recommend `challenge`
+//! hand out a challenge token bound to the IP and its score. Redeeming it at
+//! `POST /api/challenge/verify` with a solution returns a clearance token;
+//! lookups of the same IP that present the clearance in
+//! `X-InfraLock-Clearance` are answered `allow` instead of `challenge` until
+//! it expires. Each challenge token is redeemable once.
+//!
+//! Clearances and redeemed challenge tokens are remembered in memory and,
+//! with `cache.redis_url` set, in Redis, so every replica honors a clearance
+//! and refuses a token another replica already redeemed. While Redis is
+//! unreachable each replica falls back to what it remembers itself.
+//!
+//! Tokens are `<claims>.<signature>`: unpadded base64url JSON claims and
+//! their HMAC-SHA256 under the secret. The solution is a proof-of-work
+//! nonce: the SHA-256 of `<challenge token>:<nonce>` must start with
+//! `difficulty` zero bits. At difficulty 0 the solution is the challenge
+//! token itself, echoed back by integrators who run their own CAPTCHA.
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use moka::sync::Cache;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config::ChallengeSettings;
+use crate::services::shared_cache::SharedCache;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Clearances, and redeemed challenge tokens, tracked in memory at once;
+/// the oldest are dropped beyond this
+const MAX_CLEARANCES: u64 = 100_000;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ChallengeError {
+    #[error("malformed token")]
+    Malformed,
+    #[error("invalid token signature")]
+    BadSignature,
+    #[error("token expired")]
+    Expired,
+    #[error("not a {0} token")]
+    WrongKind(TokenKind),
+    #[error("token was issued for another IP")]
+    WrongIp,
+    #[error("solution does not meet difficulty {0}")]
+    Unsolved(u8),
+    #[error("challenge token was already redeemed")]
+    AlreadyRedeemed,
+    #[error("clearance was not issued by this service")]
+    UnknownClearance,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenKind {
+    Challenge,
+    Clearance,
+}
+
+impl std::fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            TokenKind::Challenge => "challenge",
+            TokenKind::Clearance => "clearance",
+        })
+    }
+}
+
+/// What a token vouches for
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Claims {
+    pub kind: TokenKind,
+    pub ip: IpAddr,
+    /// Threat score the challenge was issued at
+    pub score: u8,
+    /// Expiry, in Unix seconds
+    pub exp: i64,
+}
+
+/// Issues and checks challenge and clearance tokens
+pub struct ChallengeService {
+    key: Vec<u8>,
+    challenge_ttl: Duration,
+    clearance_ttl: Duration,
+    difficulty: u8,
+    /// Signatures of the clearances issued by this instance
+    clearances: Cache<String, IpAddr>,
+    /// Signatures of the challenge tokens redeemed by this instance, kept
+    /// for as long as a challenge token lives
+    redeemed: Cache<String, ()>,
+    /// Shares clearances and redeemed tokens with the other replicas
+    shared: Option<Arc<SharedCache>>,
+}
+
+impl std::fmt::Debug for ChallengeService {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChallengeService")
+            .field("challenge_ttl", &self.challenge_ttl)
+            .field("clearance_ttl", &self.clearance_ttl)
+            .field("difficulty", &self.difficulty)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ChallengeService {
+    /// `None` unless `challenge.secret` is set
+    pub fn from_settings(settings: &ChallengeSettings) -> Option<Self> {
+        let secret = settings.secret.as_deref()?;
+        Some(Self::new(
+            secret.as_bytes(),
+            Duration::from_secs(settings.ttl_secs),
+            Duration::from_secs(settings.clearance_ttl_secs),
+            settings.difficulty,
+        ))
+    }
+
+    pub fn new(key: &[u8], challenge_ttl: Duration, clearance_ttl: Duration, difficulty: u8) -> Self {
+        Self {
+            key: key.to_vec(),
+            challenge_ttl,
+            clearance_ttl,
+            difficulty,
+            clearances: Cache::builder()
+                .max_capacity(MAX_CLEARANCES)
+                .time_to_live(clearance_ttl)
+                .build(),
+            redeemed: Cache::builder()
+                .max_capacity(MAX_CLEARANCES)
+                .time_to_live(challenge_ttl)
+                .build(),
+            shared: None,
+        }
+    }
+
+    /// Keep clearances and redeemed tokens in `shared` too, if set
+    pub fn with_shared_cache(mut self, shared: Option<Arc<SharedCache>>) -> Self {
+        self.shared = shared;
+        self
+    }
+
+    /// A challenge token for `ip`, scored `score`
+    pub fn issue_challenge(&self, ip: IpAddr, score: u8) -> String {
+        self.issue_challenge_at(ip, score, now())
+    }
+
+    fn issue_challenge_at(&self, ip: IpAddr, score: u8, now: i64) -> String {
+        self.sign(&Claims {
+            kind: TokenKind::Challenge,
+            ip,
+            score,
+            exp: now + self.challenge_ttl.as_secs() as i64,
+        })
+    }
+
+    /// Checks `solution` against the challenge `token` and returns a
+    /// clearance token for its IP. A token is only redeemed once.
+    pub async fn redeem(&self, token: &str, solution: &str) -> Result<(String, Claims), ChallengeError> {
+        self.redeem_at(token, solution, now()).await
+    }
+
+    async fn redeem_at(&self, token: &str, solution: &str, now: i64) -> Result<(String, Claims), ChallengeError> {
+        let challenge = self.verify(token, TokenKind::Challenge, now)?;
+        if !self.solves(token, solution) {
+            return Err(ChallengeError::Unsolved(self.difficulty));
+        }
+        // Past its expiry the token is refused anyway, so it need not be kept longer
+        let remaining = (challenge.exp - now) as u64;
+        if !self.mark_redeemed(signature_of(token), remaining).await {
+            return Err(ChallengeError::AlreadyRedeemed);
+        }
+
+        let claims = Claims {
+            kind: TokenKind::Clearance,
+            exp: now + self.clearance_ttl.as_secs() as i64,
+            ..challenge
+        };
+        let clearance = self.sign(&claims);
+        let signature = signature_of(&clearance);
+        self.clearances.insert(signature.to_string(), claims.ip);
+        if let Some(shared) = &self.shared {
+            let key = clearance_key(signature);
+            // Without Redis the clearance is honored by this replica alone
+            let _ = shared.insert_new(&key, &claims.ip.to_string(), self.clearance_ttl.as_secs()).await;
+        }
+        Ok((clearance, claims))
+    }
+
+    /// Whether `token` is a live clearance issued for `ip`, here or, with a
+    /// shared cache, by another replica
+    pub async fn check_clearance(&self, token: &str, ip: IpAddr) -> Result<Claims, ChallengeError> {
+        self.check_clearance_at(token, ip, now()).await
+    }
+
+    async fn check_clearance_at(&self, token: &str, ip: IpAddr, now: i64) -> Result<Claims, ChallengeError> {
+        let claims = self.verify(token, TokenKind::Clearance, now)?;
+        if claims.ip != ip {
+            return Err(ChallengeError::WrongIp);
+        }
+        let signature = signature_of(token);
+        if self.clearances.get(signature) == Some(ip) {
+            return Ok(claims);
+        }
+        let shared_ip = match &self.shared {
+            Some(shared) => shared.value(&clearance_key(signature)).await.ok().flatten(),
+            None => None,
+        };
+        if shared_ip.and_then(|shared_ip| shared_ip.parse().ok()) != Some(ip) {
+            return Err(ChallengeError::UnknownClearance);
+        }
+        Ok(claims)
+    }
+
+    /// Record the challenge token signed `signature` as redeemed for
+    /// `ttl_secs`, returning false if it already was, here or on another
+    /// replica
+    async fn mark_redeemed(&self, signature: &str, ttl_secs: u64) -> bool {
+        let fresh = self.redeemed.entry(signature.to_string()).or_insert(()).is_fresh();
+        if !fresh {
+            return false;
+        }
+        match &self.shared {
+            // With Redis unreachable, only this replica's record is checked
+            Some(shared) => shared.insert_new(&redeemed_key(signature), "1", ttl_secs).await.unwrap_or(true),
+            None => true,
+        }
+    }
+
+    fn sign(&self, claims: &Claims) -> String {
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims).expect("claims serialize"));
+        let signature = URL_SAFE_NO_PAD.encode(self.mac(&payload).finalize().into_bytes());
+        format!("{}.{}", payload, signature)
+    }
+
+    fn mac(&self, payload: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    /// The claims of `token` if its signature, kind and expiry check out
+    fn verify(&self, token: &str, kind: TokenKind, now: i64) -> Result<Claims, ChallengeError> {
+        let (payload, signature) = token.split_once('.').ok_or(ChallengeError::Malformed)?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| ChallengeError::Malformed)?;
+        // Constant-time comparison
+        self.mac(payload)
+            .verify_slice(&signature)
+            .map_err(|_| ChallengeError::BadSignature)?;
+
+        let claims: Claims = URL_SAFE_NO_PAD
+            .decode(payload)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or(ChallengeError::Malformed)?;
+        if claims.kind != kind {
+            return Err(ChallengeError::WrongKind(kind));
+        }
+        if claims.exp <= now {
+            return Err(ChallengeError::Expired);
+        }
+        Ok(claims)
+    }
+
+    fn solves(&self, token: &str, solution: &str) -> bool {
+        if self.difficulty == 0 {
+            return solution == token;
+        }
+        let digest = Sha256::new()
+            .chain_update(token.as_bytes())
+            .chain_update(b":")
+            .chain_update(solution.as_bytes())
+            .finalize();
+        leading_zero_bits(&digest) >= u32::from(self.difficulty)
+    }
+}
+
+fn clearance_key(signature: &str) -> String {
+    format!("challenge:clearance:{}", signature)
+}
+
+fn redeemed_key(signature: &str) -> String {
+    format!("challenge:redeemed:{}", signature)
+}
+
+fn signature_of(token: &str) -> &str {
+    token.rsplit_once('.').map_or(token, |(_, signature)| signature)
+}
+
+fn leading_zero_bits(bytes: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in bytes {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+fn now() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(5, 1, 1, 1));
+    const NOW: i64 = 1_700_000_000;
+
+    fn service(difficulty: u8) -> ChallengeService {
+        ChallengeService::new(b"test-secret", Duration::from_secs(300), Duration::from_secs(3600), difficulty)
+    }
+
+    /// The first nonce meeting `service`'s difficulty
+    fn solve(service: &ChallengeService, token: &str) -> String {
+        (0u64..).map(|nonce| nonce.to_string()).find(|nonce| service.solves(token, nonce)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_proof_of_work_clears_ip() {
+        let service = service(8);
+        let token = service.issue_challenge_at(IP, 60, NOW);
+        assert_eq!(service.redeem_at(&token, "not-a-solution", NOW).await, Err(ChallengeError::Unsolved(8)));
+
+        let (clearance, claims) = service.redeem_at(&token, &solve(&service, &token), NOW + 10).await.unwrap();
+        assert_eq!(claims, Claims { kind: TokenKind::Clearance, ip: IP, score: 60, exp: NOW + 3610 });
+        assert_eq!(service.check_clearance_at(&clearance, IP, NOW + 20).await, Ok(claims));
+
+        let other_ip = "5.1.1.2".parse().unwrap();
+        assert_eq!(service.check_clearance_at(&clearance, other_ip, NOW + 20).await, Err(ChallengeError::WrongIp));
+        // A challenge token is not a clearance, nor the other way round
+        assert_eq!(
+            service.check_clearance_at(&token, IP, NOW + 20).await,
+            Err(ChallengeError::WrongKind(TokenKind::Clearance))
+        );
+        assert_eq!(
+            service.redeem_at(&clearance, "0", NOW + 20).await,
+            Err(ChallengeError::WrongKind(TokenKind::Challenge))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_echo_solution_at_difficulty_zero() {
+        let service = service(0);
+        let token = service.issue_challenge_at(IP, 60, NOW);
+        assert_eq!(service.redeem_at(&token, "0", NOW).await, Err(ChallengeError::Unsolved(0)));
+        assert!(service.redeem_at(&token, &token, NOW).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_tampered_tokens_rejected() {
+        let service = service(0);
+        let token = service.issue_challenge_at(IP, 60, NOW);
+        let (payload, signature) = token.split_once('.').unwrap();
+
+        // Claims rewritten to a lower score keep the old signature
+        let mut claims: Claims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).unwrap()).unwrap();
+        claims.score = 0;
+        let forged = format!("{}.{}", URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).unwrap()), signature);
+        assert_eq!(service.redeem_at(&forged, &forged, NOW).await, Err(ChallengeError::BadSignature));
+
+        // Signed under another secret
+        let other = ChallengeService::new(b"other-secret", Duration::from_secs(300), Duration::from_secs(3600), 0);
+        let foreign = other.issue_challenge_at(IP, 60, NOW);
+        assert_eq!(service.redeem_at(&foreign, &foreign, NOW).await, Err(ChallengeError::BadSignature));
+
+        assert_eq!(service.redeem_at("garbage", "garbage", NOW).await, Err(ChallengeError::Malformed));
+        assert_eq!(service.redeem_at("a.!!", "a.!!", NOW).await, Err(ChallengeError::Malformed));
+    }
+
+    #[tokio::test]
+    async fn test_expired_tokens_rejected() {
+        let service = service(0);
+        let token = service.issue_challenge_at(IP, 60, NOW);
+        assert_eq!(service.redeem_at(&token, &token, NOW + 300).await, Err(ChallengeError::Expired));
+
+        let (clearance, _) = service.redeem_at(&token, &token, NOW + 299).await.unwrap();
+        assert!(service.check_clearance_at(&clearance, IP, NOW + 299 + 3599).await.is_ok());
+        assert_eq!(
+            service.check_clearance_at(&clearance, IP, NOW + 299 + 3600).await,
+            Err(ChallengeError::Expired)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_clearance_from_another_instance_not_honored() {
+        let token = service(0).issue_challenge_at(IP, 60, NOW);
+        let issuer = service(0);
+        let (clearance, _) = issuer.redeem_at(&token, &token, NOW).await.unwrap();
+        assert_eq!(
+            service(0).check_clearance_at(&clearance, IP, NOW).await,
+            Err(ChallengeError::UnknownClearance)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_challenge_redeemed_once() {
+        let service = service(0);
+        let token = service.issue_challenge_at(IP, 60, NOW);
+        assert!(service.redeem_at(&token, &token, NOW).await.is_ok());
+        assert_eq!(service.redeem_at(&token, &token, NOW + 1).await, Err(ChallengeError::AlreadyRedeemed));
+        // Another token for the same IP is still redeemable
+        let token = service.issue_challenge_at(IP, 60, NOW + 1);
+        assert!(service.redeem_at(&token, &token, NOW + 1).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_replicas_share_clearances_and_redeemed_tokens() {
+        use crate::config::CacheSettings;
+        use crate::test_support::redis::MockRedis;
+
+        let redis = MockRedis::start().await;
+        let replica = || {
+            let settings = CacheSettings { redis_url: Some(redis.url()), redis_timeout_ms: 500, ..CacheSettings::default() };
+            let shared = SharedCache::from_settings(&settings, Arc::new(Cache::new(100))).unwrap().map(Arc::new);
+            service(0).with_shared_cache(shared)
+        };
+        let (first, second) = (replica(), replica());
+
+        let token = first.issue_challenge_at(IP, 60, NOW);
+        let (clearance, claims) = first.redeem_at(&token, &token, NOW).await.unwrap();
+        assert_eq!(second.check_clearance_at(&clearance, IP, NOW + 1).await, Ok(claims));
+        assert_eq!(second.redeem_at(&token, &token, NOW + 1).await, Err(ChallengeError::AlreadyRedeemed));
+    }
+}
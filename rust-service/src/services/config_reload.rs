@@ -0,0 +1,317 @@
+//! Applies a re-read configuration to the running service.
+//!
+//! Triggered by SIGHUP or `POST /api/admin/reload-config`. Scoring weights,
+//! response action thresholds, profiles, the log filter and IP redaction
+//! are swapped in place; everything else is only reported as needing a
+//! restart. The radix tree and geo readers are left alone, but cached
+//! lookups are dropped when their verdicts could change.
+
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::config::runtime::{RuntimeConfig, SharedRuntimeConfig};
+use crate::config::validation;
+use crate::config::Settings;
+use crate::errors::AppError;
+use crate::services::lookup_service::LookupCache;
+use crate::services::shared_cache::SharedCache;
+use crate::telemetry::{self, LogFilterHandle};
+use crate::utils::redact::{self, IpRedactor};
+
+/// Sections that only change how the service logs, not its verdicts
+const LOGGING_SECTIONS: [&str; 3] = ["telemetry.log_filter", "telemetry.log_ip_redaction", "telemetry.log_ip_hash_key"];
+
+/// Outcome of a reload: the changed settings, by config section
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub struct ReloadReport {
+    /// Changed and now in effect
+    pub applied: Vec<&'static str>,
+    /// Changed, but ignored until the service restarts
+    pub restart_required: Vec<&'static str>,
+}
+
+#[derive(Debug)]
+pub struct ConfigReloader {
+    /// The settings in effect, including any pending restart-only changes
+    /// as they were at startup
+    current: Mutex<Settings>,
+    runtime: SharedRuntimeConfig,
+    lookup_cache: Arc<LookupCache>,
+    shared_cache: Option<Arc<SharedCache>>,
+    /// Set when the reloader owns the process's logging; without it the log
+    /// filter and IP redactor are left alone
+    log_filter: Option<LogFilterHandle>,
+}
+
+impl ConfigReloader {
+    pub fn new(
+        settings: Settings,
+        runtime: SharedRuntimeConfig,
+        lookup_cache: Arc<LookupCache>,
+        log_filter: Option<LogFilterHandle>,
+    ) -> Self {
+        Self {
+            current: Mutex::new(settings),
+            runtime,
+            lookup_cache,
+            shared_cache: None,
+            log_filter,
+        }
+    }
+
+    /// Purge the other replicas' caches too when a reload changes verdicts
+    pub fn with_shared_cache(mut self, shared_cache: Option<Arc<SharedCache>>) -> Self {
+        self.shared_cache = shared_cache;
+        self
+    }
+
+    /// Re-read `Settings` from the config files and environment and apply it
+    pub fn reload(&self) -> Result<ReloadReport, AppError> {
+        let settings = Settings::layered()?;
+        self.apply(settings)
+    }
+
+    /// Apply the hot-swappable parts of `settings`. Invalid settings are
+    /// rejected as a whole and the running configuration is kept.
+    pub fn apply(&self, settings: Settings) -> Result<ReloadReport, AppError> {
+        let diagnostics = validation::validate_runtime(&settings, std::env::vars());
+        if validation::has_errors(&diagnostics) {
+            let problems: Vec<String> = diagnostics.iter().map(ToString::to_string).collect();
+            return Err(config::ConfigError::Message(problems.join("; ")).into());
+        }
+
+        let mut current = self.current.lock();
+        let report = diff(&current, &settings);
+
+        if report.applied.iter().any(|section| !LOGGING_SECTIONS.contains(section)) {
+            let generation = self.runtime.load().generation + 1;
+            self.runtime.store(Arc::new(RuntimeConfig { generation, ..RuntimeConfig::from_settings(&settings) }));
+            // Cached responses carry verdicts computed with the old thresholds
+            self.lookup_cache.invalidate_all();
+            if let Some(shared_cache) = &self.shared_cache {
+                let shared_cache = Arc::clone(shared_cache);
+                tokio::spawn(async move { shared_cache.invalidate().await });
+            }
+        }
+        if report.applied.contains(&"telemetry.log_filter") {
+            if let Some(handle) = &self.log_filter {
+                let filter = telemetry::log_filter(&settings.telemetry)
+                    .map_err(|e| config::ConfigError::Message(e.to_string()))?;
+                handle
+                    .reload(filter)
+                    .map_err(|e| config::ConfigError::Message(e.to_string()))?;
+            }
+        }
+        if report.applied.iter().any(|section| section.starts_with("telemetry.log_ip_")) && self.log_filter.is_some() {
+            redact::install(IpRedactor::new(
+                settings.telemetry.log_ip_redaction,
+                settings.telemetry.log_ip_hash_key.as_deref(),
+            ));
+        }
+
+        current.scoring = settings.scoring;
+        current.response_action = settings.response_action;
+        current.profiles = settings.profiles;
+        current.telemetry.log_filter = settings.telemetry.log_filter;
+        current.telemetry.log_ip_redaction = settings.telemetry.log_ip_redaction;
+        current.telemetry.log_ip_hash_key = settings.telemetry.log_ip_hash_key;
+
+        info!(applied = ?report.applied, "Configuration reloaded");
+        if !report.restart_required.is_empty() {
+            warn!(
+                restart_required = ?report.restart_required,
+                "Changed settings take effect after a restart"
+            );
+        }
+        Ok(report)
+    }
+}
+
+/// Changed sections of `new` relative to `old`, split by whether a reload
+/// can apply them
+fn diff(old: &Settings, new: &Settings) -> ReloadReport {
+    let hot = [
+        ("scoring", old.scoring != new.scoring),
+        ("response_action", old.response_action != new.response_action),
+        ("profiles", old.profiles != new.profiles),
+        ("telemetry.log_filter", old.telemetry.log_filter != new.telemetry.log_filter),
+        ("telemetry.log_ip_redaction", old.telemetry.log_ip_redaction != new.telemetry.log_ip_redaction),
+        ("telemetry.log_ip_hash_key", old.telemetry.log_ip_hash_key != new.telemetry.log_ip_hash_key),
+    ];
+    let restart = [
+        ("server", old.server != new.server),
+        ("maxmind", old.maxmind != new.maxmind),
+        ("geo", old.geo != new.geo),
+        ("vpn_detector", old.vpn_detector != new.vpn_detector),
+        ("proxy_detector", old.proxy_detector != new.proxy_detector),
+        ("ip_lookup", old.ip_lookup != new.ip_lookup),
+        ("features", old.features != new.features),
+        ("cache_warming", old.cache_warming != new.cache_warming),
+        ("usage", old.usage != new.usage),
+        ("challenge", old.challenge != new.challenge),
+        ("background_updater", old.background_updater != new.background_updater),
+        ("outbound_http", old.outbound_http != new.outbound_http),
+        ("cache", old.cache != new.cache),
+        ("telemetry.otlp_endpoint", old.telemetry.otlp_endpoint != new.telemetry.otlp_endpoint),
+        ("telemetry.service_name", old.telemetry.service_name != new.telemetry.service_name),
+    ];
+    let changed = |sections: &[(&'static str, bool)]| {
+        sections.iter().filter(|(_, changed)| *changed).map(|(name, _)| *name).collect()
+    };
+
+    ReloadReport {
+        applied: changed(&hot),
+        restart_required: changed(&restart),
+    }
+}
+
+/// Reload the configuration on every SIGHUP
+#[cfg(unix)]
+pub fn spawn_sighup_handler(reloader: Arc<ConfigReloader>) -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            info!("SIGHUP received, reloading configuration");
+            let reloader = Arc::clone(&reloader);
+            match tokio::task::spawn_blocking(move || reloader.reload()).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => warn!("Configuration reload rejected: {}", e),
+                Err(e) => warn!("Configuration reload failed: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ip_lookup::{tree::RadixTree, IpCategory};
+    use crate::routes::create_router;
+    use crate::test_support;
+    use crate::utils::redact::IpRedaction;
+    use axum::body::Body;
+    use axum::Router;
+    use tower::ServiceExt;
+
+    const VPN_IP: &str = "5.2.2.2";
+
+    async fn recommended_action(router: &Router) -> String {
+        let request = test_support::request()
+            .uri(format!("/api/lookup/{}", VPN_IP))
+            .body(Body::empty())
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let lookup: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        lookup["recommended_action"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_reloaded_threshold_applies_to_next_lookup() {
+        let state = test_support::app_state();
+        let mut tree = RadixTree::new();
+        tree.insert(format!("{}/32", VPN_IP).parse().unwrap(), IpCategory::Vpn);
+        state.ip_lookup_service.tree().replace(tree);
+        let reloader = Arc::clone(&state.config_reloader);
+        let router = create_router(state);
+
+        // The VPN finding scores 60 with the default weights
+        assert_eq!(recommended_action(&router).await, "challenge");
+        assert_eq!(recommended_action(&router).await, "challenge");
+
+        let mut settings = Settings::default();
+        settings.response_action.challenge_threshold = 70;
+        settings.response_action.redirect_threshold = 90;
+        let report = reloader.apply(settings).unwrap();
+        assert_eq!(report.applied, vec!["response_action"]);
+        assert!(report.restart_required.is_empty());
+
+        assert_eq!(recommended_action(&router).await, "monitor");
+    }
+
+    #[tokio::test]
+    async fn test_reloaded_datacenter_asn_orgs_flag_lookups() {
+        use crate::test_support::mmdb::FIXTURE_US_IP;
+
+        let state = test_support::app_state();
+        let reloader = Arc::clone(&state.config_reloader);
+        let router = create_router(state);
+        let lookup = || async {
+            let request = test_support::request()
+                .uri(format!("/api/lookup/{}", FIXTURE_US_IP))
+                .body(Body::empty())
+                .unwrap();
+            let response = router.clone().oneshot(request).await.unwrap();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+        assert_eq!(lookup().await["threat_score"], 0);
+
+        // The fixture's ASN organization is `GOOGLE`
+        let mut settings = Settings::default();
+        settings.scoring.datacenter_asn_orgs = vec!["digitalocean".to_string(), "^goo".to_string()];
+        assert_eq!(reloader.apply(settings.clone()).unwrap().applied, vec!["scoring"]);
+        let response = lookup().await;
+        assert_eq!(response["threat_score"], 50);
+        assert_eq!(
+            response["threat_details"],
+            serde_json::json!(["IP is announced by datacenter ASN organization GOOGLE"])
+        );
+
+        settings.scoring.datacenter_asn_orgs = vec!["(unclosed".to_string()];
+        assert!(reloader.apply(settings).is_err());
+        assert_eq!(lookup().await["threat_score"], 50);
+    }
+
+    #[test]
+    fn test_restart_only_changes_are_reported_not_applied() {
+        let state = test_support::app_state();
+        let reloader = &state.config_reloader;
+
+        let mut settings = Settings::default();
+        settings.server.port = 7000;
+        settings.maxmind.db_path = "elsewhere.mmdb".into();
+        settings.scoring.vpn_weight = 0.9;
+        let report = reloader.apply(settings.clone()).unwrap();
+        assert_eq!(report.applied, vec!["scoring"]);
+        assert_eq!(report.restart_required, vec!["server", "maxmind"]);
+        assert_eq!(state.runtime.load().scoring_config.vpn_weight, 0.9);
+        assert_eq!(state.runtime.load().generation, 1);
+
+        // Still pending, but the applied change is now current
+        let report = reloader.apply(settings).unwrap();
+        assert!(report.applied.is_empty());
+        assert_eq!(report.restart_required, vec!["server", "maxmind"]);
+        assert_eq!(state.runtime.load().generation, 1);
+    }
+
+    #[test]
+    fn test_redaction_changes_apply_without_purging_lookups() {
+        let state = test_support::app_state();
+
+        let mut settings = Settings::default();
+        settings.telemetry.log_ip_redaction = IpRedaction::Hash;
+        settings.telemetry.log_ip_hash_key = Some("secret".to_string());
+        let report = state.config_reloader.apply(settings).unwrap();
+        assert_eq!(report.applied, vec!["telemetry.log_ip_redaction", "telemetry.log_ip_hash_key"]);
+        assert!(report.restart_required.is_empty());
+        // Verdicts are unaffected, so the config generation stays put
+        assert_eq!(state.runtime.load().generation, 0);
+    }
+
+    #[test]
+    fn test_invalid_reload_keeps_running_config() {
+        let state = test_support::app_state();
+
+        let mut settings = Settings::default();
+        settings.response_action.challenge_threshold = 90;
+        assert!(state.config_reloader.apply(settings).is_err());
+        assert_eq!(state.runtime.load().response_action_config.challenge_threshold, 50);
+    }
+}
@@ -0,0 +1,302 @@
+//! On-demand reachability checks of the service's outbound dependencies.
+//!
+//! Every configured feed URL and the web API are probed concurrently with a
+//! `HEAD` request, falling back to a ranged `GET` of the first KB for servers
+//! that do not allow `HEAD`. The probes go through a plain client built from
+//! `outbound_http`, never through [`ResilientClient`], so a failing check
+//! neither opens a circuit breaker nor shows up in the request metrics.
+//!
+//! [`ResilientClient`]: crate::clients::resilient_client::ResilientClient
+
+use std::time::{Duration, Instant};
+
+use futures_util::future::join_all;
+use reqwest::header::RANGE;
+use reqwest::{Client, StatusCode};
+use serde::Serialize;
+
+use crate::config::Settings;
+use crate::ip_lookup::IpRangeSource;
+use crate::utils::http_client::redact_credentials;
+
+/// How long a single target gets, `HEAD` and fallback together
+pub const TARGET_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a whole check may take, however many targets there are
+pub const TOTAL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An outbound dependency to probe
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    /// e.g. `source:x4bnet-vpn`, `updater:vpn` or `web_api`
+    pub name: String,
+    pub url: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProbeStatus {
+    Ok,
+    DnsError,
+    TlsError,
+    /// Reached, but answered with a 4xx or 5xx
+    HttpStatus,
+    Timeout,
+    /// Refused, reset, or failed some other way before a response
+    ConnectionError,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TargetReport {
+    pub name: String,
+    /// With any credentials redacted
+    pub url: String,
+    pub status: ProbeStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_status: Option<u16>,
+    pub latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct ConnectivityChecker {
+    client: Client,
+    targets: Vec<Target>,
+    target_timeout: Duration,
+    total_timeout: Duration,
+}
+
+impl ConnectivityChecker {
+    pub fn new(client: Client, targets: Vec<Target>) -> Self {
+        Self { client, targets, target_timeout: TARGET_TIMEOUT, total_timeout: TOTAL_TIMEOUT }
+    }
+
+    /// Probe every target at once; reports are in target order
+    pub async fn check(&self) -> Vec<TargetReport> {
+        // All probes start together, so capping each one caps the total
+        let timeout = self.target_timeout.min(self.total_timeout);
+        join_all(self.targets.iter().map(|target| self.probe(target, timeout))).await
+    }
+
+    async fn probe(&self, target: &Target, timeout: Duration) -> TargetReport {
+        let started = Instant::now();
+        let outcome = tokio::time::timeout(timeout, self.request(&target.url)).await;
+        let latency_ms = started.elapsed().as_millis() as u64;
+
+        let (status, http_status, error) = match outcome {
+            Err(_) => (ProbeStatus::Timeout, None, Some(format!("no response within {:?}", timeout))),
+            Ok(Ok(code)) if code.is_client_error() || code.is_server_error() => {
+                (ProbeStatus::HttpStatus, Some(code.as_u16()), None)
+            }
+            Ok(Ok(code)) => (ProbeStatus::Ok, Some(code.as_u16()), None),
+            Ok(Err(e)) => (classify(&e), None, Some(error_chain(&e))),
+        };
+        TargetReport {
+            name: target.name.clone(),
+            url: redact_credentials(&target.url),
+            status,
+            http_status,
+            latency_ms,
+            error,
+        }
+    }
+
+    async fn request(&self, url: &str) -> reqwest::Result<StatusCode> {
+        let status = self.client.head(url).send().await?.status();
+        if status != StatusCode::METHOD_NOT_ALLOWED && status != StatusCode::NOT_IMPLEMENTED {
+            return Ok(status);
+        }
+        // The body is dropped unread after the first KB at most
+        Ok(self.client.get(url).header(RANGE, "bytes=0-1023").send().await?.status())
+    }
+}
+
+/// The enabled feed URLs and their mirrors, the updater URLs and the web
+/// API, in that order. `file://` feeds are local, so they are left out.
+pub fn targets(settings: &Settings, sources: &[IpRangeSource], web_api_url: &str) -> Vec<Target> {
+    let feeds = sources
+        .iter()
+        .filter(|source| source.enabled)
+        .flat_map(|source| {
+            source.urls().enumerate().map(move |(i, url)| Target {
+                name: match i {
+                    0 => format!("source:{}", source.name),
+                    _ => format!("source:{}:mirror{}", source.name, i),
+                },
+                url: url.to_string(),
+            })
+        })
+        .filter(|target| !target.url.starts_with("file:"));
+    let updater = settings
+        .background_updater
+        .sources()
+        .into_iter()
+        .filter(|(_, source)| settings.background_updater.enabled && source.enabled)
+        .map(|(name, source)| Target { name: format!("updater:{}", name), url: source.url.clone() });
+    let web_api = Target { name: "web_api".to_string(), url: web_api_url.to_string() };
+    feeds.chain(updater).chain(std::iter::once(web_api)).collect()
+}
+
+/// reqwest only tells connect errors apart from the rest; DNS and TLS
+/// failures are recognised by the messages of their underlying errors
+fn classify(error: &reqwest::Error) -> ProbeStatus {
+    if error.is_timeout() {
+        return ProbeStatus::Timeout;
+    }
+    let chain = error_chain(error).to_lowercase();
+    if chain.contains("dns error") || chain.contains("failed to lookup address") {
+        ProbeStatus::DnsError
+    } else if ["tls", "ssl", "certificate", "handshake"].iter().any(|word| chain.contains(word)) {
+        ProbeStatus::TlsError
+    } else {
+        ProbeStatus::ConnectionError
+    }
+}
+
+/// `error` and its sources, joined with `: `
+fn error_chain(error: &reqwest::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = std::error::Error::source(error);
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{HeaderMap, Method, StatusCode};
+    use axum::routing::any;
+    use axum::Router;
+
+    /// Serves `router` on an ephemeral port and returns its base URL
+    async fn serve(router: Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    fn checker(targets: Vec<Target>, target_timeout: Duration, total_timeout: Duration) -> ConnectivityChecker {
+        ConnectivityChecker { client: Client::new(), targets, target_timeout, total_timeout }
+    }
+
+    fn target(name: &str, url: String) -> Target {
+        Target { name: name.to_string(), url }
+    }
+
+    #[tokio::test]
+    async fn test_reports_status_per_target() {
+        let ok = serve(Router::new().route("/list.txt", any(|| async { "10.0.0.0/8\n" }))).await;
+        let forbidden = serve(Router::new().route("/", any(|| async { StatusCode::FORBIDDEN }))).await;
+        // Accepts connections and never answers
+        let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let silent_url = format!("http://{}/", silent.local_addr().unwrap());
+        // Nothing listens on a port that was just released
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed_url = format!("http://{}/", closed.local_addr().unwrap());
+        drop(closed);
+
+        let checker = checker(
+            vec![
+                target("ok", format!("{}/list.txt", ok)),
+                target("forbidden", format!("{}/", forbidden)),
+                target("silent", silent_url),
+                target("closed", closed_url),
+            ],
+            Duration::from_millis(300),
+            Duration::from_secs(5),
+        );
+
+        let started = Instant::now();
+        let reports = checker.check().await;
+        assert!(started.elapsed() < Duration::from_secs(2), "probes ran one after another");
+
+        let statuses: Vec<_> = reports.iter().map(|r| (r.name.as_str(), r.status, r.http_status)).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                ("ok", ProbeStatus::Ok, Some(200)),
+                ("forbidden", ProbeStatus::HttpStatus, Some(403)),
+                ("silent", ProbeStatus::Timeout, None),
+                ("closed", ProbeStatus::ConnectionError, None),
+            ]
+        );
+        assert!(reports[2].latency_ms >= 300);
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_to_ranged_get_when_head_is_refused() {
+        let base = serve(Router::new().route(
+            "/",
+            any(|method: Method, headers: HeaderMap| async move {
+                match (method, headers.get("range")) {
+                    (Method::HEAD, _) => StatusCode::METHOD_NOT_ALLOWED,
+                    (Method::GET, Some(range)) if range == "bytes=0-1023" => StatusCode::PARTIAL_CONTENT,
+                    _ => StatusCode::BAD_REQUEST,
+                }
+            }),
+        ))
+        .await;
+
+        let reports = ConnectivityChecker::new(Client::new(), vec![target("feed", base)]).check().await;
+        assert_eq!(reports[0].status, ProbeStatus::Ok);
+        assert_eq!(reports[0].http_status, Some(206));
+    }
+
+    #[tokio::test]
+    async fn test_total_timeout_caps_every_target() {
+        let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", silent.local_addr().unwrap());
+        let checker = checker(
+            vec![target("a", url.clone()), target("b", url)],
+            Duration::from_secs(30),
+            Duration::from_millis(200),
+        );
+
+        let started = Instant::now();
+        let reports = checker.check().await;
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(reports.iter().all(|r| r.status == ProbeStatus::Timeout));
+    }
+
+    #[test]
+    fn test_targets_cover_enabled_sources_and_web_api() {
+        use crate::ip_lookup::{IpCategory, IpVersion};
+        use crate::ip_lookup::types::SourceFormat;
+
+        let source = |name: &str, enabled| IpRangeSource {
+            url: format!("https://lists.example/{}.txt", name),
+            category: IpCategory::Vpn,
+            name: name.to_string(),
+            enabled,
+            format: SourceFormat::Default,
+            max_delta_percent: None,
+            ip_version: IpVersion::V4,
+            mirrors: Vec::new(),
+        };
+        let mut settings = Settings::default();
+        settings.background_updater.socks4_proxy.enabled = false;
+
+        let mirrored = IpRangeSource {
+            url: "file:///srv/lists/tor.txt".to_string(),
+            mirrors: vec!["https://mirror.example/tor.txt".to_string()],
+            ..source("tor", true)
+        };
+        let sources = [source("vpn", true), source("old", false), mirrored];
+        let names: Vec<String> = targets(&settings, &sources, "http://web-api:3000")
+            .into_iter()
+            .map(|target| target.name)
+            .collect();
+        assert_eq!(
+            names,
+            vec!["source:vpn", "source:tor:mirror1", "updater:vpn", "updater:http_proxy", "updater:socks5_proxy", "web_api"]
+        );
+
+        settings.background_updater.enabled = false;
+        assert_eq!(targets(&settings, &[], "http://web-api:3000").len(), 1);
+    }
+}
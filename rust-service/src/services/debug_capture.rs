@@ -0,0 +1,135 @@
+//! Per-IP debug capture: lookups of a few watched IPs record everything that
+//! went into their verdict, so support can answer "why was this IP
+//! challenged?" without turning on debug logging for all traffic.
+//!
+//! The watch list sits behind an [`ArcSwap`], so a lookup of an unwatched IP
+//! costs one atomic load and a hash lookup, and takes no lock.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::errors::validation::canonical_ip;
+use crate::handlers::LookupResponse;
+use crate::ip_lookup::service::ExplainedMatch;
+use crate::models::threat_score::ThreatFinding;
+
+/// Most IPs watched at once
+pub const MAX_WATCHES: usize = 32;
+/// Records kept across all IPs; the oldest are dropped first
+pub const MAX_RECORDS: usize = 1000;
+/// Longest a single watch may run
+pub const MAX_WATCH_DURATION: Duration = Duration::from_secs(24 * 3600);
+
+/// Everything a watched lookup decided its verdict on
+#[derive(Debug, Clone, Serialize)]
+pub struct CaptureRecord {
+    pub captured_at: DateTime<Utc>,
+    /// Scoring profile the lookup ran under
+    pub profile: String,
+    pub cache_hit: bool,
+    /// Config reloads applied before the lookup; 0 is the startup config
+    pub config_generation: u64,
+    /// Whether monitor mode was forced through the admin API
+    pub monitor_forced: bool,
+    /// Every tree entry containing the IP, most specific first
+    pub matches: Vec<ExplainedMatch>,
+    /// The findings and weights behind the score; `None` on a cache hit,
+    /// whose score an earlier lookup computed
+    pub findings: Option<Vec<ThreatFinding>>,
+    /// The response as the lookup returned it
+    pub response: LookupResponse,
+}
+
+#[derive(Debug, Default)]
+pub struct DebugCapture {
+    /// Watched canonical IPs and when each watch ends
+    watches: ArcSwap<HashMap<IpAddr, DateTime<Utc>>>,
+    records: Mutex<VecDeque<CaptureRecord>>,
+}
+
+impl DebugCapture {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Watch `ip` for `duration`, replacing any current watch of it, and
+    /// return when the watch ends. `None` if [`MAX_WATCHES`] other IPs are
+    /// already watched.
+    pub fn watch(&self, ip: IpAddr, duration: Duration) -> Option<DateTime<Utc>> {
+        let ip = canonical_ip(ip);
+        let now = Utc::now();
+        let until = now + chrono::Duration::from_std(duration.min(MAX_WATCH_DURATION)).ok()?;
+        let mut full = false;
+        self.watches.rcu(|watches| {
+            let mut next: HashMap<IpAddr, DateTime<Utc>> =
+                watches.iter().filter(|(_, end)| **end > now).map(|(ip, end)| (*ip, *end)).collect();
+            full = !next.contains_key(&ip) && next.len() >= MAX_WATCHES;
+            if !full {
+                next.insert(ip, until);
+            }
+            next
+        });
+        (!full).then_some(until)
+    }
+
+    /// When the watch of `ip` ends, if it is watched
+    pub fn watched_until(&self, ip: IpAddr) -> Option<DateTime<Utc>> {
+        let watches = self.watches.load();
+        if watches.is_empty() {
+            return None;
+        }
+        watches.get(&canonical_ip(ip)).copied().filter(|until| Utc::now() < *until)
+    }
+
+    pub fn is_watched(&self, ip: IpAddr) -> bool {
+        self.watched_until(ip).is_some()
+    }
+
+    /// Keep `record`, dropping the oldest record beyond [`MAX_RECORDS`]
+    pub fn record(&self, record: CaptureRecord) {
+        let mut records = self.records.lock();
+        if records.len() >= MAX_RECORDS {
+            records.pop_front();
+        }
+        records.push_back(record);
+    }
+
+    /// The records kept for `ip`, oldest first
+    pub fn records(&self, ip: IpAddr) -> Vec<CaptureRecord> {
+        let ip = canonical_ip(ip).to_string();
+        self.records.lock().iter().filter(|record| record.response.canonical_ip == ip).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn test_watches_expire_and_are_capped() {
+        let capture = DebugCapture::new();
+        assert!(!capture.is_watched(ip("203.0.113.7")));
+
+        capture.watch(ip("::ffff:203.0.113.7"), Duration::from_millis(50)).unwrap();
+        assert!(capture.is_watched(ip("203.0.113.7")));
+        std::thread::sleep(Duration::from_millis(80));
+        assert!(!capture.is_watched(ip("203.0.113.7")));
+
+        for host in 0..MAX_WATCHES {
+            assert!(capture.watch(ip(&format!("198.51.100.{}", host)), Duration::from_secs(60)).is_some());
+        }
+        assert!(capture.watch(ip("203.0.113.8"), Duration::from_secs(60)).is_none());
+        // Re-watching an already watched IP only moves its end
+        assert!(capture.watch(ip("198.51.100.0"), Duration::from_secs(120)).is_some());
+    }
+}
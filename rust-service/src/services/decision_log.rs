@@ -0,0 +1,233 @@
+//! Structured log of the response actions served to callers, for security
+//! review.
+//!
+//! Enforcing decisions (block, redirect, challenge) are always logged. Allow
+//! and monitor decisions are too frequent for that and are sampled at
+//! `decision_log.sample_rate`; every event records the rate it was logged at
+//! so counts can be extrapolated downstream (one event at rate 0.01 stands
+//! for about 100 decisions).
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::BuildHasher;
+
+use crate::handlers::LookupResponse;
+use crate::services::response_action::ResponseAction;
+use crate::utils::redact;
+
+/// `tracing` target of decision events, for routing them to their own sink
+pub const TARGET: &str = "infralock::decision";
+
+/// Who asked for a decision, and through which endpoint
+#[derive(Debug, Clone, Copy)]
+pub struct DecisionContext<'a> {
+    /// Route pattern, e.g. `/api/gate/{ip}`
+    pub endpoint: &'static str,
+    pub user_id: Option<&'a str>,
+}
+
+pub struct DecisionLog {
+    sample_rate: f64,
+    /// Uniform in `[0, 1)`; replaceable so tests can sample deterministically
+    rng: Box<dyn Fn() -> f64 + Send + Sync>,
+}
+
+impl fmt::Debug for DecisionLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DecisionLog").field("sample_rate", &self.sample_rate).finish_non_exhaustive()
+    }
+}
+
+impl DecisionLog {
+    /// Logs allow and monitor decisions with probability `sample_rate`
+    pub fn new(sample_rate: f64) -> Self {
+        Self::with_rng(sample_rate, random_unit)
+    }
+
+    /// Like [`DecisionLog::new`], sampling with draws from `rng`
+    pub fn with_rng(sample_rate: f64, rng: impl Fn() -> f64 + Send + Sync + 'static) -> Self {
+        Self {
+            sample_rate: sample_rate.clamp(0.0, 1.0),
+            rng: Box::new(rng),
+        }
+    }
+
+    /// The probability a decision for `action` is logged
+    pub fn rate(&self, action: ResponseAction) -> f64 {
+        match action {
+            ResponseAction::Block | ResponseAction::Redirect | ResponseAction::Challenge => 1.0,
+            ResponseAction::Allow | ResponseAction::Monitor => self.sample_rate,
+        }
+    }
+
+    /// Whether this decision for `action` is logged
+    fn sampled(&self, action: ResponseAction) -> bool {
+        match self.rate(action) {
+            rate if rate >= 1.0 => true,
+            rate if rate <= 0.0 => false,
+            rate => (self.rng)() < rate,
+        }
+    }
+
+    /// Emit the decision in `response` if it is sampled
+    pub fn record(&self, response: &LookupResponse, context: DecisionContext<'_>) {
+        let Ok(action) = response.recommended_action.parse::<ResponseAction>() else {
+            return;
+        };
+        if !self.sampled(action) {
+            return;
+        }
+        tracing::info!(
+            target: TARGET,
+            ip = %redact::text(&response.canonical_ip),
+            action = %response.recommended_action,
+            shadow_action = response.shadow_action.as_deref(),
+            score = response.threat_score,
+            findings = ?response.threat_details,
+            matched_source = response.matched_source.as_deref(),
+            user_id = context.user_id,
+            endpoint = context.endpoint,
+            sample_rate = self.rate(action),
+            "decision"
+        );
+    }
+}
+
+/// Uniform in `[0, 1)`. Each `RandomState` gets fresh keys, which is enough
+/// randomness for sampling.
+fn random_unit() -> f64 {
+    (RandomState::new().hash_one(0u8) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::handlers::LookupErrors;
+
+    /// Collects formatted events as JSON lines
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Captured {
+        fn events(&self) -> Vec<serde_json::Value> {
+            let bytes = self.0.lock().unwrap();
+            String::from_utf8_lossy(&bytes)
+                .lines()
+                .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["fields"].clone())
+                .collect()
+        }
+    }
+
+    fn capture(f: impl FnOnce()) -> Vec<serde_json::Value> {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_writer(move || writer.clone())
+            .with_max_level(tracing::Level::INFO)
+            .finish();
+        tracing::subscriber::with_default(subscriber, f);
+        captured.events()
+    }
+
+    fn response(action: &str, score: u8) -> LookupResponse {
+        LookupResponse {
+            ip: "::ffff:5.1.1.1".to_string(),
+            canonical_ip: "5.1.1.1".to_string(),
+            geo_info: None,
+            asn_info: None,
+            is_vpn_or_datacenter: false,
+            is_proxy: false,
+            proxy_type: None,
+            is_tor_exit_node: true,
+            recently_delisted: false,
+            categories: crate::ip_lookup::CategoryFlags { tor: true, ..Default::default() },
+            category: Some("tor_exit_node".to_string()),
+            matched_source: Some("dan-me-uk".to_string()),
+            cloud_provider: None,
+            is_anonymous_proxy: None,
+            is_anycast: None,
+            is_satellite_provider: None,
+            is_hosting_provider: None,
+            threat_score: score,
+            threat_details: vec!["IP is a known Tor exit node".to_string()],
+            findings_truncated: false,
+            recommended_action: action.to_string(),
+            shadow_action: None,
+            disabled_features: Vec::new(),
+            errors: LookupErrors::default(),
+            partial: false,
+            data_unavailable: false,
+            skipped: vec![],
+            challenge_token: None,
+        }
+    }
+
+    const CONTEXT: DecisionContext<'static> = DecisionContext {
+        endpoint: "/api/gate/{ip}",
+        user_id: Some("u1"),
+    };
+
+    #[test]
+    fn test_enforcing_decisions_always_logged() {
+        let log = DecisionLog::with_rng(0.0, || panic!("enforcing decisions are not sampled"));
+        let events = capture(|| {
+            for action in ["block", "redirect", "challenge"] {
+                log.record(&response(action, 90), CONTEXT);
+            }
+        });
+
+        assert_eq!(events.len(), 3);
+        let event = &events[0];
+        assert_eq!(event["action"], "block");
+        assert_eq!(event["ip"], "5.1.1.1");
+        assert_eq!(event["score"], 90);
+        assert_eq!(event["findings"], "[\"IP is a known Tor exit node\"]");
+        assert_eq!(event["matched_source"], "dan-me-uk");
+        assert_eq!(event["user_id"], "u1");
+        assert_eq!(event["endpoint"], "/api/gate/{ip}");
+        assert_eq!(event["sample_rate"], 1.0);
+    }
+
+    #[test]
+    fn test_allow_and_monitor_sampled_at_rate() {
+        // A sequence of draws spread evenly over [0, 1)
+        let draws = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&draws);
+        let log = DecisionLog::with_rng(0.25, move || (counter.fetch_add(1, Ordering::Relaxed) % 100) as f64 / 100.0);
+
+        let events = capture(|| {
+            for i in 0..200 {
+                let action = if i % 2 == 0 { "allow" } else { "monitor" };
+                log.record(&response(action, 0), DecisionContext { user_id: None, ..CONTEXT });
+            }
+        });
+
+        assert_eq!(draws.load(Ordering::Relaxed), 200);
+        assert_eq!(events.len(), 50);
+        assert!(events.iter().all(|event| event["sample_rate"] == 0.25));
+        assert!(events.iter().all(|event| event.get("user_id").is_none()));
+        assert!(events.iter().any(|event| event["action"] == "monitor"));
+
+        // Rates of 0 and 1 skip the draw
+        let log = DecisionLog::with_rng(1.0, || panic!("no draw at rate 1"));
+        assert_eq!(capture(|| log.record(&response("allow", 0), CONTEXT)).len(), 1);
+        let log = DecisionLog::with_rng(0.0, || panic!("no draw at rate 0"));
+        assert!(capture(|| log.record(&response("allow", 0), CONTEXT)).is_empty());
+    }
+}
@@ -1,106 +1,1106 @@
 // lookup_service.rs
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr};
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use crate::models::location::{GeoInfo, AsnInfo};
-use crate::models::threat_score::ThreatScore;
-use crate::handlers::LookupResponse;
+use std::time::Duration;
+use crate::config::{FeatureSettings, MissingDataPolicy, ThreatDataPolicy};
+use crate::geo::{GeoProvider, GeoProviderError};
+use crate::models::location::{AsnInfo, GeoInfo, NetworkTraits};
+use crate::models::threat_score::{ThreatFinding, ThreatScore, ThreatScoringConfig, ThreatType};
+use crate::handlers::{LookupErrors, LookupResponse};
 use crate::errors::AppError;
-use crate::services::response_action::ResponseActionService;
-use crate::ip_lookup::{IpLookupService, IpCategory};
-use maxminddb;
+use crate::services::aggregates::LookupAggregates;
+use crate::services::debug_capture::{CaptureRecord, DebugCapture};
+use crate::services::profiles::{ScoringProfile, DEFAULT_PROFILE};
+use crate::services::response_action::{
+    ActionDecision, MonitorOverride, ResponseAction, ResponseActionConfig, ResponseActionService,
+};
+use crate::services::shared_cache::SharedCache;
+use crate::ip_lookup::tree::RangeMatch;
+use crate::ip_lookup::tunnel::{self, TunnelKind};
+use crate::ip_lookup::{CategoryFlags, IpLookupService, IpCategory};
+use crate::monitoring::{self, GEO_LOOKUP_ERRORS};
+use crate::utils::redact;
 use moka::sync::Cache;
+use tokio::time::Instant;
+
+/// Cached lookups, partitioned by scoring profile so one tenant's verdicts
+/// are never served to another. Keyed by the canonical IP, so
+/// `::ffff:1.2.3.4` and `1.2.3.4` share an entry.
+pub type LookupCache = Cache<(Arc<str>, IpAddr), LookupResponse>;
+
+/// Whether a lookup was answered from the cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheStatus {
+    Hit,
+    Miss,
+}
+
+impl CacheStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Hit => "hit",
+            Self::Miss => "miss",
+        }
+    }
+}
+
+/// A geo or ASN database lookup
+type DatabaseResult<T> = Result<Option<T>, GeoProviderError>;
 
 pub struct LookupService {
-    maxmind_reader: Arc<RwLock<maxminddb::Reader<Vec<u8>>>>,
-    asn_reader: Arc<RwLock<maxminddb::Reader<Vec<u8>>>>,
-    lookup_cache: Arc<Cache<IpAddr, LookupResponse>>,
+    geo_provider: Arc<dyn GeoProvider>,
+    lookup_cache: Arc<LookupCache>,
+    shared_cache: Option<Arc<SharedCache>>,
     ip_lookup_service: Arc<IpLookupService>,
+    scoring_config: ThreatScoringConfig,
+    features: FeatureSettings,
+    require_geo: bool,
+    tunnel_extraction: bool,
+    category_fallback: Option<Arc<str>>,
+    response_action_config: ResponseActionConfig,
+    monitor_override: MonitorOverride,
+    profile: Arc<str>,
+    /// What responses are cached under; see [`ScoringProfile::key`]
+    profile_key: Arc<str>,
+    aggregates: Option<Arc<LookupAggregates>>,
+    timeout: Option<Duration>,
+    debug_capture: Option<Arc<DebugCapture>>,
+    /// Reported in debug captures
+    config_generation: u64,
+    canonicalize_6to4: bool,
+    missing_data: MissingDataPolicy,
+    count_blocked: bool,
+    max_findings: Option<usize>,
 }
 
 impl LookupService {
     pub fn new(
-        maxmind_reader: Arc<RwLock<maxminddb::Reader<Vec<u8>>>>,
-        asn_reader: Arc<RwLock<maxminddb::Reader<Vec<u8>>>>,
-        lookup_cache: Arc<Cache<IpAddr, LookupResponse>>,
+        geo_provider: Arc<dyn GeoProvider>,
+        lookup_cache: Arc<LookupCache>,
         ip_lookup_service: Arc<IpLookupService>,
+        scoring_config: ThreatScoringConfig,
     ) -> Self {
         Self {
-            maxmind_reader,
-            asn_reader,
+            geo_provider,
             lookup_cache,
+            shared_cache: None,
             ip_lookup_service,
+            scoring_config,
+            features: FeatureSettings::default(),
+            require_geo: false,
+            tunnel_extraction: false,
+            category_fallback: None,
+            response_action_config: ResponseActionConfig::default(),
+            monitor_override: MonitorOverride::default(),
+            profile: DEFAULT_PROFILE.into(),
+            profile_key: DEFAULT_PROFILE.into(),
+            aggregates: None,
+            timeout: None,
+            debug_capture: None,
+            config_generation: 0,
+            canonicalize_6to4: false,
+            missing_data: MissingDataPolicy::default(),
+            count_blocked: false,
+            max_findings: None,
         }
     }
 
-    pub async fn lookup_ip(&self, ip_addr: IpAddr) -> Result<LookupResponse, AppError> {
+    /// Score, recommend actions and cache under a tenant's profile
+    pub fn with_profile(mut self, profile: &ScoringProfile) -> Self {
+        self.scoring_config = profile.scoring.clone();
+        self.response_action_config = profile.response_action.clone();
+        self.profile = Arc::clone(&profile.name);
+        self.profile_key = Arc::clone(&profile.key);
+        self
+    }
+
+    fn cache_key(&self, ip_addr: IpAddr) -> (Arc<str>, IpAddr) {
+        (Arc::clone(&self.profile_key), self.canonical(ip_addr))
+    }
+
+    /// The address lookups are computed and cached under, per
+    /// [`tunnel::canonicalize_ip`]
+    fn canonical(&self, ip_addr: IpAddr) -> IpAddr {
+        tunnel::canonicalize_ip(ip_addr, self.canonicalize_6to4)
+    }
+
+    /// Use `config` instead of the defaults when recommending an action
+    pub fn with_response_action_config(mut self, config: ResponseActionConfig) -> Self {
+        self.response_action_config = config;
+        self
+    }
+
+    /// Recommend `monitor` whenever `monitor_override` is forced
+    pub fn with_monitor_override(mut self, monitor_override: MonitorOverride) -> Self {
+        self.monitor_override = monitor_override;
+        self
+    }
+
+    /// Skip the geo and ASN portions of lookups for disabled features
+    pub fn with_features(mut self, features: FeatureSettings) -> Self {
+        self.features = features;
+        self
+    }
+
+    /// Fail `lookup_ip` with `NotFound` when the geo database has no city
+    /// record for the IP. Threat scoring never requires geo data, and a geo
+    /// database error is reported in `errors` rather than as `NotFound`.
+    pub fn with_require_geo(mut self, require_geo: bool) -> Self {
+        self.require_geo = require_geo;
+        self
+    }
+
+    /// Also look up the IPv4 origin of 6to4 and Teredo addresses, merging
+    /// its findings into the result
+    pub fn with_tunnel_extraction(mut self, tunnel_extraction: bool) -> Self {
+        self.tunnel_extraction = tunnel_extraction;
+        self
+    }
+
+    /// Look up and cache 6to4 addresses as the IPv4 address they embed
+    pub fn with_6to4_canonicalization(mut self, canonicalize_6to4: bool) -> Self {
+        self.canonicalize_6to4 = canonicalize_6to4;
+        self
+    }
+
+    /// Flag, and under the `closed` policy escalate, lookups made while the
+    /// tree lacks or holds stale data for a category it should have
+    pub fn with_missing_data_policy(mut self, missing_data: MissingDataPolicy) -> Self {
+        self.missing_data = missing_data;
+        self
+    }
+
+    /// Report `fallback` as the `category` of lookups that match no range
+    pub fn with_category_fallback(mut self, fallback: Option<Arc<str>>) -> Self {
+        self.category_fallback = fallback;
+        self
+    }
+
+    /// Consult `shared_cache` on a local cache miss, and fill it too
+    pub fn with_shared_cache(mut self, shared_cache: Option<Arc<SharedCache>>) -> Self {
+        self.shared_cache = shared_cache;
+        self
+    }
+
+    /// Count every served lookup, cached or not, in `aggregates`
+    pub fn with_aggregates(mut self, aggregates: Option<Arc<LookupAggregates>>) -> Self {
+        self.aggregates = aggregates;
+        self
+    }
+
+    /// List at most `max_findings` of the heaviest findings in
+    /// `threat_details`, flagging responses that had more
+    pub fn with_max_findings(mut self, max_findings: Option<usize>) -> Self {
+        self.max_findings = max_findings;
+        self
+    }
+
+    /// Count served lookups answered with `block` in `blocked_total`, by
+    /// country and reason
+    pub fn with_blocked_metric(mut self, count_blocked: bool) -> Self {
+        self.count_blocked = count_blocked;
+        self
+    }
+
+    /// Give every lookup's geo and ASN portions at most `timeout`, as if
+    /// each had been called with that deadline
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Record lookups of the IPs watched by `capture`, tagged with the
+    /// config generation the service was built from
+    pub fn with_debug_capture(mut self, capture: Arc<DebugCapture>, config_generation: u64) -> Self {
+        self.debug_capture = Some(capture);
+        self.config_generation = config_generation;
+        self
+    }
+
+    /// The tree category of the IPv4 origin behind a tunnelled address
+    fn tunnel_match(&self, ip_addr: IpAddr) -> Option<TunnelMatch> {
+        if !self.tunnel_extraction {
+            return None;
+        }
+        let (kind, origin) = tunnel::embedded_ipv4(ip_addr)?;
+        let RangeMatch { category, source } = self.ip_lookup_service.tree().lookup_match(IpAddr::V4(origin))?;
+        let flagged = self.flagged_category(IpAddr::V4(origin), Some(category));
+        Some(TunnelMatch { kind, origin, category, flagged, source })
+    }
+
+    /// The category the threat flags of `ip_addr` come from, given its most
+    /// specific match. A cloud provider range says nothing about the IP, so
+    /// a VPN or proxy range it sits inside still flags it.
+    fn flagged_category(&self, ip_addr: IpAddr, longest: Option<IpCategory>) -> Option<IpCategory> {
+        match longest {
+            Some(IpCategory::CloudProvider(_)) => self
+                .ip_lookup_service
+                .tree()
+                .lookup_networks(ip_addr)
+                .into_iter()
+                .map(|found| found.category)
+                .find(|category| !matches!(category, IpCategory::CloudProvider(_))),
+            longest => longest,
+        }
+    }
+
+    pub async fn lookup_ip(&self, requested_ip: IpAddr) -> Result<LookupResponse, AppError> {
+        self.lookup_ip_within(requested_ip, None).await
+    }
+
+    /// Like [`LookupService::lookup_ip`], but the geo and ASN lookups only
+    /// get until `deadline`, or the configured timeout if that is sooner.
+    /// The tree flags are always computed; a database lookup still running
+    /// at the deadline is left out and listed in `skipped`, and the
+    /// `partial` response is not cached.
+    pub async fn lookup_ip_within(
+        &self,
+        requested_ip: IpAddr,
+        deadline: Option<Instant>,
+    ) -> Result<LookupResponse, AppError> {
+        self.lookup_ip_with_status(requested_ip, deadline).await.map(|(response, _)| response)
+    }
+
+    /// [`LookupService::lookup_ip_within`], also telling whether the
+    /// response was served from the cache
+    #[tracing::instrument(
+        name = "infralock.lookup_service",
+        skip_all,
+        fields(
+            ip = %redact::ip(requested_ip),
+            cache_hit = tracing::field::Empty,
+            category = tracing::field::Empty,
+            score = tracing::field::Empty,
+            action = tracing::field::Empty,
+        )
+    )]
+    pub async fn lookup_ip_with_status(
+        &self,
+        requested_ip: IpAddr,
+        deadline: Option<Instant>,
+    ) -> Result<(LookupResponse, CacheStatus), AppError> {
+        let span = tracing::Span::current();
+        let capture = self.debug_capture.as_deref().filter(|capture| capture.is_watched(requested_ip));
+
+        // Missing or stale threat data voids the verdicts cached before it
+        // went missing, so the cache is bypassed until the data is back
+        let data_unavailable = self.data_unavailable();
+
         // Check cache first
-        if let Some(cached) = self.lookup_cache.get(&ip_addr) {
-            return Ok(cached.clone());
+        let cached = match data_unavailable {
+            true => None,
+            false => self.cached(requested_ip).await,
+        };
+        if let Some(mut cached) = cached {
+            span.record("cache_hit", true);
+            span.record("score", cached.threat_score);
+            span.record("action", cached.recommended_action.as_str());
+            // The entry may have been cached under the other spelling
+            cached.ip = requested_ip.to_string();
+            self.record(&cached);
+            if let Some(capture) = capture {
+                self.capture(capture, &cached, true, None);
+            }
+            return Ok((cached, CacheStatus::Hit));
         }
+        span.record("cache_hit", false);
+
+        let deadline = match (deadline, self.timeout.map(|timeout| Instant::now() + timeout)) {
+            (Some(deadline), Some(timeout)) => Some(deadline.min(timeout)),
+            (deadline, timeout) => deadline.or(timeout),
+        };
+
+        // Everything below works on the embedded IPv4 address, if there is one
+        let ip_addr = self.canonical(requested_ip);
 
         // Get IP category using the new ip_lookup_service
-        let ip_category = self.ip_lookup_service.tree().lookup(ip_addr);
-        
-        // Get geo and ASN information
-        let reader = self.maxmind_reader.read().await;
-        let asn_reader = self.asn_reader.read().await;
+        let range_match = self.ip_lookup_service.tree().lookup_match(ip_addr);
+        let ip_category = range_match.as_ref().map(|found| found.category);
+        if let Some(category) = ip_category {
+            span.record("category", tracing::field::debug(category));
+        }
         
-        let (geo_result, asn_result) = tokio::join!(
-            async { reader.lookup(ip_addr) },
-            async { asn_reader.lookup(ip_addr) },
-        );
+        let tunnel = self.tunnel_match(ip_addr);
 
-        // Convert the raw results to our models
-        let city: Option<maxminddb::geoip2::City<'_>> = geo_result?;
-        let asn: Option<maxminddb::geoip2::Asn<'_>> = asn_result?;
-        
-        let geo_info = city.map(GeoInfo::from);
-        let asn_info = asn.as_ref().map(AsnInfo::from);
-
-        // Determine threat type based on IP category
-        let (is_vpn, is_proxy, is_tor, proxy_type) = match ip_category {
-            Some(IpCategory::Vpn) => (true, false, false, None),
-            Some(IpCategory::ProxyHttp) => (false, true, false, Some("http")),
-            Some(IpCategory::ProxySocks4) => (false, true, false, Some("socks4")),
-            Some(IpCategory::ProxySocks5) => (false, true, false, Some("socks5")),
-            Some(IpCategory::TorExitNode) => (false, false, true, None),
-            None => (false, false, false, None),
+        // Every range containing the IP, not just the most specific one
+        let mut categories: CategoryFlags = match range_match {
+            Some(_) => self.ip_lookup_service.tree().lookup_all(ip_addr).into_iter().collect(),
+            None => CategoryFlags::default(),
         };
+        if let Some(tunnel) = &tunnel {
+            categories.extend([tunnel.category]);
+        }
 
-        // Calculate threat score
-        let threat_score = ThreatScore::from_ip_info(
-            ip_addr,
-            is_vpn,
-            is_proxy,
-            proxy_type,
-            is_tor,
-        );
+        // Get geo and ASN information from the configured provider. A failing
+        // database only costs its own portion of the response, and so does
+        // one that misses the deadline.
+        let (geo, asn) = self.lookup_databases(ip_addr, deadline).await;
+        let mut skipped = Vec::new();
+        let (geo_info, geo_error) = match geo {
+            Some(result) => degrade(result, "geo", ip_addr),
+            None => {
+                skipped.push("geo");
+                (None, None)
+            }
+        };
+        let geo_missing = geo_info.is_none() && geo_error.is_none() && skipped.is_empty();
+        if self.require_geo && self.features.geo_lookup && geo_missing {
+            return Err(AppError::NotFound(format!("No geo data for {}", ip_addr)));
+        }
+        let (asn_info, asn_error) = match asn {
+            Some(result) => degrade(result, "asn", ip_addr),
+            None => {
+                skipped.push("asn");
+                (None, None)
+            }
+        };
+        // With every enabled database failing and no range match there is
+        // nothing left to answer with
+        let geo_down = geo_error.is_some() || !self.features.geo_lookup;
+        let asn_down = asn_error.is_some() || !self.features.asn_lookup;
+        let unanswerable = geo_down && asn_down && range_match.is_none() && tunnel.is_none();
+        let (geo_error, asn_error) = match (geo_error, asn_error) {
+            (Some(error), _) | (None, Some(error)) if unanswerable => return Err(error.into()),
+            errors => errors,
+        };
+        let errors = LookupErrors {
+            geo: geo_error.map(|error| error.to_string()),
+            asn: asn_error.map(|error| error.to_string()),
+        };
+
+        let traits = geo_info.as_ref().and_then(|geo| geo.traits.clone()).unwrap_or_default();
+        let Assessment { is_vpn, is_proxy, is_tor, proxy_type, threat_score } =
+            self.assess(ip_addr, ip_category, tunnel.as_ref(), Some(&traits), asn_info.as_ref());
 
         // Determine recommended response action
-        let response_action_service = ResponseActionService::new();
-        let recommended_action = response_action_service.determine_action(&threat_score);
+        let (mut recommended_action, mut shadow_action) =
+            self.response_action_service().determine_action_with_shadow(&threat_score);
+        // Without the data a clean score proves nothing; in monitor mode the
+        // escalation is what would have been enforced
+        if data_unavailable && self.missing_data.policy == ThreatDataPolicy::Closed {
+            match &mut shadow_action {
+                Some(shadow) => *shadow = (*shadow).max(self.missing_data.action),
+                None => recommended_action = recommended_action.max(self.missing_data.action),
+            }
+        }
+        span.record("score", threat_score.score);
+
+        // The score and action above count every finding; only the listing
+        // is cut
+        let (threat_details, findings_truncated) = match self.max_findings {
+            Some(max) => {
+                let (findings, truncated) = threat_score.top_findings(max, &self.scoring_config);
+                (findings.into_iter().map(|f| f.description.clone()).collect(), truncated)
+            }
+            None => (threat_score.findings.iter().map(|f| f.description.clone()).collect(), false),
+        };
 
         // Build the response
         let response = LookupResponse {
-            ip: ip_addr.to_string(),
+            ip: requested_ip.to_string(),
+            canonical_ip: ip_addr.to_string(),
             geo_info,
             asn_info,
             is_vpn_or_datacenter: is_vpn,
             is_proxy,
-            proxy_type,
+            proxy_type: proxy_type.map(str::to_string),
             is_tor_exit_node: is_tor,
+            recently_delisted: !is_tor && self.ip_lookup_service.is_recently_delisted(ip_addr),
+            categories,
+            category: ip_category
+                .or(tunnel.as_ref().map(|tunnel| tunnel.category))
+                .map(|category| category.to_string())
+                .or_else(|| self.category_fallback.as_deref().map(str::to_string)),
+            matched_source: match range_match {
+                Some(found) => found.source,
+                None => tunnel.and_then(|tunnel| tunnel.source),
+            }
+            .map(|source| source.to_string()),
+            cloud_provider: match ip_category {
+                Some(IpCategory::CloudProvider(kind)) => Some(kind),
+                _ => None,
+            },
+            is_anonymous_proxy: traits.is_anonymous_proxy,
+            is_anycast: traits.is_anycast,
+            is_satellite_provider: traits.is_satellite_provider,
+            is_hosting_provider: traits.is_hosting_provider,
             threat_score: threat_score.score,
-            threat_details: threat_score.findings
-                .iter()
-                .map(|f| f.description.clone())
+            threat_details,
+            findings_truncated,
+                recommended_action: format!("{:?}", recommended_action).to_lowercase(),
+            shadow_action: shadow_action.map(|action| format!("{:?}", action).to_lowercase()),
+            disabled_features: self
+                .features
+                .disabled()
+                .into_iter()
+                .filter(|f| matches!(*f, "geo_lookup" | "asn_lookup"))
+                .map(str::to_string)
                 .collect(),
-                recommended_action: format!("{:?}", recommended_action).to_lowercase()
+            errors,
+            partial: !skipped.is_empty(),
+            skipped: skipped.into_iter().map(str::to_string).collect(),
+            data_unavailable,
+            challenge_token: None,
+        };
+
+        span.record("action", response.recommended_action.as_str());
+
+        // Cache the response, unless a database failed or was skipped, or the
+        // threat data was missing: the next lookup should see the fixed or
+        // reloaded data, or have time for it
+        if response.errors.is_empty() && !response.partial && !response.data_unavailable {
+            let key = self.cache_key(ip_addr);
+            if let Some(shared_cache) = &self.shared_cache {
+                shared_cache.store(&key.0, key.1, &response);
+            }
+            self.lookup_cache.insert(key, response.clone());
+        }
+        self.record(&response);
+        if let Some(capture) = capture {
+            self.capture(capture, &response, false, Some(threat_score.findings));
+        }
+
+        Ok((response, CacheStatus::Miss))
+    }
+
+    fn record(&self, response: &LookupResponse) {
+        if let Some(aggregates) = &self.aggregates {
+            aggregates.record(response);
+        }
+        if self.count_blocked && response.recommended_action == "block" {
+            let country = response
+                .geo_info
+                .as_ref()
+                .and_then(|geo| geo.country.as_ref())
+                .and_then(|country| country.iso_code.as_deref());
+            monitoring::record_blocked(country, self.block_reason(response));
+        }
+    }
+
+    /// Why `response` was blocked, from its flags rather than the decision
+    /// so cached responses report the same: the most severe flagged type in
+    /// `block_immediate`, else `missing_data` under the closed policy, else
+    /// `other`
+    fn block_reason(&self, response: &LookupResponse) -> &'static str {
+        let mut flagged = [
+            (ThreatType::TorExitNode, response.is_tor_exit_node),
+            (ThreatType::Proxy, response.is_proxy),
+            (ThreatType::AnonymousProxy, response.is_anonymous_proxy == Some(true)),
+            (ThreatType::VpnOrDatacenter, response.is_vpn_or_datacenter),
+            (ThreatType::HostingProvider, response.is_hosting_provider == Some(true)),
+        ]
+        .into_iter()
+        .filter(|(threat_type, flagged)| *flagged && self.response_action_config.block_immediate.contains(threat_type));
+        match flagged.next() {
+            Some((threat_type, _)) => threat_type.as_str(),
+            None if response.data_unavailable => "missing_data",
+            None => "other",
+        }
+    }
+
+    /// Keep what went into `response` for a watched IP
+    fn capture(
+        &self,
+        capture: &DebugCapture,
+        response: &LookupResponse,
+        cache_hit: bool,
+        findings: Option<Vec<ThreatFinding>>,
+    ) {
+        let matches = match response.canonical_ip.parse() {
+            Ok(ip_addr) => self.ip_lookup_service.explain(ip_addr),
+            Err(_) => Vec::new(),
+        };
+        capture.record(CaptureRecord {
+            captured_at: chrono::Utc::now(),
+            profile: self.profile.to_string(),
+            cache_hit,
+            config_generation: self.config_generation,
+            monitor_forced: self.monitor_override.is_forced(),
+            matches,
+            findings,
+            response: response.clone(),
+        });
+    }
+
+    /// The cached response for `ip_addr`, from the local cache or else the
+    /// shared one, whose entry is then kept locally as well
+    async fn cached(&self, ip_addr: IpAddr) -> Option<LookupResponse> {
+        let key = self.cache_key(ip_addr);
+        if let Some(cached) = self.lookup_cache.get(&key) {
+            return Some(cached);
+        }
+        let cached = self.shared_cache.as_ref()?.get(&key.0, key.1).await?;
+        self.lookup_cache.insert(key, cached.clone());
+        Some(cached)
+    }
+
+    /// Whether a response for `ip_addr` is already cached
+    pub fn is_cached(&self, ip_addr: IpAddr) -> bool {
+        self.lookup_cache.contains_key(&self.cache_key(ip_addr))
+    }
+
+    /// Computes the threat score for an IP exactly as
+    /// [`LookupService::lookup_ip`] would, without building a full response
+    /// or touching the cache
+    pub fn threat_score(&self, ip_addr: IpAddr) -> Result<ThreatScore, AppError> {
+        let ip_addr = self.canonical(ip_addr);
+        let ip_category = self.ip_lookup_service.tree().lookup(ip_addr);
+        let tunnel = self.tunnel_match(ip_addr);
+        let (geo_info, _) = degrade(self.lookup_geo(ip_addr), "geo", ip_addr);
+        let traits = geo_info.and_then(|geo| geo.traits).unwrap_or_default();
+        let (asn_info, _) = degrade(self.lookup_asn(ip_addr), "asn", ip_addr);
+
+        let assessment = self.assess(ip_addr, ip_category, tunnel.as_ref(), Some(&traits), asn_info.as_ref());
+        Ok(assessment.threat_score)
+    }
+
+    /// The action a lookup scored `threat_score` would recommend, and the
+    /// rule behind it, including the escalation of the `closed`
+    /// missing-data policy. The flag tells whether threat data was missing.
+    pub fn decide(&self, threat_score: &ThreatScore) -> (ResponseAction, ActionDecision, bool) {
+        let service = self.response_action_service();
+        let (action, decision) = service.decide(threat_score);
+        let data_unavailable = self.data_unavailable();
+        // In monitor mode the escalation only reaches the shadow action
+        let escalate = data_unavailable
+            && self.missing_data.policy == ThreatDataPolicy::Closed
+            && !service.monitor_mode()
+            && self.missing_data.action > action;
+        match escalate {
+            true => (self.missing_data.action, ActionDecision::MissingData, data_unavailable),
+            false => (action, decision, data_unavailable),
+        }
+    }
+
+    fn response_action_service(&self) -> ResponseActionService {
+        ResponseActionService::with_config(self.response_action_config.clone())
+            .with_monitor_override(&self.monitor_override)
+    }
+
+    /// Whether the tree lacks, or holds stale data for, a category it should
+    /// have, under a policy that acts on it
+    fn data_unavailable(&self) -> bool {
+        self.missing_data.policy != ThreatDataPolicy::Open
+            && !self.ip_lookup_service.missing_categories(self.missing_data.max_age).is_empty()
+    }
+
+    /// Flags and scores a canonical `ip_addr` from its most specific tree
+    /// match, the IPv4 origin of a tunnel and the database records. Every
+    /// scoring path goes through here, so they cannot disagree.
+    fn assess(
+        &self,
+        ip_addr: IpAddr,
+        ip_category: Option<IpCategory>,
+        tunnel: Option<&TunnelMatch>,
+        traits: Option<&NetworkTraits>,
+        asn_info: Option<&AsnInfo>,
+    ) -> Assessment {
+        let (mut is_vpn, mut is_proxy, mut is_tor, mut proxy_type) =
+            category_flags(self.flagged_category(ip_addr, ip_category));
+
+        let mut threat_score = ThreatScore::from_ip_info(
+            ip_addr,
+            is_vpn,
+            is_proxy,
+            proxy_type,
+            is_tor,
+            traits,
+            &self.scoring_config,
+        );
+        threat_score.add_asn_findings(asn_info, &self.scoring_config);
+
+        // The tunnel's origin is flagged as if it had been looked up directly
+        if let Some(tunnel) = tunnel {
+            let (origin_vpn, origin_proxy, origin_tor, origin_proxy_type) = category_flags(tunnel.flagged);
+            is_vpn |= origin_vpn;
+            is_proxy |= origin_proxy;
+            is_tor |= origin_tor;
+            proxy_type = proxy_type.or(origin_proxy_type);
+            threat_score.add_tunnel_findings(tunnel.score(&self.scoring_config), tunnel.kind, &self.scoring_config);
+        }
+
+        Assessment { is_vpn, is_proxy, is_tor, proxy_type, threat_score }
+    }
+
+    fn lookup_geo(&self, ip_addr: IpAddr) -> DatabaseResult<GeoInfo> {
+        if !self.features.geo_lookup {
+            return Ok(None);
+        }
+        self.geo_provider.lookup_city(ip_addr)
+    }
+
+    fn lookup_asn(&self, ip_addr: IpAddr) -> DatabaseResult<AsnInfo> {
+        if !self.features.asn_lookup {
+            return Ok(None);
+        }
+        self.geo_provider.lookup_asn(ip_addr)
+    }
+
+    /// The geo and ASN lookups, each `None` if it missed `deadline`. Without
+    /// a deadline they run inline; with one, concurrently on the blocking
+    /// pool, so a slow database read cannot hold the response past it.
+    async fn lookup_databases(
+        &self,
+        ip_addr: IpAddr,
+        deadline: Option<Instant>,
+    ) -> (Option<DatabaseResult<GeoInfo>>, Option<DatabaseResult<AsnInfo>>) {
+        let Some(deadline) = deadline else {
+            return (Some(self.lookup_geo(ip_addr)), Some(self.lookup_asn(ip_addr)));
         };
+        let geo = async {
+            if !self.features.geo_lookup {
+                return Some(Ok(None));
+            }
+            let provider = Arc::clone(&self.geo_provider);
+            before(deadline, move || provider.lookup_city(ip_addr)).await
+        };
+        let asn = async {
+            if !self.features.asn_lookup {
+                return Some(Ok(None));
+            }
+            let provider = Arc::clone(&self.geo_provider);
+            before(deadline, move || provider.lookup_asn(ip_addr)).await
+        };
+        tokio::join!(geo, asn)
+    }
+}
+
+/// Run a database lookup on the blocking pool, giving up on it at
+/// `deadline`. A lookup still running then finishes unread.
+async fn before<T: Send + 'static>(
+    deadline: Instant,
+    lookup: impl FnOnce() -> DatabaseResult<T> + Send + 'static,
+) -> Option<DatabaseResult<T>> {
+    if Instant::now() >= deadline {
+        return None;
+    }
+    let joined = tokio::time::timeout_at(deadline, tokio::task::spawn_blocking(lookup)).await.ok()?;
+    Some(joined.unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic())))
+}
 
-        // Cache the response
-        self.lookup_cache.insert(ip_addr, response.clone());
+/// Split a database lookup into its record and its error, logging and
+/// counting the error so the lookup can carry on without that database
+fn degrade<T>(
+    result: Result<Option<T>, GeoProviderError>,
+    database: &'static str,
+    ip_addr: IpAddr,
+) -> (Option<T>, Option<GeoProviderError>) {
+    match result {
+        Ok(record) => (record, None),
+        Err(error) => {
+            tracing::warn!(database, ip = %redact::ip(ip_addr), "Database lookup failed, omitting it: {}", error);
+            GEO_LOOKUP_ERRORS.with_label_values(&[database]).inc();
+            (None, Some(error))
+        }
+    }
+}
+
+/// The threat flags and score of an IP, before an action is recommended
+struct Assessment {
+    is_vpn: bool,
+    is_proxy: bool,
+    is_tor: bool,
+    proxy_type: Option<&'static str>,
+    threat_score: ThreatScore,
+}
+
+/// A tree entry for the IPv4 origin of a 6to4 or Teredo address
+struct TunnelMatch {
+    kind: TunnelKind,
+    origin: Ipv4Addr,
+    category: IpCategory,
+    /// Where the origin's flags come from; see [`LookupService::flagged_category`]
+    flagged: Option<IpCategory>,
+    source: Option<Arc<str>>,
+}
+
+impl TunnelMatch {
+    /// The findings the origin would get if looked up directly
+    fn score(&self, config: &ThreatScoringConfig) -> ThreatScore {
+        let (is_vpn, is_proxy, is_tor, proxy_type) = category_flags(self.flagged);
+        ThreatScore::from_ip_info(IpAddr::V4(self.origin), is_vpn, is_proxy, proxy_type, is_tor, None, config)
+    }
+}
+
+/// Maps a tree category to (is_vpn, is_proxy, is_tor, proxy_type)
+fn category_flags(ip_category: Option<IpCategory>) -> (bool, bool, bool, Option<&'static str>) {
+    match ip_category {
+        Some(IpCategory::Vpn) => (true, false, false, None),
+        Some(IpCategory::ProxyHttp) => (false, true, false, Some("http")),
+        Some(IpCategory::ProxySocks4) => (false, true, false, Some("socks4")),
+        Some(IpCategory::ProxySocks5) => (false, true, false, Some("socks5")),
+        Some(IpCategory::TorExitNode) => (false, false, true, None),
+        // Cloud ranges are reported as `cloud_provider`, not as a VPN/datacenter
+        Some(IpCategory::CloudProvider(_)) | None => (false, false, false, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::OutboundHttpSettings;
+    use crate::geo::{MaxMindProvider, ProviderMetadata};
+    use crate::handlers::lookup_service;
+    use crate::ip_lookup::{CloudKind, IpLookupServiceConfig};
+    use crate::models::location::Country;
+    use crate::test_support;
+
+    #[derive(Debug)]
+    struct MockProvider;
+
+    impl GeoProvider for MockProvider {
+        fn lookup_city(&self, _ip: IpAddr) -> Result<Option<GeoInfo>, GeoProviderError> {
+            Ok(Some(GeoInfo {
+                city: None,
+                country: Some(Country {
+                    names: Some([("en".to_string(), "Mockland".to_string())].into_iter().collect()),
+                    iso_code: None,
+                }),
+                location: None,
+                traits: None,
+            }))
+        }
+
+        fn lookup_asn(&self, _ip: IpAddr) -> Result<Option<AsnInfo>, GeoProviderError> {
+            Ok(Some(AsnInfo {
+                autonomous_system_number: Some(64500),
+                autonomous_system_organization: Some("Mock Networks".to_string()),
+            }))
+        }
+
+        fn metadata(&self) -> ProviderMetadata {
+            ProviderMetadata {
+                provider: "mock",
+                databases: vec![],
+                build_epoch: None,
+            }
+        }
+    }
+
+    /// Fails lookups in the databases marked broken, like a truncated file
+    #[derive(Debug)]
+    struct BrokenProvider {
+        city: bool,
+        asn: bool,
+    }
+
+    impl GeoProvider for BrokenProvider {
+        fn lookup_city(&self, ip: IpAddr) -> Result<Option<GeoInfo>, GeoProviderError> {
+            if self.city {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "truncated city database").into());
+            }
+            MockProvider.lookup_city(ip)
+        }
+
+        fn lookup_asn(&self, ip: IpAddr) -> Result<Option<AsnInfo>, GeoProviderError> {
+            if self.asn {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "truncated ASN database").into());
+            }
+            MockProvider.lookup_asn(ip)
+        }
+
+        fn metadata(&self) -> ProviderMetadata {
+            MockProvider.metadata()
+        }
+    }
 
-        Ok(response)
+    /// Answers like [`MockProvider`], but the databases marked slow block
+    /// while the test holds `gate`
+    #[derive(Debug)]
+    struct SlowProvider {
+        city: bool,
+        asn: bool,
+        gate: Arc<std::sync::Mutex<()>>,
     }
-}
\ No newline at end of file
+
+    impl GeoProvider for SlowProvider {
+        fn lookup_city(&self, ip: IpAddr) -> Result<Option<GeoInfo>, GeoProviderError> {
+            if self.city {
+                drop(self.gate.lock());
+            }
+            MockProvider.lookup_city(ip)
+        }
+
+        fn lookup_asn(&self, ip: IpAddr) -> Result<Option<AsnInfo>, GeoProviderError> {
+            if self.asn {
+                drop(self.gate.lock());
+            }
+            MockProvider.lookup_asn(ip)
+        }
+
+        fn metadata(&self) -> ProviderMetadata {
+            MockProvider.metadata()
+        }
+    }
+
+    fn with_provider(provider: Arc<dyn GeoProvider>) -> LookupService {
+        let ip_lookup_service = Arc::new(IpLookupService::new(IpLookupServiceConfig {
+            data_dir: std::env::temp_dir(),
+            check_updates: false,
+            update_interval_secs: 3600,
+            max_cache_age_secs: 86400,
+            snapshot_retention: 0,
+            archive_dir: std::env::temp_dir().join("archive"),
+            archive_retention: 0,
+            ipv6_aggregate_prefix: 128,
+            bloom_filter: true,
+            flat_snapshot: false,
+            offline: false,
+            overrides_file: None,
+            outbound_http: OutboundHttpSettings::default(),
+            tor_delisted_window_secs: 0,
+            feed_backoff_max_secs: 0,
+            enabled_categories: None,
+            sources: vec![],
+        }));
+        LookupService::new(
+            provider,
+            Arc::new(Cache::new(100)),
+            ip_lookup_service,
+            ThreatScoringConfig::default(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_lookup_service_uses_trait_object() {
+        let provider: Arc<dyn GeoProvider> = Arc::new(MockProvider);
+        assert_eq!(provider.metadata().provider, "mock");
+        let service = with_provider(provider);
+
+        let response = service.lookup_ip("8.8.8.8".parse().unwrap()).await.unwrap();
+        let country = response.geo_info.unwrap().country.unwrap().names.unwrap();
+        assert_eq!(country.get("en").map(String::as_str), Some("Mockland"));
+        let asn = response.asn_info.unwrap();
+        assert_eq!(asn.autonomous_system_number, Some(64500));
+        assert_eq!(asn.autonomous_system_organization.as_deref(), Some("Mock Networks"));
+    }
+
+    #[tokio::test]
+    async fn test_lookup_service_with_maxmind_provider() {
+        use crate::test_support::mmdb::{asn_fixture, city_fixture, FIXTURE_US_IP};
+
+        let provider: Arc<dyn GeoProvider> =
+            Arc::new(MaxMindProvider::new(city_fixture(), asn_fixture()));
+        let service = with_provider(provider);
+
+        let response = service.lookup_ip(FIXTURE_US_IP.parse().unwrap()).await.unwrap();
+        assert_eq!(response.is_anycast, Some(true));
+        assert_eq!(response.is_hosting_provider, None);
+        let asn = response.asn_info.unwrap();
+        assert_eq!(asn.autonomous_system_number, Some(15169));
+        let country = response.geo_info.unwrap().country.unwrap().names.unwrap();
+        assert_eq!(country.get("en").map(String::as_str), Some("United States"));
+    }
+
+    #[tokio::test]
+    async fn test_lookup_service_omits_disabled_geo() {
+
+        let service = with_provider(Arc::new(MockProvider)).with_features(FeatureSettings {
+            geo_lookup: false,
+            ..FeatureSettings::default()
+        });
+
+        let response = service.lookup_ip("8.8.8.8".parse().unwrap()).await.unwrap();
+        assert!(response.geo_info.is_none());
+        assert!(response.asn_info.is_some());
+        assert_eq!(response.disabled_features, vec!["geo_lookup"]);
+    }
+
+    #[tokio::test]
+    async fn test_lookup_service_reports_shadow_action_in_monitor_mode() {
+
+        let response = with_provider(Arc::new(MockProvider))
+            .lookup_ip("8.8.8.8".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.shadow_action, None);
+
+        let service = with_provider(Arc::new(MockProvider)).with_response_action_config(
+            ResponseActionConfig {
+                monitor_mode: true,
+                ..Default::default()
+            },
+        );
+        let response = service.lookup_ip("8.8.4.4".parse().unwrap()).await.unwrap();
+        assert_eq!(response.recommended_action, "monitor");
+        assert_eq!(response.shadow_action.as_deref(), Some("allow"));
+    }
+
+    #[tokio::test]
+    async fn test_failing_database_nulls_only_its_field() {
+
+        let mut state = test_support::app_state();
+        state.geo_provider = Arc::new(BrokenProvider { city: false, asn: true });
+        let service = lookup_service(&state);
+
+        let ip: IpAddr = "8.8.8.8".parse().unwrap();
+        let response = service.lookup_ip(ip).await.unwrap();
+        assert!(response.geo_info.is_some());
+        assert!(response.asn_info.is_none());
+        assert_eq!(response.errors.geo, None);
+        assert!(response.errors.asn.as_deref().unwrap().contains("truncated ASN database"));
+        // Not cached, so the next lookup sees a repaired database
+        assert!(!service.is_cached(ip));
+
+        // The endpoint still answers 200, with the error alongside the data
+        let router = crate::routes::create_router(state);
+        let request = test_support::request()
+            .uri("/api/lookup/8.8.8.8")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let response = tower::ServiceExt::oneshot(router, request).await.unwrap();
+        assert_eq!(response.status(), axum::http::StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["asn_info"], serde_json::Value::Null);
+        assert_eq!(body["geo_info"]["country"]["names"]["en"], "Mockland");
+        assert!(body["errors"].get("geo").is_none());
+        assert!(body["errors"]["asn"].as_str().unwrap().contains("truncated ASN database"));
+    }
+
+    #[tokio::test]
+    async fn test_failing_databases_fail_lookup_only_without_threat_data() {
+        use crate::test_support::{app_state_with_ranges, range};
+
+        let mut state = app_state_with_ranges(vec![range("5.1.1.0/24", IpCategory::TorExitNode)]);
+        state.geo_provider = Arc::new(BrokenProvider { city: true, asn: true });
+        let service = lookup_service(&state);
+
+        // The tree still has an answer
+        let response = service.lookup_ip("5.1.1.1".parse().unwrap()).await.unwrap();
+        assert!(response.is_tor_exit_node);
+        assert!(response.errors.geo.is_some() && response.errors.asn.is_some());
+
+        // Nothing is left to answer with
+        let error = service.lookup_ip("8.8.8.8".parse().unwrap()).await.unwrap_err();
+        assert!(matches!(error, crate::errors::AppError::GeoProviderError(_)));
+
+        // Scoring carries on without traits
+        let score = service.threat_score("5.1.1.1".parse().unwrap()).unwrap();
+        assert!(score.score > 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    // Holding the gate across awaits is what stalls the databases
+    #[allow(clippy::await_holding_lock)]
+    async fn test_deadline_skips_slow_databases() {
+        use crate::test_support::{app_state_with_ranges, range};
+        use tokio::time::Instant;
+
+        let gate = Arc::new(std::sync::Mutex::new(()));
+        let held = gate.lock().unwrap();
+        let mut state = app_state_with_ranges(vec![range("5.1.1.0/24", IpCategory::TorExitNode)]);
+        state.geo_provider = Arc::new(SlowProvider { city: true, asn: true, gate: Arc::clone(&gate) });
+        let service = lookup_service(&state);
+
+        let started = Instant::now();
+        let deadline = started + Duration::from_millis(50);
+        let lookup = tokio::spawn(async move { service.lookup_ip_within("5.1.1.1".parse().unwrap(), Some(deadline)).await });
+        // Let the lookup start both database reads before time moves on
+        tokio::task::yield_now().await;
+        tokio::time::advance(Duration::from_millis(50)).await;
+        let response = lookup.await.unwrap().unwrap();
+        assert_eq!(started.elapsed(), Duration::from_millis(50));
+
+        // The tree flags are in, the databases are not
+        assert!(response.is_tor_exit_node);
+        assert_eq!(response.recommended_action, "block");
+        assert!(response.partial);
+        assert_eq!(response.skipped, vec!["geo", "asn"]);
+        assert!(response.geo_info.is_none() && response.asn_info.is_none());
+        assert!(response.errors.is_empty());
+        drop(held);
+    }
+
+    #[tokio::test(start_paused = true)]
+    // Holding the gate across awaits is what stalls the databases
+    #[allow(clippy::await_holding_lock)]
+    async fn test_lookup_timeout_bounds_every_lookup() {
+        use tokio::time::Instant;
+
+        let gate = Arc::new(std::sync::Mutex::new(()));
+        let held = gate.lock().unwrap();
+        let mut state = crate::test_support::app_state();
+        state.geo_provider = Arc::new(SlowProvider { city: true, asn: true, gate: Arc::clone(&gate) });
+        state.lookup_timeout = Some(Duration::from_millis(50));
+        let service = Arc::new(lookup_service(&state));
+
+        // No deadline of its own
+        let started = Instant::now();
+        let lookup = tokio::spawn({
+            let service = Arc::clone(&service);
+            async move { service.lookup_ip("8.8.8.8".parse().unwrap()).await }
+        });
+        tokio::task::yield_now().await;
+        tokio::time::advance(Duration::from_millis(50)).await;
+        let response = lookup.await.unwrap().unwrap();
+        assert_eq!(started.elapsed(), Duration::from_millis(50));
+        assert!(response.partial);
+        assert_eq!(response.skipped, vec!["geo", "asn"]);
+
+        // A shorter request deadline still wins
+        let started = Instant::now();
+        let deadline = started + Duration::from_millis(10);
+        let lookup = tokio::spawn(async move { service.lookup_ip_within("8.8.4.4".parse().unwrap(), Some(deadline)).await });
+        tokio::task::yield_now().await;
+        tokio::time::advance(Duration::from_millis(10)).await;
+        assert!(lookup.await.unwrap().unwrap().partial);
+        assert_eq!(started.elapsed(), Duration::from_millis(10));
+        drop(held);
+    }
+
+    #[tokio::test]
+    // Holding the gate across awaits is what stalls the databases
+    #[allow(clippy::await_holding_lock)]
+    async fn test_deadline_keeps_databases_that_finished() {
+        let gate = Arc::new(std::sync::Mutex::new(()));
+        let held = gate.lock().unwrap();
+        let service = with_provider(Arc::new(SlowProvider { city: false, asn: true, gate: Arc::clone(&gate) }));
+        let ip: IpAddr = "8.8.8.8".parse().unwrap();
+
+        let started = std::time::Instant::now();
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(200);
+        let response = service.lookup_ip_within(ip, Some(deadline)).await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(2), "waited for the stalled database");
+        assert!(response.geo_info.is_some());
+        assert!(response.asn_info.is_none());
+        assert!(response.partial);
+        assert_eq!(response.skipped, vec!["asn"]);
+        assert!(!service.is_cached(ip));
+
+        // Without a deadline the lookup waits for every database
+        drop(held);
+        let response = service.lookup_ip(ip).await.unwrap();
+        assert!(response.asn_info.is_some());
+        assert!(!response.partial && response.skipped.is_empty());
+        assert!(service.is_cached(ip));
+    }
+
+    #[tokio::test]
+    async fn test_cloud_ranges_do_not_mask_vpn_ranges() {
+        let aws = IpCategory::CloudProvider(CloudKind::Aws);
+        let state = test_support::app_state_with_ranges(vec![
+            test_support::range("52.0.0.0/8", IpCategory::Vpn),
+            test_support::range("52.1.0.0/16", aws),
+            test_support::range("54.0.0.0/16", IpCategory::Vpn),
+            test_support::range("54.0.0.0/16", aws),
+        ]);
+        let service = lookup_service(&state);
+        let vpn_only = service.lookup_ip("52.2.0.1".parse().unwrap()).await.unwrap();
+        assert!(vpn_only.is_vpn_or_datacenter);
+        assert!(vpn_only.threat_score > 0);
+
+        for ip in ["52.1.2.3", "54.0.0.1"] {
+            let ip_addr: IpAddr = ip.parse().unwrap();
+            let response = service.lookup_ip(ip_addr).await.unwrap();
+            assert!(response.is_vpn_or_datacenter, "{}", ip);
+            assert!(response.categories.vpn, "{}", ip);
+            assert_eq!(response.threat_score, vpn_only.threat_score, "{}", ip);
+            assert_eq!(service.threat_score(ip_addr).unwrap().score, vpn_only.threat_score, "{}", ip);
+        }
+        // The nested cloud range is still reported
+        let nested = service.lookup_ip("52.1.2.3".parse().unwrap()).await.unwrap();
+        assert_eq!(nested.cloud_provider, Some(CloudKind::Aws));
+        assert_eq!(nested.category.as_deref(), Some("cloud_aws"));
+    }
+}
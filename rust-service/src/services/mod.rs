@@ -1,6 +1,14 @@
 pub mod vpn_detection;
 pub mod proxy_detection;
-pub mod tor_detection;
 pub mod background_updater;
 pub mod lookup_service;
-pub mod response_action;
\ No newline at end of file
+pub mod profiles;
+pub mod response_action;
+pub mod cache_warming;
+pub mod config_reload;pub mod usage;
+pub mod decision_log;
+pub mod challenge;
+pub mod aggregates;
+pub mod connectivity;
+pub mod shared_cache;
+pub mod debug_capture;
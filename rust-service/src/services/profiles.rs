@@ -0,0 +1,204 @@
+//! Per-tenant scoring profiles, selected by the role on the caller's API key.
+//!
+//! Each profile overlays the service-wide scoring weights and response action
+//! thresholds from `Settings`. Unknown roles, and requests without a role,
+//! get the default profile.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::config::{ProfileSettings, Settings};
+use crate::models::threat_score::ThreatScoringConfig;
+use crate::services::response_action::ResponseActionConfig;
+
+/// Name of the profile built from the service-wide settings
+pub const DEFAULT_PROFILE: &str = "default";
+
+const ROLE_KEY_PREFIX: &str = "role:";
+
+/// Key of the profile resolved for a request (see [`ScoringProfile::key`]),
+/// attached as a request extension by the API key middleware
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfileName(pub Arc<str>);
+
+impl From<&str> for ProfileName {
+    fn from(name: &str) -> Self {
+        Self(name.into())
+    }
+}
+
+/// Resolved scoring and action configuration for one tenant
+#[derive(Debug, Clone)]
+pub struct ScoringProfile {
+    pub name: Arc<str>,
+    /// Unique across profiles, even for a role named `default`: role
+    /// profiles are `role:<role>`. Responses are cached under it.
+    pub key: Arc<str>,
+    pub scoring: ThreatScoringConfig,
+    pub response_action: ResponseActionConfig,
+}
+
+impl ScoringProfile {
+    /// Apply `overrides` on top of the base configuration
+    fn overlay(
+        name: &str,
+        base_scoring: &ThreatScoringConfig,
+        base_action: &ResponseActionConfig,
+        overrides: &ProfileSettings,
+    ) -> Self {
+        let mut scoring = base_scoring.clone();
+        let mut response_action = base_action.clone();
+
+        let weights = [
+            (&mut scoring.vpn_weight, overrides.vpn_weight),
+            (&mut scoring.proxy_weight, overrides.proxy_weight),
+            (&mut scoring.tor_weight, overrides.tor_weight),
+            (&mut scoring.anonymous_proxy_weight, overrides.anonymous_proxy_weight),
+            (&mut scoring.hosting_provider_weight, overrides.hosting_provider_weight),
+            (&mut scoring.datacenter_weight, overrides.datacenter_weight),
+        ];
+        for (weight, value) in weights {
+            if let Some(value) = value {
+                *weight = value;
+            }
+        }
+
+        let thresholds = [
+            (&mut response_action.monitor_threshold, overrides.monitor_threshold),
+            (&mut response_action.challenge_threshold, overrides.challenge_threshold),
+            (&mut response_action.redirect_threshold, overrides.redirect_threshold),
+        ];
+        for (threshold, value) in thresholds {
+            if let Some(value) = value {
+                *threshold = value;
+            }
+        }
+
+        if let Some(block_immediate) = &overrides.block_immediate {
+            response_action.block_immediate = block_immediate.clone();
+        }
+
+        Self {
+            name: name.into(),
+            key: format!("{}{}", ROLE_KEY_PREFIX, name).into(),
+            scoring,
+            response_action,
+        }
+    }
+}
+
+/// The default profile plus any role-specific ones
+#[derive(Debug, Clone)]
+pub struct ScoringProfiles {
+    default: Arc<ScoringProfile>,
+    by_role: HashMap<String, Arc<ScoringProfile>>,
+}
+
+impl ScoringProfiles {
+    /// Only the default profile, built from the given configuration
+    pub fn new(scoring: ThreatScoringConfig, response_action: ResponseActionConfig) -> Self {
+        Self {
+            default: Arc::new(ScoringProfile {
+                name: DEFAULT_PROFILE.into(),
+                key: DEFAULT_PROFILE.into(),
+                scoring,
+                response_action,
+            }),
+            by_role: HashMap::new(),
+        }
+    }
+
+    /// Build the default profile and one profile per entry in `profiles`
+    pub fn from_settings(settings: &Settings) -> Self {
+        let mut profiles = Self::new((&settings.scoring).into(), settings.response_action.clone());
+        for (role, overrides) in &settings.profiles {
+            profiles.insert(role, overrides);
+        }
+        profiles
+    }
+
+    /// Add (or replace) the profile for `role`
+    pub fn insert(&mut self, role: &str, overrides: &ProfileSettings) {
+        let profile = ScoringProfile::overlay(
+            role,
+            &self.default.scoring,
+            &self.default.response_action,
+            overrides,
+        );
+        self.by_role.insert(role.to_string(), Arc::new(profile));
+    }
+
+    /// The profile for `role`, falling back to the default
+    pub fn resolve(&self, role: Option<&str>) -> Arc<ScoringProfile> {
+        role.and_then(|role| self.by_role.get(role))
+            .unwrap_or(&self.default)
+            .clone()
+    }
+
+    /// The profile with `key`, as carried by [`ProfileName`], falling back
+    /// to the default
+    pub fn by_key(&self, key: &str) -> Arc<ScoringProfile> {
+        self.resolve(key.strip_prefix(ROLE_KEY_PREFIX))
+    }
+
+    /// Configured role names, in no particular order
+    pub fn roles(&self) -> impl Iterator<Item = &str> {
+        self.by_role.keys().map(String::as_str)
+    }
+}
+
+impl Default for ScoringProfiles {
+    fn default() -> Self {
+        Self::new(ThreatScoringConfig::default(), ResponseActionConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::threat_score::ThreatType;
+
+    #[test]
+    fn test_overrides_apply_on_top_of_defaults() {
+        let mut profiles = ScoringProfiles::default();
+        profiles.insert(
+            "strict",
+            &ProfileSettings {
+                vpn_weight: Some(1.0),
+                challenge_threshold: Some(30),
+                block_immediate: Some(vec![ThreatType::TorExitNode, ThreatType::VpnOrDatacenter]),
+                ..Default::default()
+            },
+        );
+
+        let strict = profiles.resolve(Some("strict"));
+        assert_eq!(&*strict.name, "strict");
+        assert_eq!(strict.scoring.vpn_weight, 1.0);
+        assert_eq!(strict.scoring.proxy_weight, ThreatScoringConfig::default().proxy_weight);
+        assert_eq!(strict.response_action.challenge_threshold, 30);
+        assert_eq!(strict.response_action.monitor_threshold, 20);
+        assert_eq!(strict.response_action.block_immediate.len(), 2);
+    }
+
+    #[test]
+    fn test_unknown_role_falls_back_to_default() {
+        let mut profiles = ScoringProfiles::default();
+        profiles.insert("strict", &ProfileSettings::default());
+
+        assert_eq!(&*profiles.resolve(Some("nobody")).name, DEFAULT_PROFILE);
+        assert_eq!(&*profiles.resolve(None).name, DEFAULT_PROFILE);
+        assert_eq!(profiles.roles().collect::<Vec<_>>(), vec!["strict"]);
+    }
+
+    #[test]
+    fn test_role_named_default_has_its_own_key() {
+        let mut profiles = ScoringProfiles::default();
+        profiles.insert("default", &ProfileSettings { challenge_threshold: Some(10), ..Default::default() });
+
+        let role = profiles.resolve(Some("default"));
+        let fallback = profiles.resolve(None);
+        assert_ne!(role.key, fallback.key);
+        assert_eq!(profiles.by_key(&role.key).response_action.challenge_threshold, 10);
+        assert_eq!(&*profiles.by_key(&fallback.key).key, DEFAULT_PROFILE);
+    }
+}
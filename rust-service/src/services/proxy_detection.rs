@@ -1,18 +1,16 @@
 use crate::config::Settings;
+use crate::errors::validation::canonical_ip;
+use crate::utils::redact;
 use ipnetwork::IpNetwork;
-use once_cell::sync::Lazy;
-use std::fs::File;
-use std::io::{self, BufRead};
+use crate::ip_lookup::loader::load_feed_file;
+use std::fmt;
+use std::io;
 use std::net::IpAddr;
-use std::path::{Path};
+use std::path::{Path, PathBuf};
 use std::collections::HashSet;
+use std::time::Instant;
 use tracing::{debug, info, warn};
 
-static PROXY_DETECTOR: Lazy<ProxyDetector> = Lazy::new(|| {
-    let settings = Settings::new().expect("Failed to load settings");
-    ProxyDetector::new(&settings).expect("Failed to initialize ProxyDetector")
-});
-
 /// Detects if an IP address is a known proxy server.
 pub struct ProxyDetector {
     http_proxies: HashSet<IpAddr>,
@@ -20,23 +18,26 @@ pub struct ProxyDetector {
     socks5_proxies: HashSet<IpAddr>,
 }
 
+impl fmt::Debug for ProxyDetector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProxyDetector")
+            .field("http_proxies", &self.http_proxies.len())
+            .field("socks4_proxies", &self.socks4_proxies.len())
+            .field("socks5_proxies", &self.socks5_proxies.len())
+            .finish()
+    }
+}
+
 impl ProxyDetector {
-    /// Creates a new ProxyDetector instance and loads proxy IPs from the configured paths.
-    pub fn new(settings: &Settings) -> io::Result<Self> {
+    /// Loads the three proxy lists from the configured paths concurrently.
+    /// Run once at startup; the handlers share the result through `AppState`.
+    pub async fn load(settings: &Settings) -> io::Result<Self> {
         let (http_path, socks4_path, socks5_path) = settings.resolve_proxy_detector_db_paths()?;
-        
-        info!("Loading HTTP proxies from: {}", http_path.display());
-        let http_proxies = Self::load_proxy_ips(&http_path)?;
-        info!("Loaded {} HTTP proxies", http_proxies.len());
-        
-        info!("Loading SOCKS4 proxies from: {}", socks4_path.display());
-        let socks4_proxies = Self::load_proxy_ips(&socks4_path)?;
-        info!("Loaded {} SOCKS4 proxies", socks4_proxies.len());
-        
-        info!("Loading SOCKS5 proxies from: {}", socks5_path.display());
-        let socks5_proxies = Self::load_proxy_ips(&socks5_path)?;
-        info!("Loaded {} SOCKS5 proxies", socks5_proxies.len());
-        
+        let (http_proxies, socks4_proxies, socks5_proxies) = tokio::try_join!(
+            Self::load_list("HTTP", http_path),
+            Self::load_list("SOCKS4", socks4_path),
+            Self::load_list("SOCKS5", socks5_path),
+        )?;
         Ok(Self {
             http_proxies,
             socks4_proxies,
@@ -44,18 +45,38 @@ impl ProxyDetector {
         })
     }
 
-    fn load_proxy_ips<P: AsRef<Path>>(path: P) -> io::Result<HashSet<IpAddr>> {
-        debug!("Loading proxy IPs from: {}", path.as_ref().display());
-        let file = File::open(path)?;
-        let reader = io::BufReader::new(file);
+    /// Creates a detector over in-memory proxy lists.
+    #[cfg(test)]
+    pub fn from_lists(http_proxies: HashSet<IpAddr>, socks4_proxies: HashSet<IpAddr>, socks5_proxies: HashSet<IpAddr>) -> Self {
+        Self {
+            http_proxies,
+            socks4_proxies,
+            socks5_proxies,
+        }
+    }
+
+    async fn load_list(kind: &'static str, path: PathBuf) -> io::Result<HashSet<IpAddr>> {
+        info!("Loading {} proxies from: {}", kind, path.display());
+        let started = Instant::now();
+        let proxy_ips = load_feed_file(path, Self::parse_proxy_ips).await?;
+        info!(
+            kind,
+            proxies = proxy_ips.len(),
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "Loaded proxy list"
+        );
+        Ok(proxy_ips)
+    }
+
+    fn parse_proxy_ips(content: &str, path: &Path) -> io::Result<HashSet<IpAddr>> {
+        debug!("Parsing proxy IPs from: {}", path.display());
         let mut proxy_ips = HashSet::new();
         let mut line_count = 0;
         let mut invalid_count = 0;
         let mut duplicate_count = 0;
 
-        for line in reader.lines() {
+        for line in content.lines() {
             line_count += 1;
-            let line = line?;
             let line = line.trim();
             if line.is_empty() || line.starts_with('#') {
                 continue;
@@ -83,6 +104,12 @@ impl ProxyDetector {
         if invalid_count > 0 {
             warn!("Failed to parse {}/{} proxy entries", invalid_count, line_count);
         }
+        if proxy_ips.is_empty() && !content.trim().is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("no proxy IPs parsed from {} ({} invalid entries)", path.display(), invalid_count),
+            ));
+        }
         if duplicate_count > 0 {
             debug!("Skipped {} duplicate proxy IPs", duplicate_count);
         }
@@ -93,8 +120,10 @@ impl ProxyDetector {
     }
 
     /// Checks if the given IP address is a known proxy and returns its type if found.
-    /// Returns None if the IP is not a known proxy.
+    /// Returns None if the IP is not a known proxy. IPv4-mapped IPv6 addresses are
+    /// checked as IPv4.
     pub fn check_proxy(&self, ip: IpAddr) -> Option<&'static str> {
+        let ip = canonical_ip(ip);
         if self.http_proxies.contains(&ip) {
             Some("HTTP/HTTPS")
         } else if self.socks5_proxies.contains(&ip) {
@@ -113,16 +142,18 @@ impl ProxyDetector {
     }
 
     /// Checks if any IP in the given network range is a known proxy server.
-    pub fn is_range_proxy(&self, cidr: &str) -> Option<bool> {
+    ///
+    /// A scan still running at `deadline` stops early and reports `false`.
+    pub fn is_range_proxy(&self, cidr: &str, deadline: Instant) -> Option<bool> {
         let input_network = match cidr.parse::<IpNetwork>() {
             Ok(net) => net,
             Err(_) => {
-                warn!("Failed to parse network: {}", cidr);
+                warn!("Failed to parse network: {}", redact::text(cidr));
                 return None;
             }
         };
 
-        debug!("Checking network for proxy IPs: {}", input_network);
+        debug!("Checking network for proxy IPs: {}", redact::text(input_network.to_string()));
         
         // For small networks, do a full scan
         let prefix = input_network.prefix();
@@ -130,30 +161,33 @@ impl ProxyDetector {
             let ip_count = 2u32.pow(32 - prefix as u32);
             info!(
                 "Performing full IP scan for {}/{} ({} IPs)",
-                input_network.ip(),
+                redact::ip(input_network.ip()),
                 prefix,
                 ip_count
             );
             
             for ip in input_network.iter() {
+                if Instant::now() >= deadline {
+                    warn!(
+                        "Proxy scan of {}/{} timed out; reporting no proxy",
+                        redact::ip(input_network.ip()),
+                        prefix
+                    );
+                    return Some(false);
+                }
                 if self.is_proxy(ip) {
-                    debug!("Found proxy IP in range: {}", ip);
+                    debug!("Found proxy IP in range: {}", redact::ip(ip));
                     return Some(true);
                 }
             }
-            debug!("No proxy IPs found in network {}", input_network);
+            debug!("No proxy IPs found in network {}", redact::text(input_network.to_string()));
             return Some(false);
         }
 
         // For larger networks, just check the network IP
-        debug!("Checking network IP for proxy: {}", input_network.ip());
+        debug!("Checking network IP for proxy: {}", redact::ip(input_network.ip()));
         Some(self.is_proxy(input_network.ip()))
     }
-    
-    /// Returns a reference to the global ProxyDetector instance.
-    pub fn get() -> &'static ProxyDetector {
-        &PROXY_DETECTOR
-    }
 }
 
 #[cfg(test)]
@@ -190,10 +224,10 @@ mod tests {
         (settings, dir)
     }
 
-    #[test]
-    fn test_proxy_detection() {
+    #[tokio::test]
+    async fn test_proxy_detection() {
         let (settings, _dir) = create_test_settings();
-        let detector = ProxyDetector::new(&settings).unwrap();
+        let detector = ProxyDetector::load(&settings).await.unwrap();
         
         // Test HTTP proxy detection
         assert_eq!(detector.check_proxy("1.1.1.1".parse().unwrap()), Some("HTTP/HTTPS"));
@@ -213,24 +247,34 @@ mod tests {
         assert!(!detector.is_proxy("8.8.8.8".parse().unwrap()));
     }
 
-    #[test]
-    fn test_range_proxy_detection() {
+    #[tokio::test]
+    async fn test_range_proxy_detection() {
         let (settings, _dir) = create_test_settings();
-        let detector = ProxyDetector::new(&settings).unwrap();
+        let detector = ProxyDetector::load(&settings).await.unwrap();
+        let deadline = Instant::now() + std::time::Duration::from_secs(5);
         
         // Test range containing HTTP proxy
-        assert_eq!(detector.is_range_proxy("1.1.1.0/24"), Some(true));
+        assert_eq!(detector.is_range_proxy("1.1.1.0/24", deadline), Some(true));
         
         // Test range containing SOCKS4 proxy
-        assert_eq!(detector.is_range_proxy("3.3.3.0/24"), Some(true));
+        assert_eq!(detector.is_range_proxy("3.3.3.0/24", deadline), Some(true));
         
         // Test range containing SOCKS5 proxy
-        assert_eq!(detector.is_range_proxy("4.4.4.0/24"), Some(true));
+        assert_eq!(detector.is_range_proxy("4.4.4.0/24", deadline), Some(true));
         
         // Test range with no proxies
-        assert_eq!(detector.is_range_proxy("8.8.8.0/24"), Some(false));
+        assert_eq!(detector.is_range_proxy("8.8.8.0/24", deadline), Some(false));
         
         // Test invalid range
-        assert_eq!(detector.is_range_proxy("invalid"), None);
+        assert_eq!(detector.is_range_proxy("invalid", deadline), None);
+    }
+
+    #[tokio::test]
+    async fn test_range_scan_stops_at_deadline() {
+        let (settings, _dir) = create_test_settings();
+        let detector = ProxyDetector::load(&settings).await.unwrap();
+
+        // 1.1.1.1 is a proxy, but the scan gives up before reaching it
+        assert_eq!(detector.is_range_proxy("1.1.1.0/24", Instant::now()), Some(false));
     }
 }
\ No newline at end of file
@@ -1,8 +1,14 @@
-use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 use crate::models::threat_score::{ThreatScore, ThreatType};
 
-/// Represents the recommended response action for a given threat level
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+/// Represents the recommended response action for a given threat level,
+/// ordered from least to most strict
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
 pub enum ResponseAction {
     /// Allow the request without any challenges
     Allow,
@@ -20,8 +26,42 @@ pub enum ResponseAction {
     Block,
 }
 
+/// Parses the lowercase names used in responses, e.g. `challenge`
+impl std::str::FromStr for ResponseAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(Self::Allow),
+            "monitor" => Ok(Self::Monitor),
+            "challenge" => Ok(Self::Challenge),
+            "redirect" => Ok(Self::Redirect),
+            "block" => Ok(Self::Block),
+            _ => Err(format!("Unknown response action: {}", s)),
+        }
+    }
+}
+
+/// The rule that produced a [`ResponseAction`]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum ActionDecision {
+    /// A finding's threat type is in `block_immediate`
+    BlockImmediate { threat_type: ThreatType },
+    /// Monitor mode overrides the score thresholds
+    MonitorMode,
+    /// The score fell within the inclusive `min_score..=max_score` band
+    ScoreBand { min_score: u8, max_score: u8 },
+    /// Threat data was missing or stale under the `closed` policy, which
+    /// raised the action to its configured minimum
+    MissingData,
+}
+
 /// Configuration for response action determination
-#[derive(Debug, Clone)]
+///
+/// Missing fields fall back to their defaults when deserialized.
+#[derive(Debug, Clone, PartialEq, Deserialize, ToSchema)]
+#[serde(default)]
 pub struct ResponseActionConfig {
     /// Threshold for Monitor action (0-100)
     pub monitor_threshold: u8,
@@ -53,9 +93,29 @@ impl Default for ResponseActionConfig {
     }
 }
 
+/// Process-wide switch that forces monitor mode regardless of the configured
+/// `monitor_mode`, for downgrading enforcement during an incident without a
+/// config change. Clones share the switch.
+#[derive(Debug, Clone, Default)]
+pub struct MonitorOverride(Arc<AtomicBool>);
+
+impl MonitorOverride {
+    /// Whether monitor mode is currently forced
+    pub fn is_forced(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Force monitor mode on, or hand back to the configured `monitor_mode`.
+    /// Returns the previous state.
+    pub fn set(&self, forced: bool) -> bool {
+        self.0.swap(forced, Ordering::Relaxed)
+    }
+}
+
 /// Service for determining the appropriate response action based on threat assessment
 pub struct ResponseActionService {
     config: ResponseActionConfig,
+    monitor_override: Option<MonitorOverride>,
 }
 
 impl ResponseActionService {
@@ -66,40 +126,91 @@ impl ResponseActionService {
     
     /// Creates a new ResponseActionService with custom configuration
     pub fn with_config(config: ResponseActionConfig) -> Self {
-        Self { config }
+        Self { config, monitor_override: None }
+    }
+
+    /// Monitor whenever `monitor_override` is forced, whatever the config says
+    pub fn with_monitor_override(mut self, monitor_override: &MonitorOverride) -> Self {
+        self.monitor_override = Some(monitor_override.clone());
+        self
+    }
+
+    /// Whether actions are downgraded to monitor, by config or the override
+    pub fn monitor_mode(&self) -> bool {
+        self.config.monitor_mode || self.monitor_override.as_ref().is_some_and(MonitorOverride::is_forced)
     }
     
     /// Determines the recommended response action based on the threat score and findings
     /// Takes in already-implemented ThreatScore struct
     pub fn determine_action(&self, threat_score: &ThreatScore) -> ResponseAction {
-        // First check for immediate blocks
-        for finding in &threat_score.findings {
-            if self.config.block_immediate.contains(&finding.threat_type) {
-                return if self.config.monitor_mode {
-                    ResponseAction::Monitor
-                } else {
-                    ResponseAction::Block
-                };
-            }
+        self.decide(threat_score).0
+    }
+
+    /// Determines the effective action plus, in monitor mode, the "shadow"
+    /// action that would have been enforced with monitor mode off
+    pub fn determine_action_with_shadow(
+        &self,
+        threat_score: &ThreatScore,
+    ) -> (ResponseAction, Option<ResponseAction>) {
+        let (action, _) = self.decide(threat_score);
+        let shadow = self.monitor_mode().then(|| self.enforced_decision(threat_score).0);
+        (action, shadow)
+    }
+
+    /// Determines the recommended action along with the rule that produced it
+    pub fn decide(&self, threat_score: &ThreatScore) -> (ResponseAction, ActionDecision) {
+        self.monitored(self.enforced_decision(threat_score))
+    }
+
+    /// Like [`ResponseActionService::decide`], for a score computed elsewhere
+    /// and the threat types behind it
+    pub fn decide_score(&self, score: u8, threat_types: &[ThreatType]) -> (ResponseAction, ActionDecision) {
+        self.monitored(self.enforced_for(score, threat_types.iter().copied()))
+    }
+
+    /// `enforced` as it applies once monitor mode is taken into account
+    fn monitored(&self, enforced: (ResponseAction, ActionDecision)) -> (ResponseAction, ActionDecision) {
+        let (action, decision) = enforced;
+        if !self.monitor_mode() {
+            return (action, decision);
         }
-        
-        // If in monitor mode, just monitor regardless of score
-        if self.config.monitor_mode {
-            return ResponseAction::Monitor;
+
+        // In monitor mode, just monitor regardless of score
+        match decision {
+            ActionDecision::BlockImmediate { .. } => (ResponseAction::Monitor, decision),
+            _ => (ResponseAction::Monitor, ActionDecision::MonitorMode),
         }
-        
+    }
+
+    /// The action and rule that apply when monitor mode is off
+    fn enforced_decision(&self, threat_score: &ThreatScore) -> (ResponseAction, ActionDecision) {
+        self.enforced_for(threat_score.score, threat_score.findings.iter().map(|finding| finding.threat_type))
+    }
+
+    fn enforced_for(
+        &self,
+        score: u8,
+        mut threat_types: impl Iterator<Item = ThreatType>,
+    ) -> (ResponseAction, ActionDecision) {
+        // First check for immediate blocks
+        if let Some(threat_type) = threat_types.find(|threat_type| self.config.block_immediate.contains(threat_type)) {
+            return (ResponseAction::Block, ActionDecision::BlockImmediate { threat_type });
+        }
+
         // Determine action based on score thresholds
-        let score = threat_score.score;
+        let config = &self.config;
         
-        if score > self.config.redirect_threshold {
-            ResponseAction::Redirect
-        } else if score > self.config.challenge_threshold {
-            ResponseAction::Challenge
-        } else if score > self.config.monitor_threshold {
-            ResponseAction::Monitor
+        let (action, min_score, max_score) = if score > config.redirect_threshold {
+            (ResponseAction::Redirect, config.redirect_threshold.saturating_add(1), 100)
+        } else if score > config.challenge_threshold {
+            (ResponseAction::Challenge, config.challenge_threshold.saturating_add(1), config.redirect_threshold)
+        } else if score > config.monitor_threshold {
+            (ResponseAction::Monitor, config.monitor_threshold.saturating_add(1), config.challenge_threshold)
         } else {
-            ResponseAction::Allow
-        }
+            (ResponseAction::Allow, 0, config.monitor_threshold)
+        };
+
+        (action, ActionDecision::ScoreBand { min_score, max_score })
     }
     
     // Convenience method to determine action from raw score and findings
@@ -162,6 +273,7 @@ mod tests {
         for (score, expected) in test_cases {
             let test_score = ThreatScore {
                 score,
+                score_precise: score as f32,
                 findings: vec![],
                 ip,
             };
@@ -176,6 +288,7 @@ mod tests {
         // Test immediate block for Tor exit nodes
         let tor_score = ThreatScore {
             score: 10,  // Low score but should be blocked immediately
+            score_precise: 10.0,
             findings: vec![crate::models::threat_score::ThreatFinding {
                 threat_type: ThreatType::TorExitNode,
                 description: "Tor exit node".to_string(),
@@ -193,6 +306,7 @@ mod tests {
         
         let high_score = ThreatScore {
             score: 100,
+            score_precise: 100.0,
             findings: vec![],
             ip,
         };
@@ -200,4 +314,151 @@ mod tests {
         assert_eq!(monitor_service.determine_action(&high_score), ResponseAction::Monitor);
         assert_eq!(monitor_service.determine_action(&tor_score), ResponseAction::Monitor);
     }
+
+    #[test]
+    fn test_config_deserialize_partial() {
+        let config: ResponseActionConfig = serde_json::from_value(serde_json::json!({
+            "challenge_threshold": 10,
+            "block_immediate": ["Proxy"],
+        }))
+        .unwrap();
+
+        assert_eq!(config.challenge_threshold, 10);
+        assert_eq!(config.block_immediate, vec![ThreatType::Proxy]);
+        // Unspecified fields keep their defaults
+        assert_eq!(config.monitor_threshold, 20);
+        assert_eq!(config.redirect_threshold, 75);
+        assert!(!config.monitor_mode);
+
+        let service = ResponseActionService::with_config(config);
+        let score = ThreatScore {
+            score: 15,
+            score_precise: 15.0,
+            findings: vec![],
+            ip: IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)),
+        };
+        assert_eq!(service.determine_action(&score), ResponseAction::Challenge);
+        assert_eq!(serde_json::to_value(ResponseAction::Challenge).unwrap(), "challenge");
+    }
+
+    #[test]
+    fn test_decide_reports_rule() {
+        let service = ResponseActionService::new();
+        let ip = IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1));
+        let score = |score| ThreatScore { score, score_precise: score as f32, findings: vec![], ip };
+
+        assert_eq!(
+            service.decide(&score(0)),
+            (ResponseAction::Allow, ActionDecision::ScoreBand { min_score: 0, max_score: 20 })
+        );
+        assert_eq!(
+            service.decide(&score(60)),
+            (ResponseAction::Challenge, ActionDecision::ScoreBand { min_score: 51, max_score: 75 })
+        );
+        assert_eq!(
+            service.decide(&score(100)),
+            (ResponseAction::Redirect, ActionDecision::ScoreBand { min_score: 76, max_score: 100 })
+        );
+
+        let tor = ThreatScore {
+            score: 10,
+            score_precise: 10.0,
+            findings: vec![crate::models::threat_score::ThreatFinding {
+                threat_type: ThreatType::TorExitNode,
+                description: "Tor exit node".to_string(),
+                weight: 1.0,
+            }],
+            ip,
+        };
+        assert_eq!(
+            service.decide(&tor),
+            (ResponseAction::Block, ActionDecision::BlockImmediate { threat_type: ThreatType::TorExitNode })
+        );
+
+        let json = serde_json::to_value(ActionDecision::ScoreBand { min_score: 0, max_score: 20 }).unwrap();
+        assert_eq!(json, serde_json::json!({ "rule": "score_band", "min_score": 0, "max_score": 20 }));
+    }
+
+    #[test]
+    fn test_decide_score_matches_decide() {
+        let service = ResponseActionService::new();
+        assert_eq!(
+            service.decide_score(60, &[]),
+            (ResponseAction::Challenge, ActionDecision::ScoreBand { min_score: 51, max_score: 75 })
+        );
+        assert_eq!(
+            service.decide_score(10, &[ThreatType::Proxy, ThreatType::TorExitNode]),
+            (ResponseAction::Block, ActionDecision::BlockImmediate { threat_type: ThreatType::TorExitNode })
+        );
+        // Types outside `block_immediate` leave it to the score
+        assert_eq!(service.decide_score(10, &[ThreatType::Proxy]).0, ResponseAction::Allow);
+
+        let monitor_override = MonitorOverride::default();
+        monitor_override.set(true);
+        let service = service.with_monitor_override(&monitor_override);
+        assert_eq!(service.decide_score(90, &[]), (ResponseAction::Monitor, ActionDecision::MonitorMode));
+    }
+
+    #[test]
+    fn test_shadow_action_in_monitor_mode() {
+        let ip = IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1));
+        let tor = ThreatScore {
+            score: 10,
+            score_precise: 10.0,
+            findings: vec![crate::models::threat_score::ThreatFinding {
+                threat_type: ThreatType::TorExitNode,
+                description: "Tor exit node".to_string(),
+                weight: 1.0,
+            }],
+            ip,
+        };
+        let high = ThreatScore { score: 90, score_precise: 90.0, findings: vec![], ip };
+
+        // Enforcing mode has no shadow verdict
+        let service = ResponseActionService::new();
+        assert_eq!(service.determine_action_with_shadow(&high), (ResponseAction::Redirect, None));
+
+        let monitor_service = ResponseActionService::with_config(ResponseActionConfig {
+            monitor_mode: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            monitor_service.determine_action_with_shadow(&tor),
+            (ResponseAction::Monitor, Some(ResponseAction::Block))
+        );
+        assert_eq!(
+            monitor_service.determine_action_with_shadow(&high),
+            (ResponseAction::Monitor, Some(ResponseAction::Redirect))
+        );
+        assert_eq!(
+            monitor_service.decide(&high),
+            (ResponseAction::Monitor, ActionDecision::MonitorMode)
+        );
+    }
+
+    #[test]
+    fn test_monitor_override_beats_config() {
+        let ip = IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1));
+        let high = ThreatScore { score: 90, score_precise: 90.0, findings: vec![], ip };
+        let monitor_override = MonitorOverride::default();
+        let service = ResponseActionService::new().with_monitor_override(&monitor_override);
+        assert_eq!(service.determine_action_with_shadow(&high), (ResponseAction::Redirect, None));
+
+        // Takes effect on services already built
+        assert!(!monitor_override.clone().set(true));
+        assert_eq!(
+            service.determine_action_with_shadow(&high),
+            (ResponseAction::Monitor, Some(ResponseAction::Redirect))
+        );
+
+        // Turning it off hands back to the configured mode
+        assert!(monitor_override.set(false));
+        assert_eq!(service.determine_action(&high), ResponseAction::Redirect);
+        let configured = ResponseActionService::with_config(ResponseActionConfig {
+            monitor_mode: true,
+            ..Default::default()
+        })
+        .with_monitor_override(&monitor_override);
+        assert_eq!(configured.determine_action(&high), ResponseAction::Monitor);
+    }
 }
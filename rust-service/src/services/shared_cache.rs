@@ -0,0 +1,380 @@
+//! A Redis cache shared by every replica, behind the in-process lookup cache.
+//!
+//! Lookups check the local cache, then Redis, and fill both on a miss. Keys
+//! carry a generation number: a purge increments it in Redis and publishes
+//! the new value, so every replica drops its local entries and stops reading
+//! the old keys, which then expire on their own. Redis only ever saves work;
+//! a failed or slow command counts as a miss, and after a failure the local
+//! cache is used alone for a few seconds before Redis is tried again.
+//!
+//! Challenge clearances and redeemed challenge tokens are shared through
+//! the same connection; see [`crate::services::challenge`].
+
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures_util::StreamExt;
+use parking_lot::Mutex;
+use redis::aio::MultiplexedConnection;
+use redis::{Client, Cmd, FromRedisValue};
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
+
+use crate::config::CacheSettings;
+use crate::handlers::LookupResponse;
+use crate::monitoring::SHARED_CACHE_ERRORS;
+use crate::services::lookup_service::LookupCache;
+
+/// How long Redis is left alone after a failed command, and how long the
+/// invalidation channel waits before resubscribing
+const RETRY_AFTER: Duration = Duration::from_secs(5);
+
+pub struct SharedCache {
+    client: Client,
+    /// Opened on first use and dropped after a failure
+    connection: tokio::sync::Mutex<Option<MultiplexedConnection>>,
+    prefix: String,
+    ttl_secs: u64,
+    timeout: Duration,
+    /// The generation last read from Redis; keys of any other are never read
+    generation: AtomicU64,
+    /// Whether `generation` is current, i.e. the invalidation channel is
+    /// subscribed. Until it is, lookups skip Redis.
+    synced: AtomicBool,
+    local: Arc<LookupCache>,
+    /// Set after a failure; Redis is skipped until then
+    retry_at: Mutex<Option<Instant>>,
+}
+
+/// The Redis URL is left out, since it may hold a password
+impl std::fmt::Debug for SharedCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedCache")
+            .field("prefix", &self.prefix)
+            .field("generation", &self.generation)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SharedCache {
+    /// A cache in front of `local`, if `cache.redis_url` is set. Nothing
+    /// connects until the first command.
+    pub fn from_settings(settings: &CacheSettings, local: Arc<LookupCache>) -> redis::RedisResult<Option<Self>> {
+        let Some(url) = &settings.redis_url else {
+            return Ok(None);
+        };
+        Ok(Some(Self {
+            client: Client::open(url.as_str())?,
+            connection: tokio::sync::Mutex::new(None),
+            prefix: settings.redis_key_prefix.clone(),
+            ttl_secs: settings.redis_ttl_secs,
+            timeout: Duration::from_millis(settings.redis_timeout_ms),
+            generation: AtomicU64::new(0),
+            synced: AtomicBool::new(false),
+            local,
+            retry_at: Mutex::new(None),
+        }))
+    }
+
+    /// The response another replica (or this one) cached for `ip` under
+    /// `profile`. Unreachable Redis and undecodable entries are misses.
+    pub async fn get(&self, profile: &str, ip: IpAddr) -> Option<LookupResponse> {
+        let key = self.lookup_key(profile, ip)?;
+        let value: Option<String> = self.run("get", redis::cmd("GET").arg(&key)).await.ok()?;
+        match serde_json::from_str(&value?) {
+            Ok(response) => Some(response),
+            Err(e) => {
+                debug!(key, error = %e, "Ignoring undecodable shared cache entry");
+                None
+            }
+        }
+    }
+
+    /// Cache `response` in Redis in the background, so the lookup does not
+    /// wait for the write
+    pub fn store(self: &Arc<Self>, profile: &str, ip: IpAddr, response: &LookupResponse) {
+        let Some(key) = self.lookup_key(profile, ip) else {
+            return;
+        };
+        let Ok(value) = serde_json::to_string(response) else {
+            return;
+        };
+        let cache = Arc::clone(self);
+        tokio::spawn(async move {
+            let command = redis::cmd("SET").arg(&key).arg(value).arg("EX").arg(cache.ttl_secs).clone();
+            let _: anyhow::Result<()> = cache.run("set", &command).await;
+        });
+    }
+
+    /// Set `key`, under the prefix, to `value` for `ttl_secs` unless it is
+    /// already set, returning whether it was. For state every replica must
+    /// agree on, such as redeemed challenge tokens.
+    pub async fn insert_new(&self, key: &str, value: &str, ttl_secs: u64) -> anyhow::Result<bool> {
+        self.check_available()?;
+        let command = redis::cmd("SET").arg(self.shared_key(key)).arg(value).arg("NX").arg("EX").arg(ttl_secs.max(1)).clone();
+        let reply: Option<String> = self.run("set", &command).await?;
+        Ok(reply.is_some())
+    }
+
+    /// The value set at `key` by [`SharedCache::insert_new`], on any replica
+    pub async fn value(&self, key: &str) -> anyhow::Result<Option<String>> {
+        self.check_available()?;
+        self.run("get", redis::cmd("GET").arg(self.shared_key(key))).await
+    }
+
+    /// Drop this replica's cached lookups and tell every other replica to do
+    /// the same. With Redis down only the local cache is purged, and other
+    /// replicas keep their entries until they expire.
+    pub async fn invalidate(&self) {
+        self.local.invalidate_all();
+        let Ok(generation) = self.run::<u64>("invalidate", redis::cmd("INCR").arg(self.generation_key())).await else {
+            return;
+        };
+        self.apply_generation(generation);
+        let publish = redis::cmd("PUBLISH").arg(self.channel()).arg(generation).clone();
+        let _: anyhow::Result<u64> = self.run("invalidate", &publish).await;
+    }
+
+    /// Follow invalidations published by other replicas, resubscribing
+    /// whenever the channel is lost
+    pub fn spawn_subscriber(self: &Arc<Self>) {
+        let cache = Arc::clone(self);
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = cache.follow_invalidations().await {
+                    warn!(error = %e, "Lost the shared cache invalidation channel");
+                    SHARED_CACHE_ERRORS.with_label_values(&["subscribe"]).inc();
+                }
+                // Invalidations published meanwhile would go unseen
+                cache.synced.store(false, Ordering::Relaxed);
+                tokio::time::sleep(RETRY_AFTER).await;
+            }
+        });
+    }
+
+    /// Purge every replica's cache after each feed update, so none keeps
+    /// serving verdicts from the ranges it replaced
+    pub fn spawn_tree_watch(self: &Arc<Self>, mut updates: watch::Receiver<u64>) {
+        let cache = Arc::clone(self);
+        tokio::spawn(async move {
+            while updates.changed().await.is_ok() {
+                cache.invalidate().await;
+            }
+        });
+    }
+
+    async fn follow_invalidations(&self) -> anyhow::Result<()> {
+        let mut pubsub = tokio::time::timeout(RETRY_AFTER, self.client.get_async_pubsub()).await??;
+        pubsub.subscribe(self.channel()).await?;
+        // Read after subscribing, so no invalidation can fall in between
+        let generation: Option<u64> = self.run("subscribe", redis::cmd("GET").arg(self.generation_key())).await?;
+        self.apply_generation(generation.unwrap_or(0));
+        self.synced.store(true, Ordering::Relaxed);
+        info!(generation = generation.unwrap_or(0), "Subscribed to shared cache invalidations");
+
+        let mut messages = pubsub.into_on_message();
+        while let Some(message) = messages.next().await {
+            match message.get_payload::<u64>() {
+                Ok(generation) => self.apply_generation(generation),
+                Err(e) => warn!(error = %e, "Ignoring malformed shared cache invalidation"),
+            }
+        }
+        anyhow::bail!("subscription closed")
+    }
+
+    /// Switch to `generation`, purging the local cache if it is a new one
+    fn apply_generation(&self, generation: u64) {
+        if self.generation.swap(generation, Ordering::Relaxed) != generation {
+            self.local.invalidate_all();
+        }
+    }
+
+    fn backing_off(&self) -> bool {
+        self.retry_at.lock().is_some_and(|at| Instant::now() < at)
+    }
+
+    fn check_available(&self) -> anyhow::Result<()> {
+        if self.backing_off() {
+            anyhow::bail!("skipped after a recent failure");
+        }
+        Ok(())
+    }
+
+    /// The key for a lookup, or `None` while Redis is skipped
+    fn lookup_key(&self, profile: &str, ip: IpAddr) -> Option<String> {
+        if self.backing_off() || !self.synced.load(Ordering::Relaxed) {
+            return None;
+        }
+        let generation = self.generation.load(Ordering::Relaxed);
+        Some(format!("{}:lookup:{}:{}:{}", self.prefix, generation, profile, ip))
+    }
+
+    fn shared_key(&self, key: &str) -> String {
+        format!("{}:{}", self.prefix, key)
+    }
+
+    fn generation_key(&self) -> String {
+        format!("{}:lookup:generation", self.prefix)
+    }
+
+    fn channel(&self) -> String {
+        format!("{}:lookup:invalidations", self.prefix)
+    }
+
+    /// Run `command`, connecting first if need be, within the timeout. A
+    /// failure is counted and starts the back-off.
+    async fn run<T: FromRedisValue>(&self, operation: &'static str, command: &Cmd) -> anyhow::Result<T> {
+        let outcome = tokio::time::timeout(self.timeout, async {
+            let mut connection = self.connection().await?;
+            command.query_async::<T>(&mut connection).await
+        })
+        .await;
+        let error = match outcome {
+            Ok(Ok(value)) => return Ok(value),
+            Ok(Err(e)) => anyhow::Error::from(e),
+            Err(_) => anyhow::anyhow!("no reply within {:?}", self.timeout),
+        };
+        warn!(operation, error = %error, "Shared cache unavailable, using the local cache alone for {:?}", RETRY_AFTER);
+        SHARED_CACHE_ERRORS.with_label_values(&[operation]).inc();
+        // A connect in progress replaces the connection anyway
+        if let Ok(mut connection) = self.connection.try_lock() {
+            *connection = None;
+        }
+        *self.retry_at.lock() = Some(Instant::now() + RETRY_AFTER);
+        Err(error)
+    }
+
+    async fn connection(&self) -> redis::RedisResult<MultiplexedConnection> {
+        let mut connection = self.connection.lock().await;
+        if let Some(connection) = &*connection {
+            return Ok(connection.clone());
+        }
+        let opened = self.client.get_multiplexed_async_connection().await?;
+        *connection = Some(opened.clone());
+        Ok(opened)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, redis::MockRedis};
+    use moka::sync::Cache;
+
+    const IP: &str = "5.2.2.2";
+
+    fn shared_cache(url: String) -> (Arc<SharedCache>, Arc<LookupCache>) {
+        let local = Arc::new(Cache::new(100));
+        let settings = CacheSettings { redis_url: Some(url), redis_timeout_ms: 500, ..CacheSettings::default() };
+        let cache = SharedCache::from_settings(&settings, Arc::clone(&local)).unwrap().unwrap();
+        (Arc::new(cache), local)
+    }
+
+    /// Subscribes `cache` and waits until it reads from Redis
+    async fn subscribed(cache: &Arc<SharedCache>) {
+        cache.spawn_subscriber();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !cache.synced.load(Ordering::Relaxed) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("never subscribed");
+    }
+
+    async fn response(ip: &str) -> LookupResponse {
+        let state = test_support::app_state_with_ranges(vec![test_support::range("5.2.2.0/24", crate::ip_lookup::IpCategory::Vpn)]);
+        crate::handlers::lookup_service(&state).lookup_ip(ip.parse().unwrap()).await.unwrap()
+    }
+
+    /// Retries `get` until the background `store` has landed
+    async fn eventually_get(cache: &SharedCache, ip: IpAddr) -> Option<LookupResponse> {
+        for _ in 0..100 {
+            if let Some(response) = cache.get("default", ip).await {
+                return Some(response);
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        None
+    }
+
+    #[tokio::test]
+    async fn test_replicas_share_lookups_and_invalidations() {
+        let redis = MockRedis::start().await;
+        let (first, first_local) = shared_cache(redis.url());
+        let (second, second_local) = shared_cache(redis.url());
+        subscribed(&first).await;
+        subscribed(&second).await;
+
+        let ip: IpAddr = IP.parse().unwrap();
+        let stored = response(IP).await;
+        first.store("default", ip, &stored);
+        let fetched = eventually_get(&second, ip).await.expect("not shared");
+        assert_eq!(serde_json::to_value(&fetched).unwrap(), serde_json::to_value(&stored).unwrap());
+        assert!(second.get("strict", ip).await.is_none());
+
+        second_local.insert((Arc::from("default"), ip), stored.clone());
+        first_local.insert((Arc::from("default"), ip), stored);
+        first.invalidate().await;
+        assert!(!first_local.contains_key(&(Arc::from("default"), ip)));
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while second_local.contains_key(&(Arc::from("default"), ip)) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the other replica kept its entry");
+        // Entries from before the purge are no longer read
+        assert!(second.get("default", ip).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_tree_updates_purge_every_replica() {
+        let redis = MockRedis::start().await;
+        let (first, _) = shared_cache(redis.url());
+        let (second, second_local) = shared_cache(redis.url());
+        subscribed(&first).await;
+        subscribed(&second).await;
+
+        let ip: IpAddr = IP.parse().unwrap();
+        second_local.insert((Arc::from("default"), ip), response(IP).await);
+        let (updates, receiver) = watch::channel(0);
+        first.spawn_tree_watch(receiver);
+        updates.send_replace(1);
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while second_local.contains_key(&(Arc::from("default"), ip)) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the tree update did not reach the other replica");
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_redis_degrades_to_the_local_cache() {
+        // Nothing listens on a port that was just released
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("redis://{}", closed.local_addr().unwrap());
+        drop(closed);
+        let (cache, local) = shared_cache(url);
+        // As if it had subscribed before Redis went away
+        cache.synced.store(true, Ordering::Relaxed);
+
+        let ip: IpAddr = IP.parse().unwrap();
+        let errors = SHARED_CACHE_ERRORS.with_label_values(&["get"]).get();
+        assert!(cache.get("default", ip).await.is_none());
+        assert_eq!(SHARED_CACHE_ERRORS.with_label_values(&["get"]).get(), errors + 1);
+        // Backing off, so the next lookup does not even try
+        assert!(cache.lookup_key("default", ip).is_none());
+        assert!(cache.get("default", ip).await.is_none());
+        assert_eq!(SHARED_CACHE_ERRORS.with_label_values(&["get"]).get(), errors + 1);
+
+        // Purges still clear this replica
+        local.insert((Arc::from("default"), ip), response(IP).await);
+        cache.invalidate().await;
+        assert!(!local.contains_key(&(Arc::from("default"), ip)));
+    }
+}
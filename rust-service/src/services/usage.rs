@@ -0,0 +1,484 @@
+//! Per-API-key request counts for billing.
+//!
+//! Authenticated requests are counted per caller and endpoint class in the
+//! current window. Every flush closes the window into a [`UsageReport`] with
+//! its own idempotency key and delivers the queued reports, oldest first.
+//! A report stays queued until its sink accepts it, so delivery is
+//! at-least-once: consumers dedupe on `idempotency_key`. The queue is
+//! bounded; while the sink is down past that bound the oldest reports are
+//! dropped and counted in [`UsageSnapshot::dropped_reports`].
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::time::MissedTickBehavior;
+use tracing::{info, warn};
+
+use crate::clients::web_api::{WebApiClient, WebApiError};
+
+#[derive(Debug, Error)]
+pub enum UsageError {
+    #[error("Failed to write usage report: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Failed to serialize usage report: {0}")]
+    Serialize(#[from] serde_json::Error),
+
+    #[error("Failed to report usage to the web API: {0}")]
+    WebApi(#[from] WebApiError),
+}
+
+/// Groups of endpoints that are billed alike
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EndpointClass {
+    Lookup,
+    ThreatScore,
+    Ranges,
+    Gate,
+    Admin,
+    Other,
+}
+
+impl EndpointClass {
+    /// Class of a request path
+    pub fn from_path(path: &str) -> Self {
+        // Versioned paths count the same as their unversioned aliases
+        let path = &match path.strip_prefix("/api/v1/") {
+            Some(rest) => format!("/api/{}", rest),
+            None => path.to_string(),
+        };
+        let under = |prefix: &str| {
+            path.strip_prefix(prefix)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        };
+
+        if under("/api/lookup") {
+            EndpointClass::Lookup
+        } else if under("/api/threat-score") || under("/api/simulate") {
+            EndpointClass::ThreatScore
+        } else if ["/api/tor", "/api/vpn", "/api/proxy", "/api/ranges", "/api/is_in_ranges"]
+            .iter()
+            .any(|prefix| under(prefix))
+        {
+            EndpointClass::Ranges
+        } else if under("/api/gate") {
+            EndpointClass::Gate
+        } else if under("/api/admin") {
+            EndpointClass::Admin
+        } else {
+            EndpointClass::Other
+        }
+    }
+}
+
+/// The key usage is recorded under: the caller's user ID, or a hash of the
+/// API key for keys without one (unlimited keys). Raw keys are never stored.
+pub fn usage_key(user_id: Option<&str>, api_key: &str) -> String {
+    match user_id {
+        Some(user_id) => user_id.to_string(),
+        None => {
+            let digest = Sha256::digest(api_key.as_bytes());
+            let hex: String = digest[..8].iter().map(|b| format!("{:02x}", b)).collect();
+            format!("key-{}", hex)
+        }
+    }
+}
+
+/// One caller's counts within a window
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyUsage {
+    pub key: String,
+    /// Unlimited keys are counted but not billed
+    pub unlimited: bool,
+    pub requests: BTreeMap<EndpointClass, u64>,
+    pub total: u64,
+}
+
+/// A closed window, as delivered to the sink
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageReport {
+    /// Stable across redeliveries of this window
+    pub idempotency_key: String,
+    pub window_start: DateTime<Utc>,
+    pub window_end: DateTime<Utc>,
+    pub keys: Vec<KeyUsage>,
+}
+
+/// Counts in the open window, for the admin endpoint
+#[derive(Debug, Clone, Serialize)]
+pub struct UsageSnapshot {
+    pub window_start: DateTime<Utc>,
+    pub keys: Vec<KeyUsage>,
+    /// Closed windows waiting for a successful delivery
+    pub pending_reports: usize,
+    /// Closed windows dropped undelivered because the queue was full
+    pub dropped_reports: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    unlimited: bool,
+    requests: BTreeMap<EndpointClass, u64>,
+}
+
+#[derive(Debug)]
+struct Window {
+    start: DateTime<Utc>,
+    counts: DashMap<String, Counts>,
+}
+
+impl Window {
+    fn open() -> Self {
+        Self {
+            start: Utc::now(),
+            counts: DashMap::new(),
+        }
+    }
+
+    fn keys(&self) -> Vec<KeyUsage> {
+        let mut keys: Vec<KeyUsage> = self
+            .counts
+            .iter()
+            .map(|entry| KeyUsage {
+                key: entry.key().clone(),
+                unlimited: entry.unlimited,
+                total: entry.requests.values().sum(),
+                requests: entry.requests.clone(),
+            })
+            .collect();
+        keys.sort_by(|a, b| a.key.cmp(&b.key));
+        keys
+    }
+}
+
+/// Where closed windows are delivered
+#[derive(Debug, Clone)]
+pub enum UsageSink {
+    /// Append one JSON line per report to `usage-YYYY-MM.jsonl` in this directory
+    File(PathBuf),
+    /// POST each report to the web API
+    WebApi(Arc<WebApiClient>),
+}
+
+impl UsageSink {
+    async fn deliver(&self, report: &UsageReport) -> Result<(), UsageError> {
+        match self {
+            UsageSink::File(dir) => {
+                let dir = dir.clone();
+                let report = report.clone();
+                tokio::task::spawn_blocking(move || append_report(&dir, &report))
+                    .await
+                    .map_err(std::io::Error::other)?
+            }
+            UsageSink::WebApi(client) => Ok(client.report_usage(report).await?),
+        }
+    }
+}
+
+fn append_report(dir: &std::path::Path, report: &UsageReport) -> Result<(), UsageError> {
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(format!("usage-{}.jsonl", report.window_start.format("%Y-%m")));
+    let mut line = serde_json::to_vec(report)?;
+    line.push(b'\n');
+
+    // A single write so a failed attempt leaves at most one partial line
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(&line)?;
+    file.sync_data()?;
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct UsageAccounting {
+    window: RwLock<Window>,
+    /// Closed windows, oldest first, not yet accepted by the sink
+    pending: Mutex<VecDeque<UsageReport>>,
+    /// Most reports `pending` holds
+    max_pending: usize,
+    dropped_reports: AtomicU64,
+    /// Distinguishes this process's idempotency keys from other replicas'
+    instance: String,
+    /// Number of windows closed so far
+    closed_windows: AtomicU64,
+}
+
+impl Default for UsageAccounting {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UsageAccounting {
+    pub fn new() -> Self {
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let seed = format!("{}:{}", std::process::id(), started.as_nanos());
+        let digest = Sha256::digest(seed.as_bytes());
+
+        Self {
+            window: RwLock::new(Window::open()),
+            pending: Mutex::new(VecDeque::new()),
+            max_pending: usize::MAX,
+            dropped_reports: AtomicU64::new(0),
+            instance: digest[..6].iter().map(|b| format!("{:02x}", b)).collect(),
+            closed_windows: AtomicU64::new(0),
+        }
+    }
+
+    /// Keep at most `max` undelivered reports, dropping the oldest beyond that
+    pub fn with_max_pending(mut self, max: usize) -> Self {
+        self.max_pending = max.max(1);
+        self
+    }
+
+    /// Count one request by `key` in the open window
+    pub fn record(&self, key: &str, class: EndpointClass, unlimited: bool) {
+        let window = self.window.read();
+        let mut counts = window.counts.entry(key.to_string()).or_default();
+        counts.unlimited |= unlimited;
+        *counts.requests.entry(class).or_insert(0) += 1;
+    }
+
+    /// Counts in the open window
+    pub fn snapshot(&self) -> UsageSnapshot {
+        let window = self.window.read();
+        UsageSnapshot {
+            window_start: window.start,
+            keys: window.keys(),
+            pending_reports: self.pending.lock().len(),
+            dropped_reports: self.dropped_reports.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Close the open window and queue it for delivery. Empty windows are
+    /// dropped.
+    fn close_window(&self) {
+        let next = Window::open();
+        let window_end = next.start;
+        let closed = std::mem::replace(&mut *self.window.write(), next);
+        if closed.counts.is_empty() {
+            return;
+        }
+
+        let sequence = self.closed_windows.fetch_add(1, Ordering::Relaxed);
+        let mut pending = self.pending.lock();
+        pending.push_back(UsageReport {
+            idempotency_key: format!("usage-{}-{}", self.instance, sequence),
+            window_start: closed.start,
+            window_end,
+            keys: closed.keys(),
+        });
+        while pending.len() > self.max_pending {
+            let Some(dropped) = pending.pop_front() else { break };
+            self.dropped_reports.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                idempotency_key = %dropped.idempotency_key,
+                window_start = %dropped.window_start,
+                keys = dropped.keys.len(),
+                "Usage queue full, dropping the oldest undelivered report"
+            );
+        }
+    }
+
+    /// Close the open window and deliver every queued report in order.
+    /// Stops at the first failure; that report and the ones after it are
+    /// retried, unchanged, on the next flush. Returns the number delivered.
+    pub async fn flush(&self, sink: &UsageSink) -> Result<usize, UsageError> {
+        self.close_window();
+
+        let mut delivered = 0;
+        loop {
+            let Some(report) = self.pending.lock().front().cloned() else {
+                return Ok(delivered);
+            };
+            sink.deliver(&report).await?;
+            self.pending.lock().pop_front();
+            delivered += 1;
+        }
+    }
+}
+
+/// Flush `accounting` to `sink` every `interval`
+pub fn spawn_flush_loop(accounting: Arc<UsageAccounting>, sink: UsageSink, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // The first tick completes immediately
+        ticker.tick().await;
+
+        loop {
+            ticker.tick().await;
+            match accounting.flush(&sink).await {
+                Ok(0) => {}
+                Ok(delivered) => info!(delivered, "Flushed usage reports"),
+                Err(e) => warn!(
+                    error = %e,
+                    pending = accounting.snapshot().pending_reports,
+                    "Usage flush failed, retrying next interval"
+                ),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_reports(dir: &std::path::Path) -> Vec<UsageReport> {
+        let mut reports = Vec::new();
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let content = std::fs::read_to_string(entry.unwrap().path()).unwrap();
+            for line in content.lines() {
+                reports.push(serde_json::from_str(line).unwrap());
+            }
+        }
+        reports
+    }
+
+    #[test]
+    fn test_endpoint_class_from_path() {
+        assert_eq!(EndpointClass::from_path("/api/lookup/8.8.8.8"), EndpointClass::Lookup);
+        assert_eq!(EndpointClass::from_path("/api/lookup/self"), EndpointClass::Lookup);
+        assert_eq!(EndpointClass::from_path("/api/threat-score/1.1.1.1"), EndpointClass::ThreatScore);
+        assert_eq!(EndpointClass::from_path("/api/simulate"), EndpointClass::ThreatScore);
+        assert_eq!(EndpointClass::from_path("/api/vpn/10.0.0.0/8"), EndpointClass::Ranges);
+        assert_eq!(EndpointClass::from_path("/api/is_in_ranges"), EndpointClass::Ranges);
+        assert_eq!(EndpointClass::from_path("/api/gate/1.1.1.1"), EndpointClass::Gate);
+        assert_eq!(EndpointClass::from_path("/api/admin/usage"), EndpointClass::Admin);
+        assert_eq!(EndpointClass::from_path("/api/v1/lookup/8.8.8.8"), EndpointClass::Lookup);
+        assert_eq!(EndpointClass::from_path("/api/v1/admin/usage"), EndpointClass::Admin);
+        assert_eq!(EndpointClass::from_path("/api/lookups"), EndpointClass::Other);
+        assert_eq!(EndpointClass::from_path("/health"), EndpointClass::Other);
+    }
+
+    #[test]
+    fn test_usage_key_hashes_keys_without_user() {
+        assert_eq!(usage_key(Some("user-1"), "secret"), "user-1");
+
+        let key = usage_key(None, "secret");
+        assert!(key.starts_with("key-"));
+        assert!(!key.contains("secret"));
+        assert_eq!(key, usage_key(None, "secret"));
+        assert_ne!(key, usage_key(None, "other"));
+    }
+
+    #[test]
+    fn test_record_aggregates_per_key_and_class() {
+        let usage = UsageAccounting::new();
+        usage.record("user-1", EndpointClass::Lookup, false);
+        usage.record("user-1", EndpointClass::Lookup, false);
+        usage.record("user-1", EndpointClass::Gate, false);
+        usage.record("key-ab", EndpointClass::Lookup, true);
+
+        let snapshot = usage.snapshot();
+        assert_eq!(snapshot.pending_reports, 0);
+        assert_eq!(snapshot.keys.len(), 2);
+
+        let unlimited = &snapshot.keys[0];
+        assert_eq!(unlimited.key, "key-ab");
+        assert!(unlimited.unlimited);
+        assert_eq!(unlimited.total, 1);
+
+        let user = &snapshot.keys[1];
+        assert_eq!(user.key, "user-1");
+        assert!(!user.unlimited);
+        assert_eq!(user.requests[&EndpointClass::Lookup], 2);
+        assert_eq!(user.requests[&EndpointClass::Gate], 1);
+        assert_eq!(user.total, 3);
+    }
+
+    #[tokio::test]
+    async fn test_flush_writes_one_line_per_window() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = UsageSink::File(dir.path().join("usage"));
+        let usage = UsageAccounting::new();
+
+        // Nothing counted, nothing written
+        assert_eq!(usage.flush(&sink).await.unwrap(), 0);
+
+        usage.record("user-1", EndpointClass::Lookup, false);
+        usage.record("key-ab", EndpointClass::Ranges, true);
+        assert_eq!(usage.flush(&sink).await.unwrap(), 1);
+        assert!(usage.snapshot().keys.is_empty());
+
+        usage.record("user-1", EndpointClass::Lookup, false);
+        assert_eq!(usage.flush(&sink).await.unwrap(), 1);
+
+        let reports = read_reports(&dir.path().join("usage"));
+        assert_eq!(reports.len(), 2);
+        assert_ne!(reports[0].idempotency_key, reports[1].idempotency_key);
+        assert!(reports[0].window_end <= reports[1].window_start);
+
+        let json = serde_json::to_value(&reports[0]).unwrap();
+        assert_eq!(json["keys"][0]["key"], "key-ab");
+        assert_eq!(json["keys"][0]["unlimited"], true);
+        assert_eq!(json["keys"][0]["requests"]["ranges"], 1);
+        assert_eq!(json["keys"][1]["requests"]["lookup"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_pending_reports_are_capped() {
+        let dir = tempfile::tempdir().unwrap();
+        let usage_dir = dir.path().join("usage");
+        std::fs::write(&usage_dir, "").unwrap();
+        let sink = UsageSink::File(usage_dir.clone());
+        let usage = UsageAccounting::new().with_max_pending(2);
+
+        for user in ["user-1", "user-2", "user-3"] {
+            usage.record(user, EndpointClass::Lookup, false);
+            assert!(usage.flush(&sink).await.is_err());
+        }
+        let snapshot = usage.snapshot();
+        assert_eq!(snapshot.pending_reports, 2);
+        assert_eq!(snapshot.dropped_reports, 1);
+
+        // The newest windows survive
+        std::fs::remove_file(&usage_dir).unwrap();
+        assert_eq!(usage.flush(&sink).await.unwrap(), 2);
+        let reports = read_reports(&usage_dir);
+        let keys: Vec<&str> = reports.iter().map(|report| report.keys[0].key.as_str()).collect();
+        assert_eq!(keys, vec!["user-2", "user-3"]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_flush_is_retried_without_double_counting() {
+        let dir = tempfile::tempdir().unwrap();
+        let usage_dir = dir.path().join("usage");
+        // A file where the directory should be makes every write fail
+        std::fs::write(&usage_dir, "").unwrap();
+        let sink = UsageSink::File(usage_dir.clone());
+        let usage = UsageAccounting::new();
+
+        usage.record("user-1", EndpointClass::Lookup, false);
+        assert!(usage.flush(&sink).await.is_err());
+        usage.record("user-1", EndpointClass::Lookup, false);
+        assert!(usage.flush(&sink).await.is_err());
+        assert_eq!(usage.snapshot().pending_reports, 2);
+
+        std::fs::remove_file(&usage_dir).unwrap();
+        assert_eq!(usage.flush(&sink).await.unwrap(), 2);
+        assert_eq!(usage.snapshot().pending_reports, 0);
+        assert_eq!(usage.flush(&sink).await.unwrap(), 0);
+
+        // Each window is delivered once, with one request each
+        let reports = read_reports(&usage_dir);
+        assert_eq!(reports.len(), 2);
+        for report in &reports {
+            assert_eq!(report.keys.len(), 1);
+            assert_eq!(report.keys[0].total, 1);
+        }
+        assert_ne!(reports[0].idempotency_key, reports[1].idempotency_key);
+    }
+}
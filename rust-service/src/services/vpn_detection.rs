@@ -1,43 +1,57 @@
 use crate::config::Settings;
+use crate::errors::validation::canonical_ip;
+use crate::utils::redact;
 use ipnetwork::IpNetwork;
-use once_cell::sync::Lazy;
-use std::fs::File;
-use std::io::{self, BufRead};
+use crate::ip_lookup::loader::load_feed_file;
+use std::fmt;
+use std::io;
 use std::net::IpAddr;
 use std::path::Path;
+use std::time::Instant;
 use tracing::{debug, info, warn};
 
-static VPN_DETECTOR: Lazy<VpnDetector> = Lazy::new(|| {
-    let settings = Settings::new().expect("Failed to load settings");
-    VpnDetector::new(&settings).expect("Failed to initialize VpnDetector")
-});
-
 /// Detects if an IP address belongs to a known VPN or datacenter network.
 pub struct VpnDetector {
     networks: Vec<IpNetwork>,
 }
 
+impl fmt::Debug for VpnDetector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VpnDetector").field("networks", &self.networks.len()).finish()
+    }
+}
+
 impl VpnDetector {
-    /// Creates a new VpnDetector instance and loads networks from the configured path.
-    pub fn new(settings: &Settings) -> io::Result<Self> {
+    /// Loads the networks from the configured path. Run once at startup; the
+    /// handlers share the result through `AppState`.
+    pub async fn load(settings: &Settings) -> io::Result<Self> {
         let db_path = settings.resolve_vpn_detector_db_path()?;
         info!("Loading VPN detection database from: {}", db_path.display());
-        let networks = Self::load_networks(&db_path)?;
-        info!("Loaded {} VPN/datacenter networks", networks.len());
-        Ok(Self { networks })
+        let started = Instant::now();
+        let networks = load_feed_file(db_path, Self::parse_networks).await?;
+        info!(
+            networks = networks.len(),
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "Loaded VPN/datacenter networks"
+        );
+        Ok(Self::from_networks(networks))
+    }
+
+    /// Creates a detector over an in-memory network list.
+    pub fn from_networks(mut networks: Vec<IpNetwork>) -> Self {
+        // Sort networks by prefix length (most specific first) for faster lookups
+        networks.sort_by(|a, b| b.prefix().cmp(&a.prefix()));
+        Self { networks }
     }
 
-    fn load_networks<P: AsRef<Path>>(path: P) -> io::Result<Vec<IpNetwork>> {
-        debug!("Loading networks from: {}", path.as_ref().display());
-        let file = File::open(path)?;
-        let reader = io::BufReader::new(file);
+    fn parse_networks(content: &str, path: &Path) -> io::Result<Vec<IpNetwork>> {
+        debug!("Parsing networks from: {}", path.display());
         let mut networks = Vec::new();
         let mut line_count = 0;
         let mut invalid_count = 0;
 
-        for line in reader.lines() {
+        for line in content.lines() {
             line_count += 1;
-            let line = line?;
             let line = line.trim();
             if line.is_empty() || line.starts_with('#') {
                 continue;
@@ -51,33 +65,40 @@ impl VpnDetector {
             }
         }
 
-        // Sort networks by prefix length (most specific first) for faster lookups
-        networks.sort_by(|a, b| b.prefix().cmp(&a.prefix()));
-        
         if invalid_count > 0 {
             warn!("Failed to parse {}/{} network entries", invalid_count, line_count);
         }
+        if networks.is_empty() && !content.trim().is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("no networks parsed from {} ({} invalid entries)", path.display(), invalid_count),
+            ));
+        }
         debug!("Successfully loaded {} networks ({} invalid entries)", networks.len(), invalid_count);
         
         Ok(networks)
     }
 
     /// Checks if the given IP address belongs to a known VPN or datacenter network.
+    /// IPv4-mapped IPv6 addresses are checked as IPv4.
     pub fn is_vpn_or_datacenter(&self, ip: IpAddr) -> bool {
+        let ip = canonical_ip(ip);
         self.networks.iter().any(|network| network.contains(ip))
     }
     
     /// Checks if any IP in the given network range belongs to a known VPN or datacenter network.
-    pub fn is_range_vpn_or_datacenter(&self, cidr: &str) -> Option<bool> {
+    ///
+    /// A scan still running at `deadline` stops early and reports `false`.
+    pub fn is_range_vpn_or_datacenter(&self, cidr: &str, deadline: Instant) -> Option<bool> {
         let input_network = match cidr.parse::<IpNetwork>() {
             Ok(net) => net,
             Err(_) => {
-                warn!("Failed to parse network: {}", cidr);
+                warn!("Failed to parse network: {}", redact::text(cidr));
                 return None;
             }
         };
 
-        debug!("Checking network: {}", input_network);
+        debug!("Checking network: {}", redact::text(input_network.to_string()));
         
         // 1. Check if the input network is exactly in our database
         if self.networks.contains(&input_network) {
@@ -101,27 +122,30 @@ impl VpnDetector {
             let ip_count = 2u32.pow(32 - prefix as u32);
             info!(
                 "Performing full IP scan for {}/{} ({} IPs)",
-                input_network.ip(),
+                redact::ip(input_network.ip()),
                 prefix,
                 ip_count
             );
             
             for ip in input_network.iter() {
+                if Instant::now() >= deadline {
+                    warn!(
+                        "VPN scan of {}/{} timed out; reporting no VPN",
+                        redact::ip(input_network.ip()),
+                        prefix
+                    );
+                    return Some(false);
+                }
                 if self.is_vpn_or_datacenter(ip) {
-                    debug!("Found VPN IP in range: {}", ip);
+                    debug!("Found VPN IP in range: {}", redact::ip(ip));
                     return Some(true);
                 }
             }
         }
     
-        debug!("No VPN found in network {}", input_network);
+        debug!("No VPN found in network {}", redact::text(input_network.to_string()));
         Some(false)
     }
-    
-    /// Returns a reference to the global VpnDetector instance.
-    pub fn get() -> &'static VpnDetector {
-        &VPN_DETECTOR
-    }
 }
 
 #[cfg(test)]
@@ -129,9 +153,14 @@ mod tests {
     use super::*;
     use std::time::Instant;
 
+    fn fixture_detector() -> VpnDetector {
+        let networks = ["10.0.0.0/8", "192.0.2.0/24"];
+        VpnDetector::from_networks(networks.iter().map(|n| n.parse().unwrap()).collect())
+    }
+
     #[test]
     fn test_performance() {
-        let detector = VpnDetector::get();
+        let detector = fixture_detector();
         let start = Instant::now();
         detector.is_vpn_or_datacenter("1.1.1.1".parse().unwrap());
         let duration = start.elapsed();
@@ -140,13 +169,39 @@ mod tests {
         assert!(duration < std::time::Duration::from_millis(10), "Lookup took too long");
     }
 
+    #[tokio::test]
+    async fn test_load_handles_encodings() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vpn.txt");
+        let mut settings = Settings::default();
+        settings.vpn_detector.db_path = path.clone();
+
+        std::fs::write(&path, b"\xEF\xBB\xBF10.0.0.0/8\r\n192.168.0.0/16\r\n").unwrap();
+        let detector = VpnDetector::load(&settings).await.unwrap();
+        assert_eq!(detector.networks.len(), 2);
+        assert!(detector.is_vpn_or_datacenter("192.168.4.4".parse().unwrap()));
+
+        let utf16: Vec<u8> = [0xFF, 0xFE].into_iter().chain("10.0.0.0/8\n".encode_utf16().flat_map(u16::to_le_bytes)).collect();
+        std::fs::write(&path, utf16).unwrap();
+        assert_eq!(VpnDetector::load(&settings).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        std::fs::write(&path, "<html>rate limited</html>\n").unwrap();
+        assert_eq!(VpnDetector::load(&settings).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        std::fs::remove_file(&path).unwrap();
+        let error = VpnDetector::load(&settings).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(error.to_string().contains("vpn.txt"), "{}", error);
+    }
+
     #[test]
     fn test_vpn_detection() {
-        let detector = VpnDetector::get();
+        let detector = fixture_detector();
         
         // Test cases: (input, expected_result)
         let test_cases = [
-            // Known VPN IPs (replace with actual test cases)
+            ("10.1.2.3", true),
+            ("192.0.2.200", true),
             ("1.1.1.1", false),
             ("8.8.8.8", false),
         ];
@@ -177,7 +232,7 @@ mod tests {
                 debug!("Exact network in database: {}", is_in_db);
                 
                 // Check if any IP in the network is in our database
-                let result = detector.is_range_vpn_or_datacenter(input);
+                let result = detector.is_range_vpn_or_datacenter(input, Instant::now() + std::time::Duration::from_secs(5));
                 info!("Network range check - Result: {:?}, Expected: {}", result, expected);
                 
                 if let Some(found) = result {
@@ -0,0 +1,245 @@
+//! Tracing subscriber setup.
+//!
+//! Logs always go to stdout through the `fmt` layer. With the `otel` cargo
+//! feature and `telemetry.otlp_endpoint` set, spans are also exported to an
+//! OTLP collector, and W3C `traceparent` headers are read from incoming
+//! requests and forwarded on calls to the web API.
+//!
+//! The log filter sits behind a reload layer so a configuration reload can
+//! replace it without reinstalling the subscriber.
+
+use axum::http::{Request, Uri};
+use tracing::Span;
+use tracing_subscriber::{
+    filter::ParseError, fmt::format::FmtSpan, layer::SubscriberExt, reload, util::SubscriberInitExt,
+    EnvFilter, Registry,
+};
+
+use crate::config::TelemetrySettings;
+use crate::utils::redact;
+
+/// Replaces the installed log filter
+pub type LogFilterHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Flushes exported spans when dropped; keep it alive for the whole run
+pub struct TelemetryGuard {
+    #[cfg(feature = "otel")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+    log_filter: LogFilterHandle,
+}
+
+impl TelemetryGuard {
+    pub fn log_filter(&self) -> LogFilterHandle {
+        self.log_filter.clone()
+    }
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = self.provider.take() {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Failed to flush OpenTelemetry spans: {}", e);
+            }
+        }
+    }
+}
+
+/// `telemetry.log_filter` when set, otherwise `RUST_LOG` plus the built-in
+/// directives
+pub fn log_filter(settings: &TelemetrySettings) -> Result<EnvFilter, ParseError> {
+    if let Some(directives) = &settings.log_filter {
+        return EnvFilter::try_new(directives);
+    }
+    Ok(EnvFilter::from_default_env()
+        .add_directive("debug".parse()?)
+        .add_directive("hyper=info".parse()?)
+        .add_directive("tower_http=info".parse()?))
+}
+
+/// Install the global subscriber
+pub fn init(settings: &TelemetrySettings) -> Result<TelemetryGuard, Box<dyn std::error::Error>> {
+    let (filter, log_filter) = reload::Layer::new(log_filter(settings)?);
+
+    // Configure logging format based on environment
+    let fmt_layer = tracing_subscriber::fmt::layer()
+        .with_target(true)
+        .with_thread_ids(true)
+        // Span close events carry each handler's latency
+        .with_span_events(FmtSpan::CLOSE);
+
+    #[cfg(feature = "otel")]
+    {
+        let (otel_layer, provider) = match settings.otlp_endpoint.as_deref() {
+            Some(endpoint) => {
+                let (layer, provider) = otel::layer(endpoint, &settings.service_name)?;
+                (Some(layer), Some(provider))
+            }
+            None => (None, None),
+        };
+
+        tracing_subscriber::registry()
+            .with(filter)
+            .with(fmt_layer)
+            .with(otel_layer)
+            .init();
+
+        if let Some(endpoint) = &settings.otlp_endpoint {
+            tracing::info!(endpoint = %endpoint, "Exporting spans over OTLP");
+        }
+        Ok(TelemetryGuard { provider, log_filter })
+    }
+
+    #[cfg(not(feature = "otel"))]
+    {
+        tracing_subscriber::registry().with(filter).with(fmt_layer).init();
+
+        if settings.otlp_endpoint.is_some() {
+            tracing::warn!("telemetry.otlp_endpoint is set but the `otel` feature is not compiled in");
+        }
+        Ok(TelemetryGuard { log_filter })
+    }
+}
+
+/// Root span for an HTTP request, parented to the caller's trace if it sent one
+pub fn http_span<B>(request: &Request<B>) -> Span {
+    let span = tracing::debug_span!(
+        "request",
+        method = %request.method(),
+        uri = %redacted_uri(request.uri()),
+        version = ?request.version(),
+    );
+    #[cfg(feature = "otel")]
+    otel::set_parent(&span, request.headers());
+    span
+}
+
+/// The request URI with any addresses in the path redacted
+fn redacted_uri(uri: &Uri) -> String {
+    match uri.query() {
+        Some(query) => format!("{}?{}", redact::path(uri.path()), query),
+        None => redact::path(uri.path()),
+    }
+}
+
+/// Trace context headers for an outgoing request from the current span
+pub fn trace_context_headers() -> Vec<(String, String)> {
+    #[cfg(feature = "otel")]
+    {
+        otel::current_context_headers()
+    }
+    #[cfg(not(feature = "otel"))]
+    {
+        Vec::new()
+    }
+}
+
+#[cfg(feature = "otel")]
+mod otel {
+    use super::*;
+    use axum::http::HeaderMap;
+    use opentelemetry::propagation::{Extractor, Injector};
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry::{global, KeyValue};
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::SdkTracerProvider, Resource};
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    pub(super) fn layer<S>(
+        endpoint: &str,
+        service_name: &str,
+    ) -> Result<
+        (tracing_opentelemetry::OpenTelemetryLayer<S, opentelemetry_sdk::trace::Tracer>, SdkTracerProvider),
+        Box<dyn std::error::Error>,
+    >
+    where
+        S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+    {
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .build()?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(service_name.to_string())
+                    .with_attribute(KeyValue::new("service.version", env!("CARGO_PKG_VERSION")))
+                    .build(),
+            )
+            .build();
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
+        let tracer = provider.tracer("infralock");
+        Ok((tracing_opentelemetry::layer().with_tracer(tracer), provider))
+    }
+
+    struct HeaderExtractor<'a>(&'a HeaderMap);
+
+    impl Extractor for HeaderExtractor<'_> {
+        fn get(&self, key: &str) -> Option<&str> {
+            self.0.get(key).and_then(|value| value.to_str().ok())
+        }
+
+        fn keys(&self) -> Vec<&str> {
+            self.0.keys().map(|key| key.as_str()).collect()
+        }
+    }
+
+    struct VecInjector(Vec<(String, String)>);
+
+    impl Injector for VecInjector {
+        fn set(&mut self, key: &str, value: String) {
+            self.0.push((key.to_string(), value));
+        }
+    }
+
+    pub(super) fn set_parent(span: &Span, headers: &HeaderMap) {
+        let parent = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(headers))
+        });
+        if let Err(e) = span.set_parent(parent) {
+            tracing::debug!("Ignoring incoming trace context: {:?}", e);
+        }
+    }
+
+    pub(super) fn current_context_headers() -> Vec<(String, String)> {
+        let context = Span::current().context();
+        let mut injector = VecInjector(Vec::new());
+        global::get_text_map_propagator(|propagator| {
+            propagator.inject_context(&context, &mut injector)
+        });
+        injector.0
+    }
+}
+
+#[cfg(all(test, feature = "otel"))]
+mod tests {
+    use super::*;
+    use opentelemetry::global;
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::SdkTracerProvider};
+
+    #[test]
+    fn test_trace_context_propagates_through_request_span() {
+        let provider = SdkTracerProvider::builder().build();
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let request = Request::builder()
+                .header("traceparent", "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01")
+                .body(())
+                .unwrap();
+            let span = http_span(&request);
+            let _entered = span.enter();
+
+            let headers = trace_context_headers();
+            let (_, traceparent) = headers.iter().find(|(key, _)| key == "traceparent").unwrap();
+            // Same trace, with our span as the new parent
+            assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+            assert!(!traceparent.contains("00f067aa0ba902b7"));
+        });
+    }
+}
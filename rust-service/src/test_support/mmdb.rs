@@ -0,0 +1,287 @@
+//! Minimal MaxMind DB writer used to build fixture databases for tests.
+//!
+//! Only the subset of the format needed by our readers is supported: an IPv6
+//! search tree with 32-bit records, and the string/double/uint/bool/map/array
+//! data types. IPv4 networks are stored in the IPv4-compatible `::/96` subtree,
+//! which is where the maxminddb reader looks them up.
+
+use std::collections::BTreeMap;
+
+use ip_network::IpNetwork;
+
+const METADATA_MARKER: &[u8] = b"\xAB\xCD\xEFMaxMind.com";
+const DATA_SECTION_SEPARATOR: [u8; 16] = [0; 16];
+
+/// A value in the MaxMind DB data section.
+#[derive(Debug, Clone)]
+pub enum MmdbValue {
+    String(String),
+    Double(f64),
+    Uint16(u16),
+    Uint32(u32),
+    Uint64(u64),
+    Bool(bool),
+    Map(BTreeMap<String, MmdbValue>),
+    Array(Vec<MmdbValue>),
+}
+
+impl MmdbValue {
+    /// Build a map value from `(key, value)` pairs.
+    pub fn map<const N: usize>(entries: [(&str, MmdbValue); N]) -> Self {
+        MmdbValue::Map(entries.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+    }
+
+    /// Build a string value.
+    pub fn string(value: &str) -> Self {
+        MmdbValue::String(value.to_string())
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            MmdbValue::String(s) => {
+                write_control(out, 2, s.len());
+                out.extend_from_slice(s.as_bytes());
+            }
+            MmdbValue::Double(d) => {
+                write_control(out, 3, 8);
+                out.extend_from_slice(&d.to_be_bytes());
+            }
+            MmdbValue::Uint16(v) => write_uint(out, 5, *v as u64),
+            MmdbValue::Uint32(v) => write_uint(out, 6, *v as u64),
+            MmdbValue::Uint64(v) => write_uint(out, 9, *v),
+            MmdbValue::Bool(b) => write_control(out, 14, *b as usize),
+            MmdbValue::Map(entries) => {
+                write_control(out, 7, entries.len());
+                for (key, value) in entries {
+                    MmdbValue::String(key.clone()).encode(out);
+                    value.encode(out);
+                }
+            }
+            MmdbValue::Array(items) => {
+                write_control(out, 11, items.len());
+                for item in items {
+                    item.encode(out);
+                }
+            }
+        }
+    }
+}
+
+/// Write a control byte (plus extended type and size bytes) for `type_num`.
+fn write_control(out: &mut Vec<u8>, type_num: u8, size: usize) {
+    let (size_marker, size_bytes): (u8, Vec<u8>) = if size < 29 {
+        (size as u8, Vec::new())
+    } else if size < 29 + 256 {
+        (29, vec![(size - 29) as u8])
+    } else if size < 285 + 65_536 {
+        (30, ((size - 285) as u16).to_be_bytes().to_vec())
+    } else {
+        (31, ((size - 65_821) as u32).to_be_bytes()[1..].to_vec())
+    };
+
+    if type_num <= 7 {
+        out.push((type_num << 5) | size_marker);
+    } else {
+        out.push(size_marker);
+        out.push(type_num - 7);
+    }
+    out.extend_from_slice(&size_bytes);
+}
+
+/// Write an unsigned integer using the minimal number of big-endian bytes.
+fn write_uint(out: &mut Vec<u8>, type_num: u8, value: u64) {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&b| b == 0).count();
+    write_control(out, type_num, bytes.len() - skip);
+    out.extend_from_slice(&bytes[skip..]);
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Record {
+    Empty,
+    Node(u32),
+    Data(usize),
+}
+
+/// Builds an in-memory MaxMind DB file.
+#[derive(Debug)]
+pub struct MmdbWriter {
+    database_type: String,
+    nodes: Vec<[Record; 2]>,
+    data: Vec<u8>,
+}
+
+impl MmdbWriter {
+    /// Create a writer for a database of the given type (e.g. `GeoLite2-City`).
+    pub fn new(database_type: &str) -> Self {
+        Self {
+            database_type: database_type.to_string(),
+            nodes: vec![[Record::Empty, Record::Empty]],
+            data: Vec::new(),
+        }
+    }
+
+    /// Insert a network (e.g. `"203.0.113.0/24"`) mapped to `value`.
+    pub fn insert(&mut self, network: &str, value: MmdbValue) -> &mut Self {
+        let network: IpNetwork = network.parse().expect("invalid fixture network");
+        let (bits, prefix_len) = match network {
+            IpNetwork::V4(net) => (
+                u32::from(net.network_address()) as u128,
+                net.netmask() as usize + 96,
+            ),
+            IpNetwork::V6(net) => (u128::from(net.network_address()), net.netmask() as usize),
+        };
+        assert!(prefix_len > 0, "fixture networks must have a non-zero prefix");
+
+        let offset = self.data.len();
+        value.encode(&mut self.data);
+
+        let mut node = 0usize;
+        for i in 0..prefix_len {
+            let bit = ((bits >> (127 - i)) & 1) as usize;
+            if i == prefix_len - 1 {
+                self.nodes[node][bit] = Record::Data(offset);
+                break;
+            }
+            node = match self.nodes[node][bit] {
+                Record::Node(next) => next as usize,
+                existing => {
+                    // Split an empty or data record into a new node that keeps
+                    // the existing value for the rest of the subtree.
+                    let fill = match existing {
+                        Record::Data(_) => existing,
+                        _ => Record::Empty,
+                    };
+                    self.nodes.push([fill, fill]);
+                    let next = self.nodes.len() - 1;
+                    self.nodes[node][bit] = Record::Node(next as u32);
+                    next
+                }
+            };
+        }
+        self
+    }
+
+    /// Serialize the database to bytes readable by `maxminddb::Reader::from_source`.
+    pub fn build(&self) -> Vec<u8> {
+        let node_count = self.nodes.len() as u32;
+        let mut out = Vec::with_capacity(self.nodes.len() * 8 + self.data.len() + 256);
+
+        for node in &self.nodes {
+            for record in node {
+                let value = match *record {
+                    Record::Empty => node_count,
+                    Record::Node(next) => next,
+                    Record::Data(offset) => node_count + 16 + offset as u32,
+                };
+                out.extend_from_slice(&value.to_be_bytes());
+            }
+        }
+
+        out.extend_from_slice(&DATA_SECTION_SEPARATOR);
+        out.extend_from_slice(&self.data);
+        out.extend_from_slice(METADATA_MARKER);
+
+        MmdbValue::map([
+            ("binary_format_major_version", MmdbValue::Uint16(2)),
+            ("binary_format_minor_version", MmdbValue::Uint16(0)),
+            ("build_epoch", MmdbValue::Uint64(1_700_000_000)),
+            ("database_type", MmdbValue::string(&self.database_type)),
+            ("description", MmdbValue::map([("en", MmdbValue::string("InfraLock test fixture"))])),
+            ("ip_version", MmdbValue::Uint16(6)),
+            ("languages", MmdbValue::Array(vec![MmdbValue::string("en")])),
+            ("node_count", MmdbValue::Uint32(node_count)),
+            ("record_size", MmdbValue::Uint16(32)),
+        ])
+        .encode(&mut out);
+
+        out
+    }
+}
+
+/// Addresses present in [`city_fixture`] / [`asn_fixture`].
+pub const FIXTURE_US_IP: &str = "8.8.8.8";
+pub const FIXTURE_DE_IP: &str = "2a00:1450:4001::1";
+
+/// A tiny City database with one IPv4 and one IPv6 network.
+pub fn city_fixture() -> maxminddb::Reader<Vec<u8>> {
+    let mut writer = MmdbWriter::new("GeoLite2-City");
+    writer.insert(
+        "8.8.8.0/24",
+        MmdbValue::map([
+            ("city", MmdbValue::map([("names", MmdbValue::map([("en", MmdbValue::string("Mountain View"))]))])),
+            (
+                "country",
+                MmdbValue::map([
+                    ("iso_code", MmdbValue::string("US")),
+                    ("names", MmdbValue::map([("en", MmdbValue::string("United States"))])),
+                ]),
+            ),
+            (
+                "location",
+                MmdbValue::map([
+                    ("latitude", MmdbValue::Double(37.386)),
+                    ("longitude", MmdbValue::Double(-122.0838)),
+                ]),
+            ),
+            ("traits", MmdbValue::map([("is_anycast", MmdbValue::Bool(true))])),
+        ]),
+    );
+    writer.insert(
+        "2a00:1450::/32",
+        MmdbValue::map([(
+            "country",
+            MmdbValue::map([
+                ("iso_code", MmdbValue::string("DE")),
+                ("names", MmdbValue::map([("en", MmdbValue::string("Germany"))])),
+            ]),
+        )]),
+    );
+    maxminddb::Reader::from_source(writer.build()).expect("valid city fixture")
+}
+
+/// A tiny Enterprise database whose traits include `is_hosting_provider`.
+pub fn enterprise_fixture() -> maxminddb::Reader<Vec<u8>> {
+    let mut writer = MmdbWriter::new("GeoIP2-Enterprise");
+    writer.insert(
+        "8.8.8.0/24",
+        MmdbValue::map([
+            (
+                "country",
+                MmdbValue::map([
+                    ("iso_code", MmdbValue::string("US")),
+                    ("names", MmdbValue::map([("en", MmdbValue::string("United States"))])),
+                ]),
+            ),
+            (
+                "traits",
+                MmdbValue::map([
+                    ("is_anonymous_proxy", MmdbValue::Bool(true)),
+                    ("is_anycast", MmdbValue::Bool(false)),
+                    ("is_hosting_provider", MmdbValue::Bool(true)),
+                ]),
+            ),
+        ]),
+    );
+    maxminddb::Reader::from_source(writer.build()).expect("valid enterprise fixture")
+}
+
+/// A tiny ASN database covering the same networks as [`city_fixture`].
+pub fn asn_fixture() -> maxminddb::Reader<Vec<u8>> {
+    let mut writer = MmdbWriter::new("GeoLite2-ASN");
+    writer.insert(
+        "8.8.8.0/24",
+        MmdbValue::map([
+            ("autonomous_system_number", MmdbValue::Uint32(15169)),
+            ("autonomous_system_organization", MmdbValue::string("GOOGLE")),
+        ]),
+    );
+    writer.insert(
+        "2a00:1450::/32",
+        MmdbValue::map([
+            ("autonomous_system_number", MmdbValue::Uint32(15169)),
+            ("autonomous_system_organization", MmdbValue::string("GOOGLE")),
+        ]),
+    );
+    maxminddb::Reader::from_source(writer.build()).expect("valid ASN fixture")
+}
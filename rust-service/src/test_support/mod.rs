@@ -0,0 +1,164 @@
+//! Shared helpers for unit tests.
+
+pub mod mmdb;
+pub mod redis;
+pub mod spans;
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use moka::sync::Cache;
+
+use crate::clients::web_api::{WebApiClient, WebApiClientConfig};
+use crate::config::{runtime::RuntimeConfig, FeatureSettings, OutboundHttpSettings, Settings};
+use crate::geo::MaxMindProvider;
+use crate::handlers::AppState;
+use crate::ip_lookup::types::{IpRange, SourceFormat};
+use crate::ip_lookup::{IpCategory, IpLookupService, IpLookupServiceConfig};
+use crate::services::config_reload::ConfigReloader;
+use crate::services::aggregates::LookupAggregates;
+use crate::services::connectivity::ConnectivityChecker;
+use crate::services::debug_capture::DebugCapture;
+use crate::services::decision_log::DecisionLog;
+use crate::services::proxy_detection::ProxyDetector;
+use crate::services::response_action::MonitorOverride;
+use crate::services::usage::UsageAccounting;
+use crate::services::vpn_detection::VpnDetector;
+
+/// Key of an admin caller in every [`app_state`]
+pub const API_KEY: &str = "test-admin-key";
+
+/// Key of a caller with the `user` role
+pub const USER_API_KEY: &str = "test-user-key";
+
+/// A request builder carrying [`API_KEY`]
+pub fn request() -> axum::http::request::Builder {
+    axum::http::Request::builder().header("x-api-key", API_KEY)
+}
+
+/// Build an [`AppState`] backed by the fixture databases and an empty,
+/// offline radix tree and empty VPN and proxy lists, with every feature
+/// enabled.
+pub fn app_state() -> AppState {
+    app_state_with_ranges(Vec::new())
+}
+
+/// Like [`app_state`], with `ranges` already loaded into the radix tree.
+pub fn app_state_with_ranges(ranges: Vec<IpRange>) -> AppState {
+    let geo_provider = MaxMindProvider::new(mmdb::city_fixture(), mmdb::asn_fixture());
+    let ip_lookup_service = IpLookupService::from_ranges(ranges, ip_lookup_config());
+
+    let lookup_cache = Arc::new(Cache::new(100));
+    let runtime = RuntimeConfig::from_settings(&Settings::default()).shared();
+    let config_reloader =
+        ConfigReloader::new(Settings::default(), Arc::clone(&runtime), Arc::clone(&lookup_cache), None);
+
+    AppState {
+        geo_provider: Arc::new(geo_provider),
+        lookup_cache,
+        shared_cache: None,
+        ip_lookup_service: Arc::new(ip_lookup_service),
+        web_api_client: Arc::new(
+            WebApiClient::new(WebApiClientConfig::default())
+                .with_cached_key(API_KEY, "admin-1", "admin")
+                .with_cached_key(USER_API_KEY, "user-1", "user"),
+        ),
+        unlimited_api_keys: Arc::new(HashSet::new()),
+        features: FeatureSettings { admin: true, ..FeatureSettings::default() },
+        require_geo: false,
+        tunnel_extraction: Settings::default().ip_lookup.tunnel_extraction,
+        canonicalize_6to4: Settings::default().ip_lookup.canonicalize_6to4,
+        missing_data: Settings::default().ip_lookup.missing_data_policy(),
+        category_fallback: None,
+        runtime,
+        config_reloader: Arc::new(config_reloader),
+        range_scan_timeout: Duration::from_millis(Settings::default().server.range_scan_timeout_ms),
+        lookup_timeout: Settings::default().server.lookup_timeout(),
+        legacy_sunset: Some(Settings::default().server.legacy_routes_sunset),
+        verdict_headers: false,
+        max_findings: None,
+        on_missing_ip: Settings::default().server.on_missing_ip,
+        max_forwarded_hops: Settings::default().server.max_forwarded_hops,
+        max_concurrent_requests: Settings::default().server.max_concurrent_requests,
+        cidr_limits: Settings::default().server.cidr_limits(),
+        usage: Arc::new(UsageAccounting::new()),
+        decision_log: Arc::new(DecisionLog::new(Settings::default().decision_log.sample_rate)),
+        monitor_override: MonitorOverride::default(),
+        challenges: None,
+        aggregates: Arc::new(LookupAggregates::new()),
+        blocked_metric: true,
+        connectivity: Arc::new(ConnectivityChecker::new(reqwest::Client::new(), Vec::new())),
+        vpn_detector: Some(Arc::new(VpnDetector::from_networks(Vec::new()))),
+        proxy_detector: Some(Arc::new(ProxyDetector::from_lists(HashSet::new(), HashSet::new(), HashSet::new()))),
+        debug_capture: Arc::new(DebugCapture::new()),
+    }
+}
+
+/// A fixture range for [`app_state_with_ranges`]
+pub fn range(network: &str, category: IpCategory) -> IpRange {
+    IpRange::new(network, category, "fixture", SourceFormat::Default)
+}
+
+/// Offline service settings: no feeds, snapshots or archives
+pub fn ip_lookup_config() -> IpLookupServiceConfig {
+    IpLookupServiceConfig {
+        data_dir: std::env::temp_dir(),
+        check_updates: false,
+        update_interval_secs: 3600,
+        max_cache_age_secs: 86400,
+        snapshot_retention: 0,
+        archive_dir: std::env::temp_dir().join("archive"),
+        archive_retention: 0,
+        ipv6_aggregate_prefix: 128,
+        bloom_filter: true,
+        flat_snapshot: false,
+        offline: false,
+        overrides_file: None,
+        outbound_http: OutboundHttpSettings::default(),
+        tor_delisted_window_secs: 0,
+        feed_backoff_max_secs: 0,
+        enabled_categories: None,
+        sources: vec![],
+    }
+}
+
+/// A one-shot HTTP forward proxy on localhost that answers with `body`.
+///
+/// The handle resolves to the request head it received, so tests can check
+/// the request line and headers a client sent through the proxy.
+pub async fn mock_proxy(body: &'static str) -> (std::net::SocketAddr, tokio::task::JoinHandle<String>) {
+    mock_response("200 OK", body).await
+}
+
+/// [`mock_proxy`] answering with `status`, e.g. `503 Service Unavailable`
+pub async fn mock_response(
+    status: &'static str,
+    body: &'static str,
+) -> (std::net::SocketAddr, tokio::task::JoinHandle<String>) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handle = tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut head = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !head.ends_with(b"\r\n\r\n") {
+            let n = socket.read(&mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            head.extend_from_slice(&buf[..n]);
+        }
+        let response = format!(
+            "HTTP/1.1 {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        socket.write_all(response.as_bytes()).await.unwrap();
+        String::from_utf8_lossy(&head).into_owned()
+    });
+    (addr, handle)
+}
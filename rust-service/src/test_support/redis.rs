@@ -0,0 +1,141 @@
+//! An in-process stand-in for Redis, speaking just enough RESP for the
+//! shared cache: `GET`, `SET` (with `NX`), `INCR`, `PUBLISH` and
+//! `SUBSCRIBE`. Any other command (e.g. the `CLIENT SETINFO` clients send
+//! on connect) gets `+OK`.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{self, UnboundedSender};
+
+#[derive(Default)]
+struct State {
+    values: HashMap<String, String>,
+    /// Connections subscribed to each channel
+    subscribers: HashMap<String, Vec<UnboundedSender<Vec<u8>>>>,
+}
+
+pub struct MockRedis {
+    addr: std::net::SocketAddr,
+}
+
+impl MockRedis {
+    /// Serve on an ephemeral port until the test's runtime shuts down
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(State::default()));
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                tokio::spawn(serve(socket, Arc::clone(&state)));
+            }
+        });
+        Self { addr }
+    }
+
+    pub fn url(&self) -> String {
+        format!("redis://{}", self.addr)
+    }
+}
+
+async fn serve(socket: TcpStream, state: Arc<Mutex<State>>) {
+    let (reader, mut writer) = socket.into_split();
+    // Replies and published messages share the socket, so one task writes both
+    let (out, mut outgoing) = mpsc::unbounded_channel::<Vec<u8>>();
+    tokio::spawn(async move {
+        while let Some(frame) = outgoing.recv().await {
+            if writer.write_all(&frame).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut reader = BufReader::new(reader);
+    while let Some(command) = read_command(&mut reader).await {
+        let name = command.first().map(|name| name.to_ascii_uppercase()).unwrap_or_default();
+        let reply = {
+            let mut state = state.lock();
+            match (name.as_str(), &command[1..]) {
+                ("GET", [key]) => bulk(state.values.get(key).map(String::as_str)),
+                ("SET", [key, value, options @ ..]) => {
+                    let only_new = options.iter().any(|option| option.eq_ignore_ascii_case("NX"));
+                    if only_new && state.values.contains_key(key) {
+                        bulk(None)
+                    } else {
+                        state.values.insert(key.clone(), value.clone());
+                        b"+OK\r\n".to_vec()
+                    }
+                }
+                ("INCR", [key]) => {
+                    let value = state.values.get(key).and_then(|value| value.parse::<i64>().ok()).unwrap_or(0) + 1;
+                    state.values.insert(key.clone(), value.to_string());
+                    format!(":{}\r\n", value).into_bytes()
+                }
+                ("PUBLISH", [channel, message]) => {
+                    let subscribers = state.subscribers.entry(channel.clone()).or_default();
+                    subscribers.retain(|subscriber| !subscriber.is_closed());
+                    let frame = array(&["message", channel, message]);
+                    for subscriber in subscribers.iter() {
+                        let _ = subscriber.send(frame.clone());
+                    }
+                    format!(":{}\r\n", subscribers.len()).into_bytes()
+                }
+                ("SUBSCRIBE", [channel]) => {
+                    state.subscribers.entry(channel.clone()).or_default().push(out.clone());
+                    // The subscription count is an integer, not a bulk string
+                    let mut reply = b"*3\r\n".to_vec();
+                    reply.extend(bulk(Some("subscribe")));
+                    reply.extend(bulk(Some(channel)));
+                    reply.extend_from_slice(b":1\r\n");
+                    reply
+                }
+                _ => b"+OK\r\n".to_vec(),
+            }
+        };
+        if out.send(reply).is_err() {
+            break;
+        }
+    }
+}
+
+/// One command, as the array of bulk strings clients send
+async fn read_command(reader: &mut BufReader<OwnedReadHalf>) -> Option<Vec<String>> {
+    let count: usize = read_line(reader).await?.strip_prefix('*')?.parse().ok()?;
+    let mut command = Vec::with_capacity(count);
+    for _ in 0..count {
+        let len: usize = read_line(reader).await?.strip_prefix('$')?.parse().ok()?;
+        let mut value = vec![0; len + 2];
+        reader.read_exact(&mut value).await.ok()?;
+        value.truncate(len);
+        command.push(String::from_utf8(value).ok()?);
+    }
+    Some(command)
+}
+
+async fn read_line(reader: &mut BufReader<OwnedReadHalf>) -> Option<String> {
+    let mut line = String::new();
+    match reader.read_line(&mut line).await {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line.trim_end().to_string()),
+    }
+}
+
+fn bulk(value: Option<&str>) -> Vec<u8> {
+    match value {
+        Some(value) => format!("${}\r\n{}\r\n", value.len(), value).into_bytes(),
+        None => b"$-1\r\n".to_vec(),
+    }
+}
+
+/// An array of bulk strings
+fn array(values: &[&str]) -> Vec<u8> {
+    let mut frame = format!("*{}\r\n", values.len()).into_bytes();
+    for value in values {
+        frame.extend(bulk(Some(value)));
+    }
+    frame
+}
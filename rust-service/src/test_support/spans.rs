@@ -0,0 +1,80 @@
+//! A tracing layer that records spans so tests can assert on them.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::Subscriber;
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+/// A span seen by [`SpanCapture`], with its fields rendered as strings
+#[derive(Debug, Clone)]
+pub struct CapturedSpan {
+    pub name: &'static str,
+    pub parent: Option<&'static str>,
+    pub fields: BTreeMap<String, String>,
+}
+
+/// Records every span created while it is installed
+#[derive(Debug, Clone, Default)]
+pub struct SpanCapture {
+    spans: Arc<Mutex<Vec<(Id, CapturedSpan)>>>,
+}
+
+impl SpanCapture {
+    /// All captured spans, in creation order
+    pub fn spans(&self) -> Vec<CapturedSpan> {
+        self.spans.lock().unwrap().iter().map(|(_, span)| span.clone()).collect()
+    }
+
+    /// The first captured span called `name`
+    pub fn span(&self, name: &str) -> Option<CapturedSpan> {
+        self.spans().into_iter().find(|span| span.name == name)
+    }
+}
+
+struct FieldVisitor<'a>(&'a mut BTreeMap<String, String>);
+
+impl Visit for FieldVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(field.name().to_string(), format!("{:?}", value));
+    }
+}
+
+impl<S> Layer<S> for SpanCapture
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let parent = ctx
+            .span(id)
+            .and_then(|span| span.parent())
+            .map(|parent| parent.name());
+
+        let mut fields = BTreeMap::new();
+        attrs.record(&mut FieldVisitor(&mut fields));
+
+        self.spans.lock().unwrap().push((
+            id.clone(),
+            CapturedSpan {
+                name: attrs.metadata().name(),
+                parent,
+                fields,
+            },
+        ));
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, _ctx: Context<'_, S>) {
+        let mut spans = self.spans.lock().unwrap();
+        if let Some((_, span)) = spans.iter_mut().rev().find(|(span_id, _)| span_id == id) {
+            values.record(&mut FieldVisitor(&mut span.fields));
+        }
+    }
+}
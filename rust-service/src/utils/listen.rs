@@ -0,0 +1,158 @@
+//! Listening on every configured `server.listen` address.
+//!
+//! Each address is resolved with [`tokio::net::lookup_host`], so hostnames
+//! such as `localhost:3000` work and bind every address they resolve to.
+//! IPv6 sockets are bound IPv6-only, so `0.0.0.0:3000` and `[::]:3000` can be
+//! listened on side by side.
+
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+
+use axum::Router;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::TcpListener;
+
+/// Pending connections queued per listener
+const BACKLOG: i32 = 1024;
+
+/// Every address that could not be resolved or bound
+#[derive(Debug)]
+pub struct ListenError {
+    failures: Vec<(String, io::Error)>,
+}
+
+impl fmt::Display for ListenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cannot listen on ")?;
+        for (i, (addr, e)) in self.failures.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{} ({})", addr, e)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ListenError {}
+
+/// Resolve `addrs` (`host:port`), in order and without duplicates
+pub async fn resolve(addrs: &[String]) -> Result<Vec<SocketAddr>, ListenError> {
+    let mut resolved = Vec::new();
+    let mut failures = Vec::new();
+    for addr in addrs {
+        match tokio::net::lookup_host(addr.as_str()).await {
+            Ok(found) => {
+                for socket_addr in found {
+                    if !resolved.contains(&socket_addr) {
+                        resolved.push(socket_addr);
+                    }
+                }
+            }
+            Err(e) => failures.push((addr.clone(), e)),
+        }
+    }
+    if failures.is_empty() {
+        Ok(resolved)
+    } else {
+        Err(ListenError { failures })
+    }
+}
+
+/// Bind a listener per address, failing with every address that could not
+/// be bound
+pub fn bind(addrs: &[SocketAddr]) -> Result<Vec<TcpListener>, ListenError> {
+    let mut listeners = Vec::new();
+    let mut failures = Vec::new();
+    for addr in addrs {
+        match bind_one(*addr) {
+            Ok(listener) => listeners.push(listener),
+            Err(e) => failures.push((addr.to_string(), e)),
+        }
+    }
+    if failures.is_empty() {
+        Ok(listeners)
+    } else {
+        Err(ListenError { failures })
+    }
+}
+
+fn bind_one(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Serve `app` on every listener, with the peer address available as
+/// `ConnectInfo<SocketAddr>`. Returns when any listener fails.
+pub async fn serve(listeners: Vec<TcpListener>, app: Router) -> io::Result<()> {
+    let servers = listeners.into_iter().map(|listener| {
+        let app = app.clone();
+        async move { axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await }
+    });
+    futures_util::future::try_join_all(servers).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::ConnectInfo;
+    use axum::routing::get;
+
+    #[tokio::test]
+    async fn test_resolve_hostnames_and_report_failures() {
+        let addrs = resolve(&["localhost:3000".to_string(), "127.0.0.1:3000".to_string()]).await.unwrap();
+        assert!(addrs.contains(&"127.0.0.1:3000".parse().unwrap()));
+        // `localhost` may also resolve to ::1, but 127.0.0.1 is listed once
+        assert_eq!(addrs.iter().filter(|addr| addr.is_ipv4()).count(), 1);
+
+        let err = resolve(&["127.0.0.1:3000".to_string(), "no-port".to_string(), "[::1]:99999".to_string()])
+            .await
+            .unwrap_err();
+        let message = err.to_string();
+        assert!(message.starts_with("cannot listen on no-port ("), "{}", message);
+        assert!(message.contains(", [::1]:99999 ("), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn test_bind_reports_every_failed_address() {
+        let taken = bind(&["127.0.0.1:0".parse().unwrap()]).unwrap();
+        let taken_addr = taken[0].local_addr().unwrap();
+
+        let err = bind(&["127.0.0.1:0".parse().unwrap(), taken_addr]).unwrap_err();
+        assert_eq!(err.failures.len(), 1);
+        assert!(err.to_string().starts_with(&format!("cannot listen on {} (", taken_addr)));
+    }
+
+    #[tokio::test]
+    async fn test_serves_v4_and_v6_loopback_with_connect_info() {
+        // The same port on both families, which needs the IPv6 socket to be IPv6-only
+        let v4 = bind(&["0.0.0.0:0".parse().unwrap()]).unwrap().remove(0);
+        let port = v4.local_addr().unwrap().port();
+        let v6 = bind(&[SocketAddr::from(([0u16; 8], port))]).unwrap().remove(0);
+
+        let app = Router::new().route(
+            "/peer",
+            get(|ConnectInfo(peer): ConnectInfo<SocketAddr>| async move { peer.ip().to_string() }),
+        );
+        tokio::spawn(serve(vec![v4, v6], app));
+
+        let client = reqwest::Client::new();
+        for (url, peer) in [
+            (format!("http://127.0.0.1:{}/peer", port), "127.0.0.1"),
+            (format!("http://[::1]:{}/peer", port), "::1"),
+        ] {
+            let response = client.get(&url).send().await.unwrap();
+            assert_eq!(response.status(), 200);
+            assert_eq!(response.text().await.unwrap(), peer);
+        }
+    }
+}
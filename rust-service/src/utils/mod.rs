@@ -1,2 +1,4 @@
 pub mod file_ops;
-pub mod http_client; 
\ No newline at end of file
+pub mod http_client;
+pub mod listen;
+pub mod redact; 
\ No newline at end of file
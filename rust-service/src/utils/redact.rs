@@ -0,0 +1,212 @@
+//! Redaction of IP addresses in logs and spans.
+//!
+//! Log sites wrap addresses in [`ip`] or [`text`] instead of printing them
+//! directly; the wrapper renders according to `telemetry.log_ip_redaction`.
+//! The redactor is installed at startup and replaced when a config reload
+//! changes the redaction settings. Until then (and in tests) IPs are logged
+//! unchanged.
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+
+use arc_swap::ArcSwapOption;
+use hmac::{Hmac, Mac};
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use sha2::Sha256;
+
+static REDACTOR: ArcSwapOption<IpRedactor> = ArcSwapOption::const_empty();
+
+/// How IP addresses appear in logs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IpRedaction {
+    /// Log the full address
+    #[default]
+    None,
+    /// Zero the last IPv4 octet, or everything after the /48 for IPv6
+    LastOctet,
+    /// Log a keyed hash: the same IP always maps to the same token, but the
+    /// token cannot be reversed without the key
+    Hash,
+}
+
+#[derive(Clone)]
+pub struct IpRedactor {
+    mode: IpRedaction,
+    key: Vec<u8>,
+}
+
+// The key must not end up in logs
+impl fmt::Debug for IpRedactor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IpRedactor").field("mode", &self.mode).finish_non_exhaustive()
+    }
+}
+
+impl IpRedactor {
+    /// `key` is only used by [`IpRedaction::Hash`]. Without one a random key
+    /// is generated, so tokens only correlate within a single run.
+    pub fn new(mode: IpRedaction, key: Option<&str>) -> Self {
+        let key = match key {
+            Some(key) => key.as_bytes().to_vec(),
+            None => random_key(),
+        };
+        Self { mode, key }
+    }
+
+    pub fn redact(&self, ip: IpAddr) -> String {
+        match self.mode {
+            IpRedaction::None => ip.to_string(),
+            IpRedaction::LastOctet => truncate(ip).to_string(),
+            IpRedaction::Hash => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes keys of any length");
+                match ip {
+                    IpAddr::V4(ip) => mac.update(&ip.octets()),
+                    IpAddr::V6(ip) => mac.update(&ip.octets()),
+                }
+                let digest = mac.finalize().into_bytes();
+                let hex: String = digest[..8].iter().map(|byte| format!("{:02x}", byte)).collect();
+                format!("ip-{}", hex)
+            }
+        }
+    }
+
+    /// Redact `value` if it is an address or a CIDR range (possibly
+    /// percent-encoded, as in range query paths); anything else is unchanged
+    pub fn redact_text(&self, value: &str) -> String {
+        if self.mode == IpRedaction::None {
+            return value.to_string();
+        }
+        if let Ok(ip) = value.parse::<IpAddr>() {
+            return self.redact(ip);
+        }
+        let decoded = percent_decode_str(value).decode_utf8_lossy();
+        match decoded.split_once('/') {
+            Some((ip, prefix)) if prefix.parse::<u8>().is_ok() => match ip.parse::<IpAddr>() {
+                Ok(ip) => format!("{}/{}", self.redact(ip), prefix),
+                Err(_) => value.to_string(),
+            },
+            _ => value.to_string(),
+        }
+    }
+
+    /// `path` with every address or range segment redacted
+    pub fn redact_path(&self, path: &str) -> String {
+        if self.mode == IpRedaction::None {
+            return path.to_string();
+        }
+        path.split('/')
+            .map(|segment| self.redact_text(segment))
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+}
+
+fn truncate(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::V4(Ipv4Addr::new(a, b, c, 0))
+        }
+        IpAddr::V6(ip) => {
+            let mask = !0u128 << 80;
+            IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+        }
+    }
+}
+
+fn random_key() -> Vec<u8> {
+    // Each RandomState is seeded from the OS, which is all a throwaway key needs
+    (0..4u8)
+        .flat_map(|i| RandomState::new().hash_one(i).to_le_bytes())
+        .collect()
+}
+
+/// Install the process-wide redactor, replacing the one installed before
+pub fn install(redactor: IpRedactor) {
+    REDACTOR.store(Some(Arc::new(redactor)));
+}
+
+/// An address as it should appear in logs
+pub fn ip(ip: IpAddr) -> RedactedIp {
+    RedactedIp(ip)
+}
+
+/// A user-supplied address or range as it should appear in logs
+pub fn text<S: AsRef<str>>(value: S) -> RedactedText<S> {
+    RedactedText(value)
+}
+
+/// Renders an address through the installed redactor
+pub struct RedactedIp(IpAddr);
+
+/// Renders text through the installed redactor if it holds an address
+pub struct RedactedText<S>(S);
+
+impl fmt::Display for RedactedIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match REDACTOR.load().as_deref() {
+            Some(redactor) => f.write_str(&redactor.redact(self.0)),
+            None => self.0.fmt(f),
+        }
+    }
+}
+
+impl<S: AsRef<str>> fmt::Display for RedactedText<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match REDACTOR.load().as_deref() {
+            Some(redactor) => f.write_str(&redactor.redact_text(self.0.as_ref())),
+            None => f.write_str(self.0.as_ref()),
+        }
+    }
+}
+
+/// A request path with any addresses in it redacted
+pub fn path(path: &str) -> String {
+    match REDACTOR.load().as_deref() {
+        Some(redactor) => redactor.redact_path(path),
+        None => path.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_last_octet() {
+        let redactor = IpRedactor::new(IpRedaction::LastOctet, None);
+        assert_eq!(redactor.redact(addr("203.0.113.77")), "203.0.113.0");
+        assert_eq!(redactor.redact(addr("2001:db8:1234:5678::1")), "2001:db8:1234::");
+        assert_eq!(redactor.redact_text("198.51.100.9%2F32"), "198.51.100.0/32");
+    }
+
+    #[test]
+    fn test_hash_is_keyed_and_stable() {
+        let redactor = IpRedactor::new(IpRedaction::Hash, Some("secret"));
+        let token = redactor.redact(addr("203.0.113.77"));
+        assert!(token.starts_with("ip-") && token.len() == 19, "{}", token);
+        assert_eq!(token, IpRedactor::new(IpRedaction::Hash, Some("secret")).redact(addr("203.0.113.77")));
+        assert_ne!(token, redactor.redact(addr("203.0.113.78")));
+        assert_ne!(token, IpRedactor::new(IpRedaction::Hash, Some("other")).redact(addr("203.0.113.77")));
+    }
+
+    #[test]
+    fn test_redact_path() {
+        let redactor = IpRedactor::new(IpRedaction::LastOctet, None);
+        assert_eq!(redactor.redact_path("/api/lookup/8.8.4.4"), "/api/lookup/8.8.4.0");
+        assert_eq!(redactor.redact_path("/api/vpn/10.1.2.3%2F24"), "/api/vpn/10.1.2.0/24");
+        assert_eq!(redactor.redact_path("/api/lookup/self"), "/api/lookup/self");
+
+        let none = IpRedactor::new(IpRedaction::None, None);
+        assert_eq!(none.redact_path("/api/lookup/8.8.4.4"), "/api/lookup/8.8.4.4");
+    }
+}